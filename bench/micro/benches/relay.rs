@@ -0,0 +1,80 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::time::Duration;
+use trusttunnel::benchmarking::{self, TlsRecords};
+use trusttunnel_bench::{Loopback, Protocol};
+
+/// The amount of data relayed in each direction per iteration
+const PIPE_EXCHANGE_SIZE: usize = 4 * 1024 * 1024;
+const TLS_TRANSFER_SIZE: usize = 1024 * 1024;
+const STREAM_RELAY_SIZE: usize = 16 * 1024 * 1024;
+
+fn duplex_pipe(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("duplex_pipe_exchange");
+    group.throughput(Throughput::Bytes(2 * PIPE_EXCHANGE_SIZE as u64));
+    for chunk_size in [1024, 16 * 1024, 64 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_size),
+            &chunk_size,
+            |b, &chunk_size| {
+                b.to_async(&runtime).iter(|| async {
+                    benchmarking::pipe_exchange(PIPE_EXCHANGE_SIZE, chunk_size)
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn tls_records(c: &mut Criterion) {
+    let (cert_chain, key) = trusttunnel_bench::tls_identity().unwrap();
+    let mut records = TlsRecords::new(cert_chain, key).unwrap();
+    let mut group = c.benchmark_group("tls_records");
+    group.throughput(Throughput::Bytes(TLS_TRANSFER_SIZE as u64));
+    // The write size decides how the data is split into the records
+    for write_size in [1024, 16 * 1024, TLS_TRANSFER_SIZE] {
+        let data = vec![0; write_size];
+        group.bench_with_input(BenchmarkId::from_parameter(write_size), &data, |b, data| {
+            b.iter(|| {
+                for _ in 0..TLS_TRANSFER_SIZE / data.len() {
+                    records.transfer(data).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn stream_relay(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let loopback = Loopback::start(&runtime).unwrap();
+    let mut group = c.benchmark_group("stream_relay");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(20));
+    group.throughput(Throughput::Bytes(STREAM_RELAY_SIZE as u64));
+    for (protocol, streams) in [
+        (Protocol::H2, 1),
+        (Protocol::H2, 8),
+        (Protocol::H3, 1),
+        (Protocol::H3, 8),
+    ] {
+        group.bench_with_input(
+            BenchmarkId::new(format!("{:?}", protocol).to_lowercase(), streams),
+            &streams,
+            |b, &streams| {
+                b.to_async(&runtime).iter(|| async {
+                    loopback
+                        .upload(protocol, streams, STREAM_RELAY_SIZE / streams)
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, duplex_pipe, tls_records, stream_relay);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+//! An iperf-style throughput measurement of the tunnels through an endpoint
+//! running on the loopback interface.
+//!
+//! Prints the result as JSON. With `--baseline <file>`, compares it against a result saved
+//! earlier with `--save <file>`, and fails if the throughput dropped by more than
+//! `--tolerance` percent.
+
+use serde::{Deserialize, Serialize};
+use std::process::ExitCode;
+use std::time::Instant;
+use trusttunnel_bench::{Loopback, Protocol, CHUNK_SIZE};
+
+const USAGE: &str = "\
+Usage: loopback [OPTIONS]
+
+Options:
+  --protocol <h2|h3>    The tunnel protocol [default: h2]
+  --streams <N>         The number of the concurrent tunnels [default: 4]
+  --megabytes <N>       The upload size per tunnel [default: 64]
+  --rounds <N>          The number of the measured uploads, the best one is reported [default: 3]
+  --save <FILE>         Save the result as the baseline
+  --baseline <FILE>     Compare the result against the baseline
+  --tolerance <PERCENT> The allowed throughput drop against the baseline [default: 10]";
+
+#[derive(Serialize, Deserialize)]
+struct Report {
+    protocol: Protocol,
+    streams: usize,
+    bytes_per_stream: usize,
+    seconds: f64,
+    megabits_per_sec: f64,
+}
+
+struct Args {
+    protocol: Protocol,
+    streams: usize,
+    megabytes: usize,
+    rounds: usize,
+    save: Option<String>,
+    baseline: Option<String>,
+    tolerance: f64,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        protocol: Protocol::H2,
+        streams: 4,
+        megabytes: 64,
+        rounds: 3,
+        save: None,
+        baseline: None,
+        tolerance: 10.0,
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(name) = iter.next() {
+        let mut value = || iter.next().ok_or_else(|| format!("No value for {}", name));
+        let invalid = |e: std::num::ParseIntError| format!("Invalid {}: {}", name, e);
+        match name.as_str() {
+            "--protocol" => {
+                args.protocol = match value()?.as_str() {
+                    "h2" => Protocol::H2,
+                    "h3" => Protocol::H3,
+                    x => return Err(format!("Unknown protocol: {}", x)),
+                }
+            }
+            "--streams" => args.streams = value()?.parse().map_err(invalid)?,
+            "--megabytes" => args.megabytes = value()?.parse().map_err(invalid)?,
+            "--rounds" => args.rounds = value()?.parse().map_err(invalid)?,
+            "--save" => args.save = Some(value()?),
+            "--baseline" => args.baseline = Some(value()?),
+            "--tolerance" => {
+                args.tolerance = value()?
+                    .parse()
+                    .map_err(|e| format!("Invalid {}: {}", name, e))?
+            }
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            x => return Err(format!("Unknown option: {}\n\n{}", x, USAGE)),
+        }
+    }
+
+    if args.streams == 0 || args.megabytes == 0 || args.rounds == 0 {
+        return Err("The streams, the size and the rounds must be positive".to_string());
+    }
+    Ok(args)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let loopback = Loopback::start(&runtime).expect("Failed to start the endpoint");
+    let bytes = (args.megabytes * 1024 * 1024).div_ceil(CHUNK_SIZE) * CHUNK_SIZE;
+
+    let mut best = f64::MAX;
+    for _ in 0..args.rounds {
+        let started = Instant::now();
+        runtime
+            .block_on(loopback.upload(args.protocol, args.streams, bytes))
+            .expect("Upload failed");
+        best = best.min(started.elapsed().as_secs_f64());
+    }
+
+    let report = Report {
+        protocol: args.protocol,
+        streams: args.streams,
+        bytes_per_stream: bytes,
+        seconds: best,
+        megabits_per_sec: (args.streams * bytes * 8) as f64 / best / 1e6,
+    };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if let Some(path) = &args.save {
+        std::fs::write(path, serde_json::to_vec_pretty(&report).unwrap())
+            .expect("Failed to save the baseline");
+    }
+
+    if let Some(path) = &args.baseline {
+        let baseline: Report =
+            serde_json::from_slice(&std::fs::read(path).expect("Failed to read the baseline"))
+                .expect("Invalid baseline");
+        if (
+            baseline.protocol,
+            baseline.streams,
+            baseline.bytes_per_stream,
+        ) != (report.protocol, report.streams, report.bytes_per_stream)
+        {
+            eprintln!("The baseline is measured with different parameters");
+            return ExitCode::FAILURE;
+        }
+
+        let change = (report.megabits_per_sec / baseline.megabits_per_sec - 1.0) * 100.0;
+        eprintln!(
+            "Throughput change against the baseline: {:+.1}% ({:.1} -> {:.1} Mbit/s)",
+            change, baseline.megabits_per_sec, report.megabits_per_sec
+        );
+        if change < -args.tolerance {
+            eprintln!("Regression exceeds the tolerance of {}%", args.tolerance);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,204 @@
+//! The endpoint running on the loopback interface, relaying the tunneled uploads
+//! to a local sink, shared by the benchmarks and the `loopback` throughput harness
+
+use futures::{future, StreamExt};
+use http::Request;
+use rustls::{Certificate, PrivateKey};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use trusttunnel::net_utils;
+
+#[allow(dead_code)]
+#[path = "../../../lib/tests/common/mod.rs"]
+mod common;
+
+/// The sizes of the uploads must be non-zero multiples of this
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// The sink acknowledges the whole upload with this byte
+const ACK: u8 = 1;
+
+/// The tunnel protocols of the benchmarked relay
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    H2,
+    H3,
+}
+
+/// An endpoint and a sink accepting the tunneled connections
+pub struct Loopback {
+    endpoint: SocketAddr,
+    sink: SocketAddr,
+}
+
+impl Loopback {
+    /// Start the endpoint and the sink on the runtime, and wait for them to get ready
+    pub fn start(runtime: &Runtime) -> io::Result<Self> {
+        let endpoint = common::make_endpoint_address();
+        runtime.spawn(async move { common::run_endpoint(&endpoint).await });
+
+        let sink = runtime.block_on(async {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let address = listener.local_addr()?;
+            tokio::spawn(run_sink(listener));
+
+            for _ in 0..50 {
+                if TcpStream::connect(endpoint).await.is_ok() {
+                    return Ok(address);
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Endpoint is not started",
+            ))
+        })?;
+
+        Ok(Self { endpoint, sink })
+    }
+
+    /// Upload `bytes` through each of the `streams` tunnels at once,
+    /// the HTTP/2 ones multiplexed on a single connection
+    pub async fn upload(&self, protocol: Protocol, streams: usize, bytes: usize) -> io::Result<()> {
+        assert!(bytes >= CHUNK_SIZE && bytes % CHUNK_SIZE == 0, "{bytes}");
+        match protocol {
+            Protocol::H2 => self.h2_upload(streams, bytes).await,
+            Protocol::H3 => {
+                future::try_join_all((0..streams).map(|_| self.h3_upload(bytes))).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn h2_upload(&self, streams: usize, bytes: usize) -> io::Result<()> {
+        let stream = common::establish_tls_connection(
+            common::MAIN_DOMAIN_NAME,
+            &self.endpoint,
+            Some(net_utils::HTTP2_ALPN.as_bytes()),
+        )
+        .await;
+        let (mut request, conn) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        let conn_driver = tokio::spawn(conn);
+
+        let mut responses = Vec::with_capacity(streams);
+        for _ in 0..streams {
+            future::poll_fn(|cx| request.poll_ready(cx))
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            let connect = Request::builder()
+                .version(http::Version::HTTP_2)
+                .method(http::Method::CONNECT)
+                .uri(self.sink.to_string())
+                .body(hyper::Body::empty())
+                .unwrap();
+            responses.push(request.send_request(connect));
+        }
+
+        let uploads = responses.into_iter().map(|response| async move {
+            let response = response
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            if response.status() != http::StatusCode::OK {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("Unexpected CONNECT response: {:?}", response),
+                ));
+            }
+            let tunnel = hyper::upgrade::on(response)
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            upload_through(tunnel, bytes).await
+        });
+        let result = future::try_join_all(uploads).await;
+        conn_driver.abort();
+        result.map(|_| ())
+    }
+
+    async fn h3_upload(&self, bytes: usize) -> io::Result<()> {
+        let mut session =
+            common::Http3Session::connect(&self.endpoint, common::MAIN_DOMAIN_NAME, None).await;
+        let connect = Request::builder()
+            .method(http::Method::CONNECT)
+            .uri(self.sink.to_string())
+            .body(hyper::Body::empty())
+            .unwrap();
+        let (response, _) = session.exchange(connect).await;
+        if response.status != http::StatusCode::OK {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!("Unexpected CONNECT response: {:?}", response),
+            ));
+        }
+
+        let length = (bytes as u64).to_be_bytes();
+        session
+            .send(futures::stream::iter([length.as_slice()]))
+            .await;
+        session
+            .send(common::make_stream_of_chunks(bytes, Some(CHUNK_SIZE)))
+            .await;
+        let mut ack = [0; 1];
+        match session.recv(&mut ack).await {
+            1 if ack[0] == ACK => Ok(()),
+            _ => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Load the certificate chain and the key of the endpoint, for the TLS benchmarks
+pub fn tls_identity() -> io::Result<(Vec<Certificate>, PrivateKey)> {
+    let file = common::make_cert_key_file();
+    let path = file.path.to_str().unwrap();
+    Ok((
+        trusttunnel::utils::load_certs(path)?,
+        trusttunnel::utils::load_private_key(path)?,
+    ))
+}
+
+/// Send the length of the upload followed by the data, and wait for the acknowledgement
+async fn upload_through<IO: AsyncRead + AsyncWrite + Unpin>(
+    mut io: IO,
+    bytes: usize,
+) -> io::Result<()> {
+    io.write_all(&(bytes as u64).to_be_bytes()).await?;
+    let mut content = common::make_stream_of_chunks(bytes, Some(CHUNK_SIZE));
+    while let Some(chunk) = content.next().await {
+        io.write_all(chunk).await?;
+    }
+    io.flush().await?;
+
+    match io.read_u8().await? {
+        ACK => Ok(()),
+        x => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected acknowledgement: {}", x),
+        )),
+    }
+}
+
+async fn run_sink(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut remaining = socket.read_u64().await?;
+            let mut buffer = vec![0; 64 * 1024];
+            while remaining > 0 {
+                match socket.read(&mut buffer).await? {
+                    0 => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                    n => remaining = remaining.saturating_sub(n as u64),
+                }
+            }
+            socket.write_all(&[ACK]).await?;
+            socket.flush().await
+        });
+    }
+}
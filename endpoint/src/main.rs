@@ -3,11 +3,20 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::signal;
+use trusttunnel::authentication::caching::CachingAuthenticator;
+use trusttunnel::authentication::chain::ChainAuthenticator;
+use trusttunnel::authentication::database::DatabaseAuthenticator;
 use trusttunnel::authentication::file_based::FileBasedAuthenticator;
+use trusttunnel::authentication::introspection::IntrospectionAuthenticator;
+use trusttunnel::authentication::jwt::JwtAuthenticator;
+use trusttunnel::authentication::ldap::LdapAuthenticator;
+#[cfg(all(unix, feature = "pam"))]
+use trusttunnel::authentication::pam::PamAuthenticator;
+use trusttunnel::authentication::redis::RedisAuthenticator;
 use trusttunnel::authentication::Authenticator;
 use trusttunnel::client_config;
 use trusttunnel::core::Core;
-use trusttunnel::settings::Settings;
+use trusttunnel::settings::{AuthBackend, Settings};
 use trusttunnel::shutdown::Shutdown;
 use trusttunnel::{log_utils, settings};
 
@@ -61,6 +70,56 @@ fn increase_fd_limit() {
 #[cfg(not(unix))]
 fn increase_fd_limit() {}
 
+/// Set up the authenticator of the backend, [`None`] if the backend is not configured
+fn make_authenticator(settings: &Settings, backend: AuthBackend) -> Option<Box<dyn Authenticator>> {
+    Some(match backend {
+        AuthBackend::Ldap => Box::new(
+            LdapAuthenticator::new(settings.ldap()?.clone())
+                .expect("Couldn't set up LDAP authenticator"),
+        ),
+        AuthBackend::Database => Box::new(
+            DatabaseAuthenticator::new(settings.database()?.clone())
+                .expect("Couldn't set up database authenticator"),
+        ),
+        AuthBackend::Redis => Box::new(
+            RedisAuthenticator::new(settings.redis()?.clone())
+                .expect("Couldn't set up Redis authenticator"),
+        ),
+        AuthBackend::Jwt => Box::new(
+            JwtAuthenticator::new(settings.jwt()?.clone())
+                .expect("Couldn't set up JWT authenticator"),
+        ),
+        #[cfg(all(unix, feature = "pam"))]
+        AuthBackend::Pam => Box::new(PamAuthenticator::new(settings.pam()?.clone())),
+        // Refused by the settings validation
+        #[cfg(not(all(unix, feature = "pam")))]
+        AuthBackend::Pam => return None,
+        AuthBackend::Introspection => Box::new(
+            IntrospectionAuthenticator::new(settings.introspection()?.clone())
+                .expect("Couldn't set up token introspection authenticator"),
+        ),
+        AuthBackend::CredentialsFile => {
+            let path = settings.credentials_file_path()?;
+            let x = FileBasedAuthenticator::new(path.to_string());
+            // The problems of the entries are logged by the authenticator, which serves
+            // the valid ones regardless
+            match x.validate() {
+                Ok((n, problems)) if problems.is_empty() => {
+                    info!("Loaded {} clients from credentials file {}", n, path)
+                }
+                Ok((n, problems)) => error!(
+                    "Loaded {} clients from credentials file {} with {} problems",
+                    n,
+                    path,
+                    problems.len()
+                ),
+                Err(e) => panic!("Couldn't load credentials file {}: {}", path, e),
+            }
+            Box::new(x)
+        }
+    })
+}
+
 fn main() {
     let args = clap::Command::new("VPN endpoint")
         .args(&[
@@ -144,7 +203,7 @@ fn main() {
     })
     .expect("Couldn't set logger");
 
-    log::set_max_level(
+    log_utils::set_level(
         match args
             .get_one::<String>(LOG_LEVEL_PARAM_NAME)
             .map(String::as_str)
@@ -165,9 +224,14 @@ fn main() {
     )
     .expect("Couldn't parse the settings file");
 
-    if settings.credentials_file_path().is_none() && settings.get_listen_address().ip().is_loopback() {
+    if settings.credentials_file_path().is_none()
+        && settings.ldap().is_none()
+        && settings.database().is_none()
+        && settings.redis().is_none()
+        && settings.get_listen_address().ip().is_loopback()
+    {
         warn!(
-            "No credentials configured (credentials_file is missing). \
+            "No credentials configured (none of credentials_file, ldap, database and redis is set). \
             Anyone can connect to this endpoint. This is acceptable for local development \
             but should not be used in production."
         );
@@ -221,9 +285,34 @@ fn main() {
     };
 
     let shutdown = Shutdown::new();
-    let authenticator: Option<Arc<dyn Authenticator>> = settings
-        .credentials_file_path()
-        .map(|path| Arc::new(FileBasedAuthenticator::new(path.to_string())) as Arc<dyn Authenticator>);
+    let authenticator: Option<Arc<dyn Authenticator>> = match settings.auth_chain() {
+        Some(chain) => Some(Arc::new(ChainAuthenticator::new(
+            chain
+                .backends()
+                .iter()
+                .filter_map(|x| make_authenticator(&settings, *x))
+                .collect(),
+            chain.mode(),
+        ))),
+        None => [
+            AuthBackend::Ldap,
+            AuthBackend::Database,
+            AuthBackend::Redis,
+            AuthBackend::Jwt,
+            AuthBackend::Pam,
+            AuthBackend::Introspection,
+            AuthBackend::CredentialsFile,
+        ]
+        .into_iter()
+        .find_map(|x| make_authenticator(&settings, x))
+        .map(Arc::from),
+    };
+    let authenticator = match (authenticator, settings.auth_cache()) {
+        (Some(x), Some(cache)) => {
+            Some(Arc::new(CachingAuthenticator::new(x, cache.clone())) as Arc<dyn Authenticator>)
+        }
+        (x, _) => x,
+    };
     let core = Arc::new(
         Core::new(
             settings,
@@ -2,6 +2,17 @@ fn main() {
     println!("cargo:rerun-if-changed=src/net_utils.c");
     let target_family = std::env::var("CARGO_CFG_TARGET_FAMILY").unwrap_or_default();
     if target_family == "unix" {
-        cc::Build::new().file("src/net_utils.c").compile("net_utils");
+        cc::Build::new()
+            .file("src/net_utils.c")
+            .compile("net_utils");
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/admin.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/admin.proto"], &["proto"])
+            .expect("Failed to generate the admin service code");
     }
 }
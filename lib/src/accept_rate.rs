@@ -0,0 +1,329 @@
+//! The limiting of the rate of the new client connections, enforced right after accepting
+//! a TCP connection and before taking a new QUIC connection, so that a flood of connections
+//! does not get to the TLS handshake. Each client address has a bucket of
+//! [`AcceptRateSettings::burst`] connections refilled at [`AcceptRateSettings::connects_per_sec`],
+//! and so does each subnet of the addresses, so that a client spreading its connections
+//! over the addresses of its network is limited as well. The addresses refused too many times
+//! in a row are banned for a while.
+
+use crate::settings::AcceptRateSettings;
+use crate::state_store::StateStore;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The namespace of the state store the bans are kept in
+const BANS_NAMESPACE: &str = "bans";
+const BAN_REASON: &str = "connection rate";
+
+pub(crate) struct AcceptRateLimiter {
+    settings: AcceptRateSettings,
+    /// Keeps the bans across restarts, if set
+    state_store: Option<Arc<StateStore>>,
+    entries: Mutex<Entries>,
+}
+
+/// The reason a connection is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Refusal {
+    /// The rate of the client address is exceeded
+    Address,
+    /// The aggregate rate of the client subnet is exceeded
+    Subnet,
+    /// The client address is banned
+    Banned,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Address(IpAddr),
+    /// The address truncated to the subnet prefix
+    Subnet(IpAddr),
+}
+
+struct Bucket {
+    /// The time the bucket gets full again
+    full_at: Instant,
+    /// The refusals since the bucket was full last time
+    refusals: u32,
+}
+
+#[derive(Default)]
+struct Entries {
+    buckets: HashMap<Key, Bucket>,
+    /// The time the ban is lifted keyed by address
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl Refusal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Address => "address",
+            Self::Subnet => "subnet",
+            Self::Banned => "banned",
+        }
+    }
+}
+
+impl AcceptRateLimiter {
+    pub fn new(settings: AcceptRateSettings, state_store: Option<Arc<StateStore>>) -> Self {
+        Self {
+            settings,
+            state_store,
+            entries: Default::default(),
+        }
+    }
+
+    /// Take a slot of the connection from the `address` accepted at `now`
+    pub fn accept(&self, address: IpAddr, now: Instant) -> Result<(), Refusal> {
+        let address = address.to_canonical();
+        let mut entries = self.entries.lock().unwrap();
+        if self.is_banned(&mut entries, address, now) {
+            return Err(Refusal::Banned);
+        }
+
+        let address_key = Key::Address(address);
+        let subnet_key = Key::Subnet(self.subnet(address));
+        if !self.reserve_entries(&mut entries.buckets, &[address_key, subnet_key], now) {
+            debug!("Accept rate table is full, not tracking {}", address);
+            return Ok(());
+        }
+
+        let address_interval = Duration::from_secs(1) / self.settings.connects_per_sec;
+        let address_full_at = match admit(
+            entries.buckets.get(&address_key),
+            address_interval,
+            self.settings.burst,
+            now,
+        ) {
+            Some(x) => x,
+            None => {
+                let bucket = entries.buckets.get_mut(&address_key).unwrap();
+                bucket.refusals += 1;
+                if self.settings.ban_after == Some(bucket.refusals) {
+                    bucket.refusals = 0;
+                    self.ban(&mut entries, address, now);
+                }
+                return Err(Refusal::Address);
+            }
+        };
+
+        let subnet_interval = Duration::from_secs(1) / self.settings.subnet_connects_per_sec;
+        let subnet_full_at = match admit(
+            entries.buckets.get(&subnet_key),
+            subnet_interval,
+            self.settings.subnet_burst,
+            now,
+        ) {
+            Some(x) => x,
+            None => return Err(Refusal::Subnet),
+        };
+
+        for (key, full_at) in [(address_key, address_full_at), (subnet_key, subnet_full_at)] {
+            let bucket = entries.buckets.entry(key).or_insert(Bucket {
+                full_at,
+                refusals: 0,
+            });
+            if bucket.full_at <= now {
+                bucket.refusals = 0;
+            }
+            bucket.full_at = full_at;
+        }
+
+        Ok(())
+    }
+
+    /// Make room for the entries of the `keys` not tracked yet.
+    /// Returns `false` if the table is full of the clients still being limited.
+    fn reserve_entries(
+        &self,
+        buckets: &mut HashMap<Key, Bucket>,
+        keys: &[Key],
+        now: Instant,
+    ) -> bool {
+        let missing = keys.iter().filter(|x| !buckets.contains_key(x)).count();
+        if buckets.len() + missing <= self.settings.max_entries {
+            return true;
+        }
+        buckets.retain(|_, x| x.full_at > now);
+        buckets.len() + missing <= self.settings.max_entries
+    }
+
+    fn is_banned(&self, entries: &mut Entries, address: IpAddr, now: Instant) -> bool {
+        match entries.bans.get(&address) {
+            Some(x) if *x > now => return true,
+            Some(_) => {
+                entries.bans.remove(&address);
+            }
+            None => (),
+        }
+
+        // The bans made before a restart are known to the state store only
+        self.state_store
+            .as_ref()
+            .and_then(|x| x.get(BANS_NAMESPACE, &address.to_string()))
+            .is_some()
+    }
+
+    fn ban(&self, entries: &mut Entries, address: IpAddr, now: Instant) {
+        let duration = self.settings.ban_duration;
+        warn!(
+            "Banning {} for {:?} after {} refused connections",
+            address,
+            duration,
+            self.settings.ban_after.unwrap_or_default()
+        );
+        if entries.bans.len() >= self.settings.max_entries {
+            entries.bans.retain(|_, x| *x > now);
+        }
+        if entries.bans.len() < self.settings.max_entries {
+            entries.bans.insert(address, now + duration);
+        }
+        if let Some(x) = &self.state_store {
+            x.set(
+                BANS_NAMESPACE,
+                &address.to_string(),
+                BAN_REASON.to_string(),
+                Some(duration),
+            );
+        }
+    }
+
+    fn subnet(&self, address: IpAddr) -> IpAddr {
+        match address {
+            IpAddr::V4(x) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.settings.ipv4_prefix_len as u32)
+                    .unwrap_or_default();
+                IpAddr::V4(Ipv4Addr::from(u32::from(x) & mask))
+            }
+            IpAddr::V6(x) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.settings.ipv6_prefix_len as u32)
+                    .unwrap_or_default();
+                IpAddr::V6(Ipv6Addr::from(u128::from(x) & mask))
+            }
+        }
+    }
+}
+
+/// Get the time the `bucket` gets full again after admitting a connection at `now`,
+/// or [`None`] if it is empty
+fn admit(bucket: Option<&Bucket>, interval: Duration, burst: u32, now: Instant) -> Option<Instant> {
+    let tolerance = interval * (burst - 1);
+    let full_at = bucket.map_or(now, |x| now.max(x.full_at));
+    if full_at.duration_since(now) > tolerance {
+        return None;
+    }
+    Some(full_at + interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> AcceptRateLimiter {
+        AcceptRateLimiter::new(
+            AcceptRateSettings::builder()
+                .connects_per_sec(10)
+                .burst(2)
+                .subnet_connects_per_sec(10)
+                .subnet_burst(3)
+                .ban_after(3)
+                .ban_duration(Duration::from_secs(60))
+                .build()
+                .unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn limits_address_and_subnet() {
+        let limiter = limiter();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "192.0.2.2".parse().unwrap();
+        let carol: IpAddr = "198.51.100.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.accept(alice, now));
+        assert_eq!(Ok(()), limiter.accept(alice, now));
+        assert_eq!(Err(Refusal::Address), limiter.accept(alice, now));
+        // The subnet shares the bucket of 3 connections
+        assert_eq!(Ok(()), limiter.accept(bob, now));
+        assert_eq!(Err(Refusal::Subnet), limiter.accept(bob, now));
+        assert_eq!(Ok(()), limiter.accept(carol, now));
+
+        // The bucket is refilled over time
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Ok(()), limiter.accept(alice, later));
+        assert_eq!(Ok(()), limiter.accept(bob, later));
+    }
+
+    #[test]
+    fn aggregates_ipv6_subnet() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for x in ["2001:db8:0:1::1", "2001:db8:0:2::1", "2001:db8:0:3::1"] {
+            assert_eq!(Ok(()), limiter.accept(x.parse().unwrap(), now));
+        }
+        assert_eq!(
+            Err(Refusal::Subnet),
+            limiter.accept("2001:db8:0:4::1".parse().unwrap(), now)
+        );
+        assert_eq!(
+            Ok(()),
+            limiter.accept("2001:db8:1::1".parse().unwrap(), now)
+        );
+    }
+
+    #[test]
+    fn bans_egregious_offenders() {
+        let limiter = limiter();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        limiter.accept(alice, now).unwrap();
+        limiter.accept(alice, now).unwrap();
+        for _ in 0..3 {
+            assert_eq!(Err(Refusal::Address), limiter.accept(alice, now));
+        }
+        // Banned even though the bucket is refilled
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Err(Refusal::Banned), limiter.accept(alice, later));
+        // The mapped address is the same client
+        assert_eq!(
+            Err(Refusal::Banned),
+            limiter.accept("::ffff:192.0.2.1".parse().unwrap(), later)
+        );
+
+        let unbanned = now + Duration::from_secs(61);
+        assert_eq!(Ok(()), limiter.accept(alice, unbanned));
+    }
+
+    #[test]
+    fn persists_bans() {
+        let path = std::env::temp_dir().join(format!("accept_rate_bans_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = Arc::new(StateStore::open(&path).unwrap());
+        let settings = AcceptRateSettings::builder()
+            .burst(1)
+            .ban_after(1)
+            .build()
+            .unwrap();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        let limiter = AcceptRateLimiter::new(settings.clone(), Some(store.clone()));
+        limiter.accept(alice, now).unwrap();
+        assert_eq!(Err(Refusal::Address), limiter.accept(alice, now));
+        assert_eq!(
+            Some(BAN_REASON.to_string()),
+            store.get(BANS_NAMESPACE, "192.0.2.1")
+        );
+
+        // A restarted endpoint knows the ban
+        let limiter = AcceptRateLimiter::new(settings, Some(store));
+        assert_eq!(Err(Refusal::Banned), limiter.accept(alice, now));
+    }
+}
@@ -0,0 +1,150 @@
+//! The session affinity tokens. An endpoint instance behind a load balancer hands out a token
+//! naming itself in the successful tunnel responses, a client presents it in its next requests,
+//! and the balancer routes the requests with the token to the named instance.
+//!
+//! A token looks like `<instance ID>.<expiration timestamp>.<signature>`, so a balancer
+//! may take the instance part right from the header. The signature is the HMAC-SHA256 of
+//! the first two parts keyed with the secret shared by the instances, which lets an instance
+//! tell a misrouted client from a forged token.
+
+use crate::http_codec::RequestHeaders;
+use crate::settings::AffinitySettings;
+use crate::{log_id, log_utils};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_ENGINE;
+use base64::Engine;
+use ring::hmac;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum TokenStatus {
+    /// The token was issued by this instance
+    Own,
+    /// The token was issued by another instance, i.e., the client is routed
+    /// to a wrong instance
+    Other(String),
+    /// The token is malformed, forged or expired
+    Invalid,
+}
+
+/// Make a token valid for [`AffinitySettings.ttl`] since `now`
+pub(crate) fn issue(settings: &AffinitySettings, now: u64) -> String {
+    let payload = format!(
+        "{}.{}",
+        settings.instance_id,
+        now.saturating_add(settings.ttl.as_secs())
+    );
+    let signature = hmac::sign(&key(settings), payload.as_bytes());
+    format!("{}.{}", payload, BASE64_ENGINE.encode(signature))
+}
+
+pub(crate) fn check(settings: &AffinitySettings, token: &str, now: u64) -> TokenStatus {
+    let Some((payload, signature)) = token.rsplit_once('.') else {
+        return TokenStatus::Invalid;
+    };
+    let Some((instance_id, expires_at)) = payload.split_once('.') else {
+        return TokenStatus::Invalid;
+    };
+    let is_valid = BASE64_ENGINE
+        .decode(signature)
+        .is_ok_and(|x| hmac::verify(&key(settings), payload.as_bytes(), &x).is_ok());
+    match expires_at.parse::<u64>() {
+        Ok(x) if is_valid && now <= x => (),
+        _ => return TokenStatus::Invalid,
+    }
+
+    if instance_id == settings.instance_id {
+        TokenStatus::Own
+    } else {
+        TokenStatus::Other(instance_id.to_string())
+    }
+}
+
+/// Check the token presented with the request, if any, and make the header to be set
+/// on the successful response
+pub(crate) fn response_header(
+    settings: &AffinitySettings,
+    request: &RequestHeaders,
+    id: &log_utils::IdChain<u64>,
+) -> (String, String) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    if let Some(token) = request
+        .headers
+        .get(settings.header_name.as_str())
+        .and_then(|x| x.to_str().ok())
+    {
+        match check(settings, token, now) {
+            TokenStatus::Own => (),
+            TokenStatus::Other(x) => {
+                log_id!(
+                    debug,
+                    id,
+                    "Affinity: client is routed here instead of {}",
+                    x
+                )
+            }
+            TokenStatus::Invalid => log_id!(debug, id, "Affinity: invalid token: {}", token),
+        }
+    }
+
+    (settings.header_name.clone(), issue(settings, now))
+}
+
+fn key(settings: &AffinitySettings) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, settings.secret.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    fn settings(instance_id: &str) -> AffinitySettings {
+        AffinitySettings::builder(instance_id, SECRET)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn issued_token_is_checked() {
+        let a = settings("a");
+        let token = issue(&a, 1000);
+        assert!(token.starts_with(&format!("a.{}.", 1000 + a.ttl.as_secs())));
+
+        assert_eq!(TokenStatus::Own, check(&a, &token, 1000));
+        assert_eq!(
+            TokenStatus::Other("a".into()),
+            check(&settings("b"), &token, 1000)
+        );
+        // Expired
+        assert_eq!(
+            TokenStatus::Invalid,
+            check(&a, &token, 1001 + a.ttl.as_secs())
+        );
+        // Signed with another secret
+        let other = AffinitySettings::builder("a", "fedcba9876543210")
+            .build()
+            .unwrap();
+        assert_eq!(TokenStatus::Invalid, check(&other, &token, 1000));
+    }
+
+    #[test]
+    fn forged_token_is_invalid() {
+        let a = settings("a");
+        let token = issue(&a, 1000);
+        let (_, signature) = token.rsplit_once('.').unwrap();
+
+        for x in [
+            format!("b.{}.{}", 1000 + a.ttl.as_secs(), signature),
+            format!("a.{}.{}", u64::MAX, signature),
+            "a".to_string(),
+            "a.1".to_string(),
+            format!("{}!", token),
+        ] {
+            assert_eq!(TokenStatus::Invalid, check(&a, &x, 1000), "{}", x);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! The audit trail of the authentication attempts. Unlike the debug log, it does not depend
+//! on the logging level: every attempt, passed or not, makes a JSON record with the time,
+//! the client address, the authentication method, the username or the server name,
+//! the TLS handshake parameters, the outcome and the log ID chain to look the connection up in the debug log with.
+//! The records are tagged with the [instance](crate::instance) they come from.
+//! The credentials themselves are never recorded.
+
+use crate::authentication::Source;
+use crate::instance::Instance;
+use crate::log_rotation::RotatingFile;
+use crate::settings::AuditLogSettings;
+use crate::tls_info::TlsInfo;
+use crate::{core, log_utils};
+use serde::Serialize;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// The number of the records waiting to be written at most, the ones beyond are dropped
+const QUEUE_SIZE: usize = 4096;
+
+pub(crate) struct AuditLog {
+    tx: mpsc::Sender<String>,
+    rx: Mutex<Option<mpsc::Receiver<String>>>,
+    instance: Instance,
+}
+
+/// An authentication attempt of a client
+pub(crate) struct Attempt<'a> {
+    pub log_id: &'a log_utils::IdChain<u64>,
+    pub client_ip: Option<IpAddr>,
+    /// The credentials presented by the client, [`None`] if it has presented none
+    pub source: Option<&'a Source<'a>>,
+    pub server_name: Option<&'a str>,
+    /// The TLS handshake of the connection, [`None`] if it is unknown
+    pub tls: Option<&'a TlsInfo>,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    Pass,
+    Reject,
+    /// Rejected without asking the authenticator, see [`crate::auth_lockout`]
+    LockedOut,
+    /// Rejected without asking the authenticator, see [`crate::revocation`]
+    Revoked,
+    /// Let through with no credentials, see [`crate::settings::GuestSettings`]
+    Guest,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    client_ip: Option<IpAddr>,
+    method: &'static str,
+    username: Option<String>,
+    server_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<&'a TlsInfo>,
+    outcome: Outcome,
+    log_id: String,
+    instance_id: &'a str,
+    epoch: u64,
+}
+
+enum Sink {
+    File(RotatingFile),
+    Udp(UdpSocket),
+}
+
+impl AuditLog {
+    pub fn new(instance: Instance) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            instance,
+        }
+    }
+
+    /// Queue the record of the attempt to be written to the sink
+    pub fn record(&self, attempt: Attempt<'_>) {
+        let record = Record {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            client_ip: attempt.client_ip,
+            method: attempt.source.map_or("none", method),
+            username: attempt.source.and_then(Source::username),
+            server_name: attempt.server_name,
+            tls: attempt.tls,
+            outcome: attempt.outcome,
+            log_id: attempt.log_id.to_string(),
+            instance_id: &self.instance.id,
+            epoch: self.instance.epoch,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to encode audit record: {}", e);
+                return;
+            }
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(line) {
+            warn!(
+                "Audit log queue is full, dropping record of {}",
+                attempt.log_id
+            );
+        }
+    }
+}
+
+fn method(source: &Source<'_>) -> &'static str {
+    match source {
+        Source::Sni(_) => "sni",
+        Source::ProxyBasic(_) => "basic",
+        Source::ProxyBearer(_) => "bearer",
+        Source::ProxyDigest(_) => "digest",
+        Source::ClientCert(_) => "client_certificate",
+    }
+}
+
+/// Write the queued records to the configured sink until the endpoint shuts down
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let (log, settings) = match (
+        context.audit_log.as_ref(),
+        context.settings.audit_log.as_ref(),
+    ) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Ok(()),
+    };
+    let mut rx = log
+        .rx
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "Audit log is already running"))?;
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+    let mut sink = Sink::open(settings).await?;
+    let write = async {
+        loop {
+            tokio::select! {
+                x = rx.recv() => match x {
+                    Some(x) => sink.write(&x).await,
+                    None => break,
+                },
+                _ = sink.rotation_due() => sink.rotate().await,
+            }
+        }
+    };
+
+    let result = tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = write => Ok(()),
+    };
+
+    // The attempts made till the shutdown must not get lost
+    while let Ok(x) = rx.try_recv() {
+        sink.write(&x).await;
+    }
+    result
+}
+
+impl Sink {
+    async fn open(settings: &AuditLogSettings) -> io::Result<Self> {
+        if let Some(path) = &settings.path {
+            return RotatingFile::open(path, settings.rotation.as_ref())
+                .await
+                .map(Self::File);
+        }
+
+        let address = settings.address.ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "Audit log sink is not configured")
+        })?;
+        let bind_address: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_address).await?;
+        socket.connect(address).await?;
+        Ok(Self::Udp(socket))
+    }
+
+    /// Write the record, a failure is reported in the log, but must not break the endpoint
+    async fn write(&mut self, record: &str) {
+        let result = match self {
+            Self::File(x) => x.write(format!("{}\n", record).as_bytes()).await,
+            Self::Udp(x) => x.send(record.as_bytes()).await.map(|_| ()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write audit record: {}", e);
+        }
+    }
+
+    /// Wait for the scheduled rotation of the file, if there is one
+    async fn rotation_due(&self) {
+        match self {
+            Self::File(x) => x.rotation_due().await,
+            Self::Udp(_) => std::future::pending().await,
+        }
+    }
+
+    async fn rotate(&mut self) {
+        if let Self::File(x) = self {
+            if let Err(e) = x.rotate().await {
+                warn!("Failed to rotate audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[tokio::test]
+    async fn records_attempts() {
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-audit-{}.log", std::process::id()));
+        let settings = AuditLogSettings::builder()
+            .path(path.to_str().unwrap().to_string())
+            .build()
+            .unwrap();
+        let log = AuditLog::new(Instance {
+            id: "5f0c6a3e-2b1d-4c8e-9a7f-0e4d3c2b1a09".to_string(),
+            epoch: 3,
+        });
+        let mut sink = Sink::open(&settings).await.unwrap();
+
+        let source = Source::ProxyBasic(Cow::Borrowed("YWxpY2U6c2VjcmV0"));
+        log.record(Attempt {
+            log_id: &log_utils::IdChain::empty(),
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            source: Some(&source),
+            server_name: Some("vpn.example.org"),
+            tls: Some(&TlsInfo {
+                version: Some("TLSv1.3".to_string()),
+                ..Default::default()
+            }),
+            outcome: Outcome::Reject,
+        });
+        log.record(Attempt {
+            log_id: &log_utils::IdChain::empty(),
+            client_ip: None,
+            source: None,
+            server_name: None,
+            tls: None,
+            outcome: Outcome::LockedOut,
+        });
+        let mut rx = log.rx.lock().unwrap().take().unwrap();
+        while let Ok(x) = rx.try_recv() {
+            sink.write(&x).await;
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(2, records.len());
+        assert_eq!("192.0.2.1", records[0]["client_ip"]);
+        assert_eq!("basic", records[0]["method"]);
+        assert_eq!("alice", records[0]["username"]);
+        assert_eq!("vpn.example.org", records[0]["server_name"]);
+        assert_eq!("TLSv1.3", records[0]["tls"]["version"]);
+        assert_eq!(None, records[0]["tls"].get("cipher_suite"));
+        assert_eq!("reject", records[0]["outcome"]);
+        assert!(!content.contains("secret") && !content.contains("YWxpY2U6c2VjcmV0"));
+        assert_eq!("none", records[1]["method"]);
+        assert_eq!("locked_out", records[1]["outcome"]);
+        assert_eq!(None, records[1].get("tls"));
+        assert_eq!(3, records[1]["epoch"]);
+        assert_eq!(
+            "5f0c6a3e-2b1d-4c8e-9a7f-0e4d3c2b1a09",
+            records[1]["instance_id"]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! The protection of the authenticator against the password guessing. The failed
+//! authentications are counted per client address and per username, and the ones failing
+//! too many times in a row are locked out for a period doubling with each lockout.
+
+use crate::settings::AuthLockoutSettings;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub(crate) struct AuthLockout {
+    settings: AuthLockoutSettings,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    /// The IPv6 addresses are truncated to the /64 prefix
+    Address(IpAddr),
+    Username(String),
+}
+
+struct Entry {
+    /// The failures in a row since the latest lockout
+    failures: u32,
+    /// The lockouts since the entry was reset
+    lockouts: u32,
+    last_failure: Instant,
+    locked_till: Option<Instant>,
+}
+
+impl AuthLockout {
+    pub fn new(settings: AuthLockoutSettings) -> Self {
+        Self {
+            settings,
+            entries: Default::default(),
+        }
+    }
+
+    /// Get the time left till the client is let to authenticate,
+    /// [`None`] if it is not locked out
+    pub fn locked(&self, address: IpAddr, username: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        self.keys(address, username)
+            .filter_map(|x| entries.get(&x)?.locked_till)
+            .filter_map(|x| x.checked_duration_since(now))
+            .max()
+    }
+
+    /// Account a failed authentication of the client
+    pub fn failed(&self, address: IpAddr, username: Option<&str>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        for key in self.keys(address, username) {
+            if !entries.contains_key(&key) && entries.len() >= self.settings.max_entries {
+                entries.retain(|_, x| !self.is_stale(x, now));
+                if entries.len() >= self.settings.max_entries {
+                    debug!(
+                        "Authentication lockout table is full, not tracking {:?}",
+                        key
+                    );
+                    continue;
+                }
+            }
+
+            let entry = entries.entry(key.clone()).or_insert(Entry {
+                failures: 0,
+                lockouts: 0,
+                last_failure: now,
+                locked_till: None,
+            });
+            if self.is_stale(entry, now) {
+                entry.failures = 0;
+                entry.lockouts = 0;
+            }
+            entry.last_failure = now;
+            entry.failures += 1;
+            if entry.failures < self.settings.max_failures {
+                continue;
+            }
+
+            let period = self
+                .settings
+                .lockout
+                .saturating_mul(1 << entry.lockouts.min(31))
+                .min(self.settings.max_lockout);
+            entry.failures = 0;
+            entry.lockouts += 1;
+            entry.locked_till = Some(now + period);
+            warn!(
+                "Locking out {:?} for {:?} after {} failed authentications",
+                key, period, self.settings.max_failures
+            );
+        }
+    }
+
+    /// Forget the failures of the username once it has authenticated.
+    /// The client address is not reset, so that a client having valid credentials
+    /// is not able to guess the others.
+    pub fn passed(&self, username: Option<&str>) {
+        if let Some(x) = username {
+            self.entries
+                .lock()
+                .unwrap()
+                .remove(&Key::Username(x.to_string()));
+        }
+    }
+
+    fn keys(&self, address: IpAddr, username: Option<&str>) -> impl Iterator<Item = Key> {
+        let address = match address {
+            IpAddr::V4(_) => address,
+            IpAddr::V6(x) => IpAddr::V6(Ipv6Addr::from(u128::from(x) & (u128::MAX << 64))),
+        };
+        let username = username
+            .filter(|_| self.settings.lock_usernames)
+            .map(|x| Key::Username(x.to_string()));
+        std::iter::once(Key::Address(address)).chain(username)
+    }
+
+    /// Whether the entry is out of lockout and has not failed for the reset period
+    fn is_stale(&self, entry: &Entry, now: Instant) -> bool {
+        let since = entry
+            .locked_till
+            .map_or(entry.last_failure, |x| x.max(entry.last_failure));
+        now.saturating_duration_since(since) >= self.settings.reset_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout() -> AuthLockout {
+        AuthLockout::new(
+            AuthLockoutSettings::builder()
+                .max_failures(3)
+                .lockout(Duration::from_secs(60))
+                .max_lockout(Duration::from_secs(100))
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn locks_out_after_failures() {
+        let lockout = lockout();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "192.0.2.2".parse().unwrap();
+
+        lockout.failed(alice, Some("alice"));
+        lockout.failed(alice, Some("alice"));
+        assert_eq!(lockout.locked(alice, Some("alice")), None);
+        lockout.failed(alice, Some("carol"));
+        assert!(lockout.locked(alice, None).is_some());
+        // the username is locked out from the other addresses only once it fails enough
+        assert_eq!(lockout.locked(bob, Some("alice")), None);
+
+        lockout.passed(Some("alice"));
+        lockout.failed(bob, Some("alice"));
+        lockout.failed(bob, Some("alice"));
+        assert_eq!(lockout.locked(bob, Some("alice")), None);
+        lockout.failed(bob, Some("alice"));
+        assert!(lockout.locked(bob, Some("alice")).is_some());
+    }
+
+    #[test]
+    fn lockout_grows() {
+        let lockout = lockout();
+        let address: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::2".parse().unwrap();
+
+        let mut periods = vec![];
+        for _ in 0..3 {
+            for _ in 0..3 {
+                lockout.failed(address, None);
+            }
+            periods.push(lockout.locked(neighbour, None).unwrap().as_secs());
+        }
+        assert!(periods[0] <= 60 && periods[0] > 50);
+        assert!(periods[1] <= 100 && periods[1] > 90);
+        assert!(periods[2] <= 100 && periods[2] > 90);
+    }
+}
@@ -0,0 +1,417 @@
+use crate::authentication::destination_acl::DestinationAcl;
+use crate::authentication::{Authenticator, DataQuota, Source, Status};
+use crate::settings::AuthCacheSettings;
+use crate::tls_info::TlsInfo;
+use crate::{log_id, log_utils, policy};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The [`Authenticator`] wrapper which remembers the results of the wrapped one, so
+/// the backends exchanging with a server on each authentication (LDAP, database, Redis)
+/// are not asked again on each tunnel request of the same client.
+/// The successful results are kept for [`AuthCacheSettings::ttl`] and the rejections for
+/// [`AuthCacheSettings::negative_ttl`]. The entries are keyed by the SHA-256 digest of
+/// the presented credentials, so the cache does not keep the passwords in memory.
+pub struct CachingAuthenticator<A> {
+    inner: A,
+    settings: AuthCacheSettings,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    /// Bumped on each invalidation, so that the results of the authentications which were
+    /// in progress at the moment are not cached
+    generation: u64,
+}
+
+pub(super) type Key = [u8; 32];
+
+struct Entry {
+    status: Status,
+    expires: Instant,
+    /// The identity the entry is dropped by in [`CachingAuthenticator::invalidate`]
+    identity: Option<String>,
+    /// [`None`] until [`Authenticator::username`] is asked for the client
+    username: Option<Option<String>>,
+    /// [`None`] until [`Authenticator::token_id`] is asked for the client
+    token_id: Option<Option<String>>,
+    /// [`None`] until [`Authenticator::tier`] is asked for the client
+    tier: Option<Option<String>>,
+    /// [`None`] until [`Authenticator::egress_address`] is asked for the client
+    egress_address: Option<Option<IpAddr>>,
+    /// [`None`] until [`Authenticator::max_connections`] is asked for the client
+    max_connections: Option<Option<usize>>,
+    /// [`None`] until [`Authenticator::max_streams`] is asked for the client
+    max_streams: Option<Option<usize>>,
+    /// [`None`] until [`Authenticator::data_quota`] is asked for the client
+    data_quota: Option<Option<DataQuota>>,
+    /// [`None`] until [`Authenticator::profile`] is asked for the client
+    profile: Option<Option<String>>,
+    /// [`None`] until [`Authenticator::destination_acl`] is asked for the client
+    destination_acl: Option<Option<Arc<DestinationAcl>>>,
+    /// [`None`] until [`Authenticator::valid_till`] is asked for the client
+    valid_till: Option<Option<u64>>,
+}
+
+impl<A: Authenticator> CachingAuthenticator<A> {
+    pub fn new(inner: A, settings: AuthCacheSettings) -> Self {
+        Self {
+            inner,
+            settings,
+            state: Default::default(),
+        }
+    }
+
+    /// Get the wrapped authenticator
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Drop the cached results of the client with the username, or the SNI credentials
+    /// for the clients authenticated through SNI, so that it is authenticated anew
+    /// on the next request. Returns the number of the dropped entries.
+    pub fn invalidate_identity(&self, identity: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.entries.len();
+        state
+            .entries
+            .retain(|_, x| x.identity.as_deref() != Some(identity));
+        state.generation += 1;
+        before - state.entries.len()
+    }
+
+    /// Drop all the cached results. Returns the number of the dropped entries.
+    pub fn clear(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        std::mem::take(&mut state.entries).len()
+    }
+
+    /// Get the number of the cached results, including the expired ones which are
+    /// not dropped yet
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn ttl(&self, status: &Status) -> std::time::Duration {
+        match status {
+            Status::Pass => self.settings.ttl,
+            Status::Reject => self.settings.negative_ttl,
+        }
+    }
+
+    /// Get an attribute of a cached successful result, or fill it in with
+    /// the wrapped authenticator
+    fn attribute<T: Clone>(
+        &self,
+        source: &Source<'_>,
+        field: fn(&mut Entry) -> &mut Option<T>,
+        fetch: impl FnOnce() -> T,
+    ) -> T {
+        let key = key(source);
+        let now = Instant::now();
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            match state.entries.get_mut(&key) {
+                Some(x) if x.status == Status::Pass && x.expires > now => {
+                    if let Some(x) = field(x) {
+                        return x.clone();
+                    }
+                }
+                _ => return fetch(),
+            }
+            state.generation
+        };
+
+        let value = fetch();
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            if let Some(x) = state.entries.get_mut(&key) {
+                *field(x) = Some(value.clone());
+            }
+        }
+        value
+    }
+}
+
+impl<A: Authenticator> Authenticator for CachingAuthenticator<A> {
+    fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        let key = key(source);
+        let now = Instant::now();
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            match state.entries.get(&key) {
+                Some(x) if x.expires > now => {
+                    log_id!(trace, log_id, "Authentication result is cached");
+                    return x.status.clone();
+                }
+                Some(_) => {
+                    state.entries.remove(&key);
+                }
+                None => (),
+            }
+            state.generation
+        };
+
+        let status = self.inner.authenticate(source, log_id);
+        let ttl = self.ttl(&status);
+        if ttl.is_zero() {
+            return status;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return status;
+        }
+        if state.entries.len() >= self.settings.max_entries {
+            state.entries.retain(|_, x| x.expires > now);
+            if state.entries.len() >= self.settings.max_entries {
+                log_id!(debug, log_id, "Authentication cache is full, dropping it");
+                state.entries.clear();
+            }
+        }
+        state.entries.insert(
+            key,
+            Entry {
+                status: status.clone(),
+                expires: now + ttl,
+                identity: policy::identity(&self.inner, source),
+                username: None,
+                token_id: None,
+                tier: None,
+                egress_address: None,
+                max_connections: None,
+                max_streams: None,
+                data_quota: None,
+                profile: None,
+                destination_acl: None,
+                valid_till: None,
+            },
+        );
+        status
+    }
+
+    fn revalidate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        let now = Instant::now();
+        let cached = {
+            let state = self.state.lock().unwrap();
+            state
+                .entries
+                .get(&key(source))
+                .filter(|x| x.expires > now)
+                .map(|x| x.status.clone())
+        };
+        cached.unwrap_or_else(|| self.inner.revalidate(source, log_id))
+    }
+
+    fn username(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| &mut x.username, || self.inner.username(source))
+    }
+
+    fn token_id(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| &mut x.token_id, || self.inner.token_id(source))
+    }
+
+    fn tier(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| &mut x.tier, || self.inner.tier(source))
+    }
+
+    fn egress_address(&self, source: &Source<'_>) -> Option<IpAddr> {
+        self.attribute(
+            source,
+            |x| &mut x.egress_address,
+            || self.inner.egress_address(source),
+        )
+    }
+
+    fn max_connections(&self, source: &Source<'_>) -> Option<usize> {
+        self.attribute(
+            source,
+            |x| &mut x.max_connections,
+            || self.inner.max_connections(source),
+        )
+    }
+
+    fn max_streams(&self, source: &Source<'_>) -> Option<usize> {
+        self.attribute(
+            source,
+            |x| &mut x.max_streams,
+            || self.inner.max_streams(source),
+        )
+    }
+
+    fn data_quota(&self, source: &Source<'_>) -> Option<DataQuota> {
+        self.attribute(
+            source,
+            |x| &mut x.data_quota,
+            || self.inner.data_quota(source),
+        )
+    }
+
+    fn profile(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| &mut x.profile, || self.inner.profile(source))
+    }
+
+    fn destination_acl(&self, source: &Source<'_>) -> Option<Arc<DestinationAcl>> {
+        self.attribute(
+            source,
+            |x| &mut x.destination_acl,
+            || self.inner.destination_acl(source),
+        )
+    }
+
+    fn valid_till(&self, source: &Source<'_>) -> Option<u64> {
+        self.attribute(
+            source,
+            |x| &mut x.valid_till,
+            || self.inner.valid_till(source),
+        )
+    }
+
+    fn accept_tls(&self, source: &Source<'_>, tls: &TlsInfo) -> bool {
+        self.inner.accept_tls(source, tls)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.inner.is_healthy()
+    }
+
+    fn invalidate(&self, identity: Option<&str>) -> usize {
+        let n = match identity {
+            Some(x) => self.invalidate_identity(x),
+            None => self.clear(),
+        };
+        n + self.inner.invalidate(identity)
+    }
+}
+
+/// Get the SHA-256 digest of the credentials
+pub(super) fn key(source: &Source<'_>) -> Key {
+    let (kind, credentials) = match source {
+        Source::Sni(x) => (b's', x.as_bytes()),
+        Source::ProxyBasic(x) => (b'b', x.as_bytes()),
+        Source::ProxyBearer(x) => (b'j', x.as_bytes()),
+        Source::ProxyDigest(x) => (b'd', x.as_bytes()),
+        Source::ClientCert(_) => (b'c', source.client_cert().unwrap_or_default()),
+    };
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(&[kind]);
+    context.update(credentials);
+    let mut key = Key::default();
+    key.copy_from_slice(context.finish().as_ref());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Passes `alice:secret` and counts the authentications
+    #[derive(Default)]
+    struct Counting {
+        calls: AtomicUsize,
+        tier_calls: AtomicUsize,
+    }
+
+    impl Authenticator for Counting {
+        fn authenticate(&self, source: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match source {
+                Source::ProxyBasic(x) if *x == BASE64_ENGINE.encode("alice:secret") => Status::Pass,
+                _ => Status::Reject,
+            }
+        }
+
+        fn tier(&self, _: &Source<'_>) -> Option<String> {
+            self.tier_calls.fetch_add(1, Ordering::Relaxed);
+            Some("gold".into())
+        }
+    }
+
+    fn basic(creds: &str) -> Source<'static> {
+        Source::ProxyBasic(BASE64_ENGINE.encode(creds).into())
+    }
+
+    fn calls(authenticator: &CachingAuthenticator<Counting>) -> usize {
+        authenticator.inner().calls.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn caches_results() {
+        let authenticator = CachingAuthenticator::new(
+            Counting::default(),
+            AuthCacheSettings::builder()
+                .negative_ttl(Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        let log_id = log_utils::IdChain::empty();
+
+        for _ in 0..3 {
+            assert!(authenticator.authenticate(&basic("alice:secret"), &log_id) == Status::Pass);
+            assert!(authenticator.authenticate(&basic("alice:wrong"), &log_id) == Status::Reject);
+        }
+        assert_eq!(calls(&authenticator), 2);
+
+        for _ in 0..3 {
+            assert_eq!(
+                authenticator.tier(&basic("alice:secret")).as_deref(),
+                Some("gold")
+            );
+        }
+        assert_eq!(authenticator.inner().tier_calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn skips_rejections_without_negative_ttl() {
+        let authenticator = CachingAuthenticator::new(
+            Counting::default(),
+            AuthCacheSettings::builder()
+                .negative_ttl(Duration::ZERO)
+                .build()
+                .unwrap(),
+        );
+        let log_id = log_utils::IdChain::empty();
+
+        authenticator.authenticate(&basic("alice:wrong"), &log_id);
+        authenticator.authenticate(&basic("alice:wrong"), &log_id);
+        assert_eq!(calls(&authenticator), 2);
+        assert!(authenticator.is_empty());
+    }
+
+    #[test]
+    fn invalidates_entries() {
+        let authenticator = CachingAuthenticator::new(
+            Counting::default(),
+            AuthCacheSettings::builder()
+                .negative_ttl(Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        let log_id = log_utils::IdChain::empty();
+
+        authenticator.authenticate(&basic("alice:secret"), &log_id);
+        authenticator.authenticate(&basic("alice:wrong"), &log_id);
+        authenticator.authenticate(&basic("bob:secret"), &log_id);
+        assert_eq!(authenticator.invalidate(Some("alice")), 2);
+        assert_eq!(authenticator.len(), 1);
+
+        authenticator.authenticate(&basic("alice:secret"), &log_id);
+        assert_eq!(calls(&authenticator), 4);
+
+        assert_eq!(authenticator.invalidate(None), 2);
+        assert!(authenticator.is_empty());
+    }
+}
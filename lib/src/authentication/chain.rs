@@ -0,0 +1,279 @@
+use crate::authentication::destination_acl::DestinationAcl;
+use crate::authentication::{caching, Authenticator, DataQuota, Source, Status};
+use crate::settings::AuthChainMode;
+use crate::tls_info::TlsInfo;
+use crate::{log_id, log_utils, policy};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// The limit of the clients the passing members are remembered for. The records are
+/// dropped all at once on reaching it, and the members are asked again then.
+const MAX_PASSED_CLIENTS: usize = 64 * 1024;
+
+/// The [`Authenticator`] combining several ones, e.g., the credentials file with the local
+/// users in front of an LDAP server with the rest of them.
+/// The members are asked in order, and the outcome is decided by the [`AuthChainMode`].
+/// The attributes of a client, like [`Authenticator::tier`], are taken from the member
+/// which has passed it, or from the first member which knows them in case all of them
+/// have to pass.
+pub struct ChainAuthenticator {
+    members: Vec<Box<dyn Authenticator>>,
+    mode: AuthChainMode,
+    /// The members the clients have passed by in the [`AuthChainMode::FirstPass`] mode,
+    /// keyed like the entries of the [`caching::CachingAuthenticator`]
+    passed: Mutex<HashMap<caching::Key, Passed>>,
+}
+
+struct Passed {
+    /// The index of the member
+    member: usize,
+    /// The identity the record is dropped by in [`Authenticator::invalidate`]
+    identity: Option<String>,
+}
+
+impl ChainAuthenticator {
+    pub fn new(members: Vec<Box<dyn Authenticator>>, mode: AuthChainMode) -> Self {
+        Self {
+            members,
+            mode,
+            passed: Default::default(),
+        }
+    }
+
+    /// Get the attribute value of the client from the member which has passed it
+    fn attribute<T>(
+        &self,
+        source: &Source<'_>,
+        f: impl Fn(&dyn Authenticator) -> Option<T>,
+    ) -> Option<T> {
+        match self.mode {
+            AuthChainMode::FirstPass => f(self.members[self.passed_by(source)?].as_ref()),
+            // Each member has passed the client
+            AuthChainMode::All => self.members.iter().find_map(|x| f(x.as_ref())),
+        }
+    }
+
+    /// Get the index of the member the client has passed by. The members are asked again
+    /// in case the record is dropped.
+    fn passed_by(&self, source: &Source<'_>) -> Option<usize> {
+        let key = caching::key(source);
+        let lookup = || self.passed.lock().unwrap().get(&key).map(|x| x.member);
+        lookup().or_else(|| {
+            let log_id = log_utils::IdChain::empty();
+            self.decide(source, &log_id, |x| x.revalidate(source, &log_id));
+            lookup()
+        })
+    }
+
+    /// Ask the members in order with `f` deciding the outcome by the mode.
+    /// The member passing the client is recorded.
+    fn decide(
+        &self,
+        source: &Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+        f: impl Fn(&dyn Authenticator) -> Status,
+    ) -> Status {
+        for (i, x) in self.members.iter().enumerate() {
+            let status = f(x.as_ref());
+            match (&self.mode, status) {
+                (AuthChainMode::FirstPass, Status::Pass) => {
+                    log_id!(trace, log_id, "Passed by chained authenticator #{}", i);
+                    self.record(source, Some(i));
+                    return Status::Pass;
+                }
+                (AuthChainMode::All, Status::Reject) => {
+                    log_id!(trace, log_id, "Rejected by chained authenticator #{}", i);
+                    return Status::Reject;
+                }
+                _ => (),
+            }
+        }
+
+        match self.mode {
+            AuthChainMode::FirstPass => {
+                self.record(source, None);
+                Status::Reject
+            }
+            AuthChainMode::All if self.members.is_empty() => Status::Reject,
+            AuthChainMode::All => Status::Pass,
+        }
+    }
+
+    /// Remember the member the client has passed by, or forget it in case of [`None`]
+    fn record(&self, source: &Source<'_>, member: Option<usize>) {
+        let key = caching::key(source);
+        let Some(member) = member else {
+            self.passed.lock().unwrap().remove(&key);
+            return;
+        };
+
+        let identity = policy::identity(self.members[member].as_ref(), source);
+        let mut passed = self.passed.lock().unwrap();
+
+        if passed.len() >= MAX_PASSED_CLIENTS && !passed.contains_key(&key) {
+            passed.clear();
+        }
+        passed.insert(key, Passed { member, identity });
+    }
+}
+
+impl Authenticator for ChainAuthenticator {
+    fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        self.decide(source, log_id, |x| x.authenticate(source, log_id))
+    }
+
+    fn revalidate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        self.decide(source, log_id, |x| x.revalidate(source, log_id))
+    }
+
+    fn username(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| x.username(source))
+    }
+
+    fn token_id(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| x.token_id(source))
+    }
+
+    fn tier(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| x.tier(source))
+    }
+
+    fn egress_address(&self, source: &Source<'_>) -> Option<IpAddr> {
+        self.attribute(source, |x| x.egress_address(source))
+    }
+
+    fn max_connections(&self, source: &Source<'_>) -> Option<usize> {
+        self.attribute(source, |x| x.max_connections(source))
+    }
+
+    fn max_streams(&self, source: &Source<'_>) -> Option<usize> {
+        self.attribute(source, |x| x.max_streams(source))
+    }
+
+    fn data_quota(&self, source: &Source<'_>) -> Option<DataQuota> {
+        self.attribute(source, |x| x.data_quota(source))
+    }
+
+    fn profile(&self, source: &Source<'_>) -> Option<String> {
+        self.attribute(source, |x| x.profile(source))
+    }
+
+    fn destination_acl(&self, source: &Source<'_>) -> Option<Arc<DestinationAcl>> {
+        self.attribute(source, |x| x.destination_acl(source))
+    }
+
+    fn valid_till(&self, source: &Source<'_>) -> Option<u64> {
+        self.attribute(source, |x| x.valid_till(source))
+    }
+
+    fn accept_tls(&self, source: &Source<'_>, tls: &TlsInfo) -> bool {
+        self.members.iter().all(|x| x.accept_tls(source, tls))
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.members.iter().all(|x| x.is_healthy())
+    }
+
+    fn invalidate(&self, identity: Option<&str>) -> usize {
+        {
+            let mut passed = self.passed.lock().unwrap();
+            match identity {
+                Some(identity) => passed.retain(|_, x| x.identity.as_deref() != Some(identity)),
+                None => passed.clear(),
+            }
+        }
+        self.members.iter().map(|x| x.invalidate(identity)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+
+    /// Passes the listed credentials and assigns them the tier
+    struct Static {
+        passed: &'static [&'static str],
+        tier: Option<&'static str>,
+    }
+
+    impl Authenticator for Static {
+        fn authenticate(&self, source: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            match source {
+                Source::ProxyBasic(x)
+                    if self.passed.iter().any(|c| *x == BASE64_ENGINE.encode(c)) =>
+                {
+                    Status::Pass
+                }
+                _ => Status::Reject,
+            }
+        }
+
+        fn tier(&self, _: &Source<'_>) -> Option<String> {
+            self.tier.map(String::from)
+        }
+    }
+
+    fn chain(mode: AuthChainMode) -> ChainAuthenticator {
+        ChainAuthenticator::new(
+            vec![
+                Box::new(Static {
+                    passed: &["alice:local"],
+                    tier: None,
+                }),
+                Box::new(Static {
+                    passed: &["alice:local", "bob:remote"],
+                    tier: Some("gold"),
+                }),
+            ],
+            mode,
+        )
+    }
+
+    fn authenticate(authenticator: &ChainAuthenticator, creds: &str) -> Status {
+        let source = Source::ProxyBasic(BASE64_ENGINE.encode(creds).into());
+        authenticator.authenticate(&source, &log_utils::IdChain::empty())
+    }
+
+    #[test]
+    fn modes() {
+        let first_pass = chain(AuthChainMode::FirstPass);
+        assert!(authenticate(&first_pass, "alice:local") == Status::Pass);
+        assert!(authenticate(&first_pass, "bob:remote") == Status::Pass);
+        assert!(authenticate(&first_pass, "carol:none") == Status::Reject);
+
+        let all = chain(AuthChainMode::All);
+        assert!(authenticate(&all, "alice:local") == Status::Pass);
+        assert!(authenticate(&all, "bob:remote") == Status::Reject);
+
+        let empty = ChainAuthenticator::new(vec![], AuthChainMode::All);
+        assert!(authenticate(&empty, "alice:local") == Status::Reject);
+
+        let source = Source::ProxyBasic(BASE64_ENGINE.encode("bob:remote").into());
+        assert_eq!(first_pass.tier(&source).as_deref(), Some("gold"));
+    }
+
+    #[test]
+    fn attributes_of_passing_member() {
+        let basic = |x: &str| Source::ProxyBasic(BASE64_ENGINE.encode(x).into());
+
+        let first_pass = chain(AuthChainMode::FirstPass);
+        assert!(authenticate(&first_pass, "alice:local") == Status::Pass);
+        // The second member knows alice too, but she is passed by the first one
+        assert_eq!(first_pass.tier(&basic("alice:local")), None);
+        assert_eq!(first_pass.tier(&basic("carol:none")), None);
+
+        // The members are asked again once the records are dropped
+        first_pass.invalidate(None);
+        assert_eq!(
+            first_pass.tier(&basic("bob:remote")).as_deref(),
+            Some("gold")
+        );
+
+        let all = chain(AuthChainMode::All);
+        assert!(authenticate(&all, "alice:local") == Status::Pass);
+        assert_eq!(all.tier(&basic("alice:local")).as_deref(), Some("gold"));
+    }
+}
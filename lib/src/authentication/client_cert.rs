@@ -0,0 +1,95 @@
+//! The identities of the TLS client certificates the authenticators are able to authorize
+//! a client by. See [`authentication::Source::ClientCert`](super::Source::ClientCert).
+
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+
+/// Get the SHA-256 fingerprint of the DER encoded certificate as a lowercase hex string
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, der))
+}
+
+/// Bring a fingerprint written as, e.g., `AB:CD:...` to the form [`fingerprint`] produces
+pub fn normalize_fingerprint(x: &str) -> String {
+    x.chars()
+        .filter(|x| *x != ':')
+        .map(|x| x.to_ascii_lowercase())
+        .collect()
+}
+
+/// Get the DNS, e-mail and URI subject alternative names of the DER encoded certificate.
+/// Empty in case the certificate could not be parsed.
+pub fn subject_alt_names(der: &[u8]) -> Vec<String> {
+    let Some(x) = parse(der) else {
+        return vec![];
+    };
+    let Ok(Some(san)) = x.subject_alternative_name() else {
+        return vec![];
+    };
+
+    san.value
+        .general_names
+        .iter()
+        .filter_map(|x| match x {
+            GeneralName::DNSName(x) | GeneralName::RFC822Name(x) | GeneralName::URI(x) => {
+                Some(x.to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Get the common name of the subject of the DER encoded certificate
+pub fn common_name(der: &[u8]) -> Option<String> {
+    parse(der)?
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|x| x.as_str().ok())
+        .map(str::to_string)
+}
+
+/// Get the distinguished name of the subject of the DER encoded certificate,
+/// e.g., `CN=alice, O=Example`
+pub fn subject(der: &[u8]) -> Option<String> {
+    parse(der).map(|x| x.subject().to_string())
+}
+
+fn parse(der: &[u8]) -> Option<X509Certificate<'_>> {
+    x509_parser::parse_x509_certificate(der)
+        .ok()
+        .map(|(_, x)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_identities() {
+        let mut params =
+            rcgen::CertificateParams::new(vec!["alice.example.org".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "alice");
+        params.subject_alt_names.push(rcgen::SanType::Rfc822Name(
+            "alice@example.org".try_into().unwrap(),
+        ));
+        let key = rcgen::KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key).unwrap();
+        let der = certificate.der().as_ref();
+
+        assert_eq!(Some("alice".to_string()), common_name(der));
+        assert_eq!(Some("CN=alice".to_string()), subject(der));
+        assert_eq!(
+            vec!["alice.example.org", "alice@example.org"],
+            subject_alt_names(der)
+        );
+        assert_eq!(64, fingerprint(der).len());
+        assert_eq!(
+            fingerprint(der),
+            normalize_fingerprint(&fingerprint(der).to_uppercase())
+        );
+        assert_eq!("abcd", normalize_fingerprint("AB:cd"));
+    }
+}
@@ -0,0 +1,412 @@
+use crate::authentication::file_based::FileBasedAuthenticator;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use toml_edit::{value, Array, ArrayOfTables, Document, Item, Table};
+
+/// The runtime changes of the credentials file, so that the clients are managed without
+/// editing the file by hand. A change is written to a temporary file which then replaces
+/// the credentials file at once, keeping its permissions, the other entries, and the comments.
+/// The [`FileBasedAuthenticator`] picks the change up by the modification time of the file.
+pub struct CredentialsStore {
+    path: String,
+    /// Keeps the concurrent changes from losing each other
+    lock: Mutex<()>,
+}
+
+/// An entry of the credentials file, see [`FileBasedAuthenticator`] for the meaning
+/// of the fields
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientEntry {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_hash: Option<String>,
+    pub certificate_fingerprint: Option<String>,
+    pub certificate_san: Option<String>,
+    pub totp_secret: Option<String>,
+    pub valid_till: Option<u64>,
+    pub tier: Option<String>,
+    pub egress_address: Option<IpAddr>,
+    pub max_connections: Option<usize>,
+    pub max_streams: Option<usize>,
+    pub data_quota_bytes: Option<u64>,
+    pub data_quota_period_days: Option<u32>,
+    pub profile: Option<String>,
+    pub allowed_destinations: Vec<String>,
+    pub blocked_destinations: Vec<String>,
+    /// A disabled entry is kept in the file, but is not authenticated
+    pub disabled: bool,
+}
+
+#[derive(Debug)]
+pub enum CredentialsStoreError {
+    /// The entry would be skipped by the authenticator
+    Invalid(String),
+    /// No entry of the username
+    NotFound(String),
+    /// The credentials file failed to be read, parsed, or written
+    Io(io::Error),
+}
+
+impl CredentialsStore {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            lock: Default::default(),
+        }
+    }
+
+    /// Get the entries of the file in its order, the malformed ones being skipped
+    pub fn list(&self) -> Result<Vec<ClientEntry>, CredentialsStoreError> {
+        let doc = self.read()?;
+        Ok(doc
+            .get("client")
+            .and_then(Item::as_array_of_tables)
+            .map(|x| x.iter().filter_map(ClientEntry::from_table).collect())
+            .unwrap_or_default())
+    }
+
+    /// Replace the entries of the username with the entry, which takes the place of
+    /// the first of them, or append the entry if the username has none
+    pub fn upsert(&self, entry: &ClientEntry) -> Result<(), CredentialsStoreError> {
+        entry.validate().map_err(CredentialsStoreError::Invalid)?;
+        self.modify(|clients| {
+            let position = clients.iter().position(|x| entry.is_owner_of(x));
+            let table = entry.to_table();
+            let Some(position) = position else {
+                clients.push(table);
+                return Ok(());
+            };
+
+            let mut kept = ArrayOfTables::new();
+            for (i, x) in clients.iter().enumerate() {
+                if i == position {
+                    let mut table = table.clone();
+                    table.decor_mut().clone_from(x.decor());
+                    kept.push(table);
+                } else if !entry.is_owner_of(x) {
+                    kept.push(x.clone());
+                }
+            }
+            *clients = kept;
+            Ok(())
+        })
+    }
+
+    /// Disable or enable the entries of the username.
+    /// Returns the number of the entries.
+    pub fn set_disabled(
+        &self,
+        username: &str,
+        disabled: bool,
+    ) -> Result<usize, CredentialsStoreError> {
+        self.modify(|clients| {
+            let mut n = 0;
+            for x in clients.iter_mut().filter(|x| is_entry_of(x, username)) {
+                match disabled {
+                    true => x["disabled"] = value(true),
+                    false => drop(x.remove("disabled")),
+                }
+                n += 1;
+            }
+            match n {
+                0 => Err(CredentialsStoreError::NotFound(username.to_string())),
+                n => Ok(n),
+            }
+        })
+    }
+
+    /// Remove the entries of the username.
+    /// Returns the number of the removed entries.
+    pub fn remove(&self, username: &str) -> Result<usize, CredentialsStoreError> {
+        self.modify(|clients| {
+            let n = clients.len();
+            clients.retain(|x| !is_entry_of(x, username));
+            match n - clients.len() {
+                0 => Err(CredentialsStoreError::NotFound(username.to_string())),
+                n => Ok(n),
+            }
+        })
+    }
+
+    fn read(&self) -> Result<Document, CredentialsStoreError> {
+        match fs::read_to_string(&self.path) {
+            Ok(x) => x.parse::<Document>().map_err(|e| {
+                CredentialsStoreError::Io(io::Error::new(ErrorKind::InvalidData, e.to_string()))
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Document::new()),
+            Err(e) => Err(CredentialsStoreError::Io(e)),
+        }
+    }
+
+    /// Apply `f` to the entries of the file, and replace the file with the result
+    fn modify<T>(
+        &self,
+        f: impl FnOnce(&mut ArrayOfTables) -> Result<T, CredentialsStoreError>,
+    ) -> Result<T, CredentialsStoreError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut doc = self.read()?;
+        if doc.get("client").is_none() {
+            doc["client"] = Item::ArrayOfTables(ArrayOfTables::new());
+        }
+        let clients = doc["client"].as_array_of_tables_mut().ok_or_else(|| {
+            CredentialsStoreError::Invalid("Clients are not an array of tables".into())
+        })?;
+        let result = f(clients)?;
+        self.write(&doc).map_err(CredentialsStoreError::Io)?;
+        Ok(result)
+    }
+
+    fn write(&self, doc: &Document) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let mut file = fs::File::create(&tmp_path)?;
+        // The file carries the secrets, so the new one must not be more readable
+        if let Ok(x) = fs::metadata(&self.path) {
+            file.set_permissions(x.permissions())?;
+        }
+        file.write_all(doc.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+impl ClientEntry {
+    /// Check the entry is usable, as the authenticator would skip it otherwise
+    fn validate(&self) -> Result<(), String> {
+        // A user-id containing a colon is invalid (RFC 7617)
+        if self.username.is_empty() || self.username.contains(':') {
+            return Err(format!("Invalid username: {}", self.username));
+        }
+        let enabled = Self {
+            disabled: false,
+            ..self.clone()
+        };
+        let mut doc = Document::new();
+        doc["client"] = Item::ArrayOfTables([enabled.to_table()].into_iter().collect());
+        let problems = FileBasedAuthenticator::entry_problems(&doc);
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join("; ")),
+        }
+    }
+
+    fn is_owner_of(&self, table: &Table) -> bool {
+        is_entry_of(table, &self.username)
+    }
+
+    fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        table["username"] = value(&self.username);
+        for (key, x) in [
+            ("password", &self.password),
+            ("password_hash", &self.password_hash),
+            ("certificate_fingerprint", &self.certificate_fingerprint),
+            ("certificate_san", &self.certificate_san),
+            ("totp_secret", &self.totp_secret),
+            ("tier", &self.tier),
+            ("profile", &self.profile),
+        ] {
+            if let Some(x) = x {
+                table[key] = value(x);
+            }
+        }
+        if let Some(x) = self.egress_address {
+            table["egress_address"] = value(x.to_string());
+        }
+        for (key, x) in [
+            ("valid_till", self.valid_till),
+            ("max_connections", self.max_connections.map(|x| x as u64)),
+            ("max_streams", self.max_streams.map(|x| x as u64)),
+            ("data_quota_bytes", self.data_quota_bytes),
+            (
+                "data_quota_period_days",
+                self.data_quota_period_days.map(u64::from),
+            ),
+        ] {
+            // The values beyond the TOML integers are refused by the authenticator anyway
+            if let Some(x) = x {
+                table[key] = value(i64::try_from(x).unwrap_or(-1));
+            }
+        }
+        for (key, x) in [
+            ("allowed_destinations", &self.allowed_destinations),
+            ("blocked_destinations", &self.blocked_destinations),
+        ] {
+            if !x.is_empty() {
+                table[key] = value(x.iter().collect::<Array>());
+            }
+        }
+        if self.disabled {
+            table["disabled"] = value(true);
+        }
+        table
+    }
+
+    fn from_table(table: &Table) -> Option<Self> {
+        let string = |key| table.get(key)?.as_str().map(str::to_string);
+        let integer = |key| u64::try_from(table.get(key)?.as_integer()?).ok();
+        let strings = |key| {
+            table
+                .get(key)
+                .and_then(Item::as_array)
+                .map(|x| {
+                    x.iter()
+                        .filter_map(|x| x.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Some(Self {
+            username: string("username")?,
+            password: string("password"),
+            password_hash: string("password_hash"),
+            certificate_fingerprint: string("certificate_fingerprint"),
+            certificate_san: string("certificate_san"),
+            totp_secret: string("totp_secret"),
+            valid_till: integer("valid_till"),
+            tier: string("tier"),
+            egress_address: string("egress_address").and_then(|x| x.parse().ok()),
+            max_connections: integer("max_connections").and_then(|x| x.try_into().ok()),
+            max_streams: integer("max_streams").and_then(|x| x.try_into().ok()),
+            data_quota_bytes: integer("data_quota_bytes"),
+            data_quota_period_days: integer("data_quota_period_days")
+                .and_then(|x| x.try_into().ok()),
+            profile: string("profile"),
+            allowed_destinations: strings("allowed_destinations"),
+            blocked_destinations: strings("blocked_destinations"),
+            disabled: table.get("disabled").and_then(Item::as_bool) == Some(true),
+        })
+    }
+}
+
+fn is_entry_of(table: &Table, username: &str) -> bool {
+    table.get("username").and_then(Item::as_str) == Some(username)
+}
+
+impl Display for CredentialsStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(x) => write!(f, "Invalid client entry: {}", x),
+            Self::NotFound(x) => write!(f, "No client entry of {}", x),
+            Self::Io(x) => write!(f, "Failed to update credentials file: {}", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{Authenticator, Source, Status};
+    use crate::log_utils;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+
+    #[test]
+    fn changes_clients() {
+        let path = std::env::temp_dir()
+            .join(format!("trusttunnel-store-{}.toml", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(
+            &path,
+            "# The staff\n[[client]]\nusername = \"alice\"\npassword = \"secret\"\n",
+        )
+        .unwrap();
+        let store = CredentialsStore::new(path.clone());
+        let authenticator = FileBasedAuthenticator::new(path.clone());
+        let authenticate = |username: &str, password: &str| {
+            let source = Source::ProxyBasic(
+                BASE64_ENGINE
+                    .encode(format!("{}:{}", username, password))
+                    .into(),
+            );
+            authenticator.authenticate(&source, &log_utils::IdChain::empty()) == Status::Pass
+        };
+        assert!(authenticate("alice", "secret"));
+
+        let bob = ClientEntry {
+            username: "bob".into(),
+            password: Some("hunter2".into()),
+            tier: Some("paid".into()),
+            blocked_destinations: vec!["*.example.org:25".into()],
+            ..Default::default()
+        };
+        store.upsert(&bob).unwrap();
+        assert!(authenticate("bob", "hunter2"));
+        assert_eq!(
+            Some("paid".to_string()),
+            authenticator.tier(&Source::Sni("bob".into()))
+        );
+
+        store
+            .upsert(&ClientEntry {
+                username: "alice".into(),
+                password: Some("changed".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(!authenticate("alice", "secret"));
+        assert!(authenticate("alice", "changed"));
+        // The replaced entry keeps its place and its comment
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# The staff\n[[client]]\nusername = \"alice\""));
+
+        assert_eq!(1, store.set_disabled("bob", true).unwrap());
+        assert!(!authenticate("bob", "hunter2"));
+        assert!(store.list().unwrap()[1].disabled);
+        assert_eq!(1, store.set_disabled("bob", false).unwrap());
+        assert!(authenticate("bob", "hunter2"));
+        assert_eq!(bob, store.list().unwrap()[1]);
+
+        assert_eq!(1, store.remove("alice").unwrap());
+        assert!(!authenticate("alice", "changed"));
+        assert!(matches!(
+            store.remove("alice"),
+            Err(CredentialsStoreError::NotFound(_))
+        ));
+        assert_eq!(vec![bob], store.list().unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refuses_invalid_entries() {
+        let store = CredentialsStore::new(
+            std::env::temp_dir()
+                .join(format!(
+                    "trusttunnel-store-invalid-{}.toml",
+                    std::process::id()
+                ))
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        let invalid = [
+            ClientEntry {
+                username: "alice".into(),
+                ..Default::default()
+            },
+            ClientEntry {
+                username: "alice:admin".into(),
+                password: Some("secret".into()),
+                ..Default::default()
+            },
+            ClientEntry {
+                username: "alice".into(),
+                password: Some("secret".into()),
+                totp_secret: Some("not base32!".into()),
+                ..Default::default()
+            },
+        ];
+        for x in &invalid {
+            assert!(
+                matches!(store.upsert(x), Err(CredentialsStoreError::Invalid(_))),
+                "{:?}",
+                x
+            );
+        }
+    }
+}
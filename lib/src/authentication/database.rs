@@ -0,0 +1,408 @@
+use crate::authentication::{password_hash, Authenticator};
+use crate::settings::{DatabaseDriver, DatabaseSettings};
+use crate::{authentication, log_id, log_utils};
+use sqlx::mysql::{MySqlConnectOptions, MySqlSslMode};
+use sqlx::pool::PoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::Row;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The [`Authenticator`] implementation which looks up the credentials of a client
+/// in a PostgreSQL, MySQL or SQLite database with a configured query.
+/// The password column holds either the plain text password, or its hash of one of
+/// the schemes [`password_hash`] supports.
+/// Is only able to authenticate a client using the Proxy basic authorization.
+/// Each authentication is a query over one of the pooled server connections, which
+/// the calling thread waits for.
+pub struct DatabaseAuthenticator {
+    settings: DatabaseSettings,
+    pool: Pool,
+    /// Drives the server connections, as the serving runtime may have no spare thread
+    /// to run a query while the calling one waits for it
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+/// The server connection pool of the configured driver
+#[derive(Clone)]
+enum Pool {
+    Postgres(sqlx::PgPool),
+    Mysql(sqlx::MySqlPool),
+    Sqlite(sqlx::SqlitePool),
+}
+
+/// A client record found by the query
+#[derive(Debug, PartialEq)]
+struct Record {
+    password: Option<String>,
+    valid_till: Option<u64>,
+}
+
+impl DatabaseAuthenticator {
+    pub fn new(settings: DatabaseSettings) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("database")
+            .enable_all()
+            .build()?;
+        let pool = {
+            // The pools spawn their maintenance tasks on the current runtime
+            let _guard = runtime.enter();
+            Pool::new(&settings)?
+        };
+
+        Ok(Self {
+            settings,
+            pool,
+            runtime: Some(runtime),
+        })
+    }
+
+    fn check(&self, username: &str, password: &str) -> io::Result<bool> {
+        let lookup = self.pool.clone().lookup(
+            self.settings.lookup_query().to_string(),
+            username.to_string(),
+        );
+        let timeout = self.settings.timeout;
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.runtime.as_ref().unwrap().spawn(async move {
+            let _ = tx.send(tokio::time::timeout(timeout, lookup).await);
+        });
+        let records = match rx.recv() {
+            Ok(Ok(Ok(x))) => x,
+            Ok(Ok(Err(e))) => return Err(io::Error::new(ErrorKind::Other, e)),
+            Ok(Err(_)) => return Err(io::Error::new(ErrorKind::TimedOut, "Query timed out")),
+            Err(_) => return Err(io::Error::new(ErrorKind::Other, "Query was dropped")),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        Ok(records.iter().any(|x| {
+            x.valid_till.is_none_or(|x| now <= x)
+                && x.password.as_deref().is_some_and(|stored| {
+                    password_hash::matches(password, stored).unwrap_or_else(|e| {
+                        warn!("Database: unusable password hash of {}: {}", username, e);
+                        false
+                    })
+                })
+        }))
+    }
+}
+
+impl Drop for DatabaseAuthenticator {
+    fn drop(&mut self) {
+        // Dropping a runtime waits for its threads, which is not allowed on the serving ones
+        if let Some(x) = self.runtime.take() {
+            x.shutdown_background();
+        }
+    }
+}
+
+impl Authenticator for DatabaseAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let Some((username, password)) = source.basic_credentials() else {
+            return authentication::Status::Reject;
+        };
+
+        match authentication::block_in_place(|| self.check(&username, &password)) {
+            Ok(true) => authentication::Status::Pass,
+            Ok(false) => {
+                log_id!(
+                    debug,
+                    log_id,
+                    "Database: invalid credentials of {}",
+                    username
+                );
+                authentication::Status::Reject
+            }
+            Err(e) => {
+                log_id!(
+                    warn,
+                    log_id,
+                    "Database: failed to authenticate {}: {}",
+                    username,
+                    e
+                );
+                authentication::Status::Reject
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Make the pool of the configured driver. The server connections are established
+    /// on demand.
+    fn new(settings: &DatabaseSettings) -> io::Result<Self> {
+        fn options<DB: sqlx::Database>(settings: &DatabaseSettings) -> PoolOptions<DB> {
+            PoolOptions::new()
+                .max_connections(settings.pool_size.try_into().unwrap_or(u32::MAX))
+                .acquire_timeout(settings.timeout)
+        }
+
+        let pool = match settings.driver {
+            DatabaseDriver::Postgres => {
+                let (host, port) = host_port(&settings.address, 5432)?;
+                let mut connect = PgConnectOptions::new()
+                    .host(&host)
+                    .port(port)
+                    .username(&settings.user)
+                    .password(&settings.password)
+                    .database(&settings.database)
+                    .application_name("trusttunnel")
+                    .ssl_mode(PgSslMode::Disable);
+                if let Some(x) = &settings.tls {
+                    connect = connect.ssl_mode(if x.insecure_skip_verify {
+                        PgSslMode::Require
+                    } else {
+                        PgSslMode::VerifyFull
+                    });
+                    if let Some(x) = &x.ca_bundle_path {
+                        connect = connect.ssl_root_cert(x);
+                    }
+                }
+                Pool::Postgres(options(settings).connect_lazy_with(connect))
+            }
+            DatabaseDriver::Mysql => {
+                let (host, port) = host_port(&settings.address, 3306)?;
+                let mut connect = MySqlConnectOptions::new()
+                    .host(&host)
+                    .port(port)
+                    .username(&settings.user)
+                    .password(&settings.password)
+                    .database(&settings.database)
+                    .ssl_mode(MySqlSslMode::Disabled);
+                if let Some(x) = &settings.tls {
+                    connect = connect.ssl_mode(if x.insecure_skip_verify {
+                        MySqlSslMode::Required
+                    } else {
+                        MySqlSslMode::VerifyIdentity
+                    });
+                    if let Some(x) = &x.ca_bundle_path {
+                        connect = connect.ssl_ca(x);
+                    }
+                }
+                Pool::Mysql(options(settings).connect_lazy_with(connect))
+            }
+            DatabaseDriver::Sqlite => {
+                let connect = SqliteConnectOptions::new()
+                    .filename(&settings.database)
+                    .read_only(true);
+                Pool::Sqlite(options(settings).connect_lazy_with(connect))
+            }
+        };
+
+        Ok(pool)
+    }
+
+    async fn lookup(self, query: String, username: String) -> sqlx::Result<Vec<Record>> {
+        match self {
+            Pool::Postgres(x) => sqlx::query(&query)
+                .bind(username)
+                .fetch_all(&x)
+                .await?
+                .iter()
+                .map(parse_row)
+                .collect(),
+            Pool::Mysql(x) => sqlx::query(&query)
+                .bind(username)
+                .fetch_all(&x)
+                .await?
+                .iter()
+                .map(parse_row)
+                .collect(),
+            Pool::Sqlite(x) => sqlx::query(&query)
+                .bind(username)
+                .fetch_all(&x)
+                .await?
+                .iter()
+                .map(parse_row)
+                .collect(),
+        }
+    }
+}
+
+/// Parse a row of the password and the optional expiration timestamp columns.
+/// The timestamp column may be of either of the integer types.
+fn parse_row<R>(row: &R) -> sqlx::Result<Record>
+where
+    R: Row,
+    usize: sqlx::ColumnIndex<R>,
+    for<'r> Option<String>: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> Option<i64>: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> Option<i32>: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+{
+    let valid_till = if row.len() < 2 {
+        None
+    } else {
+        row.try_get::<Option<i64>, _>(1)
+            .or_else(|_| row.try_get::<Option<i32>, _>(1).map(|x| x.map(i64::from)))?
+    };
+
+    Ok(Record {
+        password: row.try_get(0)?,
+        // A timestamp before the epoch has passed
+        valid_till: valid_till.map(|x| x.max(0) as u64),
+    })
+}
+
+/// Split the `host:port` address, the port being optional
+fn host_port(address: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid server address: {}", address),
+        )
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => (host, port.parse().map_err(|_| invalid())?),
+        _ => (address, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+    use sqlx::sqlite::SqlitePool;
+
+    /// The rows of the clients table: username, password and expiration timestamp
+    const CLIENTS: &[(&str, Option<&str>, Option<i64>)] = &[
+        ("alice", Some("secret"), None),
+        ("bob", Some("hunter2"), Some(1)),
+        ("carol", None, None),
+        ("dave", Some("pass"), Some(i64::MAX)),
+    ];
+
+    fn make_database(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-database-{}-{}.sqlite",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let pool = SqlitePool::connect_with(
+                SqliteConnectOptions::new()
+                    .filename(&path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+            sqlx::query("CREATE TABLE clients (username TEXT, password TEXT, valid_till INTEGER)")
+                .execute(&pool)
+                .await
+                .unwrap();
+            for &(username, password, valid_till) in CLIENTS {
+                sqlx::query("INSERT INTO clients VALUES ($1, $2, $3)")
+                    .bind(username)
+                    .bind(password)
+                    .bind(valid_till)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            pool.close().await;
+        });
+
+        path
+    }
+
+    fn check(authenticator: &DatabaseAuthenticator, username: &str, password: &str) -> bool {
+        let source = authentication::Source::ProxyBasic(
+            BASE64_ENGINE
+                .encode(format!("{}:{}", username, password))
+                .into(),
+        );
+        authenticator.authenticate(&source, &log_utils::IdChain::empty())
+            == authentication::Status::Pass
+    }
+
+    #[test]
+    fn looks_up_clients() {
+        let path = make_database("lookup");
+        let settings = DatabaseSettings::builder("", path.display(), "")
+            .driver(DatabaseDriver::Sqlite)
+            .build()
+            .unwrap();
+        let authenticator = DatabaseAuthenticator::new(settings).unwrap();
+
+        assert!(check(&authenticator, "alice", "secret"));
+        assert!(!check(&authenticator, "alice", "hunter2"));
+        // Expired
+        assert!(!check(&authenticator, "bob", "hunter2"));
+        // No password
+        assert!(!check(&authenticator, "carol", ""));
+        assert!(check(&authenticator, "dave", "pass"));
+        assert!(!check(&authenticator, "eve", "secret"));
+
+        drop(authenticator);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn query_without_expiration() {
+        let path = make_database("no-expiration");
+        let settings = DatabaseSettings::builder("", path.display(), "")
+            .driver(DatabaseDriver::Sqlite)
+            .query("SELECT password FROM clients WHERE username = $1")
+            .build()
+            .unwrap();
+        let authenticator = DatabaseAuthenticator::new(settings).unwrap();
+
+        assert!(check(&authenticator, "bob", "hunter2"));
+
+        drop(authenticator);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn fails_on_unreachable_server() {
+        let settings = DatabaseSettings::builder("127.0.0.1:1", "clients", "trusttunnel")
+            .timeout(std::time::Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let authenticator = DatabaseAuthenticator::new(settings).unwrap();
+
+        // The waiting on a single threaded runtime does not block the lookup
+        authenticator.check("alice", "secret").unwrap_err();
+        assert!(!check(&authenticator, "alice", "secret"));
+    }
+
+    #[test]
+    fn splits_address() {
+        assert_eq!(
+            ("db.example.org".to_string(), 5433),
+            host_port("db.example.org:5433", 5432).unwrap()
+        );
+        assert_eq!(
+            ("db.example.org".to_string(), 5432),
+            host_port("db.example.org", 5432).unwrap()
+        );
+        assert_eq!(
+            ("::1".to_string(), 3306),
+            host_port("[::1]:3306", 5432).unwrap()
+        );
+        host_port("db.example.org:port", 5432).unwrap_err();
+        host_port(":5432", 5432).unwrap_err();
+    }
+}
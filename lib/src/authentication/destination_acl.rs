@@ -0,0 +1,178 @@
+use crate::host_patterns::HostPatterns;
+use crate::{host_patterns, net_utils};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// The destinations a client is restricted to, and the ones it is barred from.
+/// An entry is a host name pattern (see [`crate::rules::RouteRule::destination`]),
+/// an IP address or an IP network, optionally followed by a port or a range of ports,
+/// like `*.example.org:443`, `10.0.0.0/8:8000-8999`, or `[2001:db8::/32]:22`.
+/// A connection is let through if no blocked entry matches it, and an allowed one
+/// does, unless there are no allowed entries at all. The entries are matched against
+/// both the requested host name and the address the name is resolved into.
+#[derive(Debug)]
+pub struct DestinationAcl {
+    /// [`None`] means all the destinations which are not blocked are allowed
+    allowed: Option<EntryList>,
+    blocked: EntryList,
+}
+
+#[derive(Debug)]
+struct EntryList {
+    entries: Vec<Entry>,
+    /// The compiled host name patterns of the entries
+    hosts: HostPatterns,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// [`None`] for a host name pattern, which is matched through [`EntryList::hosts`]
+    network: Option<IpNet>,
+    /// [`None`] means any port
+    ports: Option<RangeInclusive<u16>>,
+}
+
+impl DestinationAcl {
+    /// Compile the `allowed_destinations` and `blocked_destinations` of a client
+    pub fn new(allowed: &[String], blocked: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            allowed: match allowed.is_empty() {
+                true => None,
+                false => Some(EntryList::new(allowed)?),
+            },
+            blocked: EntryList::new(blocked)?,
+        })
+    }
+
+    /// Check if the client is let to the destination by the requested host name,
+    /// if not an address, and the address it is resolved into
+    pub fn is_allowed(&self, host: Option<&str>, address: IpAddr, port: u16) -> bool {
+        !self.blocked.matches(host, address, port)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|x| x.matches(host, address, port))
+    }
+}
+
+impl EntryList {
+    fn new(entries: &[String]) -> Result<Self, String> {
+        let mut hosts = vec![];
+        let entries = entries
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let (target, ports) =
+                    split_ports(x).ok_or_else(|| format!("Invalid destination {}", x))?;
+                let network = match parse_network(target) {
+                    Some(x) => Some(x),
+                    None => {
+                        host_patterns::validate(target)?;
+                        hosts.push((i, net_utils::canonicalize_host_pattern(target)));
+                        None
+                    }
+                };
+                Ok(Entry { network, ports })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            entries,
+            hosts: HostPatterns::new(hosts.iter().map(|(i, x)| (*i, x.as_str()))),
+        })
+    }
+
+    fn matches(&self, host: Option<&str>, address: IpAddr, port: u16) -> bool {
+        let port_matches = |x: &Entry| x.ports.as_ref().is_none_or(|x| x.contains(&port));
+        host.and_then(|x| self.hosts.find(x, |i| port_matches(&self.entries[*i])))
+            .is_some()
+            || self
+                .entries
+                .iter()
+                .any(|x| x.network.is_some_and(|x| x.contains(&address)) && port_matches(x))
+    }
+}
+
+/// Split an entry into the target and the ports, the port part being optional
+fn split_ports(entry: &str) -> Option<(&str, Option<RangeInclusive<u16>>)> {
+    if let Some(x) = entry.strip_prefix('[') {
+        let (target, rest) = x.split_once(']')?;
+        return match rest {
+            "" => Some((target, None)),
+            x => Some((target, Some(parse_ports(x.strip_prefix(':')?)?))),
+        };
+    }
+    // A bare IPv6 address or network has the colons, but no ports
+    if parse_network(entry).is_some() {
+        return Some((entry, None));
+    }
+    match entry.rsplit_once(':') {
+        Some((target, x)) => Some((target, Some(parse_ports(x)?))),
+        None => Some((entry, None)),
+    }
+}
+
+fn parse_ports(x: &str) -> Option<RangeInclusive<u16>> {
+    let (first, last) = x.split_once('-').unwrap_or((x, x));
+    let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+    (first <= last).then_some(first..=last)
+}
+
+fn parse_network(x: &str) -> Option<IpNet> {
+    x.parse::<IpNet>()
+        .ok()
+        .or_else(|| x.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allowed: &[&str], blocked: &[&str]) -> DestinationAcl {
+        let strings = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        DestinationAcl::new(&strings(allowed), &strings(blocked)).unwrap()
+    }
+
+    #[test]
+    fn checks_destinations() {
+        let restricted = acl(
+            &[
+                "*.example.org:443",
+                "10.0.0.0/8:8000-8999",
+                "[2001:db8::/32]:22",
+            ],
+            &["admin.example.org", "10.0.0.1"],
+        );
+        let public: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(restricted.is_allowed(Some("www.example.org"), public, 443));
+        assert!(!restricted.is_allowed(Some("www.example.org"), public, 80));
+        assert!(!restricted.is_allowed(Some("admin.example.org"), public, 443));
+        assert!(!restricted.is_allowed(Some("example.com"), public, 443));
+
+        assert!(restricted.is_allowed(None, "10.1.2.3".parse().unwrap(), 8080));
+        assert!(!restricted.is_allowed(None, "10.1.2.3".parse().unwrap(), 9000));
+        // The resolved address is checked too
+        assert!(restricted.is_allowed(Some("internal.test"), "10.1.2.3".parse().unwrap(), 8080));
+        assert!(!restricted.is_allowed(Some("www.example.org"), "10.0.0.1".parse().unwrap(), 443));
+        assert!(restricted.is_allowed(None, "2001:db8::1".parse().unwrap(), 22));
+
+        let blocked_only = acl(&[], &[".example.com:25"]);
+        assert!(blocked_only.is_allowed(Some("example.com"), public, 443));
+        assert!(!blocked_only.is_allowed(Some("mx.example.com"), public, 25));
+    }
+
+    #[test]
+    fn refuses_malformed_entries() {
+        for x in [
+            "example.org:",
+            "example.org:99999",
+            "example.org:2-1",
+            "[::1",
+            "~(",
+            "*.",
+        ] {
+            assert!(DestinationAcl::new(&[x.to_string()], &[]).is_err(), "{}", x);
+        }
+        assert!(DestinationAcl::new(&["2001:db8::1".to_string()], &[]).is_ok());
+    }
+}
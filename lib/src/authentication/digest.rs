@@ -0,0 +1,388 @@
+//! The digest access authentication ([RFC 7616](https://datatracker.ietf.org/doc/html/rfc7616))
+//! of the tunnel requests, for the clients which refuse to send the Basic credentials.
+//! The 407 responses carry the challenges, and a client answers one with a digest of its
+//! password instead of the password itself. The SHA-256 and SHA-512/256 digests are
+//! supported, MD5 is not.
+//!
+//! The nonces are stateless: a nonce is the time it is issued at, signed with the key of
+//! the endpoint instance, so a nonce is accepted by the instance which has issued it until
+//! it is older than the lifetime. A client presenting an outdated one is challenged again
+//! with `stale=true` and retries without asking the user for the password.
+//!
+//! The nonce counts are not: the instance remembers the nonce, the client nonce and the
+//! count of each accepted credentials until the nonce goes stale, and a request presenting
+//! them once more is challenged again with `stale=true`, so a captured digest can not be
+//! replayed. The only exception is the session which has been established with them.
+
+use crate::authentication::password_hash::constant_time_eq;
+use crate::settings::DigestAuthSettings;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_ENGINE;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::{digest, hmac};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The method of the tunnel requests, which the digests are computed over
+const METHOD: &str = "CONNECT";
+/// The length of the truncated signature of a nonce
+const SIGNATURE_LEN: usize = 16;
+/// The algorithms of the challenges, the preferred one first
+const ALGORITHMS: [&str; 2] = ["SHA-256", "SHA-512-256"];
+/// The maximum number of the remembered nonce counts
+const MAX_USED_COUNTS: usize = 65536;
+
+/// Issues the challenges and checks the nonces of the digest authentication
+pub(crate) struct DigestAuth {
+    settings: DigestAuthSettings,
+    key: hmac::Key,
+    /// The nonce counts of the accepted credentials, keyed by the nonce, the client nonce
+    /// and the count, with the time the nonce goes stale at
+    used: Mutex<HashMap<(String, String, String), u64>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum NonceState {
+    Fresh,
+    /// Issued by the endpoint, but older than the lifetime, or its count is used already
+    Stale,
+    /// Not issued by the endpoint instance, or for another realm
+    Invalid,
+}
+
+/// The parameters of the `Digest` credentials of a request
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Credentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Option<String>,
+    pub qop: Option<String>,
+    pub nc: Option<String>,
+    pub cnonce: Option<String>,
+    /// Whether the username is hashed, which is not supported
+    pub userhash: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Algorithm {
+    digest: &'static digest::Algorithm,
+    /// Whether it is a `-sess` variant, hashing the nonces into the password hash
+    session: bool,
+}
+
+impl DigestAuth {
+    pub fn new(settings: &DigestAuthSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .expect("Failed to generate digest nonce key"),
+            used: Default::default(),
+        }
+    }
+
+    /// Make the challenges of a 407 response, the preferred one first
+    pub fn challenges(&self, now: u64, stale: bool) -> Vec<String> {
+        let nonce = self.nonce(now);
+        ALGORITHMS
+            .iter()
+            .map(|algorithm| {
+                format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                    self.settings.realm,
+                    algorithm,
+                    nonce,
+                    if stale { ", stale=true" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    /// Check the nonce of the credentials is issued by the endpoint instance
+    /// for the realm and is not outdated
+    pub fn check(&self, credentials: &Credentials, now: u64) -> NonceState {
+        if credentials.realm != self.settings.realm {
+            return NonceState::Invalid;
+        }
+        let Some(data) = BASE64_ENGINE
+            .decode(&credentials.nonce)
+            .ok()
+            .filter(|x| x.len() == 8 + SIGNATURE_LEN)
+        else {
+            return NonceState::Invalid;
+        };
+        let (issued_at, signature) = data.split_at(8);
+        let tag = hmac::sign(&self.key, issued_at);
+        if !constant_time_eq(&tag.as_ref()[..SIGNATURE_LEN], signature) {
+            return NonceState::Invalid;
+        }
+        let issued_at = u64::from_be_bytes(issued_at.try_into().unwrap());
+        match now.saturating_sub(issued_at) <= self.settings.nonce_lifetime.as_secs() {
+            true => NonceState::Fresh,
+            false => NonceState::Stale,
+        }
+    }
+
+    /// Check the nonce count of the credentials is used by some accepted credentials already
+    pub fn is_used(&self, credentials: &Credentials) -> bool {
+        self.used
+            .lock()
+            .unwrap()
+            .contains_key(&Self::count_key(credentials))
+    }
+
+    /// Remember the nonce count of the accepted credentials until the nonce goes stale.
+    /// Returns `false` if the count is used already, or there is no room to remember it.
+    pub fn use_count(&self, credentials: &Credentials, now: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= MAX_USED_COUNTS {
+            used.retain(|_, stale_at| *stale_at >= now);
+            if used.len() >= MAX_USED_COUNTS {
+                return false;
+            }
+        }
+        let stale_at = now.saturating_add(self.settings.nonce_lifetime.as_secs());
+        used.insert(Self::count_key(credentials), stale_at)
+            .is_none()
+    }
+
+    fn count_key(credentials: &Credentials) -> (String, String, String) {
+        (
+            credentials.nonce.clone(),
+            credentials.cnonce.clone().unwrap_or_default(),
+            credentials.nc.clone().unwrap_or_default(),
+        )
+    }
+
+    fn nonce(&self, now: u64) -> String {
+        let issued_at = now.to_be_bytes();
+        let mut data = issued_at.to_vec();
+        data.extend_from_slice(&hmac::sign(&self.key, &issued_at).as_ref()[..SIGNATURE_LEN]);
+        BASE64_ENGINE.encode(data)
+    }
+}
+
+impl Credentials {
+    /// Parse the parameters following the `Digest` scheme name
+    pub fn parse(x: &str) -> Option<Self> {
+        let mut result = Self::default();
+        for (name, value) in parse_params(x)? {
+            match name.as_str() {
+                "username" => result.username = value,
+                "realm" => result.realm = value,
+                "nonce" => result.nonce = value,
+                "uri" => result.uri = value,
+                "response" => result.response = value,
+                "algorithm" => result.algorithm = Some(value),
+                "qop" => result.qop = Some(value),
+                "nc" => result.nc = Some(value),
+                "cnonce" => result.cnonce = Some(value),
+                "userhash" => result.userhash = value.eq_ignore_ascii_case("true"),
+                _ => (),
+            }
+        }
+        let is_complete = [
+            &result.username,
+            &result.nonce,
+            &result.uri,
+            &result.response,
+        ]
+        .iter()
+        .all(|x| !x.is_empty());
+        is_complete.then_some(result)
+    }
+
+    /// Check the response is computed with the password
+    pub fn verify(&self, password: &str) -> bool {
+        self.expected_response(METHOD, password).is_some_and(|x| {
+            constant_time_eq(x.as_bytes(), self.response.to_ascii_lowercase().as_bytes())
+        })
+    }
+
+    /// Compute the response of the `qop=auth` protection, the only one defined
+    /// for the requests with no body to protect
+    fn expected_response(&self, method: &str, password: &str) -> Option<String> {
+        let algorithm = Algorithm::parse(self.algorithm.as_deref())?;
+        if self.userhash || self.qop.as_deref() != Some("auth") {
+            return None;
+        }
+        let (nc, cnonce) = (self.nc.as_deref()?, self.cnonce.as_deref()?);
+
+        let mut ha1 = algorithm.hash(&format!("{}:{}:{}", self.username, self.realm, password));
+        if algorithm.session {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = algorithm.hash(&format!("{}:{}", method, self.uri));
+        Some(algorithm.hash(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1, self.nonce, nc, cnonce, ha2
+        )))
+    }
+}
+
+impl Algorithm {
+    /// Parse the `algorithm` parameter, which is MD5 if there is none
+    fn parse(x: Option<&str>) -> Option<Self> {
+        let x = x.unwrap_or("MD5").to_ascii_uppercase();
+        let (name, session) = match x.strip_suffix("-SESS") {
+            Some(x) => (x, true),
+            None => (x.as_str(), false),
+        };
+        let digest = match name {
+            "SHA-256" => &digest::SHA256,
+            "SHA-512-256" => &digest::SHA512_256,
+            _ => return None,
+        };
+        Some(Self { digest, session })
+    }
+
+    fn hash(&self, data: &str) -> String {
+        hex::encode(digest::digest(self.digest, data.as_bytes()))
+    }
+}
+
+/// Parse a comma separated list of `name=value` pairs, the values being either tokens or
+/// quoted strings. The names are lowercased.
+fn parse_params(x: &str) -> Option<Vec<(String, String)>> {
+    let mut result = vec![];
+    let mut rest = x.trim();
+    while !rest.is_empty() {
+        let (name, tail) = rest.split_once('=')?;
+        let tail = tail.trim_start();
+        let (value, tail) = match tail.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i,
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, &quoted[end + 1..])
+            }
+            None => {
+                let end = tail.find(',').unwrap_or(tail.len());
+                (tail[..end].trim().to_string(), &tail[end..])
+            }
+        };
+        result.push((name.trim().to_ascii_lowercase(), value));
+
+        let tail = tail.trim_start();
+        rest = match tail.strip_prefix(',') {
+            Some(x) => x.trim_start(),
+            None if tail.is_empty() => tail,
+            None => return None,
+        };
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The example of RFC 7616 section 3.9.1
+    const EXAMPLE: &str = r#"username="Mufasa", realm="http-auth@example.org",
+        uri="/dir/index.html", algorithm=SHA-256,
+        nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", nc=00000001,
+        cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth,
+        response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1",
+        opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+
+    #[test]
+    fn computes_responses() {
+        let mut credentials = Credentials::parse(EXAMPLE).unwrap();
+        assert_eq!("Mufasa", credentials.username);
+        assert_eq!(
+            Some("753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"),
+            credentials
+                .expected_response("GET", "Circle of Life")
+                .as_deref()
+        );
+        // The tunnel requests are the CONNECT ones
+        assert!(!credentials.verify("Circle of Life"));
+        credentials.algorithm = Some("SHA-512-256-sess".to_string());
+        assert!(credentials
+            .expected_response("GET", "Circle of Life")
+            .is_some_and(|x| x.len() == 64));
+
+        credentials.algorithm = Some("MD5".to_string());
+        assert_eq!(None, credentials.expected_response("GET", "Circle of Life"));
+        credentials.algorithm = Some("SHA-256".to_string());
+        credentials.qop = None;
+        assert_eq!(None, credentials.expected_response("GET", "Circle of Life"));
+        assert_eq!(None, Credentials::parse(r#"username="Mufasa", realm="x""#));
+        assert_eq!(None, Credentials::parse(r#"username="Muf"#));
+    }
+
+    #[test]
+    fn checks_nonces() {
+        let settings = DigestAuthSettings::builder()
+            .realm("vpn".to_string())
+            .nonce_lifetime(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let auth = DigestAuth::new(&settings);
+        let challenges = auth.challenges(1000, false);
+        assert!(challenges[0].starts_with("Digest realm=\"vpn\", qop=\"auth\", algorithm=SHA-256"));
+        assert!(auth.challenges(1000, true)[1].ends_with(", stale=true"));
+
+        let nonce = challenges[0]
+            .split("nonce=\"")
+            .nth(1)
+            .and_then(|x| x.strip_suffix('"'))
+            .unwrap();
+        let credentials = |realm: &str, nonce: &str| Credentials {
+            realm: realm.to_string(),
+            nonce: nonce.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            NonceState::Fresh,
+            auth.check(&credentials("vpn", nonce), 1060)
+        );
+        assert_eq!(
+            NonceState::Stale,
+            auth.check(&credentials("vpn", nonce), 1061)
+        );
+        assert_eq!(
+            NonceState::Invalid,
+            auth.check(&credentials("other", nonce), 1000)
+        );
+        assert_eq!(
+            NonceState::Invalid,
+            DigestAuth::new(&settings).check(&credentials("vpn", nonce), 1000)
+        );
+    }
+
+    #[test]
+    fn rejects_used_counts() {
+        let settings = DigestAuthSettings::builder()
+            .realm("vpn".to_string())
+            .nonce_lifetime(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let auth = DigestAuth::new(&settings);
+        let credentials = |cnonce: &str, nc: &str| Credentials {
+            nonce: auth.nonce(1000),
+            cnonce: Some(cnonce.to_string()),
+            nc: Some(nc.to_string()),
+            ..Default::default()
+        };
+
+        assert!(!auth.is_used(&credentials("a", "00000001")));
+        assert!(auth.use_count(&credentials("a", "00000001"), 1000));
+        // The replayed credentials
+        assert!(auth.is_used(&credentials("a", "00000001")));
+        assert!(!auth.use_count(&credentials("a", "00000001"), 1010));
+        // The next count, and another client challenged with the same nonce
+        assert!(auth.use_count(&credentials("a", "00000002"), 1010));
+        assert!(auth.use_count(&credentials("b", "00000001"), 1010));
+        assert!(!auth.is_used(&credentials("a", "00000003")));
+    }
+}
@@ -1,18 +1,117 @@
-use crate::authentication::Authenticator;
+use crate::authentication::destination_acl::DestinationAcl;
+use crate::authentication::{
+    caching, client_cert, digest, password_hash, totp, Authenticator, DataQuota,
+};
 use crate::{authentication, log_utils};
 use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
 use base64::Engine;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 use toml_edit::{Document, Item};
 
+/// The [`Authenticator`] implementation which looks a client up in the credentials file.
+/// The parsed file is kept in memory and is parsed anew once its modification time or size
+/// changes, so the changes take effect right away without parsing the file on each
+/// authentication. A client entry carries either the plain text `password`, or
+/// the `password_hash` verified with [`password_hash::verify`]. The digest credentials
+/// are verified against the plain text passwords only. An entry may also carry
+/// the `certificate_fingerprint` or the `certificate_san` the client is authorized by
+/// in case it presents a TLS client certificate, in which case the password is optional.
+/// A client entry with the `totp_secret` is required to append the current time-based
+/// one-time code to its password, see [`totp`]. The code is checked once the session
+/// is established, and is accepted once per user, so that the intercepted credentials
+/// can't be replayed. The attributes of an authenticated client and
+/// [`Authenticator::revalidate`] look up the entry the credentials have passed with
+/// instead of verifying them again. The `allowed_destinations` and
+/// the `blocked_destinations` of an entry make up its [`DestinationAcl`].
+/// An entry with `disabled = true` is kept in the file, but is not authenticated.
+/// The problems the file is parsed with, like the entries which are skipped, are logged
+/// once per change of the file, and [`FileBasedAuthenticator::validate`] reports them
+/// upfront. The valid entries are served regardless of the problems of the others.
 pub struct FileBasedAuthenticator {
     credentials_file_path: String,
+    cache: RwLock<Cache>,
+    /// The time step of the last one-time code accepted per username
+    totp_steps: Mutex<HashMap<String, u64>>,
+}
+
+/// The limit of the credentials the passed entries are remembered for
+const MAX_PASSED_ENTRIES: usize = 4096;
+
+#[derive(Default)]
+struct Cache {
+    /// The modification time and the size of the file the clients are parsed from,
+    /// [`None`] if the file could not be read
+    stamp: Option<(SystemTime, u64)>,
+    /// The entries keyed by username in the order of the file
+    clients: HashMap<String, Vec<Client>>,
+    /// The problems of the last parsing of the file, empty if it went fine
+    problems: Vec<String>,
+    /// The error the file could not be read or parsed with, [`None`] if it is loaded
+    load_error: Option<String>,
+    /// The username and the index of the entry the credentials have passed with,
+    /// keyed by the digest of the credentials. Dropped once the file is parsed anew.
+    passed: Mutex<HashMap<caching::Key, (String, usize)>>,
+}
+
+struct Client {
+    /// [`None`] for the clients authorized by a client certificate only
+    password: Option<Password>,
+    /// The normalized SHA-256 fingerprint of the client certificate
+    certificate_fingerprint: Option<String>,
+    /// A subject alternative name of the client certificate
+    certificate_san: Option<String>,
+    /// The decoded TOTP secret
+    totp_key: Option<Vec<u8>>,
+    valid_till: Option<u64>,
+    tier: Option<String>,
+    max_connections: Option<usize>,
+    max_streams: Option<usize>,
+    data_quota: Option<DataQuota>,
+    profile: Option<String>,
+    destination_acl: Option<Arc<DestinationAcl>>,
+}
+
+enum Password {
+    Plain(String),
+    Hash(String),
+}
+
+/// How the one-time code appended to the password is treated
+#[derive(Clone, Copy)]
+enum Totp<'a> {
+    /// The code is verified, and the time step it is of is recorded in the steps
+    /// of the usernames, so that it is not accepted again
+    Verify(&'a Mutex<HashMap<String, u64>>),
+    /// The code is stripped unchecked, as it has been verified once the session
+    /// was established
+    Skip,
+}
+
+enum LoadError {
+    Read(io::Error),
+    Parse(toml_edit::TomlError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "Failed to read: {}", e),
+            Self::Parse(e) => write!(f, "Failed to parse: {}", e),
+        }
+    }
 }
 
 impl FileBasedAuthenticator {
     pub fn new(credentials_file_path: String) -> Self {
         Self {
             credentials_file_path,
+            cache: Default::default(),
+            totp_steps: Default::default(),
         }
     }
 
@@ -23,52 +122,394 @@ impl FileBasedAuthenticator {
             .map(|d| d.as_secs())
     }
 
-    fn is_valid_client(
-        doc: &Document,
-        source: &authentication::Source<'_>,
-        now: Option<u64>,
-    ) -> bool {
-        let clients = match doc.get("client").and_then(Item::as_array_of_tables) {
-            Some(x) => x,
-            None => return false,
-        };
+    fn stamp(&self) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(&self.credentials_file_path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
 
-        for client in clients.iter() {
-            let username = client.get("username").and_then(Item::as_str);
-            let password = client.get("password").and_then(Item::as_str);
+    /// Parse the file reporting its problems, so that a misconfiguration is caught
+    /// before the clients get rejected.
+    /// Returns the number of the usable entries along with the problems of the file,
+    /// like the entries which are skipped, or the error the file could not be read
+    /// or parsed at all with.
+    pub fn validate(&self) -> Result<(usize, Vec<String>), String> {
+        let n = self.with_clients(|x| x.values().map(Vec::len).sum());
+        let cache = self.cache.read().unwrap();
+        match &cache.load_error {
+            None => Ok((n, cache.problems.clone())),
+            Some(e) => Err(e.clone()),
+        }
+    }
 
-            let (Some(username), Some(password)) = (username, password) else {
-                continue;
+    /// Run `f` over the clients, parsing the file first if it has changed
+    fn with_clients<T>(&self, f: impl FnOnce(&HashMap<String, Vec<Client>>) -> T) -> T {
+        self.with_cache(|x| f(&x.clients))
+    }
+
+    /// Run `f` over the cache, parsing the file first if it has changed
+    fn with_cache<T>(&self, f: impl FnOnce(&Cache) -> T) -> T {
+        let stamp = self.stamp();
+        {
+            let cache = self.cache.read().unwrap();
+            if cache.stamp.is_some() && cache.stamp == stamp {
+                return f(&cache);
+            }
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        if cache.stamp.is_none() || cache.stamp != stamp {
+            let problems = match self.read_document() {
+                Ok((content, doc)) => {
+                    let (clients, problems) = Self::parse_clients(&content, &doc);
+                    cache.clients = clients;
+                    cache.load_error = None;
+                    problems
+                }
+                // The file may be read while it is being written, in which case it is parsed
+                // again once the writing is complete
+                Err(e @ LoadError::Parse(_)) if stamp.is_some() => {
+                    cache.load_error = Some(e.to_string());
+                    vec![format!("{}, keeping previous clients", e)]
+                }
+                Err(e) => {
+                    cache.clients.clear();
+                    cache.load_error = Some(e.to_string());
+                    vec![e.to_string()]
+                }
             };
+            // The file which could not be read is read again on each authentication
+            if problems != cache.problems {
+                for x in &problems {
+                    error!("Credentials file {}: {}", self.credentials_file_path, x);
+                }
+            }
+            cache.problems = problems;
+            cache.stamp = stamp;
+            cache.passed.get_mut().unwrap().clear();
+        }
+        f(&cache)
+    }
+
+    fn read_document(&self) -> Result<(String, Document), LoadError> {
+        let content =
+            std::fs::read_to_string(&self.credentials_file_path).map_err(LoadError::Read)?;
+        let doc = content.parse().map_err(LoadError::Parse)?;
+        Ok((content, doc))
+    }
+
+    /// Returns the problems of the entries which would be skipped
+    pub(super) fn entry_problems(doc: &Document) -> Vec<String> {
+        Self::parse_clients(&doc.to_string(), doc).1
+    }
+
+    /// Returns the usable clients and the problems of the entries which are skipped
+    fn parse_clients(content: &str, doc: &Document) -> (HashMap<String, Vec<Client>>, Vec<String>) {
+        let mut result: HashMap<String, Vec<Client>> = HashMap::new();
+        let mut problems = vec![];
+        let Some(clients) = doc.get("client").and_then(Item::as_array_of_tables) else {
+            problems.push("No [[client]] entries".to_string());
+            return (result, problems);
+        };
 
-            if let Some(valid_till) = client
-                .get("valid_till")
-                .and_then(Item::as_integer)
-                .and_then(|x| u64::try_from(x).ok())
+        let lines = Self::entry_lines(content);
+        for (idx, client) in clients.iter().enumerate() {
+            let mut skip = |reason: &str| {
+                problems.push(
+                    match lines.get(idx).filter(|_| lines.len() == clients.len()) {
+                        Some(line) => {
+                            format!("Client #{} at line {}: {}, skipping", idx + 1, line, reason)
+                        }
+                        None => format!("Client #{}: {}, skipping", idx + 1, reason),
+                    },
+                );
+            };
+            if client.get("disabled").and_then(Item::as_bool) == Some(true) {
+                continue;
+            }
+            let password = match (
+                client.get("password").and_then(Item::as_str),
+                client.get("password_hash").and_then(Item::as_str),
+            ) {
+                (Some(x), None) => Some(Password::Plain(x.to_string())),
+                (None, Some(x)) => Some(Password::Hash(x.to_string())),
+                (None, None) => None,
+                (Some(_), Some(_)) => {
+                    skip("only one of password and password_hash may be set");
+                    continue;
+                }
+            };
+            let certificate_fingerprint = client
+                .get("certificate_fingerprint")
+                .and_then(Item::as_str)
+                .map(client_cert::normalize_fingerprint);
+            let certificate_san = client
+                .get("certificate_san")
+                .and_then(Item::as_str)
+                .map(str::to_string);
+            if password.is_none() && certificate_fingerprint.is_none() && certificate_san.is_none()
             {
-                if let Some(now) = now {
-                    if now > valid_till {
+                skip("no password, password_hash, certificate_fingerprint or certificate_san");
+                continue;
+            }
+            let Some(username) = client.get("username").and_then(Item::as_str) else {
+                skip("no username");
+                continue;
+            };
+            let totp_key = match client.get("totp_secret").and_then(Item::as_str) {
+                None => None,
+                Some(x) => match totp::decode_secret(x) {
+                    Some(x) if !x.is_empty() => Some(x),
+                    _ => {
+                        skip("invalid TOTP secret");
                         continue;
                     }
+                },
+            };
+            let integer = |key| client.get(key).map(|x| x.as_integer().unwrap_or(-1));
+            let data_quota_bytes = match integer("data_quota_bytes").map(u64::try_from) {
+                None => None,
+                Some(Ok(x)) => Some(x),
+                Some(Err(_)) => {
+                    skip("invalid data_quota_bytes");
+                    continue;
                 }
-            }
-
-            match source {
-                authentication::Source::ProxyBasic(auth_str) => {
-                    let expected = BASE64_ENGINE.encode(format!("{}:{}", username, password));
-                    if expected == auth_str.as_ref() {
-                        return true;
+            };
+            let data_quota_period_days = match integer("data_quota_period_days") {
+                None => None,
+                Some(x) if (1..=DataQuota::MAX_PERIOD_DAYS as i64).contains(&x) => Some(x as u32),
+                Some(_) => {
+                    skip("invalid data_quota_period_days");
+                    continue;
+                }
+            };
+            let strings = |key| match client.get(key) {
+                None => Some(vec![]),
+                Some(x) => x
+                    .as_array()?
+                    .iter()
+                    .map(|x| x.as_str().map(str::to_string))
+                    .collect(),
+            };
+            let destination_acl = match (
+                strings("allowed_destinations"),
+                strings("blocked_destinations"),
+            ) {
+                (Some(allowed), Some(blocked)) if allowed.is_empty() && blocked.is_empty() => None,
+                (Some(allowed), Some(blocked)) => match DestinationAcl::new(&allowed, &blocked) {
+                    Ok(x) => Some(Arc::new(x)),
+                    Err(e) => {
+                        skip(&e);
+                        continue;
                     }
+                },
+                _ => {
+                    skip("destinations are not an array of strings");
+                    continue;
                 }
-                authentication::Source::Sni(creds) => {
-                    if creds.as_ref() == username {
-                        return true;
+            };
+
+            result
+                .entry(username.to_string())
+                .or_default()
+                .push(Client {
+                    password,
+                    certificate_fingerprint,
+                    certificate_san,
+                    totp_key,
+                    valid_till: client
+                        .get("valid_till")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| u64::try_from(x).ok()),
+                    tier: client
+                        .get("tier")
+                        .and_then(Item::as_str)
+                        .map(str::to_string),
+                    max_connections: client
+                        .get("max_connections")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| usize::try_from(x).ok()),
+                    max_streams: client
+                        .get("max_streams")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| usize::try_from(x).ok()),
+                    data_quota: DataQuota::from_attributes(
+                        data_quota_bytes,
+                        data_quota_period_days,
+                    ),
+                    profile: client
+                        .get("profile")
+                        .and_then(Item::as_str)
+                        .map(str::to_string),
+                    destination_acl,
+                });
+        }
+
+        (result, problems)
+    }
+
+    /// Get the numbers of the lines the `[[client]]` entries start at
+    fn entry_lines(content: &str) -> Vec<usize> {
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, x)| {
+                x.chars()
+                    .filter(|x| !x.is_whitespace())
+                    .collect::<String>()
+                    .starts_with("[[client]]")
+            })
+            .map(|(i, _)| i + 1)
+            .collect()
+    }
+
+    fn is_valid(client: &Client, now: Option<u64>) -> bool {
+        client
+            .valid_till
+            .is_none_or(|till| now.is_none_or(|now| now <= till))
+    }
+
+    /// Find the entry the credentials pass with, returning its username and its index
+    /// among the entries of the username
+    fn find_client<'a>(
+        clients: &'a HashMap<String, Vec<Client>>,
+        source: &authentication::Source<'_>,
+        now: Option<u64>,
+        totp: Totp<'_>,
+    ) -> Option<(&'a str, usize)> {
+        let find = |username: &str, f: &dyn Fn(&Client) -> bool| {
+            let (username, entries) = clients.get_key_value(username)?;
+            entries
+                .iter()
+                .position(|x| Self::is_valid(x, now) && f(x))
+                .map(|i| (username.as_str(), i))
+        };
+
+        match source {
+            authentication::Source::ProxyBasic(auth_str) => {
+                let credentials = BASE64_ENGINE
+                    .decode(auth_str.as_ref())
+                    .ok()
+                    .and_then(|x| String::from_utf8(x).ok())?;
+                // A user-id containing a colon is invalid (RFC 7617)
+                let (username, password) = credentials.split_once(':')?;
+                let step = Cell::new(None);
+                let found = find(username, &|x| {
+                    step.set(None);
+                    let password = match (&x.totp_key, Self::split_totp_code(password), totp) {
+                        (None, ..) => password,
+                        (Some(_), None, _) => return false,
+                        (Some(_), Some((password, _)), Totp::Skip) => password,
+                        (Some(key), Some((password, code)), Totp::Verify(_)) => {
+                            match totp::verify_step(key, code, now.unwrap_or_default()) {
+                                Some(x) => {
+                                    step.set(Some(x));
+                                    password
+                                }
+                                None => return false,
+                            }
+                        }
+                    };
+                    Self::verify_password(username, password, x)
+                })?;
+                if let (Some(step), Totp::Verify(steps)) = (step.get(), totp) {
+                    // A code is accepted once, so that the intercepted credentials
+                    // can't be replayed
+                    let mut steps = steps.lock().unwrap();
+                    if steps.get(username).is_some_and(|x| *x >= step) {
+                        return None;
                     }
+                    steps.insert(username.to_string(), step);
                 }
+                Some(found)
+            }
+            // The SNI does not carry the one-time codes
+            authentication::Source::Sni(creds) => {
+                find(creds, &|x| x.password.is_some() && x.totp_key.is_none())
+            }
+            authentication::Source::ProxyDigest(x) => {
+                let credentials = digest::Credentials::parse(x)?;
+                // A digest is computed over the password, so it is verified against
+                // the plain text ones only, and it does not carry the one-time codes
+                find(&credentials.username, &|x| {
+                    x.totp_key.is_none()
+                        && match &x.password {
+                            Some(Password::Plain(password)) => credentials.verify(password),
+                            _ => false,
+                        }
+                })
+            }
+            authentication::Source::ProxyBearer(_) => None,
+            authentication::Source::ClientCert(_) => {
+                let certificate = source.client_cert()?;
+                let fingerprint = client_cert::fingerprint(certificate);
+                let names = client_cert::subject_alt_names(certificate);
+                clients.iter().find_map(|(username, entries)| {
+                    entries
+                        .iter()
+                        .position(|x| {
+                            Self::is_valid(x, now)
+                                && (x.certificate_fingerprint.as_ref() == Some(&fingerprint)
+                                    || x.certificate_san
+                                        .as_ref()
+                                        .is_some_and(|x| names.contains(x)))
+                        })
+                        .map(|i| (username.as_str(), i))
+                })
             }
         }
+    }
+
+    /// Get the entry the credentials have passed with. The credentials which have not
+    /// passed yet, e.g., the ones redeemed from a reconnect token after a restart,
+    /// are verified except for the one-time code.
+    fn resolve<'a>(
+        cache: &'a Cache,
+        source: &authentication::Source<'_>,
+        now: Option<u64>,
+    ) -> Option<&'a Client> {
+        let key = caching::key(source);
+        let passed = cache.passed.lock().unwrap().get(&key).cloned();
+        let (username, i) = match passed {
+            Some(x) => x,
+            None => {
+                let (username, i) = Self::find_client(&cache.clients, source, now, Totp::Skip)?;
+                Self::remember(cache, key, username, i);
+                (username.to_string(), i)
+            }
+        };
+        cache
+            .clients
+            .get(&username)?
+            .get(i)
+            .filter(|x| Self::is_valid(x, now))
+    }
+
+    fn remember(cache: &Cache, key: caching::Key, username: &str, index: usize) {
+        let mut passed = cache.passed.lock().unwrap();
+        if passed.len() >= MAX_PASSED_ENTRIES {
+            passed.clear();
+        }
+        passed.insert(key, (username.to_string(), index));
+    }
 
-        false
+    /// Split the one-time code appended to the password
+    fn split_totp_code(x: &str) -> Option<(&str, &str)> {
+        let at = x.len().checked_sub(totp::DIGITS)?;
+        x.is_char_boundary(at).then(|| x.split_at(at))
+    }
+
+    fn verify_password(username: &str, password: &str, client: &Client) -> bool {
+        match &client.password {
+            None => false,
+            Some(Password::Plain(expected)) => expected == password,
+            Some(Password::Hash(hash)) => match password_hash::verify(password, hash) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Unusable password hash of {}: {}", username, e);
+                    false
+                }
+            },
+        }
     }
 }
 
@@ -78,21 +519,428 @@ impl Authenticator for FileBasedAuthenticator {
         source: &authentication::Source<'_>,
         _log_id: &log_utils::IdChain<u64>,
     ) -> authentication::Status {
-        let content = match std::fs::read_to_string(&self.credentials_file_path) {
-            Ok(x) => x,
-            Err(_) => return authentication::Status::Reject,
-        };
-
-        let doc: Document = match content.parse() {
-            Ok(x) => x,
-            Err(_) => return authentication::Status::Reject,
-        };
+        let now = Self::now_unix_ts();
+        let totp = Totp::Verify(&self.totp_steps);
+        let passed = self.with_cache(|x| match Self::find_client(&x.clients, source, now, totp) {
+            Some((username, i)) => {
+                Self::remember(x, caching::key(source), username, i);
+                true
+            }
+            None => false,
+        });
+        if passed {
+            authentication::Status::Pass
+        } else {
+            authentication::Status::Reject
+        }
+    }
 
+    fn revalidate(
+        &self,
+        source: &authentication::Source<'_>,
+        _log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
         let now = Self::now_unix_ts();
-        if Self::is_valid_client(&doc, source, now) {
+        if self.with_cache(|x| Self::resolve(x, source, now).is_some()) {
             authentication::Status::Pass
         } else {
             authentication::Status::Reject
         }
     }
+
+    fn tier(&self, source: &authentication::Source<'_>) -> Option<String> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.tier.clone())
+    }
+
+    fn max_connections(&self, source: &authentication::Source<'_>) -> Option<usize> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.max_connections)
+    }
+
+    fn max_streams(&self, source: &authentication::Source<'_>) -> Option<usize> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.max_streams)
+    }
+
+    fn data_quota(&self, source: &authentication::Source<'_>) -> Option<DataQuota> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.data_quota)
+    }
+
+    fn profile(&self, source: &authentication::Source<'_>) -> Option<String> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.profile.clone())
+    }
+
+    fn destination_acl(&self, source: &authentication::Source<'_>) -> Option<Arc<DestinationAcl>> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.destination_acl.clone())
+    }
+
+    fn valid_till(&self, source: &authentication::Source<'_>) -> Option<u64> {
+        let now = Self::now_unix_ts();
+        self.with_cache(|x| Self::resolve(x, source, now)?.valid_till)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.with_clients(|_| ());
+        self.cache.read().unwrap().problems.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticate(path: &str, username: &str, password: &str) -> authentication::Status {
+        let source = authentication::Source::ProxyBasic(
+            BASE64_ENGINE
+                .encode(format!("{}:{}", username, password))
+                .into(),
+        );
+        FileBasedAuthenticator::new(path.to_string())
+            .authenticate(&source, &log_utils::IdChain::empty())
+    }
+
+    #[test]
+    fn verifies_password_hashes() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+[[client]]
+username = "alice"
+password_hash = "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"
+
+[[client]]
+username = "bob"
+password = "Hello world!"
+"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(authentication::Status::Pass == authenticate(path, "alice", "Hello world!"));
+        assert!(authentication::Status::Reject == authenticate(path, "alice", "Hello world?"));
+        assert!(authentication::Status::Reject == authenticate(path, "alice", ""));
+        assert!(authentication::Status::Pass == authenticate(path, "bob", "Hello world!"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verifies_digests() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-digest-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+[[client]]
+username = "alice"
+password_hash = "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"
+
+[[client]]
+username = "bob"
+password = "Hello world!"
+"#,
+        )
+        .unwrap();
+        let authenticator = FileBasedAuthenticator::new(path.to_str().unwrap().to_string());
+        let authenticate = |username: &str, password: &str| {
+            let sha256 =
+                |x: String| hex::encode(ring::digest::digest(&ring::digest::SHA256, x.as_bytes()));
+            let ha1 = sha256(format!("{}:vpn:{}", username, password));
+            let ha2 = sha256("CONNECT:example.org:443".to_string());
+            let response = sha256(format!("{}:n:00000001:c:auth:{}", ha1, ha2));
+            let source = authentication::Source::ProxyDigest(
+                format!(
+                    r#"username="{}", realm="vpn", nonce="n", uri="example.org:443", algorithm=SHA-256, qop=auth, nc=00000001, cnonce="c", response="{}""#,
+                    username, response
+                )
+                .into(),
+            );
+            authenticator.authenticate(&source, &log_utils::IdChain::empty())
+        };
+
+        assert!(authentication::Status::Pass == authenticate("bob", "Hello world!"));
+        assert!(authentication::Status::Reject == authenticate("bob", "Hello world?"));
+        // The hashed passwords can't verify the digests
+        assert!(authentication::Status::Reject == authenticate("alice", "Hello world!"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reloads_changed_file() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-reload-{}.toml",
+            std::process::id()
+        ));
+        let authenticator = FileBasedAuthenticator::new(path.to_str().unwrap().to_string());
+        let authenticate = |username: &str, password: &str| {
+            let source = authentication::Source::ProxyBasic(
+                BASE64_ENGINE
+                    .encode(format!("{}:{}", username, password))
+                    .into(),
+            );
+            authenticator.authenticate(&source, &log_utils::IdChain::empty())
+        };
+
+        std::fs::write(
+            &path,
+            "[[client]]\nusername = \"alice\"\npassword = \"secret\"\ntier = \"paid\"\nprofile = \"staff\"\nvalid_till = 4102444800\n",
+        )
+        .unwrap();
+        assert!(authentication::Status::Pass == authenticate("alice", "secret"));
+        assert_eq!(
+            Some("paid".to_string()),
+            authenticator.tier(&authentication::Source::Sni("alice".into()))
+        );
+        assert_eq!(
+            Some("staff".to_string()),
+            authenticator.profile(&authentication::Source::Sni("alice".into()))
+        );
+        assert_eq!(
+            Some(4102444800),
+            authenticator.valid_till(&authentication::Source::Sni("alice".into()))
+        );
+
+        std::fs::write(
+            &path,
+            "[[client]]\nusername = \"bob\"\npassword = \"another secret\"\n",
+        )
+        .unwrap();
+        assert!(authentication::Status::Reject == authenticate("alice", "secret"));
+        assert!(authentication::Status::Pass == authenticate("bob", "another secret"));
+
+        // A partially written file does not revoke the clients
+        std::fs::write(&path, "[[client]]\nusername = \"bob").unwrap();
+        assert!(authentication::Status::Pass == authenticate("bob", "another secret"));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(authentication::Status::Reject == authenticate("bob", "another secret"));
+    }
+
+    #[test]
+    fn reports_problems() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-problems-{}.toml",
+            std::process::id()
+        ));
+        let authenticator = FileBasedAuthenticator::new(path.to_str().unwrap().to_string());
+        assert!(!authenticator.is_healthy());
+        assert!(authenticator
+            .validate()
+            .unwrap_err()
+            .starts_with("Failed to read"));
+
+        std::fs::write(&path, "[[client]\n").unwrap();
+        assert!(authenticator
+            .validate()
+            .unwrap_err()
+            .starts_with("Failed to parse"));
+
+        std::fs::write(
+            &path,
+            r#"
+[[client]]
+username = "alice"
+password = "secret"
+
+[[client]]
+password = "no username"
+
+[[client]]
+username = "carol"
+password = "secret"
+password_hash = "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"
+"#,
+        )
+        .unwrap();
+        assert!(!authenticator.is_healthy());
+        // The valid entries are served regardless
+        assert_eq!(
+            Ok((
+                1,
+                vec![
+                    "Client #2 at line 6: no username, skipping".to_string(),
+                    "Client #3 at line 9: only one of password and password_hash may be set, \
+                    skipping"
+                        .to_string(),
+                ]
+            )),
+            authenticator.validate()
+        );
+
+        std::fs::write(
+            &path,
+            "[[client]]\nusername = \"alice\"\npassword = \"secret\"\n",
+        )
+        .unwrap();
+        assert!(authenticator.is_healthy());
+        assert_eq!(Ok((1, vec![])), authenticator.validate());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restricts_destinations() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-acl-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+[[client]]
+username = "alice"
+password = "secret"
+allowed_destinations = ["*.example.org:443"]
+blocked_destinations = ["admin.example.org"]
+
+[[client]]
+username = "bob"
+password = "secret"
+blocked_destinations = ["example.org:0-"]
+"#,
+        )
+        .unwrap();
+        let authenticator = FileBasedAuthenticator::new(path.to_str().unwrap().to_string());
+        assert_eq!(
+            vec!["Client #2 at line 8: Invalid destination example.org:0-, skipping"],
+            authenticator.validate().unwrap().1
+        );
+
+        let acl = authenticator
+            .destination_acl(&authentication::Source::Sni("alice".into()))
+            .unwrap();
+        let address = "198.51.100.1".parse().unwrap();
+        assert!(acl.is_allowed(Some("www.example.org"), address, 443));
+        assert!(!acl.is_allowed(Some("admin.example.org"), address, 443));
+        assert!(!acl.is_allowed(None, address, 443));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn authorizes_client_certificates() {
+        let certificate = |name: &str| {
+            let params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+            let key = rcgen::KeyPair::generate().unwrap();
+            params.self_signed(&key).unwrap().der().to_vec()
+        };
+        let alice = certificate("alice.example.org");
+        let bob = certificate("bob.example.org");
+        let carol = certificate("carol.example.org");
+
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-cert-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[[client]]
+username = "alice"
+certificate_fingerprint = "{}"
+tier = "paid"
+
+[[client]]
+username = "bob"
+certificate_san = "bob.example.org"
+"#,
+                client_cert::fingerprint(&alice).to_uppercase()
+            ),
+        )
+        .unwrap();
+        let authenticator = FileBasedAuthenticator::new(path.to_str().unwrap().to_string());
+        let source = |x: &Vec<u8>| authentication::Source::ClientCert(vec![x.clone()].into());
+        let authenticate = |x| authenticator.authenticate(&source(x), &log_utils::IdChain::empty());
+
+        assert!(authentication::Status::Pass == authenticate(&alice));
+        assert!(authentication::Status::Pass == authenticate(&bob));
+        assert!(authentication::Status::Reject == authenticate(&carol));
+        assert_eq!(
+            Some("paid".to_string()),
+            authenticator.tier(&source(&alice))
+        );
+        // The entries without a password are not usable through SNI
+        assert!(
+            authentication::Status::Reject
+                == authenticator.authenticate(
+                    &authentication::Source::Sni("alice".into()),
+                    &log_utils::IdChain::empty()
+                )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requires_totp_codes() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-credentials-totp-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+[[client]]
+username = "alice"
+password = "secret"
+totp_secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+profile = "limited"
+"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let basic = |password: &str| {
+            authentication::Source::ProxyBasic(
+                BASE64_ENGINE.encode(format!("alice:{}", password)).into(),
+            )
+        };
+        let steps = Mutex::default();
+        let clients = FileBasedAuthenticator::new(path.to_string()).with_clients(|x| {
+            let find = |password: &str, now| {
+                let source = basic(password);
+                FileBasedAuthenticator::find_client(x, &source, Some(now), Totp::Verify(&steps))
+                    .is_some()
+            };
+            [
+                find("secret081804", 1111111109),
+                // A code is accepted once
+                find("secret081804", 1111111109 + 30),
+                find("secret081804", 1111111109 + 3600),
+                find("secret", 1111111109),
+                find("wrong081804", 1111111109),
+            ]
+        });
+        assert_eq!([true, false, false, false, false], clients);
+
+        // The client is revalidated and its attributes are looked up after the code expires
+        let authenticator = FileBasedAuthenticator::new(path.to_string());
+        let source = basic("secret081804");
+        let passed = authenticator.with_cache(|x| {
+            let totp = Totp::Verify(&authenticator.totp_steps);
+            let (username, i) =
+                FileBasedAuthenticator::find_client(&x.clients, &source, Some(1111111109), totp)
+                    .unwrap();
+            FileBasedAuthenticator::remember(x, caching::key(&source), username, i);
+            FileBasedAuthenticator::resolve(x, &source, Some(1111111109 + 3600)).is_some()
+        });
+        assert!(passed);
+        assert!(
+            authentication::Status::Pass
+                == authenticator.revalidate(&source, &log_utils::IdChain::empty())
+        );
+        assert_eq!(Some("limited".to_string()), authenticator.profile(&source));
+        assert!(
+            authentication::Status::Reject
+                == authenticator.revalidate(&basic("wrong081804"), &log_utils::IdChain::empty())
+        );
+        assert!(authentication::Status::Reject == authenticate(path, "alice", "secret"));
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,553 @@
+use crate::authentication::Authenticator;
+use crate::settings::IntrospectionSettings;
+use crate::upstream_tls::UpstreamTls;
+use crate::{authentication, log_id, log_utils};
+use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use rustls::{ClientConfig, RootCertStore};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The responses are small JSON objects, so anything larger is not an introspection response
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// The [`Authenticator`] implementation which checks the OAuth 2.0 access tokens against
+/// the introspection endpoint of the authorization server
+/// ([RFC 7662](https://www.rfc-editor.org/rfc/rfc7662)), authenticating to it with
+/// the client credentials. The tokens are opaque to the endpoint, so they need not be JWTs.
+/// Is only able to authenticate a client using the Proxy bearer authorization.
+/// An active token is cached for a while, so each token costs one request over
+/// the pooled server connections per [`IntrospectionSettings::max_cache_ttl`],
+/// unless the cache is full. The client is named by the owner of the token.
+pub struct IntrospectionAuthenticator {
+    settings: IntrospectionSettings,
+    url: http::Uri,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    /// Drives the server connections, as the serving runtime may have no spare thread
+    /// to run a request while the calling one waits for it
+    runtime: Option<tokio::runtime::Runtime>,
+    /// The active tokens keyed by their SHA-256 digests, so that the tokens themselves
+    /// are not kept in memory
+    cache: Mutex<HashMap<Vec<u8>, Entry>>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    /// The UNIX time the entry is dropped at
+    cached_till: u64,
+    /// The UNIX time the token expires at
+    valid_till: Option<u64>,
+    /// The username of the token owner, or its subject
+    identity: Option<String>,
+}
+
+/// The members of an introspection response the tokens are checked with
+#[derive(Deserialize)]
+struct Introspection {
+    active: bool,
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(default)]
+    aud: Option<Audience>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    sub: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl IntrospectionAuthenticator {
+    pub fn new(settings: IntrospectionSettings) -> io::Result<Self> {
+        let url = settings
+            .url
+            .parse::<http::Uri>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        if url.host().is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "URL has no host"));
+        }
+
+        let tls = match url.scheme_str() {
+            // The certificate is checked against the URL host unless told otherwise
+            Some("https") => UpstreamTls::new(
+                &settings.tls.clone().unwrap_or_default(),
+                "token introspection endpoint",
+            )?
+            .client_config(),
+            // A plain text URL makes no TLS connections
+            _ => Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(RootCertStore::empty())
+                    .with_no_client_auth(),
+            ),
+        };
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        connector.set_connect_timeout(Some(settings.timeout));
+        connector.set_nodelay(true);
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(ClientConfig::clone(&tls))
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(connector);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("introspection")
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            settings,
+            url,
+            client: hyper::Client::builder().build(connector),
+            runtime: Some(runtime),
+            cache: Default::default(),
+        })
+    }
+
+    /// Ask the server about the token
+    fn introspect(&self, token: &str) -> io::Result<Introspection> {
+        let credentials = BASE64_ENGINE.encode(format!(
+            "{}:{}",
+            form_encode(&self.settings.client_id),
+            form_encode(&self.settings.client_secret)
+        ));
+        let request = http::Request::post(self.url.clone())
+            .header(
+                http::header::AUTHORIZATION,
+                format!("Basic {}", credentials),
+            )
+            .header(http::header::ACCEPT, "application/json")
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(hyper::Body::from(format!(
+                "token={}&token_type_hint=access_token",
+                form_encode(token)
+            )))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        let exchange = {
+            let client = self.client.clone();
+            async move {
+                let response = client
+                    .request(request)
+                    .await
+                    .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+                let status = response.status();
+                let body = read_body(response.into_body()).await?;
+                parse_response(status, &body)
+            }
+        };
+        let timeout = self.settings.timeout;
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.runtime.as_ref().unwrap().spawn(async move {
+            let _ = tx.send(tokio::time::timeout(timeout, exchange).await);
+        });
+        match rx.recv() {
+            Ok(Ok(x)) => x,
+            Ok(Err(_)) => Err(io::Error::new(ErrorKind::TimedOut, "Request timed out")),
+            Err(_) => Err(io::Error::new(ErrorKind::Other, "Request was dropped")),
+        }
+    }
+
+    /// Check the introspected token is good for a tunnel
+    fn check(&self, token: &Introspection, now: u64) -> Result<(), &'static str> {
+        if !token.active {
+            return Err("inactive token");
+        }
+        if token.exp.is_some_and(|x| x <= now) {
+            return Err("expired token");
+        }
+        if let Some(audience) = &self.settings.audience {
+            let matches = match &token.aud {
+                None => false,
+                Some(Audience::One(x)) => x == audience,
+                Some(Audience::Many(x)) => x.contains(audience),
+            };
+            if !matches {
+                return Err("token issued for another audience");
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the entry of the active token from the cache, or ask the server about it
+    fn lookup(&self, token: &str, log_id: &log_utils::IdChain<u64>) -> Option<Entry> {
+        if token.is_empty() {
+            return None;
+        }
+        if let Some(x) = self.cached(token, Entry::clone) {
+            return Some(x);
+        }
+
+        match authentication::block_in_place(|| self.introspect(token)) {
+            Ok(x) => {
+                let now = unix_now();
+                match self.check(&x, now) {
+                    Ok(()) => Some(self.remember(token, x, now)),
+                    Err(e) => {
+                        log_id!(debug, log_id, "Introspection: rejected {}", e);
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                log_id!(warn, log_id, "Introspection: failed to check token: {}", e);
+                None
+            }
+        }
+    }
+
+    fn cached<T>(&self, token: &str, f: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let now = unix_now();
+        self.cache
+            .lock()
+            .unwrap()
+            .get(&digest(token))
+            .filter(|x| x.cached_till > now)
+            .map(f)
+    }
+
+    fn remember(&self, token: &str, introspection: Introspection, now: u64) -> Entry {
+        let max_ttl = now + self.settings.max_cache_ttl.as_secs();
+        let entry = Entry {
+            cached_till: introspection.exp.map_or(max_ttl, |x| x.min(max_ttl)),
+            valid_till: introspection.exp,
+            identity: introspection.username.or(introspection.sub),
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.settings.cache_size {
+            cache.retain(|_, x| x.cached_till > now);
+            if cache.len() >= self.settings.cache_size {
+                return entry;
+            }
+        }
+        cache.insert(digest(token), entry.clone());
+        entry
+    }
+
+    /// The entry of the active token of the source
+    fn entry(&self, source: &authentication::Source<'_>) -> Option<Entry> {
+        match source {
+            authentication::Source::ProxyBearer(x) => self.lookup(x, &log_utils::IdChain::empty()),
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBasic(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+}
+
+impl Drop for IntrospectionAuthenticator {
+    fn drop(&mut self) {
+        // Dropping a runtime waits for its threads, which is not allowed on the serving ones
+        if let Some(x) = self.runtime.take() {
+            x.shutdown_background();
+        }
+    }
+}
+
+impl Authenticator for IntrospectionAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let token = match source {
+            authentication::Source::ProxyBearer(x) => x.as_ref(),
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBasic(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => return authentication::Status::Reject,
+        };
+
+        match self.lookup(token, log_id) {
+            Some(_) => authentication::Status::Pass,
+            None => authentication::Status::Reject,
+        }
+    }
+
+    /// The owner of the active token
+    fn username(&self, source: &authentication::Source<'_>) -> Option<String> {
+        self.entry(source)?.identity
+    }
+
+    /// The expiration time of the active token
+    fn valid_till(&self, source: &authentication::Source<'_>) -> Option<u64> {
+        self.entry(source)?.valid_till
+    }
+
+    fn invalidate(&self, identity: Option<&str>) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let n = cache.len();
+        match identity {
+            None => cache.clear(),
+            Some(identity) => cache.retain(|_, x| x.identity.as_deref() != Some(identity)),
+        }
+        n - cache.len()
+    }
+}
+
+/// Read the body of the response unless it is too large
+async fn read_body(mut body: hyper::Body) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    while let Some(x) = body.data().await {
+        content.extend_from_slice(&x.map_err(|e| io::Error::new(ErrorKind::Other, e))?);
+        if content.len() > MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Response is too large",
+            ));
+        }
+    }
+    Ok(content)
+}
+
+fn parse_response(status: http::StatusCode, body: &[u8]) -> io::Result<Introspection> {
+    if status != http::StatusCode::OK {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Unexpected response status: {}", status),
+        ));
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Malformed introspection response: {}", e),
+        )
+    })
+}
+
+/// Encode the value in the `application/x-www-form-urlencoded` form
+fn form_encode(x: &str) -> String {
+    x.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn digest(token: &str) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const CLIENT_ID: &str = "trusttunnel";
+    const CLIENT_SECRET: &str = "s3cret&more";
+
+    #[derive(Default)]
+    struct Counters {
+        connections: AtomicUsize,
+        requests: AtomicUsize,
+    }
+
+    /// Answers for the tokens: `good` is active for an hour, `other` is issued
+    /// for another audience, `stale` has expired, anything else is inactive
+    fn run_server(counters: Arc<Counters>) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counters.connections.fetch_add(1, Ordering::Relaxed);
+                let counters = counters.clone();
+                let mut stream = BufReader::new(stream.unwrap());
+                thread::spawn(move || {
+                    while serve(&mut stream).is_ok() {
+                        counters.requests.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    fn serve(stream: &mut BufReader<TcpStream>) -> io::Result<()> {
+        let mut authorization = None;
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(": ") {
+                Some((x, y)) if x.eq_ignore_ascii_case("authorization") => {
+                    authorization = Some(y.to_string())
+                }
+                Some((x, y)) if x.eq_ignore_ascii_case("content-length") => {
+                    length = y.parse().unwrap()
+                }
+                _ => (),
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body)?;
+        let body = String::from_utf8(body).unwrap();
+
+        let expected = BASE64_ENGINE.encode(format!("{}:s3cret%26more", CLIENT_ID));
+        let exp = unix_now() + 3600;
+        let (status, content) = match body.strip_suffix("&token_type_hint=access_token") {
+            _ if authorization != Some(format!("Basic {}", expected)) => (
+                "401 Unauthorized",
+                r#"{"error":"invalid_client"}"#.to_string(),
+            ),
+            Some("token=good") => (
+                "200 OK",
+                format!(
+                    r#"{{"active":true,"exp":{},"aud":["account","vpn"],"username":"alice"}}"#,
+                    exp
+                ),
+            ),
+            Some("token=other") => (
+                "200 OK",
+                format!(r#"{{"active":true,"exp":{},"aud":"account"}}"#, exp),
+            ),
+            Some("token=stale") => ("200 OK", r#"{"active":true,"exp":1}"#.to_string()),
+            _ => ("200 OK", r#"{"active":false}"#.to_string()),
+        };
+        write!(
+            stream.get_mut(),
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            content.len(),
+            content
+        )
+    }
+
+    fn settings(address: SocketAddr) -> IntrospectionSettings {
+        IntrospectionSettings::builder(
+            format!("http://{}/token/introspect", address).as_str(),
+            CLIENT_ID,
+            CLIENT_SECRET,
+        )
+        .audience("vpn")
+        .build()
+        .unwrap()
+    }
+
+    fn bearer(token: &str) -> authentication::Source<'_> {
+        authentication::Source::ProxyBearer(token.into())
+    }
+
+    fn check(authenticator: &IntrospectionAuthenticator, token: &str) -> bool {
+        authenticator.authenticate(&bearer(token), &log_utils::IdChain::empty())
+            == authentication::Status::Pass
+    }
+
+    #[test]
+    fn checks_tokens() {
+        let counters = Arc::new(Counters::default());
+        let authenticator =
+            IntrospectionAuthenticator::new(settings(run_server(counters.clone()))).unwrap();
+        let requests = || counters.requests.load(Ordering::Relaxed);
+
+        assert!(check(&authenticator, "good"));
+        assert!(!check(&authenticator, "other"));
+        assert!(!check(&authenticator, "stale"));
+        assert!(!check(&authenticator, "revoked"));
+        assert!(!check(&authenticator, ""));
+        assert_eq!(4, requests());
+        // The server connections are reused
+        assert!(counters.connections.load(Ordering::Relaxed) < requests());
+
+        // The active token is cached
+        assert!(check(&authenticator, "good"));
+        assert_eq!(4, requests());
+        let valid_till = authenticator.valid_till(&bearer("good")).unwrap();
+        assert!(valid_till > unix_now() + 3000);
+        assert_eq!(None, authenticator.valid_till(&bearer("other")));
+        // The client is named by the owner of the token
+        assert_eq!(
+            Some("alice".to_string()),
+            authenticator.username(&bearer("good"))
+        );
+        assert_eq!(None, authenticator.username(&bearer("revoked")));
+        assert_eq!(5, requests());
+
+        assert_eq!(0, authenticator.invalidate(Some("bob")));
+        assert_eq!(1, authenticator.invalidate(Some("alice")));
+        assert!(check(&authenticator, "good"));
+        assert_eq!(6, requests());
+    }
+
+    #[test]
+    fn cache_limits() {
+        let counters = Arc::new(Counters::default());
+        let address = run_server(counters.clone());
+        let mut settings = settings(address);
+        settings.cache_size = 0;
+        let authenticator = IntrospectionAuthenticator::new(settings.clone()).unwrap();
+        assert!(check(&authenticator, "good"));
+        assert!(check(&authenticator, "good"));
+        assert_eq!(2, counters.requests.load(Ordering::Relaxed));
+        // The identity is known without the cache too
+        assert_eq!(
+            Some("alice".to_string()),
+            authenticator.username(&bearer("good"))
+        );
+
+        // The token is cached for a short while by default, though it expires in an hour
+        settings.cache_size = 1;
+        let authenticator = IntrospectionAuthenticator::new(settings).unwrap();
+        assert!(check(&authenticator, "good"));
+        let entry = authenticator.cached("good", |x| (x.cached_till, x.valid_till));
+        let (cached_till, valid_till) = entry.unwrap();
+        assert!(cached_till <= unix_now() + 60 && cached_till < valid_till.unwrap());
+    }
+
+    #[test]
+    fn rejects_wrong_client_credentials() {
+        let address = run_server(Default::default());
+        let mut settings = settings(address);
+        settings.client_secret = "wrong".into();
+        let authenticator = IntrospectionAuthenticator::new(settings).unwrap();
+
+        let error = authenticator.introspect("good").err().unwrap();
+        assert!(error.to_string().contains("401"), "{}", error);
+    }
+
+    #[test]
+    fn encodes_form_values() {
+        assert_eq!("a-b_c.d~e", form_encode("a-b_c.d~e"));
+        assert_eq!("a%2Bb%2F%3D%20%26", form_encode("a+b/= &"));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::authentication::Authenticator;
+use crate::settings::JwtSettings;
+use crate::{authentication, log_id, log_utils};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+/// The [`Authenticator`] implementation which verifies the
+/// [JSON Web Tokens](https://datatracker.ietf.org/doc/html/rfc7519) presented by the clients.
+/// A token is accepted in the Proxy bearer authorization and in place of the SNI credentials.
+/// The signature, the validity period and the audience of a token are checked against
+/// the configured secret and the keys of the JSON Web Key Set, which is read once on start.
+pub struct JwtAuthenticator {
+    settings: JwtSettings,
+    secret: Option<DecodingKey>,
+    keys: Vec<Key>,
+}
+
+/// A public key of the JSON Web Key Set
+struct Key {
+    id: Option<String>,
+    /// The only algorithm the key may be used with, if restricted by the set
+    algorithm: Option<Algorithm>,
+    key: DecodingKey,
+}
+
+#[derive(Deserialize)]
+struct KeySet {
+    keys: Vec<Value>,
+}
+
+/// The claims of a verified token the clients are identified by
+#[derive(Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    jti: Option<String>,
+    exp: u64,
+}
+
+impl JwtAuthenticator {
+    pub fn new(settings: JwtSettings) -> io::Result<Self> {
+        let keys = match &settings.jwks_file {
+            None => vec![],
+            Some(path) => parse_key_set(&std::fs::read_to_string(path)?)?,
+        };
+        Ok(Self {
+            secret: settings
+                .secret
+                .as_ref()
+                .map(|x| DecodingKey::from_secret(x.as_bytes())),
+            settings,
+            keys,
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|e| format!("Malformed token: {}", e))?;
+        let validation = self.validation(header.alg);
+        let decode = |key: &DecodingKey| {
+            jsonwebtoken::decode::<Claims>(token, key, &validation)
+                .map(|x| x.claims)
+                .map_err(|e| e.to_string())
+        };
+
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            // The public keys are never used as the HMAC secrets
+            return match &self.secret {
+                None => Err("No secret of HMAC signatures".into()),
+                Some(x) => decode(x),
+            };
+        }
+
+        let mut result = Err(format!("No key of {:?} signatures", header.alg));
+        for key in self
+            .keys
+            .iter()
+            .filter(|x| header.kid.is_none() || x.id == header.kid)
+            .filter(|x| x.algorithm.is_none_or(|a| a == header.alg))
+        {
+            result = decode(&key.key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.settings.leeway.as_secs();
+        validation.validate_nbf = true;
+        let mut required = vec!["exp"];
+        match &self.settings.audience {
+            None => validation.validate_aud = false,
+            Some(x) => {
+                validation.set_audience(&[x]);
+                required.push("aud");
+            }
+        }
+        if let Some(x) = &self.settings.issuer {
+            validation.set_issuer(&[x]);
+            required.push("iss");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    /// The claims of the token of the source in case it is verified
+    fn claims(&self, source: &authentication::Source<'_>) -> Option<Claims> {
+        match source {
+            authentication::Source::ProxyBearer(x) | authentication::Source::Sni(x) => {
+                self.verify(x).ok()
+            }
+            authentication::Source::ProxyBasic(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let token = match source {
+            authentication::Source::ProxyBearer(x) | authentication::Source::Sni(x) => x,
+            authentication::Source::ProxyBasic(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => return authentication::Status::Reject,
+        };
+
+        match self.verify(token) {
+            Ok(_) => authentication::Status::Pass,
+            Err(e) => {
+                log_id!(debug, log_id, "JWT: rejected token: {}", e);
+                authentication::Status::Reject
+            }
+        }
+    }
+
+    /// The subject claim of the token
+    fn username(&self, source: &authentication::Source<'_>) -> Option<String> {
+        self.claims(source)?.sub
+    }
+
+    /// The ID claim of the token
+    fn token_id(&self, source: &authentication::Source<'_>) -> Option<String> {
+        self.claims(source)?.jti
+    }
+
+    /// The expiration time claim of the token
+    fn valid_till(&self, source: &authentication::Source<'_>) -> Option<u64> {
+        self.claims(source).map(|x| x.exp)
+    }
+}
+
+fn parse_key_set(x: &str) -> io::Result<Vec<Key>> {
+    let invalid = |e: String| io::Error::new(ErrorKind::InvalidData, format!("JWKS: {}", e));
+    let set: KeySet = serde_json::from_str(x).map_err(|e| invalid(e.to_string()))?;
+
+    let mut keys = Vec::with_capacity(set.keys.len());
+    for x in set.keys {
+        let jwk: Jwk = match serde_json::from_value(x.clone()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("JWKS: skipping unsupported key: {}: {}", x, e);
+                continue;
+            }
+        };
+        if jwk
+            .common
+            .public_key_use
+            .as_ref()
+            .is_some_and(|x| *x != PublicKeyUse::Signature)
+        {
+            continue;
+        }
+        if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) {
+            warn!("JWKS: skipping symmetric key {:?}", jwk.common.key_id);
+            continue;
+        }
+        let algorithm = match x
+            .get("alg")
+            .and_then(Value::as_str)
+            .map(Algorithm::from_str)
+        {
+            None => None,
+            Some(Ok(x)) => Some(x),
+            Some(Err(e)) => {
+                warn!("JWKS: skipping key of unsupported algorithm: {}: {}", x, e);
+                continue;
+            }
+        };
+        keys.push(Key {
+            key: DecodingKey::from_jwk(&jwk).map_err(|e| invalid(e.to_string()))?,
+            id: jwk.common.key_id,
+            algorithm,
+        });
+    }
+
+    if keys.is_empty() {
+        return Err(invalid("No supported signature keys".into()));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_ENGINE;
+    use base64::Engine;
+    use ring::hmac;
+    use ring::rand::SystemRandom;
+    use ring::signature;
+    use ring::signature::KeyPair;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(header: &str, claims: &str, signer: impl FnOnce(&[u8]) -> Vec<u8>) -> String {
+        let message = format!(
+            "{}.{}",
+            BASE64_ENGINE.encode(header),
+            BASE64_ENGINE.encode(claims)
+        );
+        let signature = signer(message.as_bytes());
+        format!("{}.{}", message, BASE64_ENGINE.encode(signature))
+    }
+
+    fn hs256(claims: &str) -> String {
+        sign(r#"{"alg":"HS256","typ":"JWT"}"#, claims, |x| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+            hmac::sign(&key, x).as_ref().to_vec()
+        })
+    }
+
+    fn authenticator(settings: JwtSettings) -> JwtAuthenticator {
+        JwtAuthenticator::new(settings).unwrap()
+    }
+
+    fn check(a: &JwtAuthenticator, token: &str) -> Result<(), String> {
+        a.verify(token).map(|_| ())
+    }
+
+    fn bearer(token: &str) -> authentication::Source<'static> {
+        authentication::Source::ProxyBearer(token.to_string().into())
+    }
+
+    #[test]
+    fn checks_claims() {
+        let a = authenticator(
+            JwtSettings::builder()
+                .secret(SECRET)
+                .audience("vpn")
+                .issuer("https://id.example.org")
+                .build()
+                .unwrap(),
+        );
+        let now = unix_now();
+        let claims = |exp: u64, aud: &str| {
+            format!(
+                r#"{{"sub":"alice","jti":"token-1","iss":"https://id.example.org","aud":{},"exp":{}}}"#,
+                aud, exp
+            )
+        };
+
+        let token = hs256(&claims(now + 100, r#""vpn""#));
+        assert_eq!(Ok(()), check(&a, &token));
+        assert_eq!(Some("alice".to_string()), a.username(&bearer(&token)));
+        assert_eq!(Some("token-1".to_string()), a.token_id(&bearer(&token)));
+        assert_eq!(Some(now + 100), a.valid_till(&bearer(&token)));
+        assert_eq!(
+            Ok(()),
+            check(&a, &hs256(&claims(now + 100, r#"["x","vpn"]"#)))
+        );
+        // Within the leeway
+        assert_eq!(Ok(()), check(&a, &hs256(&claims(now - 10, r#""vpn""#))));
+
+        for x in [
+            claims(now - 120, r#""vpn""#),
+            claims(now + 100, r#""web""#),
+            r#"{"aud":"vpn","iss":"https://id.example.org"}"#.to_string(),
+            format!(
+                r#"{{"aud":"vpn","iss":"https://evil.example.org","exp":{}}}"#,
+                now + 100
+            ),
+            format!(r#"{{"iss":"https://id.example.org","exp":{}}}"#, now + 100),
+            format!(
+                r#"{{"aud":"vpn","iss":"https://id.example.org","exp":{},"nbf":{}}}"#,
+                now + 1000,
+                now + 100
+            ),
+        ] {
+            let token = hs256(&x);
+            assert!(check(&a, &token).is_err(), "{}", x);
+            assert_eq!(None, a.username(&bearer(&token)), "{}", x);
+        }
+    }
+
+    #[test]
+    fn rejects_forged_tokens() {
+        let a = authenticator(JwtSettings::builder().secret(SECRET).build().unwrap());
+        let claims = format!(r#"{{"sub":"alice","exp":{}}}"#, unix_now() + 100);
+        let token = hs256(&claims);
+        let (message, _) = token.rsplit_once('.').unwrap();
+
+        for x in [
+            sign(r#"{"alg":"none"}"#, &claims, |_| vec![]),
+            sign(r#"{"alg":"HS256"}"#, &claims, |x| {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, b"another secret of the same length");
+                hmac::sign(&key, x).as_ref().to_vec()
+            }),
+            format!("{}.", message),
+            message.to_string(),
+            format!("{}.x", token),
+        ] {
+            assert!(check(&a, &x).is_err(), "{}", x);
+            // The identity of a forged token is not trusted
+            assert_eq!(None, a.username(&bearer(&x)), "{}", x);
+            assert_eq!(None, a.valid_till(&bearer(&x)), "{}", x);
+        }
+    }
+
+    #[test]
+    fn verifies_with_key_set() {
+        let rng = SystemRandom::new();
+        let ec_pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            &rng,
+        )
+        .unwrap();
+        let ec = signature::EcdsaKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            ec_pkcs8.as_ref(),
+            &rng,
+        )
+        .unwrap();
+        let ed = signature::Ed25519KeyPair::from_pkcs8(
+            signature::Ed25519KeyPair::generate_pkcs8(&rng)
+                .unwrap()
+                .as_ref(),
+        )
+        .unwrap();
+        let point = &ec.public_key().as_ref()[1..];
+        let jwks = format!(
+            r#"{{"keys":[
+                {{"kty":"EC","kid":"ec","crv":"P-256","x":"{}","y":"{}"}},
+                {{"kty":"OKP","kid":"ed","crv":"Ed25519","alg":"EdDSA","x":"{}"}},
+                {{"kty":"oct","k":"c2VjcmV0"}}
+            ]}}"#,
+            BASE64_ENGINE.encode(&point[..32]),
+            BASE64_ENGINE.encode(&point[32..]),
+            BASE64_ENGINE.encode(ed.public_key().as_ref()),
+        );
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-jwks-{}.json", std::process::id()));
+        std::fs::write(&path, jwks).unwrap();
+        let a = authenticator(
+            JwtSettings::builder()
+                .jwks_file(path.to_str().unwrap())
+                .build()
+                .unwrap(),
+        );
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(2, a.keys.len());
+
+        let claims = format!(r#"{{"exp":{}}}"#, unix_now() + 100);
+        let es256 = |header| {
+            sign(header, &claims, |x| {
+                ec.sign(&rng, x).unwrap().as_ref().to_vec()
+            })
+        };
+        let eddsa = |header| sign(header, &claims, |x| ed.sign(x).as_ref().to_vec());
+        assert_eq!(Ok(()), check(&a, &es256(r#"{"alg":"ES256","kid":"ec"}"#)));
+        assert_eq!(Ok(()), check(&a, &es256(r#"{"alg":"ES256"}"#)));
+        assert_eq!(Ok(()), check(&a, &eddsa(r#"{"alg":"EdDSA","kid":"ed"}"#)));
+        assert!(check(&a, &es256(r#"{"alg":"ES256","kid":"ed"}"#)).is_err());
+        assert!(check(&a, &eddsa(r#"{"alg":"ES256","kid":"ed"}"#)).is_err());
+        // No secret is configured
+        assert!(check(&a, &hs256(&claims)).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use crate::authentication::Authenticator;
+use crate::settings::LdapSettings;
+use crate::upstream_tls::UpstreamTls;
+use crate::{authentication, log_id, log_utils};
+use ldap3::{LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry, SearchOptions};
+use std::io;
+use std::io::ErrorKind;
+
+const RESULT_SUCCESS: u32 = 0;
+const RESULT_SIZE_LIMIT_EXCEEDED: u32 = 4;
+const RESULT_INVALID_CREDENTIALS: u32 = 49;
+
+/// The [`Authenticator`] implementation which checks the credentials of a client by binding
+/// with them to an LDAP server, like Active Directory.
+/// Is only able to authenticate a client using the Proxy basic authorization.
+/// Each authentication is a blocking exchange with the server over a new connection.
+pub struct LdapAuthenticator {
+    settings: LdapSettings,
+    tls: Option<UpstreamTls>,
+}
+
+#[derive(Debug, PartialEq)]
+enum BindResult {
+    Success,
+    InvalidCredentials,
+}
+
+impl LdapAuthenticator {
+    pub fn new(settings: LdapSettings) -> io::Result<Self> {
+        let tls = settings
+            .tls
+            .as_ref()
+            .map(|x| UpstreamTls::new(x, "LDAP server"))
+            .transpose()?;
+        Ok(Self { settings, tls })
+    }
+
+    fn check(&self, username: &str, password: &str) -> io::Result<BindResult> {
+        let mut connection = self.connect()?;
+        let result = match &self.settings.bind_dn {
+            Some(x) => {
+                let dn = x.replace("{username}", &escape_dn_value(username));
+                self.bind(&mut connection, &dn, password)
+            }
+            None => match self.find_entry(&mut connection, username) {
+                Ok(Some(dn)) => self.bind(&mut connection, &dn, password),
+                Ok(None) => Ok(BindResult::InvalidCredentials),
+                Err(e) => Err(e),
+            },
+        };
+
+        let _ = connection.unbind();
+        result
+    }
+
+    /// Find the DN of the client entry. [`None`] if there is no such entry, or the username
+    /// is ambiguous.
+    fn find_entry(&self, connection: &mut LdapConn, username: &str) -> io::Result<Option<String>> {
+        if let (Some(dn), Some(password)) = (
+            &self.settings.search_bind_dn,
+            &self.settings.search_bind_password,
+        ) {
+            if self.bind(connection, dn, password)? != BindResult::Success {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Service account credentials are rejected",
+                ));
+            }
+        }
+
+        let filter = format!(
+            "({}={})",
+            self.settings.search_attribute,
+            ldap3::ldap_escape(username)
+        );
+        // More than one entry is of no use
+        let options = SearchOptions::new()
+            .sizelimit(2)
+            .timelimit(self.settings.timeout.as_secs().min(i32::MAX as u64) as i32);
+        // The special attribute selection `1.1` means no attributes
+        let result = connection
+            .with_search_options(options)
+            .with_timeout(self.settings.timeout)
+            .search(
+                self.settings.search_base_dn.as_deref().unwrap_or_default(),
+                Scope::Subtree,
+                &filter,
+                vec!["1.1"],
+            )
+            .map_err(ldap_error)?;
+        match result.1.rc {
+            RESULT_SUCCESS | RESULT_SIZE_LIMIT_EXCEEDED => (),
+            x => {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("Search failed with result code {}", x),
+                ))
+            }
+        }
+
+        let mut entries = result.0;
+        Ok(match entries.len() {
+            1 => entries.pop().map(|x| SearchEntry::construct(x).dn),
+            _ => None,
+        })
+    }
+
+    fn bind(&self, connection: &mut LdapConn, dn: &str, password: &str) -> io::Result<BindResult> {
+        let result = connection
+            .with_timeout(self.settings.timeout)
+            .simple_bind(dn, password)
+            .map_err(ldap_error)?;
+        match result.rc {
+            RESULT_SUCCESS => Ok(BindResult::Success),
+            RESULT_INVALID_CREDENTIALS => Ok(BindResult::InvalidCredentials),
+            x => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("Bind failed with result code {}", x),
+            )),
+        }
+    }
+
+    fn connect(&self) -> io::Result<LdapConn> {
+        let mut settings = LdapConnSettings::new().set_conn_timeout(self.settings.timeout);
+        let scheme = match &self.tls {
+            None => "ldap",
+            Some(x) => {
+                settings = settings.set_config(x.client_config());
+                "ldaps"
+            }
+        };
+        LdapConn::with_settings(settings, &format!("{}://{}", scheme, self.settings.address))
+            .map_err(ldap_error)
+    }
+}
+
+impl Authenticator for LdapAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let Some((username, password)) = source.basic_credentials() else {
+            return authentication::Status::Reject;
+        };
+        // The servers let the binds with an empty password through as the anonymous ones
+        if username.is_empty() || password.is_empty() {
+            return authentication::Status::Reject;
+        }
+
+        match authentication::block_in_place(|| self.check(&username, &password)) {
+            Ok(BindResult::Success) => authentication::Status::Pass,
+            Ok(BindResult::InvalidCredentials) => {
+                log_id!(debug, log_id, "LDAP: invalid credentials of {}", username);
+                authentication::Status::Reject
+            }
+            Err(e) => {
+                log_id!(
+                    warn,
+                    log_id,
+                    "LDAP: failed to authenticate {}: {}",
+                    username,
+                    e
+                );
+                authentication::Status::Reject
+            }
+        }
+    }
+}
+
+/// Escape the special characters of a DN attribute value ([RFC 4514](https://datatracker.ietf.org/doc/html/rfc4514#section-2.4)),
+/// so that the username cannot change the DN structure
+fn escape_dn_value(value: &str) -> String {
+    let mut x = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+                x.push('\\');
+                x.push(c);
+            }
+            '#' if i == 0 => x.push_str("\\#"),
+            ' ' if i == 0 || i == last => x.push_str("\\ "),
+            '\0' => x.push_str("\\00"),
+            c => x.push(c),
+        }
+    }
+    x
+}
+
+fn ldap_error(e: LdapError) -> io::Error {
+    io::Error::new(ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    const TAG_INTEGER: u8 = 0x02;
+    const TAG_OCTET_STRING: u8 = 0x04;
+    const TAG_ENUMERATED: u8 = 0x0a;
+    const TAG_SEQUENCE: u8 = 0x30;
+    const TAG_BIND_REQUEST: u8 = 0x60;
+    const TAG_BIND_RESPONSE: u8 = 0x61;
+    const TAG_SEARCH_REQUEST: u8 = 0x63;
+    const TAG_SEARCH_RESULT_ENTRY: u8 = 0x64;
+    const TAG_SEARCH_RESULT_DONE: u8 = 0x65;
+    const TAG_SIMPLE_AUTHENTICATION: u8 = 0x80;
+    const TAG_EQUALITY_MATCH: u8 = 0xa3;
+
+    const SERVICE_DN: &str = "cn=service,dc=example,dc=org";
+    const SERVICE_PASSWORD: &str = "service-secret";
+    /// The entries of the test directory: DN, `uid` and password
+    const ENTRIES: &[(&str, &str, &str)] = &[
+        ("uid=alice,ou=people,dc=example,dc=org", "alice", "secret"),
+        ("uid=bob,ou=people,dc=example,dc=org", "bob", "hunter2"),
+    ];
+
+    /// Iterates over the BER encoded elements of the requests
+    struct Reader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self { data }
+        }
+
+        fn next(&mut self) -> io::Result<(u8, &'a [u8])> {
+            let truncated = || io::Error::new(ErrorKind::InvalidData, "Truncated BER element");
+            let (&tag, rest) = self.data.split_first().ok_or_else(truncated)?;
+            let (&first, mut rest) = rest.split_first().ok_or_else(truncated)?;
+            let length = match first {
+                x if x < 0x80 => x as usize,
+                x => {
+                    let n = (x & 0x7f) as usize;
+                    let length = rest[..n].iter().fold(0, |l, b| (l << 8) | *b as usize);
+                    rest = &rest[n..];
+                    length
+                }
+            };
+            if rest.len() < length {
+                return Err(truncated());
+            }
+
+            let (content, rest) = rest.split_at(length);
+            self.data = rest;
+            Ok((tag, content))
+        }
+
+        fn expect(&mut self, tag: u8) -> io::Result<&'a [u8]> {
+            match self.next()? {
+                (x, content) if x == tag => Ok(content),
+                (x, _) => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected BER tag: {:#04x}", x),
+                )),
+            }
+        }
+
+        fn integer(&mut self, tag: u8) -> io::Result<i64> {
+            let content = self.expect(tag)?;
+            let sign = if content.first().is_some_and(|x| x & 0x80 != 0) {
+                -1
+            } else {
+                0
+            };
+            Ok(content.iter().fold(sign, |x, b| (x << 8) | *b as i64))
+        }
+    }
+
+    fn read_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head)?;
+        let length = match head[1] {
+            x if x < 0x80 => x as usize,
+            x => {
+                let mut bytes = [0; 4];
+                stream.read_exact(&mut bytes[4 - (x & 0x7f) as usize..])?;
+                u32::from_be_bytes(bytes) as usize
+            }
+        };
+        let mut message = vec![0; length];
+        stream.read_exact(&mut message)?;
+        Ok(message)
+    }
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut x = vec![tag];
+        if content.len() < 0x80 {
+            x.push(content.len() as u8);
+        } else {
+            x.push(0x82);
+            x.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        }
+        x.extend_from_slice(content);
+        x
+    }
+
+    /// Encode the integer in the minimal number of octets
+    fn integer(tag: u8, value: i64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let mut skip = 0;
+        while skip < bytes.len() - 1
+            && ((bytes[skip] == 0 && bytes[skip + 1] & 0x80 == 0)
+                || (bytes[skip] == 0xff && bytes[skip + 1] & 0x80 != 0))
+        {
+            skip += 1;
+        }
+        tlv(tag, &bytes[skip..])
+    }
+
+    fn run_server() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || while serve(&mut stream).is_ok() {});
+            }
+        });
+        address
+    }
+
+    fn serve(stream: &mut TcpStream) -> io::Result<()> {
+        let message = read_message(stream)?;
+        let mut reader = Reader::new(&message);
+        let id = reader.integer(TAG_INTEGER)?;
+        let (tag, operation) = reader.next()?;
+        let mut operation = Reader::new(operation);
+        let respond = |stream: &mut TcpStream, tag, response: &[u8]| {
+            let message = [integer(TAG_INTEGER, id), tlv(tag, response)].concat();
+            stream.write_all(&tlv(TAG_SEQUENCE, &message))
+        };
+        let result = |code| {
+            [
+                integer(TAG_ENUMERATED, code),
+                tlv(TAG_OCTET_STRING, b""),
+                tlv(TAG_OCTET_STRING, b""),
+            ]
+            .concat()
+        };
+
+        match tag {
+            TAG_BIND_REQUEST => {
+                assert_eq!(3, operation.integer(TAG_INTEGER)?);
+                let dn = operation.expect(TAG_OCTET_STRING)?;
+                let password = operation.expect(TAG_SIMPLE_AUTHENTICATION)?;
+                let is_valid = ENTRIES
+                    .iter()
+                    .map(|(dn, _, password)| (*dn, *password))
+                    .chain([(SERVICE_DN, SERVICE_PASSWORD)])
+                    .any(|x| (x.0.as_bytes(), x.1.as_bytes()) == (dn, password));
+                let code = if is_valid {
+                    RESULT_SUCCESS
+                } else {
+                    RESULT_INVALID_CREDENTIALS
+                };
+                respond(stream, TAG_BIND_RESPONSE, &result(code as i64))
+            }
+            TAG_SEARCH_REQUEST => {
+                for _ in 0..6 {
+                    operation.next()?;
+                }
+                let mut filter = Reader::new(operation.expect(TAG_EQUALITY_MATCH)?);
+                assert_eq!(b"uid", filter.expect(TAG_OCTET_STRING)?);
+                let uid = filter.expect(TAG_OCTET_STRING)?;
+                for (dn, _, _) in ENTRIES.iter().filter(|x| x.1.as_bytes() == uid) {
+                    let entry = [tlv(TAG_OCTET_STRING, dn.as_bytes()), tlv(TAG_SEQUENCE, &[])];
+                    respond(stream, TAG_SEARCH_RESULT_ENTRY, &entry.concat())?;
+                }
+                respond(
+                    stream,
+                    TAG_SEARCH_RESULT_DONE,
+                    &result(RESULT_SUCCESS as i64),
+                )
+            }
+            _ => Err(ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    fn basic(username: &str, password: &str) -> authentication::Source<'static> {
+        authentication::Source::ProxyBasic(
+            BASE64_ENGINE
+                .encode(format!("{}:{}", username, password))
+                .into(),
+        )
+    }
+
+    fn check(authenticator: &LdapAuthenticator, username: &str, password: &str) -> bool {
+        authenticator.authenticate(&basic(username, password), &log_utils::IdChain::empty())
+            == authentication::Status::Pass
+    }
+
+    #[test]
+    fn binds_with_template() {
+        let settings = LdapSettings::builder(run_server())
+            .bind_dn("uid={username},ou=people,dc=example,dc=org")
+            .build()
+            .unwrap();
+        let authenticator = LdapAuthenticator::new(settings).unwrap();
+
+        assert!(check(&authenticator, "alice", "secret"));
+        assert!(check(&authenticator, "bob", "hunter2"));
+        assert!(!check(&authenticator, "alice", "hunter2"));
+        assert!(!check(&authenticator, "alice", ""));
+        assert!(!check(&authenticator, "alice,ou=people", "secret"));
+    }
+
+    #[test]
+    fn binds_with_found_entry() {
+        let settings = LdapSettings::builder(run_server())
+            .search("dc=example,dc=org", "uid")
+            .search_bind(SERVICE_DN, SERVICE_PASSWORD)
+            .build()
+            .unwrap();
+        let authenticator = LdapAuthenticator::new(settings).unwrap();
+
+        assert!(check(&authenticator, "alice", "secret"));
+        assert!(!check(&authenticator, "alice", "wrong"));
+        assert!(!check(&authenticator, "carol", "secret"));
+
+        let settings = LdapSettings::builder(run_server())
+            .search("dc=example,dc=org", "uid")
+            .search_bind(SERVICE_DN, "wrong")
+            .build()
+            .unwrap();
+        let authenticator = LdapAuthenticator::new(settings).unwrap();
+        assert!(!check(&authenticator, "alice", "secret"));
+    }
+
+    #[test]
+    fn escapes_dn_values() {
+        assert_eq!("alice", escape_dn_value("alice"));
+        assert_eq!("a\\,b\\=c", escape_dn_value("a,b=c"));
+        assert_eq!("\\#a\\ ", escape_dn_value("#a "));
+        assert_eq!("\\ a\\\\b", escape_dn_value(" a\\b"));
+    }
+}
@@ -0,0 +1,435 @@
+//! The conversion of the client credentials between the formats of the authenticator backends,
+//! so that the clients are moved from one backend to another without retyping them.
+//! The credentials are imported from a credentials file ([`Format::Toml`]) or an Apache
+//! `htpasswd` file, and are exported to either of them, to an SQL script filling the table
+//! the default [`DatabaseSettings::query`] looks the clients up in, or to a Redis mass insertion
+//! script for `redis-cli --pipe` making the keys of [`RedisSettings::key_prefix`].
+//!
+//! [`DatabaseSettings::query`]: crate::settings::DatabaseSettings
+//! [`RedisSettings::key_prefix`]: crate::settings::RedisSettings
+
+use crate::authentication::{password_hash, DataQuota, QuotaPeriod};
+use crate::settings::RedisSettings;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
+
+/// The keys of a credentials file entry [`Credential`] has the dedicated fields for
+const TOML_KEYS: [&str; 9] = [
+    "username",
+    "password",
+    "password_hash",
+    "valid_till",
+    "tier",
+    "max_connections",
+    "max_streams",
+    "data_quota_bytes",
+    "data_quota_period_days",
+];
+/// The attributes which loosen the authentication of a client once they are dropped
+const SECURITY_ATTRIBUTES: [&str; 1] = ["totp_secret"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The credentials file of [`FileBasedAuthenticator`](super::file_based::FileBasedAuthenticator)
+    Toml,
+    /// The `username:hash` lines, the hashes are of the schemes [`password_hash`] supports
+    Htpasswd,
+    /// The `INSERT` statements of the `username`, `password` and `valid_till` columns,
+    /// export only
+    Sql,
+    /// The `SET` and `EXPIREAT` commands in the Redis protocol, export only
+    Redis,
+}
+
+/// The password of a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum Secret {
+    Plain(String),
+    Hash(String),
+}
+
+/// A client record
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Credential {
+    pub username: String,
+    /// [`None`] for the clients authorized by a client certificate only
+    pub secret: Option<Secret>,
+    /// The expiration time as a UNIX timestamp
+    pub valid_till: Option<u64>,
+    pub tier: Option<String>,
+    /// The maximum number of the concurrent tunneled connections
+    pub max_connections: Option<usize>,
+    /// The maximum number of the concurrent tunnel streams across the sessions
+    pub max_streams: Option<usize>,
+    pub data_quota: Option<DataQuota>,
+    /// The rest of the string fields of a credentials file entry, e.g., `egress_address`,
+    /// which only the credentials file is able to carry
+    pub attributes: BTreeMap<String, String>,
+}
+
+pub struct ExportOptions {
+    /// Replace the plain text passwords with their hashes.
+    /// The passwords are hashed for [`Format::Htpasswd`] regardless.
+    pub hash_passwords: bool,
+    /// The table of [`Format::Sql`]
+    pub sql_table: String,
+    /// The key prefix of [`Format::Redis`]
+    pub redis_key_prefix: String,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            hash_passwords: false,
+            sql_table: "clients".into(),
+            redis_key_prefix: RedisSettings::default_key_prefix(),
+        }
+    }
+}
+
+impl Format {
+    /// Whether the credentials can be read from the format
+    pub fn is_importable(&self) -> bool {
+        matches!(self, Self::Toml | Self::Htpasswd)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Self::Toml),
+            "htpasswd" => Ok(Self::Htpasswd),
+            "sql" => Ok(Self::Sql),
+            "redis" => Ok(Self::Redis),
+            x => Err(format!("Unknown credentials format: {}", x)),
+        }
+    }
+}
+
+/// Read the credentials of the format
+pub fn import(format: Format, content: &str) -> Result<Vec<Credential>, String> {
+    match format {
+        Format::Toml => import_toml(content),
+        Format::Htpasswd => import_htpasswd(content),
+        Format::Sql | Format::Redis => Err(format!("Import from {:?} is not supported", format)),
+    }
+}
+
+/// Write the credentials in the format. Fails on the clients the format is not able
+/// to authenticate at least as strictly, e.g., the ones with a TOTP secret for the formats
+/// other than [`Format::Toml`]. The other attributes the format lacks are dropped,
+/// see [`dropped_attributes`].
+pub fn export(
+    format: Format,
+    credentials: &[Credential],
+    options: &ExportOptions,
+) -> Result<String, String> {
+    let hashed = |x: &Credential| -> Result<Option<Secret>, String> {
+        match &x.secret {
+            Some(Secret::Plain(p)) if options.hash_passwords || format == Format::Htpasswd => {
+                password_hash::hash(p).map(|h| Some(Secret::Hash(h)))
+            }
+            x => Ok(x.clone()),
+        }
+    };
+
+    let mut out = String::new();
+    let mut document = ArrayOfTables::new();
+    for x in credentials {
+        let secret = hashed(x)?;
+        if format == Format::Toml {
+            document.push(toml_entry(x, secret.as_ref()));
+            continue;
+        }
+
+        let secret = match secret {
+            Some(Secret::Plain(x) | Secret::Hash(x)) => x,
+            None => return Err(format!("Client {} has no password", x.username)),
+        };
+        if let Some(a) = SECURITY_ATTRIBUTES
+            .iter()
+            .find(|a| x.attributes.contains_key(**a))
+        {
+            return Err(format!(
+                "Client {} has {} which {:?} can't carry",
+                x.username, a, format
+            ));
+        }
+        match format {
+            Format::Toml => unreachable!(),
+            Format::Htpasswd => {
+                if x.username.contains(':') {
+                    return Err(format!("Username contains colon: {}", x.username));
+                }
+                let _ = writeln!(out, "{}:{}", x.username, secret);
+            }
+            Format::Sql => {
+                let _ = writeln!(
+                    out,
+                    "INSERT INTO {} (username, password, valid_till) VALUES ({}, {}, {});",
+                    options.sql_table,
+                    sql_string(&x.username),
+                    sql_string(&secret),
+                    x.valid_till
+                        .map_or_else(|| "NULL".to_string(), |x| x.to_string())
+                );
+            }
+            Format::Redis => {
+                let key = format!("{}{}", options.redis_key_prefix, x.username);
+                let mut commands = redis::cmd("SET")
+                    .arg(&key)
+                    .arg(&secret)
+                    .get_packed_command();
+                if let Some(t) = x.valid_till {
+                    commands.extend(redis::cmd("EXPIREAT").arg(&key).arg(t).get_packed_command());
+                }
+                out.push_str(&String::from_utf8_lossy(&commands));
+            }
+        }
+    }
+
+    if format == Format::Toml {
+        let mut doc = Document::new();
+        doc.insert("client", Item::ArrayOfTables(document));
+        out = doc.to_string();
+    }
+    Ok(out)
+}
+
+/// Get the attributes of the client the format is not able to carry
+pub fn dropped_attributes(format: Format, credential: &Credential) -> Vec<&str> {
+    if format == Format::Toml {
+        return vec![];
+    }
+    let mut x: Vec<&str> = credential.attributes.keys().map(String::as_str).collect();
+    if credential.tier.is_some() {
+        x.push("tier");
+    }
+    if credential.max_connections.is_some() {
+        x.push("max_connections");
+    }
+    if credential.max_streams.is_some() {
+        x.push("max_streams");
+    }
+    if credential.data_quota.is_some() {
+        x.push("data_quota_bytes");
+    }
+    if credential.valid_till.is_some() && format == Format::Htpasswd {
+        x.push("valid_till");
+    }
+    x
+}
+
+/// Make the hash of the password, which the credentials file, the database
+/// and the Redis authenticators accept in place of the password
+pub fn hash_password(password: &str) -> Result<String, String> {
+    password_hash::hash(password)
+}
+
+fn import_toml(content: &str) -> Result<Vec<Credential>, String> {
+    let doc: Document = content.parse().map_err(|e| format!("{}", e))?;
+    let Some(clients) = doc.get("client").and_then(Item::as_array_of_tables) else {
+        return Ok(vec![]);
+    };
+
+    clients
+        .iter()
+        .enumerate()
+        .map(|(idx, x)| {
+            let error = |e: &str| format!("Client #{}: {}", idx + 1, e);
+            let string = |key| x.get(key).and_then(Item::as_str).map(str::to_string);
+            let secret = match (string("password"), string("password_hash")) {
+                (Some(x), None) => Some(Secret::Plain(x)),
+                (None, Some(x)) => Some(Secret::Hash(x)),
+                (None, None) => None,
+                (Some(_), Some(_)) => {
+                    return Err(error("only one of password and password_hash may be set"))
+                }
+            };
+            let mut attributes = BTreeMap::new();
+            for (key, item) in x.iter().filter(|(k, _)| !TOML_KEYS.contains(k)) {
+                let item = item
+                    .as_str()
+                    .ok_or_else(|| error(&format!("{} is not a string", key)))?;
+                attributes.insert(key.to_string(), item.to_string());
+            }
+
+            Ok(Credential {
+                username: string("username").ok_or_else(|| error("no username"))?,
+                secret,
+                valid_till: x
+                    .get("valid_till")
+                    .map(|x| {
+                        x.as_integer()
+                            .and_then(|x| u64::try_from(x).ok())
+                            .ok_or_else(|| error("invalid valid_till"))
+                    })
+                    .transpose()?,
+                tier: string("tier"),
+                max_connections: integer(x, "max_connections").map_err(|e| error(&e))?,
+                max_streams: integer(x, "max_streams").map_err(|e| error(&e))?,
+                data_quota: DataQuota::from_attributes(
+                    integer(x, "data_quota_bytes").map_err(|e| error(&e))?,
+                    integer(x, "data_quota_period_days").map_err(|e| error(&e))?,
+                ),
+                attributes,
+            })
+        })
+        .collect()
+}
+
+/// Get the non-negative integer field of a credentials file entry
+fn integer<T: TryFrom<i64>>(entry: &Table, key: &str) -> Result<Option<T>, String> {
+    entry
+        .get(key)
+        .map(|x| {
+            x.as_integer()
+                .and_then(|x| T::try_from(x).ok())
+                .ok_or_else(|| format!("invalid {}", key))
+        })
+        .transpose()
+}
+
+fn import_htpasswd(content: &str) -> Result<Vec<Credential>, String> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, x)| !x.trim().is_empty() && !x.starts_with('#'))
+        .map(|(idx, x)| {
+            let (username, hash) = x
+                .trim_end()
+                .split_once(':')
+                .ok_or_else(|| format!("Line {}: no password hash", idx + 1))?;
+            password_hash::validate(hash).map_err(|e| format!("Line {}: {}", idx + 1, e))?;
+            Ok(Credential {
+                username: username.to_string(),
+                secret: Some(Secret::Hash(hash.to_string())),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn toml_entry(credential: &Credential, secret: Option<&Secret>) -> Table {
+    let mut x = Table::new();
+    x.insert("username", value(&credential.username));
+    match secret {
+        Some(Secret::Plain(p)) => x.insert("password", value(p)),
+        Some(Secret::Hash(h)) => x.insert("password_hash", value(h)),
+        None => None,
+    };
+    if let Some(t) = credential.valid_till {
+        x.insert("valid_till", value(t as i64));
+    }
+    if let Some(t) = &credential.tier {
+        x.insert("tier", value(t));
+    }
+    if let Some(n) = credential.max_connections {
+        x.insert("max_connections", value(n as i64));
+    }
+    if let Some(n) = credential.max_streams {
+        x.insert("max_streams", value(n as i64));
+    }
+    if let Some(quota) = credential.data_quota {
+        x.insert("data_quota_bytes", value(quota.bytes as i64));
+        if let QuotaPeriod::Rolling(n) = quota.period {
+            x.insert("data_quota_period_days", value(n as i64));
+        }
+    }
+    for (key, v) in &credential.attributes {
+        x.insert(key, value(v));
+    }
+    x
+}
+
+/// Quote the string as an SQL literal
+fn sql_string(x: &str) -> String {
+    format!("'{}'", x.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREDENTIALS: &str = r#"
+[[client]]
+username = "alice"
+password = "it's a secret"
+valid_till = 1735689600
+tier = "paid"
+max_connections = 4
+max_streams = 16
+data_quota_bytes = 1073741824
+data_quota_period_days = 30
+
+[[client]]
+username = "bob"
+password_hash = "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"
+egress_address = "203.0.113.10"
+"#;
+
+    #[test]
+    fn converts_credentials() {
+        let credentials = import(Format::Toml, CREDENTIALS).unwrap();
+        assert_eq!(2, credentials.len());
+        assert_eq!(
+            Some(&"203.0.113.10".to_string()),
+            credentials[1].attributes.get("egress_address")
+        );
+        assert_eq!(
+            credentials,
+            import(
+                Format::Toml,
+                &export(Format::Toml, &credentials, &ExportOptions::default()).unwrap()
+            )
+            .unwrap()
+        );
+
+        let options = ExportOptions::default();
+        assert_eq!(
+            "INSERT INTO clients (username, password, valid_till) \
+                VALUES ('alice', 'it''s a secret', 1735689600);\n\
+            INSERT INTO clients (username, password, valid_till) \
+                VALUES ('bob', '$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5', NULL);\n",
+            export(Format::Sql, &credentials, &options).unwrap()
+        );
+        assert!(export(Format::Redis, &credentials, &options)
+            .unwrap()
+            .starts_with(
+                "*3\r\n$3\r\nSET\r\n$24\r\ntrusttunnel:client:alice\r\n$13\r\nit's a secret\r\n\
+                *3\r\n$8\r\nEXPIREAT\r\n"
+            ));
+        assert_eq!(
+            vec!["tier", "max_connections", "max_streams", "data_quota_bytes"],
+            dropped_attributes(Format::Sql, &credentials[0])
+        );
+
+        // The plain text passwords are hashed for htpasswd
+        let htpasswd = export(Format::Htpasswd, &credentials, &options).unwrap();
+        let imported = import(Format::Htpasswd, &htpasswd).unwrap();
+        assert!(matches!(
+            &imported[0].secret,
+            Some(Secret::Hash(x)) if password_hash::verify("it's a secret", x) == Ok(true)
+        ));
+        assert_eq!(credentials[1].secret, imported[1].secret);
+    }
+
+    #[test]
+    fn refuses_loosening_authentication() {
+        let mut credentials = import(Format::Toml, CREDENTIALS).unwrap();
+        credentials[0]
+            .attributes
+            .insert("totp_secret".into(), "JBSWY3DPEHPK3PXP".into());
+        assert!(export(Format::Toml, &credentials, &ExportOptions::default()).is_ok());
+        assert!(export(Format::Sql, &credentials, &ExportOptions::default()).is_err());
+
+        credentials[0].attributes.clear();
+        credentials[0].secret = None;
+        assert!(export(Format::Redis, &credentials, &ExportOptions::default()).is_err());
+        assert!(import(Format::Htpasswd, "alice:plain").is_err());
+    }
+}
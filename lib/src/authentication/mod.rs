@@ -1,17 +1,54 @@
+pub mod caching;
+pub mod chain;
+pub mod client_cert;
+pub mod credentials_store;
+pub mod database;
+pub mod destination_acl;
+pub(crate) mod digest;
 pub mod file_based;
+pub mod introspection;
+pub mod jwt;
+pub mod ldap;
+pub mod migration;
+#[cfg(all(unix, feature = "pam"))]
+pub mod pam;
+pub(crate) mod password_hash;
+pub mod redis;
 pub mod registry_based;
+pub(crate) mod totp;
 
+use crate::authentication::destination_acl::DestinationAcl;
 use crate::log_utils;
+use crate::tls_info::TlsInfo;
+use crate::upstream_tls::UpstreamTls;
+use base64::Engine;
 use std::borrow::Cow;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Authentication request source
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Source<'this> {
     /// A client tries to authenticate using SNI
     Sni(Cow<'this, str>),
     /// A client tries to authenticate using
     /// [the basic authentication scheme](https://datatracker.ietf.org/doc/html/rfc7617)
     ProxyBasic(Cow<'this, str>),
+    /// A client tries to authenticate using a [JSON Web Token](https://datatracker.ietf.org/doc/html/rfc7519)
+    /// of the bearer authentication scheme
+    ProxyBearer(Cow<'this, str>),
+    /// A client tries to authenticate using
+    /// [the digest authentication scheme](https://datatracker.ietf.org/doc/html/rfc7616).
+    /// Contains the parameters following the scheme name.
+    ProxyDigest(Cow<'this, str>),
+    /// A client presented a TLS certificate chain verified against the configured
+    /// certificate authorities (see `ClientAuthSettings`).
+    /// Contains the DER encoded certificates, the client one first.
+    ClientCert(Cow<'this, [Vec<u8>]>),
 }
 
 /// Authentication procedure status
@@ -23,10 +60,239 @@ pub enum Status {
     Reject,
 }
 
+/// The amount of data a client is allowed to transfer through its tunnels in a period
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataQuota {
+    /// The limit of the bytes transferred in both directions
+    pub bytes: u64,
+    pub period: QuotaPeriod,
+}
+
+/// The period a [`DataQuota`] is renewed with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaPeriod {
+    /// The calendar month in UTC
+    Monthly,
+    /// The window of the number of the last days, the current one included
+    Rolling(u32),
+}
+
+impl DataQuota {
+    /// The longest window of a [`QuotaPeriod::Rolling`] quota
+    pub const MAX_PERIOD_DAYS: u32 = 366;
+
+    /// Make the quota of the `data_quota_bytes` and `data_quota_period_days` attributes
+    /// of a client. The quota is monthly if the period is not set.
+    pub fn from_attributes(bytes: Option<u64>, period_days: Option<u32>) -> Option<Self> {
+        Some(Self {
+            bytes: bytes?,
+            period: period_days.map_or(QuotaPeriod::Monthly, QuotaPeriod::Rolling),
+        })
+    }
+}
+
 /// The authenticator abstract interface
 pub trait Authenticator: Send + Sync {
     /// Authenticate client
     fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status;
+
+    /// Check the credentials of an established session still pass, e.g., the client is not
+    /// removed or expired since. Called on the later requests of the session and
+    /// periodically over its lifetime. Unlike [`Authenticator::authenticate`], the one-time
+    /// parts of the credentials, like the TOTP codes, are not checked again.
+    fn revalidate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        self.authenticate(source, log_id)
+    }
+
+    /// Get the username of an authenticated client, e.g., the subject of its verified token.
+    /// [`None`] means the client is not named by its credentials.
+    fn username(&self, source: &Source<'_>) -> Option<String> {
+        source.username()
+    }
+
+    /// Get the ID of the verified token of an authenticated client.
+    /// [`None`] means the credentials are not a token, or the token carries no ID.
+    fn token_id(&self, _source: &Source<'_>) -> Option<String> {
+        None
+    }
+
+    /// Get the quality of service tier of an authenticated client.
+    /// [`None`] means the client is not assigned to any tier explicitly.
+    fn tier(&self, _source: &Source<'_>) -> Option<String> {
+        None
+    }
+
+    /// Get the source address of the outgoing connections of an authenticated client.
+    /// [`None`] means the address is picked from the `egress_addresses` pool of the settings.
+    fn egress_address(&self, _source: &Source<'_>) -> Option<IpAddr> {
+        None
+    }
+
+    /// Get the maximum number of the concurrent tunneled connections of an authenticated client.
+    /// [`None`] means the `max_connections_per_user` limit of the settings applies.
+    fn max_connections(&self, _source: &Source<'_>) -> Option<usize> {
+        None
+    }
+
+    /// Get the maximum number of the concurrent tunnel streams of an authenticated client
+    /// across all its sessions.
+    /// [`None`] means the `max_streams_per_user` limit of the settings applies.
+    fn max_streams(&self, _source: &Source<'_>) -> Option<usize> {
+        None
+    }
+
+    /// Get the data transfer quota of an authenticated client.
+    /// [`None`] means the client is not limited.
+    fn data_quota(&self, _source: &Source<'_>) -> Option<DataQuota> {
+        None
+    }
+
+    /// Get the name of the destination profile of an authenticated client.
+    /// [`None`] means the client is not restricted by any profile.
+    fn profile(&self, _source: &Source<'_>) -> Option<String> {
+        None
+    }
+
+    /// Get the destinations an authenticated client is restricted to.
+    /// [`None`] means the client is not restricted on its own.
+    fn destination_acl(&self, _source: &Source<'_>) -> Option<Arc<DestinationAcl>> {
+        None
+    }
+
+    /// Get the UNIX time the credentials of an authenticated client expire at.
+    /// [`None`] means the credentials do not expire, or the expiration is unknown.
+    fn valid_till(&self, _source: &Source<'_>) -> Option<u64> {
+        None
+    }
+
+    /// Check the TLS handshake of the connection of an authenticated client, e.g., to let
+    /// some clients in over TLS 1.3 only. Called after the credentials have passed,
+    /// `false` rejects the client as if the credentials have not.
+    fn accept_tls(&self, _source: &Source<'_>, _tls: &TlsInfo) -> bool {
+        true
+    }
+
+    /// Whether the store the clients are looked up in is usable, e.g., the credentials file
+    /// is read and parsed without problems. The authenticators which can't tell
+    /// report `true`.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Drop the results kept for the client with the identity, or all of them
+    /// in case of [`None`], so that the clients are authenticated anew.
+    /// Returns the number of the dropped results.
+    fn invalidate(&self, _identity: Option<&str>) -> usize {
+        0
+    }
+}
+
+impl<T: Authenticator + ?Sized> Authenticator for Arc<T> {
+    fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        (**self).authenticate(source, log_id)
+    }
+
+    fn revalidate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        (**self).revalidate(source, log_id)
+    }
+
+    fn username(&self, source: &Source<'_>) -> Option<String> {
+        (**self).username(source)
+    }
+
+    fn token_id(&self, source: &Source<'_>) -> Option<String> {
+        (**self).token_id(source)
+    }
+
+    fn tier(&self, source: &Source<'_>) -> Option<String> {
+        (**self).tier(source)
+    }
+
+    fn egress_address(&self, source: &Source<'_>) -> Option<IpAddr> {
+        (**self).egress_address(source)
+    }
+
+    fn max_connections(&self, source: &Source<'_>) -> Option<usize> {
+        (**self).max_connections(source)
+    }
+
+    fn max_streams(&self, source: &Source<'_>) -> Option<usize> {
+        (**self).max_streams(source)
+    }
+
+    fn data_quota(&self, source: &Source<'_>) -> Option<DataQuota> {
+        (**self).data_quota(source)
+    }
+
+    fn profile(&self, source: &Source<'_>) -> Option<String> {
+        (**self).profile(source)
+    }
+
+    fn destination_acl(&self, source: &Source<'_>) -> Option<Arc<DestinationAcl>> {
+        (**self).destination_acl(source)
+    }
+
+    fn valid_till(&self, source: &Source<'_>) -> Option<u64> {
+        (**self).valid_till(source)
+    }
+
+    fn accept_tls(&self, source: &Source<'_>, tls: &TlsInfo) -> bool {
+        (**self).accept_tls(source, tls)
+    }
+
+    fn is_healthy(&self) -> bool {
+        (**self).is_healthy()
+    }
+
+    fn invalidate(&self, identity: Option<&str>) -> usize {
+        (**self).invalidate(identity)
+    }
+}
+
+/// A blocking connection to an authentication server
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Run a blocking exchange with an authentication server letting the runtime move the other
+/// tasks of the worker to another thread
+pub(crate) fn block_in_place<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(x) if x.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// Open a blocking connection to the first reachable address of an authentication server,
+/// running the TLS handshake in case of `tls`. The reads and writes of the connection
+/// time out after `timeout`.
+pub(crate) fn connect<A: ToSocketAddrs>(
+    address: A,
+    timeout: Duration,
+    tls: Option<&UpstreamTls>,
+) -> io::Result<Box<dyn Stream>> {
+    let mut error = io::Error::new(ErrorKind::NotFound, "Server address is not resolved");
+    for address in address.to_socket_addrs()? {
+        let stream = match TcpStream::connect_timeout(&address, timeout) {
+            Ok(x) => x,
+            Err(e) => {
+                error = e;
+                continue;
+            }
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        return Ok(match tls {
+            None => Box::new(stream),
+            Some(x) => Box::new(x.connect_blocking(stream, address)?),
+        });
+    }
+
+    Err(error)
 }
 
 impl Source<'_> {
@@ -34,6 +300,52 @@ impl Source<'_> {
         match self {
             Source::Sni(x) => Source::Sni(Cow::Owned(x.into_owned())),
             Source::ProxyBasic(x) => Source::ProxyBasic(Cow::Owned(x.into_owned())),
+            Source::ProxyBearer(x) => Source::ProxyBearer(Cow::Owned(x.into_owned())),
+            Source::ProxyDigest(x) => Source::ProxyDigest(Cow::Owned(x.into_owned())),
+            Source::ClientCert(x) => Source::ClientCert(Cow::Owned(x.into_owned())),
+        }
+    }
+
+    /// Get the DER encoded client certificate in case of [`Source::ClientCert`]
+    pub fn client_cert(&self) -> Option<&[u8]> {
+        match self {
+            Source::ClientCert(x) => x.first().map(Vec::as_slice),
+            _ => None,
+        }
+    }
+
+    /// Split the credentials of [`Source::ProxyBasic`] into the username and the password.
+    /// [`None`] for the other sources, or in case the credentials are malformed.
+    pub fn basic_credentials(&self) -> Option<(String, String)> {
+        let x = match self {
+            Source::ProxyBasic(x) => x,
+            Source::Sni(_)
+            | Source::ProxyBearer(_)
+            | Source::ProxyDigest(_)
+            | Source::ClientCert(_) => return None,
+        };
+        base64::engine::general_purpose::STANDARD
+            .decode(x.as_ref())
+            .ok()
+            .and_then(|x| String::from_utf8(x).ok())?
+            .split_once(':')
+            .map(|(username, password)| (username.to_string(), password.to_string()))
+    }
+
+    /// Extract the username from the basic or digest authentication credentials,
+    /// or the common name of the client certificate falling back to its first alternative name.
+    /// [`None`] in case the credentials are malformed or carry no username. The bearer tokens
+    /// are only named by the authenticator verifying them, see [`Authenticator::username`].
+    pub fn username(&self) -> Option<String> {
+        match self {
+            Source::Sni(_) | Source::ProxyBearer(_) => None,
+            Source::ProxyBasic(_) => self.basic_credentials().map(|(username, _)| username),
+            Source::ProxyDigest(x) => digest::Credentials::parse(x).map(|x| x.username),
+            Source::ClientCert(_) => {
+                let x = self.client_cert()?;
+                client_cert::common_name(x)
+                    .or_else(|| client_cert::subject_alt_names(x).into_iter().next())
+            }
         }
     }
 }
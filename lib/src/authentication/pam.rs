@@ -0,0 +1,62 @@
+use crate::authentication::Authenticator;
+use crate::settings::PamSettings;
+use crate::{authentication, log_id, log_utils};
+use pam_client::conv_mock::Conversation;
+use pam_client::{Context, Flag};
+
+/// The [`Authenticator`] implementation which checks the credentials of a client against
+/// the PAM stack of the host, so that the system accounts are able to connect.
+/// Is only able to authenticate a client using the Proxy basic authorization.
+/// Each authentication is a blocking PAM transaction. Note that the modules may need
+/// the endpoint to run with the privileges to read the password database,
+/// e.g., `pam_unix` checks only the password of the invoking user otherwise.
+pub struct PamAuthenticator {
+    settings: PamSettings,
+}
+
+impl PamAuthenticator {
+    pub fn new(settings: PamSettings) -> Self {
+        Self { settings }
+    }
+
+    fn check(&self, username: &str, password: &str) -> Result<(), String> {
+        // The modules prompting for the username and the password are answered
+        // with the client credentials
+        let mut context = Context::new(
+            &self.settings.service,
+            Some(username),
+            Conversation::with_credentials(username, password),
+        )
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+        let flags = Flag::SILENT | Flag::DISALLOW_NULL_AUTHTOK;
+        context.authenticate(flags).map_err(|e| e.to_string())?;
+        if self.settings.check_account {
+            context.acct_mgmt(flags).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl Authenticator for PamAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let Some((username, password)) = source.basic_credentials() else {
+            return authentication::Status::Reject;
+        };
+        if username.is_empty() || password.is_empty() {
+            return authentication::Status::Reject;
+        }
+
+        match authentication::block_in_place(|| self.check(&username, &password)) {
+            Ok(()) => authentication::Status::Pass,
+            Err(e) => {
+                log_id!(debug, log_id, "PAM: rejected {}: {}", username, e);
+                authentication::Status::Reject
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! The verification of the client passwords against their hashes, so that the credentials
+//! files need not keep the passwords in plain text. The scheme of a hash is detected
+//! by its prefix:
+//!
+//! * `$argon2id$`, `$argon2i$` and `$argon2d$` - Argon2 ([RFC 9106](https://datatracker.ietf.org/doc/html/rfc9106))
+//!   in the PHC string format, e.g., `$argon2id$v=19$m=65536,t=3,p=4$<salt>$<hash>`
+//! * `$2a$`, `$2b$` and `$2y$` - bcrypt, as made by `htpasswd -B`
+//! * `$5$` and `$6$` - SHA-crypt ([SHA-256 and SHA-512](https://www.akkadia.org/drepper/SHA-crypt.txt)),
+//!   as made by `openssl passwd -5` and `openssl passwd -6`
+//!
+//! The cost parameters of a hash are capped, so that a hash can't make a verification
+//! take the endpoint an unbounded time or memory. The verification runs in
+//! [`authentication::block_in_place`].
+
+use crate::authentication;
+use argon2::password_hash::{self, PasswordVerifier};
+
+/// Limits the memory an Argon2 hash is allowed to make a verification take, in KiB
+const ARGON2_MAX_MEMORY: u32 = 256 * 1024;
+const ARGON2_MAX_ITERATIONS: u32 = 16;
+const ARGON2_MAX_PARALLELISM: u32 = 16;
+const ARGON2_VERSION: u32 = 0x13;
+const BCRYPT_MAX_COST: u32 = 16;
+const SHA_CRYPT_MAX_ROUNDS: usize = 1_000_000;
+/// The alphabet of the crypt(3) hashes
+const CRYPT_ALPHABET: &[u8; 64] =
+    b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    Argon2,
+    Bcrypt,
+    Sha256Crypt,
+    Sha512Crypt,
+}
+
+impl Scheme {
+    fn of(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2") {
+            Some(Self::Argon2)
+        } else if ["$2a$", "$2b$", "$2y$"].iter().any(|x| hash.starts_with(x)) {
+            Some(Self::Bcrypt)
+        } else if hash.starts_with("$5$") {
+            Some(Self::Sha256Crypt)
+        } else if hash.starts_with("$6$") {
+            Some(Self::Sha512Crypt)
+        } else {
+            None
+        }
+    }
+}
+
+/// Check the hash is of a supported scheme, well-formed, and its cost is within the limits
+pub(crate) fn validate(hash: &str) -> Result<(), String> {
+    match Scheme::of(hash) {
+        Some(Scheme::Argon2) => validate_argon2(hash),
+        Some(Scheme::Bcrypt) => {
+            let parts = hash
+                .parse::<bcrypt::HashParts>()
+                .map_err(|e| format!("Malformed bcrypt hash: {}", e))?;
+            if parts.get_cost() > BCRYPT_MAX_COST {
+                return Err(format!(
+                    "bcrypt cost is above {}: {}",
+                    BCRYPT_MAX_COST,
+                    parts.get_cost()
+                ));
+            }
+            Ok(())
+        }
+        Some(Scheme::Sha256Crypt) => validate_sha_crypt(&hash[3..], 43),
+        Some(Scheme::Sha512Crypt) => validate_sha_crypt(&hash[3..], 86),
+        None => Err("Unknown password hash scheme".into()),
+    }
+}
+
+/// Verify the password against the hash
+pub(crate) fn verify(password: &str, hash: &str) -> Result<bool, String> {
+    validate(hash)?;
+    authentication::block_in_place(|| match Scheme::of(hash) {
+        Some(Scheme::Argon2) => {
+            let hash = password_hash::PasswordHash::new(hash)
+                .map_err(|e| format!("Malformed Argon2 hash: {}", e))?;
+            match argon2::Argon2::default().verify_password(password.as_bytes(), &hash) {
+                Ok(()) => Ok(true),
+                Err(password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(format!("Failed to verify Argon2 hash: {}", e)),
+            }
+        }
+        Some(Scheme::Bcrypt) => bcrypt::verify(password, hash)
+            .map_err(|e| format!("Failed to verify bcrypt hash: {}", e)),
+        Some(Scheme::Sha256Crypt) => sha_crypt_outcome(sha_crypt::sha256_check(password, hash)),
+        Some(Scheme::Sha512Crypt) => sha_crypt_outcome(sha_crypt::sha512_check(password, hash)),
+        None => Ok(false),
+    })
+}
+
+/// Check the stored value is a password hash rather than a plain text password
+pub(crate) fn is_hash(x: &str) -> bool {
+    Scheme::of(x).is_some()
+}
+
+/// Check the password against the stored value, which is either a hash or the plain text
+pub(crate) fn matches(password: &str, stored: &str) -> Result<bool, String> {
+    if is_hash(stored) {
+        verify(password, stored)
+    } else {
+        Ok(constant_time_eq(password.as_bytes(), stored.as_bytes()))
+    }
+}
+
+/// Make the SHA-512 crypt hash of the password with a random salt and the default rounds,
+/// the same kind `openssl passwd -6` makes
+pub(crate) fn hash(password: &str) -> Result<String, String> {
+    let params = sha_crypt::Sha512Params::new(sha_crypt::ROUNDS_DEFAULT)
+        .map_err(|e| format!("Failed to hash password: {:?}", e))?;
+    sha_crypt::sha512_simple(password, &params)
+        .map_err(|e| format!("Failed to hash password: {:?}", e))
+}
+
+/// Compare the slices without an early return on the first mismatch
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn validate_argon2(hash: &str) -> Result<(), String> {
+    let hash = password_hash::PasswordHash::new(hash)
+        .map_err(|e| format!("Malformed Argon2 hash: {}", e))?;
+    argon2::Algorithm::try_from(hash.algorithm)
+        .map_err(|_| format!("Unknown Argon2 variant: {}", hash.algorithm))?;
+    if hash.version != Some(ARGON2_VERSION) {
+        return Err("Argon2 versions other than 19 are not supported".into());
+    }
+    if ["m", "t", "p"]
+        .iter()
+        .any(|x| hash.params.get(*x).is_none())
+    {
+        return Err("Argon2 parameters are incomplete".into());
+    }
+    let params = argon2::Params::try_from(&hash)
+        .map_err(|e| format!("Argon2 parameters are out of range: {}", e))?;
+    if params.m_cost() > ARGON2_MAX_MEMORY {
+        return Err(format!(
+            "Argon2 memory size is above {} KiB: {} KiB",
+            ARGON2_MAX_MEMORY,
+            params.m_cost()
+        ));
+    }
+    if params.t_cost() > ARGON2_MAX_ITERATIONS {
+        return Err(format!(
+            "Argon2 iterations are above {}: {}",
+            ARGON2_MAX_ITERATIONS,
+            params.t_cost()
+        ));
+    }
+    if params.p_cost() > ARGON2_MAX_PARALLELISM {
+        return Err(format!(
+            "Argon2 parallelism is above {}: {}",
+            ARGON2_MAX_PARALLELISM,
+            params.p_cost()
+        ));
+    }
+    match (hash.salt, hash.hash) {
+        (Some(_), Some(x)) if x.len() >= 4 => Ok(()),
+        _ => Err("Argon2 salt or hash is missing or too short".into()),
+    }
+}
+
+/// Check the SHA-crypt hash following the prefix, `rounds=<N>$<salt>$<hash>`
+/// or `<salt>$<hash>`
+fn validate_sha_crypt(rest: &str, hash_len: usize) -> Result<(), String> {
+    let rest = match rest.strip_prefix("rounds=") {
+        Some(x) => {
+            let (rounds, rest) = x
+                .split_once('$')
+                .ok_or_else(|| "Malformed SHA-crypt rounds".to_string())?;
+            let rounds = rounds
+                .parse::<usize>()
+                .map_err(|e| format!("Malformed SHA-crypt rounds: {}", e))?;
+            if !(sha_crypt::ROUNDS_MIN..=SHA_CRYPT_MAX_ROUNDS).contains(&rounds) {
+                return Err(format!(
+                    "SHA-crypt rounds are out of {}..={}: {}",
+                    sha_crypt::ROUNDS_MIN,
+                    SHA_CRYPT_MAX_ROUNDS,
+                    rounds
+                ));
+            }
+            rest
+        }
+        None => rest,
+    };
+    let (_, hash) = rest
+        .rsplit_once('$')
+        .ok_or_else(|| "No SHA-crypt hash".to_string())?;
+    if hash.len() != hash_len || !hash.bytes().all(|x| CRYPT_ALPHABET.contains(&x)) {
+        return Err("Malformed SHA-crypt hash".into());
+    }
+    Ok(())
+}
+
+fn sha_crypt_outcome(x: Result<(), sha_crypt::CheckError>) -> Result<bool, String> {
+    match x {
+        Ok(()) => Ok(true),
+        Err(sha_crypt::CheckError::HashMismatch) => Ok(false),
+        Err(e) => Err(format!("Failed to verify SHA-crypt hash: {:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_argon2() {
+        // The test vector of the reference implementation
+        let hash =
+            "$argon2id$v=19$m=65536,t=2,p=1$c29tZXNhbHQ$CTFhFdXPJO1aFaMaO6Mm5c8y7cJHAph8ArZWb2GRPPc";
+        assert_eq!(Ok(true), verify("password", hash));
+        assert_eq!(Ok(false), verify("passwore", hash));
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        // The test vector of the OpenBSD implementation
+        let hash = "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+        assert_eq!(Ok(true), verify("U*U", hash));
+        assert_eq!(Ok(false), verify("U*V", hash));
+    }
+
+    #[test]
+    fn verifies_sha_crypt() {
+        // Made with `openssl passwd`
+        for hash in [
+            "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5",
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+            "$5$rounds=10000$saltstringsaltst$3xv.VbSHBb41AL9AvLeujZkZRBAwqFMz2.opqey6IcA",
+        ] {
+            assert_eq!(Ok(true), verify("Hello world!", hash), "{}", hash);
+            assert_eq!(Ok(false), verify("Hello world?", hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn hashes_passwords() {
+        let hash = hash("Hello world!").unwrap();
+        assert!(validate(&hash).is_ok(), "{}", hash);
+        assert_eq!(Ok(true), verify("Hello world!", &hash));
+        assert_ne!(super::hash("Hello world!").unwrap(), hash);
+
+        assert_eq!(Ok(true), matches("Hello world!", &hash));
+        assert_eq!(Ok(true), matches("plain", "plain"));
+        assert_eq!(Ok(false), matches("plain", "plaim"));
+    }
+
+    #[test]
+    fn rejects_unsupported_hashes() {
+        for x in [
+            "plain",
+            "$argon2id$m=64,t=2,p=1$c29tZXNhbHQ$6vhUgvvpNLa1DkQA0yqWDMrhS0j1SzAiz+QvOsSeWxw",
+            "$argon2id$v=19$m=4,t=2,p=1$c29tZXNhbHQ$6vhUgvvpNLa1DkQA0yqWDMrhS0j1SzAiz+QvOsSeWxw",
+            "$argon2id$v=19$m=64,t=2$c29tZXNhbHQ$6vhUgvvpNLa1DkQA0yqWDMrhS0j1SzAiz+QvOsSeWxw",
+            "$5$saltstring$short",
+        ] {
+            assert!(validate(x).is_err(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn caps_costs() {
+        for x in [
+            "$argon2id$v=19$m=1048576,t=2,p=1$c29tZXNhbHQ$6vhUgvvpNLa1DkQA0yqWDMrhS0j1SzAiz+QvOsSeWxw",
+            "$argon2id$v=19$m=64,t=1000000,p=1$c29tZXNhbHQ$6vhUgvvpNLa1DkQA0yqWDMrhS0j1SzAiz+QvOsSeWxw",
+            "$2b$31$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW",
+            "$5$rounds=999999999$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5",
+        ] {
+            assert!(validate(x).is_err(), "{}", x);
+            assert!(verify("Hello world!", x).is_err(), "{}", x);
+        }
+        assert!(validate("$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW").is_ok());
+    }
+}
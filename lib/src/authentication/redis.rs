@@ -0,0 +1,264 @@
+use crate::authentication::{password_hash, Authenticator};
+use crate::settings::RedisSettings;
+use crate::upstream_tls::UpstreamTls;
+use crate::{authentication, log_id, log_utils};
+use ::redis::Value;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::sync::Mutex;
+
+/// The [`Authenticator`] implementation which looks up the password of a client in Redis.
+/// The key of a client is made of its username, so the client expires along with the key.
+/// The value is either the plain text password, or its hash of one of the schemes
+/// [`password_hash`] supports.
+/// Is only able to authenticate a client using the Proxy basic authorization.
+/// Each authentication is a blocking command over one of the pooled server connections.
+pub struct RedisAuthenticator {
+    settings: RedisSettings,
+    tls: Option<UpstreamTls>,
+    /// The idle server connections
+    pool: Mutex<Vec<Connection>>,
+}
+
+/// A client connection to the server
+struct Connection {
+    stream: Box<dyn authentication::Stream>,
+    /// Keeps the data read past a reply
+    parser: ::redis::Parser,
+}
+
+impl RedisAuthenticator {
+    pub fn new(settings: RedisSettings) -> io::Result<Self> {
+        let tls = settings
+            .tls
+            .as_ref()
+            .map(|x| UpstreamTls::new(x, "Redis server"))
+            .transpose()?;
+        Ok(Self {
+            settings,
+            tls,
+            pool: Default::default(),
+        })
+    }
+
+    fn check(&self, username: &str, password: &str) -> io::Result<bool> {
+        let key = format!("{}{}", self.settings.key_prefix, username);
+        let pooled = self.pool.lock().unwrap().pop();
+        let get = |mut connection: Connection| {
+            let reply = connection.command(::redis::cmd("GET").arg(&key))?;
+            io::Result::Ok((connection, reply))
+        };
+        let (connection, reply) = match pooled.map(get) {
+            Some(Ok(x)) => x,
+            // The server might have closed the connection while it was idle
+            None | Some(Err(_)) => get(self.connect()?)?,
+        };
+
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < self.settings.pool_size {
+            pool.push(connection);
+        }
+        drop(pool);
+
+        match reply {
+            Value::Nil => Ok(false),
+            Value::Data(x) => {
+                let stored =
+                    String::from_utf8(x).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                password_hash::matches(password, &stored)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            }
+            x => Err(unexpected_reply(x)),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let mut connection = Connection {
+            stream: authentication::connect(
+                self.settings.address.as_str(),
+                self.settings.timeout,
+                self.tls.as_ref(),
+            )?,
+            parser: ::redis::Parser::new(),
+        };
+        connection.start(&self.settings)?;
+        Ok(connection)
+    }
+}
+
+impl Authenticator for RedisAuthenticator {
+    fn authenticate(
+        &self,
+        source: &authentication::Source<'_>,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> authentication::Status {
+        let Some((username, password)) = source.basic_credentials() else {
+            return authentication::Status::Reject;
+        };
+
+        match authentication::block_in_place(|| self.check(&username, &password)) {
+            Ok(true) => authentication::Status::Pass,
+            Ok(false) => {
+                log_id!(debug, log_id, "Redis: invalid credentials of {}", username);
+                authentication::Status::Reject
+            }
+            Err(e) => {
+                log_id!(
+                    warn,
+                    log_id,
+                    "Redis: failed to authenticate {}: {}",
+                    username,
+                    e
+                );
+                authentication::Status::Reject
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Authenticate the connection and select the database
+    fn start(&mut self, settings: &RedisSettings) -> io::Result<()> {
+        if let Some(password) = &settings.password {
+            let mut command = ::redis::cmd("AUTH");
+            if let Some(x) = &settings.username {
+                command.arg(x);
+            }
+            expect_ok(self.command(command.arg(password))?)?;
+        }
+        if settings.database != 0 {
+            expect_ok(self.command(::redis::cmd("SELECT").arg(settings.database))?)?;
+        }
+        Ok(())
+    }
+
+    /// Send the command and receive its reply. The error replies of the server
+    /// are turned into errors.
+    fn command(&mut self, command: &::redis::Cmd) -> io::Result<Value> {
+        self.stream.write_all(&command.get_packed_command())?;
+        self.stream.flush()?;
+        self.parser
+            .parse_value(&mut self.stream)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))
+    }
+}
+
+fn expect_ok(reply: Value) -> io::Result<()> {
+    match reply {
+        Value::Okay => Ok(()),
+        x => Err(unexpected_reply(x)),
+    }
+}
+
+fn unexpected_reply(reply: Value) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Unexpected reply: {:?}", reply),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+    use base64::Engine;
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const SERVER_PASSWORD: &str = "redis-secret";
+
+    fn run_server(connections: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                connections.fetch_add(1, Ordering::Relaxed);
+                thread::spawn(move || {
+                    let mut parser = ::redis::Parser::new();
+                    let mut is_authenticated = false;
+                    while serve(&mut stream, &mut parser, &mut is_authenticated).is_ok() {}
+                });
+            }
+        });
+        address
+    }
+
+    fn serve(
+        stream: &mut TcpStream,
+        parser: &mut ::redis::Parser,
+        is_authenticated: &mut bool,
+    ) -> io::Result<()> {
+        let args: Vec<String> = match parser.parse_value(&mut *stream) {
+            Ok(Value::Bulk(x)) => x
+                .into_iter()
+                .map(|x| match x {
+                    Value::Data(x) => String::from_utf8(x).unwrap(),
+                    x => panic!("Unexpected argument: {:?}", x),
+                })
+                .collect(),
+            Ok(x) => panic!("Unexpected command: {:?}", x),
+            Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
+        };
+
+        let values = HashMap::from([
+            ("trusttunnel:client:alice", "secret"),
+            ("trusttunnel:client:bob", "hunter2"),
+        ]);
+        let reply = match (args[0].as_str(), &args[1..]) {
+            ("AUTH", [password]) if password == SERVER_PASSWORD => {
+                *is_authenticated = true;
+                "+OK\r\n".to_string()
+            }
+            ("AUTH", _) => "-WRONGPASS invalid username-password pair\r\n".to_string(),
+            _ if !*is_authenticated => "-NOAUTH Authentication required.\r\n".to_string(),
+            ("GET", [key]) => match values.get(key.as_str()) {
+                Some(x) => format!("${}\r\n{}\r\n", x.len(), x),
+                None => "$-1\r\n".to_string(),
+            },
+            _ => "-ERR unknown command\r\n".to_string(),
+        };
+        stream.write_all(reply.as_bytes())
+    }
+
+    fn check(authenticator: &RedisAuthenticator, username: &str, password: &str) -> bool {
+        let source = authentication::Source::ProxyBasic(
+            BASE64_ENGINE
+                .encode(format!("{}:{}", username, password))
+                .into(),
+        );
+        authenticator.authenticate(&source, &log_utils::IdChain::empty())
+            == authentication::Status::Pass
+    }
+
+    #[test]
+    fn looks_up_clients() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let settings = RedisSettings::builder(run_server(connections.clone()))
+            .credentials(None, SERVER_PASSWORD)
+            .build()
+            .unwrap();
+        let authenticator = RedisAuthenticator::new(settings).unwrap();
+
+        assert!(check(&authenticator, "alice", "secret"));
+        assert!(check(&authenticator, "bob", "hunter2"));
+        assert!(!check(&authenticator, "alice", "hunter2"));
+        assert!(!check(&authenticator, "carol", ""));
+        // The connection is reused
+        assert_eq!(1, connections.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn rejects_on_server_error() {
+        let settings = RedisSettings::builder(run_server(Default::default()))
+            .build()
+            .unwrap();
+        let authenticator = RedisAuthenticator::new(settings).unwrap();
+
+        let error = authenticator.check("alice", "secret").unwrap_err();
+        assert!(error.to_string().contains("NOAUTH"), "{}", error);
+    }
+}
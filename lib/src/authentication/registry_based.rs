@@ -1,24 +1,68 @@
-use crate::authentication::Authenticator;
+use crate::authentication::{Authenticator, DataQuota};
 use crate::{authentication, log_utils};
 use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
 use base64::Engine;
 use serde::Deserialize;
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::net::IpAddr;
 
 /// A client descriptor
-#[derive(Deserialize, serde::Serialize)]
+#[derive(Default, Deserialize, serde::Serialize)]
 pub struct Client {
     /// The client username
     pub username: String,
-    /// The client password
+    /// The client password, empty if [`Client::password_hash`] is set
     pub password: String,
+    /// The hash of the client password, so that the password is not kept in plain text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// The base32 encoded secret of the one-time codes the client appends to its password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// The quality of service tier of the client (see [`crate::settings::TierSettings`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>,
+    /// The source address of the outgoing connections of the client,
+    /// overrides the `egress_addresses` pool of the settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_address: Option<IpAddr>,
+    /// The maximum number of the concurrent tunneled connections of the client,
+    /// overrides the `max_connections_per_user` limit of the settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// The maximum number of the concurrent tunnel streams of the client across its sessions,
+    /// overrides the `max_streams_per_user` limit of the settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_streams: Option<usize>,
+    /// The limit of the bytes the client transfers in a period
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_quota_bytes: Option<u64>,
+    /// The number of the last days the data quota is accounted in,
+    /// the calendar month if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_quota_period_days: Option<u32>,
+    /// The destination profile of the client (see [`crate::settings::ProfileSettings`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+/// The attributes of a registered client
+struct ClientInfo {
+    tier: Option<String>,
+    egress_address: Option<IpAddr>,
+    max_connections: Option<usize>,
+    max_streams: Option<usize>,
+    data_quota: Option<DataQuota>,
+    profile: Option<String>,
 }
 
 /// The [`Authenticator`] implementation which checks presence of a client in the list.
 /// Is only able to authenticate a client using the Proxy basic authorization.
+/// The clients with a password hash, a TOTP secret or without a password are not registered.
 pub struct RegistryBasedAuthenticator {
-    clients: HashSet<Cow<'static, str>>,
+    /// Encoded credentials mapped to the client attributes
+    clients: HashMap<Cow<'static, str>, ClientInfo>,
 }
 
 impl RegistryBasedAuthenticator {
@@ -26,8 +70,25 @@ impl RegistryBasedAuthenticator {
         Self {
             clients: clients
                 .iter()
-                .map(|x| BASE64_ENGINE.encode(format!("{}:{}", x.username, x.password)))
-                .map(Cow::Owned)
+                .filter(|x| {
+                    x.password_hash.is_none() && x.totp_secret.is_none() && !x.password.is_empty()
+                })
+                .map(|x| {
+                    (
+                        Cow::Owned(BASE64_ENGINE.encode(format!("{}:{}", x.username, x.password))),
+                        ClientInfo {
+                            tier: x.tier.clone(),
+                            egress_address: x.egress_address,
+                            max_connections: x.max_connections,
+                            max_streams: x.max_streams,
+                            data_quota: DataQuota::from_attributes(
+                                x.data_quota_bytes,
+                                x.data_quota_period_days,
+                            ),
+                            profile: x.profile.clone(),
+                        },
+                    )
+                })
                 .collect(),
         }
     }
@@ -40,10 +101,82 @@ impl Authenticator for RegistryBasedAuthenticator {
         _log_id: &log_utils::IdChain<u64>,
     ) -> authentication::Status {
         match &source {
-            authentication::Source::ProxyBasic(str) if self.clients.contains(str) => {
+            authentication::Source::ProxyBasic(str) if self.clients.contains_key(str) => {
                 authentication::Status::Pass
             }
             _ => authentication::Status::Reject,
         }
     }
+
+    fn tier(&self, source: &authentication::Source<'_>) -> Option<String> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.tier.clone())
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+
+    fn egress_address(&self, source: &authentication::Source<'_>) -> Option<IpAddr> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.egress_address)
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+
+    fn max_connections(&self, source: &authentication::Source<'_>) -> Option<usize> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.max_connections)
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+
+    fn max_streams(&self, source: &authentication::Source<'_>) -> Option<usize> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.max_streams)
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+
+    fn data_quota(&self, source: &authentication::Source<'_>) -> Option<DataQuota> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.data_quota)
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
+
+    fn profile(&self, source: &authentication::Source<'_>) -> Option<String> {
+        match &source {
+            authentication::Source::ProxyBasic(str) => {
+                self.clients.get(str).and_then(|x| x.profile.clone())
+            }
+            authentication::Source::Sni(_)
+            | authentication::Source::ProxyBearer(_)
+            | authentication::Source::ProxyDigest(_)
+            | authentication::Source::ClientCert(_) => None,
+        }
+    }
 }
@@ -0,0 +1,116 @@
+//! The time-based one-time passwords ([RFC 6238](https://datatracker.ietf.org/doc/html/rfc6238))
+//! the clients of the credentials file may be required to present along with their passwords.
+//! The parameters are the ones the authenticator applications assume by default:
+//! HMAC-SHA1, 6 digits and 30 seconds steps. The shared secret is in base32 encoding
+//! ([RFC 4648](https://datatracker.ietf.org/doc/html/rfc4648#section-6)), as in
+//! the `otpauth://` URIs.
+
+use ring::hmac;
+
+/// The number of digits of a code
+pub const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+/// The number of the steps a code is accepted before and after its one,
+/// to tolerate the clock drift of the clients
+const SKEW_STEPS: u64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Check the base32 encoded secret is usable
+pub fn validate(secret: &str) -> Result<(), String> {
+    match decode_secret(secret) {
+        Some(x) if !x.is_empty() => Ok(()),
+        Some(_) => Err("Empty secret".into()),
+        None => Err("Not a base32 string".into()),
+    }
+}
+
+/// Decode the base32 encoded secret. The letter case, the spaces and the padding are ignored.
+pub fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(secret.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for x in secret.bytes().filter(|x| !matches!(x, b' ' | b'=')) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == x.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+/// Check the code against the secret at the UNIX time `now`
+pub fn verify(key: &[u8], code: &str, now: u64) -> bool {
+    verify_step(key, code, now).is_some()
+}
+
+/// Check the code against the secret at the UNIX time `now`, returning the time step
+/// the code is of, so that the caller can refuse the codes of the steps already used
+pub fn verify_step(key: &[u8], code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS || !code.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let step = now / STEP_SECS;
+    (step.saturating_sub(SKEW_STEPS)..=step + SKEW_STEPS).find(|x| generate(key, *x) == code)
+}
+
+/// Make the code of the time step (RFC 4226, section 5.3)
+fn generate(key: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let digest = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated =
+        u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `12345678901234567890`, the SHA1 secret of the RFC 6238 test vectors
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_test_vectors() {
+        let key = decode_secret(SECRET).unwrap();
+        assert_eq!(b"12345678901234567890".as_slice(), key);
+
+        // The 6 last digits of the 8 digits codes of the RFC
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert!(verify(&key, code, time), "{} {}", time, code);
+        }
+    }
+
+    #[test]
+    fn checks_codes() {
+        let key = decode_secret(&SECRET.to_lowercase()).unwrap();
+        assert!(verify(&key, "081804", 1111111109 + STEP_SECS));
+        assert!(!verify(&key, "081804", 1111111109 + 3 * STEP_SECS));
+        assert!(!verify(&key, "81804", 1111111109));
+        assert!(!verify(&key, "08180a", 1111111109));
+        assert_eq!(
+            Some(1111111109 / STEP_SECS),
+            verify_step(&key, "081804", 1111111109 + STEP_SECS)
+        );
+
+        assert!(validate("JBSW Y3DP EHPK 3PXP").is_ok());
+        assert!(validate("JBSWY3DP1").is_err());
+        assert!(validate("").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! The estimation of the bandwidth available to a client session. The traffic counters
+//! of each session are sampled periodically, and the delivery rates of the samples are
+//! run through a windowed max filter: a sample where the session has had less data to send
+//! than the path would take in does not lower the estimate. The estimate is reported
+//! with the sessions list, and is optionally hinted to the clients as the pacing rate to
+//! keep their traffic from queueing up inside the tunnel.
+
+use crate::core;
+use crate::settings::BandwidthEstimationSettings;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The response header of a tunnel request with the pacing hint
+pub(crate) const PACING_HINT_HEADER: &str = "x-trusttunnel-pacing-hint";
+
+/// Estimates the bandwidth of a session from the samples of its traffic counters
+#[derive(Default)]
+pub(crate) struct Estimator {
+    /// The time and the counters of the latest sample
+    last: Option<(Instant, u64, u64)>,
+    /// The delivery rates of the samples in the window (bits per second)
+    samples: VecDeque<Sample>,
+}
+
+struct Sample {
+    taken_at: Instant,
+    inbound_bps: u64,
+    outbound_bps: u64,
+}
+
+/// The estimated bandwidth of a session in bits per second.
+/// A direction is [`None`] if no data has been delivered in it within the window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Estimate {
+    /// From the client to the peers
+    pub inbound_bps: Option<u64>,
+    /// From the peers to the client
+    pub outbound_bps: Option<u64>,
+}
+
+impl Estimator {
+    /// Take a sample of the cumulative traffic counters of the session
+    pub fn sample(
+        &mut self,
+        now: Instant,
+        window: Duration,
+        inbound_bytes: u64,
+        outbound_bytes: u64,
+    ) {
+        if let Some((taken_at, inbound, outbound)) = self.last {
+            let elapsed = now.saturating_duration_since(taken_at).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = |n: u64| (n as f64 * 8.0 / elapsed) as u64;
+                self.samples.push_back(Sample {
+                    taken_at: now,
+                    inbound_bps: rate(inbound_bytes.saturating_sub(inbound)),
+                    outbound_bps: rate(outbound_bytes.saturating_sub(outbound)),
+                });
+            }
+        }
+        self.last = Some((now, inbound_bytes, outbound_bytes));
+
+        while self
+            .samples
+            .front()
+            .is_some_and(|x| now.saturating_duration_since(x.taken_at) > window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Get the maximum delivery rates within the window
+    pub fn estimate(&self) -> Estimate {
+        let max = |f: fn(&Sample) -> u64| self.samples.iter().map(f).max().filter(|x| *x > 0);
+        Estimate {
+            inbound_bps: max(|x| x.inbound_bps),
+            outbound_bps: max(|x| x.outbound_bps),
+        }
+    }
+}
+
+impl Estimate {
+    /// Make the value of the pacing hint header, [`None`] if nothing is estimated yet
+    pub fn pacing_hint(&self, settings: &BandwidthEstimationSettings) -> Option<String> {
+        let paced = |x: Option<u64>| x.map(|x| (x as f64 * settings.pacing_gain) as u64);
+        let hints: Vec<String> = [
+            ("down", paced(self.outbound_bps)),
+            ("up", paced(self.inbound_bps)),
+        ]
+        .into_iter()
+        .filter_map(|(direction, x)| Some(format!("{}={}", direction, x?)))
+        .collect();
+        (!hints.is_empty()).then(|| hints.join(", "))
+    }
+}
+
+/// Periodically sample the traffic of the active sessions
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let settings = match context.settings.bandwidth_estimation.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+    let mut interval = tokio::time::interval(settings.sample_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let sample = async {
+        loop {
+            interval.tick().await;
+            context.sessions.sample_bandwidth(settings.window);
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = sample => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn max_filter() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut estimator = Estimator::default();
+        assert_eq!(Estimate::default(), estimator.estimate());
+
+        estimator.sample(at(0), WINDOW, 0, 0);
+        // 1 MB/s downstream, nothing upstream
+        estimator.sample(at(500), WINDOW, 0, 500_000);
+        // the session has been idle, which must not lower the estimate
+        estimator.sample(at(1000), WINDOW, 0, 500_000);
+        estimator.sample(at(1500), WINDOW, 1000, 600_000);
+        let estimate = estimator.estimate();
+        assert_eq!(Some(8_000_000), estimate.outbound_bps);
+        assert_eq!(Some(16_000), estimate.inbound_bps);
+
+        let settings = BandwidthEstimationSettings::builder()
+            .pacing_gain(0.5)
+            .build()
+            .unwrap();
+        assert_eq!(
+            Some("down=4000000, up=8000".to_string()),
+            estimate.pacing_hint(&settings)
+        );
+        assert_eq!(None, Estimate::default().pacing_hint(&settings));
+    }
+
+    #[test]
+    fn samples_expire() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut estimator = Estimator::default();
+
+        estimator.sample(at(0), WINDOW, 0, 0);
+        estimator.sample(at(1000), WINDOW, 0, 1_000_000);
+        estimator.sample(at(2000), WINDOW, 0, 1_100_000);
+        assert_eq!(Some(8_000_000), estimator.estimate().outbound_bps);
+
+        estimator.sample(at(11_500), WINDOW, 0, 1_100_000);
+        // the peak is out of the window, but the following sample is not
+        assert_eq!(Some(800_000), estimator.estimate().outbound_bps);
+        estimator.sample(at(12_500), WINDOW, 0, 1_100_000);
+        assert_eq!(None, estimator.estimate().outbound_bps);
+    }
+}
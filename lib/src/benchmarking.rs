@@ -0,0 +1,209 @@
+//! The workloads of the relay hot path benchmarks in `bench/micro`.
+//! They stand for the parts of the tunneling which are not reachable through the public API,
+//! the rest is benchmarked on a running endpoint.
+
+use crate::pipe::{DuplexPipe, SimplexDirection};
+use crate::{pipe, sim};
+use bytes::Bytes;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The flow control window of the in-memory streams around a benchmarked pipe
+const PIPE_WINDOW: usize = 256 * 1024;
+/// Big enough to never fire during a benchmark iteration
+const PIPE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Relay `total` bytes in each direction through a [`DuplexPipe`] in chunks of `chunk_size`
+pub async fn pipe_exchange(total: usize, chunk_size: usize) -> io::Result<()> {
+    let ((client_rx, client_tx), (left_rx, left_tx)) = sim::duplex(PIPE_WINDOW);
+    let ((peer_rx, peer_tx), (right_rx, right_tx)) = sim::duplex(PIPE_WINDOW);
+    let mut pipe = DuplexPipe::new(
+        (SimplexDirection::Outgoing, left_rx, right_tx),
+        (SimplexDirection::Incoming, right_rx, left_tx),
+        |_, _| (),
+    );
+
+    let chunk = Bytes::from(vec![0; chunk_size]);
+    let (exchanged, client, peer) = tokio::join!(
+        pipe.exchange(PIPE_TIMEOUT),
+        transfer((client_rx, client_tx), total, chunk.clone()),
+        transfer((peer_rx, peer_tx), total, chunk),
+    );
+    exchanged?;
+    for received in [client?, peer?] {
+        if received != total {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Received {} bytes of {}", received, total),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Send `total` bytes through the stream end while receiving the peer data till the eof
+async fn transfer(
+    (mut source, mut sink): sim::StreamEnd,
+    total: usize,
+    chunk: Bytes,
+) -> io::Result<usize> {
+    let send = async {
+        let mut sent = 0;
+        while sent < total {
+            let n = chunk.len().min(total - sent);
+            sink.write_all(chunk.slice(..n)).await?;
+            sent += n;
+        }
+        sink.eof()
+    };
+    let receive = async {
+        let mut received = 0;
+        while let pipe::Data::Chunk(x) = source.read().await? {
+            source.consume(x.len())?;
+            received += x.len();
+        }
+        Ok(received)
+    };
+
+    let ((), received) = tokio::try_join!(send, receive)?;
+    Ok(received)
+}
+
+/// An established TLS session between an in-memory client and server,
+/// the server side configured like the endpoint TLS listener
+pub struct TlsRecords {
+    client: rustls::Connection,
+    server: rustls::Connection,
+    plaintext: Vec<u8>,
+}
+
+/// The benchmark client trusts any server certificate
+struct NoVerification;
+
+impl TlsRecords {
+    pub fn new(cert_chain: Vec<Certificate>, key: PrivateKey) -> io::Result<Self> {
+        let alpn = vec![crate::net_utils::HTTP2_ALPN.as_bytes().to_vec()];
+
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        server_config.alpn_protocols = alpn.clone();
+
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoVerification))
+            .with_no_client_auth();
+        client_config.alpn_protocols = alpn;
+
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut x = Self {
+            client: rustls::ClientConnection::new(Arc::new(client_config), server_name)
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+                .into(),
+            server: rustls::ServerConnection::new(Arc::new(server_config))
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+                .into(),
+            plaintext: vec![0; 64 * 1024],
+        };
+
+        while x.client.is_handshaking() || x.server.is_handshaking() {
+            move_records(&mut x.client, &mut x.server)?;
+            move_records(&mut x.server, &mut x.client)?;
+        }
+        // Deliver the session tickets
+        move_records(&mut x.server, &mut x.client)?;
+
+        Ok(x)
+    }
+
+    /// Encrypt the data on the client side and decrypt it on the server side.
+    /// Returns the number of the decrypted bytes.
+    pub fn transfer(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut sent = 0;
+        let mut received = 0;
+        let mut records = Vec::new();
+        while received < data.len() {
+            if sent < data.len() {
+                sent += self.client.writer().write(&data[sent..])?;
+            }
+
+            records.clear();
+            while self.client.wants_write() {
+                self.client.write_tls(&mut records)?;
+            }
+
+            let mut pending = records.as_slice();
+            loop {
+                // The server stops accepting the records once its plaintext buffer is full
+                received += self.read_plaintext()?;
+                if pending.is_empty() {
+                    break;
+                }
+                self.server.read_tls(&mut pending)?;
+                self.server
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+        }
+
+        Ok(received)
+    }
+
+    fn read_plaintext(&mut self) -> io::Result<usize> {
+        let mut n = 0;
+        loop {
+            match self.server.reader().read(&mut self.plaintext) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(x) => n += x,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(n),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn move_records(from: &mut rustls::Connection, to: &mut rustls::Connection) -> io::Result<()> {
+    let mut records = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut records)?;
+    }
+
+    let mut pending = records.as_slice();
+    while !pending.is_empty() {
+        to.read_tls(&mut pending)?;
+        to.process_new_packets()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pipe_exchange_delivers_everything() {
+        pipe_exchange(PIPE_WINDOW + 1, 1000).await.unwrap();
+        pipe_exchange(0, 1000).await.unwrap();
+    }
+}
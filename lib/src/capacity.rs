@@ -0,0 +1,321 @@
+//! The attribution of the CPU time and the buffer memory of the endpoint to its subsystems,
+//! so that the resources needed per a number of tunnels may be estimated from the real load
+//! instead of guessing.
+//!
+//! The CPU time is measured around each poll of the futures and the I/O objects of
+//! a [`Category`] with the CPU clock of the thread. The time of the nested polls of another
+//! category is excluded, e.g., the TLS stream polled by an HTTP codec is accounted as
+//! [`Category::Tls`] only. The buffer memory is the size of the buffers held by the pipes
+//! and the codecs themselves; the buffers internal to the TLS and the QUIC libraries
+//! are not included.
+//!
+//! Both are accumulated in the process-wide counters, which are sampled into the metrics
+//! each [`SAMPLE_INTERVAL`]. Measuring is off until the sampling is started, as reading
+//! the thread CPU clock costs a system call per poll.
+
+use crate::metrics::Metrics;
+use crate::{net_utils, stats_history};
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The period of sampling the counters into the metrics
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Category {
+    /// The TLS handshakes and the encryption of the TCP-based tunnels
+    Tls,
+    /// The QUIC packet processing
+    Quic,
+    /// The relaying of the tunneled data between the client and the target sides
+    Pipe,
+    /// The HTTP framing of the tunnels
+    Codec,
+    /// The CPU time of the process not attributed to the categories above,
+    /// e.g., the forwarding and the authentication. Never measured directly.
+    Other,
+}
+
+/// The state of the counters at some moment
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Sample {
+    /// The CPU time consumed by each category, except [`Category::Other`], since the start
+    cpu_time: [Duration; CATEGORIES_NUM],
+    /// The buffer memory held by each category, except [`Category::Other`]
+    buffer_bytes: [i64; CATEGORIES_NUM],
+}
+
+/// Measures the CPU time of each poll of a future
+pub(crate) struct Metered<F> {
+    category: Category,
+    inner: Pin<Box<F>>,
+}
+
+/// Measures the CPU time of each read and write of an I/O object
+pub(crate) struct MeteredIo<IO> {
+    category: Category,
+    inner: IO,
+}
+
+/// The buffer memory of a [`Category`] held by an owner, released on drop
+pub(crate) struct Buffer {
+    category: Category,
+    bytes: usize,
+}
+
+const CATEGORIES_NUM: usize = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CPU_TIME_NANOS: [AtomicU64; CATEGORIES_NUM] = [const { AtomicU64::new(0) }; CATEGORIES_NUM];
+static BUFFER_BYTES: [AtomicI64; CATEGORIES_NUM] = [const { AtomicI64::new(0) }; CATEGORIES_NUM];
+
+thread_local! {
+    /// The CPU time of the measured polls nested in the current one
+    static NESTED_CPU_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+impl Category {
+    /// The categories accumulated in the counters
+    const MEASURED: [Category; CATEGORIES_NUM] = [Self::Tls, Self::Quic, Self::Pipe, Self::Codec];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tls => "tls",
+            Self::Quic => "quic",
+            Self::Pipe => "pipe",
+            Self::Codec => "codec",
+            Self::Other => "other",
+        }
+    }
+
+    fn index(self) -> usize {
+        Self::MEASURED
+            .iter()
+            .position(|x| *x == self)
+            .expect("Category is not measured")
+    }
+}
+
+impl Sample {
+    /// Read the current state of the counters
+    pub fn take() -> Self {
+        Self {
+            cpu_time: std::array::from_fn(|i| {
+                Duration::from_nanos(CPU_TIME_NANOS[i].load(Ordering::Relaxed))
+            }),
+            buffer_bytes: std::array::from_fn(|i| BUFFER_BYTES[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn cpu_time(&self, category: Category) -> Duration {
+        self.cpu_time[category.index()]
+    }
+
+    pub fn buffer_bytes(&self, category: Category) -> i64 {
+        self.buffer_bytes[category.index()]
+    }
+}
+
+/// Start measuring and publish the counters to the `metrics` each [`SAMPLE_INTERVAL`]
+/// until cancelled
+pub(crate) async fn run(metrics: &Metrics) {
+    ENABLED.store(true, Ordering::Relaxed);
+
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    let mut prev = Sample::take();
+    let mut prev_process_cpu_time = stats_history::process_cpu_time();
+    loop {
+        interval.tick().await;
+        let sample = Sample::take();
+        let process_cpu_time = stats_history::process_cpu_time();
+
+        let mut attributed = Duration::ZERO;
+        for x in Category::MEASURED {
+            let cpu_time = sample.cpu_time(x).saturating_sub(prev.cpu_time(x));
+            attributed += cpu_time;
+            metrics.add_capacity_cpu_time(x, cpu_time);
+            metrics.set_capacity_buffer_bytes(x, sample.buffer_bytes(x));
+        }
+        metrics.add_capacity_cpu_time(
+            Category::Other,
+            process_cpu_time
+                .saturating_sub(prev_process_cpu_time)
+                .saturating_sub(attributed),
+        );
+
+        prev = sample;
+        prev_process_cpu_time = process_cpu_time;
+    }
+}
+
+/// Measure the CPU time of each poll of the future `f`
+pub(crate) fn metered<F: Future>(category: Category, f: F) -> Metered<F> {
+    Metered {
+        category,
+        inner: Box::pin(f),
+    }
+}
+
+impl<F: Future> Future for Metered<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let category = self.category;
+        measure(category, || self.inner.as_mut().poll(cx))
+    }
+}
+
+impl<IO> MeteredIo<IO> {
+    pub fn new(category: Category, inner: IO) -> Self {
+        Self { category, inner }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for MeteredIo<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let category = self.category;
+        measure(category, || Pin::new(&mut self.inner).poll_read(cx, buf))
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for MeteredIo<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let category = self.category;
+        measure(category, || Pin::new(&mut self.inner).poll_write(cx, buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let category = self.category;
+        measure(category, || Pin::new(&mut self.inner).poll_flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let category = self.category;
+        measure(category, || Pin::new(&mut self.inner).poll_shutdown(cx))
+    }
+}
+
+impl<IO: net_utils::PeerAddr> net_utils::PeerAddr for MeteredIo<IO> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl Buffer {
+    pub fn new(category: Category) -> Self {
+        Self { category, bytes: 0 }
+    }
+
+    /// Account the `bytes` the owner holds now
+    pub fn set(&mut self, bytes: usize) {
+        if bytes != self.bytes {
+            BUFFER_BYTES[self.category.index()]
+                .fetch_add(bytes as i64 - self.bytes as i64, Ordering::Relaxed);
+            self.bytes = bytes;
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/// Account the CPU time spent in `f` excluding the nested measurements
+fn measure<T>(category: Category, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    let outer_nested = NESTED_CPU_TIME.replace(Duration::ZERO);
+    let start = thread_cpu_time();
+    let result = f();
+    let elapsed = thread_cpu_time().saturating_sub(start);
+    // The enclosing measurement excludes this one as a whole
+    let nested = NESTED_CPU_TIME.replace(outer_nested + elapsed);
+
+    CPU_TIME_NANOS[category.index()].fetch_add(
+        elapsed.saturating_sub(nested).as_nanos() as u64,
+        Ordering::Relaxed,
+    );
+    result
+}
+
+/// The CPU time consumed by the current thread
+fn thread_cpu_time() -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `clock_gettime` only writes to the passed structure
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut now) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spin(time: Duration) {
+        let start = thread_cpu_time();
+        while thread_cpu_time() - start < time {
+            std::hint::black_box(());
+        }
+    }
+
+    #[tokio::test]
+    async fn nested_time_is_excluded() {
+        ENABLED.store(true, Ordering::Relaxed);
+        let before = Sample::take();
+
+        metered(Category::Codec, async {
+            spin(Duration::from_millis(10));
+            metered(Category::Tls, async { spin(Duration::from_millis(50)) }).await;
+        })
+        .await;
+
+        let after = Sample::take();
+        let delta = |x| after.cpu_time(x) - before.cpu_time(x);
+        assert!(delta(Category::Tls) >= Duration::from_millis(50));
+        assert!(delta(Category::Codec) >= Duration::from_millis(10));
+        assert!(delta(Category::Codec) < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn buffers_are_released() {
+        // Nothing else holds the QUIC buffers
+        let bytes = || Sample::take().buffer_bytes(Category::Quic);
+        let mut x = Buffer::new(Category::Quic);
+        let mut y = Buffer::new(Category::Quic);
+        x.set(100);
+        y.set(30);
+        assert_eq!(130, bytes());
+        x.set(40);
+        assert_eq!(70, bytes());
+        drop(y);
+        assert_eq!(40, bytes());
+        drop(x);
+        assert_eq!(0, bytes());
+    }
+}
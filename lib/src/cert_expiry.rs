@@ -0,0 +1,192 @@
+//! The monitoring of the expiration of the loaded certificates. The certificates are checked
+//! periodically, the ones approaching their expiration are reported with the warnings which
+//! escalate to errors as the expiration gets closer, the expiration times are exported as
+//! a metric, and the escalations are published to the event subscribers of the admin interface.
+
+use crate::core;
+use crate::events::Event;
+use crate::settings::CertificateExpirySettings;
+use crate::status_report::CertificateInfo;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How close a certificate is to its expiration
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Stage {
+    Valid,
+    /// Within the warning threshold
+    Expiring,
+    /// Within the last quarter of the warning threshold
+    Critical,
+    Expired,
+}
+
+impl Stage {
+    fn of(remaining_secs: i64, threshold: Duration) -> Self {
+        let threshold = threshold.as_secs() as i64;
+        if remaining_secs <= 0 {
+            Self::Expired
+        } else if remaining_secs <= threshold / 4 {
+            Self::Critical
+        } else if remaining_secs <= threshold {
+            Self::Expiring
+        } else {
+            Self::Valid
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Valid => "valid",
+            Self::Expiring => "expiring",
+            Self::Critical => "critical",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let settings = match context.settings.certificate_expiry.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+
+    let mut interval = tokio::time::interval(settings.check_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut stages = HashMap::new();
+    let check_periodically = async {
+        loop {
+            interval.tick().await;
+            check(&context, settings, &mut stages);
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = check_periodically => Ok(()),
+    }
+}
+
+/// Find an expired certificate among the ones in effect
+pub(crate) fn find_expired(certificates: &[CertificateInfo]) -> Option<&CertificateInfo> {
+    let now = unix_now();
+    certificates
+        .iter()
+        .find(|x| x.not_after.is_some_and(|x| x <= now))
+}
+
+/// Check the certificates in effect. `stages` keeps the stages of the previous check,
+/// so that only the escalations are published as the events.
+fn check(
+    context: &core::Context,
+    settings: &CertificateExpirySettings,
+    stages: &mut HashMap<(&'static str, String), Stage>,
+) {
+    let now = unix_now();
+    // The hosts might have been dropped by a reload
+    context.metrics.reset_certificate_expiry();
+    let mut checked = HashMap::new();
+    for x in context.status_report.certificates() {
+        let not_after = match x.not_after {
+            Some(x) => x,
+            None => continue,
+        };
+        context
+            .metrics
+            .set_certificate_expiry(x.role, &x.hostname, not_after);
+
+        let remaining = not_after - now;
+        let stage = Stage::of(remaining, settings.warning_threshold);
+        match stage {
+            Stage::Valid => (),
+            Stage::Expiring => warn!(
+                "Certificate of {} host {} expires in {} days: {}",
+                x.role,
+                x.hostname,
+                remaining / SECS_PER_DAY,
+                x.path
+            ),
+            Stage::Critical => error!(
+                "Certificate of {} host {} expires in {} hours: {}",
+                x.role,
+                x.hostname,
+                remaining / 3600,
+                x.path
+            ),
+            Stage::Expired => error!(
+                "Certificate of {} host {} expired {} days ago: {}",
+                x.role,
+                x.hostname,
+                -remaining / SECS_PER_DAY,
+                x.path
+            ),
+        }
+
+        let key = (x.role, x.hostname);
+        if stage > stages.get(&key).copied().unwrap_or(Stage::Valid) {
+            context.events.publish(Event::CertificateExpiring {
+                role: key.0,
+                hostname: key.1.clone(),
+                stage: stage.as_str(),
+                expires_in_secs: remaining,
+            });
+        }
+        checked.insert(key, stage);
+    }
+    *stages = checked;
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_escalate() {
+        let threshold = Duration::from_secs(40 * SECS_PER_DAY as u64);
+        let stage = |days: i64| Stage::of(days * SECS_PER_DAY, threshold);
+
+        assert_eq!(Stage::Valid, stage(41));
+        assert_eq!(Stage::Expiring, stage(40));
+        assert_eq!(Stage::Expiring, stage(11));
+        assert_eq!(Stage::Critical, stage(10));
+        assert_eq!(Stage::Critical, stage(1));
+        assert_eq!(Stage::Expired, stage(0));
+        assert_eq!(Stage::Expired, stage(-1));
+    }
+
+    #[test]
+    fn expired_certificates_are_found() {
+        let certificate = |not_after| CertificateInfo {
+            role: "main",
+            hostname: "vpn.example.org".into(),
+            path: "cert.pem".into(),
+            not_after,
+        };
+        let now = unix_now();
+
+        let certificates = [certificate(None), certificate(Some(now + SECS_PER_DAY))];
+        assert!(find_expired(&certificates).is_none());
+
+        let certificates = [certificate(Some(now + 60)), certificate(Some(now - 60))];
+        assert_eq!(
+            Some(now - 60),
+            find_expired(&certificates).and_then(|x| x.not_after)
+        );
+    }
+}
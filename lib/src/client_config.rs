@@ -15,6 +15,18 @@ pub fn build(
         .iter()
         .find(|x| x.username == *client)
         .expect("There is no user config for specified username");
+    assert!(
+        user.password_hash.is_none(),
+        "The password of the user is hashed, so it can't be put into the client config"
+    );
+    assert!(
+        !user.password.is_empty(),
+        "The user is authorized by a client certificate only, so it has no password for the client config"
+    );
+    assert!(
+        user.totp_secret.is_none(),
+        "The user has to append one-time codes to the password, so it can't be put into the client config"
+    );
 
     let host = hostsettings
         .main_hosts
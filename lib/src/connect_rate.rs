@@ -0,0 +1,159 @@
+//! The pacing of the outgoing TCP connections to the same destination address, so that
+//! a burst of the new tunnels to an origin, like the clients reconnecting all at once after
+//! a restart, is not taken for an attack by its anti-DDoS protection getting the egress
+//! addresses of the endpoint banned. Each destination address has a bucket of
+//! [`ConnectRateSettings::burst`] connections refilled at [`ConnectRateSettings::connects_per_sec`].
+//! A connection finding the bucket empty waits for its turn, unless the wait is longer than
+//! [`ConnectRateSettings::max_wait`], in which case it is refused.
+
+use crate::settings::ConnectRateSettings;
+use ring::rand::SecureRandom;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub(crate) struct ConnectRateLimiter {
+    settings: ConnectRateSettings,
+    /// The time the bucket of a destination gets full again keyed by destination.
+    /// The destinations with a full bucket are dropped once the table is full.
+    entries: Mutex<HashMap<IpAddr, Instant>>,
+    random: ring::rand::SystemRandom,
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectRateError {
+    destination: IpAddr,
+    wait: Duration,
+}
+
+impl Display for ConnectRateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Connect rate limit reached for {}, next slot in {:?}",
+            self.destination, self.wait
+        )
+    }
+}
+
+impl ConnectRateLimiter {
+    pub fn new(settings: ConnectRateSettings) -> Self {
+        Self {
+            settings,
+            entries: Default::default(),
+            random: ring::rand::SystemRandom::new(),
+        }
+    }
+
+    /// Take a slot of the connection to the `destination` made at `now`.
+    /// Returns the time to wait before connecting, the jitter included.
+    pub fn reserve(&self, destination: IpAddr, now: Instant) -> Result<Duration, ConnectRateError> {
+        let interval = Duration::from_secs(1) / self.settings.connects_per_sec;
+        let tolerance = interval * (self.settings.burst - 1);
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&destination) && entries.len() >= self.settings.max_destinations {
+            entries.retain(|_, x| *x > now);
+            if entries.len() >= self.settings.max_destinations {
+                debug!("Connect rate table is full, not tracking {}", destination);
+                return Ok(self.jitter());
+            }
+        }
+
+        let full_at = entries.get(&destination).map_or(now, |x| now.max(*x));
+        let wait = full_at.duration_since(now).saturating_sub(tolerance);
+        if wait > self.settings.max_wait {
+            return Err(ConnectRateError { destination, wait });
+        }
+        entries.insert(destination, full_at + interval);
+
+        Ok(wait + self.jitter())
+    }
+
+    /// Get a uniformly distributed delay up to [`ConnectRateSettings::jitter`]
+    fn jitter(&self) -> Duration {
+        if self.settings.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        let mut x = [0; 8];
+        self.random.fill(&mut x).unwrap();
+        let fraction = (u64::from_be_bytes(x) >> 11) as f64 / (1u64 << 53) as f64;
+        self.settings.jitter.mul_f64(fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_destinations: usize) -> ConnectRateLimiter {
+        ConnectRateLimiter::new(
+            ConnectRateSettings::builder()
+                .connects_per_sec(10)
+                .burst(2)
+                .max_wait(Duration::from_millis(250))
+                .max_destinations(max_destinations)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn paces_connections_per_destination() {
+        let limiter = limiter(16);
+        let origin: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        // The burst goes through at once, the following ones are spaced by the interval
+        assert_eq!(ms(0), limiter.reserve(origin, now).unwrap());
+        assert_eq!(ms(0), limiter.reserve(origin, now).unwrap());
+        assert_eq!(ms(100), limiter.reserve(origin, now).unwrap());
+        assert_eq!(ms(200), limiter.reserve(origin, now).unwrap());
+        // The refused connection does not take a slot
+        assert!(limiter.reserve(origin, now).is_err());
+        assert_eq!(ms(200), limiter.reserve(origin, now + ms(100)).unwrap());
+        // The limit is per destination
+        assert_eq!(ms(0), limiter.reserve(other, now).unwrap());
+
+        // The bucket is refilled over time
+        let later = now + Duration::from_secs(1);
+        assert_eq!(ms(0), limiter.reserve(origin, later).unwrap());
+        assert_eq!(ms(0), limiter.reserve(origin, later).unwrap());
+    }
+
+    #[test]
+    fn drops_refilled_destinations_once_full() {
+        let limiter = limiter(1);
+        let origin: IpAddr = "2001:db8::1".parse().unwrap();
+        let other: IpAddr = "2001:db8::2".parse().unwrap();
+        let now = Instant::now();
+
+        limiter.reserve(origin, now).unwrap();
+        // The untracked destination is not limited
+        for _ in 0..5 {
+            assert_eq!(Duration::ZERO, limiter.reserve(other, now).unwrap());
+        }
+        let later = now + Duration::from_secs(1);
+        limiter.reserve(other, later).unwrap();
+        assert!(limiter.entries.lock().unwrap().contains_key(&other));
+        assert!(!limiter.entries.lock().unwrap().contains_key(&origin));
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        let limiter = ConnectRateLimiter::new(
+            ConnectRateSettings::builder()
+                .jitter(Duration::from_millis(50))
+                .build()
+                .unwrap(),
+        );
+        let origin: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..10 {
+            assert!(limiter.reserve(origin, Instant::now()).unwrap() < Duration::from_millis(50));
+        }
+    }
+}
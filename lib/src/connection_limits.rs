@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// Keeps track of the active tunneled connections of the authenticated identities,
+/// so that a single client, e.g., the one with leaked credentials, is not able to
+/// occupy the whole endpoint
+#[derive(Default)]
+pub(crate) struct ConnectionLimiter {
+    /// The number of the active connections keyed by identity.
+    /// The identities without active connections are not kept.
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct LimitError {
+    identity: String,
+    limit: usize,
+}
+
+/// Occupies a connection slot of an identity until dropped
+pub(crate) struct ConnectionPermit {
+    active: Arc<Mutex<HashMap<String, usize>>>,
+    identity: String,
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Connections limit of {} reached for {}",
+            self.limit, self.identity
+        )
+    }
+}
+
+impl ConnectionLimiter {
+    /// Try to occupy a connection slot of the `identity` allowed to have
+    /// up to `limit` active connections
+    pub fn admit(&self, identity: String, limit: usize) -> Result<ConnectionPermit, LimitError> {
+        let mut active = self.active.lock().unwrap();
+        let n = active.entry(identity.clone()).or_default();
+        if *n >= limit {
+            if *n == 0 {
+                active.remove(&identity);
+            }
+            return Err(LimitError { identity, limit });
+        }
+        *n += 1;
+
+        Ok(ConnectionPermit {
+            active: self.active.clone(),
+            identity,
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(n) = active.get_mut(&self.identity) {
+            *n -= 1;
+            if *n == 0 {
+                active.remove(&self.identity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active(limiter: &ConnectionLimiter, identity: &str) -> Option<usize> {
+        limiter.active.lock().unwrap().get(identity).copied()
+    }
+
+    #[test]
+    fn connections_limit() {
+        let limiter = ConnectionLimiter::default();
+
+        let first = limiter.admit("alice".into(), 2).unwrap();
+        let _second = limiter.admit("alice".into(), 2).unwrap();
+        assert!(limiter.admit("alice".into(), 2).is_err());
+        // the limit is per identity
+        let _bob = limiter.admit("bob".into(), 2).unwrap();
+        assert_eq!(active(&limiter, "alice"), Some(2));
+
+        drop(first);
+        assert_eq!(active(&limiter, "alice"), Some(1));
+        assert!(limiter.admit("alice".into(), 2).is_ok());
+
+        assert!(limiter.admit("carol".into(), 0).is_err());
+        assert_eq!(active(&limiter, "carol"), None);
+    }
+}
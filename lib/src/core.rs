@@ -1,29 +1,62 @@
+use crate::accept_rate::AcceptRateLimiter;
+use crate::audit_log::AuditLog;
+use crate::auth_lockout::AuthLockout;
+use crate::authentication::credentials_store::CredentialsStore;
+use crate::authentication::digest::DigestAuth;
+use crate::capacity::Category;
+use crate::connect_rate::ConnectRateLimiter;
+use crate::connection_limits::ConnectionLimiter;
+use crate::custom_forwarder::CustomForwarder;
 use crate::direct_forwarder::DirectForwarder;
+use crate::events::{Event, EventBus};
 use crate::forwarder::Forwarder;
+use crate::hop_health::HopSet;
 use crate::http1_codec::Http1Codec;
 use crate::http2_codec::Http2Codec;
 use crate::http3_codec::Http3Codec;
 use crate::http_codec::HttpCodec;
 use crate::http_downstream::HttpDownstream;
 use crate::icmp_forwarder::IcmpForwarder;
+use crate::instance::Instance;
+use crate::interception::Interceptor;
 use crate::metrics::Metrics;
 use crate::net_utils::PeerAddr;
+use crate::port_blocks::PortBlocks;
+use crate::profiles::ProfileRegistry;
+use crate::proxy_protocol::ProxyProtocol;
 use crate::quic_multiplexer::{QuicMultiplexer, QuicSocket};
+use crate::quotas::QuotaTracker;
+use crate::reconnect_tokens::ReconnectTokens;
+use crate::response_cache::ResponseCache;
+use crate::revocation::RevocationList;
+use crate::rules::LiveRules;
+use crate::schedule::Schedule;
+use crate::sessions::SessionRegistry;
 use crate::settings::{ForwardProtocolSettings, Settings};
 use crate::shutdown::Shutdown;
 use crate::socks5_forwarder::Socks5Forwarder;
+use crate::state_store::StateStore;
+use crate::status_report::StatusReport;
+use crate::tiers::TierRegistry;
 use crate::tls_demultiplexer::TlsDemux;
+use crate::tls_info::TlsInfo;
 use crate::tls_listener::{TlsAcceptor, TlsListener};
+use crate::tls_triage::Triage;
 use crate::tunnel::Tunnel;
+use crate::uplinks::UplinkSet;
+use crate::upstream_tls::UpstreamTls;
 use crate::{
-    authentication, http_ping_handler, http_speedtest_handler, log_id, log_utils, metrics,
-    net_utils, reverse_proxy, rules, settings, tls_demultiplexer, tunnel,
+    audit_log, authentication, bandwidth, capacity, cert_expiry, custom_forwarder, grpc_admin,
+    hop_health, http_ping_handler, http_redirect, http_speedtest_handler, log_id, log_utils,
+    metrics, metrics_sink, net_utils, policy, reverse_proxy, revocation, rules, schedule, settings,
+    statsd, tls_demultiplexer, tunnel,
 };
 use socket2::SockRef;
 use std::io;
 use std::io::ErrorKind;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::watch;
@@ -36,6 +69,27 @@ pub enum Error {
     TlsDemultiplexer(String),
     /// Metrics module initialization failed
     Metrics(String),
+    /// TLS client initialization of an upstream hop failed
+    UpstreamTls(String),
+    /// TLS interception initialization failed
+    Interception(String),
+    /// A loaded certificate has expired, see [`settings::CertificateExpirySettings`]
+    CertificateExpired(String),
+    /// Client certificate verification initialization failed
+    ClientAuth(String),
+    /// The revocation list could not be loaded
+    Revocation(String),
+    /// The state store could not be opened
+    StateStore(String),
+}
+
+/// The order of selecting multiplexed sessions for rebalancing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RebalanceOrder {
+    /// The sessions established earlier are selected first
+    LongestLived,
+    /// The sessions with more in-flight streams are selected first
+    MostLoaded,
 }
 
 pub struct Core {
@@ -79,6 +133,71 @@ pub(crate) struct Context {
     /// Spawned tasks report errors via Context::report_fatal_io_error().
     fatal_error: watch::Sender<Option<FatalIoError>>,
     pub metrics: Arc<Metrics>,
+    /// The active sessions of the quality of service tiers
+    pub tiers: Arc<TierRegistry>,
+    /// The destination profiles the clients are assigned to
+    pub profiles: ProfileRegistry,
+    /// The active tunneled connections of the authenticated identities
+    pub connection_limiter: ConnectionLimiter,
+    /// The lockout of the clients failing to authenticate
+    pub auth_lockout: Option<AuthLockout>,
+    /// The audit trail of the authentication attempts
+    pub audit_log: Option<AuditLog>,
+    /// The revoked client credentials
+    pub revocations: Option<RevocationList>,
+    /// The issuer of the tokens the clients reconnect with
+    pub reconnect_tokens: Option<ReconnectTokens>,
+    /// The issuer of the digest authentication challenges
+    pub digest_auth: Option<DigestAuth>,
+    /// The data transferred by the clients with a quota
+    pub quotas: QuotaTracker,
+    /// The active client tunnels
+    pub sessions: SessionRegistry,
+    /// The filtering and routing rules in effect
+    pub rules: LiveRules,
+    /// The runtime changes of the credentials file
+    pub credentials: Option<CredentialsStore>,
+    /// The state persisted across restarts
+    pub state_store: Option<Arc<StateStore>>,
+    /// The identity of the instance in the telemetry
+    pub instance: Instance,
+    /// The live activity notifications for the admin interface subscribers
+    pub events: EventBus,
+    /// The cache of the reverse-proxied responses
+    pub response_cache: Option<ResponseCache>,
+    /// The source port blocks assigned to the clients
+    pub port_blocks: Option<Arc<PortBlocks>>,
+    /// The pacing of the outgoing TCP connections per destination address
+    pub connect_rate: Option<ConnectRateLimiter>,
+    /// The egress uplinks with their health scores
+    pub uplinks: Option<UplinkSet>,
+    /// The triage of the accepted TCP connections before the TLS handshake
+    pub triage: Option<Triage>,
+    /// The limiting of the rate of the new client connections
+    pub accept_rate: Option<Arc<AcceptRateLimiter>>,
+    /// The acceptance of the PROXY protocol header from the load balancers
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Whether the reverse proxy responds with the maintenance page instead of forwarding
+    /// the requests to the origin server
+    pub maintenance: AtomicBool,
+    /// The state of the scheduled maintenance windows
+    pub schedule: Option<Schedule>,
+    /// The TLS client of the reverse proxy origin server
+    pub reverse_proxy_tls: Option<UpstreamTls>,
+    /// The TLS client of the SOCKS5 proxy
+    pub socks5_tls: Option<UpstreamTls>,
+    /// The TLS client of the upstream HTTP proxy of the direct forwarder
+    pub upstream_http_tls: Option<UpstreamTls>,
+    /// The SOCKS5 proxies with their health state
+    pub socks5_hops: Option<HopSet>,
+    /// The TLS interception of the configured destinations
+    pub interceptor: Option<Interceptor>,
+    /// The verifier of the client certificates of the tunnel connections
+    pub client_cert_verifier: Option<Arc<dyn rustls::server::ClientCertVerifier>>,
+    /// The egress supplied by the embedder instead of the configured forwarding
+    pub custom_forwarder: Option<Arc<dyn custom_forwarder::Forwarder>>,
+    /// The report of the listeners, the certificates and the subsystems
+    pub status_report: StatusReport,
     next_client_id: Arc<AtomicU64>,
     next_tunnel_id: Arc<AtomicU64>,
 }
@@ -87,6 +206,11 @@ impl Context {
     pub(crate) fn report_fatal_io_error(&self, e: &io::Error) {
         let _ = self.fatal_error.send(Some(FatalIoError::from_io_error(e)));
     }
+
+    /// Get the identity of an authenticated client, see [`policy::identity`]
+    pub(crate) fn identity(&self, source: &authentication::Source<'_>) -> Option<String> {
+        policy::identity(self.authenticator.as_deref()?, source)
+    }
 }
 
 impl Core {
@@ -100,21 +224,131 @@ impl Core {
     pub fn new(
         settings: Settings,
         authenticator: Option<Arc<dyn authentication::Authenticator>>,
-        tls_hosts_settings: settings::TlsHostsSettings,
+        mut tls_hosts_settings: settings::TlsHostsSettings,
         shutdown: Arc<Mutex<Shutdown>>,
     ) -> Result<Self, Error> {
         if !settings.is_built() {
             settings.validate().map_err(Error::SettingsValidation)?;
         }
         if !tls_hosts_settings.is_built() {
+            tls_hosts_settings
+                .generate_self_signed()
+                .map_err(Error::SettingsValidation)?;
             tls_hosts_settings
                 .validate()
                 .map_err(Error::SettingsValidation)?;
         }
 
         let settings = Arc::new(settings);
+        let tiers = Arc::new(TierRegistry::new(&settings.tiers));
+        let profiles = ProfileRegistry::new(&settings.profiles);
+        let rules = LiveRules::new(settings.rules_engine.as_ref());
+        let credentials = settings
+            .credentials_file_path()
+            .map(|x| CredentialsStore::new(x.to_string()));
+        let auth_lockout = settings.auth_lockout.clone().map(AuthLockout::new);
+        let revocations = settings
+            .revocation
+            .as_ref()
+            .map(RevocationList::new)
+            .transpose()
+            .map_err(|e| Error::Revocation(e.to_string()))?;
+        let reconnect_tokens = settings.reconnect_tokens.as_ref().map(ReconnectTokens::new);
+        let digest_auth = settings.digest_auth.as_ref().map(DigestAuth::new);
+        let state_store = settings
+            .state_store
+            .as_ref()
+            .map(|x| StateStore::open(&x.path).map(Arc::new))
+            .transpose()
+            .map_err(|e| Error::StateStore(e.to_string()))?;
+        let instance = Instance::new(state_store.as_deref());
+        let audit_log = settings
+            .audit_log
+            .as_ref()
+            .map(|_| AuditLog::new(instance.clone()));
+        let response_cache = settings
+            .reverse_proxy
+            .as_ref()
+            .and_then(|x| x.cache.as_ref())
+            .map(ResponseCache::new);
+        let port_blocks = settings
+            .egress_port_blocks
+            .as_ref()
+            .map(|x| Arc::new(PortBlocks::new(x)));
+        let connect_rate = settings
+            .egress_connect_rate
+            .clone()
+            .map(ConnectRateLimiter::new);
+        let uplinks = settings.egress_uplinks.as_ref().map(UplinkSet::new);
+        let triage = settings.triage.clone().map(Triage::new);
+        let accept_rate = settings
+            .accept_rate
+            .clone()
+            .map(|x| Arc::new(AcceptRateLimiter::new(x, state_store.clone())));
+        let proxy_protocol = settings.proxy_protocol.as_ref().map(ProxyProtocol::new);
+        let maintenance = settings
+            .reverse_proxy
+            .as_ref()
+            .is_some_and(|x| x.maintenance);
+        let schedule = settings.schedule.as_ref().map(|_| Schedule::default());
+        let reverse_proxy_tls = settings
+            .reverse_proxy
+            .as_ref()
+            .and_then(|x| x.tls.as_ref())
+            .map(|x| UpstreamTls::new(x, "reverse proxy origin server"))
+            .transpose()
+            .map_err(|e| Error::UpstreamTls(format!("Reverse proxy: {}", e)))?;
+        let socks5_tls = match &settings.forward_protocol {
+            ForwardProtocolSettings::Socks5(x) => x
+                .tls
+                .as_ref()
+                .map(|x| UpstreamTls::new(x, "SOCKS5 proxy"))
+                .transpose()
+                .map_err(|e| Error::UpstreamTls(format!("SOCKS5 proxy: {}", e)))?,
+            _ => None,
+        };
+        let upstream_http_tls = match &settings.forward_protocol {
+            ForwardProtocolSettings::Direct(x) => x
+                .upstream_http
+                .as_ref()
+                .and_then(|x| x.tls.as_ref())
+                .map(|x| UpstreamTls::new(x, "upstream HTTP proxy"))
+                .transpose()
+                .map_err(|e| Error::UpstreamTls(format!("Upstream HTTP proxy: {}", e)))?,
+            _ => None,
+        };
+        let socks5_hops = match &settings.forward_protocol {
+            ForwardProtocolSettings::Socks5(x) => Some(HopSet::new(x)),
+            _ => None,
+        };
+
+        let interceptor = settings
+            .interception
+            .as_ref()
+            .map(Interceptor::new)
+            .transpose()
+            .map_err(|e| Error::Interception(e.to_string()))?;
+        let client_cert_verifier = settings
+            .client_auth
+            .as_ref()
+            .map(crate::tls_listener::client_cert_verifier)
+            .transpose()
+            .map_err(|e| Error::ClientAuth(e.to_string()))?;
 
         let (fatal_error, _fatal_error_rx) = watch::channel(None);
+        let status_report = StatusReport::new(&settings, &tls_hosts_settings, &instance);
+        if settings
+            .certificate_expiry
+            .as_ref()
+            .is_some_and(|x| x.refuse_expired)
+        {
+            if let Some(x) = cert_expiry::find_expired(&status_report.certificates()) {
+                return Err(Error::CertificateExpired(format!(
+                    "{} host {}: {}",
+                    x.role, x.hostname, x.path
+                )));
+            }
+        }
 
         Ok(Self {
             context: Arc::new(Context {
@@ -132,12 +366,66 @@ impl Core {
                 shutdown,
                 fatal_error,
                 metrics: Metrics::new().map_err(|e| Error::Metrics(e.to_string()))?,
+                tiers,
+                profiles,
+                connection_limiter: Default::default(),
+                auth_lockout,
+                audit_log,
+                revocations,
+                reconnect_tokens,
+                digest_auth,
+                quotas: QuotaTracker::new(state_store.clone()),
+                sessions: Default::default(),
+                rules,
+                credentials,
+                state_store,
+                instance,
+                events: Default::default(),
+                response_cache,
+                port_blocks,
+                connect_rate,
+                uplinks,
+                triage,
+                accept_rate,
+                proxy_protocol,
+                maintenance: AtomicBool::new(maintenance),
+                schedule,
+                reverse_proxy_tls,
+                socks5_tls,
+                upstream_http_tls,
+                socks5_hops,
+                interceptor,
+                client_cert_verifier,
+                custom_forwarder: None,
+                status_report,
                 next_client_id: Default::default(),
                 next_tunnel_id: Default::default(),
             }),
         })
     }
 
+    /// Forward the client connections through the `forwarder` instead of
+    /// the forwarding configured in the settings. Must be called before [`Core::listen`].
+    pub fn with_forwarder(mut self, forwarder: Arc<dyn custom_forwarder::Forwarder>) -> Self {
+        Arc::get_mut(&mut self.context)
+            .expect("Core is not listening yet")
+            .custom_forwarder = Some(forwarder);
+        self
+    }
+
+    /// Report the metrics to the `sink` along with the built-in Prometheus registry.
+    /// Must be called before [`Core::listen`].
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn metrics_sink::MetricsSink>) -> Self {
+        Arc::get_mut(
+            &mut Arc::get_mut(&mut self.context)
+                .expect("Core is not listening yet")
+                .metrics,
+        )
+        .expect("Metrics are not shared yet")
+        .add_sink(sink);
+        self
+    }
+
     /// Run an endpoint instance inside the caller provided asynchronous runtime.
     pub async fn listen(&self) -> io::Result<()> {
         let listen_tcp = async {
@@ -164,6 +452,68 @@ impl Core {
                 .map_err(|e| io::Error::new(e.kind(), format!("Metrics listener failure: {}", e)))
         };
 
+        let listen_http_redirect = async {
+            http_redirect::listen(self.context.clone(), log_utils::IdChain::empty())
+                .await
+                .map_err(|e| {
+                    io::Error::new(e.kind(), format!("HTTP redirect listener failure: {}", e))
+                })
+        };
+
+        let export_statsd = async {
+            statsd::run(self.context.clone())
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Statsd exporter failure: {}", e)))
+        };
+
+        let listen_grpc_admin = async {
+            grpc_admin::listen(self.context.clone()).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("gRPC admin listener failure: {}", e))
+            })
+        };
+
+        let probe_upstream_hops = async {
+            hop_health::run(self.context.clone()).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("Upstream hop probing failure: {}", e))
+            })
+        };
+
+        let run_schedule = async {
+            schedule::run(self.context.clone()).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("Maintenance scheduler failure: {}", e))
+            })
+        };
+
+        let monitor_certificates = async {
+            cert_expiry::run(self.context.clone()).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("Certificate monitoring failure: {}", e))
+            })
+        };
+
+        let sample_bandwidth = async {
+            bandwidth::run(self.context.clone()).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("Bandwidth estimation failure: {}", e))
+            })
+        };
+
+        let write_audit_log = async {
+            audit_log::run(self.context.clone())
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Audit log failure: {}", e)))
+        };
+
+        let reload_revocations = async {
+            revocation::run(self.context.clone())
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Revocation list failure: {}", e)))
+        };
+
+        let checkpoint_state = async {
+            self.checkpoint_state_periodically()
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("State store failure: {}", e)))
+        };
+
         let (mut shutdown_notification, _shutdown_completion) = {
             let shutdown = self.context.shutdown.lock().unwrap();
             (
@@ -175,8 +525,12 @@ impl Core {
         };
 
         let mut fatal_error_rx = self.context.fatal_error.subscribe();
+        self.context
+            .metrics
+            .set_instance_info(&self.context.instance);
+        self.context.status_report.flush();
 
-        tokio::select! {
+        let result = tokio::select! {
             x = shutdown_notification.wait() => {
                 x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
             },
@@ -187,35 +541,101 @@ impl Core {
                 },
                 Err(_) => Err(io::Error::new(ErrorKind::Other, "Fatal error channel is unexpectedly closed")),
             },
-            x = futures::future::try_join4(
-                listen_tcp,
-                listen_udp,
-                listen_icmp,
-                listen_metrics,
-            ) => x.map(|_| ()),
+            x = async {
+                tokio::try_join!(
+                    listen_tcp,
+                    listen_udp,
+                    listen_icmp,
+                    listen_metrics,
+                    export_statsd,
+                    listen_http_redirect,
+                    listen_grpc_admin,
+                    probe_upstream_hops,
+                    checkpoint_state,
+                    run_schedule,
+                    monitor_certificates,
+                    write_audit_log,
+                    reload_revocations,
+                    sample_bandwidth,
+                )
+            } => x.map(|_| ()),
+        };
+
+        if let Some(store) = self.context.state_store.clone() {
+            if let Err(e) = tokio::task::spawn_blocking(move || store.checkpoint())
+                .await
+                .unwrap_or_else(|e| Err(io::Error::new(ErrorKind::Other, e)))
+            {
+                error!("Failed to checkpoint the state on exit: {}", e);
+            }
         }
+
+        result
+    }
+
+    /// The store of the state persisted across restarts, if configured
+    pub fn state_store(&self) -> Option<Arc<StateStore>> {
+        self.context.state_store.clone()
     }
 
     /// Reload the TLS hosts settings
     pub fn reload_tls_hosts_settings(
         &self,
-        settings: settings::TlsHostsSettings,
+        mut settings: settings::TlsHostsSettings,
     ) -> io::Result<()> {
         let mut demux = self.context.tls_demux.write().unwrap();
 
         if !settings.is_built() {
-            settings.validate().map_err(|e| {
-                io::Error::new(
-                    ErrorKind::Other,
-                    format!("Settings validation failure: {:?}", e),
-                )
-            })?;
+            settings
+                .generate_self_signed()
+                .and_then(|_| settings.validate())
+                .map_err(|e| {
+                    io::Error::new(
+                        ErrorKind::Other,
+                        format!("Settings validation failure: {:?}", e),
+                    )
+                })?;
         }
 
         *demux = TlsDemux::new(&self.context.settings, &settings)?;
+        drop(demux);
+        self.context.status_report.tls_hosts_reloaded(&settings);
         Ok(())
     }
 
+    /// Ask up to `count` HTTP/2 and HTTP/3 sessions to shut down gracefully, so that
+    /// the clients reconnect, possibly to another endpoint of a cluster.
+    /// The sessions stop accepting new streams (GOAWAY) and are closed after
+    /// the in-flight ones are finished.
+    ///
+    /// # Return
+    ///
+    /// The number of the sessions asked to shut down
+    pub fn rebalance_sessions(&self, count: usize, order: RebalanceOrder) -> usize {
+        self.context.sessions.rebalance(count, order)
+    }
+
+    async fn checkpoint_state_periodically(&self) -> io::Result<()> {
+        let (store, settings) = match (
+            self.context.state_store.as_ref(),
+            self.context.settings.state_store.as_ref(),
+        ) {
+            (Some(store), Some(settings)) => (store, settings),
+            _ => return Ok(()),
+        };
+
+        let mut interval = tokio::time::interval(settings.checkpoint_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || store.checkpoint())
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+                .unwrap_or_else(|e| error!("Failed to checkpoint the state: {}", e));
+        }
+    }
+
     async fn listen_tcp(&self) -> io::Result<()> {
         let settings = self.context.settings.clone();
         let has_tcp_based_codec =
@@ -223,6 +643,9 @@ impl Core {
 
         let tcp_listener = TcpListener::bind(settings.listen_address).await?;
         info!("Listening to TCP {}", settings.listen_address);
+        self.context
+            .status_report
+            .listener_bound("tunnel", "tcp", tcp_listener.local_addr()?);
 
         let tls_listener = Arc::new(TlsListener::new());
         loop {
@@ -231,7 +654,7 @@ impl Core {
                 self.context.next_client_id.fetch_add(1, Ordering::Relaxed),
             ));
             log_id!(trace, client_id, "Accepting TCP connection");
-            let (stream, client_addr) = match tcp_listener.accept().await.and_then(|(s, a)| {
+            let (mut stream, client_addr) = match tcp_listener.accept().await.and_then(|(s, a)| {
                 s.set_nodelay(true)?;
 
                 // Enable TCP keepalive to detect broken connections.
@@ -240,6 +663,19 @@ impl Core {
                 Ok((s, a))
             }) {
                 Ok((stream, addr)) => {
+                    if self.context.tiers.is_overloaded() {
+                        log_id!(trace, client_id, "Shedding TCP client: {}", addr);
+                        continue;
+                    }
+                    // The connections of the balancers are limited by the conveyed addresses
+                    let is_proxied = self
+                        .context
+                        .proxy_protocol
+                        .as_ref()
+                        .is_some_and(|x| x.is_trusted(addr.ip()));
+                    if !is_proxied && Self::is_rate_limited(&self.context, addr, &client_id) {
+                        continue;
+                    }
                     if has_tcp_based_codec {
                         log_id!(debug, client_id, "New TCP client: {}", addr);
                         (stream, addr)
@@ -257,11 +693,70 @@ impl Core {
                 let context = self.context.clone();
                 let tls_listener = tls_listener.clone();
                 async move {
+                    let client_addr = match &context.proxy_protocol {
+                        Some(x) if x.is_trusted(client_addr.ip()) => {
+                            match x.read_header(&mut stream).await {
+                                Ok(Some(addr)) => {
+                                    log_id!(
+                                        debug,
+                                        client_id,
+                                        "PROXY protocol client: {} via {}",
+                                        addr,
+                                        client_addr
+                                    );
+                                    if Self::is_rate_limited(&context, addr, &client_id) {
+                                        return;
+                                    }
+                                    addr
+                                }
+                                Ok(None) => client_addr,
+                                Err(e) => {
+                                    log_id!(
+                                        debug,
+                                        client_id,
+                                        "Failed to read PROXY protocol header: {}",
+                                        e
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                        _ => client_addr,
+                    };
+
                     log_id!(trace, client_id, "Starting TLS handshake");
-                    let handshake_timeout = context.settings.tls_handshake_timeout;
-                    match tokio::time::timeout(handshake_timeout, tls_listener.listen(stream))
-                        .await
-                        .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+                    // The protocol is not negotiated yet
+                    let handshake_timeout = context
+                        .settings
+                        .timeouts(tls_demultiplexer::Protocol::Http1)
+                        .tls_handshake
+                        .max(
+                            context
+                                .settings
+                                .timeouts(tls_demultiplexer::Protocol::Http2)
+                                .tls_handshake,
+                        );
+                    let stream = match &context.triage {
+                        None => stream,
+                        Some(x) => match x
+                            .triage(
+                                stream,
+                                context.settings.listen_address.port(),
+                                handshake_timeout,
+                                &client_id,
+                            )
+                            .await
+                        {
+                            Some(x) => x,
+                            None => return,
+                        },
+                    };
+                    match tokio::time::timeout(
+                        handshake_timeout,
+                        capacity::metered(Category::Tls, tls_listener.listen(stream, client_addr)),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
                     {
                         Ok(acceptor) => {
                             log_id!(
@@ -287,6 +782,32 @@ impl Core {
         }
     }
 
+    /// Check the new connection of the client at the `addr` against the accept rate limits
+    fn is_rate_limited(
+        context: &Context,
+        addr: std::net::SocketAddr,
+        client_id: &log_utils::IdChain<u64>,
+    ) -> bool {
+        let refusal = context
+            .accept_rate
+            .as_ref()
+            .and_then(|x| x.accept(addr.ip(), Instant::now()).err());
+        match refusal {
+            None => false,
+            Some(x) => {
+                log_id!(
+                    trace,
+                    client_id,
+                    "Rate limited TCP client: {}, {:?}",
+                    addr,
+                    x
+                );
+                context.metrics.add_rate_limited_connection(x);
+                true
+            }
+        }
+    }
+
     async fn listen_udp(&self) -> io::Result<()> {
         let settings = self.context.settings.clone();
         if settings.listen_protocols.quic.is_none() {
@@ -295,16 +816,22 @@ impl Core {
 
         let socket = UdpSocket::bind(settings.listen_address).await?;
         info!("Listening to UDP {}", settings.listen_address);
+        self.context
+            .status_report
+            .listener_bound("tunnel", "udp", socket.local_addr()?);
 
         let mut quic_listener = QuicMultiplexer::new(
             settings,
             socket,
             self.context.tls_demux.clone(),
             self.context.next_client_id.clone(),
+            self.context.metrics.clone(),
+            self.context.accept_rate.clone(),
+            self.context.tiers.clone(),
         )?;
 
         loop {
-            let socket = quic_listener.listen().await?;
+            let socket = capacity::metered(Category::Quic, quic_listener.listen()).await?;
 
             tokio::spawn({
                 let context = self.context.clone();
@@ -398,12 +925,23 @@ impl Core {
             tls_connection_meta.protocol
         );
         let stream = match tokio::time::timeout(
-            context.settings.tls_handshake_timeout,
-            acceptor.accept(
-                tls_connection_meta.protocol,
-                tls_connection_meta.cert_chain,
-                tls_connection_meta.key,
-                &client_id,
+            context
+                .settings
+                .timeouts(tls_connection_meta.protocol)
+                .tls_handshake,
+            capacity::metered(
+                Category::Tls,
+                acceptor.accept(
+                    tls_connection_meta.protocol,
+                    tls_connection_meta.cert_chain,
+                    tls_connection_meta.key,
+                    // The other channels serve the clients without the certificates
+                    match tls_connection_meta.channel {
+                        net_utils::Channel::Tunnel => context.client_cert_verifier.clone(),
+                        _ => None,
+                    },
+                    &client_id,
+                ),
             ),
         )
         .await
@@ -436,6 +974,17 @@ impl Core {
                     context.next_tunnel_id.fetch_add(1, Ordering::Relaxed),
                 ));
                 log_id!(trace, tunnel_id, "Creating tunnel");
+                let client_cert = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .map(|x| x.iter().map(|x| x.0.clone()).collect::<Vec<_>>());
+                let tls = Self::on_tls_handshake(
+                    &context,
+                    TlsInfo::from_rustls(stream.get_ref().1),
+                    &tls_connection_meta,
+                    &tunnel_id,
+                );
                 Self::on_tunnel_request(
                     context,
                     tls_connection_meta.protocol,
@@ -452,13 +1001,16 @@ impl Core {
                     },
                     tls_connection_meta.sni,
                     tls_connection_meta.sni_auth_creds,
+                    client_cert,
+                    Some(tls),
+                    Some(client_ip),
                     tunnel_id,
                 )
                 .await
             }
             net_utils::Channel::Ping => {
                 http_ping_handler::listen(
-                    context.shutdown.clone(),
+                    context.clone(),
                     match Self::make_tcp_http_codec(
                         tls_connection_meta.protocol,
                         core_settings,
@@ -470,7 +1022,9 @@ impl Core {
                             return Err((client_id, format!("Failed to create HTTP codec: {}", e)))
                         }
                     },
-                    context.settings.tls_handshake_timeout,
+                    context
+                        .settings
+                        .handler_request_timeout(tls_connection_meta.protocol),
                     client_id,
                 )
                 .await
@@ -489,7 +1043,9 @@ impl Core {
                             return Err((client_id, format!("Failed to create HTTP codec: {}", e)))
                         }
                     },
-                    context.settings.tls_handshake_timeout,
+                    context
+                        .settings
+                        .handler_request_timeout(tls_connection_meta.protocol),
                     client_id,
                 )
                 .await
@@ -554,6 +1110,12 @@ impl Core {
 
                 let sni = tls_connection_meta.sni.clone();
                 let sni_auth_creds = tls_connection_meta.sni_auth_creds.clone();
+                let tls = Self::on_tls_handshake(
+                    &context,
+                    socket.tls_info().clone(),
+                    tls_connection_meta,
+                    &tunnel_id,
+                );
 
                 Self::on_tunnel_request(
                     context,
@@ -561,15 +1123,20 @@ impl Core {
                     Box::new(Http3Codec::new(socket, tunnel_id.clone())),
                     sni,
                     sni_auth_creds,
+                    None,
+                    Some(tls),
+                    client_ip,
                     tunnel_id,
                 )
                 .await
             }
             net_utils::Channel::Ping => {
                 http_ping_handler::listen(
-                    context.shutdown.clone(),
+                    context.clone(),
                     Box::new(Http3Codec::new(socket, client_id.clone())),
-                    context.settings.tls_handshake_timeout,
+                    context
+                        .settings
+                        .handler_request_timeout(tls_demultiplexer::Protocol::Http3),
                     client_id,
                 )
                 .await
@@ -578,7 +1145,9 @@ impl Core {
                 http_speedtest_handler::listen(
                     context.shutdown.clone(),
                     Box::new(Http3Codec::new(socket, client_id.clone())),
-                    context.settings.tls_handshake_timeout,
+                    context
+                        .settings
+                        .handler_request_timeout(tls_demultiplexer::Protocol::Http3),
                     client_id,
                 )
                 .await
@@ -604,21 +1173,28 @@ impl Core {
         client_random: Option<&[u8]>,
         log_id: &log_utils::IdChain<u64>,
     ) -> Result<(), String> {
-        if let Some(rules_engine) = &context.settings.rules_engine {
+        if context.settings.rules_engine.is_some() {
             if let Some(ip) = client_ip {
-                let rule_result = rules_engine.evaluate(&ip, client_random);
+                let (rule_result, found) =
+                    context.rules.current().evaluate_traced(&ip, client_random);
                 match rule_result {
                     rules::RuleEvaluation::Deny => {
                         log_id!(
                             debug,
                             log_id,
-                            "Connection denied by filtering rules for IP: {}",
-                            ip
+                            "Connection denied by filtering rules for IP: {} ({:?})",
+                            ip,
+                            found
                         );
                         return Err("Connection denied by filtering rules".to_string());
                     }
                     rules::RuleEvaluation::Allow => {
-                        log_id!(debug, log_id, "Connection allowed by filtering rules");
+                        log_id!(
+                            debug,
+                            log_id,
+                            "Connection allowed by filtering rules ({:?})",
+                            found
+                        );
                     }
                 }
             } else {
@@ -632,26 +1208,135 @@ impl Core {
         Ok(())
     }
 
-    async fn on_tunnel_request(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn on_tunnel_request(
         context: Arc<Context>,
         protocol: tls_demultiplexer::Protocol,
         codec: Box<dyn HttpCodec>,
         server_name: String,
         sni_auth_creds: Option<String>,
+        client_cert: Option<Vec<Vec<u8>>>,
+        tls: Option<TlsInfo>,
+        client_ip: Option<std::net::IpAddr>,
         tunnel_id: log_utils::IdChain<u64>,
     ) {
         let _metrics_guard = Metrics::client_sessions_counter(context.metrics.clone(), protocol);
+        let session = context.sessions.register(protocol);
+        let session_permit = context.tiers.open_session();
+        let session_id = session.id();
+        let started_at = Instant::now();
+        context.events.publish(Event::SessionOpened {
+            session: session_id,
+            protocol,
+            server_name: server_name.clone(),
+        });
+        let publish_closed = || {
+            context.events.publish(Event::SessionClosed {
+                session: session_id,
+                duration: started_at.elapsed(),
+            })
+        };
 
-        let authentication_policy = match context.authenticator.as_ref().zip(sni_auth_creds) {
+        // The client certificate takes precedence over the SNI credentials
+        let credentials = match (client_cert, sni_auth_creds) {
+            (Some(x), _) => Some(authentication::Source::ClientCert(x.into())),
+            (None, Some(x)) => Some(authentication::Source::Sni(x.into())),
+            (None, None) => None,
+        };
+        let audit = |source: &authentication::Source<'_>, outcome| {
+            if let Some(x) = &context.audit_log {
+                x.record(audit_log::Attempt {
+                    log_id: &tunnel_id,
+                    client_ip,
+                    source: Some(source),
+                    server_name: Some(&server_name),
+                    tls: tls.as_ref(),
+                    outcome,
+                });
+            }
+        };
+        let authentication_policy = match context.authenticator.as_ref().zip(credentials) {
             None => tunnel::AuthenticationPolicy::Default,
-            Some((authenticator, credentials)) => {
-                let auth = authentication::Source::Sni(credentials.into());
-                match authenticator.authenticate(&auth, &tunnel_id) {
+            Some((authenticator, auth)) => {
+                let lockout = context.auth_lockout.as_ref().zip(client_ip);
+                let username = auth.username();
+                if let Some(x) = lockout.and_then(|(x, ip)| x.locked(ip, username.as_deref())) {
+                    log_id!(debug, tunnel_id, "Client is locked out for {:?}", x);
+                    audit(&auth, audit_log::Outcome::LockedOut);
+                    context.events.publish(Event::AuthFailure {
+                        session: session_id,
+                        username,
+                    });
+                    publish_closed();
+                    return;
+                }
+                if context
+                    .revocations
+                    .as_ref()
+                    .is_some_and(|x| x.is_revoked(authenticator.as_ref(), &auth))
+                {
+                    log_id!(debug, tunnel_id, "Client credentials are revoked");
+                    audit(&auth, audit_log::Outcome::Revoked);
+                    context.events.publish(Event::AuthFailure {
+                        session: session_id,
+                        username,
+                    });
+                    publish_closed();
+                    return;
+                }
+                let status = match authenticator.authenticate(&auth, &tunnel_id) {
+                    authentication::Status::Pass
+                        if tls
+                            .as_ref()
+                            .is_some_and(|x| !authenticator.accept_tls(&auth, x)) =>
+                    {
+                        log_id!(debug, tunnel_id, "TLS handshake is not accepted");
+                        authentication::Status::Reject
+                    }
+                    x => x,
+                };
+                match status {
                     authentication::Status::Pass => {
+                        if let Some((x, _)) = lockout {
+                            x.passed(username.as_deref());
+                        }
+                        audit(&auth, audit_log::Outcome::Pass);
                         tunnel::AuthenticationPolicy::Authenticated(auth)
                     }
+                    // An optional certificate unknown to the authenticator leaves
+                    // the requests to be authenticated by their proxy authorization
+                    authentication::Status::Reject
+                        if auth.client_cert().is_some()
+                            && !context
+                                .settings
+                                .client_auth
+                                .as_ref()
+                                .is_some_and(|x| x.required) =>
+                    {
+                        log_id!(debug, tunnel_id, "Client certificate is not authorized");
+                        audit(&auth, audit_log::Outcome::Reject);
+                        tunnel::AuthenticationPolicy::Default
+                    }
                     authentication::Status::Reject => {
-                        log_id!(debug, tunnel_id, "SNI authentication failed");
+                        log_id!(
+                            debug,
+                            tunnel_id,
+                            "{} authentication failed",
+                            if auth.client_cert().is_some() {
+                                "Client certificate"
+                            } else {
+                                "SNI"
+                            }
+                        );
+                        if let Some((x, ip)) = lockout {
+                            x.failed(ip, username.as_deref());
+                        }
+                        audit(&auth, audit_log::Outcome::Reject);
+                        context.events.publish(Event::AuthFailure {
+                            session: session_id,
+                            username,
+                        });
+                        publish_closed();
                         return;
                     }
                 }
@@ -662,16 +1347,37 @@ impl Core {
         let mut tunnel = Tunnel::new(
             context.clone(),
             Box::new(HttpDownstream::new(context.clone(), codec, server_name)),
-            Self::make_forwarder(context),
+            Self::make_forwarder(context.clone()),
             authentication_policy,
+            session,
+            session_permit,
+            tls,
             tunnel_id.clone(),
         );
 
         log_id!(trace, tunnel_id, "Listening for client tunnel");
-        match tunnel.listen().await {
+        match capacity::metered(Category::Codec, tunnel.listen()).await {
             Ok(_) => log_id!(debug, tunnel_id, "Tunnel stopped gracefully"),
             Err(e) => log_id!(debug, tunnel_id, "Tunnel stopped with error: {}", e),
         }
+        drop(tunnel);
+        publish_closed();
+    }
+
+    /// Account the TLS handshake of a tunnel connection, scrubbing the SNI credentials
+    /// from the server name
+    fn on_tls_handshake(
+        context: &Context,
+        mut tls: TlsInfo,
+        meta: &tls_demultiplexer::ConnectionMeta,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> TlsInfo {
+        if meta.sni_auth_creds.is_some() {
+            tls.server_name = tls.server_name.map(net_utils::scrub_sni);
+        }
+        log_id!(debug, log_id, "TLS handshake: {}", tls);
+        context.metrics.add_tls_handshake(&tls);
+        tls
     }
 
     fn make_tcp_http_codec<IO>(
@@ -683,6 +1389,8 @@ impl Core {
     where
         IO: 'static + AsyncRead + AsyncWrite + Unpin + Send + PeerAddr,
     {
+        // The transport of the TCP-based codecs is a TLS stream
+        let io = capacity::MeteredIo::new(Category::Tls, io);
         match protocol {
             tls_demultiplexer::Protocol::Http1 => {
                 Ok(Box::new(Http1Codec::new(core_settings, io, log_id)))
@@ -695,6 +1403,10 @@ impl Core {
     }
 
     fn make_forwarder(context: Arc<Context>) -> Box<dyn Forwarder> {
+        if let Some(x) = &context.custom_forwarder {
+            return Box::new(CustomForwarder::new(x.clone()));
+        }
+
         match &context.settings.forward_protocol {
             ForwardProtocolSettings::Direct(_) => Box::new(DirectForwarder::new(context)),
             ForwardProtocolSettings::Socks5(_) => Box::new(Socks5Forwarder::new(context)),
@@ -707,6 +1419,7 @@ impl Default for Context {
     fn default() -> Self {
         let settings = Arc::new(Settings::default());
         let (fatal_error, _fatal_error_rx) = watch::channel(None);
+        let instance = Instance::new(None);
         Self {
             settings: settings.clone(),
             authenticator: None,
@@ -717,6 +1430,42 @@ impl Default for Context {
             shutdown: Shutdown::new(),
             fatal_error,
             metrics: Metrics::new().unwrap(),
+            tiers: Arc::new(TierRegistry::new(&settings.tiers)),
+            profiles: ProfileRegistry::new(&settings.profiles),
+            connection_limiter: Default::default(),
+            auth_lockout: None,
+            audit_log: None,
+            revocations: None,
+            reconnect_tokens: None,
+            digest_auth: None,
+            quotas: QuotaTracker::new(None),
+            sessions: Default::default(),
+            rules: LiveRules::new(settings.rules_engine.as_ref()),
+            credentials: None,
+            state_store: None,
+            instance: instance.clone(),
+            events: Default::default(),
+            response_cache: None,
+            port_blocks: None,
+            connect_rate: None,
+            uplinks: None,
+            triage: None,
+            accept_rate: None,
+            proxy_protocol: None,
+            maintenance: Default::default(),
+            schedule: None,
+            reverse_proxy_tls: None,
+            socks5_tls: None,
+            upstream_http_tls: None,
+            socks5_hops: None,
+            interceptor: None,
+            client_cert_verifier: None,
+            custom_forwarder: None,
+            status_report: StatusReport::new(
+                &settings,
+                &settings::TlsHostsSettings::default(),
+                &instance,
+            ),
             next_client_id: Default::default(),
             next_tunnel_id: Default::default(),
         }
@@ -0,0 +1,432 @@
+//! The extension point for the embedders supplying their own egress, like a serial link,
+//! a QUIC tunnel to another host or a test double. A [`Forwarder`] passed to
+//! [`crate::core::Core::with_forwarder`] replaces the forwarding configured in the settings.
+
+use crate::net_utils::TcpDestination;
+use crate::{
+    authentication, datagram_pipe, downstream, forwarder, log_id, log_utils, pipe, tunnel,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The number of the datagrams received from the flows and not yet read by the tunnel
+const UDP_QUEUE_SIZE: usize = 64;
+
+/// A request of a client for a TCP connection
+#[derive(Debug, Clone)]
+pub struct TcpRequest {
+    /// Address of the VPN client made the connection request
+    pub client_address: IpAddr,
+    /// Destination address of the connection, the host names are resolved by the forwarder
+    pub destination: TcpDestination,
+    /// Authentication request source
+    pub auth: Option<authentication::Source<'static>>,
+    /// The domain name used for TLS session (SNI)
+    pub tls_domain: String,
+    /// May contain a platform name of the VPN client and name of the application
+    /// initiated the request
+    pub user_agent: Option<String>,
+}
+
+/// A request of a client for a UDP flow, that is the datagrams between a pair of addresses
+#[derive(Debug, Clone)]
+pub struct UdpRequest {
+    /// Address of the VPN client made the request
+    pub client_address: IpAddr,
+    /// The source address of the datagrams on the client side
+    pub source: SocketAddr,
+    /// The peer address
+    pub destination: SocketAddr,
+    /// Authentication request source
+    pub auth: Option<authentication::Source<'static>>,
+    /// The domain name used for TLS session (SNI)
+    pub tls_domain: String,
+    /// May contain a platform name of the VPN client
+    pub user_agent: Option<String>,
+}
+
+/// An abstract interface for an egress implementation
+#[async_trait]
+pub trait Forwarder: Send + Sync {
+    /// Establish a TCP connection to the peer
+    async fn connect_tcp(
+        &self,
+        id: log_utils::IdChain<u64>,
+        request: TcpRequest,
+    ) -> io::Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>)>;
+
+    /// Open a UDP flow to the peer. Each chunk is a datagram: the source must yield
+    /// a received datagram in whole, and the sink is expected to take a datagram in whole
+    /// once it is writable. The flow is closed on an error or the eof from the source.
+    /// The default implementation does not support UDP.
+    async fn connect_udp(
+        &self,
+        _id: log_utils::IdChain<u64>,
+        _request: UdpRequest,
+    ) -> io::Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>)> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "UDP is not supported by the forwarder",
+        ))
+    }
+}
+
+/// Adapts a [`Forwarder`] to the tunnel
+pub(crate) struct CustomForwarder {
+    inner: Arc<dyn Forwarder>,
+}
+
+struct Connector {
+    inner: Arc<dyn Forwarder>,
+}
+
+struct Flow {
+    sink: Arc<tokio::sync::Mutex<Box<dyn pipe::Sink>>>,
+    reader: JoinHandle<()>,
+}
+
+struct MultiplexerShared {
+    inner: Arc<dyn Forwarder>,
+    meta: forwarder::UdpMultiplexerMeta,
+    flows: Mutex<HashMap<forwarder::UdpDatagramMeta, Flow>>,
+    tx: mpsc::Sender<forwarder::UdpDatagramReadStatus>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct MultiplexerSource {
+    shared: Arc<MultiplexerShared>,
+    rx: mpsc::Receiver<forwarder::UdpDatagramReadStatus>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct MultiplexerSink {
+    shared: Arc<MultiplexerShared>,
+}
+
+impl CustomForwarder {
+    pub fn new(inner: Arc<dyn Forwarder>) -> Self {
+        Self { inner }
+    }
+}
+
+impl forwarder::Forwarder for CustomForwarder {
+    fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
+        Box::new(Connector {
+            inner: self.inner.clone(),
+        })
+    }
+
+    fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
+        struct Dummy;
+
+        #[async_trait]
+        impl forwarder::DatagramMultiplexerAuthenticator for Dummy {
+            async fn check_auth(
+                self: Box<Self>,
+                _: IpAddr,
+                _: &'_ str,
+                _: authentication::Source<'_>,
+                _: Option<&'_ str>,
+            ) -> Result<(), tunnel::ConnectionError> {
+                Ok(())
+            }
+        }
+
+        Box::new(Dummy)
+    }
+
+    fn make_udp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::UdpMultiplexerMeta,
+    ) -> io::Result<forwarder::UdpMultiplexer> {
+        let (tx, rx) = mpsc::channel(UDP_QUEUE_SIZE);
+        let shared = Arc::new(MultiplexerShared {
+            inner: self.inner.clone(),
+            meta,
+            flows: Default::default(),
+            tx,
+            id: id.clone(),
+        });
+
+        Ok((
+            shared.clone(),
+            Box::new(MultiplexerSource {
+                shared: shared.clone(),
+                rx,
+                id,
+            }),
+            Box::new(MultiplexerSink { shared }),
+        ))
+    }
+
+    fn make_icmp_datagram_multiplexer(
+        &self,
+        _id: log_utils::IdChain<u64>,
+    ) -> io::Result<Option<forwarder::IcmpMultiplexer>> {
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl forwarder::TcpConnector for Connector {
+    async fn connect(
+        self: Box<Self>,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::TcpConnectionMeta,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        let request = TcpRequest {
+            client_address: meta.client_address,
+            destination: meta.destination,
+            auth: meta.auth,
+            tls_domain: meta.tls_domain,
+            user_agent: meta.user_agent,
+        };
+        self.inner
+            .connect_tcp(id, request)
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::TimedOut => tunnel::ConnectionError::Timeout,
+                _ => tunnel::ConnectionError::Io(e),
+            })
+    }
+}
+
+/// Pass the datagrams of a flow to the tunnel till the flow is closed
+async fn read_flow(
+    mut source: Box<dyn pipe::Source>,
+    meta: forwarder::UdpDatagramMeta,
+    tx: mpsc::Sender<forwarder::UdpDatagramReadStatus>,
+) {
+    let error = loop {
+        match source.read().await {
+            Ok(pipe::Data::Chunk(payload)) => {
+                if let Err(e) = source.consume(payload.len()) {
+                    break e;
+                }
+                let datagram = forwarder::UdpDatagram {
+                    meta: meta.reversed(),
+                    payload,
+                };
+                if tx
+                    .send(forwarder::UdpDatagramReadStatus::Read(datagram))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Ok(pipe::Data::Eof) => break io::Error::from(ErrorKind::UnexpectedEof),
+            Err(e) => break e,
+        }
+    };
+
+    let _ = tx
+        .send(forwarder::UdpDatagramReadStatus::UdpClose(meta, error))
+        .await;
+}
+
+#[async_trait]
+impl forwarder::UdpDatagramPipeShared for MultiplexerShared {
+    async fn on_new_udp_connection(&self, meta: &downstream::UdpDatagramMeta) -> io::Result<()> {
+        let key = forwarder::UdpDatagramMeta::from(meta);
+        if self.flows.lock().unwrap().contains_key(&key) {
+            return Err(io::Error::new(ErrorKind::Other, "Already present"));
+        }
+
+        let request = UdpRequest {
+            client_address: self.meta.client_address,
+            source: meta.source,
+            destination: meta.destination,
+            auth: self.meta.auth.clone(),
+            tls_domain: self.meta.tls_domain.clone(),
+            user_agent: self.meta.user_agent.clone(),
+        };
+        let (source, sink) = self.inner.connect_udp(self.id.clone(), request).await?;
+        log_id!(trace, self.id, "Opened UDP flow: {:?}", key);
+
+        let flow = Flow {
+            sink: Arc::new(tokio::sync::Mutex::new(sink)),
+            reader: tokio::spawn(read_flow(source, key, self.tx.clone())),
+        };
+        if let Some(x) = self.flows.lock().unwrap().insert(key, flow) {
+            x.reader.abort();
+        }
+        Ok(())
+    }
+
+    fn on_connection_closed(&self, meta: &forwarder::UdpDatagramMeta) {
+        if let Some(x) = self.flows.lock().unwrap().remove(&meta.reversed()) {
+            x.reader.abort();
+        }
+    }
+}
+
+impl Drop for MultiplexerShared {
+    fn drop(&mut self) {
+        for (_, x) in self.flows.get_mut().unwrap().drain() {
+            x.reader.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Source for MultiplexerSource {
+    type Output = forwarder::UdpDatagramReadStatus;
+
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<forwarder::UdpDatagramReadStatus> {
+        let status = self
+            .rx
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+        if let forwarder::UdpDatagramReadStatus::UdpClose(meta, e) = &status {
+            log_id!(trace, self.id, "UDP flow closed: {:?}: {}", meta, e);
+            self.shared.flows.lock().unwrap().remove(meta);
+        }
+        Ok(status)
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Sink for MultiplexerSink {
+    type Input = downstream::UdpDatagram;
+
+    async fn write(
+        &mut self,
+        datagram: downstream::UdpDatagram,
+    ) -> io::Result<datagram_pipe::SendStatus> {
+        let meta = forwarder::UdpDatagramMeta::from(&datagram.meta);
+        let sink = self
+            .shared
+            .flows
+            .lock()
+            .unwrap()
+            .get(&meta)
+            .map(|x| x.sink.clone())
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+
+        let mut sink = sink.lock().await;
+        sink.wait_writable().await?;
+        Ok(match sink.write(datagram.payload)? {
+            x if x.is_empty() => datagram_pipe::SendStatus::Sent,
+            _ => datagram_pipe::SendStatus::Dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forwarder::Forwarder as _;
+    use crate::sim;
+    use bytes::Bytes;
+    use std::net::Ipv4Addr;
+
+    /// Connects everything to an echo peer
+    struct Echo;
+
+    #[async_trait]
+    impl Forwarder for Echo {
+        async fn connect_tcp(
+            &self,
+            _id: log_utils::IdChain<u64>,
+            _request: TcpRequest,
+        ) -> io::Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>)> {
+            Ok(echo())
+        }
+
+        async fn connect_udp(
+            &self,
+            _id: log_utils::IdChain<u64>,
+            _request: UdpRequest,
+        ) -> io::Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>)> {
+            Ok(echo())
+        }
+    }
+
+    fn echo() -> sim::StreamEnd {
+        let (ours, (mut source, mut sink)) = sim::duplex(1024);
+        tokio::spawn(async move {
+            while let Ok(pipe::Data::Chunk(x)) = source.read().await {
+                source.consume(x.len()).unwrap();
+                sink.write_all(x).await.unwrap();
+            }
+        });
+        ours
+    }
+
+    #[tokio::test]
+    async fn tcp() {
+        let forwarder = CustomForwarder::new(Arc::new(Echo));
+        let meta = forwarder::TcpConnectionMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            destination: TcpDestination::HostName(("example.org".into(), 80)),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+            destination_acl: None,
+            race_uplinks: vec![],
+        };
+        let (mut source, mut sink) = forwarder
+            .tcp_connector()
+            .connect(log_utils::IdChain::empty(), meta)
+            .await
+            .unwrap();
+
+        sink.write_all(Bytes::from_static(b"ping")).await.unwrap();
+        match source.read().await.unwrap() {
+            pipe::Data::Chunk(x) => assert_eq!(b"ping".as_slice(), x),
+            pipe::Data::Eof => panic!("Unexpected eof"),
+        }
+    }
+
+    #[tokio::test]
+    async fn udp() {
+        let forwarder = CustomForwarder::new(Arc::new(Echo));
+        let meta = forwarder::UdpMultiplexerMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+        };
+        let (shared, mut source, mut sink) = forwarder
+            .make_udp_datagram_multiplexer(log_utils::IdChain::empty(), meta)
+            .unwrap();
+
+        let meta = || downstream::UdpDatagramMeta {
+            source: SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 5000)),
+            destination: SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 53)),
+            app_name: None,
+        };
+        shared.on_new_udp_connection(&meta()).await.unwrap();
+        assert!(shared.on_new_udp_connection(&meta()).await.is_err());
+
+        let status = sink
+            .write(downstream::UdpDatagram {
+                meta: meta(),
+                payload: Bytes::from_static(b"query"),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(status, datagram_pipe::SendStatus::Sent));
+
+        match source.read().await.unwrap() {
+            forwarder::UdpDatagramReadStatus::Read(x) => {
+                assert_eq!(forwarder::UdpDatagramMeta::from(&meta()).reversed(), x.meta);
+                assert_eq!(b"query".as_slice(), x.payload);
+            }
+            x => panic!("Unexpected status: {:?}", x),
+        }
+    }
+}
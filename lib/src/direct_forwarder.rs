@@ -43,9 +43,9 @@ impl Forwarder for DirectForwarder {
     fn make_udp_datagram_multiplexer(
         &self,
         id: log_utils::IdChain<u64>,
-        _: forwarder::UdpMultiplexerMeta,
+        meta: forwarder::UdpMultiplexerMeta,
     ) -> io::Result<UdpMultiplexer> {
-        udp_forwarder::make_multiplexer(self.context.clone(), id)
+        udp_forwarder::make_multiplexer(self.context.clone(), id, meta.auth)
     }
 
     fn make_icmp_datagram_multiplexer(
@@ -53,6 +53,19 @@ pub(crate) trait PendingMultiplexedRequest:
 {
     /// Get the authorization info
     fn auth_info(&self) -> io::Result<Option<authentication::Source<'_>>>;
+
+    /// Get the address of a VPN client made the request
+    fn client_address(&self) -> io::Result<IpAddr>;
+
+    /// Get the version of the terms of use the client acknowledges with the request, if any
+    fn terms_acknowledgment(&self) -> Option<String>;
+
+    /// Get the reconnect token the client presents with the request instead of its credentials,
+    /// if any
+    fn reconnect_token(&self) -> Option<String>;
+
+    /// Add a header to the response in case the request succeeds
+    fn add_ok_header(&mut self, name: &str, value: String);
 }
 
 pub(crate) enum PendingDemultiplexedRequest {
@@ -72,6 +85,9 @@ pub(crate) trait PendingTcpConnectRequest:
 
     /// Get the user agent
     fn user_agent(&self) -> Option<String>;
+
+    /// Add a header to the response in case the request succeeds
+    fn add_ok_header(&mut self, name: &str, value: String);
 }
 
 pub(crate) enum DatagramPipeHalves {
@@ -94,6 +110,17 @@ pub(crate) trait PendingDatagramMultiplexerRequest:
 
     /// Get the user agent
     fn user_agent(&self) -> Option<String>;
+
+    /// Add a header to the response in case the request succeeds
+    fn add_ok_header(&mut self, name: &str, value: String);
+
+    /// Get the target of a CONNECT-UDP request, which relays a single flow.
+    /// [`None`] for the other requests, which carry the destination in each datagram.
+    fn connect_udp_target(&self) -> Option<TcpDestination>;
+
+    /// Set the address the datagrams of a CONNECT-UDP request are relayed to,
+    /// once its target passes the checks
+    fn set_connect_udp_destination(&mut self, destination: SocketAddr);
 }
 
 /// An abstract interface for a downstream implementation which communicates with a client
@@ -108,6 +135,10 @@ pub(crate) trait Downstream: Send {
     /// Shut down the downstream connection gracefully
     async fn graceful_shutdown(&mut self) -> io::Result<()>;
 
+    /// Stop accepting new requests and shut down the downstream connection gracefully
+    /// after the in-flight ones are finished
+    async fn drain(&mut self) -> io::Result<()>;
+
     /// Get the downstream protocol
     fn protocol(&self) -> Protocol;
 
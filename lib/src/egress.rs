@@ -0,0 +1,77 @@
+use crate::{authentication, core};
+use std::net::IpAddr;
+
+/// Select the source address of an outgoing connection of a client.
+/// [`None`] means the system chooses the address.
+pub(crate) fn select(
+    context: &core::Context,
+    auth: Option<&authentication::Source<'_>>,
+    peer: IpAddr,
+) -> Option<IpAddr> {
+    let (source, authenticator) = (auth?, context.authenticator.as_ref()?);
+    if let Some(x) = authenticator
+        .egress_address(source)
+        .filter(|x| x.is_ipv4() == peer.is_ipv4())
+    {
+        return Some(x);
+    }
+
+    pick(
+        &context.settings.egress_addresses,
+        &authenticator.username(source)?,
+        peer,
+    )
+}
+
+/// Pick an address of the peer family from the pool using the rendezvous hashing,
+/// so that adding or removing an address only moves the clients assigned to it
+fn pick(pool: &[IpAddr], username: &str, peer: IpAddr) -> Option<IpAddr> {
+    pool.iter()
+        .filter(|x| x.is_ipv4() == peer.is_ipv4())
+        .max_by_key(|x| {
+            let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+            context.update(username.as_bytes());
+            match x {
+                IpAddr::V4(x) => context.update(&x.octets()),
+                IpAddr::V6(x) => context.update(&x.octets()),
+            }
+            let digest = context.finish();
+            u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap())
+        })
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks() {
+        let pool: Vec<IpAddr> = vec![
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+            "192.0.2.3".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ];
+        let v4_peer = "198.51.100.1".parse().unwrap();
+        let v6_peer = "2001:db8:1::1".parse().unwrap();
+
+        assert_eq!(None, pick(&[], "alice", v4_peer));
+        assert_eq!(None, pick(&pool[..3], "alice", v6_peer));
+        assert_eq!(Some(pool[3]), pick(&pool, "alice", v6_peer));
+
+        let x = pick(&pool, "alice", v4_peer).unwrap();
+        assert!(x.is_ipv4());
+        assert_eq!(Some(x), pick(&pool, "alice", v4_peer));
+
+        // The clients of a removed address are the only ones to move
+        let users: Vec<String> = (0..100).map(|i| format!("user{}", i)).collect();
+        let before: Vec<_> = users.iter().map(|u| pick(&pool, u, v4_peer)).collect();
+        let after: Vec<_> = users.iter().map(|u| pick(&pool[1..], u, v4_peer)).collect();
+        assert!(before
+            .iter()
+            .zip(&after)
+            .all(|(b, a)| b == a || *b == Some(pool[0])));
+        assert!(before.iter().any(|x| *x != Some(pool[0])));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::tls_demultiplexer::Protocol;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// The number of events a slow subscriber may lag behind before missing some
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// An endpoint activity notification
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Event {
+    /// A client session is established
+    SessionOpened {
+        session: u64,
+        protocol: Protocol,
+        server_name: String,
+    },
+    /// A client session is closed
+    SessionClosed { session: u64, duration: Duration },
+    /// A client failed to authenticate
+    AuthFailure {
+        session: u64,
+        username: Option<String>,
+    },
+    /// A tunnel request was rejected or failed
+    RequestFailed { session: u64, reason: String },
+    /// A loaded certificate got closer to its expiration, see [`crate::cert_expiry`]
+    CertificateExpiring {
+        role: &'static str,
+        hostname: String,
+        stage: &'static str,
+        expires_in_secs: i64,
+    },
+}
+
+/// An event along with the time it happened at
+#[derive(Clone, Debug)]
+pub(crate) struct EventRecord {
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+/// Broadcasts the events to the live subscribers
+pub(crate) struct EventBus {
+    tx: broadcast::Sender<EventRecord>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    /// Notify the subscribers of the event. Does nothing if there are none.
+    pub fn publish(&self, event: Event) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+
+        let _ = self.tx.send(EventRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_millis() as u64)
+                .unwrap_or_default(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.tx.subscribe()
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SessionOpened { .. } => "session_opened",
+            Self::SessionClosed { .. } => "session_closed",
+            Self::AuthFailure { .. } => "auth_failure",
+            Self::RequestFailed { .. } => "request_failed",
+            Self::CertificateExpiring { .. } => "certificate_expiring",
+        }
+    }
+}
+
+impl EventRecord {
+    /// Serialize the event into a single-line JSON object
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"timestamp\":{},\"type\":\"{}\"",
+            self.timestamp,
+            self.event.name()
+        );
+        match &self.event {
+            Event::SessionOpened {
+                session,
+                protocol,
+                server_name,
+            } => {
+                let _ = write!(
+                    out,
+                    ",\"session\":{},\"protocol\":\"{}\",\"server_name\":",
+                    session,
+                    protocol.as_str()
+                );
+                write_json_string(&mut out, server_name);
+            }
+            Event::SessionClosed { session, duration } => {
+                let _ = write!(
+                    out,
+                    ",\"session\":{},\"duration_ms\":{}",
+                    session,
+                    duration.as_millis()
+                );
+            }
+            Event::AuthFailure { session, username } => {
+                let _ = write!(out, ",\"session\":{},\"username\":", session);
+                match username {
+                    None => out.push_str("null"),
+                    Some(x) => write_json_string(&mut out, x),
+                }
+            }
+            Event::RequestFailed { session, reason } => {
+                let _ = write!(out, ",\"session\":{},\"reason\":", session);
+                write_json_string(&mut out, reason);
+            }
+            Event::CertificateExpiring {
+                role,
+                hostname,
+                stage,
+                expires_in_secs,
+            } => {
+                let _ = write!(out, ",\"role\":\"{}\",\"hostname\":", role);
+                write_json_string(&mut out, hostname);
+                let _ = write!(
+                    out,
+                    ",\"stage\":\"{}\",\"expires_in_secs\":{}",
+                    stage, expires_in_secs
+                );
+            }
+        }
+        out.push('}');
+        out
+    }
+
+    /// Format the event as a server-sent events message
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.event.name(), self.to_json())
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_message() {
+        let record = EventRecord {
+            timestamp: 1000,
+            event: Event::AuthFailure {
+                session: 7,
+                username: Some("a\"b\n".into()),
+            },
+        };
+        assert_eq!(
+            "event: auth_failure\n\
+            data: {\"timestamp\":1000,\"type\":\"auth_failure\",\"session\":7,\"username\":\"a\\\"b\\n\"}\n\n",
+            record.to_sse()
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered_to_subscribers_only() {
+        let bus = EventBus::default();
+        bus.publish(Event::RequestFailed {
+            session: 1,
+            reason: "lost".into(),
+        });
+
+        let mut rx = bus.subscribe();
+        let event = Event::SessionClosed {
+            session: 2,
+            duration: Duration::from_secs(1),
+        };
+        bus.publish(event.clone());
+        assert_eq!(event, rx.recv().await.unwrap().event);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::settings::Settings;
+
+/// The path of the exit policy document on the ping hosts
+pub(crate) const PATH: &str = "/.well-known/exit-policy.json";
+
+/// The format version of the document, bumped on incompatible changes
+const VERSION: u32 = 1;
+
+/// Generate the exit policy document from the configuration in effect.
+/// [`None`] if the publication is not configured.
+pub(crate) fn document(settings: &Settings) -> Option<String> {
+    let policy = settings.exit_policy.as_ref()?;
+
+    let mut protocols = vec!["tcp", "udp"];
+    if settings.icmp.is_some() {
+        protocols.push("icmp");
+    }
+
+    // The tunneled connections are not filtered by the destination port
+    let allowed_ports = ["1-65535"];
+
+    let client_filtering = settings
+        .rules_engine
+        .as_ref()
+        .is_some_and(|x| !x.config().rule.is_empty());
+
+    Some(format!(
+        concat!(
+            "{{",
+            r#""version":{},"#,
+            r#""abuse_contact":{},"#,
+            r#""protocols":{},"#,
+            r#""allowed_ports":{},"#,
+            r#""ipv6":{},"#,
+            r#""private_networks":{},"#,
+            r#""client_filtering":{},"#,
+            r#""exit_addresses":{},"#,
+            r#""blocked_categories":{}"#,
+            "}}",
+        ),
+        VERSION,
+        policy
+            .abuse_contact
+            .as_ref()
+            .map(|x| format!(r#""{}""#, x))
+            .unwrap_or_else(|| "null".to_string()),
+        json_strings(protocols),
+        json_strings(allowed_ports),
+        settings.ipv6_available,
+        settings.allow_private_network_connections,
+        client_filtering,
+        json_strings(settings.egress_addresses.iter().map(|x| x.to_string())),
+        json_strings(&policy.blocked_categories),
+    ))
+}
+
+/// The values must not need escaping, which is ensured by the settings validation
+fn json_strings<I, S>(values: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let values: Vec<_> = values
+        .into_iter()
+        .map(|x| format!(r#""{}""#, x.as_ref()))
+        .collect();
+    format!("[{}]", values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ExitPolicySettings;
+
+    #[test]
+    fn document_reflects_settings() {
+        let mut settings = Settings::default();
+        assert_eq!(None, document(&settings));
+
+        settings.exit_policy = Some(
+            ExitPolicySettings::builder()
+                .abuse_contact("abuse@example.org")
+                .blocked_categories(vec!["smtp".into(), "torrent".into()])
+                .build()
+                .unwrap(),
+        );
+        settings.egress_addresses = vec!["192.0.2.1".parse().unwrap()];
+        assert_eq!(
+            Some(concat!(
+                "{",
+                r#""version":1,"#,
+                r#""abuse_contact":"abuse@example.org","#,
+                r#""protocols":["tcp","udp"],"#,
+                r#""allowed_ports":["1-65535"],"#,
+                r#""ipv6":false,"#,
+                r#""private_networks":true,"#,
+                r#""client_filtering":false,"#,
+                r#""exit_addresses":["192.0.2.1"],"#,
+                r#""blocked_categories":["smtp","torrent"]"#,
+                "}",
+            )),
+            document(&settings).as_deref()
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::authentication::destination_acl::DestinationAcl;
 use crate::net_utils::TcpDestination;
 use crate::{authentication, datagram_pipe, downstream, icmp_utils, log_utils, pipe, tunnel};
 use async_trait::async_trait;
@@ -42,6 +43,10 @@ pub(crate) struct TcpConnectionMeta {
     /// May contain a platform name of the VPN client and name of the application
     /// initiated the request
     pub user_agent: Option<String>,
+    /// The destinations the authenticated client is restricted to
+    pub destination_acl: Option<Arc<DestinationAcl>>,
+    /// The egress uplinks the connection is raced across, if any
+    pub race_uplinks: Vec<String>,
 }
 
 pub(crate) struct UdpMultiplexerMeta {
@@ -0,0 +1,378 @@
+//! The entry points of the fuzz targets in `fuzz/`, also exercised by the property tests below.
+//! Each one feeds the untrusted input to the code under test and panics if any invariant
+//! of the result is broken, so that both the crashes and the logic errors are reported.
+
+use crate::http1_codec::{DecodeStatus, MAX_HEADERS_NUM, MAX_RAW_HEADERS_SIZE};
+use crate::net_utils::Channel;
+use crate::settings::{
+    Http1Settings, Http2Settings, ListenProtocolSettings, Settings, TlsHostInfo, TlsHostsSettings,
+};
+use crate::tls_demultiplexer::{Protocol, TlsDemux};
+use crate::{http1_codec, http_codec};
+use bytes::BytesMut;
+use once_cell::sync::Lazy;
+
+/// The main TLS host of the demultiplexer under test
+const MAIN_HOST: &str = "vpn.example.org";
+const ALLOWED_SNI: &str = "alt.example.org";
+const PING_HOST: &str = "ping.example.org";
+const SPEEDTEST_HOST: &str = "speed.example.org";
+
+/// Decode an HTTP/1 request head, as a client sends it to the endpoint
+pub fn http1_request(data: &[u8]) {
+    let request = match decode_request(data, MAX_HEADERS_NUM, MAX_RAW_HEADERS_SIZE) {
+        None => return,
+        Some(x) => x,
+    };
+
+    // The re-encoded request has the authority in the `Host` header
+    let encoded = http1_codec::encode_request(&request);
+    let decoded = decode_request(&encoded, MAX_HEADERS_NUM + 1, usize::MAX)
+        .unwrap_or_else(|| panic!("Re-encoded request is not decoded: {:?}", encoded));
+    let target = |x: &http_codec::RequestHeaders| match x.uri.path_and_query() {
+        Some(x) => (x.path().to_string(), x.query().map(str::to_string)),
+        None => ("/".to_string(), None),
+    };
+    assert_eq!(request.method, decoded.method);
+    assert_eq!(request.version, decoded.version);
+    assert_eq!(request.uri.authority(), decoded.uri.authority());
+    assert_eq!(target(&request), target(&decoded));
+    assert_eq!(request.headers, decoded.headers);
+}
+
+/// Decode an HTTP/1 response head, as an upstream server sends it to the endpoint
+pub fn http1_response(data: &[u8]) {
+    let response = match decode_response(data, MAX_HEADERS_NUM, MAX_RAW_HEADERS_SIZE) {
+        None => return,
+        Some(x) => x,
+    };
+
+    let (status, version, headers) = (response.status, response.version, response.headers.clone());
+    let encoded = http1_codec::encode_response(response);
+    let decoded = decode_response(&encoded, MAX_HEADERS_NUM, usize::MAX)
+        .unwrap_or_else(|| panic!("Re-encoded response is not decoded: {:?}", encoded));
+    assert_eq!(status, decoded.status);
+    assert_eq!(version, decoded.version);
+    assert_eq!(headers, decoded.headers);
+}
+
+/// Select the channel and the protocol of a TLS connection by the client hello values.
+/// The input is the server name followed by the advertised ALPN protocols, all separated
+/// by zero bytes.
+pub fn tls_selection(data: &[u8]) {
+    static DEMUX: Lazy<TlsDemux> = Lazy::new(|| {
+        let mut settings = Settings::default();
+        settings.listen_protocols = ListenProtocolSettings {
+            http1: Some(Http1Settings::builder().build()),
+            http2: Some(Http2Settings::builder().build()),
+            quic: None,
+        };
+        let host = |hostname: &str| TlsHostInfo {
+            hostname: hostname.to_string(),
+            ..Default::default()
+        };
+        let mut tls_settings = TlsHostsSettings::default();
+        tls_settings.main_hosts = vec![TlsHostInfo {
+            allowed_sni: vec![ALLOWED_SNI.to_string()],
+            ..host(MAIN_HOST)
+        }];
+        tls_settings.ping_hosts = vec![host(PING_HOST)];
+        tls_settings.speedtest_hosts = vec![host(SPEEDTEST_HOST)];
+        TlsDemux::new(&settings, &tls_settings).unwrap()
+    });
+
+    let mut fields = data.split(|x| *x == 0);
+    let sni = String::from_utf8_lossy(fields.next().unwrap_or_default()).into_owned();
+    let alpn: Vec<&[u8]> = fields.collect();
+
+    let meta = match DEMUX.select(alpn.iter().copied(), sni.clone()) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+
+    assert_eq!(sni, meta.sni);
+    if alpn.is_empty() {
+        assert_eq!(Protocol::Http1, meta.protocol);
+    } else {
+        assert!(alpn.contains(&meta.protocol.as_alpn().as_bytes()));
+    }
+    match meta.channel {
+        Channel::Tunnel => {
+            assert_ne!(Protocol::Http3, meta.protocol);
+            match &meta.sni_auth_creds {
+                None => assert!(sni == MAIN_HOST || sni == ALLOWED_SNI),
+                Some(x) => assert_eq!(format!("{}.{}", x, MAIN_HOST), sni),
+            }
+        }
+        Channel::Ping => assert_eq!(PING_HOST, sni),
+        Channel::Speedtest => assert_eq!(SPEEDTEST_HOST, sni),
+        Channel::ReverseProxy => panic!("Reverse proxy is not configured"),
+    }
+}
+
+/// Decode a complete request head checking the consistency of the decoding status
+fn decode_request(
+    data: &[u8],
+    headers_num_cap: usize,
+    raw_buffer_cap: usize,
+) -> Option<http_codec::RequestHeaders> {
+    match http1_codec::decode_request(BytesMut::from(data), headers_num_cap, raw_buffer_cap).ok()? {
+        DecodeStatus::Partial(x) => {
+            assert_eq!(data, &x[..]);
+            None
+        }
+        DecodeStatus::Complete(request, tail) => {
+            assert!(data.ends_with(&tail));
+            Some(request)
+        }
+    }
+}
+
+/// Decode a complete response head checking the consistency of the decoding status
+fn decode_response(
+    data: &[u8],
+    headers_num_cap: usize,
+    raw_buffer_cap: usize,
+) -> Option<http_codec::ResponseHeaders> {
+    match http1_codec::decode_response(BytesMut::from(data), headers_num_cap, raw_buffer_cap)
+        .ok()?
+    {
+        DecodeStatus::Partial(x) => {
+            assert_eq!(data, &x[..]);
+            None
+        }
+        DecodeStatus::Complete(response, tail) => {
+            assert!(data.ends_with(&tail));
+            Some(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::{select, Index};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    /// The number of the generated inputs per target
+    const CASES: u32 = 2000;
+
+    /// How a generated message head ends
+    #[derive(Clone, Debug)]
+    enum Ending {
+        Complete,
+        Truncated(Index),
+        Tail(Vec<u8>),
+        /// Exceeds the raw headers size limit
+        Oversized,
+    }
+
+    fn replay_corpus(name: &str, target: fn(&[u8])) {
+        let dir = format!("{}/../fuzz/corpus/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let mut n = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let input = std::fs::read(&path).unwrap();
+            assert!(
+                catch_unwind(AssertUnwindSafe(|| target(&input))).is_ok(),
+                "Failed on {}",
+                path.display()
+            );
+            n += 1;
+        }
+        assert_ne!(0, n, "Empty corpus {}", dir);
+    }
+
+    fn bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..=max_len)
+    }
+
+    /// A header line, rarely a malformed one
+    fn header_line() -> impl Strategy<Value = Vec<u8>> {
+        let name = prop_oneof![
+            9 => select(vec![
+                "Host",
+                "host",
+                "Content-Length",
+                "Transfer-Encoding",
+                "Proxy-Authorization",
+                "User-Agent",
+                "X-Forwarded-For",
+            ]),
+            1 => select(vec!["x-\u{e9}", "X_Y", "x.y~z"]),
+        ];
+        let value = prop_oneof![
+            9 => select(vec!["example.org", "example.org:443", "[::1]:80", "u@a", "0", ""])
+                .prop_map(|x| x.as_bytes().to_vec()),
+            // Obs-text and control characters
+            1 => (0..6usize).prop_map(|n| [0xe9, b'\t', 0x7f, 0x80, b'x'][..n].to_vec()),
+        ];
+        let well_formed = (name, select(vec![": ", ":", ":  ", ":\t"]), value).prop_map(
+            |(name, separator, value)| [name.as_bytes(), separator.as_bytes(), &value].concat(),
+        );
+        let malformed = select(vec![
+            ": no-name",
+            "Name no-colon",
+            "X: a\r\n folded",
+            "X\0Y: z",
+            " X: y",
+        ])
+        .prop_map(|x| x.as_bytes().to_vec());
+
+        (
+            prop_oneof![19 => well_formed, 1 => malformed],
+            // The bare LF line endings are tolerated by the decoder
+            select(vec!["\r\n", "\r\n", "\r\n", "\n"]),
+        )
+            .prop_map(|(line, ending)| [line.as_slice(), ending.as_bytes()].concat())
+    }
+
+    /// A message head with the start line, the header edge cases,
+    /// and optionally a truncation or a tail
+    fn head(start_line: impl Strategy<Value = String>) -> impl Strategy<Value = Vec<u8>> {
+        let headers = prop_oneof![
+            9 => vec(header_line(), 0..6),
+            // Around the cap of the headers number
+            1 => vec(header_line(), MAX_HEADERS_NUM - 2..MAX_HEADERS_NUM + 2),
+        ];
+        let ending = prop_oneof![
+            12 => Just(Ending::Complete),
+            2 => any::<Index>().prop_map(Ending::Truncated),
+            4 => bytes(16).prop_map(Ending::Tail),
+            1 => Just(Ending::Oversized),
+        ];
+
+        (start_line, headers, ending).prop_map(|(start_line, headers, ending)| {
+            let mut head = start_line.into_bytes();
+            head.extend(headers.concat());
+            head.extend(b"\r\n");
+            match ending {
+                Ending::Complete => (),
+                Ending::Truncated(x) => head.truncate(x.index(head.len() + 1)),
+                Ending::Tail(x) => head.extend(x),
+                Ending::Oversized => {
+                    head.splice(head.len() - 2..head.len() - 2, b"X: ".iter().copied());
+                    let filler = vec![b'x'; MAX_RAW_HEADERS_SIZE];
+                    head.splice(head.len() - 2..head.len() - 2, filler);
+                }
+            }
+            head
+        })
+    }
+
+    fn request() -> impl Strategy<Value = Vec<u8>> {
+        let method = prop_oneof![
+            19 => select(vec!["GET", "CONNECT", "POST", "OPTIONS", "HEAD"]),
+            1 => select(vec!["G\tT", "get", "", "M-SEARCH"]),
+        ];
+        let target = prop_oneof![
+            9 => select(vec![
+                "/",
+                "/path?query",
+                "*",
+                "example.org:443",
+                "[2001:db8::1]:443",
+                "http://example.org",
+                "http://example.org?x",
+                "https://u@example.org:8443/p",
+                "/%41?a=%zz",
+            ])
+            .prop_map(str::to_string),
+            1 => bytes(12).prop_map(|x| String::from_utf8_lossy(&x).into_owned()),
+        ];
+        let version = prop_oneof![
+            19 => select(vec!["HTTP/1.1", "HTTP/1.0"]),
+            1 => select(vec!["HTTP/2.0", "HTTP/1.1 ", "http/1.1"]),
+        ];
+        head(
+            (method, target, version).prop_map(|(method, target, version)| {
+                format!("{} {} {}\r\n", method, target, version)
+            }),
+        )
+    }
+
+    fn response() -> impl Strategy<Value = Vec<u8>> {
+        let status = prop_oneof![
+            9 => select(vec!["200", "101", "407", "502"]).prop_map(str::to_string),
+            1 => (0..1100u32).prop_map(|x| x.to_string()),
+        ];
+        let reason = select(vec!["OK", "", "Some Reason", "\u{e9}"]);
+        let version = prop_oneof![
+            19 => select(vec!["HTTP/1.1", "HTTP/1.0"]),
+            1 => Just("HTTP/1.2"),
+        ];
+        head(
+            (version, status, reason).prop_map(|(version, status, reason)| {
+                format!("{} {} {}\r\n", version, status, reason)
+            }),
+        )
+    }
+
+    /// The server name followed by the ALPN protocols, as [`tls_selection`] takes them
+    fn client_hello_values() -> impl Strategy<Value = Vec<u8>> {
+        let sni = prop_oneof![
+            4 => select(vec![
+                MAIN_HOST,
+                ALLOWED_SNI,
+                PING_HOST,
+                SPEEDTEST_HOST,
+                "creds.vpn.example.org",
+                "a.b.vpn.example.org",
+                ".vpn.example.org",
+                "example.org",
+                "",
+            ])
+            .prop_map(|x| x.as_bytes().to_vec()),
+            1 => bytes(16),
+        ];
+        let alpn = prop_oneof![
+            4 => select(vec!["http/1.1", "h2", "h3", "h3-29", ""])
+                .prop_map(|x| x.as_bytes().to_vec()),
+            1 => bytes(8),
+        ];
+        (sni, vec(alpn, 0..4)).prop_map(|(sni, alpn)| {
+            std::iter::once(sni)
+                .chain(alpn)
+                .collect::<Vec<_>>()
+                .join(&0)
+        })
+    }
+
+    #[test]
+    fn http1_request_corpus() {
+        replay_corpus("http1_request", http1_request);
+    }
+
+    #[test]
+    fn http1_response_corpus() {
+        replay_corpus("http1_response", http1_response);
+    }
+
+    #[test]
+    fn tls_selection_corpus() {
+        replay_corpus("tls_selection", tls_selection);
+    }
+
+    // A failure is reported with the shrunk input, which is to be added
+    // to the target corpus in `fuzz/corpus/` as a regression test
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+        #[test]
+        fn http1_request_properties(input in request()) {
+            http1_request(&input);
+        }
+
+        #[test]
+        fn http1_response_properties(input in response()) {
+            http1_response(&input);
+        }
+
+        #[test]
+        fn tls_selection_properties(input in client_hello_values()) {
+            tls_selection(&input);
+        }
+    }
+}
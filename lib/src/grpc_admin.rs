@@ -0,0 +1,634 @@
+//! The gRPC flavour of the administration interface.
+//! See `proto/admin.proto` for the service definition, the message and service types are
+//! generated from it by the build script.
+
+use crate::core;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(feature = "grpc")]
+pub(crate) async fn listen(context: Arc<core::Context>) -> io::Result<()> {
+    let settings = match context.settings.grpc_admin.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let token = context
+        .settings
+        .metrics
+        .as_ref()
+        .and_then(|x| x.admin_token.clone())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "gRPC admin service requires the admin token",
+            )
+        })?;
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+    let address = settings.listen_address;
+    info!("Starting gRPC admin service on {}", address);
+    context
+        .status_report
+        .listener_bound("grpc_admin", "tcp", address);
+    tonic::transport::Server::builder()
+        .add_service(proto::admin_server::AdminServer::with_interceptor(
+            service::AdminService::new(context),
+            move |x| service::check_admin_token(&token, x),
+        ))
+        .serve_with_shutdown(address, async move {
+            let _ = shutdown_notification.wait().await;
+        })
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(not(feature = "grpc"))]
+pub(crate) async fn listen(context: Arc<core::Context>) -> io::Result<()> {
+    if context.settings.grpc_admin.is_some() {
+        warn!("gRPC admin service is configured, but the endpoint is built without the `grpc` feature");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "grpc")]
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub(crate) mod proto {
+    tonic::include_proto!("trusttunnel.admin.v1");
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use super::proto;
+    use crate::events::{Event, EventRecord};
+    use crate::{core, log_utils, metrics, schedule};
+    use futures::Stream;
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::sync::broadcast;
+
+    type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, tonic::Status>> + Send>>;
+    type Reply<T> = Result<tonic::Response<T>, tonic::Status>;
+
+    struct Admin {
+        context: Arc<core::Context>,
+    }
+
+    /// The handlers of [`Admin`] behind the generated service trait
+    pub(super) struct AdminService(Admin);
+
+    impl AdminService {
+        pub fn new(context: Arc<core::Context>) -> Self {
+            Self(Admin { context })
+        }
+    }
+
+    /// Let through the calls carrying the admin `token` in the `authorization` metadata
+    pub(super) fn check_admin_token(
+        token: &str,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let is_authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .is_some_and(|x| metrics::is_same_secret(x, token));
+        if !is_authorized {
+            return Err(tonic::Status::unauthenticated("Admin token is required"));
+        }
+
+        Ok(request)
+    }
+
+    impl Admin {
+        fn health(&self, _: proto::HealthRequest) -> Result<proto::HealthResponse, tonic::Status> {
+            Ok(proto::HealthResponse {
+                serving: true,
+                active_sessions: self.context.sessions.list().len() as u64,
+                instance_id: self.context.instance.id.clone(),
+                epoch: self.context.instance.epoch,
+            })
+        }
+
+        fn list_sessions(
+            &self,
+            _: proto::ListSessionsRequest,
+        ) -> Result<proto::ListSessionsResponse, tonic::Status> {
+            Ok(proto::ListSessionsResponse {
+                sessions: self
+                    .context
+                    .sessions
+                    .list()
+                    .into_iter()
+                    .map(|x| proto::Session {
+                        session: x.id,
+                        protocol: x.protocol.as_str().to_string(),
+                        age_secs: x.age.as_secs(),
+                        active_streams: x.active_streams as u64,
+                        draining: x.draining,
+                        inbound_bytes: x.inbound_bytes,
+                        outbound_bytes: x.outbound_bytes,
+                    })
+                    .collect(),
+            })
+        }
+
+        fn rebalance(
+            &self,
+            request: proto::RebalanceRequest,
+        ) -> Result<proto::RebalanceResponse, tonic::Status> {
+            let order = match proto::RebalanceOrder::from_i32(request.order) {
+                Some(proto::RebalanceOrder::LongestLived) => core::RebalanceOrder::LongestLived,
+                Some(proto::RebalanceOrder::MostLoaded) => core::RebalanceOrder::MostLoaded,
+                None => return Err(tonic::Status::invalid_argument("Unknown order")),
+            };
+            let count = match request.count {
+                0 => 1,
+                x => x as usize,
+            };
+
+            let n = self.context.sessions.rebalance(count, order);
+            info!("Asked {} sessions to drain ({:?})", n, order);
+            Ok(proto::RebalanceResponse { sessions: n as u32 })
+        }
+
+        fn purge_cache(
+            &self,
+            request: proto::PurgeCacheRequest,
+        ) -> Result<proto::PurgeCacheResponse, tonic::Status> {
+            let cache = self
+                .context
+                .response_cache
+                .as_ref()
+                .ok_or_else(|| tonic::Status::failed_precondition("Cache is not configured"))?;
+            let path = match request.path_prefix.as_str() {
+                "" => "/",
+                x if x.starts_with('/') => x,
+                _ => return Err(tonic::Status::invalid_argument("Path must start with /")),
+            };
+
+            let n = cache.purge(Some(request.host.as_str()).filter(|x| !x.is_empty()), path);
+            info!("Purged {} cache entries", n);
+            Ok(proto::PurgeCacheResponse { purged: n as u64 })
+        }
+
+        fn list_log_levels(
+            &self,
+            _: proto::ListLogLevelsRequest,
+        ) -> Result<proto::LogLevelsResponse, tonic::Status> {
+            Ok(to_proto_log_levels())
+        }
+
+        fn set_log_level(
+            &self,
+            request: proto::SetLogLevelRequest,
+        ) -> Result<proto::LogLevelsResponse, tonic::Status> {
+            if request.module.is_empty() {
+                return Err(tonic::Status::invalid_argument("Module is not set"));
+            }
+            let level: log::LevelFilter = request
+                .level
+                .parse()
+                .map_err(|_| tonic::Status::invalid_argument("Unknown log level"))?;
+            let duration =
+                Some(Duration::from_secs(request.duration_secs)).filter(|x| !x.is_zero());
+
+            log_utils::set_module_level(&request.module, level, duration);
+            if let Some(x) = duration {
+                log_utils::schedule_expiration(x);
+            }
+            info!("Set log level of {} to {}", request.module, level);
+            Ok(to_proto_log_levels())
+        }
+
+        fn reset_log_level(
+            &self,
+            request: proto::ResetLogLevelRequest,
+        ) -> Result<proto::LogLevelsResponse, tonic::Status> {
+            if log_utils::reset_module_level(&request.module) {
+                info!("Reset log level of {}", request.module);
+            }
+            Ok(to_proto_log_levels())
+        }
+
+        fn list_trace_rules(
+            &self,
+            _: proto::ListTraceRulesRequest,
+        ) -> Result<proto::TraceRulesResponse, tonic::Status> {
+            Ok(to_proto_trace_rules())
+        }
+
+        fn add_trace_rule(
+            &self,
+            request: proto::AddTraceRuleRequest,
+        ) -> Result<proto::TraceRulesResponse, tonic::Status> {
+            let identity = request.identity.filter(|x| !x.is_empty());
+            let destination = request
+                .destination
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_lowercase());
+            if identity.is_none() && destination.is_none() {
+                return Err(tonic::Status::invalid_argument(
+                    "Neither identity nor destination is set",
+                ));
+            }
+            let duration =
+                Some(Duration::from_secs(request.duration_secs)).filter(|x| !x.is_zero());
+
+            let id = log_utils::add_trace_rule(identity, destination, duration);
+            if let Some(x) = duration {
+                log_utils::schedule_expiration(x);
+            }
+            info!("Added trace rule {}", id);
+            Ok(to_proto_trace_rules())
+        }
+
+        fn remove_trace_rule(
+            &self,
+            request: proto::RemoveTraceRuleRequest,
+        ) -> Result<proto::TraceRulesResponse, tonic::Status> {
+            if log_utils::remove_trace_rule(request.id) {
+                info!("Removed trace rule {}", request.id);
+            }
+            Ok(to_proto_trace_rules())
+        }
+
+        fn get_maintenance(
+            &self,
+            _: proto::GetMaintenanceRequest,
+        ) -> Result<proto::MaintenanceResponse, tonic::Status> {
+            self.maintenance_response()
+        }
+
+        fn set_maintenance(
+            &self,
+            request: proto::SetMaintenanceRequest,
+        ) -> Result<proto::MaintenanceResponse, tonic::Status> {
+            self.maintenance_response()?;
+            self.context
+                .maintenance
+                .store(request.enabled, Ordering::Relaxed);
+            info!(
+                "Maintenance mode is {}",
+                if request.enabled { "on" } else { "off" }
+            );
+            self.maintenance_response()
+        }
+
+        fn maintenance_response(&self) -> Result<proto::MaintenanceResponse, tonic::Status> {
+            if self.context.settings.reverse_proxy.is_none() {
+                return Err(tonic::Status::failed_precondition(
+                    "Reverse proxy is not configured",
+                ));
+            }
+            Ok(proto::MaintenanceResponse {
+                enabled: self.context.maintenance.load(Ordering::Relaxed),
+            })
+        }
+
+        fn get_schedule(
+            &self,
+            _: proto::GetScheduleRequest,
+        ) -> Result<proto::ScheduleResponse, tonic::Status> {
+            self.schedule_response()
+        }
+
+        fn set_schedule_override(
+            &self,
+            request: proto::SetScheduleOverrideRequest,
+        ) -> Result<proto::ScheduleResponse, tonic::Status> {
+            self.schedule_response()?;
+            let mode = match proto::ScheduleOverride::from_i32(request.mode) {
+                Some(proto::ScheduleOverride::Auto) => schedule::Override::Auto,
+                Some(proto::ScheduleOverride::Drain) => schedule::Override::Drain,
+                Some(proto::ScheduleOverride::Accept) => schedule::Override::Accept,
+                None => return Err(tonic::Status::invalid_argument("Unknown override")),
+            };
+            schedule::set_override(&self.context, mode);
+            self.schedule_response()
+        }
+
+        fn schedule_response(&self) -> Result<proto::ScheduleResponse, tonic::Status> {
+            let Some(schedule) = self.context.schedule.as_ref() else {
+                return Err(tonic::Status::failed_precondition(
+                    "Maintenance schedule is not configured",
+                ));
+            };
+            let state = schedule.state();
+            let mode = match state.mode {
+                schedule::Override::Auto => proto::ScheduleOverride::Auto,
+                schedule::Override::Drain => proto::ScheduleOverride::Drain,
+                schedule::Override::Accept => proto::ScheduleOverride::Accept,
+            };
+            Ok(proto::ScheduleResponse {
+                draining: state.draining,
+                mode: mode as i32,
+                window_start: state.occurrence.map(|x| x.start),
+                window_end: state.occurrence.map(|x| x.end),
+            })
+        }
+
+        fn watch_events(&self, _: proto::WatchEventsRequest) -> EventStream {
+            let rx = self.context.events.subscribe();
+            Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+                let event = match rx.recv().await {
+                    Ok(x) => to_proto_event(&x),
+                    Err(broadcast::error::RecvError::Lagged(n)) => proto::Event {
+                        timestamp_ms: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|x| x.as_millis() as u64)
+                            .unwrap_or_default(),
+                        session: 0,
+                        kind: Some(proto::event::Kind::Dropped(n)),
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                };
+                Some((Ok(event), rx))
+            }))
+        }
+    }
+
+    fn to_proto_log_levels() -> proto::LogLevelsResponse {
+        let now = Instant::now();
+        proto::LogLevelsResponse {
+            level: log_utils::level().as_str().to_lowercase(),
+            modules: log_utils::module_levels()
+                .into_iter()
+                .map(|x| proto::ModuleLogLevel {
+                    module: x.module,
+                    level: x.level.as_str().to_lowercase(),
+                    expires_in_secs: x
+                        .expires_at
+                        .map(|x| x.saturating_duration_since(now).as_secs()),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_proto_trace_rules() -> proto::TraceRulesResponse {
+        let now = Instant::now();
+        proto::TraceRulesResponse {
+            rules: log_utils::trace_rules()
+                .into_iter()
+                .map(|x| proto::TraceRule {
+                    id: x.id,
+                    identity: x.identity,
+                    destination: x.destination,
+                    expires_in_secs: x
+                        .expires_at
+                        .map(|x| x.saturating_duration_since(now).as_secs()),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_proto_event(record: &EventRecord) -> proto::Event {
+        use proto::event::Kind;
+
+        let (session, kind) = match &record.event {
+            Event::SessionOpened {
+                session,
+                protocol,
+                server_name,
+            } => (
+                *session,
+                Kind::SessionOpened(proto::SessionOpened {
+                    protocol: protocol.as_str().to_string(),
+                    server_name: server_name.clone(),
+                }),
+            ),
+            Event::SessionClosed { session, duration } => (
+                *session,
+                Kind::SessionClosed(proto::SessionClosed {
+                    duration_ms: duration.as_millis() as u64,
+                }),
+            ),
+            Event::AuthFailure { session, username } => (
+                *session,
+                Kind::AuthFailure(proto::AuthFailure {
+                    username: username.clone(),
+                }),
+            ),
+            Event::RequestFailed { session, reason } => (
+                *session,
+                Kind::RequestFailed(proto::RequestFailed {
+                    reason: reason.clone(),
+                }),
+            ),
+            Event::CertificateExpiring {
+                role,
+                hostname,
+                stage,
+                expires_in_secs,
+            } => (
+                0,
+                Kind::CertificateExpiring(proto::CertificateExpiring {
+                    role: role.to_string(),
+                    hostname: hostname.clone(),
+                    stage: stage.to_string(),
+                    expires_in_secs: *expires_in_secs,
+                }),
+            ),
+        };
+
+        proto::Event {
+            timestamp_ms: record.timestamp,
+            session,
+            kind: Some(kind),
+        }
+    }
+
+    #[tonic::async_trait]
+    impl proto::admin_server::Admin for AdminService {
+        type WatchEventsStream = EventStream;
+
+        async fn health(
+            &self,
+            request: tonic::Request<proto::HealthRequest>,
+        ) -> Reply<proto::HealthResponse> {
+            self.0
+                .health(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn list_sessions(
+            &self,
+            request: tonic::Request<proto::ListSessionsRequest>,
+        ) -> Reply<proto::ListSessionsResponse> {
+            self.0
+                .list_sessions(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn rebalance(
+            &self,
+            request: tonic::Request<proto::RebalanceRequest>,
+        ) -> Reply<proto::RebalanceResponse> {
+            self.0
+                .rebalance(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn purge_cache(
+            &self,
+            request: tonic::Request<proto::PurgeCacheRequest>,
+        ) -> Reply<proto::PurgeCacheResponse> {
+            self.0
+                .purge_cache(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn list_log_levels(
+            &self,
+            request: tonic::Request<proto::ListLogLevelsRequest>,
+        ) -> Reply<proto::LogLevelsResponse> {
+            self.0
+                .list_log_levels(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn set_log_level(
+            &self,
+            request: tonic::Request<proto::SetLogLevelRequest>,
+        ) -> Reply<proto::LogLevelsResponse> {
+            self.0
+                .set_log_level(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn reset_log_level(
+            &self,
+            request: tonic::Request<proto::ResetLogLevelRequest>,
+        ) -> Reply<proto::LogLevelsResponse> {
+            self.0
+                .reset_log_level(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn list_trace_rules(
+            &self,
+            request: tonic::Request<proto::ListTraceRulesRequest>,
+        ) -> Reply<proto::TraceRulesResponse> {
+            self.0
+                .list_trace_rules(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn add_trace_rule(
+            &self,
+            request: tonic::Request<proto::AddTraceRuleRequest>,
+        ) -> Reply<proto::TraceRulesResponse> {
+            self.0
+                .add_trace_rule(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn remove_trace_rule(
+            &self,
+            request: tonic::Request<proto::RemoveTraceRuleRequest>,
+        ) -> Reply<proto::TraceRulesResponse> {
+            self.0
+                .remove_trace_rule(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn get_maintenance(
+            &self,
+            request: tonic::Request<proto::GetMaintenanceRequest>,
+        ) -> Reply<proto::MaintenanceResponse> {
+            self.0
+                .get_maintenance(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn set_maintenance(
+            &self,
+            request: tonic::Request<proto::SetMaintenanceRequest>,
+        ) -> Reply<proto::MaintenanceResponse> {
+            self.0
+                .set_maintenance(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn get_schedule(
+            &self,
+            request: tonic::Request<proto::GetScheduleRequest>,
+        ) -> Reply<proto::ScheduleResponse> {
+            self.0
+                .get_schedule(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn set_schedule_override(
+            &self,
+            request: tonic::Request<proto::SetScheduleOverrideRequest>,
+        ) -> Reply<proto::ScheduleResponse> {
+            self.0
+                .set_schedule_override(request.into_inner())
+                .map(tonic::Response::new)
+        }
+
+        async fn watch_events(
+            &self,
+            request: tonic::Request<proto::WatchEventsRequest>,
+        ) -> Reply<EventStream> {
+            Ok(tonic::Response::new(
+                self.0.watch_events(request.into_inner()),
+            ))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use prost::Message;
+        use std::time::Duration;
+
+        #[test]
+        fn calls_require_admin_token() {
+            let call = |authorization: Option<&str>| {
+                let mut request = tonic::Request::new(());
+                if let Some(x) = authorization {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", x.parse().unwrap());
+                }
+                check_admin_token("secret", request).map_err(|e| e.code())
+            };
+
+            assert_eq!(Some(tonic::Code::Unauthenticated), call(None).err());
+            assert_eq!(
+                Some(tonic::Code::Unauthenticated),
+                call(Some("Bearer wrong")).err()
+            );
+            assert_eq!(
+                Some(tonic::Code::Unauthenticated),
+                call(Some("secret")).err()
+            );
+            assert!(call(Some("Bearer secret")).is_ok());
+        }
+
+        #[test]
+        fn events_round_trip() {
+            let event = to_proto_event(&EventRecord {
+                timestamp: 1000,
+                event: Event::SessionClosed {
+                    session: 3,
+                    duration: Duration::from_millis(1500),
+                },
+            });
+
+            let decoded = proto::Event::decode(event.encode_to_vec().as_slice()).unwrap();
+            assert_eq!(3, decoded.session);
+            assert_eq!(
+                Some(proto::event::Kind::SessionClosed(proto::SessionClosed {
+                    duration_ms: 1500
+                })),
+                decoded.kind
+            );
+        }
+    }
+}
@@ -0,0 +1,480 @@
+//! The tunnels carried over the bidirectional streaming calls of a gRPC service, so that
+//! the endpoint may operate behind the gRPC-aware load balancers and the middleboxes passing
+//! nothing but the gRPC traffic. A client calls the method at
+//! [`GrpcTransportSettings::path`](crate::settings::GrpcTransportSettings) on the HTTP/2
+//! listener and then speaks HTTP/2 over the messages of the call, the regular tunnel
+//! requests included. The service is compatible with the clients generated, e.g., by Tonic
+//! from the definition:
+//!
+//! ```text
+//! syntax = "proto3";
+//! package trusttunnel;
+//!
+//! service Tunnel {
+//!   rpc Stream(stream Chunk) returns (stream Chunk);
+//! }
+//!
+//! message Chunk {
+//!   bytes data = 1;
+//! }
+//! ```
+//!
+//! The message boundaries carry no meaning, the `data` of the messages make up a byte
+//! stream, like the one of a TLS connection. Each call is a session of its own,
+//! as a load balancer may multiplex the calls of different clients over one connection.
+
+use crate::http_codec::RequestHeaders;
+use crate::{http_codec, log_utils, net_utils, pipe};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const CONTENT_TYPE: &str = "application/grpc";
+const STATUS_HEADER: &str = "grpc-status";
+const MESSAGE_HEADER: &str = "grpc-message";
+const ENCODING_HEADER: &str = "grpc-encoding";
+const STATUS_OK: &str = "0";
+const STATUS_UNIMPLEMENTED: &str = "12";
+
+/// The compressed flag and the length preceding each message
+const MESSAGE_PREFIX_LENGTH: usize = 5;
+/// The longest message a client may send, the default limit of the gRPC implementations
+const MAX_MESSAGE_LENGTH: usize = 4 * 1024 * 1024;
+/// The key of the `data` field of the `Chunk` message: field 1, length-delimited
+const DATA_FIELD_KEY: u64 = (1 << 3) | WIRE_TYPE_LEN;
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_I64: u64 = 1;
+const WIRE_TYPE_LEN: u64 = 2;
+const WIRE_TYPE_I32: u64 = 5;
+
+/// The largest `data` of a message the writes are split into
+const MAX_CHUNK_LENGTH: usize = 16 * 1024;
+/// The amount of the encoded messages buffered before a write waits for the transport
+const MAX_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The byte stream of the `data` of the messages of a gRPC call
+pub(crate) struct GrpcIo<IO> {
+    io: IO,
+    peer: SocketAddr,
+    /// The bytes received from the client which are not decoded yet
+    read_buffer: BytesMut,
+    /// The decoded data which is not read yet
+    data: Bytes,
+    /// Set once the client has finished sending
+    is_eof: bool,
+    /// The encoded messages which are not sent yet
+    write_buffer: BytesMut,
+}
+
+/// Completes the response with the status of the call once the data is finished
+struct CallSink {
+    sink: Box<dyn http_codec::BodySink>,
+}
+
+/// Check whether the `request` is a call of the tunnel method at `path`
+pub(crate) fn is_call(request: &RequestHeaders, path: &str) -> bool {
+    request.method == http::Method::POST
+        && request.uri.path() == path
+        && request
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.starts_with(CONTENT_TYPE))
+}
+
+/// Respond to the call of the `stream`, giving the byte stream of the call.
+/// A call with the compressed messages is refused and [`None`] is returned.
+pub(crate) fn accept(
+    stream: Box<dyn http_codec::Stream>,
+) -> io::Result<Option<GrpcIo<pipe::PipeIo>>> {
+    let peer = SocketAddr::new(stream.request().client_address()?, 0);
+    let is_compressed = stream
+        .request()
+        .request()
+        .headers
+        .get(ENCODING_HEADER)
+        .is_some_and(|x| x != "identity");
+    let (request, respond) = stream.split();
+    if is_compressed {
+        // A trailers-only response
+        respond.send_bad_response(
+            http::StatusCode::OK,
+            vec![
+                (
+                    http::header::CONTENT_TYPE.to_string(),
+                    CONTENT_TYPE.to_string(),
+                ),
+                (STATUS_HEADER.to_string(), STATUS_UNIMPLEMENTED.to_string()),
+                (
+                    MESSAGE_HEADER.to_string(),
+                    "Compression is not supported".to_string(),
+                ),
+            ],
+        )?;
+        return Ok(None);
+    }
+
+    let sink = respond
+        .send_ok_response_with_headers(
+            vec![(
+                http::header::CONTENT_TYPE.to_string(),
+                CONTENT_TYPE.to_string(),
+            )],
+            false,
+        )?
+        .into_body_sink();
+
+    Ok(Some(GrpcIo::new(
+        pipe::into_io(request.finalize(), Box::new(CallSink { sink })),
+        peer,
+    )))
+}
+
+/// Decode the `data` of the message at the beginning of the `buffer`,
+/// or [`None`] if it is incomplete
+fn decode_message(buffer: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if buffer.len() < MESSAGE_PREFIX_LENGTH {
+        return Ok(None);
+    }
+    if buffer[0] != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Compressed message is not supported",
+        ));
+    }
+    let length = u32::from_be_bytes(buffer[1..MESSAGE_PREFIX_LENGTH].try_into().unwrap()) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Message is too long: {}", length),
+        ));
+    }
+    if buffer.len() < MESSAGE_PREFIX_LENGTH + length {
+        return Ok(None);
+    }
+
+    buffer.advance(MESSAGE_PREFIX_LENGTH);
+    let mut message = buffer.split_to(length).freeze();
+    let mut data = BytesMut::new();
+    // The unknown fields are skipped, and the repeated `data` fields are concatenated
+    while message.has_remaining() {
+        let key = get_varint(&mut message)?;
+        let length = match key & 0x7 {
+            WIRE_TYPE_VARINT => {
+                get_varint(&mut message)?;
+                0
+            }
+            WIRE_TYPE_I64 => 8,
+            WIRE_TYPE_LEN => get_varint(&mut message)? as usize,
+            WIRE_TYPE_I32 => 4,
+            x => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported wire type: {}", x),
+                ))
+            }
+        };
+        if message.remaining() < length {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Field exceeds message",
+            ));
+        }
+        let field = message.split_to(length);
+        if key == DATA_FIELD_KEY {
+            data.extend_from_slice(&field);
+        }
+    }
+
+    Ok(Some(data.freeze()))
+}
+
+/// Append a message carrying the `data` to the `buffer`
+fn encode_message(buffer: &mut BytesMut, data: &[u8]) {
+    // A field of the default value is omitted
+    let length = match data.len() {
+        0 => 0,
+        n => varint_len(DATA_FIELD_KEY) + varint_len(n as u64) + n,
+    };
+    buffer.put_u8(0);
+    buffer.put_u32(length as u32);
+    if !data.is_empty() {
+        put_varint(buffer, DATA_FIELD_KEY);
+        put_varint(buffer, data.len() as u64);
+        buffer.extend_from_slice(data);
+    }
+}
+
+/// Read a base 128 varint of the protocol buffers encoding
+fn get_varint(buffer: &mut Bytes) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buffer.has_remaining() {
+            break;
+        }
+        let b = buffer.get_u8();
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "Malformed varint"))
+}
+
+fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+fn put_varint(buffer: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buffer.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.put_u8(value as u8);
+}
+
+impl<IO> GrpcIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(io: IO, peer: SocketAddr) -> Self {
+        Self {
+            io,
+            peer,
+            read_buffer: Default::default(),
+            data: Bytes::new(),
+            is_eof: false,
+            write_buffer: Default::default(),
+        }
+    }
+
+    /// Send the buffered messages to the transport
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let n = futures::ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buffer))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> AsyncRead for GrpcIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.data.is_empty() {
+                let n = this.data.len().min(buf.remaining());
+                buf.put_slice(&this.data.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.is_eof || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(x) = decode_message(&mut this.read_buffer)? {
+                this.data = x;
+                continue;
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            futures::ready!(Pin::new(&mut this.io).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if !this.read_buffer.is_empty() {
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+                this.is_eof = true;
+            }
+            this.read_buffer.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<IO> AsyncWrite for GrpcIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_buffer.len() >= MAX_WRITE_BUFFER_SIZE {
+            futures::ready!(this.poll_write_buffer(cx))?;
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_CHUNK_LENGTH);
+        encode_message(&mut this.write_buffer, &buf[..n]);
+        if let Poll::Ready(Err(e)) = this.poll_write_buffer(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl<IO> net_utils::PeerAddr for GrpcIo<IO> {
+    /// The port is not known as the request of the call is passed by the HTTP codec
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+#[async_trait]
+impl pipe::Sink for CallSink {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.sink.id()
+    }
+
+    fn write(&mut self, data: Bytes) -> io::Result<Bytes> {
+        self.sink.write(data)
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert(STATUS_HEADER, http::HeaderValue::from_static(STATUS_OK));
+        self.sink.send_trailers(trailers)
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        self.sink.wait_writable().await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(method: http::Method, content_type: &str) -> RequestHeaders {
+        http::Request::builder()
+            .method(method)
+            .uri("/trusttunnel.Tunnel/Stream")
+            .header(http::header::CONTENT_TYPE, content_type)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn calls() {
+        let path = "/trusttunnel.Tunnel/Stream";
+        assert!(is_call(
+            &request(http::Method::POST, "application/grpc"),
+            path
+        ));
+        assert!(is_call(
+            &request(http::Method::POST, "application/grpc+proto"),
+            path
+        ));
+        assert!(!is_call(
+            &request(http::Method::POST, "application/grpc"),
+            "/ws"
+        ));
+        assert!(!is_call(
+            &request(http::Method::GET, "application/grpc"),
+            path
+        ));
+        assert!(!is_call(&request(http::Method::POST, "text/plain"), path));
+    }
+
+    #[test]
+    fn decodes_messages() {
+        let mut buffer = BytesMut::new();
+        encode_message(&mut buffer, b"hello");
+        assert_eq!(b"\x00\x00\x00\x00\x07\x0a\x05hello", &buffer[..]);
+        // An unknown varint field and a split `data` field
+        buffer.extend_from_slice(b"\x00\x00\x00\x00\x09\x10\x96\x01\x0a\x01a\x0a\x01b");
+        // An empty message
+        encode_message(&mut buffer, b"");
+
+        assert_eq!(
+            Some(Bytes::from_static(b"hello")),
+            decode_message(&mut buffer).unwrap()
+        );
+        assert_eq!(
+            Some(Bytes::from_static(b"ab")),
+            decode_message(&mut buffer).unwrap()
+        );
+        assert_eq!(Some(Bytes::new()), decode_message(&mut buffer).unwrap());
+        assert_eq!(None, decode_message(&mut buffer).unwrap());
+
+        buffer.extend_from_slice(b"\x00\x00\x00\x00\x03\x0a\x05h");
+        assert_eq!(
+            ErrorKind::InvalidData,
+            decode_message(&mut buffer).unwrap_err().kind()
+        );
+        let mut compressed = BytesMut::from(&b"\x01\x00\x00\x00\x00"[..]);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            decode_message(&mut compressed).unwrap_err().kind()
+        );
+    }
+
+    #[tokio::test]
+    async fn exchanges_messages() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = GrpcIo::new(server, (std::net::Ipv4Addr::LOCALHOST, 0).into());
+
+        let long = vec![b'x'; 300];
+        let mut sent = BytesMut::new();
+        encode_message(&mut sent, b"hello, ");
+        encode_message(&mut sent, &long);
+        // Split in the middle of a prefix
+        client.write_all(&sent[..3]).await.unwrap();
+        tokio::task::yield_now().await;
+        client.write_all(&sent[3..]).await.unwrap();
+
+        let mut received = vec![0; 7 + long.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(b"hello, ", &received[..7]);
+        assert_eq!(long, received[7..]);
+
+        server.write_all(b"world").await.unwrap();
+        server.flush().await.unwrap();
+        let mut reply = [0; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"\x00\x00\x00\x00\x07\x0a\x05world", &reply);
+
+        // A message cut short by the end of the call
+        client.write_all(b"\x00\x00\x00").await.unwrap();
+        client.shutdown().await.unwrap();
+        let error = server.read(&mut received).await.unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, error.kind());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::core;
+use crate::metrics::Metrics;
+use crate::settings::{ForwardProtocolSettings, HopHealthCheckSettings, Socks5ForwarderSettings};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// The SOCKS5 proxies, the main one followed by the fallback ones, along with their state.
+/// A connection goes through the first proxy which is up, so the new tunnels are not failed
+/// while the main proxy is down.
+pub(crate) struct HopSet {
+    hops: Vec<Hop>,
+    /// Whether the hops are probed periodically
+    is_probed: bool,
+}
+
+struct Hop {
+    address: SocketAddr,
+    /// The time of the last failure, [`None`] if the hop is up
+    down_since: Mutex<Option<Instant>>,
+}
+
+impl HopSet {
+    pub fn new(settings: &Socks5ForwarderSettings) -> Self {
+        Self {
+            hops: std::iter::once(settings.address)
+                .chain(settings.fallback_addresses.iter().copied())
+                .map(|address| Hop {
+                    address,
+                    down_since: Default::default(),
+                })
+                .collect(),
+            is_probed: settings.health_check.is_some(),
+        }
+    }
+
+    /// Get the hops in the order of trying: the ones which are up first,
+    /// each group in the configured order
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let (up, down): (Vec<_>, Vec<_>) = self.hops.iter().partition(|x| self.is_up(x));
+        up.into_iter().chain(down).map(|x| x.address).collect()
+    }
+
+    fn is_up(&self, hop: &Hop) -> bool {
+        match *hop.down_since.lock().unwrap() {
+            None => true,
+            // Without the probing, the only way to find out whether a hop is back is to try it
+            Some(x) => {
+                !self.is_probed && x.elapsed() >= HopHealthCheckSettings::DOWN_RETRY_INTERVAL
+            }
+        }
+    }
+
+    /// Record the outcome of a connection attempt to the hop
+    pub fn report(
+        &self,
+        metrics: &Metrics,
+        address: SocketAddr,
+        result: Result<Duration, &io::Error>,
+    ) {
+        let hop = match self.hops.iter().find(|x| x.address == address) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let label = address.to_string();
+        let mut down_since = hop.down_since.lock().unwrap();
+        match result {
+            Ok(latency) => {
+                metrics.observe_upstream_hop_connect(&label, latency);
+                if down_since.take().is_some() {
+                    info!("Upstream hop {} is up", address);
+                }
+            }
+            Err(e) => {
+                if down_since.is_none() {
+                    warn!("Upstream hop {} is down: {}", address, e);
+                }
+                *down_since = Some(Instant::now());
+            }
+        }
+        metrics.set_upstream_hop_up(&label, down_since.is_none());
+    }
+}
+
+/// Connect to the first SOCKS5 proxy accepting the connection
+pub(crate) async fn connect(context: &core::Context) -> io::Result<(TcpStream, SocketAddr)> {
+    let hops = context
+        .socks5_hops
+        .as_ref()
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "No upstream hops configured"))?;
+
+    let mut error = None;
+    for address in hops.candidates() {
+        let started = Instant::now();
+        let result = tokio::time::timeout(
+            context.settings.connection_establishment_timeout,
+            TcpStream::connect(address),
+        )
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+        hops.report(
+            &context.metrics,
+            address,
+            result.as_ref().map(|_| started.elapsed()),
+        );
+        match result {
+            Ok(x) => return Ok((x, address)),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    Err(error.unwrap_or_else(|| io::Error::new(ErrorKind::Other, "No upstream hops configured")))
+}
+
+/// Probe the SOCKS5 proxies periodically, if configured
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let (hops, settings) = match (&context.socks5_hops, &context.settings.forward_protocol) {
+        (Some(hops), ForwardProtocolSettings::Socks5(x)) => match &x.health_check {
+            Some(settings) => (hops, settings),
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+
+    let mut interval = tokio::time::interval(settings.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let probe_periodically = async {
+        loop {
+            interval.tick().await;
+            futures::future::join_all(
+                hops.hops
+                    .iter()
+                    .map(|x| probe(&context, hops, x.address, settings.timeout)),
+            )
+            .await;
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = probe_periodically => Ok(()),
+    }
+}
+
+/// Check that the hop accepts connections, including the TLS handshake if configured
+async fn probe(context: &core::Context, hops: &HopSet, address: SocketAddr, timeout: Duration) {
+    let started = Instant::now();
+    let result = tokio::time::timeout(timeout, async {
+        let stream = TcpStream::connect(address).await?;
+        if let Some(x) = &context.socks5_tls {
+            x.connect(stream, address).await?;
+        }
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+
+    hops.report(
+        &context.metrics,
+        address,
+        result.as_ref().map(|_| started.elapsed()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::HopHealthCheckSettings;
+
+    #[test]
+    fn down_hops_are_tried_last() {
+        let addresses: Vec<SocketAddr> = vec![
+            "192.0.2.1:1080".parse().unwrap(),
+            "192.0.2.2:1080".parse().unwrap(),
+            "192.0.2.3:1080".parse().unwrap(),
+        ];
+        let settings = Socks5ForwarderSettings::builder()
+            .server_address(addresses[0])
+            .unwrap()
+            .fallback_addresses(addresses[1..].to_vec())
+            .health_check(HopHealthCheckSettings::builder().build().unwrap())
+            .build()
+            .unwrap();
+        let hops = HopSet::new(&settings);
+        let metrics = Metrics::new().unwrap();
+        assert_eq!(addresses, hops.candidates());
+
+        let error = io::Error::from(ErrorKind::ConnectionRefused);
+        hops.report(&metrics, addresses[0], Err(&error));
+        assert_eq!(
+            vec![addresses[1], addresses[2], addresses[0]],
+            hops.candidates()
+        );
+
+        hops.report(&metrics, addresses[0], Ok(Duration::from_millis(10)));
+        assert_eq!(addresses, hops.candidates());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unprobed_down_hop_is_retried_later() {
+        let addresses: Vec<SocketAddr> = vec![
+            "192.0.2.1:1080".parse().unwrap(),
+            "192.0.2.2:1080".parse().unwrap(),
+        ];
+        let settings = Socks5ForwarderSettings::builder()
+            .server_address(addresses[0])
+            .unwrap()
+            .fallback_addresses(addresses[1..].to_vec())
+            .build()
+            .unwrap();
+        let hops = HopSet::new(&settings);
+        let metrics = Metrics::new().unwrap();
+
+        let error = io::Error::from(ErrorKind::ConnectionRefused);
+        hops.report(&metrics, addresses[0], Err(&error));
+        tokio::time::sleep(HopHealthCheckSettings::DOWN_RETRY_INTERVAL - Duration::from_secs(1))
+            .await;
+        assert_eq!(vec![addresses[1], addresses[0]], hops.candidates());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(addresses, hops.candidates());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::{log_id, log_utils, pipe};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::io::ErrorKind;
+
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+const TLS_MAX_RECORD_LENGTH: usize = 16 * 1024;
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+/// The HTTP request heads exceeding this size are not rewritten
+const MAX_HTTP_HEAD_LENGTH: usize = 8 * 1024;
+
+/// The names replacing the ones sent by a client in the beginning of a tunneled connection
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HostOverride {
+    /// Replaces the server name in the TLS ClientHello
+    pub sni: Option<String>,
+    /// Replaces the `Host` header of the first plain HTTP request
+    pub host: Option<String>,
+}
+
+enum Rewrite {
+    /// More data is needed to make a decision
+    Incomplete,
+    Done(Bytes),
+    /// The data is passed as is
+    NotApplicable,
+}
+
+/// Rewrites the first ClientHello or HTTP request head written into the peer connection.
+/// Only the first message is inspected, the rest of the stream is passed through as is.
+struct OverridingSink {
+    sink: Box<dyn pipe::Sink>,
+    names: Option<HostOverride>,
+    /// The client data collected until the first message is complete
+    buffer: BytesMut,
+    /// The rewritten data which is not yet accepted by the sink
+    pending: Bytes,
+}
+
+/// Wrap the sink of a tunneled connection to apply the overrides
+pub(crate) fn wrap(sink: Box<dyn pipe::Sink>, names: HostOverride) -> Box<dyn pipe::Sink> {
+    if names.sni.is_none() && names.host.is_none() {
+        return sink;
+    }
+
+    Box::new(OverridingSink {
+        sink,
+        names: Some(names),
+        buffer: Default::default(),
+        pending: Bytes::new(),
+    })
+}
+
+impl OverridingSink {
+    fn rewrite(&self, names: &HostOverride) -> Rewrite {
+        match (self.buffer.first(), names) {
+            (None, _) => Rewrite::Incomplete,
+            (Some(&TLS_CONTENT_TYPE_HANDSHAKE), HostOverride { sni: Some(sni), .. }) => {
+                rewrite_client_hello(&self.buffer, sni)
+            }
+            (
+                Some(_),
+                HostOverride {
+                    host: Some(host), ..
+                },
+            ) => rewrite_http_host(&self.buffer, host),
+            _ => Rewrite::NotApplicable,
+        }
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.pending = self.sink.write(std::mem::take(&mut self.pending))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl pipe::Sink for OverridingSink {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.sink.id()
+    }
+
+    fn write(&mut self, data: Bytes) -> io::Result<Bytes> {
+        let names = match self.names.as_ref() {
+            None => {
+                self.flush_pending()?;
+                return match self.pending.is_empty() {
+                    true => self.sink.write(data),
+                    false => Ok(data),
+                };
+            }
+            Some(x) => x,
+        };
+
+        self.buffer.extend_from_slice(&data);
+        match self.rewrite(names) {
+            Rewrite::Incomplete => return Ok(Bytes::new()),
+            Rewrite::Done(x) => {
+                log_id!(debug, self.id(), "Overridden host name in {:?}", names);
+                self.pending = x;
+                self.buffer.clear();
+            }
+            Rewrite::NotApplicable => self.pending = self.buffer.split().freeze(),
+        }
+        self.names = None;
+
+        self.flush_pending()?;
+        Ok(Bytes::new())
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        if self.names.take().is_some() {
+            self.pending = self.buffer.split().freeze();
+        }
+        self.flush_pending()?;
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "Shut down with unsent data",
+            ));
+        }
+        self.sink.eof()
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            self.sink.wait_writable().await?;
+            self.flush_pending()?;
+        }
+        self.sink.wait_writable().await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.wait_writable().await?;
+        self.sink.flush().await
+    }
+}
+
+/// Replace the server name extension of a ClientHello,
+/// or add one if the client did not send it
+fn rewrite_client_hello(data: &[u8], sni: &str) -> Rewrite {
+    if data.len() < TLS_RECORD_HEADER_LENGTH {
+        return Rewrite::Incomplete;
+    }
+    let record_length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if record_length > TLS_MAX_RECORD_LENGTH {
+        return Rewrite::NotApplicable;
+    }
+    if data.len() < TLS_RECORD_HEADER_LENGTH + record_length {
+        return Rewrite::Incomplete;
+    }
+
+    let record = &data[TLS_RECORD_HEADER_LENGTH..TLS_RECORD_HEADER_LENGTH + record_length];
+    let (extensions_offset, server_name) = match locate_server_name(record) {
+        // A ClientHello fragmented over several records is not supported
+        None => return Rewrite::NotApplicable,
+        Some(x) => x,
+    };
+
+    let mut extension = Vec::with_capacity(9 + sni.len());
+    extension.extend(TLS_EXTENSION_SERVER_NAME.to_be_bytes());
+    extension.extend((5 + sni.len() as u16).to_be_bytes());
+    // server name list
+    extension.extend((3 + sni.len() as u16).to_be_bytes());
+    // host_name(0)
+    extension.push(0);
+    extension.extend((sni.len() as u16).to_be_bytes());
+    extension.extend(sni.as_bytes());
+
+    let old = server_name.unwrap_or(record.len()..record.len());
+    let new_length = record.len() - old.len() + extension.len();
+    if new_length > TLS_MAX_RECORD_LENGTH {
+        return Rewrite::NotApplicable;
+    }
+    let mut rewritten = Vec::with_capacity(data.len() - record.len() + new_length);
+    rewritten.extend(&data[..3]);
+    rewritten.extend((new_length as u16).to_be_bytes());
+    let mut record = [&record[..old.start], &extension, &record[old.end..]].concat();
+    let delta = |x: usize| x + extension.len() - old.len();
+    record[1..4].copy_from_slice(&(delta(record_length - 4) as u32).to_be_bytes()[1..]);
+    let extensions_length =
+        u16::from_be_bytes([record[extensions_offset], record[extensions_offset + 1]]) as usize;
+    record[extensions_offset..extensions_offset + 2]
+        .copy_from_slice(&(delta(extensions_length) as u16).to_be_bytes());
+    rewritten.extend(record);
+    rewritten.extend(&data[TLS_RECORD_HEADER_LENGTH + record_length..]);
+
+    Rewrite::Done(Bytes::from(rewritten))
+}
+
+/// Find the server name extension in a ClientHello handshake message.
+///
+/// # Return
+///
+/// The offset of the extensions length field and the range of the server name
+/// extension if there is one
+fn locate_server_name(message: &[u8]) -> Option<(usize, Option<std::ops::Range<usize>>)> {
+    let u8_at = |i: usize| message.get(i).map(|x| *x as usize);
+    let u16_at = |i: usize| Some((u8_at(i)? << 8) | u8_at(i + 1)?);
+
+    if u8_at(0)? != TLS_HANDSHAKE_CLIENT_HELLO as usize
+        || ((u16_at(1)? << 8) | u8_at(3)?) + 4 != message.len()
+    {
+        return None;
+    }
+
+    // legacy_version and random
+    let mut offset = 4 + 2 + 32;
+    // legacy_session_id
+    offset += 1 + u8_at(offset)?;
+    // cipher_suites
+    offset += 2 + u16_at(offset)?;
+    // legacy_compression_methods
+    offset += 1 + u8_at(offset)?;
+
+    let extensions_offset = offset;
+    let extensions_end = offset + 2 + u16_at(offset)?;
+    if extensions_end != message.len() {
+        return None;
+    }
+
+    offset += 2;
+    while offset < extensions_end {
+        let extension_type = u16_at(offset)?;
+        let extension_end = offset + 4 + u16_at(offset + 2)?;
+        if extension_end > extensions_end {
+            return None;
+        }
+        if extension_type == TLS_EXTENSION_SERVER_NAME as usize {
+            return Some((extensions_offset, Some(offset..extension_end)));
+        }
+        offset = extension_end;
+    }
+
+    Some((extensions_offset, None))
+}
+
+/// Replace the `Host` header of a plain HTTP request
+fn rewrite_http_host(data: &[u8], host: &str) -> Rewrite {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let head_length = match httparse::Request::new(&mut headers).parse(data) {
+        Ok(httparse::Status::Complete(x)) => x,
+        Ok(httparse::Status::Partial) if data.len() < MAX_HTTP_HEAD_LENGTH => {
+            return Rewrite::Incomplete
+        }
+        Ok(httparse::Status::Partial) | Err(_) => return Rewrite::NotApplicable,
+    };
+
+    let head = match std::str::from_utf8(&data[..head_length]) {
+        Ok(x) => x,
+        Err(_) => return Rewrite::NotApplicable,
+    };
+    let mut rewritten = String::with_capacity(head_length + host.len());
+    let mut replaced = false;
+    for line in head.split_inclusive("\r\n") {
+        match line.split_once(':') {
+            Some((name, _)) if !replaced && name.eq_ignore_ascii_case("host") => {
+                replaced = true;
+                rewritten.push_str(name);
+                rewritten.push_str(": ");
+                rewritten.push_str(host);
+                rewritten.push_str("\r\n");
+            }
+            _ => rewritten.push_str(line),
+        }
+    }
+    if !replaced {
+        return Rewrite::NotApplicable;
+    }
+
+    let mut rewritten = rewritten.into_bytes();
+    rewritten.extend(&data[head_length..]);
+    Rewrite::Done(Bytes::from(rewritten))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // supported_versions
+        extensions.extend([0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(x) = sni {
+            let n = x.len() as u16;
+            extensions.extend([0x00, 0x00]);
+            extensions.extend((n + 5).to_be_bytes());
+            extensions.extend((n + 3).to_be_bytes());
+            extensions.push(0);
+            extensions.extend(n.to_be_bytes());
+            extensions.extend(x.as_bytes());
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0xaa; 32]);
+        body.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut message = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        message.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+
+        let mut record = vec![TLS_CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend((message.len() as u16).to_be_bytes());
+        record.extend(message);
+        record
+    }
+
+    fn parse_sni(data: &[u8]) -> Option<String> {
+        let (_, record) = tls_parser::parse_tls_plaintext(data).unwrap();
+        let client_hello = match &record.msg[0] {
+            tls_parser::TlsMessage::Handshake(tls_parser::TlsMessageHandshake::ClientHello(x)) => {
+                x.clone()
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        };
+        let (_, extensions) =
+            tls_parser::parse_tls_client_hello_extensions(client_hello.ext.unwrap()).unwrap();
+        extensions.into_iter().find_map(|x| match x {
+            tls_parser::TlsExtension::SNI(x) => Some(String::from_utf8(x[0].1.to_vec()).unwrap()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn client_hello() {
+        for original in [None, Some("a.example.org")] {
+            let hello = make_client_hello(original);
+            assert!(matches!(
+                rewrite_client_hello(&hello[..hello.len() - 1], "x"),
+                Rewrite::Incomplete
+            ));
+
+            let mut data = hello.clone();
+            data.extend(b"tail");
+            let rewritten = match rewrite_client_hello(&data, "front.example.net") {
+                Rewrite::Done(x) => x,
+                _ => panic!("Not rewritten"),
+            };
+            assert_eq!(Some("front.example.net".to_string()), parse_sni(&rewritten));
+            assert!(rewritten.ends_with(b"tail"));
+        }
+    }
+
+    #[test]
+    fn http_host() {
+        let request = b"GET / HTTP/1.1\r\nhOsT: example.org\r\nAccept: */*\r\n\r\nbody";
+        assert!(matches!(
+            rewrite_http_host(&request[..20], "x"),
+            Rewrite::Incomplete
+        ));
+        match rewrite_http_host(request, "internal.example.org") {
+            Rewrite::Done(x) => assert_eq!(
+                &b"GET / HTTP/1.1\r\nhOsT: internal.example.org\r\nAccept: */*\r\n\r\nbody"[..],
+                x
+            ),
+            _ => panic!("Not rewritten"),
+        }
+        assert!(matches!(
+            rewrite_http_host(b"\x16\x03\x01\x00", "x"),
+            Rewrite::NotApplicable
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! The host name patterns of the destination rules. A pattern is one of:
+//!
+//! * `example.org`, matching the name itself,
+//! * `*.example.org`, matching the subdomains of the name,
+//! * `.example.org`, matching the name and its subdomains,
+//! * `~regex`, matching the names the regular expression matches as a whole.
+//!
+//! The patterns of a rule list are compiled into [`HostPatterns`], where the names are
+//! looked up in the hash maps, one lookup per label of the host name, and the regular
+//! expressions are run at once as a set, so the cost of a match does not grow with
+//! the number of the rules.
+
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+const REGEX_PREFIX: char = '~';
+
+/// The compiled patterns of a rule list
+#[derive(Debug, Default)]
+pub(crate) struct HostPatterns {
+    /// The positions of the patterns keyed by the name they match
+    exact: HashMap<String, Vec<usize>>,
+    /// The positions of the `*.` patterns keyed by the name of their parent domain
+    subdomains: HashMap<String, Vec<usize>>,
+    /// The positions of the `.` patterns keyed by the name of the domain
+    suffixes: HashMap<String, Vec<usize>>,
+    regexes: Option<RegexSet>,
+    /// The positions of the patterns of [`Self::regexes`] in the set order
+    regex_positions: Vec<usize>,
+}
+
+enum Pattern<'a> {
+    Exact(&'a str),
+    Subdomains(&'a str),
+    Suffix(&'a str),
+    Regex(&'a str),
+}
+
+impl<'a> Pattern<'a> {
+    fn parse(x: &'a str) -> Self {
+        if let Some(x) = x.strip_prefix(REGEX_PREFIX) {
+            Self::Regex(x)
+        } else if let Some(x) = x.strip_prefix("*.") {
+            Self::Subdomains(x)
+        } else if let Some(x) = x.strip_prefix('.') {
+            Self::Suffix(x)
+        } else {
+            Self::Exact(x)
+        }
+    }
+}
+
+impl HostPatterns {
+    /// Compile the patterns along with their positions in the rule list.
+    /// A malformed regular expression never matches.
+    pub fn new<'a>(patterns: impl IntoIterator<Item = (usize, &'a str)>) -> Self {
+        let mut result = Self::default();
+        let mut regexes = vec![];
+        for (i, x) in patterns {
+            let (map, key) = match Pattern::parse(x) {
+                Pattern::Exact(x) => (&mut result.exact, x),
+                Pattern::Subdomains(x) => (&mut result.subdomains, x),
+                Pattern::Suffix(x) => (&mut result.suffixes, x),
+                Pattern::Regex(x) => {
+                    regexes.push(anchored(x));
+                    result.regex_positions.push(i);
+                    continue;
+                }
+            };
+            map.entry(key.to_ascii_lowercase()).or_default().push(i);
+        }
+
+        if regexes.is_empty() {
+            return result;
+        }
+        result.regexes = match build_set(&regexes) {
+            Ok(x) => Some(x),
+            Err(_) => {
+                // Leave out the malformed ones, which are rare enough to compile
+                // them one by one to find them
+                let (kept, positions): (Vec<_>, Vec<_>) = regexes
+                    .into_iter()
+                    .zip(result.regex_positions.drain(..))
+                    .filter(|(x, _)| Regex::new(x).is_ok())
+                    .unzip();
+                result.regex_positions = positions;
+                build_set(&kept).ok()
+            }
+        };
+        result
+    }
+
+    /// Get the positions of the patterns matching the host name in ascending order
+    pub fn matching(&self, host: &str) -> SmallVec<[usize; 4]> {
+        let host = normalize(host);
+        let mut result = SmallVec::new();
+        let mut extend = |map: &HashMap<String, Vec<usize>>, key: &str| {
+            if let Some(x) = map.get(key) {
+                result.extend_from_slice(x);
+            }
+        };
+        extend(&self.exact, &host);
+        extend(&self.suffixes, &host);
+        for (i, _) in host.match_indices('.') {
+            let parent = &host[i + 1..];
+            extend(&self.subdomains, parent);
+            extend(&self.suffixes, parent);
+        }
+        if let Some(x) = &self.regexes {
+            result.extend(x.matches(&host).iter().map(|i| self.regex_positions[i]));
+        }
+
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    /// Get the position of the first pattern matching the host name which is accepted
+    pub fn find(&self, host: &str, accept: impl FnMut(&usize) -> bool) -> Option<usize> {
+        self.matching(host).into_iter().find(accept)
+    }
+}
+
+/// Check if the host name matches the pattern, compiling the pattern anew.
+/// Prefer [`HostPatterns`] for matching against a number of patterns.
+pub(crate) fn matches(pattern: &str, host: &str) -> bool {
+    let host = normalize(host);
+    let is_subdomain = |parent: &str| {
+        host.len()
+            .checked_sub(parent.len() + 1)
+            .filter(|x| host.as_bytes()[*x] == b'.')
+            .is_some_and(|x| host[x + 1..].eq_ignore_ascii_case(parent))
+    };
+    match Pattern::parse(pattern) {
+        Pattern::Exact(x) => host.eq_ignore_ascii_case(x),
+        Pattern::Subdomains(x) => is_subdomain(x),
+        Pattern::Suffix(x) => host.eq_ignore_ascii_case(x) || is_subdomain(x),
+        Pattern::Regex(x) => build_set(&[anchored(x)]).is_ok_and(|x| x.is_match(&host)),
+    }
+}
+
+/// Check the pattern is well-formed
+pub(crate) fn validate(pattern: &str) -> Result<(), String> {
+    match Pattern::parse(pattern) {
+        Pattern::Regex(x) => Regex::new(&anchored(x))
+            .map(|_| ())
+            .map_err(|e| format!("Invalid regular expression {}: {}", x, e)),
+        Pattern::Exact(x) | Pattern::Subdomains(x) | Pattern::Suffix(x) if x.is_empty() => {
+            Err(format!("Invalid host name pattern {}", pattern))
+        }
+        Pattern::Exact(_) | Pattern::Subdomains(_) | Pattern::Suffix(_) => Ok(()),
+    }
+}
+
+/// Check if the pattern is a regular expression, which is left as is on canonicalizing
+pub(crate) fn is_regex(pattern: &str) -> bool {
+    pattern.starts_with(REGEX_PREFIX)
+}
+
+fn anchored(x: &str) -> String {
+    format!("^(?:{})$", x)
+}
+
+fn build_set(regexes: &[String]) -> Result<RegexSet, regex::Error> {
+    RegexSetBuilder::new(regexes).case_insensitive(true).build()
+}
+
+fn normalize(host: &str) -> Cow<'_, str> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    match host.bytes().any(|x| x.is_ascii_uppercase()) {
+        true => Cow::Owned(host.to_ascii_lowercase()),
+        false => Cow::Borrowed(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        let patterns = HostPatterns::new(
+            [
+                "example.org",
+                "*.example.org",
+                ".example.net",
+                r"~cdn[0-9]+\.example\.com",
+                "~(unclosed",
+                "~.*\\.internal",
+            ]
+            .into_iter()
+            .enumerate(),
+        );
+        let matching = |x| patterns.matching(x).to_vec();
+
+        assert_eq!(vec![0], matching("Example.org."));
+        assert_eq!(vec![1], matching("a.b.example.org"));
+        assert!(matching("aexample.org").is_empty());
+        assert_eq!(vec![2], matching("example.net"));
+        assert_eq!(vec![2], matching("www.example.net"));
+        assert_eq!(vec![3], matching("CDN12.example.com"));
+        // The regular expressions are anchored
+        assert!(matching("cdn12.example.com.evil").is_empty());
+        assert!(matching("xcdn12.example.com").is_empty());
+        assert_eq!(vec![5], matching("db.internal"));
+        assert_eq!(Some(5), patterns.find("db.internal", |x| *x > 3));
+
+        for (pattern, host) in [
+            ("example.org", "EXAMPLE.org"),
+            ("*.example.org", "a.example.org"),
+            (".example.net", "example.net"),
+            (r"~cdn[0-9]+\.example\.com", "cdn1.example.com"),
+        ] {
+            assert!(matches(pattern, host), "{} {}", pattern, host);
+        }
+        assert!(!matches("*.example.org", "example.org"));
+        assert!(!matches("~(unclosed", "(unclosed"));
+
+        assert!(validate("~(unclosed").is_err());
+        assert!(validate("*.").is_err());
+        assert!(validate(r"~[a-z]+\.example\.org").is_ok());
+    }
+
+    #[test]
+    fn keeps_rule_order() {
+        let patterns = HostPatterns::new(
+            [".example.org", "~.*", "www.example.org", "*.example.org"]
+                .into_iter()
+                .enumerate(),
+        );
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            patterns.matching("www.example.org").to_vec()
+        );
+        assert_eq!(vec![0, 1], patterns.matching("example.org").to_vec());
+    }
+}
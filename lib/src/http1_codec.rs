@@ -1,8 +1,9 @@
+use crate::capacity::Category;
 use crate::http_codec::{RequestHeaders, ResponseHeaders};
 use crate::pipe::Sink;
 use crate::settings::Settings;
 use crate::tls_demultiplexer::Protocol;
-use crate::{datagram_pipe, http_codec, log_id, log_utils, net_utils, pipe, utils};
+use crate::{capacity, datagram_pipe, http_codec, log_id, log_utils, net_utils, pipe, utils};
 use async_trait::async_trait;
 use bytes::{BufMut, Bytes, BytesMut};
 use std::io;
@@ -18,6 +19,8 @@ pub(crate) const MAX_HEADERS_NUM: usize = 32;
 
 pub(crate) struct Http1Codec<IO> {
     state: State,
+    /// Accounts the size of the buffer of [`Self::state`]
+    state_buffer: capacity::Buffer,
     transport_stream: IO,
     /// Receives messages from [`StreamSink.download_tx`]
     download_rx: mpsc::Receiver<Bytes>,
@@ -96,6 +99,7 @@ where
             state: State::WaitingRequest(WaitingRequest {
                 buffer: BytesMut::with_capacity(MAX_RAW_HEADERS_SIZE),
             }),
+            state_buffer: capacity::Buffer::new(Category::Codec),
             transport_stream,
             download_rx,
             download_tx: Some(download_tx),
@@ -181,6 +185,7 @@ where
 {
     async fn listen(&mut self) -> io::Result<Option<Box<dyn http_codec::Stream>>> {
         loop {
+            self.state_buffer.set(self.state.buffer_capacity());
             let wait_read = async {
                 let mut buffer = self.state.take_buffer();
                 if buffer.is_empty() {
@@ -363,6 +368,13 @@ impl State {
             State::RequestInProgress(x) => std::mem::take(&mut x.buffer),
         }
     }
+
+    fn buffer_capacity(&self) -> usize {
+        match self {
+            State::WaitingRequest(x) => x.buffer.capacity(),
+            State::RequestInProgress(x) => x.buffer.capacity(),
+        }
+    }
 }
 
 #[async_trait]
@@ -392,6 +404,29 @@ impl http_codec::RespondedStreamSink for StreamSink {
     fn into_datagram_sink(self: Box<Self>) -> Box<dyn http_codec::DroppingSink> {
         self
     }
+
+    fn into_body_sink(self: Box<Self>) -> Box<dyn http_codec::BodySink> {
+        self
+    }
+}
+
+impl http_codec::BodySink for StreamSink {
+    /// The body is expected to be sent in the chunked transfer coding, as HTTP/1 has
+    /// no other means to carry the trailers
+    fn send_trailers(&mut self, trailers: http::HeaderMap) -> io::Result<()> {
+        log_id!(debug, self.id, "Sending trailers: {:?}", trailers);
+        self.download_tx
+            .as_ref()
+            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?
+            .try_send(encode_trailers(&trailers))
+            .map_err(|e| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to put trailers in queue: {}", e),
+                )
+            })?;
+        self.eof()
+    }
 }
 
 #[async_trait]
@@ -484,14 +519,19 @@ pub(crate) fn encode_request(request: &RequestHeaders) -> Bytes {
 
     encoded.put(request.method.as_str().as_bytes());
     encoded.put([b' '].as_slice());
-    encoded.put(
-        request
-            .uri
-            .path_and_query()
-            .map(http::uri::PathAndQuery::as_str)
-            .unwrap_or("/")
-            .as_bytes(),
-    );
+    match request
+        .uri
+        .path_and_query()
+        .map(http::uri::PathAndQuery::as_str)
+    {
+        // An absolute-form target may have the query without the path, like `http://a?b`
+        Some(x) if x.starts_with('?') => {
+            encoded.put([b'/'].as_slice());
+            encoded.put(x.as_bytes());
+        }
+        Some(x) => encoded.put(x.as_bytes()),
+        None => encoded.put([b'/'].as_slice()),
+    }
     encoded.put(" HTTP/1.".as_bytes());
     encoded.put([('0' as u32 + version_minor_digit(request.version)) as u8].as_slice());
     encoded.put("\r\n".as_bytes());
@@ -529,6 +569,14 @@ pub(crate) fn encode_response(response: ResponseHeaders) -> Bytes {
     encoded.freeze()
 }
 
+/// Encode the trailer section along with the last chunk of a body
+/// in the chunked transfer coding
+pub(crate) fn encode_trailers(trailers: &http::HeaderMap) -> Bytes {
+    let mut encoded = BytesMut::new();
+    encoded.put("0\r\n".as_bytes());
+    encode_headers(encoded, trailers).freeze()
+}
+
 pub(crate) fn decode_request(
     mut buffer: BytesMut,
     headers_num_cap: usize,
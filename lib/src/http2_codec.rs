@@ -19,6 +19,8 @@ pub(crate) struct Http2Codec<IO> {
     parent_id_chain: log_utils::IdChain<u64>,
     next_conn_id: std::ops::RangeFrom<u64>,
     client_address: IpAddr,
+    max_header_field_size: usize,
+    max_uri_length: usize,
 }
 
 enum State<IO> {
@@ -79,6 +81,8 @@ where
             parent_id_chain,
             next_conn_id: 0..,
             client_address,
+            max_header_field_size: http2_settings.max_header_field_size,
+            max_uri_length: http2_settings.max_uri_length,
         })
     }
 }
@@ -98,57 +102,69 @@ impl<IO: AsyncRead + AsyncWrite + Send + Unpin> HttpCodec for Http2Codec<IO> {
         };
 
         log_id!(trace, self.parent_id_chain, "H2 waiting for stream");
-        match session.accept().await {
-            Some(Ok((request, respond))) => {
-                let (request, rx) = request.into_parts();
-                let id = self.parent_id_chain.extended(log_utils::IdItem::new(
-                    log_utils::CONNECTION_ID_FMT,
-                    self.next_conn_id.next().unwrap(),
-                ));
-                // @note: [`h2::StreamId`] cannot be converted to raw integer, so just log it
-                //        to have a link between stream and out own generated IDs in the logs
-                // @note: could be worked around by allowing any id type in the id chain
-                log_id!(
-                    debug,
-                    id,
-                    "H2 stream accepted, stream_id: {:?}",
-                    rx.stream_id()
-                );
-                Ok(Some(Box::new(Stream {
-                    request: Request {
-                        request,
-                        rx,
-                        client_address: self.client_address,
-                        id: id.clone(),
-                    },
-                    respond: Respond { tx: respond, id },
-                })))
-            }
-            Some(Err(e)) if e.is_io() => {
-                log_id!(trace, self.parent_id_chain, "H2 stream accept IO error");
-                Err(e.into_io().unwrap())
-            }
-            Some(Err(e)) if e.reason() == Some(Reason::NO_ERROR) => {
-                log_id!(
-                    trace,
-                    self.parent_id_chain,
-                    "H2 connection closed gracefully"
-                );
-                Ok(None)
-            }
-            Some(Err(e)) => {
-                log_id!(
-                    trace,
-                    self.parent_id_chain,
-                    "H2 stream accept error: {:?}",
-                    e.reason()
-                );
-                Err(h2_to_io_error(e))
-            }
-            None => {
-                log_id!(trace, self.parent_id_chain, "H2 no more streams");
-                Ok(None)
-            }
+        loop {
+            break match session.accept().await {
+                Some(Ok((request, mut respond))) => {
+                    let (request, rx) = request.into_parts();
+                    let id = self.parent_id_chain.extended(log_utils::IdItem::new(
+                        log_utils::CONNECTION_ID_FMT,
+                        self.next_conn_id.next().unwrap(),
+                    ));
+                    // @note: [`h2::StreamId`] cannot be converted to raw integer, so just log it
+                    //        to have a link between stream and out own generated IDs in the logs
+                    // @note: could be worked around by allowing any id type in the id chain
+                    log_id!(
+                        debug,
+                        id,
+                        "H2 stream accepted, stream_id: {:?}",
+                        rx.stream_id()
+                    );
+                    if let Some(status) = http_codec::check_request_limits(
+                        &request,
+                        self.max_header_field_size,
+                        self.max_uri_length,
+                    ) {
+                        log_id!(debug, id, "H2 request refused: {}", status);
+                        let response = http::Response::builder().status(status).body(()).unwrap();
+                        let _ = respond.send_response(response, true);
+                        continue;
+                    }
+                    Ok(Some(Box::new(Stream {
+                        request: Request {
+                            request,
+                            rx,
+                            client_address: self.client_address,
+                            id: id.clone(),
+                        },
+                        respond: Respond { tx: respond, id },
+                    })))
+                }
+                Some(Err(e)) if e.is_io() => {
+                    log_id!(trace, self.parent_id_chain, "H2 stream accept IO error");
+                    Err(e.into_io().unwrap())
+                }
+                Some(Err(e)) if e.reason() == Some(Reason::NO_ERROR) => {
+                    log_id!(
+                        trace,
+                        self.parent_id_chain,
+                        "H2 connection closed gracefully"
+                    );
+                    Ok(None)
+                }
+                Some(Err(e)) => {
+                    log_id!(
+                        trace,
+                        self.parent_id_chain,
+                        "H2 stream accept error: {:?}",
+                        e.reason()
+                    );
+                    Err(h2_to_io_error(e))
+                }
+                None => {
+                    log_id!(trace, self.parent_id_chain, "H2 no more streams");
+                    Ok(None)
+                }
+            };
         }
     }
 
@@ -273,6 +289,18 @@ impl http_codec::PendingRespond for Respond {
         self.id.clone()
     }
 
+    fn send_intermediate_response(&self, response: ResponseHeaders) -> io::Result<()> {
+        // The h2 library allows a single response head per stream, so the informational
+        // responses can not precede the final one
+        log_id!(
+            debug,
+            self.id,
+            "H2 dropping intermediate response as not supported: status={}",
+            response.status
+        );
+        Ok(())
+    }
+
     fn send_response(
         mut self: Box<Self>,
         response: ResponseHeaders,
@@ -304,6 +332,17 @@ impl http_codec::RespondedStreamSink for RespondStream {
     fn into_datagram_sink(self: Box<Self>) -> Box<dyn http_codec::DroppingSink> {
         self
     }
+
+    fn into_body_sink(self: Box<Self>) -> Box<dyn http_codec::BodySink> {
+        self
+    }
+}
+
+impl http_codec::BodySink for RespondStream {
+    fn send_trailers(&mut self, trailers: http::HeaderMap) -> io::Result<()> {
+        log_id!(trace, self.id, "H2 sending trailers: {:?}", trailers);
+        self.tx.send_trailers(trailers).map_err(h2_to_io_error)
+    }
 }
 
 pub struct WaitWritable<'a> {
@@ -363,6 +402,20 @@ impl pipe::Sink for RespondStream {
         }
         .await
     }
+
+    async fn reserve(&mut self, size: usize) -> io::Result<usize> {
+        if size == 0 {
+            return Ok(0);
+        }
+        self.tx.reserve_capacity(size);
+        loop {
+            self.wait_writable().await?;
+            let granted = self.tx.capacity().min(size);
+            if granted > 0 {
+                return Ok(granted);
+            }
+        }
+    }
 }
 
 impl http_codec::DroppingSink for RespondStream {
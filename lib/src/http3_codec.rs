@@ -1,15 +1,29 @@
+use crate::capacity::Category;
 use crate::http_codec::{HttpCodec, RequestHeaders, ResponseHeaders};
 use crate::quic_multiplexer::{QuicSocket, QuicSocketEvent};
 use crate::tls_demultiplexer::Protocol;
-use crate::{datagram_pipe, http_codec, log_id, log_utils, net_utils, pipe};
+use crate::{capacity, datagram_pipe, http_codec, log_id, log_utils, net_utils, pipe};
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+
+/// The number of the HTTP datagrams of a request received in the QUIC DATAGRAM frames
+/// and not read yet. The ones over it are dropped.
+const DATAGRAM_QUEUE_CAPACITY: usize = 128;
+
+/// The maximum length of the target line a WebTransport stream starts with
+const MAX_WEBTRANSPORT_TARGET_LENGTH: usize = 1024;
+/// The header telling the browsers implementing the WebTransport over HTTP/3 draft 02
+/// that the endpoint speaks it
+const WEBTRANSPORT_DRAFT_HEADER: &str = "sec-webtransport-http3-draft";
+/// `WT_SESSION_GONE`, resetting the streams of a closed WebTransport session
+const WEBTRANSPORT_SESSION_GONE: u64 = 0x170d7b68;
 
 pub(crate) struct Http3Codec {
     socket: Arc<QuicSocket>,
@@ -18,6 +32,13 @@ pub(crate) struct Http3Codec {
     stream_rx: mpsc::UnboundedReceiver<StreamMessage>,
     /// See [`StreamSource.codec_tx`] and [`StreamSink.codec_tx`]
     codec_tx: Arc<mpsc::UnboundedSender<StreamMessage>>,
+    /// The ID of the latest stream initiated by a client
+    last_stream_id: Option<u64>,
+    /// The accepted WebTransport sessions by the IDs of their request streams
+    webtransport_sessions: HashMap<u64, RequestHeaders>,
+    /// The WebTransport streams which target line is not read yet: the session ID and
+    /// the bytes read so far
+    webtransport_targets: HashMap<u64, (u64, BytesMut)>,
     parent_id_chain: log_utils::IdChain<u64>,
 }
 
@@ -32,6 +53,13 @@ struct Stream {
     readable_event_tx: mpsc::Sender<()>,
     /// Sends messages to [`StreamSink.writable_event_rx`]
     writable_event_tx: mpsc::Sender<()>,
+    /// Sends messages to [`StreamSource.datagram_rx`]
+    datagram_tx: Option<mpsc::Sender<Bytes>>,
+    /// Sends the error code of the client resetting the stream to [`StreamSource.cancellation`]
+    /// and [`StreamSink.cancellation`]
+    reset_tx: watch::Sender<Option<u64>>,
+    /// The session ID if it is a WebTransport stream
+    webtransport_session: Option<u64>,
     read_shutdown: bool,
     write_shutdown: bool,
 }
@@ -47,8 +75,17 @@ struct StreamSource {
     socket: Arc<QuicSocket>,
     /// Receives messages from [`Stream.readable_event_tx`]
     readable_event_rx: mpsc::Receiver<()>,
+    /// The data of a WebTransport stream read along with its target line
+    early_data: Option<Bytes>,
+    /// Receives messages from [`Stream.datagram_tx`].
+    /// Set if the connection supports the QUIC DATAGRAM frames.
+    datagram_rx: Option<mpsc::Receiver<Bytes>>,
+    /// Receives messages from [`Stream.reset_tx`]
+    cancellation: http_codec::Cancellation,
     /// Sends messages to [`Http3Codec.stream_rx`]
     codec_tx: Arc<mpsc::UnboundedSender<StreamMessage>>,
+    /// Whether it is a WebTransport stream, carrying the raw tunnel data
+    webtransport: bool,
     id: log_utils::IdChain<u64>,
 }
 
@@ -57,12 +94,21 @@ struct StreamSink {
     socket: Arc<QuicSocket>,
     /// Receives messages from [`Stream.writable_event_tx`]
     writable_event_rx: mpsc::Receiver<()>,
+    /// Receives messages from [`Stream.reset_tx`]
+    cancellation: http_codec::Cancellation,
     /// Sends messages to [`Http3Codec.stream_rx`]
     codec_tx: Arc<mpsc::UnboundedSender<StreamMessage>>,
     /// Equals to [`net_utils::MIN_USABLE_QUIC_STREAM_CAPACITY`] by default.
     /// In some cases may be assigned to different values
     /// (see [`StreamSink::wait_writable()`]) to avoid busy loops.
     data_frame_overhead: usize,
+    /// Whether an intermediate response is sent, so the final one is sent as
+    /// an additional header section
+    is_intermediate_sent: AtomicBool,
+    /// Whether the datagrams are sent in the QUIC DATAGRAM frames instead of the stream
+    datagram_frames: bool,
+    /// Whether it is a WebTransport stream, carrying the raw tunnel data
+    webtransport: bool,
     id: log_utils::IdChain<u64>,
 }
 
@@ -75,6 +121,9 @@ impl Http3Codec {
             streams: HashMap::new(),
             stream_rx: rx,
             codec_tx: Arc::new(tx),
+            last_stream_id: None,
+            webtransport_sessions: HashMap::new(),
+            webtransport_targets: HashMap::new(),
             parent_id_chain,
         }
     }
@@ -119,6 +168,9 @@ impl Http3Codec {
         }
 
         if stream.read_shutdown && stream.write_shutdown {
+            if stream.webtransport_session.is_some() {
+                self.socket.close_webtransport_stream(stream_id);
+            }
             self.streams.remove(&stream_id);
         }
 
@@ -130,9 +182,27 @@ impl Http3Codec {
         event: QuicSocketEvent,
     ) -> io::Result<Option<Box<dyn http_codec::Stream>>> {
         match event {
+            QuicSocketEvent::Request(stream_id, request)
+                if self.is_webtransport_session(&request) =>
+            {
+                self.on_webtransport_session(stream_id, *request);
+                Ok(None)
+            }
             QuicSocketEvent::Request(stream_id, request) => {
                 self.on_request(stream_id, *request).map(Some)
             }
+            QuicSocketEvent::Readable(stream_id)
+                if self.webtransport_sessions.contains_key(&stream_id) =>
+            {
+                // The capsules of a session are of no use, as closing it closes the stream too
+                while let Ok(Some(_)) = self.socket.read(stream_id) {}
+                Ok(None)
+            }
+            QuicSocketEvent::Readable(stream_id)
+                if self.webtransport_targets.contains_key(&stream_id) =>
+            {
+                Ok(self.on_webtransport_target_readable(stream_id))
+            }
             QuicSocketEvent::Readable(stream_id) => {
                 self.on_stream_readable(stream_id).map(|_| None)
             }
@@ -140,10 +210,32 @@ impl Http3Codec {
                 self.notify_writable_streams(streams);
                 Ok(None)
             }
+            QuicSocketEvent::Close(stream_id) | QuicSocketEvent::Reset(stream_id, _)
+                if self.webtransport_sessions.contains_key(&stream_id) =>
+            {
+                self.close_webtransport_session(stream_id);
+                Ok(None)
+            }
             QuicSocketEvent::Close(stream_id) => {
                 let _ = self.on_stream_shutdown(stream_id, None);
                 Ok(None)
             }
+            QuicSocketEvent::Reset(stream_id, error_code) => {
+                if let Some(x) = self.streams.get(&stream_id) {
+                    x.reset_tx.send_replace(Some(error_code));
+                }
+                let _ = self.on_stream_shutdown(stream_id, None);
+                Ok(None)
+            }
+            QuicSocketEvent::Datagram(stream_id, payload) => {
+                self.on_datagram(stream_id, payload);
+                Ok(None)
+            }
+            QuicSocketEvent::WebTransportStream(session_id, stream_id) => {
+                self.webtransport_targets
+                    .insert(stream_id, (session_id, BytesMut::new()));
+                Ok(self.on_webtransport_target_readable(stream_id))
+            }
         }
     }
 
@@ -152,42 +244,216 @@ impl Http3Codec {
         stream_id: u64,
         request: RequestHeaders,
     ) -> io::Result<Box<dyn http_codec::Stream>> {
+        self.last_stream_id = self.last_stream_id.max(Some(stream_id));
+        Ok(self.detach_stream(stream_id, request, None))
+    }
+
+    /// Whether the request opens a WebTransport session. The sessions carrying no credentials
+    /// are handled as the other extended CONNECT requests, i.e., refused.
+    fn is_webtransport_session(&self, request: &RequestHeaders) -> bool {
+        self.socket.webtransport_enabled()
+            && request.method == http::Method::CONNECT
+            && request
+                .extensions
+                .get::<http_codec::ConnectProtocol>()
+                .is_some_and(|x| x.0 == "webtransport")
+            && webtransport_authorization(request).is_some()
+    }
+
+    fn on_webtransport_session(&mut self, stream_id: u64, request: RequestHeaders) {
+        log_id!(
+            debug,
+            self.parent_id_chain,
+            "Accepting WebTransport session: id={}, path={}",
+            stream_id,
+            request.uri.path()
+        );
+
+        self.last_stream_id = self.last_stream_id.max(Some(stream_id));
+        let response = http::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(WEBTRANSPORT_DRAFT_HEADER, "draft02")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        match self.socket.send_response(stream_id, response, false) {
+            Ok(()) => {
+                self.socket.accept_webtransport_session(stream_id);
+                self.webtransport_sessions.insert(stream_id, request);
+            }
+            Err(e) => log_id!(
+                debug,
+                self.parent_id_chain,
+                "Failed to accept WebTransport session: id={}, error={}",
+                stream_id,
+                e
+            ),
+        }
+    }
+
+    /// Reset the streams of a WebTransport session closed by the client
+    fn close_webtransport_session(&mut self, session_id: u64) {
+        log_id!(
+            debug,
+            self.parent_id_chain,
+            "WebTransport session closed: id={}",
+            session_id
+        );
+
+        self.webtransport_sessions.remove(&session_id);
+        self.webtransport_targets.retain(|stream_id, (x, _)| {
+            if *x == session_id {
+                self.socket
+                    .reset_stream(*stream_id, WEBTRANSPORT_SESSION_GONE);
+                self.socket.close_webtransport_stream(*stream_id);
+            }
+            *x != session_id
+        });
+        for (stream_id, stream) in &self.streams {
+            if stream.webtransport_session == Some(session_id) {
+                stream
+                    .reset_tx
+                    .send_replace(Some(WEBTRANSPORT_SESSION_GONE));
+                self.socket
+                    .reset_stream(*stream_id, WEBTRANSPORT_SESSION_GONE);
+            }
+        }
+
+        self.socket
+            .shutdown_stream(session_id, quiche::Shutdown::Write);
+        self.socket.close_webtransport_stream(session_id);
+    }
+
+    /// Read the target line of a WebTransport stream. Once it is complete, the stream is
+    /// handled as a CONNECT request to the target with the headers of the session request.
+    fn on_webtransport_target_readable(
+        &mut self,
+        stream_id: u64,
+    ) -> Option<Box<dyn http_codec::Stream>> {
+        let (session_id, buffer) = self.webtransport_targets.get_mut(&stream_id)?;
+        let session_id = *session_id;
+        let status = loop {
+            if let Some(n) = buffer.iter().position(|x| *x == b'\n') {
+                let target = buffer.split_to(n + 1);
+                let request = self
+                    .webtransport_sessions
+                    .get(&session_id)
+                    .and_then(|x| webtransport_request(x, &target[..n]));
+                match request {
+                    Some(request) => {
+                        let early_data = Some(buffer.split().freeze()).filter(|x| !x.is_empty());
+                        self.webtransport_targets.remove(&stream_id);
+                        return Some(self.detach_stream(
+                            stream_id,
+                            request,
+                            Some((session_id, early_data)),
+                        ));
+                    }
+                    None => break http::StatusCode::BAD_REQUEST,
+                }
+            }
+            if buffer.len() > MAX_WEBTRANSPORT_TARGET_LENGTH {
+                break http::StatusCode::URI_TOO_LONG;
+            }
+
+            match self.socket.read_raw(stream_id) {
+                Ok(Some(chunk)) => buffer.extend_from_slice(&chunk),
+                Ok(None) if !self.socket.stream_finished(stream_id) => return None,
+                Ok(None) => break http::StatusCode::BAD_REQUEST,
+                Err(e) => {
+                    log_id!(
+                        debug,
+                        self.parent_id_chain,
+                        "Failed to read WebTransport stream: id={}, error={}",
+                        stream_id,
+                        e
+                    );
+                    break http::StatusCode::BAD_REQUEST;
+                }
+            }
+        };
+
+        log_id!(
+            debug,
+            self.parent_id_chain,
+            "Rejecting WebTransport stream: id={}, status={}",
+            stream_id,
+            status
+        );
+        self.webtransport_targets.remove(&stream_id);
+        self.socket
+            .reset_stream(stream_id, webtransport_error_code(status.as_u16().into()));
+        self.socket.close_webtransport_stream(stream_id);
+        None
+    }
+
+    /// Make the request of a stream, either an HTTP/3 or a WebTransport one with its session ID
+    /// and the data read along with its target line
+    fn detach_stream(
+        &mut self,
+        stream_id: u64,
+        request: RequestHeaders,
+        webtransport: Option<(u64, Option<Bytes>)>,
+    ) -> Box<dyn http_codec::Stream> {
         let (readable_tx, readable_rx) = mpsc::channel(1);
         let (writable_tx, writable_rx) = mpsc::channel(1);
+        let (reset_tx, reset_rx) = watch::channel(None);
+        let cancellation = http_codec::Cancellation::new(reset_rx);
+        // The WebTransport datagrams are bound to the sessions, not to the streams
+        let (datagram_tx, datagram_rx) =
+            if webtransport.is_none() && self.socket.datagrams_enabled() {
+                let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE_CAPACITY);
+                (Some(tx), Some(rx))
+            } else {
+                (None, None)
+            };
 
         let id = self.parent_id_chain.extended(log_utils::IdItem::new(
             log_utils::CONNECTION_ID_FMT,
             stream_id,
         ));
 
+        let (webtransport_session, early_data) = webtransport.unzip();
         self.streams.insert(
             stream_id,
             Stream {
                 readable_event_tx: readable_tx,
                 writable_event_tx: writable_tx,
+                datagram_tx,
+                reset_tx,
+                webtransport_session,
                 read_shutdown: false,
                 write_shutdown: false,
             },
         );
 
-        Ok(Box::new(DetachedStream {
+        Box::new(DetachedStream {
             source: StreamSource {
                 stream_id,
                 request,
                 socket: self.socket.clone(),
                 readable_event_rx: readable_rx,
+                early_data: early_data.flatten(),
+                datagram_rx,
+                cancellation: cancellation.clone(),
                 codec_tx: self.codec_tx.clone(),
+                webtransport: webtransport_session.is_some(),
                 id: id.clone(),
             },
             sink: StreamSink {
                 stream_id,
                 socket: self.socket.clone(),
                 writable_event_rx: writable_rx,
+                cancellation,
                 codec_tx: self.codec_tx.clone(),
                 data_frame_overhead: net_utils::MIN_USABLE_QUIC_STREAM_CAPACITY,
+                is_intermediate_sent: AtomicBool::new(false),
+                datagram_frames: false,
+                webtransport: webtransport_session.is_some(),
                 id,
             },
-        }))
+        })
     }
 
     fn on_stream_readable(&mut self, stream_id: u64) -> io::Result<()> {
@@ -207,6 +473,31 @@ impl Http3Codec {
         }
     }
 
+    /// Deliver an HTTP datagram to its request, if it has switched to the QUIC DATAGRAM
+    /// frames. The datagram is dropped otherwise, or if the request does not keep up.
+    fn on_datagram(&self, stream_id: u64, payload: Bytes) {
+        match self
+            .streams
+            .get(&stream_id)
+            .and_then(|x| x.datagram_tx.as_ref())
+            .map(|x| x.try_send(payload))
+        {
+            Some(Ok(())) => (),
+            Some(Err(mpsc::error::TrySendError::Full(_))) => log_id!(
+                trace,
+                self.parent_id_chain,
+                "Dropping datagram of busy stream: id={}",
+                stream_id
+            ),
+            Some(Err(mpsc::error::TrySendError::Closed(_))) | None => log_id!(
+                trace,
+                self.parent_id_chain,
+                "Dropping datagram of unknown stream: id={}",
+                stream_id
+            ),
+        }
+    }
+
     fn notify_writable_streams(&self, streams: Vec<u64>) {
         for stream_id in streams {
             let r = match self
@@ -253,7 +544,7 @@ impl HttpCodec for Http3Codec {
 
         loop {
             let event = {
-                let wait_socket_event = self.socket.listen();
+                let wait_socket_event = capacity::metered(Category::Quic, self.socket.listen());
                 tokio::pin!(wait_socket_event);
 
                 let has_streams = !self.streams.is_empty();
@@ -301,6 +592,40 @@ impl HttpCodec for Http3Codec {
         self.socket.graceful_shutdown()
     }
 
+    async fn drain(&mut self) -> io::Result<()> {
+        // Client-initiated bidirectional stream IDs are multiples of 4
+        let goaway_id = self.last_stream_id.map_or(0, |x| x + 4);
+        log_id!(
+            trace,
+            self.parent_id_chain,
+            "H3 draining: GOAWAY id={}, in-flight streams={}",
+            goaway_id,
+            self.streams.len()
+        );
+        self.socket.send_goaway(goaway_id)?;
+
+        while !self.streams.is_empty() {
+            // The event loop must keep running for the in-flight streams to make progress
+            if let Some(stream) = self.listen().await? {
+                log_id!(debug, stream.id(), "Rejecting stream opened after GOAWAY");
+                if let Err(e) = stream
+                    .split()
+                    .1
+                    .send_bad_response(http::StatusCode::SERVICE_UNAVAILABLE, vec![])
+                {
+                    log_id!(
+                        debug,
+                        self.parent_id_chain,
+                        "Failed to reject stream: {}",
+                        e
+                    );
+                }
+            }
+        }
+
+        self.graceful_shutdown().await
+    }
+
     fn protocol(&self) -> Protocol {
         Protocol::Http3
     }
@@ -332,6 +657,12 @@ impl http_codec::Stream for DetachedStream {
     ) {
         (Box::new(self.source), Box::new(self.sink))
     }
+
+    fn take_datagrams(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        let rx = self.source.datagram_rx.take()?;
+        self.sink.datagram_frames = true;
+        Some(rx)
+    }
 }
 
 impl http_codec::PendingRequest for StreamSource {
@@ -360,7 +691,19 @@ impl pipe::Source for StreamSource {
 
     async fn read(&mut self) -> io::Result<pipe::Data> {
         loop {
-            match self.socket.read(self.stream_id)? {
+            // A reset stream is finished as well, but its request is incomplete
+            if let Some(x) = self.cancellation.code() {
+                return Err(http_codec::Cancellation::error(x));
+            }
+            if let Some(x) = self.early_data.take() {
+                return Ok(pipe::Data::Chunk(x));
+            }
+            let chunk = if self.webtransport {
+                self.socket.read_raw(self.stream_id)?
+            } else {
+                self.socket.read(self.stream_id)?
+            };
+            match chunk {
                 Some(chunk) => return Ok(pipe::Data::Chunk(chunk)),
                 None => {
                     if self.socket.stream_finished(self.stream_id) {
@@ -399,6 +742,34 @@ impl http_codec::PendingRespond for StreamSink {
         self.id.clone()
     }
 
+    fn cancellation(&self) -> http_codec::Cancellation {
+        self.cancellation.clone()
+    }
+
+    fn send_intermediate_response(&self, response: ResponseHeaders) -> io::Result<()> {
+        log_id!(
+            debug,
+            self.id,
+            "Sending intermediate response: {:?}",
+            response
+        );
+
+        if self.webtransport {
+            return Ok(());
+        }
+
+        if self.is_intermediate_sent.swap(true, Ordering::AcqRel) {
+            self.socket.send_additional_headers(
+                self.stream_id,
+                Some(response.status),
+                &response.headers,
+                false,
+            )
+        } else {
+            self.socket.send_response(self.stream_id, response, false)
+        }
+    }
+
     fn send_response(
         self: Box<Self>,
         response: ResponseHeaders,
@@ -412,7 +783,25 @@ impl http_codec::PendingRespond for StreamSink {
             eof
         );
 
-        self.socket.send_response(self.stream_id, response, false)?;
+        if self.webtransport {
+            // A WebTransport stream carries the tunnel data only, so a refused request
+            // resets it with the status as the error code
+            if !response.status.is_success() {
+                self.socket.reset_stream(
+                    self.stream_id,
+                    webtransport_error_code(response.status.as_u16().into()),
+                );
+            }
+        } else if self.is_intermediate_sent.load(Ordering::Acquire) {
+            self.socket.send_additional_headers(
+                self.stream_id,
+                Some(response.status),
+                &response.headers,
+                false,
+            )?;
+        } else {
+            self.socket.send_response(self.stream_id, response, false)?;
+        }
 
         if eof {
             self.codec_tx
@@ -437,6 +826,21 @@ impl http_codec::RespondedStreamSink for StreamSink {
     fn into_datagram_sink(self: Box<Self>) -> Box<dyn http_codec::DroppingSink> {
         self
     }
+
+    fn into_body_sink(self: Box<Self>) -> Box<dyn http_codec::BodySink> {
+        self
+    }
+}
+
+impl http_codec::BodySink for StreamSink {
+    fn send_trailers(&mut self, trailers: http::HeaderMap) -> io::Result<()> {
+        log_id!(debug, self.id, "Sending trailers: {:?}", trailers);
+        if self.webtransport {
+            return Ok(());
+        }
+        self.socket
+            .send_additional_headers(self.stream_id, None, &trailers, true)
+    }
 }
 
 #[async_trait]
@@ -446,6 +850,10 @@ impl pipe::Sink for StreamSink {
     }
 
     fn write(&mut self, data: Bytes) -> io::Result<Bytes> {
+        if self.webtransport {
+            return self.socket.write_raw(self.stream_id, data);
+        }
+
         let orig_len = data.len();
         let data = self.socket.write(self.stream_id, data)?;
 
@@ -486,17 +894,40 @@ impl pipe::Sink for StreamSink {
             }
         }
     }
+
+    async fn reserve(&mut self, size: usize) -> io::Result<usize> {
+        loop {
+            self.wait_writable().await?;
+            let capacity = self.socket.stream_capacity(self.stream_id)?;
+            let granted = capacity
+                .saturating_sub(self.frame_overhead(capacity))
+                .min(size);
+            if granted > 0 || size == 0 {
+                return Ok(granted);
+            }
+            // The capacity does not fit a frame with any payload, wait for it to grow
+            self.data_frame_overhead = capacity;
+        }
+    }
 }
 
 impl http_codec::DroppingSink for StreamSink {
     fn write(&mut self, data: Bytes) -> io::Result<datagram_pipe::SendStatus> {
+        if self.datagram_frames {
+            return self.socket.send_datagram(self.stream_id, &data);
+        }
+
         match self.socket.stream_capacity(self.stream_id) {
-            Ok(n) if n >= net_utils::http3_data_frame_overhead(data.len()) + data.len() => (),
+            Ok(n) if n >= self.frame_overhead(data.len()) + data.len() => (),
             Ok(_) => return Ok(datagram_pipe::SendStatus::Dropped),
             Err(e) => return Err(io::Error::new(ErrorKind::Other, e.to_string())),
         }
 
-        let unsent = self.socket.write(self.stream_id, data)?;
+        let unsent = if self.webtransport {
+            self.socket.write_raw(self.stream_id, data)?
+        } else {
+            self.socket.write(self.stream_id, data)?
+        };
         if !unsent.is_empty() {
             return Err(io::Error::new(
                 ErrorKind::Other,
@@ -507,6 +938,17 @@ impl http_codec::DroppingSink for StreamSink {
     }
 }
 
+impl StreamSink {
+    /// The overhead of sending `len` bytes in the stream
+    fn frame_overhead(&self, len: usize) -> usize {
+        if self.webtransport {
+            0
+        } else {
+            net_utils::http3_data_frame_overhead(len)
+        }
+    }
+}
+
 impl Drop for StreamSink {
     fn drop(&mut self) {
         match self.codec_tx.send(StreamMessage::Shutdown(
@@ -518,3 +960,109 @@ impl Drop for StreamSink {
         }
     }
 }
+
+/// The proxy authorization of a WebTransport session: the header, or, as the browsers
+/// cannot set one, the URL-encoded `authorization` query parameter of the session URL
+fn webtransport_authorization(request: &RequestHeaders) -> Option<http::HeaderValue> {
+    if let Some(x) = request.headers.get(http::header::PROXY_AUTHORIZATION) {
+        return Some(x.clone());
+    }
+
+    request
+        .uri
+        .query()?
+        .split('&')
+        .find_map(|x| x.strip_prefix("authorization="))
+        .map(|x| percent_encoding::percent_decode_str(&x.replace('+', " ")).collect::<Vec<_>>())
+        .and_then(|x| http::HeaderValue::from_bytes(&x).ok())
+}
+
+/// Make the CONNECT request of a WebTransport stream to the `target`, `host:port`, with
+/// the headers and the credentials of its session
+fn webtransport_request(session: &RequestHeaders, target: &[u8]) -> Option<RequestHeaders> {
+    let target = target.strip_suffix(b"\r").unwrap_or(target);
+    let mut request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .version(http::Version::HTTP_3)
+        .uri(http::uri::Authority::try_from(target).ok()?.as_str())
+        .body(())
+        .ok()?
+        .into_parts()
+        .0;
+    request.headers = session.headers.clone();
+    request.headers.insert(
+        http::header::PROXY_AUTHORIZATION,
+        webtransport_authorization(session)?,
+    );
+    Some(request)
+}
+
+/// The HTTP/3 error code carrying the WebTransport application error code
+fn webtransport_error_code(code: u32) -> u64 {
+    const FIRST: u64 = 0x52e4a40fa8db;
+    // Skips the reserved codes
+    FIRST + code as u64 + code as u64 / 0x1e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(uri: &str) -> RequestHeaders {
+        http::Request::builder()
+            .method(http::Method::CONNECT)
+            .uri(uri)
+            .header(http::header::ORIGIN, "https://example.org")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn webtransport_authorization_sources() {
+        let mut request = session("https://vpn.example.org/tunnel");
+        assert_eq!(webtransport_authorization(&request), None);
+
+        request.headers.insert(
+            http::header::PROXY_AUTHORIZATION,
+            http::HeaderValue::from_static("Basic YTpi"),
+        );
+        assert_eq!(webtransport_authorization(&request).unwrap(), "Basic YTpi");
+
+        for query in [
+            "authorization=Basic%20YWxpY2U6c2VjcmV0%2B",
+            "x=1&authorization=Basic+YWxpY2U6c2VjcmV0%2B",
+        ] {
+            let request = session(&format!("https://vpn.example.org/tunnel?{}", query));
+            assert_eq!(
+                webtransport_authorization(&request).unwrap(),
+                "Basic YWxpY2U6c2VjcmV0+"
+            );
+        }
+    }
+
+    #[test]
+    fn webtransport_stream_request() {
+        let session = session("https://vpn.example.org/tunnel?authorization=Basic%20YTpi");
+
+        let request = webtransport_request(&session, b"example.com:443\r").unwrap();
+        assert_eq!(request.method, http::Method::CONNECT);
+        assert_eq!(request.uri.authority().unwrap(), "example.com:443");
+        assert_eq!(
+            request.headers[http::header::PROXY_AUTHORIZATION],
+            "Basic YTpi"
+        );
+        assert_eq!(request.headers[http::header::ORIGIN], "https://example.org");
+
+        assert!(webtransport_request(&session, b"").is_none());
+        assert!(webtransport_request(&session, b"example.com/path").is_none());
+    }
+
+    #[test]
+    fn webtransport_error_codes() {
+        assert_eq!(webtransport_error_code(0), 0x52e4a40fa8db);
+        assert_eq!(webtransport_error_code(0x1d), 0x52e4a40fa8db + 0x1d);
+        assert_eq!(webtransport_error_code(0x1e), 0x52e4a40fa8db + 0x1f);
+    }
+}
@@ -1,16 +1,35 @@
 use crate::tls_demultiplexer::Protocol;
 use crate::{authentication, datagram_pipe, log_utils, pipe};
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
 use http::uri::Authority;
 use http::{Response, StatusCode};
 use std::io;
 use std::io::ErrorKind;
 use std::net::IpAddr;
+use tokio::sync::{mpsc, watch};
 
 pub(crate) type RequestHeaders = http::request::Parts;
 pub(crate) type ResponseHeaders = http::response::Parts;
 
+/// The protocol of an extended CONNECT request
+/// ([RFC 9220](https://datatracker.ietf.org/doc/html/rfc9220)), i.e., the value of its
+/// `:protocol` pseudo-header. Kept in the extensions of the request headers.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectProtocol(pub String);
+
+/// Tells that a client has cancelled a request, e.g., by resetting its stream mid-response
+#[derive(Clone, Default)]
+pub(crate) struct Cancellation {
+    /// Gets the error code of the client once it cancels the request.
+    /// [`None`] if the protocol does not report the cancellations.
+    rx: Option<watch::Receiver<Option<u64>>>,
+}
+
+/// The error of an operation on a request cancelled by the client
+#[derive(Debug)]
+struct Cancelled(u64);
+
 /// Encapsulates an HTTP stream implementation
 pub(crate) trait Stream: Send {
     /// Get the request ID for logging
@@ -21,6 +40,14 @@ pub(crate) trait Stream: Send {
 
     /// Split the stream into the receiving and transmitting parts
     fn split(self: Box<Self>) -> (Box<dyn PendingRequest>, Box<dyn PendingRespond>);
+
+    /// Switch the HTTP datagrams ([RFC 9297](https://datatracker.ietf.org/doc/html/rfc9297))
+    /// of the request to the QUIC DATAGRAM frames: the ones written to the [`DroppingSink`]
+    /// bypass the stream, and the received ones are delivered through the returned channel.
+    /// [`None`] if the connection does not support them.
+    fn take_datagrams(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        None
+    }
 }
 
 /// Encapsulates a receiving part of an HTTP stream state
@@ -59,11 +86,20 @@ pub(crate) trait PendingRequest: Send {
             Some(x) => x,
         };
 
+        let header = header.to_str().ok();
         header
-            .to_str()
-            .ok()
             .and_then(|s| s.strip_prefix("Basic "))
             .map(|s| Some(authentication::Source::ProxyBasic(s.into())))
+            .or_else(|| {
+                header
+                    .and_then(|s| s.strip_prefix("Bearer "))
+                    .map(|s| Some(authentication::Source::ProxyBearer(s.into())))
+            })
+            .or_else(|| {
+                header
+                    .and_then(|s| s.strip_prefix("Digest "))
+                    .map(|s| Some(authentication::Source::ProxyDigest(s.into())))
+            })
             .ok_or_else(|| {
                 io::Error::new(
                     ErrorKind::Other,
@@ -94,6 +130,11 @@ pub(crate) trait PendingRequest: Send {
 
     /// Turn the pending request into the [`pipe::Source`] object
     fn finalize(self: Box<Self>) -> Box<dyn pipe::Source>;
+
+    /// Turn the pending request into the flow-controlled reader of its body
+    fn into_body(self: Box<Self>) -> BodyReader {
+        BodyReader::new(self.finalize())
+    }
 }
 
 /// Encapsulates a non-responded transmitting part of an HTTP stream state
@@ -102,6 +143,12 @@ pub(crate) trait PendingRespond: Send {
     #[allow(dead_code)]
     fn id(&self) -> log_utils::IdChain<u64>;
 
+    /// Get the notification of the client cancelling the request,
+    /// which stays valid after the response is sent
+    fn cancellation(&self) -> Cancellation {
+        Cancellation::default()
+    }
+
     /// Send the intermediate response to a client. Unlike `send_response()`,
     /// it does not change the pending state of the object.
     fn send_intermediate_response(&self, _: ResponseHeaders) -> io::Result<()> {
@@ -120,6 +167,23 @@ pub(crate) trait PendingRespond: Send {
         self.send_response(Response::<()>::default().into_parts().0, eof)
     }
 
+    /// Send the OK response with the extra headers to a client
+    fn send_ok_response_with_headers(
+        self: Box<Self>,
+        extra_headers: Vec<(String, String)>,
+        eof: bool,
+    ) -> io::Result<Box<dyn RespondedStreamSink>> {
+        let response = {
+            let mut b = Response::builder();
+            for (n, v) in extra_headers {
+                b = b.header(n, v);
+            }
+            b.body(()).unwrap()
+        };
+
+        self.send_response(response.into_parts().0, eof)
+    }
+
     /// Send a bad response to a client
     fn send_bad_response(
         self: Box<Self>,
@@ -144,10 +208,114 @@ pub(crate) trait DroppingSink: Send {
     fn write(&mut self, data: Bytes) -> io::Result<datagram_pipe::SendStatus>;
 }
 
+/// A response body transmitter which is able to complete the body with the trailers
+pub(crate) trait BodySink: pipe::Sink {
+    /// Send the trailer section completing the body.
+    /// No data can be sent after that, and there is no need to call [`pipe::Sink::eof()`].
+    fn send_trailers(&mut self, trailers: http::HeaderMap) -> io::Result<()>;
+}
+
 /// A helper trait which converts a stream sink wrapper into one of the sink types
 pub(crate) trait RespondedStreamSink: Send {
     fn into_pipe_sink(self: Box<Self>) -> Box<dyn pipe::Sink>;
     fn into_datagram_sink(self: Box<Self>) -> Box<dyn DroppingSink>;
+    fn into_body_sink(self: Box<Self>) -> Box<dyn BodySink>;
+
+    /// Turn the sink into the flow-controlled writer of the response body
+    fn into_body_writer(self: Box<Self>) -> BodyWriter {
+        BodyWriter::new(self.into_body_sink())
+    }
+}
+
+/// Sets the extra headers on the response sent through the wrapped stream
+pub(crate) struct HeaderInjectingStream {
+    pub inner: Box<dyn Stream>,
+    pub headers: http::HeaderMap,
+}
+
+/// Sets the extra headers on the response sent through the wrapped transmitting part
+pub(crate) struct HeaderInjectingRespond {
+    pub inner: Box<dyn PendingRespond>,
+    pub headers: http::HeaderMap,
+}
+
+/// A message body reader with the explicit flow control.
+/// The read data is not acknowledged to the peer until it is released, so a handler
+/// processing the body slowly holds the peer back instead of accumulating the data.
+pub(crate) struct BodyReader {
+    source: Box<dyn pipe::Source>,
+    /// The rest of the last received chunk which did not fit the requested size
+    pending: Bytes,
+    is_finished: bool,
+}
+
+impl BodyReader {
+    pub fn new(source: Box<dyn pipe::Source>) -> Self {
+        Self {
+            source,
+            pending: Bytes::new(),
+            is_finished: false,
+        }
+    }
+
+    /// Read the next chunk of at most `max_size` bytes.
+    /// Returns [`None`] once the whole body is read.
+    pub async fn read(&mut self, max_size: usize) -> io::Result<Option<Bytes>> {
+        if self.pending.is_empty() {
+            if self.is_finished {
+                return Ok(None);
+            }
+            match self.source.read().await? {
+                pipe::Data::Chunk(x) => self.pending = x,
+                pipe::Data::Eof => {
+                    self.is_finished = true;
+                    return Ok(None);
+                }
+            }
+        }
+
+        let n = max_size.min(self.pending.len());
+        Ok(Some(self.pending.split_to(n)))
+    }
+
+    /// Release the flow control window of `size` bytes of the read data,
+    /// once they are processed
+    pub fn release(&mut self, size: usize) -> io::Result<()> {
+        self.source.consume(size)
+    }
+}
+
+/// A message body writer sending the data within the capacity granted by the peer
+pub(crate) struct BodyWriter {
+    sink: Box<dyn BodySink>,
+}
+
+impl BodyWriter {
+    pub fn new(sink: Box<dyn BodySink>) -> Self {
+        Self { sink }
+    }
+
+    /// Wait for the peer to accept some data.
+    /// Returns the number of bytes up to `size` the peer is ready to take,
+    /// so that a caller produces no more data than can be sent right away.
+    pub async fn grant(&mut self, size: usize) -> io::Result<usize> {
+        self.sink.reserve(size).await
+    }
+
+    /// Write the data, waiting for the capacity as needed
+    pub async fn write(&mut self, mut data: Bytes) -> io::Result<()> {
+        while !data.is_empty() {
+            let granted = self.sink.reserve(data.len()).await?;
+            let unsent = self.sink.write(data.slice(..granted))?;
+            data.advance(granted - unsent.len());
+        }
+        Ok(())
+    }
+
+    /// Indicate that the body is complete
+    pub fn finish(mut self) -> io::Result<()> {
+        self.sink.eof()
+    }
 }
 
 /// An abstract interface for an HTTP server-side session implementation
@@ -162,10 +330,102 @@ pub(crate) trait HttpCodec: Send {
     /// Shut down the HTTP session gracefully
     async fn graceful_shutdown(&mut self) -> io::Result<()>;
 
+    /// Tell a client to stop opening new streams, let the in-flight ones finish
+    /// and then shut down the session gracefully
+    async fn drain(&mut self) -> io::Result<()> {
+        self.graceful_shutdown().await
+    }
+
     /// Get the codec protocol
     fn protocol(&self) -> Protocol;
 }
 
+impl Cancellation {
+    pub fn new(rx: watch::Receiver<Option<u64>>) -> Self {
+        Self { rx: Some(rx) }
+    }
+
+    /// Get the error code of the client if it has cancelled the request already
+    pub fn code(&self) -> Option<u64> {
+        self.rx.as_ref().and_then(|x| *x.borrow())
+    }
+
+    /// Wait for the client to cancel the request.
+    /// Never completes if the request is finished otherwise.
+    pub async fn cancelled(&mut self) -> io::Error {
+        if let Some(rx) = self.rx.as_mut() {
+            if let Ok(x) = rx.wait_for(Option::is_some).await {
+                return Self::error((*x).unwrap_or_default());
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// Make the error of an operation on a request cancelled with the `code`
+    pub fn error(code: u64) -> io::Error {
+        io::Error::new(ErrorKind::ConnectionAborted, Cancelled(code))
+    }
+
+    /// Check whether the error is caused by the client cancelling the request
+    pub fn is_cancelled(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|x| x.is::<Cancelled>())
+    }
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request cancelled by client: error code {:#x}", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl Stream for HeaderInjectingStream {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.inner.id()
+    }
+
+    fn request(&self) -> &dyn PendingRequest {
+        self.inner.request()
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PendingRequest>, Box<dyn PendingRespond>) {
+        let (request, respond) = self.inner.split();
+        let respond = Box::new(HeaderInjectingRespond {
+            inner: respond,
+            headers: self.headers,
+        });
+        (request, respond)
+    }
+
+    fn take_datagrams(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        self.inner.take_datagrams()
+    }
+}
+
+impl PendingRespond for HeaderInjectingRespond {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.inner.id()
+    }
+
+    fn cancellation(&self) -> Cancellation {
+        self.inner.cancellation()
+    }
+
+    fn send_intermediate_response(&self, response: ResponseHeaders) -> io::Result<()> {
+        self.inner.send_intermediate_response(response)
+    }
+
+    fn send_response(
+        self: Box<Self>,
+        mut response: ResponseHeaders,
+        eof: bool,
+    ) -> io::Result<Box<dyn RespondedStreamSink>> {
+        response.headers.extend(self.headers);
+        self.inner.send_response(response, eof)
+    }
+}
+
 /// Turn a [`Stream`] into a [`HttpCodec`] which produces the single stream.
 pub(crate) fn stream_into_codec(stream: Box<dyn Stream>, protocol: Protocol) -> impl HttpCodec {
     SingleRequestCodec {
@@ -174,6 +434,28 @@ pub(crate) fn stream_into_codec(stream: Box<dyn Stream>, protocol: Protocol) ->
     }
 }
 
+/// Check the request against the size limits of the listener.
+/// Returns the status the request is to be refused with if it exceeds any of them.
+pub(crate) fn check_request_limits(
+    request: &RequestHeaders,
+    max_header_field_size: usize,
+    max_uri_length: usize,
+) -> Option<StatusCode> {
+    let uri = &request.uri;
+    let uri_length = uri.scheme_str().map_or(0, |x| x.len() + "://".len())
+        + uri.authority().map_or(0, |x| x.as_str().len())
+        + uri.path_and_query().map_or(0, |x| x.as_str().len());
+    if uri_length > max_uri_length {
+        return Some(StatusCode::URI_TOO_LONG);
+    }
+
+    request
+        .headers
+        .iter()
+        .any(|(name, value)| name.as_str().len() + value.len() > max_header_field_size)
+        .then_some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+}
+
 struct SingleRequestCodec {
     stream: Option<Box<dyn Stream>>,
     protocol: Protocol,
@@ -201,3 +483,92 @@ impl HttpCodec for SingleRequestCodec {
         self.protocol
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use std::time::Duration;
+
+    impl BodySink for sim::MemorySink {
+        fn send_trailers(&mut self, _: http::HeaderMap) -> io::Result<()> {
+            Err(ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn body_is_streamed_within_window() {
+        const WINDOW: usize = 16;
+        let (tx, rx) = sim::stream(WINDOW);
+        let mut writer = BodyWriter::new(Box::new(tx));
+        let mut reader = BodyReader::new(Box::new(rx));
+
+        let write = async {
+            assert_eq!(4, writer.grant(4).await.unwrap());
+            writer
+                .write(Bytes::from(vec![1; 3 * WINDOW]))
+                .await
+                .unwrap();
+            writer.finish().unwrap();
+        };
+        let read = async {
+            let mut received = 0;
+            while let Some(x) = reader.read(5).await.unwrap() {
+                assert!(x.len() <= 5);
+                received += x.len();
+                reader.release(x.len()).unwrap();
+            }
+            received
+        };
+
+        let ((), received) = tokio::join!(write, read);
+        assert_eq!(3 * WINDOW, received);
+    }
+
+    #[test]
+    fn request_limits() {
+        let request = |path: &str, header: &str| {
+            http::Request::builder()
+                .uri(format!("https://example.org{}", path))
+                .header("x-test", header)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+
+        // `https://example.org/` is 20 characters long, `x-test` is 6
+        assert_eq!(None, check_request_limits(&request("/", "1234"), 10, 20));
+        assert_eq!(
+            Some(StatusCode::URI_TOO_LONG),
+            check_request_limits(&request("/a", "1234"), 10, 20)
+        );
+        assert_eq!(
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            check_request_limits(&request("/", "12345"), 10, 20)
+        );
+    }
+
+    #[tokio::test]
+    async fn cancellation() {
+        let (tx, rx) = watch::channel(None);
+        let mut cancellation = Cancellation::new(rx);
+        assert_eq!(None, cancellation.code());
+
+        tx.send_replace(Some(0x10c));
+        let e = cancellation.cancelled().await;
+        assert!(Cancellation::is_cancelled(&e));
+        assert_eq!(Some(0x10c), cancellation.code());
+        assert!(!Cancellation::is_cancelled(
+            &ErrorKind::ConnectionAborted.into()
+        ));
+
+        // A request finished without a cancellation
+        let (tx, rx) = watch::channel(None);
+        drop(tx);
+        let waiting = Cancellation::new(rx).cancelled();
+        assert!(tokio::time::timeout(Duration::from_millis(10), waiting)
+            .await
+            .is_err());
+    }
+}
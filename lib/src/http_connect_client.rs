@@ -0,0 +1,137 @@
+//! The client side of the [HTTP CONNECT](https://datatracker.ietf.org/doc/html/rfc9110#section-9.3.6)
+//! tunnels through an upstream proxy
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// The longest response head a proxy is let to send
+const MAX_RESPONSE_HEAD_LENGTH: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// A socket error
+    Io(io::Error),
+    /// The response is not a valid HTTP/1.1 response head
+    Protocol(String),
+    /// The proxy has refused the tunnel with the status code
+    Status(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(x) => write!(f, "IO error: {}", x),
+            Self::Protocol(x) => write!(f, "HTTP protocol error: {}", x),
+            Self::Status(x) => write!(f, "Proxy replied with status code: {}", x),
+        }
+    }
+}
+
+/// Ask the proxy on the other side of `io` for a tunnel to the `authority`.
+/// `authorization` is the value of the `Proxy-Authorization` header, if any.
+///
+/// # Return
+///
+/// The stream of the tunnel. The bytes the destination has sent right after the proxy
+/// response are kept in the buffer of the reader.
+pub(crate) async fn connect<IO>(
+    io: IO,
+    authority: &str,
+    authorization: Option<&str>,
+) -> Result<BufReader<IO>, Error>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(io);
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(x) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", x));
+    }
+    request.push_str("\r\n");
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .map_err(Error::Io)?;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_LENGTH {
+            return Err(Error::Protocol("Response head is too long".to_string()));
+        }
+        let n = (&mut stream)
+            .take((MAX_RESPONSE_HEAD_LENGTH - head.len()) as u64)
+            .read_until(b'\n', &mut head)
+            .await
+            .map_err(Error::Io)?;
+        if n == 0 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) => {
+            return Err(Error::Protocol("Incomplete response head".to_string()))
+        }
+        Err(e) => return Err(Error::Protocol(e.to_string())),
+    }
+    match response.code {
+        Some(x) if (200..300).contains(&x) => Ok(stream),
+        Some(x) => Err(Error::Status(x)),
+        None => Err(Error::Protocol("No status code".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn establishes_tunnel() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut request = vec![0; 1024];
+            let n = proxy.read(&mut request).await.unwrap();
+            proxy
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            String::from_utf8(request[..n].to_vec()).unwrap()
+        });
+
+        let mut stream = connect(client, "192.0.2.1:443", Some("Basic YWxpY2U6c2VjcmV0"))
+            .await
+            .unwrap();
+        assert_eq!(
+            "CONNECT 192.0.2.1:443 HTTP/1.1\r\nHost: 192.0.2.1:443\r\n\
+            Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+            server.await.unwrap()
+        );
+        // The bytes following the response head are not lost
+        let mut greeting = [0; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(b"hello", &greeting);
+    }
+
+    #[tokio::test]
+    async fn reports_refusal() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut request = vec![0; 1024];
+            let _ = proxy.read(&mut request).await.unwrap();
+            proxy
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert!(matches!(
+            connect(client, "192.0.2.1:443", None).await,
+            Err(Error::Status(407))
+        ));
+    }
+}
@@ -0,0 +1,309 @@
+//! The [CONNECT-UDP](https://datatracker.ietf.org/doc/html/rfc9298) stream format.
+//! The UDP payloads are carried in the DATAGRAM capsules of the request stream
+//! (see [RFC 9297](https://datatracker.ietf.org/doc/html/rfc9297#section-3.5)):
+//!
+//! +-------------+-----------+-------------------+---------+
+//! |    Type     |  Length   | Context ID (0x00) | Payload |
+//! | 0x00 varint |  varint   |      varint       | N bytes |
+//! +-------------+-----------+-------------------+---------+
+//!
+//! The capsules of the other types and the datagrams of the other contexts are skipped.
+//!
+//! If the client supports them, the HTTP datagrams are exchanged in the QUIC DATAGRAM frames
+//! instead, which are not retransmitted and do not block each other. A frame carries
+//! the quarter stream ID of the request (added by the HTTP/3 layer) followed by the same
+//! context ID and payload.
+
+use crate::{forwarder, http_datagram_codec, log_id, log_utils, net_utils};
+use bytes::{Buf, Bytes, BytesMut};
+
+/// The value of the `:protocol` pseudo-header of a CONNECT-UDP request
+pub(crate) const PROTOCOL: &str = "connect-udp";
+/// The header telling the capsule protocol is used on the stream
+pub(crate) const CAPSULE_PROTOCOL_HEADER: (&str, &str) = ("capsule-protocol", "?1");
+
+/// The default URI template path, `/.well-known/masque/udp/{target_host}/{target_port}/`
+const WELL_KNOWN_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+const DATAGRAM_CAPSULE_TYPE: u64 = 0x00;
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0x00;
+/// The longest DATAGRAM capsule which may be buffered
+const MAX_DATAGRAM_CAPSULE_LENGTH: usize =
+    net_utils::varint_len(UDP_PAYLOAD_CONTEXT_ID as usize) + net_utils::MAX_UDP_PAYLOAD_SIZE;
+
+/// The UDP proxying target of a request
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+pub(crate) struct Decoder {
+    buffer: BytesMut,
+    /// The number of bytes of a skipped capsule yet to be received
+    skipping: usize,
+    id: log_utils::IdChain<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder {}
+
+/// Encodes the HTTP datagrams carried in the QUIC DATAGRAM frames
+#[derive(Default)]
+pub(crate) struct FrameEncoder {}
+
+/// Extract the target from the path of a request following the default URI template
+pub(crate) fn parse_target(path: &str) -> Option<Target> {
+    let (host, port) = path
+        .strip_prefix(WELL_KNOWN_PATH_PREFIX)?
+        .trim_end_matches('/')
+        .split_once('/')?;
+    if host.is_empty() || port.is_empty() || !port.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(Target {
+        host: percent_decode(host)?,
+        port: port.parse().ok().filter(|x| *x != 0)?,
+    })
+}
+
+fn percent_decode(x: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(x.len());
+    let mut bytes = x.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let mut hex_digit = || char::from(bytes.next()?).to_digit(16);
+            decoded.push((hex_digit()? * 16 + hex_digit()?) as u8);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Extract the UDP payload from an HTTP datagram received in a QUIC DATAGRAM frame.
+/// Returns [`None`] for a datagram of an unknown context.
+pub(crate) fn parse_frame(mut datagram: Bytes) -> Option<Bytes> {
+    match net_utils::get_varint(&datagram)? {
+        (UDP_PAYLOAD_CONTEXT_ID, n) => {
+            datagram.advance(n);
+            Some(datagram)
+        }
+        _ => None,
+    }
+}
+
+impl Decoder {
+    pub fn new(id: log_utils::IdChain<u64>) -> Self {
+        Self {
+            buffer: Default::default(),
+            skipping: 0,
+            id,
+        }
+    }
+}
+
+impl http_datagram_codec::Decoder for Decoder {
+    type Datagram = Bytes;
+
+    fn decode_chunk(&mut self, mut data: Bytes) -> http_datagram_codec::DecodeResult<Bytes> {
+        loop {
+            let to_skip = self.skipping.min(data.len());
+            data.advance(to_skip);
+            self.skipping -= to_skip;
+            self.buffer.extend_from_slice(&data);
+
+            let (capsule_type, type_len) = match net_utils::get_varint(&self.buffer) {
+                Some(x) => x,
+                None => return http_datagram_codec::DecodeResult::WantMore,
+            };
+            let (length, length_len) = match net_utils::get_varint(&self.buffer[type_len..]) {
+                Some((x, n)) => (x as usize, n),
+                None => return http_datagram_codec::DecodeResult::WantMore,
+            };
+            let header_len = type_len + length_len;
+
+            if capsule_type != DATAGRAM_CAPSULE_TYPE || length > MAX_DATAGRAM_CAPSULE_LENGTH {
+                log_id!(
+                    trace,
+                    self.id,
+                    "Skipping capsule: type={}, length={}",
+                    capsule_type,
+                    length
+                );
+                self.buffer.advance(header_len);
+                let skipped = length.min(self.buffer.len());
+                self.buffer.advance(skipped);
+                self.skipping = length - skipped;
+                data = self.buffer.split().freeze();
+                continue;
+            }
+
+            if self.buffer.len() < header_len + length {
+                return http_datagram_codec::DecodeResult::WantMore;
+            }
+            self.buffer.advance(header_len);
+            let mut payload = self.buffer.split_to(length).freeze();
+            data = self.buffer.split().freeze();
+
+            match net_utils::get_varint(&payload) {
+                Some((UDP_PAYLOAD_CONTEXT_ID, n)) => {
+                    payload.advance(n);
+                    return http_datagram_codec::DecodeResult::Complete(payload, data);
+                }
+                x => {
+                    log_id!(
+                        debug,
+                        self.id,
+                        "Dropping datagram of unknown context: {:?}",
+                        x.map(|(x, _)| x)
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl http_datagram_codec::Encoder for Encoder {
+    type Datagram = forwarder::UdpDatagram;
+
+    fn encode_packet(&self, datagram: &Self::Datagram) -> Option<Bytes> {
+        let length =
+            net_utils::varint_len(UDP_PAYLOAD_CONTEXT_ID as usize) + datagram.payload.len();
+        let mut encoded = BytesMut::with_capacity(
+            net_utils::varint_len(DATAGRAM_CAPSULE_TYPE as usize)
+                + net_utils::varint_len(length)
+                + length,
+        );
+
+        net_utils::put_varint(&mut encoded, DATAGRAM_CAPSULE_TYPE);
+        net_utils::put_varint(&mut encoded, length as u64);
+        net_utils::put_varint(&mut encoded, UDP_PAYLOAD_CONTEXT_ID);
+        encoded.extend_from_slice(&datagram.payload);
+
+        Some(encoded.freeze())
+    }
+}
+
+impl http_datagram_codec::Encoder for FrameEncoder {
+    type Datagram = forwarder::UdpDatagram;
+
+    fn encode_packet(&self, datagram: &Self::Datagram) -> Option<Bytes> {
+        let mut encoded = BytesMut::with_capacity(
+            net_utils::varint_len(UDP_PAYLOAD_CONTEXT_ID as usize) + datagram.payload.len(),
+        );
+        net_utils::put_varint(&mut encoded, UDP_PAYLOAD_CONTEXT_ID);
+        encoded.extend_from_slice(&datagram.payload);
+
+        Some(encoded.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_datagram_codec::{DecodeResult, Decoder as _, Encoder as _};
+    use crate::log_utils::IdChain;
+
+    #[test]
+    fn targets() {
+        let target = |host: &str, port| {
+            Some(Target {
+                host: host.to_string(),
+                port,
+            })
+        };
+
+        assert_eq!(
+            target("192.0.2.6", 443),
+            parse_target("/.well-known/masque/udp/192.0.2.6/443/")
+        );
+        assert_eq!(
+            target("2001:db8::42", 53),
+            parse_target("/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/")
+        );
+        assert_eq!(
+            target("example.org", 3478),
+            parse_target("/.well-known/masque/udp/example.org/3478")
+        );
+        assert_eq!(None, parse_target("/.well-known/masque/udp/example.org/"));
+        assert_eq!(None, parse_target("/.well-known/masque/udp/example.org/0/"));
+        assert_eq!(
+            None,
+            parse_target("/.well-known/masque/udp/example.org/+53/")
+        );
+        assert_eq!(None, parse_target("/.well-known/masque/udp//53/"));
+        assert_eq!(None, parse_target("/.well-known/masque/ip/192.0.2.6/53/"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let encoded = Encoder::default()
+            .encode_packet(&forwarder::UdpDatagram {
+                meta: forwarder::UdpDatagramMeta {
+                    source: (std::net::Ipv4Addr::LOCALHOST, 53).into(),
+                    destination: (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+                },
+                payload: Bytes::from_static(b"hello"),
+            })
+            .unwrap();
+        assert_eq!(
+            &[0x00, 0x06, 0x00, b'h', b'e', b'l', b'l', b'o'],
+            &encoded[..]
+        );
+
+        let mut decoder = Decoder::new(IdChain::empty());
+        // Fed byte by byte, followed by the next capsule
+        for b in &encoded[..encoded.len() - 1] {
+            assert!(matches!(
+                decoder.decode_chunk(Bytes::copy_from_slice(&[*b])),
+                DecodeResult::WantMore
+            ));
+        }
+        match decoder.decode_chunk(Bytes::from_static(b"o\x00\x01\x00")) {
+            DecodeResult::Complete(payload, tail) => {
+                assert_eq!(b"hello", &payload[..]);
+                assert_eq!(b"\x00\x01\x00", &tail[..]);
+            }
+            DecodeResult::WantMore => panic!("Datagram is not decoded"),
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let encoded = FrameEncoder::default()
+            .encode_packet(&forwarder::UdpDatagram {
+                meta: forwarder::UdpDatagramMeta {
+                    source: (std::net::Ipv4Addr::LOCALHOST, 53).into(),
+                    destination: (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+                },
+                payload: Bytes::from_static(b"hello"),
+            })
+            .unwrap();
+        assert_eq!(b"\x00hello", &encoded[..]);
+        assert_eq!(Some(Bytes::from_static(b"hello")), parse_frame(encoded));
+        assert_eq!(None, parse_frame(Bytes::from_static(b"\x01hello")));
+        assert_eq!(None, parse_frame(Bytes::new()));
+    }
+
+    #[test]
+    fn skips_unknown_capsules() {
+        let mut decoder = Decoder::new(IdChain::empty());
+        // An unknown capsule split over the chunks, and a datagram of an unknown context
+        assert!(matches!(
+            decoder.decode_chunk(Bytes::from_static(b"\x3f\x04ab")),
+            DecodeResult::WantMore
+        ));
+        assert!(matches!(
+            decoder.decode_chunk(Bytes::from_static(b"cd\x00\x02\x01x")),
+            DecodeResult::WantMore
+        ));
+        match decoder.decode_chunk(Bytes::from_static(b"\x00\x03\x00hi")) {
+            DecodeResult::Complete(payload, tail) => {
+                assert_eq!(b"hi", &payload[..]);
+                assert!(tail.is_empty());
+            }
+            DecodeResult::WantMore => panic!("Datagram is not decoded"),
+        }
+    }
+}
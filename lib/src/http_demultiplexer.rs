@@ -14,8 +14,11 @@ impl HttpDemux {
         &self,
         protocol: tls_demultiplexer::Protocol,
         request: &http_codec::RequestHeaders,
+        tls_domain: &str,
     ) -> net_utils::Channel {
-        if self.check_ping(request) {
+        if self.check_website(request, tls_domain) {
+            net_utils::Channel::ReverseProxy
+        } else if self.check_ping(request) {
             net_utils::Channel::Ping
         } else if self.check_speedtest(request) {
             net_utils::Channel::Speedtest
@@ -56,6 +59,36 @@ impl HttpDemux {
             .is_some()
     }
 
+    /// With [`settings::ReverseProxySettings.serve_non_tunnel_requests`] enabled, the plain
+    /// requests to the endpoint host itself are served by the reverse proxy, like
+    /// a regular website would do
+    fn check_website(&self, request: &http_codec::RequestHeaders, tls_domain: &str) -> bool {
+        static PING_HEADER: http::HeaderName = http::HeaderName::from_static("x-ping");
+
+        if !self.core_settings.reverse_proxy.as_ref().is_some_and(|x| {
+            x.serve_non_tunnel_requests
+                && (x.non_tunnel_path_prefixes.is_empty()
+                    || x.non_tunnel_path_prefixes
+                        .iter()
+                        .any(|x| matches_path_prefix(request.uri.path(), x)))
+        }) || request.method == http::Method::CONNECT
+            || request.headers.contains_key(&PING_HEADER)
+            || self.check_speedtest(request)
+        {
+            return false;
+        }
+
+        // Requests to other hosts are forwarded through the tunnel
+        let host = request.uri.host().or_else(|| {
+            request
+                .headers
+                .get(http::header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.rsplit_once(':').map_or(x, |(h, _)| h))
+        });
+        host.is_none_or(|x| x.eq_ignore_ascii_case(tls_domain))
+    }
+
     fn check_reverse_proxy(
         &self,
         protocol: tls_demultiplexer::Protocol,
@@ -78,3 +111,116 @@ impl HttpDemux {
             .is_some_and(|x| request.uri.path().starts_with(x))
     }
 }
+
+/// Check the `path` starts with the `prefix` segments
+fn matches_path_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|x| x.is_empty() || x.starts_with('/') || prefix.ends_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_demux(serve_non_tunnel_requests: bool) -> HttpDemux {
+        make_demux_with_prefixes(serve_non_tunnel_requests, &[])
+    }
+
+    fn make_demux_with_prefixes(serve_non_tunnel_requests: bool, prefixes: &[&str]) -> HttpDemux {
+        let mut settings = settings::Settings::default();
+        let mut builder = settings::ReverseProxySettings::builder()
+            .path_mask("/ws".into())
+            .static_files(
+                settings::StaticFilesSettings::builder("/var/www")
+                    .build()
+                    .unwrap(),
+            )
+            .serve_non_tunnel_requests(serve_non_tunnel_requests);
+        for x in prefixes {
+            builder = builder.non_tunnel_path_prefix(x);
+        }
+        settings.reverse_proxy = Some(builder.build().unwrap());
+        HttpDemux::new(Arc::new(settings))
+    }
+
+    fn make_request(method: http::Method, uri: &str) -> http_codec::RequestHeaders {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn website_requests() {
+        let demux = make_demux(true);
+        let select = |x: &http_codec::RequestHeaders| {
+            demux.select(tls_demultiplexer::Protocol::Http2, x, "example.org")
+        };
+
+        assert_eq!(
+            net_utils::Channel::ReverseProxy,
+            select(&make_request(
+                http::Method::GET,
+                "https://example.org/index.html"
+            ))
+        );
+        assert_eq!(
+            net_utils::Channel::ReverseProxy,
+            select(&make_request(http::Method::POST, "/form"))
+        );
+        assert_eq!(
+            net_utils::Channel::Tunnel,
+            select(&make_request(http::Method::CONNECT, "example.com:443"))
+        );
+        assert_eq!(
+            net_utils::Channel::Tunnel,
+            select(&make_request(http::Method::GET, "http://example.com/"))
+        );
+    }
+
+    #[test]
+    fn website_requests_are_rejected_by_default() {
+        let demux = make_demux(false);
+        assert_eq!(
+            net_utils::Channel::Tunnel,
+            demux.select(
+                tls_demultiplexer::Protocol::Http2,
+                &make_request(http::Method::GET, "https://example.org/"),
+                "example.org",
+            )
+        );
+    }
+
+    #[test]
+    fn website_requests_by_path() {
+        let demux = make_demux_with_prefixes(true, &["/blog", "/static/"]);
+        let select = |path: &str| {
+            demux.select(
+                tls_demultiplexer::Protocol::Http2,
+                &make_request(http::Method::GET, &format!("https://example.org{}", path)),
+                "example.org",
+            )
+        };
+
+        assert_eq!(net_utils::Channel::ReverseProxy, select("/blog"));
+        assert_eq!(net_utils::Channel::ReverseProxy, select("/blog/post.html"));
+        assert_eq!(net_utils::Channel::ReverseProxy, select("/static/a.css"));
+        assert_eq!(net_utils::Channel::Tunnel, select("/blogs"));
+        assert_eq!(net_utils::Channel::Tunnel, select("/"));
+        assert_eq!(net_utils::Channel::Tunnel, select("/static"));
+    }
+
+    #[test]
+    fn path_prefix_must_be_absolute() {
+        assert!(settings::ReverseProxySettings::builder()
+            .path_mask("/ws".into())
+            .server_address("127.0.0.1:8080")
+            .unwrap()
+            .non_tunnel_path_prefix("blog")
+            .build()
+            .is_err());
+    }
+}
@@ -1,12 +1,15 @@
 use crate::downstream::Downstream;
+use crate::http2_codec::Http2Codec;
 use crate::http_codec::HttpCodec;
 use crate::net_utils::TcpDestination;
+use crate::settings::StrictModeSettings;
 use crate::tls_demultiplexer::Protocol;
 use crate::{
-    authentication, core, datagram_pipe, downstream, http_codec, http_datagram_codec,
-    http_demultiplexer, http_forwarded_stream, http_icmp_codec, http_ping_handler,
-    http_speedtest_handler, http_udp_codec, log_id, log_utils, net_utils, pipe, reverse_proxy,
-    tunnel,
+    affinity, authentication, core, datagram_pipe, downstream, forwarder, grpc_transport,
+    http_codec, http_connect_udp_codec, http_datagram_codec, http_demultiplexer,
+    http_forwarded_stream, http_icmp_codec, http_ping_handler, http_speedtest_handler,
+    http_udp_codec, log_id, log_utils, net_utils, pipe, policy, reconnect_tokens, reverse_proxy,
+    schedule, tunnel, websocket,
 };
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -15,8 +18,9 @@ use http_demultiplexer::HttpDemux;
 use std::collections::LinkedList;
 use std::io;
 use std::io::ErrorKind;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use tokio::sync::mpsc;
 
 const HEALTH_CHECK_AUTHORITY: &str = "_check";
 const UDP_AUTHORITY: &str = "_udp2";
@@ -40,11 +44,25 @@ pub(crate) struct HttpDownstream {
 struct TcpConnection {
     stream: Box<dyn http_codec::Stream>,
     id: log_utils::IdChain<u64>,
+    /// The extra headers of the successful response
+    ok_headers: Vec<(String, String)>,
 }
 
 struct DatagramMultiplexer {
     stream: Box<dyn http_codec::Stream>,
     id: log_utils::IdChain<u64>,
+    /// The extra headers of the successful response
+    ok_headers: Vec<(String, String)>,
+    /// Set in case of a CONNECT-UDP request
+    connect_udp: Option<ConnectUdp>,
+}
+
+struct ConnectUdp {
+    /// The canonical target of the request
+    target: TcpDestination,
+    /// The address the target is resolved into, once it passes the checks
+    /// of the tunneled destinations
+    destination: Option<SocketAddr>,
 }
 
 struct DatagramEncoder<D> {
@@ -58,9 +76,20 @@ struct DatagramDecoder<D> {
     pending_bytes: LinkedList<Bytes>,
 }
 
+/// Relays the datagrams of a CONNECT-UDP request to its target
+struct ConnectUdpSource {
+    decoder: DatagramDecoder<Bytes>,
+    /// The datagrams received in the QUIC DATAGRAM frames, if the client supports them
+    frames: Option<mpsc::Receiver<Bytes>>,
+    destination: SocketAddr,
+}
+
 struct PendingRequest {
+    context: Arc<core::Context>,
     stream: Box<dyn http_codec::Stream>,
     id: log_utils::IdChain<u64>,
+    /// The extra headers of the successful response
+    ok_headers: Vec<(String, String)>,
 }
 
 impl HttpDownstream {
@@ -102,28 +131,106 @@ impl Downstream for HttpDownstream {
 
             let protocol = self.protocol();
             let context = self.context.clone();
-            let channel = self.request_demux.select(self.protocol(), request);
+            let websocket_path = (protocol == Protocol::Http1)
+                .then(|| context.settings.listen_protocols.http1.as_ref())
+                .flatten()
+                .and_then(|x| x.websocket.as_ref())
+                .map(|x| x.path.as_str());
+            if websocket_path.is_some_and(|x| websocket::is_upgrade(request, x)) {
+                log_id!(trace, stream_id, "HTTP downstream: WebSocket upgrade");
+                let io = match websocket::accept(stream).await? {
+                    Some(x) => x,
+                    None => continue,
+                };
+                // The rest of the session is HTTP/2 over the WebSocket connection,
+                // while the HTTP/1.1 codec keeps relaying it over the transport
+                let inner = Http2Codec::new(context.settings.clone(), io, stream_id)?;
+                let mut outer = std::mem::replace(&mut self.codec, Box::new(inner));
+                tokio::spawn(async move { outer.listen().await });
+                continue;
+            }
+            let grpc_path = (protocol == Protocol::Http2)
+                .then(|| context.settings.listen_protocols.http2.as_ref())
+                .flatten()
+                .and_then(|x| x.grpc.as_ref())
+                .map(|x| x.path.as_str());
+            if grpc_path.is_some_and(|x| grpc_transport::is_call(request, x)) {
+                log_id!(trace, stream_id, "HTTP downstream: gRPC call");
+                let client_ip = stream.request().client_address().ok();
+                // A failed call must not break the other calls of the connection, which may
+                // belong to the other clients behind a load balancer
+                let codec = match grpc_transport::accept(stream).and_then(|x| {
+                    x.map(|x| Http2Codec::new(context.settings.clone(), x, stream_id.clone()))
+                        .transpose()
+                }) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log_id!(debug, stream_id, "Failed to accept gRPC call: {}", e);
+                        continue;
+                    }
+                };
+                // The call is a session of its own, authenticated by its tunnel requests
+                tokio::spawn(core::Core::on_tunnel_request(
+                    context,
+                    protocol,
+                    Box::new(codec),
+                    self.tls_domain.clone(),
+                    None,
+                    None,
+                    None,
+                    client_ip,
+                    stream_id,
+                ));
+                continue;
+            }
+            let channel = self
+                .request_demux
+                .select(self.protocol(), request, &self.tls_domain);
             log_id!(
                 trace,
                 stream_id,
                 "HTTP downstream routing to channel: {:?}",
                 channel
             );
+            if let Some(strict_mode) = context.settings.strict_mode(protocol) {
+                if let Err((status, reason)) = check_strictness(strict_mode, channel, request) {
+                    log_id!(debug, stream_id, "Refused by strict mode: {}", reason);
+                    fail_request(stream, status, vec![]);
+                    continue;
+                }
+            }
             match channel {
                 net_utils::Channel::Tunnel => {
                     log_id!(trace, stream_id, "HTTP downstream: tunnel request");
+                    let motd = context
+                        .settings
+                        .policy
+                        .as_ref()
+                        .and_then(|x| x.motd.as_ref());
+                    let ok_headers = context
+                        .settings
+                        .affinity
+                        .as_ref()
+                        .map(|x| affinity::response_header(x, request, &stream_id))
+                        .into_iter()
+                        .chain(motd.map(|x| (policy::MOTD_HEADER.to_string(), x.clone())))
+                        .chain(schedule::notice_header(&context))
+                        .collect();
                     break Ok(Some(Box::new(PendingRequest {
+                        context,
                         stream,
                         id: stream_id,
+                        ok_headers,
                     })));
                 }
                 net_utils::Channel::Ping => {
                     log_id!(trace, stream_id, "HTTP downstream: ping request");
                     tokio::spawn(async move {
                         http_ping_handler::listen(
-                            context.shutdown.clone(),
+                            context.clone(),
                             Box::new(http_codec::stream_into_codec(stream, protocol)),
-                            context.settings.tls_handshake_timeout,
+                            context.settings.handler_request_timeout(protocol),
                             stream_id,
                         )
                         .await
@@ -135,7 +242,7 @@ impl Downstream for HttpDownstream {
                         http_speedtest_handler::listen(
                             context.shutdown.clone(),
                             Box::new(http_codec::stream_into_codec(stream, protocol)),
-                            context.settings.tls_handshake_timeout,
+                            context.settings.handler_request_timeout(protocol),
                             stream_id,
                         )
                         .await
@@ -164,6 +271,10 @@ impl Downstream for HttpDownstream {
         self.codec.graceful_shutdown().await
     }
 
+    async fn drain(&mut self) -> io::Result<()> {
+        self.codec.drain().await
+    }
+
     fn protocol(&self) -> Protocol {
         self.codec.protocol()
     }
@@ -193,7 +304,8 @@ impl downstream::PendingRequest for TcpConnection {
             let (source, sink) = self.stream.split();
             return Ok((
                 source.finalize(),
-                sink.send_ok_response(false)?.into_pipe_sink(),
+                sink.send_ok_response_with_headers(self.ok_headers, false)?
+                    .into_pipe_sink(),
             ));
         }
 
@@ -238,6 +350,72 @@ impl downstream::PendingTcpConnectRequest for TcpConnection {
     fn user_agent(&self) -> Option<String> {
         self.stream.request().user_agent()
     }
+
+    fn add_ok_header(&mut self, name: &str, value: String) {
+        self.ok_headers.push((name.to_string(), value));
+    }
+}
+
+impl PendingRequest {
+    /// Only the CONNECT-UDP requests are supported among the extended CONNECT ones
+    fn promote_extended_connect(
+        self: Box<Self>,
+    ) -> io::Result<Option<downstream::PendingDemultiplexedRequest>> {
+        let request = self.stream.request().request();
+        let connect_udp_enabled = request.version == http::Version::HTTP_3
+            && self
+                .context
+                .settings
+                .listen_protocols
+                .quic
+                .as_ref()
+                .is_some_and(|x| x.connect_udp);
+        if request.method != http::Method::CONNECT
+            || request
+                .extensions
+                .get::<http_codec::ConnectProtocol>()
+                .is_none_or(|x| x.0 != http_connect_udp_codec::PROTOCOL)
+            || !connect_udp_enabled
+        {
+            log_id!(
+                debug,
+                self.id,
+                "Unsupported extended CONNECT: {:?}",
+                request
+            );
+            fail_request(self.stream, StatusCode::NOT_IMPLEMENTED, vec![]);
+            return Ok(None);
+        }
+
+        let target = http_connect_udp_codec::parse_target(request.uri.path())
+            .map(|x| TcpDestination::HostName((x.host, x.port)))
+            .and_then(|x| net_utils::canonicalize_destination(x).ok());
+        let target = match target {
+            Some(x) => x,
+            None => {
+                log_id!(debug, self.id, "Invalid CONNECT-UDP target: {:?}", request);
+                fail_request(self.stream, StatusCode::BAD_REQUEST, vec![]);
+                return Ok(None);
+            }
+        };
+        let (name, value) = http_connect_udp_codec::CAPSULE_PROTOCOL_HEADER;
+        let mut ok_headers = self.ok_headers;
+        ok_headers.push((name.to_string(), value.to_string()));
+
+        Ok(Some(
+            downstream::PendingDemultiplexedRequest::DatagramMultiplexer(Box::new(
+                DatagramMultiplexer {
+                    stream: self.stream,
+                    id: self.id,
+                    ok_headers,
+                    connect_udp: Some(ConnectUdp {
+                        target,
+                        destination: None,
+                    }),
+                },
+            )),
+        ))
+    }
 }
 
 impl downstream::PendingRequest for PendingRequest {
@@ -246,6 +424,14 @@ impl downstream::PendingRequest for PendingRequest {
     fn promote_to_next_state(self: Box<Self>) -> io::Result<Self::NextState> {
         let request = self.stream.request().request();
 
+        if request
+            .extensions
+            .get::<http_codec::ConnectProtocol>()
+            .is_some()
+        {
+            return self.promote_extended_connect();
+        }
+
         match request.uri.authority().map(http::uri::Authority::as_str) {
             Some(HEALTH_CHECK_AUTHORITY) if request.method == http::Method::CONNECT => {
                 self.stream.split().1.send_ok_response(true).map(|_| None)
@@ -258,6 +444,8 @@ impl downstream::PendingRequest for PendingRequest {
                         DatagramMultiplexer {
                             stream: self.stream,
                             id: self.id,
+                            ok_headers: self.ok_headers,
+                            connect_udp: None,
                         },
                     )),
                 ))
@@ -271,6 +459,7 @@ impl downstream::PendingRequest for PendingRequest {
                 Box::new(TcpConnection {
                     stream: self.stream,
                     id: self.id,
+                    ok_headers: self.ok_headers,
                 }),
             ))),
         }
@@ -285,12 +474,77 @@ impl downstream::PendingMultiplexedRequest for PendingRequest {
     fn auth_info(&self) -> io::Result<Option<authentication::Source>> {
         self.stream.request().auth_info()
     }
+
+    fn client_address(&self) -> io::Result<IpAddr> {
+        self.stream.request().client_address()
+    }
+
+    fn terms_acknowledgment(&self) -> Option<String> {
+        self.stream
+            .request()
+            .request()
+            .headers
+            .get(policy::TERMS_ACK_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_string)
+    }
+
+    fn reconnect_token(&self) -> Option<String> {
+        self.stream
+            .request()
+            .request()
+            .headers
+            .get(reconnect_tokens::RECONNECT_TOKEN_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_string)
+    }
+
+    fn add_ok_header(&mut self, name: &str, value: String) {
+        self.ok_headers.push((name.to_string(), value));
+    }
 }
 
 impl downstream::PendingRequest for DatagramMultiplexer {
     type NextState = downstream::DatagramPipeHalves;
 
     fn promote_to_next_state(self: Box<Self>) -> io::Result<Self::NextState> {
+        if let Some(connect_udp) = self.connect_udp {
+            let destination = connect_udp.destination.ok_or_else(|| {
+                io::Error::new(ErrorKind::Other, "CONNECT-UDP target is not checked")
+            })?;
+            let mut stream = self.stream;
+            let frames = stream.take_datagrams();
+            let encoder: Box<dyn http_datagram_codec::Encoder<Datagram = forwarder::UdpDatagram>> =
+                match frames {
+                    Some(_) => Box::<http_connect_udp_codec::FrameEncoder>::default(),
+                    None => Box::<http_connect_udp_codec::Encoder>::default(),
+                };
+            log_id!(
+                debug,
+                self.id,
+                "CONNECT-UDP datagrams in QUIC DATAGRAM frames: {}",
+                frames.is_some()
+            );
+            let (source, sink) = stream.split();
+            return Ok(downstream::DatagramPipeHalves::Udp(
+                Box::new(ConnectUdpSource {
+                    decoder: DatagramDecoder {
+                        source: source.finalize(),
+                        decoder: Box::new(http_connect_udp_codec::Decoder::new(self.id.clone())),
+                        pending_bytes: Default::default(),
+                    },
+                    frames,
+                    destination,
+                }),
+                Box::new(DatagramEncoder {
+                    sink: sink
+                        .send_ok_response_with_headers(self.ok_headers, false)?
+                        .into_datagram_sink(),
+                    encoder,
+                }),
+            ));
+        }
+
         let authority = self.stream.request().authority()?.to_string();
         let (source, sink) = self.stream.split();
         match authority.as_str() {
@@ -301,7 +555,9 @@ impl downstream::PendingRequest for DatagramMultiplexer {
                     pending_bytes: Default::default(),
                 }),
                 Box::new(DatagramEncoder {
-                    sink: sink.send_ok_response(false)?.into_datagram_sink(),
+                    sink: sink
+                        .send_ok_response_with_headers(self.ok_headers, false)?
+                        .into_datagram_sink(),
                     encoder: Box::<http_udp_codec::Encoder>::default(),
                 }),
             )),
@@ -312,7 +568,9 @@ impl downstream::PendingRequest for DatagramMultiplexer {
                     pending_bytes: Default::default(),
                 }),
                 Box::new(DatagramEncoder {
-                    sink: sink.send_ok_response(false)?.into_datagram_sink(),
+                    sink: sink
+                        .send_ok_response_with_headers(self.ok_headers, false)?
+                        .into_datagram_sink(),
                     encoder: Box::<http_icmp_codec::Encoder>::default(),
                 }),
             )),
@@ -333,6 +591,20 @@ impl downstream::PendingDatagramMultiplexerRequest for DatagramMultiplexer {
     fn user_agent(&self) -> Option<String> {
         self.stream.request().user_agent()
     }
+
+    fn add_ok_header(&mut self, name: &str, value: String) {
+        self.ok_headers.push((name.to_string(), value));
+    }
+
+    fn connect_udp_target(&self) -> Option<TcpDestination> {
+        self.connect_udp.as_ref().map(|x| x.target.clone())
+    }
+
+    fn set_connect_udp_destination(&mut self, destination: SocketAddr) {
+        if let Some(x) = &mut self.connect_udp {
+            x.destination = Some(destination);
+        }
+    }
 }
 
 impl<D> downstream::StreamId for DatagramDecoder<D> {
@@ -376,6 +648,49 @@ impl<D> datagram_pipe::Source for DatagramDecoder<D> {
     }
 }
 
+#[async_trait]
+impl datagram_pipe::Source for ConnectUdpSource {
+    type Output = downstream::UdpDatagram;
+
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.decoder.source.id()
+    }
+
+    async fn read(&mut self) -> io::Result<downstream::UdpDatagram> {
+        let payload = match &mut self.frames {
+            None => datagram_pipe::Source::read(&mut self.decoder).await?,
+            // A client may still send the capsules on the stream. Reading the stream is
+            // cancel safe, as it is interrupted only while waiting for the stream data.
+            Some(frames) => loop {
+                tokio::select! {
+                    x = datagram_pipe::Source::read(&mut self.decoder) => break x?,
+                    x = frames.recv() => match x {
+                        None => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                        Some(x) => match http_connect_udp_codec::parse_frame(x) {
+                            Some(x) => break x,
+                            None => log_id!(
+                                debug,
+                                self.decoder.source.id(),
+                                "Dropping datagram frame of unknown context"
+                            ),
+                        },
+                    },
+                }
+            },
+        };
+
+        Ok(downstream::UdpDatagram {
+            meta: downstream::UdpDatagramMeta {
+                // A request relays a single flow, so the source only has to be the same
+                source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                destination: self.destination,
+                app_name: None,
+            },
+            payload,
+        })
+    }
+}
+
 #[async_trait]
 impl<D: Send> datagram_pipe::Sink for DatagramEncoder<D> {
     type Input = D;
@@ -393,7 +708,13 @@ impl<D: Send> datagram_pipe::Sink for DatagramEncoder<D> {
 
 fn tunnel_error_to_status_code(error: &tunnel::ConnectionError) -> StatusCode {
     match error {
-        tunnel::ConnectionError::Authentication(_) => AUTHORIZATION_FAILURE_STATUS_CODE,
+        tunnel::ConnectionError::Authentication(_)
+        | tunnel::ConnectionError::DigestChallenge { .. } => AUTHORIZATION_FAILURE_STATUS_CODE,
+        tunnel::ConnectionError::TermsNotAcknowledged { .. } => StatusCode::FORBIDDEN,
+        tunnel::ConnectionError::MetadataEndpoint => StatusCode::FORBIDDEN,
+        tunnel::ConnectionError::DestinationDenied => StatusCode::FORBIDDEN,
+        tunnel::ConnectionError::Maintenance { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        tunnel::ConnectionError::AuthLockout { .. } => StatusCode::TOO_MANY_REQUESTS,
         _ => BAD_STATUS_CODE,
     }
 }
@@ -411,6 +732,18 @@ fn tunnel_error_to_warn_header(
             AUTHORIZATION_FAILURE_EXTRA_HEADER.0.to_string(),
             AUTHORIZATION_FAILURE_EXTRA_HEADER.1.to_string(),
         )],
+        // The digest challenges go first as the stronger ones
+        tunnel::ConnectionError::DigestChallenge { challenges, .. } => challenges
+            .iter()
+            .map(String::as_str)
+            .chain([AUTHORIZATION_FAILURE_EXTRA_HEADER.1])
+            .map(|x| {
+                (
+                    AUTHORIZATION_FAILURE_EXTRA_HEADER.0.to_string(),
+                    x.to_string(),
+                )
+            })
+            .collect(),
         tunnel::ConnectionError::Timeout => {
             vec![(WARNING_HEADER_NAME.to_string(), format!("302 - {}", error))]
         }
@@ -425,6 +758,30 @@ fn tunnel_error_to_warn_header(
             (DNS_WARNING_HEADER_NAME.to_string(), hostname.to_string()),
             (WARNING_HEADER_NAME.to_string(), format!("311 - {}", error)),
         ],
+        tunnel::ConnectionError::MetadataEndpoint => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("312 - {}", error))]
+        }
+        tunnel::ConnectionError::DestinationDenied => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("313 - {}", error))]
+        }
+        tunnel::ConnectionError::TermsNotAcknowledged { terms, version } => vec![
+            (policy::TERMS_HEADER.to_string(), terms.clone()),
+            (policy::TERMS_VERSION_HEADER.to_string(), version.clone()),
+        ],
+        tunnel::ConnectionError::Maintenance { retry_after } => retry_after
+            .map(|x| {
+                (
+                    http::header::RETRY_AFTER.to_string(),
+                    x.as_secs().to_string(),
+                )
+            })
+            .into_iter()
+            .collect(),
+        tunnel::ConnectionError::AuthLockout { retry_after } => vec![(
+            http::header::RETRY_AFTER.to_string(),
+            // Rounded up, so that a client retrying in time is not rejected again
+            (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).to_string(),
+        )],
         tunnel::ConnectionError::Other(_) => vec![(
             WARNING_HEADER_NAME.to_string(),
             "300 - Connection failed for some reason".to_string(),
@@ -432,6 +789,54 @@ fn tunnel_error_to_warn_header(
     }
 }
 
+/// Check the request routed to the `channel` against the strictness policy of the listener.
+/// Returns the status code to refuse the request with and the reason for the log.
+fn check_strictness(
+    settings: &StrictModeSettings,
+    channel: net_utils::Channel,
+    request: &http_codec::RequestHeaders,
+) -> Result<(), (StatusCode, &'static str)> {
+    if settings.reject_http10 && request.version < http::Version::HTTP_11 {
+        return Err((StatusCode::HTTP_VERSION_NOT_SUPPORTED, "HTTP/1.0 request"));
+    }
+
+    if request.method != http::Method::CONNECT {
+        let exempt = channel != net_utils::Channel::Tunnel
+            && settings
+                .decoy_methods
+                .iter()
+                .any(|x| x == request.method.as_str());
+        return if exempt {
+            Ok(())
+        } else {
+            Err((StatusCode::METHOD_NOT_ALLOWED, "Non-CONNECT request"))
+        };
+    }
+
+    // The extended CONNECT requests target the endpoint itself, and name the destination
+    // in the path
+    if settings.require_authority_form
+        && channel == net_utils::Channel::Tunnel
+        && request
+            .extensions
+            .get::<http_codec::ConnectProtocol>()
+            .is_none()
+    {
+        let uri = &request.uri;
+        let pseudo_host = matches!(
+            uri.authority().map(http::uri::Authority::as_str),
+            Some(HEALTH_CHECK_AUTHORITY | UDP_AUTHORITY | ICMP_AUTHORITY)
+        );
+        if !pseudo_host
+            && (uri.scheme().is_some() || uri.path_and_query().is_some() || uri.port().is_none())
+        {
+            return Err((StatusCode::BAD_REQUEST, "Target is not in authority form"));
+        }
+    }
+
+    Ok(())
+}
+
 fn fail_request(
     stream: Box<dyn http_codec::Stream>,
     status: StatusCode,
@@ -454,3 +859,139 @@ fn request_hostname(request: &dyn http_codec::PendingRequest) -> &str {
         .map(http::uri::Authority::as_str)
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net_utils::Channel;
+
+    fn make_request(
+        version: http::Version,
+        method: http::Method,
+        uri: &str,
+    ) -> http_codec::RequestHeaders {
+        http::Request::builder()
+            .version(version)
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    fn check(
+        channel: Channel,
+        version: http::Version,
+        method: http::Method,
+        uri: &str,
+    ) -> Result<(), StatusCode> {
+        let settings = StrictModeSettings::builder().build().unwrap();
+        check_strictness(&settings, channel, &make_request(version, method, uri))
+            .map_err(|(x, _)| x)
+    }
+
+    #[test]
+    fn strict_mode_passes_tunnel_requests() {
+        for (version, uri) in [
+            (http::Version::HTTP_11, "example.org:443"),
+            (http::Version::HTTP_2, "192.0.2.1:80"),
+            (http::Version::HTTP_3, "[2001:db8::1]:443"),
+            (http::Version::HTTP_11, UDP_AUTHORITY),
+            (http::Version::HTTP_2, HEALTH_CHECK_AUTHORITY),
+        ] {
+            assert_eq!(
+                Ok(()),
+                check(Channel::Tunnel, version, http::Method::CONNECT, uri),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn strict_mode_passes_connect_udp_requests() {
+        let settings = StrictModeSettings::builder().build().unwrap();
+        let mut request = make_request(
+            http::Version::HTTP_3,
+            http::Method::CONNECT,
+            "https://vpn.example.org/.well-known/masque/udp/192.0.2.6/443/",
+        );
+        assert!(check_strictness(&settings, Channel::Tunnel, &request).is_err());
+        request.extensions.insert(http_codec::ConnectProtocol(
+            http_connect_udp_codec::PROTOCOL.to_string(),
+        ));
+        assert!(check_strictness(&settings, Channel::Tunnel, &request).is_ok());
+    }
+
+    #[test]
+    fn strict_mode_refuses_deviations() {
+        assert_eq!(
+            Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+            check(
+                Channel::Tunnel,
+                http::Version::HTTP_10,
+                http::Method::CONNECT,
+                "example.org:443"
+            )
+        );
+        assert_eq!(
+            Err(StatusCode::METHOD_NOT_ALLOWED),
+            check(
+                Channel::Tunnel,
+                http::Version::HTTP_11,
+                http::Method::GET,
+                "http://example.org/"
+            )
+        );
+        assert_eq!(
+            Err(StatusCode::BAD_REQUEST),
+            check(
+                Channel::Tunnel,
+                http::Version::HTTP_11,
+                http::Method::CONNECT,
+                "example.org"
+            )
+        );
+        assert_eq!(
+            Err(StatusCode::BAD_REQUEST),
+            check(
+                Channel::Tunnel,
+                http::Version::HTTP_11,
+                http::Method::CONNECT,
+                "https://example.org:443/"
+            )
+        );
+    }
+
+    #[test]
+    fn strict_mode_exempts_decoy_methods() {
+        assert_eq!(
+            Ok(()),
+            check(
+                Channel::ReverseProxy,
+                http::Version::HTTP_2,
+                http::Method::GET,
+                "/"
+            )
+        );
+        assert_eq!(
+            Ok(()),
+            check(
+                Channel::Ping,
+                http::Version::HTTP_11,
+                http::Method::HEAD,
+                "/"
+            )
+        );
+        assert_eq!(
+            Err(StatusCode::METHOD_NOT_ALLOWED),
+            check(
+                Channel::ReverseProxy,
+                http::Version::HTTP_11,
+                http::Method::POST,
+                "/"
+            )
+        );
+    }
+}
@@ -52,6 +52,22 @@ pub(crate) fn into_forwarded(
     ))
 }
 
+/// Wrap the `sink` of a response body into a sink decoding the body in the chunked transfer
+/// coding, so that the encoded trailers are sent as the trailer section of the response
+pub(crate) fn decode_chunked(
+    sink: Box<dyn http_codec::BodySink>,
+    id: log_utils::IdChain<u64>,
+) -> Box<dyn pipe::Sink> {
+    Box::new(ForwardedStreamSink {
+        state: SinkState::WaitingChunkPrefix(SinkWaitingChunkPrefix {
+            buffer: Default::default(),
+            sink,
+        }),
+        fake_unsent: false,
+        id,
+    })
+}
+
 struct ForwardedStreamSource {
     state: SourceState,
     /// Needed to not over-consume bytes sent as part of HTTP/1-specifics (like request headers and
@@ -105,18 +121,22 @@ struct SinkTransferringBodyNonEncoded {
 
 struct SinkWaitingChunkPrefix {
     buffer: BytesMut,
-    sink: Box<dyn pipe::Sink>,
+    sink: Box<dyn http_codec::BodySink>,
 }
 
 struct SinkTransferringBodyChunked {
-    sink: Box<dyn pipe::Sink>,
+    sink: Box<dyn http_codec::BodySink>,
     remaining_chunk_size: Option<u64>,
 }
 
 struct SinkWaitingChunkSuffix {
     buffer: BytesMut,
-    terminating_chunk: bool,
-    sink: Box<dyn pipe::Sink>,
+    sink: Box<dyn http_codec::BodySink>,
+}
+
+struct SinkWaitingTrailers {
+    buffer: BytesMut,
+    sink: Box<dyn http_codec::BodySink>,
 }
 
 enum SinkState {
@@ -126,6 +146,7 @@ enum SinkState {
     WaitingChunkPrefix(SinkWaitingChunkPrefix),
     TransferringBodyChunked(SinkTransferringBodyChunked),
     WaitingChunkSuffix(SinkWaitingChunkSuffix),
+    WaitingTrailers(SinkWaitingTrailers),
 }
 
 #[async_trait]
@@ -248,6 +269,7 @@ impl pipe::Sink for ForwardedStreamSink {
             SinkState::WaitingChunkPrefix(_) => self.on_encoded_chunk_prefix(data),
             SinkState::TransferringBodyChunked(_) => self.on_encoded_chunk(data),
             SinkState::WaitingChunkSuffix(_) => self.on_encoded_chunk_suffix(data),
+            SinkState::WaitingTrailers(_) => self.on_trailers_chunk(data),
         }
     }
 
@@ -261,6 +283,7 @@ impl pipe::Sink for ForwardedStreamSink {
             SinkState::WaitingChunkPrefix(mut x) => x.sink.eof(),
             SinkState::TransferringBodyChunked(mut x) => x.sink.eof(),
             SinkState::WaitingChunkSuffix(mut x) => x.sink.eof(),
+            SinkState::WaitingTrailers(mut x) => x.sink.eof(),
         }
     }
 
@@ -274,22 +297,23 @@ impl pipe::Sink for ForwardedStreamSink {
             SinkState::Idle | SinkState::WaitingResponse(_) => {
                 Err(io::Error::new(ErrorKind::Other, "Invalid state"))
             }
-            SinkState::WaitingChunkPrefix(_) | SinkState::WaitingChunkSuffix(_) => Ok(()),
+            SinkState::WaitingChunkPrefix(_)
+            | SinkState::WaitingChunkSuffix(_)
+            | SinkState::WaitingTrailers(_) => Ok(()),
             SinkState::TransferringBodyNonEncoded(x) => x.sink.wait_writable().await,
             SinkState::TransferringBodyChunked(x) => x.sink.wait_writable().await,
         }
     }
 
     async fn flush(&mut self) -> io::Result<()> {
-        let sink = match self.state {
-            SinkState::Idle | SinkState::WaitingResponse(_) => return Ok(()),
-            SinkState::TransferringBodyNonEncoded(ref mut x) => &mut x.sink,
-            SinkState::WaitingChunkPrefix(ref mut x) => &mut x.sink,
-            SinkState::TransferringBodyChunked(ref mut x) => &mut x.sink,
-            SinkState::WaitingChunkSuffix(ref mut x) => &mut x.sink,
-        };
-
-        sink.flush().await
+        match self.state {
+            SinkState::Idle | SinkState::WaitingResponse(_) => Ok(()),
+            SinkState::TransferringBodyNonEncoded(ref mut x) => x.sink.flush().await,
+            SinkState::WaitingChunkPrefix(ref mut x) => x.sink.flush().await,
+            SinkState::TransferringBodyChunked(ref mut x) => x.sink.flush().await,
+            SinkState::WaitingChunkSuffix(ref mut x) => x.sink.flush().await,
+            SinkState::WaitingTrailers(ref mut x) => x.sink.flush().await,
+        }
     }
 }
 
@@ -323,7 +347,7 @@ impl ForwardedStreamSink {
         self.state = match body_length {
             Some(BodyLength::Chunked) => SinkState::WaitingChunkPrefix(SinkWaitingChunkPrefix {
                 buffer: Default::default(),
-                sink: sink.into_pipe_sink(),
+                sink: sink.into_body_sink(),
             }),
             None | Some(BodyLength::Determined(_)) => {
                 SinkState::TransferringBodyNonEncoded(SinkTransferringBodyNonEncoded {
@@ -406,9 +430,8 @@ impl ForwardedStreamSink {
         };
 
         if chunk_size == 0 {
-            self.state = SinkState::WaitingChunkSuffix(SinkWaitingChunkSuffix {
+            self.state = SinkState::WaitingTrailers(SinkWaitingTrailers {
                 buffer: Default::default(),
-                terminating_chunk: true,
                 sink: match std::mem::replace(&mut self.state, SinkState::Idle) {
                     SinkState::WaitingChunkPrefix(x) => x.sink,
                     _ => unreachable!(),
@@ -455,7 +478,6 @@ impl ForwardedStreamSink {
         } else {
             self.state = SinkState::WaitingChunkSuffix(SinkWaitingChunkSuffix {
                 buffer: BytesMut::with_capacity(ENCODED_CHUNK_SUFFIX.len()),
-                terminating_chunk: false,
                 sink: state.sink,
             });
         }
@@ -489,17 +511,6 @@ impl ForwardedStreamSink {
         if suffix.len() < ENCODED_CHUNK_SUFFIX.len() {
             state.buffer = BytesMut::from(suffix.as_ref());
             self.state = SinkState::WaitingChunkSuffix(state);
-        } else if state.terminating_chunk {
-            if !data.is_empty() {
-                log_id!(
-                    debug,
-                    self.id,
-                    "Dropping non-processed {} bytes coming after terminating encoded chunk",
-                    data.len()
-                );
-            }
-            data = Bytes::new();
-            state.sink.eof()?;
         } else {
             self.state = SinkState::WaitingChunkPrefix(SinkWaitingChunkPrefix {
                 buffer: Default::default(),
@@ -509,6 +520,65 @@ impl ForwardedStreamSink {
 
         Ok(data)
     }
+
+    fn on_trailers_chunk(&mut self, data: Bytes) -> io::Result<Bytes> {
+        let mut state = match std::mem::replace(&mut self.state, SinkState::Idle) {
+            SinkState::WaitingTrailers(x) => x,
+            _ => unreachable!(),
+        };
+
+        let data = if state.buffer.is_empty() {
+            data
+        } else {
+            state.buffer.extend_from_slice(&data);
+            std::mem::take(&mut state.buffer).freeze()
+        };
+
+        let mut headers = vec![httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS_NUM];
+        let (pos, trailers) = match httparse::parse_headers(&data, &mut headers) {
+            Ok(httparse::Status::Complete((pos, trailers))) => (pos, trailers),
+            Ok(httparse::Status::Partial) => {
+                state.buffer = BytesMut::from(data.as_ref());
+                self.state = SinkState::WaitingTrailers(state);
+                return Ok(Bytes::new());
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("Invalid trailer section: {}", e),
+                ))
+            }
+        };
+
+        if pos < data.len() {
+            log_id!(
+                debug,
+                self.id,
+                "Dropping non-processed {} bytes coming after terminating encoded chunk",
+                data.len() - pos
+            );
+        }
+
+        if trailers.is_empty() {
+            state.sink.eof()?;
+        } else {
+            let mut map = http::HeaderMap::with_capacity(trailers.len());
+            for h in trailers {
+                map.append(
+                    http::HeaderName::from_bytes(h.name.as_bytes()).map_err(|e| {
+                        io::Error::new(ErrorKind::Other, format!("Invalid trailer name: {}", e))
+                    })?,
+                    http::HeaderValue::from_bytes(h.value).map_err(|e| {
+                        io::Error::new(ErrorKind::Other, format!("Invalid trailer value: {}", e))
+                    })?,
+                );
+            }
+            log_id!(trace, self.id, "Received trailers: {:?}", map);
+            state.sink.send_trailers(map)?;
+        }
+
+        Ok(Bytes::new())
+    }
 }
 
 impl SinkWaitingResponse {
@@ -1,30 +1,49 @@
 use crate::http_codec::HttpCodec;
-use crate::shutdown::Shutdown;
-use crate::{log_id, log_utils};
-use std::sync::{Arc, Mutex};
+use crate::server_timing::ServerTiming;
+use crate::{core, exit_policy, http_codec, log_id, log_utils, server_timing};
+use bytes::Bytes;
+use std::io;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub(crate) async fn listen(
-    shutdown: Arc<Mutex<Shutdown>>,
+    context: Arc<core::Context>,
     mut codec: Box<dyn HttpCodec>,
     timeout: Duration,
     log_id: log_utils::IdChain<u64>,
 ) {
     let (mut shutdown_notification, _shutdown_completion) = {
-        let shutdown = shutdown.lock().unwrap();
+        let shutdown = context.shutdown.lock().unwrap();
         (shutdown.notification_handler(), shutdown.completion_guard())
     };
 
     let listen_task = async {
         match codec.listen().await {
             Ok(Some(x)) => {
+                let timing = context.settings.server_timing.then(ServerTiming::start);
                 log_id!(
                     trace,
                     log_id,
                     "Received request: {:?}",
                     x.request().request()
                 );
-                if let Err(e) = x.split().1.send_ok_response(true) {
+                let policy = is_exit_policy_request(x.request().request())
+                    .then(|| exit_policy::document(&context.settings))
+                    .flatten();
+                let respond = x.split().1;
+                let result = match policy {
+                    Some(x) => send_exit_policy(respond, Bytes::from(x)).await,
+                    None => respond
+                        .send_ok_response_with_headers(
+                            timing
+                                .iter()
+                                .map(|x| (server_timing::HEADER.to_string(), x.header_value()))
+                                .collect(),
+                            true,
+                        )
+                        .map(|_| ()),
+                };
+                if let Err(e) = result {
                     log_id!(debug, log_id, "Failed to send ping response: {}", e);
                 }
             }
@@ -48,3 +67,24 @@ pub(crate) async fn listen(
         log_id!(debug, log_id, "Failed to shut down session: {}", e);
     }
 }
+
+fn is_exit_policy_request(request: &http_codec::RequestHeaders) -> bool {
+    request.method == http::Method::GET && request.uri.path() == exit_policy::PATH
+}
+
+async fn send_exit_policy(
+    respond: Box<dyn http_codec::PendingRespond>,
+    document: Bytes,
+) -> io::Result<()> {
+    let response = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, document.len())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    let mut sink = respond.send_response(response, false)?.into_pipe_sink();
+    sink.write_all(document).await?;
+    sink.eof()
+}
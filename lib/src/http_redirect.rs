@@ -0,0 +1,266 @@
+use crate::http1_codec::Http1Codec;
+use crate::http_codec::HttpCodec;
+use crate::settings::HttpRedirectSettings;
+use crate::{core, http_codec, log_id, log_utils};
+use bytes::Bytes;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+const LOG_FMT: &str = "HTTP_REDIRECT={}";
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const DEFAULT_HTTPS_PORT: u16 = 443;
+
+pub(crate) async fn listen(
+    context: Arc<core::Context>,
+    log_chain: log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let settings = match context.settings.http_redirect.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let (mut shutdown_notification, _shutdown_completion) = {
+        let shutdown = context.shutdown.lock().unwrap();
+        (shutdown.notification_handler(), shutdown.completion_guard())
+    };
+
+    let listener = TcpListener::bind(settings.listen_address).await?;
+    context
+        .status_report
+        .listener_bound("http_redirect", "tcp", listener.local_addr()?);
+    let next_id = AtomicU64::default();
+    let accept = async {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let log_id = log_chain.extended(log_utils::IdItem::new(
+                LOG_FMT,
+                next_id.fetch_add(1, Ordering::Relaxed),
+            ));
+            log_id!(trace, log_id, "New connection from {}", peer);
+            tokio::spawn(handle_connection(context.clone(), stream, log_id));
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        x = accept => x,
+    }
+}
+
+async fn handle_connection(
+    context: Arc<core::Context>,
+    io: TcpStream,
+    log_id: log_utils::IdChain<u64>,
+) {
+    let settings = context.settings.http_redirect.as_ref().unwrap();
+    let mut codec = Http1Codec::new(context.settings.clone(), io, log_id.clone());
+    let stream = match tokio::time::timeout(settings.request_timeout, codec.listen()).await {
+        Ok(Ok(Some(x))) => x,
+        Ok(Ok(None)) => {
+            log_id!(debug, log_id, "Connection closed immediately");
+            return;
+        }
+        Ok(Err(e)) => {
+            log_id!(debug, log_id, "Listen failed: {}", e);
+            return;
+        }
+        Err(_elapsed) => {
+            log_id!(
+                debug,
+                log_id,
+                "Didn't receive any request during configured period"
+            );
+            return;
+        }
+    };
+
+    let dispatch = async {
+        match codec.listen().await {
+            Ok(Some(x)) => log_id!(
+                debug,
+                log_id,
+                "Got unexpected request while processing previous: {:?}",
+                x.request().request(),
+            ),
+            Ok(None) => (),
+            Err(e) => log_id!(debug, log_id, "IO error during processing: {}", e),
+        }
+    };
+
+    let handle = async {
+        let https_port = settings
+            .https_port
+            .unwrap_or(context.settings.listen_address.port());
+        if let Err(e) = handle_request(settings, https_port, stream, &log_id).await {
+            log_id!(debug, log_id, "Failed to handle request: {}", e);
+        }
+    };
+
+    tokio::select! {
+        _ = dispatch => (),
+        _ = handle => (),
+    }
+
+    if let Err(e) = codec.graceful_shutdown().await {
+        log_id!(debug, log_id, "Failed to shutdown HTTP session: {}", e);
+    }
+}
+
+async fn handle_request(
+    settings: &HttpRedirectSettings,
+    https_port: u16,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    log_id!(
+        trace,
+        log_id,
+        "Got request: {} {}",
+        request.method,
+        request.uri
+    );
+
+    if let (Some(dir), Some(token)) = (
+        settings.acme_challenge_dir.as_ref(),
+        request.uri.path().strip_prefix(ACME_CHALLENGE_PATH),
+    ) {
+        if !is_valid_challenge_token(token) {
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::StatusCode::NOT_FOUND, vec![]);
+        }
+        return match tokio::fs::read(std::path::Path::new(dir).join(token)).await {
+            Ok(x) => send_challenge_response(stream, Bytes::from(x)).await,
+            Err(e) => {
+                log_id!(debug, log_id, "ACME challenge not found: {}: {}", token, e);
+                stream
+                    .split()
+                    .1
+                    .send_bad_response(http::StatusCode::NOT_FOUND, vec![])
+            }
+        };
+    }
+
+    let location = match make_location(request, https_port) {
+        Some(x) => x,
+        None => {
+            log_id!(debug, log_id, "No host in request: {:?}", request);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+    stream.split().1.send_bad_response(
+        http::StatusCode::MOVED_PERMANENTLY,
+        vec![
+            (http::header::LOCATION.to_string(), location),
+            (http::header::CONTENT_LENGTH.to_string(), "0".to_string()),
+        ],
+    )
+}
+
+/// Make the HTTPS URL of the requested resource
+pub(crate) fn make_location(
+    request: &http_codec::RequestHeaders,
+    https_port: u16,
+) -> Option<String> {
+    let authority = match request.uri.authority() {
+        Some(x) => x.clone(),
+        None => request
+            .headers
+            .get(http::header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<http::uri::Authority>()
+            .ok()?,
+    };
+
+    let path = request
+        .uri
+        .path_and_query()
+        .map(http::uri::PathAndQuery::as_str)
+        .unwrap_or("/");
+    Some(match https_port {
+        DEFAULT_HTTPS_PORT => format!("https://{}{}", authority.host(), path),
+        x => format!("https://{}:{}{}", authority.host(), x, path),
+    })
+}
+
+/// The challenge tokens consist of the base64url alphabet characters
+fn is_valid_challenge_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+}
+
+async fn send_challenge_response(
+    stream: Box<dyn http_codec::Stream>,
+    content: Bytes,
+) -> io::Result<()> {
+    let response = http::Response::builder()
+        .version(stream.request().request().version)
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::CONTENT_LENGTH, content.len())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+
+    let mut sink = stream
+        .split()
+        .1
+        .send_response(response, false)?
+        .into_pipe_sink();
+    sink.write_all(content).await?;
+    sink.eof()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_request(uri: &str, host: Option<&str>) -> http_codec::RequestHeaders {
+        let mut builder = http::Request::get(uri);
+        if let Some(x) = host {
+            builder = builder.header(http::header::HOST, x);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(
+            Some("https://example.org/a?b=c".to_string()),
+            make_location(&make_request("/a?b=c", Some("example.org:80")), 443)
+        );
+        assert_eq!(
+            Some("https://[::1]:8443/".to_string()),
+            make_location(&make_request("/", Some("[::1]")), 8443)
+        );
+        assert_eq!(
+            Some("https://example.org/x".to_string()),
+            make_location(&make_request("http://example.org/x", None), 443)
+        );
+        assert_eq!(None, make_location(&make_request("/", None), 443));
+    }
+
+    #[test]
+    fn challenge_tokens() {
+        assert!(is_valid_challenge_token(
+            "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"
+        ));
+        assert!(!is_valid_challenge_token(""));
+        assert!(!is_valid_challenge_token("../secret"));
+        assert!(!is_valid_challenge_token("a/b"));
+    }
+}
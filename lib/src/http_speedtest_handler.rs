@@ -1,6 +1,6 @@
 use crate::http_codec::HttpCodec;
 use crate::shutdown::Shutdown;
-use crate::{http_codec, log_id, log_utils, pipe};
+use crate::{http_codec, log_id, log_utils};
 use bytes::Bytes;
 use std::io::ErrorKind;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -225,13 +225,13 @@ async fn run_upload_test(stream: Box<dyn http_codec::Stream>, n: u32) {
     log_id!(trace, log_id, "Running upload test");
     let (request, respond) = stream.split();
 
-    let mut source = request.finalize();
+    let mut body = request.into_body();
     let mut n = n as usize;
     while n > 0 {
-        match source.read().await {
-            Ok(pipe::Data::Chunk(x)) => {
-                n = n.saturating_sub(x.len());
-                if let Err(e) = source.consume(x.len()) {
+        match body.read(n).await {
+            Ok(Some(x)) => {
+                n -= x.len();
+                if let Err(e) = body.release(x.len()) {
                     log_id!(
                         debug,
                         log_id,
@@ -242,7 +242,7 @@ async fn run_upload_test(stream: Box<dyn http_codec::Stream>, n: u32) {
                     return;
                 }
             }
-            Ok(pipe::Data::Eof) => {
+            Ok(None) => {
                 log_id!(
                     debug,
                     log_id,
@@ -0,0 +1,402 @@
+use crate::settings::ImpairmentSettings;
+use crate::tiers::RateLimiter;
+use crate::tls_demultiplexer::Protocol;
+use crate::{datagram_pipe, downstream, forwarder, log_utils, pipe};
+use async_trait::async_trait;
+use ring::rand::SecureRandom;
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// The number of the items read ahead of the impaired side of a pipe.
+/// Bounds the amount of data held by an impairment.
+const QUEUE_SIZE: usize = 32;
+
+/// Find the impairment applied to a tunnel of the client on the listener protocol
+pub(crate) fn find<'a>(
+    impairments: &'a [ImpairmentSettings],
+    protocol: Protocol,
+    username: Option<&str>,
+) -> Option<&'a ImpairmentSettings> {
+    impairments.iter().find(|x| {
+        (x.protocols.is_empty()
+            || x.protocols
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(protocol.as_str())))
+            && (x.usernames.is_empty()
+                || username.is_some_and(|u| x.usernames.iter().any(|x| x == u)))
+    })
+}
+
+/// Wrap the source of a tunneled connection to apply the impairment
+pub(crate) fn impair_stream(
+    source: Box<dyn pipe::Source>,
+    settings: Option<&ImpairmentSettings>,
+) -> Box<dyn pipe::Source> {
+    match settings {
+        None => source,
+        Some(settings) => {
+            let id = source.id();
+            Box::new(ImpairedSource {
+                queue: Queue::spawn(source, Impairment::new(settings)),
+                id,
+            })
+        }
+    }
+}
+
+/// Wrap the source of a datagram multiplexer to apply the impairment
+pub(crate) fn impair_datagrams<D: Impairable>(
+    source: Box<dyn datagram_pipe::Source<Output = D>>,
+    settings: Option<&ImpairmentSettings>,
+) -> Box<dyn datagram_pipe::Source<Output = D>> {
+    match settings {
+        None => source,
+        Some(settings) => {
+            let id = source.id();
+            Box::new(ImpairedDatagrams {
+                queue: Queue::spawn(source, Impairment::new(settings)),
+                id,
+            })
+        }
+    }
+}
+
+/// An item passing through an impaired pipe
+pub(crate) trait Impairable: Send + 'static {
+    /// The size of the item data, [`None`] if the item is a control one,
+    /// which is not delayed or lost on its own
+    fn size(&self) -> Option<usize>;
+}
+
+/// Decides the fate of the items passing through an impaired pipe
+struct Impairment {
+    delay: Duration,
+    jitter: Duration,
+    /// The probability of losing an item
+    loss: f64,
+    rate_limiter: Option<RateLimiter>,
+    /// The release time of the previous item, so that the items are not reordered
+    last_release: Instant,
+    random: ring::rand::SystemRandom,
+}
+
+/// A source of the items to be impaired
+#[async_trait]
+trait ItemSource: Send + 'static {
+    type Item: Impairable;
+
+    /// Whether the items must not be lost, like the chunks of a stream
+    const IS_RELIABLE: bool;
+
+    async fn next(&mut self) -> io::Result<Self::Item>;
+}
+
+/// The items read ahead by a background task along with their release times,
+/// so that the delays of the consecutive items overlap like on a real link
+struct Queue<T> {
+    rx: mpsc::Receiver<io::Result<(Instant, T)>>,
+    reader: JoinHandle<()>,
+}
+
+struct ImpairedSource {
+    queue: Queue<pipe::Data>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct ImpairedDatagrams<D> {
+    queue: Queue<D>,
+    id: log_utils::IdChain<u64>,
+}
+
+impl Impairment {
+    fn new(settings: &ImpairmentSettings) -> Self {
+        let now = Instant::now();
+        Self {
+            delay: settings.delay,
+            jitter: settings.jitter,
+            loss: settings.loss_percent / 100.0,
+            rate_limiter: settings.max_bytes_per_sec.map(|x| RateLimiter::new(x, now)),
+            last_release: now,
+            random: ring::rand::SystemRandom::new(),
+        }
+    }
+
+    /// Decide when the item of `size` bytes received at `now` is to be passed further.
+    /// [`None`] if the item is lost.
+    fn schedule(&mut self, size: usize, is_reliable: bool, now: Instant) -> Option<Instant> {
+        let mut hold = self.delay;
+        if !self.jitter.is_zero() {
+            let deviation = (2.0 * self.random_fraction() - 1.0) * self.jitter.as_secs_f64();
+            hold = Duration::from_secs_f64((hold.as_secs_f64() + deviation).max(0.0));
+        }
+
+        if self.loss > 0.0 && self.random_fraction() < self.loss {
+            if !is_reliable {
+                return None;
+            }
+            hold += ImpairmentSettings::RETRANSMISSION_DELAY;
+        }
+
+        if let Some(x) = self.rate_limiter.as_mut() {
+            hold += x.consume(size, now);
+        }
+
+        self.last_release = self.last_release.max(now + hold);
+        Some(self.last_release)
+    }
+
+    /// Get a uniformly distributed value in `[0, 1)`
+    fn random_fraction(&self) -> f64 {
+        let mut x = [0; 8];
+        self.random.fill(&mut x).unwrap();
+        (u64::from_be_bytes(x) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<T: Impairable> Queue<T> {
+    fn spawn<S: ItemSource<Item = T>>(source: S, impairment: Impairment) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            rx,
+            reader: tokio::spawn(read_ahead(source, impairment, tx)),
+        }
+    }
+
+    /// Get the next item once it is released
+    async fn next(&mut self) -> io::Result<T> {
+        let (release, item) = self
+            .rx
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Impaired source is closed"))??;
+        tokio::time::sleep_until(release).await;
+        Ok(item)
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_ahead<S: ItemSource>(
+    mut source: S,
+    mut impairment: Impairment,
+    tx: mpsc::Sender<io::Result<(Instant, S::Item)>>,
+) {
+    loop {
+        let item = match source.next().await {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                break;
+            }
+        };
+
+        let now = Instant::now();
+        let release = match item.size() {
+            // The control items must not overtake the data ones
+            None => impairment.last_release.max(now),
+            Some(size) => match impairment.schedule(size, S::IS_RELIABLE, now) {
+                Some(x) => x,
+                None => continue,
+            },
+        };
+        if tx.send(Ok((release, item))).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[async_trait]
+impl ItemSource for Box<dyn pipe::Source> {
+    type Item = pipe::Data;
+
+    const IS_RELIABLE: bool = true;
+
+    async fn next(&mut self) -> io::Result<pipe::Data> {
+        let data = self.read().await?;
+        // The queue size bounds the amount of the data consumed ahead
+        if let pipe::Data::Chunk(x) = &data {
+            self.consume(x.len())?;
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl<D: Impairable> ItemSource for Box<dyn datagram_pipe::Source<Output = D>> {
+    type Item = D;
+
+    const IS_RELIABLE: bool = false;
+
+    async fn next(&mut self) -> io::Result<D> {
+        self.read().await
+    }
+}
+
+#[async_trait]
+impl pipe::Source for ImpairedSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        self.queue.next().await
+    }
+
+    fn consume(&mut self, _size: usize) -> io::Result<()> {
+        // The wrapped source is consumed on reading ahead
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<D: Impairable> datagram_pipe::Source for ImpairedDatagrams<D> {
+    type Output = D;
+
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<D> {
+        self.queue.next().await
+    }
+}
+
+impl Impairable for pipe::Data {
+    fn size(&self) -> Option<usize> {
+        match self {
+            Self::Chunk(x) => Some(x.len()),
+            Self::Eof => None,
+        }
+    }
+}
+
+impl Impairable for downstream::UdpDatagram {
+    fn size(&self) -> Option<usize> {
+        Some(self.payload.len())
+    }
+}
+
+impl Impairable for forwarder::UdpDatagramReadStatus {
+    fn size(&self) -> Option<usize> {
+        match self {
+            Self::Read(x) => Some(x.payload.len()),
+            Self::UdpClose(..) => None,
+        }
+    }
+}
+
+impl Impairable for downstream::IcmpDatagram {
+    fn size(&self) -> Option<usize> {
+        Some(datagram_pipe::Datagram::len(self))
+    }
+}
+
+impl Impairable for forwarder::IcmpDatagram {
+    fn size(&self) -> Option<usize> {
+        Some(datagram_pipe::Datagram::len(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use bytes::Bytes;
+
+    #[test]
+    fn finds_first_matching() {
+        let impairments = vec![
+            ImpairmentSettings::builder()
+                .protocols(vec!["http3".into()])
+                .usernames(vec!["alice".into()])
+                .build()
+                .unwrap(),
+            ImpairmentSettings::builder()
+                .usernames(vec!["bob".into()])
+                .build()
+                .unwrap(),
+        ];
+
+        let found = |protocol, username| {
+            find(&impairments, protocol, username).map(|x| x as *const ImpairmentSettings)
+        };
+        assert_eq!(
+            Some(&impairments[0] as *const _),
+            found(Protocol::Http3, Some("alice"))
+        );
+        assert_eq!(None, found(Protocol::Http2, Some("alice")));
+        assert_eq!(
+            Some(&impairments[1] as *const _),
+            found(Protocol::Http1, Some("bob"))
+        );
+        assert_eq!(None, found(Protocol::Http3, None));
+    }
+
+    #[test]
+    fn schedules() {
+        let start = Instant::now();
+        let mut impairment = Impairment::new(
+            &ImpairmentSettings::builder()
+                .delay(Duration::from_millis(100))
+                .jitter(Duration::from_millis(50))
+                .build()
+                .unwrap(),
+        );
+        for i in 0..100 {
+            let now = start + Duration::from_secs(i);
+            let release = impairment.schedule(1000, false, now).unwrap();
+            assert!(release >= now + Duration::from_millis(50));
+            assert!(release <= now + Duration::from_millis(150));
+        }
+
+        // The items are not reordered
+        let now = start + Duration::from_secs(1000);
+        let first = impairment.schedule(1000, false, now).unwrap();
+        assert!(impairment.schedule(1000, false, now).unwrap() >= first);
+
+        let mut impairment = Impairment::new(
+            &ImpairmentSettings::builder()
+                .loss_percent(100.0)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(None, impairment.schedule(1000, false, start));
+        assert_eq!(
+            Some(start + ImpairmentSettings::RETRANSMISSION_DELAY),
+            impairment.schedule(1000, true, start)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_overlap() {
+        const DELAY: Duration = Duration::from_millis(100);
+        let (mut tx, rx) = sim::stream(1024);
+        let mut rx = impair_stream(
+            Box::new(rx),
+            Some(&ImpairmentSettings::builder().delay(DELAY).build().unwrap()),
+        );
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            pipe::Sink::write_all(&mut tx, Bytes::from_static(b"ping"))
+                .await
+                .unwrap();
+        }
+        pipe::Sink::eof(&mut tx).unwrap();
+
+        for _ in 0..3 {
+            assert!(matches!(rx.read().await.unwrap(), pipe::Data::Chunk(_)));
+            assert_eq!(DELAY, started.elapsed());
+        }
+        assert!(matches!(rx.read().await.unwrap(), pipe::Data::Eof));
+        assert_eq!(DELAY, started.elapsed());
+    }
+}
@@ -0,0 +1,95 @@
+//! The identity of an endpoint instance in its telemetry: a UUID generated on the first start
+//! and kept in the [state store](crate::state_store::StateStore), and the epoch, the number
+//! of the starts so far. The pair goes into the audit records, the metrics, the status file
+//! and the admin interface responses, so the records gathered from a fleet of endpoints
+//! are attributed to the instance and to the run of it they come from.
+//!
+//! With no state store configured, the ID is generated anew on every start and the epoch
+//! is always 0.
+
+use crate::state_store::StateStore;
+use crate::utils;
+use serde::Serialize;
+
+const NAMESPACE: &str = "instance";
+const ID_KEY: &str = "id";
+const EPOCH_KEY: &str = "epoch";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct Instance {
+    /// The UUID of the instance
+    pub id: String,
+    /// The number of the starts of the instance, including the current one
+    pub epoch: u64,
+}
+
+impl Instance {
+    /// Restore the identity from the `state_store` and count the current start in
+    pub fn new(state_store: Option<&StateStore>) -> Self {
+        let store = match state_store {
+            Some(x) => x,
+            None => {
+                return Self {
+                    id: generate_id(),
+                    epoch: 0,
+                }
+            }
+        };
+
+        let id = store.get(NAMESPACE, ID_KEY).unwrap_or_else(|| {
+            let id = generate_id();
+            store.set(NAMESPACE, ID_KEY, id.clone(), None);
+            id
+        });
+        let epoch = store.add_to_counter(NAMESPACE, EPOCH_KEY, 1);
+        // Do not wait for the periodic checkpoint, otherwise a crash soon after the start
+        // makes the next run reuse the epoch
+        if let Err(e) = store.checkpoint() {
+            warn!("Failed to checkpoint the instance epoch: {}", e);
+        }
+
+        Self { id, epoch }
+    }
+}
+
+/// Generate a random (version 4) UUID
+fn generate_id() -> String {
+    let mut x: [u8; 16] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .unwrap()
+        .expose();
+    x[6] = (x[6] & 0x0f) | 0x40;
+    x[8] = (x[8] & 0x3f) | 0x80;
+    format!(
+        "{}-{}-{}-{}-{}",
+        utils::hex_dump(&x[..4]),
+        utils::hex_dump(&x[4..6]),
+        utils::hex_dump(&x[6..8]),
+        utils::hex_dump(&x[8..10]),
+        utils::hex_dump(&x[10..]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_survives_restarts() {
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-instance-{}.db", std::process::id()));
+
+        let first = Instance::new(Some(&StateStore::open(&path).unwrap()));
+        assert_eq!(36, first.id.len());
+        assert_eq!(Some('4'), first.id.chars().nth(14));
+        assert_eq!(1, first.epoch);
+
+        let second = Instance::new(Some(&StateStore::open(&path).unwrap()));
+        assert_eq!(first.id, second.id);
+        assert_eq!(2, second.epoch);
+        let _ = std::fs::remove_file(&path);
+
+        let ephemeral = Instance::new(None);
+        assert_ne!(first.id, ephemeral.id);
+        assert_eq!(0, ephemeral.epoch);
+    }
+}
@@ -0,0 +1,419 @@
+//! The TLS interception of the tunneled connections to the explicitly configured destinations.
+//! The endpoint completes the TLS handshake with a client itself, presenting a certificate
+//! issued on the fly by the interception CA for the requested name, and opens its own TLS
+//! connection to the destination. The plaintext then goes through the same tunnel pipe as
+//! the regular connections do, including the host overrides of the rules engine.
+//! Every intercepted connection is recorded in the log, as the clients have to be aware
+//! of the interception and trust the CA.
+
+use crate::forwarder::TcpConnectionMeta;
+use crate::net_utils;
+use crate::net_utils::TcpDestination;
+use crate::settings::InterceptionSettings;
+use crate::upstream_tls::UpstreamTls;
+use crate::{authentication, log_id, log_utils, pipe, utils};
+use chrono::Datelike;
+use rustls::server::Acceptor;
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerName};
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use x509_parser::der_parser::asn1_rs::Tag;
+use x509_parser::extensions::ParsedExtension;
+
+/// The cache of the issued certificates is dropped as a whole once it grows this large
+const MAX_CACHED_CERTIFICATES: usize = 1024;
+/// The issued certificates are valid for a week starting from the day before the issuance,
+/// in case the clock of a client is behind
+const CERTIFICATE_VALIDITY_DAYS: i64 = 7;
+/// A cached certificate is issued anew after this period, so that it never expires in use
+const CERTIFICATE_REISSUE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+type Pipe = (Box<dyn pipe::Source>, Box<dyn pipe::Sink>);
+/// An issued certificate followed by the CA one
+type Chain = Arc<Vec<Certificate>>;
+
+pub(crate) struct Interceptor {
+    /// The lowercase destination patterns
+    destinations: Vec<String>,
+    ports: Vec<u16>,
+    /// The CA certificate as seen by the certificate generator
+    ca: rcgen::Certificate,
+    ca_key: rcgen::KeyPair,
+    /// The original CA certificate, sent along with the issued ones
+    ca_der: Certificate,
+    /// Whether the issued certificates refer to the CA key identifier
+    ca_has_key_id: bool,
+    /// The key shared by all the issued certificates
+    key: rcgen::KeyPair,
+    upstream: UpstreamTls,
+    /// The issued certificate chains keyed by the lowercase name
+    certificates: Mutex<HashMap<String, (Instant, Chain)>>,
+}
+
+impl Interceptor {
+    pub fn new(settings: &InterceptionSettings) -> io::Result<Self> {
+        let ca_der = utils::load_certs(&settings.ca_cert_path)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("No certificate in {}", settings.ca_cert_path),
+                )
+            })?;
+        let ca_key = utils::load_private_key(&settings.ca_key_path)?;
+        let ca_key = rcgen::KeyPair::try_from(ca_key.0.as_slice()).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported CA key in {}: {}", settings.ca_key_path, e),
+            )
+        })?;
+        let (ca, ca_has_key_id) = issuer(&ca_der, &ca_key)?;
+
+        Ok(Self {
+            destinations: settings
+                .destinations
+                .iter()
+                .map(|x| net_utils::canonicalize_host_pattern(x))
+                .collect(),
+            ports: settings.ports.clone(),
+            ca,
+            ca_key,
+            ca_der,
+            ca_has_key_id,
+            key: rcgen::KeyPair::generate().map_err(|e| io::Error::new(ErrorKind::Other, e))?,
+            upstream: UpstreamTls::new(&settings.upstream_tls, "intercepted destinations")?,
+            certificates: Default::default(),
+        })
+    }
+
+    /// Check the connections to the destination are intercepted
+    pub fn matches(&self, destination: &TcpDestination) -> bool {
+        let (host, port) = match destination {
+            TcpDestination::Address(x) => (x.ip().to_string(), x.port()),
+            TcpDestination::HostName((x, port)) => (normalize(x), *port),
+        };
+        self.ports.contains(&port) && self.destinations.iter().any(|x| matches(x, &host))
+    }
+
+    /// Terminate the TLS of the `client` connection and run a new TLS handshake over
+    /// the `destination` connection, with the name the client requested, or `server_name`
+    /// if it is set. The application protocol is the one the destination selects
+    /// among the ones the client offers.
+    /// Returns the plaintext pipes of the client and the destination connections.
+    pub async fn intercept(
+        &self,
+        id: log_utils::IdChain<u64>,
+        meta: &TcpConnectionMeta,
+        client: Pipe,
+        destination: Pipe,
+        server_name: Option<&str>,
+    ) -> io::Result<(Pipe, Pipe)> {
+        let handshake = tokio_rustls::LazyConfigAcceptor::new(
+            Acceptor::default(),
+            pipe::into_io(client.0, client.1),
+        )
+        .await?;
+        let hello = handshake.client_hello();
+        let sni = hello.server_name().map(normalize);
+        let alpn: Vec<Vec<u8>> = hello
+            .alpn()
+            .map(|x| x.map(<[u8]>::to_vec).collect())
+            .unwrap_or_default();
+
+        let requested = match (&sni, &meta.destination) {
+            (Some(x), _) => x.clone(),
+            (None, TcpDestination::Address(x)) => x.ip().to_string(),
+            (None, TcpDestination::HostName((x, _))) => normalize(x),
+        };
+        let upstream_name = server_name.unwrap_or(&requested);
+        let upstream_name = ServerName::try_from(upstream_name).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid server name {}: {}", upstream_name, e),
+            )
+        })?;
+        let destination = self
+            .upstream
+            .connect_with(
+                pipe::into_io(destination.0, destination.1),
+                upstream_name,
+                alpn,
+            )
+            .await?;
+        let protocol = destination.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                self.certificate(&requested)?.to_vec(),
+                PrivateKey(self.key.serialize_der()),
+            )
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        config.alpn_protocols = protocol.iter().cloned().collect();
+        let client = handshake.into_stream(Arc::new(config)).await?;
+
+        log_id!(
+            info,
+            id,
+            "Intercepted TLS connection: client={} identity={} destination={:?} sni={} alpn={}",
+            meta.client_address,
+            meta.auth
+                .as_ref()
+                .and_then(authentication::Source::username)
+                .as_deref()
+                .unwrap_or("-"),
+            meta.destination,
+            sni.as_deref().unwrap_or("-"),
+            protocol
+                .as_deref()
+                .map(String::from_utf8_lossy)
+                .as_deref()
+                .unwrap_or("-"),
+        );
+
+        Ok((
+            pipe::from_io(client, id.clone()),
+            pipe::from_io(destination, id),
+        ))
+    }
+
+    /// Get the certificate chain for the name, issuing a new certificate if needed
+    fn certificate(&self, name: &str) -> io::Result<Chain> {
+        let mut certificates = self.certificates.lock().unwrap();
+        if let Some((issued, chain)) = certificates.get(name) {
+            if issued.elapsed() < CERTIFICATE_REISSUE_PERIOD {
+                return Ok(chain.clone());
+            }
+        }
+
+        let chain = Arc::new(vec![self.issue(name)?, self.ca_der.clone()]);
+        if certificates.len() >= MAX_CACHED_CERTIFICATES {
+            certificates.clear();
+        }
+        certificates.insert(name.to_string(), (Instant::now(), chain.clone()));
+        Ok(chain)
+    }
+
+    fn issue(&self, name: &str) -> io::Result<Certificate> {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()])
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let today = chrono::Utc::now().date_naive();
+        let date =
+            |x: chrono::NaiveDate| rcgen::date_time_ymd(x.year(), x.month() as u8, x.day() as u8);
+        params.not_before = date(today - chrono::Duration::days(1));
+        params.not_after = date(today + chrono::Duration::days(CERTIFICATE_VALIDITY_DAYS));
+        let mut serial = [0; 16];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut serial)
+            .map_err(|_| io::Error::new(ErrorKind::Other, "Failed to generate serial number"))?;
+        // Keep the serial number positive
+        serial[0] &= 0x7f;
+        params.serial_number = Some(rcgen::SerialNumber::from_slice(&serial));
+        params.key_usages = vec![rcgen::KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = self.ca_has_key_id;
+
+        let certificate = params
+            .signed_by(&self.key, &self.ca, &self.ca_key)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        Ok(Certificate(certificate.der().to_vec()))
+    }
+}
+
+/// Check the destination pattern is either a name or `*.` followed by a domain
+pub(crate) fn is_valid_pattern(pattern: &str) -> bool {
+    let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+    !name.is_empty() && !name.contains('*')
+}
+
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        None => pattern == host,
+        Some(domain) => host
+            .strip_suffix(domain)
+            .and_then(|x| x.strip_suffix('.'))
+            .is_some_and(|x| !x.is_empty()),
+    }
+}
+
+fn normalize(host: &str) -> String {
+    net_utils::canonicalize_host_name(host)
+        .unwrap_or_else(|| host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Recreate the CA certificate to issue the certificates with.
+/// The subject name is reproduced exactly as the clients match it against the issuer name
+/// of the issued certificates byte for byte.
+/// Returns the certificate and whether the original one has the key identifier.
+fn issuer(
+    certificate: &Certificate,
+    key: &rcgen::KeyPair,
+) -> io::Result<(rcgen::Certificate, bool)> {
+    let invalid =
+        |e: String| io::Error::new(ErrorKind::InvalidData, format!("CA certificate: {}", e));
+    let (_, parsed) =
+        x509_parser::parse_x509_certificate(&certificate.0).map_err(|e| invalid(e.to_string()))?;
+
+    let mut params = rcgen::CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    for x in parsed.subject().iter_attributes() {
+        let oid = x
+            .attr_type()
+            .iter()
+            .ok_or_else(|| invalid(format!("Unsupported attribute type {}", x.attr_type())))?
+            .collect::<Vec<_>>();
+        let value = x.as_str().map_err(|e| invalid(e.to_string()))?.to_string();
+        let value = match x.attr_value().tag() {
+            Tag::PrintableString => rcgen::PrintableString::try_from(value)
+                .map(rcgen::DnValue::PrintableString)
+                .map_err(|e| invalid(e.to_string()))?,
+            Tag::Ia5String => rcgen::Ia5String::try_from(value)
+                .map(rcgen::DnValue::Ia5String)
+                .map_err(|e| invalid(e.to_string()))?,
+            _ => rcgen::DnValue::Utf8String(value),
+        };
+        params
+            .distinguished_name
+            .push(rcgen::DnType::from_oid(&oid), value);
+    }
+    let key_id = parsed
+        .extensions()
+        .iter()
+        .find_map(|x| match x.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(x) => Some(x.0.to_vec()),
+            _ => None,
+        });
+    let has_key_id = key_id.is_some();
+    if let Some(x) = key_id {
+        params.key_identifier_method = rcgen::KeyIdMethod::PreSpecified(x);
+    }
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+
+    let recreated = params
+        .self_signed(key)
+        .map_err(|e| invalid(e.to_string()))?;
+    let (_, x) =
+        x509_parser::parse_x509_certificate(recreated.der()).map_err(|e| invalid(e.to_string()))?;
+    if x.subject().as_raw() != parsed.subject().as_raw() {
+        return Err(invalid(format!(
+            "Unsupported subject name encoding: {}",
+            parsed.subject()
+        )));
+    }
+    if x.public_key().raw != parsed.public_key().raw {
+        return Err(invalid("Key does not match the certificate".into()));
+    }
+
+    Ok((recreated, has_key_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::UpstreamTlsSettings;
+    use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::SystemTime;
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches("example.org", "example.org"));
+        assert!(!matches("example.org", "www.example.org"));
+        assert!(matches("*.example.org", "www.example.org"));
+        assert!(matches("*.example.org", "a.b.example.org"));
+        assert!(!matches("*.example.org", "example.org"));
+        assert!(!matches("*.example.org", "badexample.org"));
+
+        assert!(is_valid_pattern("*.example.org"));
+        assert!(is_valid_pattern("192.0.2.1"));
+        for x in ["", "*.", "*", "www.*.org", "*example.org"] {
+            assert!(!is_valid_pattern(x), "{}", x);
+        }
+    }
+
+    #[test]
+    fn issues_trusted_certificates() {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(
+            rcgen::DnType::CountryName,
+            rcgen::DnValue::PrintableString("CY".try_into().unwrap()),
+        );
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "Interception CA");
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            rcgen::KeyUsagePurpose::KeyCertSign,
+            rcgen::KeyUsagePurpose::DigitalSignature,
+        ];
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!(
+            "trusttunnel-interception-{}.crt",
+            std::process::id()
+        ));
+        let key_path = dir.join(format!(
+            "trusttunnel-interception-{}.key",
+            std::process::id()
+        ));
+        std::fs::write(&cert_path, ca.pem()).unwrap();
+        std::fs::write(&key_path, ca_key.serialize_pem()).unwrap();
+        let settings =
+            InterceptionSettings::builder(cert_path.to_str().unwrap(), key_path.to_str().unwrap())
+                .destination("*.example.org")
+                .upstream_tls(
+                    UpstreamTlsSettings::builder()
+                        .insecure_skip_verify(true)
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap();
+        let interceptor = Interceptor::new(&settings).unwrap();
+        let _ = std::fs::remove_file(&cert_path);
+        let _ = std::fs::remove_file(&key_path);
+
+        let destination = |host: &str, port| TcpDestination::HostName((host.into(), port));
+        assert!(interceptor.matches(&destination("WWW.Example.org.", 443)));
+        assert!(!interceptor.matches(&destination("www.example.org", 80)));
+        assert!(
+            !interceptor.matches(&TcpDestination::Address(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                443
+            )))
+        );
+
+        let chain = interceptor.certificate("www.example.org").unwrap();
+        assert!(Arc::ptr_eq(
+            &chain,
+            &interceptor.certificate("www.example.org").unwrap()
+        ));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&Certificate(ca.der().to_vec())).unwrap();
+        let verifier = WebPkiVerifier::new(roots, None);
+        let verify = |name: &str| {
+            verifier.verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &ServerName::try_from(name).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify("www.example.org").is_ok());
+        assert!(verify("other.example.org").is_err());
+    }
+}
@@ -4,42 +4,102 @@ extern crate log;
 extern crate macros;
 
 pub mod authentication;
+/// The relay hot path workloads of the benchmarks in `bench/micro`
+#[cfg(any(test, feature = "bench"))]
+pub mod benchmarking;
 pub mod client_config;
 pub mod core;
+pub mod custom_forwarder;
+/// The fuzz target entry points, built under `--cfg fuzzing` by `cargo fuzz`
+#[cfg(any(test, fuzzing))]
+pub mod fuzzing;
 pub mod log_utils;
+pub mod metrics_sink;
 pub mod net_utils;
+pub mod pipe;
 pub mod rules;
 pub mod settings;
 pub mod shutdown;
+pub mod state_store;
+pub mod tls_info;
 pub mod utils;
 
+mod accept_rate;
+mod affinity;
+mod audit_log;
+mod auth_lockout;
+mod bandwidth;
+mod capacity;
+mod cert_expiry;
+mod connect_rate;
+mod connection_limits;
 mod datagram_pipe;
 mod direct_forwarder;
 mod downstream;
+mod egress;
+mod events;
+mod exit_policy;
 mod forwarder;
+mod grpc_admin;
+mod grpc_transport;
+mod hop_health;
+mod host_override;
+mod host_patterns;
 mod http1_codec;
 mod http2_codec;
 mod http3_codec;
 mod http_codec;
+mod http_connect_client;
+mod http_connect_udp_codec;
 mod http_datagram_codec;
 mod http_demultiplexer;
 mod http_downstream;
 mod http_forwarded_stream;
 mod http_icmp_codec;
 mod http_ping_handler;
+mod http_redirect;
 mod http_speedtest_handler;
 mod http_udp_codec;
 mod icmp_forwarder;
 mod icmp_utils;
+mod impairment;
+mod instance;
+mod interception;
+mod log_rotation;
 mod metrics;
-mod pipe;
+mod policy;
+mod port_blocks;
+mod profiles;
+mod proxy_protocol;
 mod quic_multiplexer;
+mod quotas;
+mod reconnect_tokens;
+mod request_mirror;
+mod response_cache;
 mod reverse_proxy;
+mod revocation;
+mod schedule;
+mod self_signed;
+mod server_timing;
+mod sessions;
+/// The in-memory transports of the tests, also driving the benchmark workloads
+#[cfg(any(test, feature = "bench"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod sim;
 mod socks5_client;
 mod socks5_forwarder;
+mod static_files;
+mod stats_history;
+mod statsd;
+mod status_report;
 mod tcp_forwarder;
+mod tiers;
 mod tls_demultiplexer;
 mod tls_listener;
+mod tls_triage;
 mod tunnel;
 mod udp_forwarder;
 mod udp_pipe;
+mod uplinks;
+mod upstream_tls;
+mod websocket;
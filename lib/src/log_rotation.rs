@@ -0,0 +1,300 @@
+//! The rotation of the log files the endpoint writes itself, so a long-running node does not
+//! need an external logrotate coordinated with it. A file is rotated at the start of each
+//! period and on reaching the size limit, whichever comes first: it is renamed after the time
+//! of the rotation, a new file is started in its place, and the rotated one is compressed,
+//! handed over to the rotation program, and the oldest rotated files beyond the limit are
+//! removed.
+
+use crate::settings::{LogCompression, LogRotationSettings, RotationPeriod};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+/// The time format in the names of the rotated files, which sorts in the rotation order
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+const GZIP_EXTENSION: &str = ".gz";
+
+/// A file the records are appended to, rotated according to the settings, if any
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    settings: Option<LogRotationSettings>,
+    file: tokio::fs::File,
+    /// The number of bytes in the current file
+    size: u64,
+    /// The time of the next scheduled rotation
+    deadline: Option<Instant>,
+}
+
+impl RotatingFile {
+    pub async fn open(path: &str, settings: Option<&LogRotationSettings>) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self {
+            deadline: settings.and_then(|x| x.period).map(next_rotation),
+            path,
+            settings: settings.cloned(),
+            file,
+            size,
+        })
+    }
+
+    /// Append the data, rotating the file if it has reached the size limit
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+        self.size += data.len() as u64;
+
+        let limit = self.settings.as_ref().and_then(|x| x.max_size_bytes);
+        if limit.is_some_and(|x| self.size >= x) {
+            self.rotate().await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the scheduled rotation to be due, forever if none is scheduled
+    pub async fn rotation_due(&self) {
+        match self.deadline {
+            Some(x) => tokio::time::sleep_until(x).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Rotate the file, unless it is empty
+    pub async fn rotate(&mut self) -> io::Result<()> {
+        let settings = match &self.settings {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        self.deadline = settings.period.map(next_rotation);
+        if self.size == 0 {
+            return Ok(());
+        }
+
+        let rotated = self.rotated_path();
+        tokio::fs::rename(&self.path, &rotated).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to rename {}: {}", self.path.display(), e),
+            )
+        })?;
+        self.file = open(&self.path).await?;
+        self.size = 0;
+
+        let rotated = match settings.compression {
+            LogCompression::None => rotated,
+            LogCompression::Gzip => tokio::task::spawn_blocking(move || compress(rotated)).await?,
+        };
+        if let Some(x) = &settings.on_rotate {
+            run_program(x, &rotated);
+        }
+        let (path, max_files) = (self.path.clone(), settings.max_files);
+        tokio::task::spawn_blocking(move || prune(&path, max_files)).await?;
+        Ok(())
+    }
+
+    /// Make a name for the rotated file, which is not taken by an earlier one
+    fn rotated_path(&self) -> PathBuf {
+        let mut time = chrono::Utc::now();
+        loop {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", time.format(TIMESTAMP_FORMAT)));
+            let path = PathBuf::from(name);
+            let compressed = with_extension(&path, GZIP_EXTENSION);
+            if !path.exists() && !compressed.exists() {
+                break path;
+            }
+            time += chrono::Duration::milliseconds(1);
+        }
+    }
+}
+
+async fn open(path: &Path) -> io::Result<tokio::fs::File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o640);
+    options.open(path).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to open {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Get the time of the start of the next period
+fn next_rotation(period: RotationPeriod) -> Instant {
+    let now = chrono::Utc::now().timestamp_millis();
+    Instant::now() + Duration::from_millis(till_next_period(now, period))
+}
+
+/// Get the number of milliseconds from the UNIX time till the start of the next period
+fn till_next_period(now_millis: i64, period: RotationPeriod) -> u64 {
+    let length = match period {
+        RotationPeriod::Hourly => 3600 * 1000,
+        RotationPeriod::Daily => 24 * 3600 * 1000,
+    };
+    (length - now_millis.rem_euclid(length)) as u64
+}
+
+/// Compress the rotated file, returning the path of the result.
+/// On failure, the file is left uncompressed.
+fn compress(path: PathBuf) -> PathBuf {
+    let compressed = with_extension(&path, GZIP_EXTENSION);
+    let partial = with_extension(&compressed, ".partial");
+    let result = (|| {
+        let mut source = std::fs::File::open(&path)?;
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&partial)?,
+            flate2::Compression::default(),
+        );
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        std::fs::rename(&partial, &compressed)?;
+        std::fs::remove_file(&path)
+    })();
+    match result {
+        Ok(()) => compressed,
+        Err(e) => {
+            warn!("Failed to compress {}: {}", path.display(), e);
+            let _ = std::fs::remove_file(&partial);
+            path
+        }
+    }
+}
+
+/// Start the rotation program, its failure is reported in the log
+fn run_program(program: &str, rotated: &Path) {
+    let mut child = match tokio::process::Command::new(program).arg(rotated).spawn() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to run {}: {}", program, e);
+            return;
+        }
+    };
+    let program = program.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(x) if x.success() => (),
+            Ok(x) => warn!("{} has failed: {}", program, x),
+            Err(e) => warn!("Failed to wait for {}: {}", program, e),
+        }
+    });
+}
+
+/// Remove the oldest rotated files beyond the limit
+fn prune(path: &Path, max_files: usize) {
+    let (directory, name) = match (path.parent(), path.file_name().and_then(|x| x.to_str())) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return,
+    };
+    let directory = match directory.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => directory,
+    };
+    let entries = match std::fs::read_dir(directory) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to list {}: {}", directory.display(), e);
+            return;
+        }
+    };
+
+    let mut rotated: Vec<String> = entries
+        .filter_map(|x| x.ok()?.file_name().into_string().ok())
+        .filter(|x| {
+            x.strip_prefix(name)
+                .and_then(|x| x.strip_prefix('.'))
+                .map(|x| x.strip_suffix(GZIP_EXTENSION).unwrap_or(x))
+                .is_some_and(|x| chrono::NaiveDateTime::parse_from_str(x, TIMESTAMP_FORMAT).is_ok())
+        })
+        .collect();
+    rotated.sort_unstable();
+    for x in rotated.iter().rev().skip(max_files) {
+        let path = directory.join(x);
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut x = path.as_os_str().to_owned();
+    x.push(extension);
+    PathBuf::from(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn periods() {
+        // 2024-03-15T10:30:00Z
+        let now = 1_710_498_600_000;
+        assert_eq!(
+            30 * 60 * 1000,
+            till_next_period(now, RotationPeriod::Hourly)
+        );
+        assert_eq!(
+            13 * 3600 * 1000 + 30 * 60 * 1000,
+            till_next_period(now, RotationPeriod::Daily)
+        );
+        assert_eq!(
+            24 * 3600 * 1000,
+            till_next_period(
+                now - 10 * 3600 * 1000 - 30 * 60 * 1000,
+                RotationPeriod::Daily
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn rotates_by_size() {
+        let directory =
+            std::env::temp_dir().join(format!("trusttunnel-rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("audit.log");
+        let settings = LogRotationSettings::builder()
+            .max_size_bytes(8)
+            .compression(LogCompression::Gzip)
+            .max_files(2)
+            .build()
+            .unwrap();
+        let mut file = RotatingFile::open(path.to_str().unwrap(), Some(&settings))
+            .await
+            .unwrap();
+
+        for x in [
+            "one\n", "two\n", "three\n", "four\n", "five\n", "six\n", "seven\n",
+        ] {
+            file.write(x.as_bytes()).await.unwrap();
+        }
+
+        let mut names: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let content = |name: &str| {
+            let mut x = String::new();
+            flate2::read::GzDecoder::new(std::fs::File::open(directory.join(name)).unwrap())
+                .read_to_string(&mut x)
+                .unwrap();
+            x
+        };
+        let rotated: Vec<String> = names.iter().skip(1).map(|x| content(x)).collect();
+        let current = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(3, names.len(), "{:?}", names);
+        assert_eq!("audit.log", names[0]);
+        assert!(names[1..].iter().all(|x| x.ends_with(GZIP_EXTENSION)));
+        // The oldest rotated file is removed
+        assert_eq!(vec!["three\nfour\n", "five\nsix\n"], rotated);
+        assert_eq!("seven\n", current);
+    }
+}
@@ -1,11 +1,31 @@
 use dynfmt::Format;
-use log::{Log, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
 use once_cell::sync::OnceCell;
+use std::cell::Cell;
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Write};
 use std::ops::DerefMut;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The module path prefix of the records emitted by the library
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+static LEVEL_OVERRIDES: RwLock<LevelOverrides> = RwLock::new(LevelOverrides::new());
+/// Lets the loggers skip locking [`LEVEL_OVERRIDES`] in the common case
+static HAS_LEVEL_OVERRIDES: AtomicBool = AtomicBool::new(false);
+
+static TRACE_RULES: RwLock<Vec<TraceRule>> = RwLock::new(Vec::new());
+/// Lets the tunnels skip locking [`TRACE_RULES`] in the common case
+static HAS_TRACE_RULES: AtomicBool = AtomicBool::new(false);
+static NEXT_TRACE_RULE_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Set while a record of a traced [`IdChain`] is being logged to bypass the level filter
+    static IS_TRACED_RECORD: Cell<bool> = const { Cell::new(false) };
+}
 
 /// Logs records in the standard output stream
 pub struct StdoutLogger;
@@ -18,6 +38,37 @@ pub struct FileLogger {
 /// Forces flushing buffered records to a destination while dropping
 pub struct LogFlushGuard;
 
+/// A log level applied to the records of a module instead of the global one
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleLevel {
+    /// The module path, e.g., `reverse_proxy` or `trusttunnel::reverse_proxy`.
+    /// It covers the nested modules too.
+    pub module: String,
+    pub level: LevelFilter,
+    /// [`None`] if the override is kept until reset
+    pub expires_at: Option<Instant>,
+}
+
+/// Makes the records of the matching tunnel requests logged regardless of the log level
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRule {
+    pub id: u64,
+    /// The username of the client
+    pub identity: Option<String>,
+    /// The destination host name or IP address.
+    /// A host name covers its subdomains too.
+    pub destination: Option<String>,
+    /// [`None`] if the rule is kept until removed
+    pub expires_at: Option<Instant>,
+}
+
+struct LevelOverrides {
+    /// The global level, [`None`] until it is set by [`set_level`] or captured
+    /// from [`log::max_level`] on the first override
+    base: Option<LevelFilter>,
+    modules: Vec<ModuleLevel>,
+}
+
 pub const fn make_stdout_logger() -> &'static impl Log {
     const LOGGER: StdoutLogger = StdoutLogger;
     &LOGGER
@@ -30,6 +81,148 @@ pub fn make_file_logger(path: &str) -> std::io::Result<&'static impl Log> {
     LOGGER.get_or_try_init(|| FileLogger::new(path))
 }
 
+/// Set the global log level.
+/// Unlike [`log::set_max_level`], keeps the module overrides in effect.
+pub fn set_level(level: LevelFilter) {
+    let mut overrides = LEVEL_OVERRIDES.write().unwrap();
+    overrides.base = Some(level);
+    overrides.apply(Instant::now());
+}
+
+/// Get the global log level
+pub fn level() -> LevelFilter {
+    LEVEL_OVERRIDES
+        .read()
+        .unwrap()
+        .base
+        .unwrap_or_else(log::max_level)
+}
+
+/// Override the log level of a module, optionally for the `duration` period only.
+/// Replaces the previous override of the module.
+pub fn set_module_level(module: &str, level: LevelFilter, duration: Option<Duration>) {
+    let now = Instant::now();
+    let mut overrides = LEVEL_OVERRIDES.write().unwrap();
+    overrides.set(module, level, duration.map(|x| now + x));
+    overrides.apply(now);
+}
+
+/// Drop the log level override of a module.
+/// Returns `false` if the module has no override.
+pub fn reset_module_level(module: &str) -> bool {
+    let mut overrides = LEVEL_OVERRIDES.write().unwrap();
+    let is_reset = overrides.reset(module);
+    overrides.apply(Instant::now());
+    is_reset
+}
+
+/// Get the active log level overrides
+pub fn module_levels() -> Vec<ModuleLevel> {
+    let mut overrides = LEVEL_OVERRIDES.write().unwrap();
+    overrides.apply(Instant::now());
+    overrides.modules.clone()
+}
+
+/// Drop the expired log level overrides and trace rules once the `duration` period passes
+pub(crate) fn schedule_expiration(duration: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let now = Instant::now();
+        LEVEL_OVERRIDES.write().unwrap().apply(now);
+        prune_trace_rules(&mut TRACE_RULES.write().unwrap(), now);
+    });
+}
+
+/// Trace the tunnel requests of the client `identity` or to the `destination` host,
+/// optionally for the `duration` period only.
+/// Returns the rule identifier to remove it with.
+pub fn add_trace_rule(
+    identity: Option<String>,
+    destination: Option<String>,
+    duration: Option<Duration>,
+) -> u64 {
+    let now = Instant::now();
+    let id = NEXT_TRACE_RULE_ID.fetch_add(1, Ordering::Relaxed);
+    let mut rules = TRACE_RULES.write().unwrap();
+    rules.push(TraceRule {
+        id,
+        identity,
+        destination,
+        expires_at: duration.map(|x| now + x),
+    });
+    prune_trace_rules(&mut rules, now);
+    id
+}
+
+/// Remove a trace rule.
+/// Returns `false` if there is no rule with the identifier.
+pub fn remove_trace_rule(id: u64) -> bool {
+    let mut rules = TRACE_RULES.write().unwrap();
+    let len = rules.len();
+    rules.retain(|x| x.id != id);
+    let is_removed = len != rules.len();
+    prune_trace_rules(&mut rules, Instant::now());
+    is_removed
+}
+
+/// Get the active trace rules
+pub fn trace_rules() -> Vec<TraceRule> {
+    let mut rules = TRACE_RULES.write().unwrap();
+    prune_trace_rules(&mut rules, Instant::now());
+    rules.clone()
+}
+
+/// Check whether the tunnel request of the client `identity` to the `destination` host
+/// matches any trace rule.
+/// The requests with no single destination, like the UDP ones, match the identity rules only.
+pub(crate) fn is_trace_requested(identity: Option<&str>, destination: Option<&str>) -> bool {
+    if !HAS_TRACE_RULES.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let now = Instant::now();
+    TRACE_RULES
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|x| x.expires_at.is_none_or(|x| x > now))
+        .any(|x| x.matches(identity, destination))
+}
+
+fn prune_trace_rules(rules: &mut Vec<TraceRule>, now: Instant) {
+    rules.retain(|x| x.expires_at.is_none_or(|x| x > now));
+    HAS_TRACE_RULES.store(!rules.is_empty(), Ordering::Relaxed);
+}
+
+/// Log a record of a traced [`IdChain`] regardless of the log level
+#[doc(hidden)]
+pub fn log_traced(level: log::Level, target: &str, args: std::fmt::Arguments<'_>) {
+    IS_TRACED_RECORD.with(|x| x.set(true));
+    log::logger().log(
+        &Record::builder()
+            .level(level)
+            .target(target)
+            .args(args)
+            .build(),
+    );
+    IS_TRACED_RECORD.with(|x| x.set(false));
+}
+
+fn is_enabled(metadata: &Metadata) -> bool {
+    if IS_TRACED_RECORD.with(Cell::get) {
+        return true;
+    }
+    if !HAS_LEVEL_OVERRIDES.load(Ordering::Relaxed) {
+        return metadata.level() <= log::max_level();
+    }
+
+    metadata.level()
+        <= LEVEL_OVERRIDES
+            .read()
+            .unwrap()
+            .level_of(metadata.target(), Instant::now())
+}
+
 fn write_record(mut w: impl Write, record: &Record) -> std::io::Result<()> {
     writeln!(
         w,
@@ -44,7 +237,7 @@ fn write_record(mut w: impl Write, record: &Record) -> std::io::Result<()> {
 
 impl Log for StdoutLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        is_enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -72,7 +265,7 @@ impl FileLogger {
 
 impl Log for FileLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        is_enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -96,6 +289,79 @@ impl Drop for FileLogger {
     }
 }
 
+impl LevelOverrides {
+    const fn new() -> Self {
+        Self {
+            base: None,
+            modules: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, module: &str, level: LevelFilter, expires_at: Option<Instant>) {
+        if self.base.is_none() {
+            self.base = Some(log::max_level());
+        }
+        self.reset(module);
+        self.modules.push(ModuleLevel {
+            module: module.to_string(),
+            level,
+            expires_at,
+        });
+    }
+
+    fn reset(&mut self, module: &str) -> bool {
+        let len = self.modules.len();
+        self.modules.retain(|x| x.module != module);
+        len != self.modules.len()
+    }
+
+    /// Drop the expired overrides and let through the records of the most verbose level in effect
+    fn apply(&mut self, now: Instant) {
+        self.modules
+            .retain(|x| x.expires_at.is_none_or(|x| x > now));
+        HAS_LEVEL_OVERRIDES.store(!self.modules.is_empty(), Ordering::Relaxed);
+        if let Some(base) = self.base {
+            log::set_max_level(self.modules.iter().map(|x| x.level).fold(base, Ord::max));
+        }
+    }
+
+    /// Get the level of the records of a module.
+    /// The most specific override matching the module wins.
+    fn level_of(&self, target: &str, now: Instant) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|x| x.expires_at.is_none_or(|x| x > now))
+            .filter(|x| module_matches(&x.module, target))
+            .max_by_key(|x| x.module.len())
+            .map(|x| x.level)
+            .or(self.base)
+            .unwrap_or_else(log::max_level)
+    }
+}
+
+/// Check whether `target` is `module` or a module nested into it.
+/// The library module paths may be specified without the crate name.
+fn module_matches(module: &str, target: &str) -> bool {
+    let is_nested = |target: &str| {
+        target
+            .strip_prefix(module)
+            .is_some_and(|x| x.is_empty() || x.starts_with("::"))
+    };
+
+    is_nested(target) || target.strip_prefix(CRATE_PREFIX).is_some_and(is_nested)
+}
+
+impl TraceRule {
+    fn matches(&self, identity: Option<&str>, destination: Option<&str>) -> bool {
+        self.identity.as_deref().is_none_or(|x| identity == Some(x))
+            && self.destination.as_deref().is_none_or(|x| {
+                destination
+                    .and_then(|d| d.strip_suffix(x))
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+            })
+    }
+}
+
 impl Drop for LogFlushGuard {
     fn drop(&mut self) {
         log::logger().flush()
@@ -105,10 +371,39 @@ impl Drop for LogFlushGuard {
 #[macro_export]
 macro_rules! log_id {
     ($lvl:ident, $id_chain:expr, $msg:expr) => {
-        $lvl!(std::concat!("[{}] ", $msg), $id_chain)
+        $crate::log_id!($lvl, $id_chain, $msg,)
+    };
+    ($lvl:ident, $id_chain:expr, $fmt:expr, $($arg:tt)*) => {{
+        let id_chain = &$id_chain;
+        if id_chain.is_traced() {
+            $crate::log_utils::log_traced(
+                $crate::__log_id_level!($lvl),
+                std::module_path!(),
+                std::format_args!(std::concat!("[{}] ", $fmt), id_chain, $($arg)*),
+            )
+        } else {
+            $lvl!(std::concat!("[{}] ", $fmt), id_chain, $($arg)*)
+        }
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_id_level {
+    (error) => {
+        log::Level::Error
+    };
+    (warn) => {
+        log::Level::Warn
+    };
+    (info) => {
+        log::Level::Info
     };
-    ($lvl:ident, $id_chain:expr, $fmt:expr, $($arg:tt)*) => {
-        $lvl!(std::concat!("[{}] ", $fmt), $id_chain, $($arg)*)
+    (debug) => {
+        log::Level::Debug
+    };
+    (trace) => {
+        log::Level::Trace
     };
 }
 
@@ -125,6 +420,8 @@ pub struct IdItem<T: Copy + serde::ser::Serialize> {
 #[derive(Clone)]
 pub struct IdChain<T: Copy + serde::ser::Serialize> {
     list: Vec<IdItem<T>>,
+    /// Whether the records are logged regardless of the log level, see [`add_trace_rule`]
+    traced: bool,
 }
 
 impl<T: Copy + serde::ser::Serialize> IdItem<T> {
@@ -137,6 +434,7 @@ impl<T: Copy + serde::ser::Serialize> IdChain<T> {
     pub fn empty() -> Self {
         Self {
             list: Default::default(),
+            traced: false,
         }
     }
 
@@ -144,19 +442,35 @@ impl<T: Copy + serde::ser::Serialize> IdChain<T> {
         let mut x = Self::with_capacity(self.list.len() + 1);
         x.list.extend(self.list.iter());
         x.list.push(new);
+        x.traced = self.traced;
         x
     }
 
+    /// Make the records of this chain and the chains extended from it logged
+    /// regardless of the log level
+    pub fn traced(mut self) -> Self {
+        self.traced = true;
+        self
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.traced
+    }
+
     fn with_capacity(cap: usize) -> Self {
         Self {
             list: Vec::with_capacity(cap),
+            traced: false,
         }
     }
 }
 
 impl<T: Copy + serde::ser::Serialize> From<IdItem<T>> for IdChain<T> {
     fn from(x: IdItem<T>) -> Self {
-        Self { list: vec![x] }
+        Self {
+            list: vec![x],
+            traced: false,
+        }
     }
 }
 
@@ -179,7 +493,9 @@ impl<T: Copy + serde::ser::Serialize> Display for IdChain<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::log_utils::{IdChain, IdItem};
+    use crate::log_utils::{module_matches, IdChain, IdItem, LevelOverrides, TraceRule};
+    use log::LevelFilter;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test() {
@@ -189,4 +505,94 @@ mod tests {
         chain = chain.extended(IdItem::new("ok {}", 73));
         assert_eq!("hello 42/ok 73", format!("{}", chain));
     }
+
+    #[test]
+    fn module_paths() {
+        assert!(module_matches(
+            "reverse_proxy",
+            "trusttunnel::reverse_proxy"
+        ));
+        assert!(module_matches(
+            "trusttunnel::reverse_proxy",
+            "trusttunnel::reverse_proxy"
+        ));
+        assert!(module_matches(
+            "authentication",
+            "trusttunnel::authentication::file_based"
+        ));
+        assert!(module_matches("quiche", "quiche::recovery"));
+        assert!(!module_matches("tunnel", "trusttunnel::tunnel_x"));
+        assert!(!module_matches("proxy", "trusttunnel::reverse_proxy"));
+    }
+
+    #[test]
+    fn module_levels() {
+        let now = Instant::now();
+        let mut overrides = LevelOverrides::new();
+        overrides.base = Some(LevelFilter::Info);
+        overrides.set("authentication", LevelFilter::Debug, None);
+        overrides.set(
+            "authentication::file_based",
+            LevelFilter::Trace,
+            Some(now + Duration::from_secs(60)),
+        );
+
+        assert_eq!(
+            LevelFilter::Info,
+            overrides.level_of("trusttunnel::tunnel", now)
+        );
+        assert_eq!(
+            LevelFilter::Debug,
+            overrides.level_of("trusttunnel::authentication::registry_based", now)
+        );
+        assert_eq!(
+            LevelFilter::Trace,
+            overrides.level_of("trusttunnel::authentication::file_based", now)
+        );
+        assert_eq!(
+            LevelFilter::Debug,
+            overrides.level_of(
+                "trusttunnel::authentication::file_based",
+                now + Duration::from_secs(60)
+            )
+        );
+
+        overrides.set("authentication", LevelFilter::Warn, None);
+        assert_eq!(
+            1,
+            overrides
+                .modules
+                .iter()
+                .filter(|x| x.module == "authentication")
+                .count()
+        );
+        assert!(overrides.reset("authentication"));
+        assert!(!overrides.reset("authentication"));
+    }
+
+    #[test]
+    fn trace_rules() {
+        let rule = |identity: Option<&str>, destination: Option<&str>| TraceRule {
+            id: 1,
+            identity: identity.map(str::to_string),
+            destination: destination.map(str::to_string),
+            expires_at: None,
+        };
+
+        assert!(rule(Some("alice"), None).matches(Some("alice"), Some("example.org")));
+        assert!(!rule(Some("alice"), None).matches(Some("bob"), Some("example.org")));
+        assert!(!rule(Some("alice"), None).matches(None, Some("example.org")));
+        assert!(rule(None, Some("example.org")).matches(None, Some("example.org")));
+        assert!(!rule(None, Some("example.org")).matches(None, None));
+        assert!(rule(Some("alice"), None).matches(Some("alice"), None));
+        assert!(rule(None, Some("example.org")).matches(None, Some("www.example.org")));
+        assert!(!rule(None, Some("example.org")).matches(None, Some("badexample.org")));
+        assert!(
+            !rule(Some("alice"), Some("example.org")).matches(Some("alice"), Some("example.com"))
+        );
+        assert!(rule(None, Some("10.0.0.1")).matches(None, Some("10.0.0.1")));
+
+        let chain = IdChain::from(IdItem::new("a {}", 1)).traced();
+        assert!(chain.extended(IdItem::new("b {}", 2)).is_traced());
+    }
 }
@@ -1,26 +1,279 @@
+use crate::accept_rate::Refusal;
+use crate::authentication::credentials_store::{ClientEntry, CredentialsStoreError};
+use crate::authentication::Authenticator;
+use crate::capacity::Category;
+use crate::core::RebalanceOrder;
 use crate::http1_codec::Http1Codec;
 use crate::http_codec::HttpCodec;
+use crate::instance::Instance;
+use crate::metrics_sink::{MetricDesc, MetricKind, MetricsSink, PrometheusSink, Subsystem};
+use crate::reverse_proxy::CloseReason;
+use crate::revocation::{Credential, RevocationChange};
+use crate::rules::{
+    RouteRule, Rule, RuleAction, RuleList, RulesChange, RulesEngine, RulesUpdateError,
+};
+use crate::stats_history::StatsHistory;
 use crate::tls_demultiplexer::Protocol;
-use crate::{core, http_codec, log_id, log_utils};
+use crate::tls_info::TlsInfo;
+use crate::{
+    capacity, core, http_codec, log_id, log_utils, revocation, schedule, sessions, static_files,
+    stats_history,
+};
 use bytes::Bytes;
 use prometheus::Encoder;
+use std::fmt::Write;
 use std::io;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
 
 const LOG_FMT: &str = "METRICS={}";
 const HEALTH_CHECK_PATH: &str = "/health-check";
 const METRICS_PATH: &str = "/metrics";
+const REBALANCE_PATH: &str = "/sessions/rebalance";
+const SESSIONS_PATH: &str = "/sessions";
+const STATS_PATH: &str = "/stats";
+const EVENTS_PATH: &str = "/events";
+const CACHE_PURGE_PATH: &str = "/cache/purge";
+const AUTH_INVALIDATE_PATH: &str = "/auth/invalidate";
+const CREDENTIALS_PATH: &str = "/credentials";
+const REVOCATIONS_PATH: &str = "/revocations";
+const LOG_LEVELS_PATH: &str = "/log-levels";
+const TRACE_RULES_PATH: &str = "/trace-rules";
+const MAINTENANCE_PATH: &str = "/maintenance";
+const SCHEDULE_PATH: &str = "/schedule";
+const RULES_PATH: &str = "/rules";
+const RULES_EXPLAIN_PATH: &str = "/rules/explain";
+static INSTANCE_ID_HEADER: http::HeaderName = http::HeaderName::from_static("x-instance-id");
+static INSTANCE_EPOCH_HEADER: http::HeaderName = http::HeaderName::from_static("x-instance-epoch");
+/// The period of sending comments to the event stream subscribers to detect dead connections
+const EVENTS_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// The limit of the form carried in the body of an administration request
+const MAX_FORM_BODY_SIZE: usize = 64 * 1024;
+
+pub(crate) const CLIENT_SESSIONS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "client_sessions",
+    help: "Number of active client sessions",
+    kind: MetricKind::Gauge,
+    labels: &["protocol_type"],
+};
+pub(crate) const CLIENT_SESSIONS_TOTAL: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "client_sessions_total",
+    help: "Total number of client sessions",
+    kind: MetricKind::Counter,
+    labels: &[],
+};
+pub(crate) const FAILED_TUNNEL_REQUESTS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "failed_tunnel_requests",
+    help: "Total number of rejected or failed tunnel requests",
+    kind: MetricKind::Counter,
+    labels: &[],
+};
+pub(crate) const CERTIFICATE_EXPIRY: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "certificate_expiry_timestamp_seconds",
+    help: "Expiration time of the loaded certificate as a UNIX timestamp",
+    kind: MetricKind::Gauge,
+    labels: &["role", "hostname"],
+};
+pub(crate) const INSTANCE_INFO: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "instance_info",
+    help: "Identity of the endpoint instance, the value is always 1",
+    kind: MetricKind::Gauge,
+    labels: &["instance_id", "epoch"],
+};
+pub(crate) const CREDENTIAL_STORE_UP: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "credential_store_up",
+    help: "Whether the client credentials store is usable",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+pub(crate) const TLS_HANDSHAKES: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "tls_handshakes_total",
+    help: "Total number of completed TLS handshakes of client tunnels",
+    kind: MetricKind::Counter,
+    labels: &["tls_version", "cipher_suite", "alpn"],
+};
+pub(crate) const RATE_LIMITED_CONNECTIONS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "rate_limited_connections_total",
+    help: "Total number of client connections dropped by the accept rate limit",
+    kind: MetricKind::Counter,
+    labels: &["reason"],
+};
+pub(crate) const CAPACITY_CPU_TIME: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "capacity_cpu_microseconds_total",
+    help: "Total CPU time attributed to the subsystem category",
+    kind: MetricKind::Counter,
+    labels: &["category"],
+};
+pub(crate) const CAPACITY_BUFFER_BYTES: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Core,
+    name: "capacity_buffer_bytes",
+    help: "Size of the buffers held by the subsystem category",
+    kind: MetricKind::Gauge,
+    labels: &["category"],
+};
+pub(crate) const REVERSE_PROXY_REQUESTS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "reverse_proxy_requests_total",
+    help: "Total number of reverse proxied requests by the way they ended",
+    kind: MetricKind::Counter,
+    labels: &["close_reason"],
+};
+pub(crate) const EGRESS_UPLINK_SCORE: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "egress_uplink_score_milliseconds",
+    help: "Moving average of the connection times over the egress uplink",
+    kind: MetricKind::Gauge,
+    labels: &["uplink"],
+};
+pub(crate) const EGRESS_UPLINK_WINS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "egress_uplink_wins_total",
+    help: "Total number of raced connections established over the egress uplink",
+    kind: MetricKind::Counter,
+    labels: &["uplink"],
+};
+pub(crate) const INBOUND_TRAFFIC: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Pipe,
+    name: "inbound_traffic_bytes",
+    help: "Total number of bytes uploaded by clients",
+    kind: MetricKind::Counter,
+    labels: &["protocol_type"],
+};
+pub(crate) const OUTBOUND_TRAFFIC: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Pipe,
+    name: "outbound_traffic_bytes",
+    help: "Total number of bytes downloaded by clients",
+    kind: MetricKind::Counter,
+    labels: &["protocol_type"],
+};
+pub(crate) const OUTBOUND_TCP_SOCKETS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "outbound_tcp_sockets",
+    help: "Number of active outbound TCP connections",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+pub(crate) const OUTBOUND_UDP_SOCKETS: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "outbound_udp_sockets",
+    help: "Number of active outbound UDP sockets",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+pub(crate) const UPSTREAM_HOP_UP: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "upstream_hop_up",
+    help: "Whether the upstream hop accepted the last connection attempt",
+    kind: MetricKind::Gauge,
+    labels: &["hop"],
+};
+pub(crate) const UPSTREAM_HOP_CONNECT_TIME: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Forwarder,
+    name: "upstream_hop_connect_seconds",
+    help: "Time of establishing a connection to the upstream hop",
+    kind: MetricKind::Histogram {
+        buckets: &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+    },
+    labels: &["hop"],
+};
+pub(crate) const QUIC_CONNECTION_RTT: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Codec,
+    name: "quic_connection_rtt_seconds",
+    help: "Smoothed round trip time of the closed QUIC connections",
+    kind: MetricKind::Histogram {
+        buckets: &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+    },
+    labels: &["congestion_control"],
+};
+pub(crate) const QUIC_CONNECTION_DELIVERY_RATE: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Codec,
+    name: "quic_connection_delivery_rate_bytes",
+    help: "Estimated delivery rate of the closed QUIC connections in bytes per second",
+    // 16 KiB times the powers of 4
+    kind: MetricKind::Histogram {
+        buckets: &[
+            16384.0,
+            65536.0,
+            262144.0,
+            1048576.0,
+            4194304.0,
+            16777216.0,
+            67108864.0,
+            268435456.0,
+        ],
+    },
+    labels: &["congestion_control"],
+};
+pub(crate) const QUIC_SENT_BYTES: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Codec,
+    name: "quic_sent_bytes",
+    help: "Total number of bytes sent over the closed QUIC connections",
+    kind: MetricKind::Counter,
+    labels: &["congestion_control"],
+};
+pub(crate) const QUIC_LOST_BYTES: MetricDesc = MetricDesc {
+    subsystem: Subsystem::Codec,
+    name: "quic_lost_bytes",
+    help: "Total number of bytes lost on the closed QUIC connections",
+    kind: MetricKind::Counter,
+    labels: &["congestion_control"],
+};
+
+/// The metrics of the endpoint in the order of registration
+const ALL_METRICS: [&MetricDesc; 23] = [
+    &CLIENT_SESSIONS,
+    &CLIENT_SESSIONS_TOTAL,
+    &FAILED_TUNNEL_REQUESTS,
+    &CERTIFICATE_EXPIRY,
+    &INSTANCE_INFO,
+    &CREDENTIAL_STORE_UP,
+    &TLS_HANDSHAKES,
+    &RATE_LIMITED_CONNECTIONS,
+    &CAPACITY_CPU_TIME,
+    &CAPACITY_BUFFER_BYTES,
+    &REVERSE_PROXY_REQUESTS,
+    &EGRESS_UPLINK_SCORE,
+    &EGRESS_UPLINK_WINS,
+    &INBOUND_TRAFFIC,
+    &OUTBOUND_TRAFFIC,
+    &OUTBOUND_TCP_SOCKETS,
+    &OUTBOUND_UDP_SOCKETS,
+    &UPSTREAM_HOP_UP,
+    &UPSTREAM_HOP_CONNECT_TIME,
+    &QUIC_CONNECTION_RTT,
+    &QUIC_CONNECTION_DELIVERY_RATE,
+    &QUIC_SENT_BYTES,
+    &QUIC_LOST_BYTES,
+];
 
 pub(crate) struct Metrics {
-    _registry: prometheus::Registry,
-    client_sessions: prometheus::IntGaugeVec,
-    inbound_traffic: prometheus::IntCounterVec,
-    outbound_traffic: prometheus::IntCounterVec,
-    outbound_tcp_sockets: prometheus::IntGauge,
-    outbound_udp_sockets: prometheus::IntGauge,
+    prometheus: PrometheusSink,
+    /// The sinks of the embedder the updates are reported to as well
+    sinks: Vec<Arc<dyn MetricsSink>>,
+}
+
+/// The current values of the metrics summed up across the labels
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MetricsSnapshot {
+    pub active_sessions: i64,
+    pub total_sessions: u64,
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+    pub failed_requests: u64,
 }
 
 pub(crate) struct ClientSessionsCounter {
@@ -38,45 +291,20 @@ pub(crate) struct OutboundUdpSocketCounter {
 
 impl Metrics {
     pub fn new() -> io::Result<Arc<Self>> {
-        let registry = prometheus::Registry::new();
         Ok(Arc::new(Self {
-            client_sessions: prometheus::register_int_gauge_vec_with_registry!(
-                "client_sessions",
-                "Number of active client sessions",
-                &["protocol_type"],
-                registry,
-            )
-            .map_err(prometheus_to_io_error)?,
-            inbound_traffic: prometheus::register_int_counter_vec_with_registry!(
-                "inbound_traffic_bytes",
-                "Total number of bytes uploaded by clients",
-                &["protocol_type"],
-                registry,
-            )
-            .map_err(prometheus_to_io_error)?,
-            outbound_traffic: prometheus::register_int_counter_vec_with_registry!(
-                "outbound_traffic_bytes",
-                "Total number of bytes downloaded by clients",
-                &["protocol_type"],
-                registry,
-            )
-            .map_err(prometheus_to_io_error)?,
-            outbound_tcp_sockets: prometheus::register_int_gauge_with_registry!(
-                "outbound_tcp_sockets",
-                "Number of active outbound TCP connections",
-                registry,
-            )
-            .map_err(prometheus_to_io_error)?,
-            outbound_udp_sockets: prometheus::register_int_gauge_with_registry!(
-                "outbound_udp_sockets",
-                "Number of active outbound UDP sockets",
-                registry,
-            )
-            .map_err(prometheus_to_io_error)?,
-            _registry: registry,
+            prometheus: PrometheusSink::new(&ALL_METRICS).map_err(prometheus_to_io_error)?,
+            sinks: vec![],
         }))
     }
 
+    /// Report the metric updates to the `sink` as well
+    pub fn add_sink(&mut self, sink: Arc<dyn MetricsSink>) {
+        for x in ALL_METRICS {
+            sink.register(x);
+        }
+        self.sinks.push(sink);
+    }
+
     pub fn client_sessions_counter(self: Arc<Self>, protocol: Protocol) -> ClientSessionsCounter {
         ClientSessionsCounter::new(self, protocol)
     }
@@ -90,34 +318,170 @@ impl Metrics {
     }
 
     pub fn add_inbound_bytes(&self, protocol: Protocol, n: usize) {
-        self.inbound_traffic
-            .with_label_values(&[protocol.as_str()])
-            .inc_by(n as u64);
+        self.report(|x| x.add_counter(&INBOUND_TRAFFIC, &[protocol.as_str()], n as u64));
     }
 
     pub fn add_outbound_bytes(&self, protocol: Protocol, n: usize) {
-        self.outbound_traffic
-            .with_label_values(&[protocol.as_str()])
-            .inc_by(n as u64);
+        self.report(|x| x.add_counter(&OUTBOUND_TRAFFIC, &[protocol.as_str()], n as u64));
+    }
+
+    pub fn add_failed_request(&self) {
+        self.report(|x| x.add_counter(&FAILED_TUNNEL_REQUESTS, &[], 1));
+    }
+
+    /// Account a completed client TLS handshake
+    pub fn add_tls_handshake(&self, tls: &TlsInfo) {
+        let labels = [
+            tls.version.as_deref().unwrap_or_default(),
+            tls.cipher_suite.as_deref().unwrap_or_default(),
+            tls.alpn.as_deref().unwrap_or_default(),
+        ];
+        self.report(|x| x.add_counter(&TLS_HANDSHAKES, &labels, 1));
+    }
+
+    /// Account a client connection dropped by the accept rate limit
+    pub fn add_rate_limited_connection(&self, refusal: Refusal) {
+        self.report(|x| x.add_counter(&RATE_LIMITED_CONNECTIONS, &[refusal.as_str()], 1));
+    }
+
+    /// Account the CPU time attributed to a subsystem category
+    pub fn add_capacity_cpu_time(&self, category: Category, time: Duration) {
+        let micros = time.as_micros() as u64;
+        self.report(|x| x.add_counter(&CAPACITY_CPU_TIME, &[category.as_str()], micros));
+    }
+
+    /// Account the size of the buffers held by a subsystem category
+    pub fn set_capacity_buffer_bytes(&self, category: Category, bytes: i64) {
+        self.report(|x| x.set_gauge(&CAPACITY_BUFFER_BYTES, &[category.as_str()], bytes));
+    }
+
+    /// Account a reverse proxied request by the way it ended
+    pub fn add_reverse_proxy_request(&self, reason: CloseReason) {
+        self.report(|x| x.add_counter(&REVERSE_PROXY_REQUESTS, &[reason.as_str()], 1));
+    }
+
+    /// Account the health score of an egress uplink
+    pub fn set_egress_uplink_score(&self, uplink: &str, score: Duration) {
+        self.report(|x| x.set_gauge(&EGRESS_UPLINK_SCORE, &[uplink], score.as_millis() as i64));
+    }
+
+    /// Account a raced connection established over an egress uplink
+    pub fn add_egress_uplink_win(&self, uplink: &str) {
+        self.report(|x| x.add_counter(&EGRESS_UPLINK_WINS, &[uplink], 1));
+    }
+
+    /// Account the state of an upstream hop
+    pub fn set_upstream_hop_up(&self, hop: &str, is_up: bool) {
+        self.report(|x| x.set_gauge(&UPSTREAM_HOP_UP, &[hop], is_up as i64));
+    }
+
+    /// Account the time of establishing a connection to an upstream hop
+    pub fn observe_upstream_hop_connect(&self, hop: &str, time: Duration) {
+        self.report(|x| {
+            x.observe_histogram(&UPSTREAM_HOP_CONNECT_TIME, &[hop], time.as_secs_f64())
+        });
+    }
+
+    /// Account the expiration time of a loaded certificate
+    pub fn set_certificate_expiry(&self, role: &str, hostname: &str, not_after: i64) {
+        self.report(|x| x.set_gauge(&CERTIFICATE_EXPIRY, &[role, hostname], not_after));
+    }
+
+    /// Drop the expiration times of the certificates, so that the ones no longer
+    /// in effect are not exported
+    pub fn reset_certificate_expiry(&self) {
+        self.report(|x| x.reset(&CERTIFICATE_EXPIRY));
+    }
+
+    /// Export the identity of the instance
+    pub fn set_instance_info(&self, instance: &Instance) {
+        let epoch = instance.epoch.to_string();
+        self.report(|x| x.set_gauge(&INSTANCE_INFO, &[&instance.id, &epoch], 1));
+    }
+
+    /// Account the state of the store the authenticator looks the clients up in
+    pub fn update_credential_store_up(&self, authenticator: Option<&dyn Authenticator>) {
+        let is_up = authenticator.is_none_or(|x| x.is_healthy()) as i64;
+        self.report(|x| x.set_gauge(&CREDENTIAL_STORE_UP, &[], is_up));
+    }
+
+    /// Account the path statistics of a closed QUIC connection
+    pub fn add_quic_connection_stats(
+        &self,
+        congestion_control: &str,
+        rtt: Duration,
+        delivery_rate: u64,
+        sent_bytes: u64,
+        lost_bytes: u64,
+    ) {
+        let labels = [congestion_control];
+        self.report(|x| {
+            x.observe_histogram(&QUIC_CONNECTION_RTT, &labels, rtt.as_secs_f64());
+            x.observe_histogram(
+                &QUIC_CONNECTION_DELIVERY_RATE,
+                &labels,
+                delivery_rate as f64,
+            );
+            x.add_counter(&QUIC_SENT_BYTES, &labels, sent_bytes);
+            x.add_counter(&QUIC_LOST_BYTES, &labels, lost_bytes);
+        });
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        const PROTOCOLS: [Protocol; 3] = [Protocol::Http1, Protocol::Http2, Protocol::Http3];
+        let labels = |x: &Protocol| [x.as_str()];
+        let prometheus = &self.prometheus;
+
+        MetricsSnapshot {
+            active_sessions: PROTOCOLS
+                .iter()
+                .map(|x| prometheus.gauge(&CLIENT_SESSIONS, &labels(x)))
+                .sum(),
+            total_sessions: prometheus.counter(&CLIENT_SESSIONS_TOTAL, &[]),
+            inbound_bytes: PROTOCOLS
+                .iter()
+                .map(|x| prometheus.counter(&INBOUND_TRAFFIC, &labels(x)))
+                .sum(),
+            outbound_bytes: PROTOCOLS
+                .iter()
+                .map(|x| prometheus.counter(&OUTBOUND_TRAFFIC, &labels(x)))
+                .sum(),
+            failed_requests: prometheus.counter(&FAILED_TUNNEL_REQUESTS, &[]),
+        }
+    }
+
+    /// Get the current values of the endpoint's own metrics
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.prometheus.registry().gather()
     }
 
     fn collect(&self) -> (String, Bytes) {
         let encoder = prometheus::TextEncoder::new();
 
-        let metric_families = prometheus::gather();
+        // The process metrics are in the default registry
+        let mut metric_families = prometheus::gather();
+        metric_families.extend(self.gather());
         let mut buffer = vec![];
         encoder.encode(&metric_families, &mut buffer).unwrap();
 
         (encoder.format_type().to_string(), Bytes::from(buffer))
     }
+
+    /// Apply the update to the built-in registry and the sinks of the embedder
+    fn report(&self, update: impl Fn(&dyn MetricsSink)) {
+        update(&self.prometheus);
+        for x in &self.sinks {
+            update(x.as_ref());
+        }
+    }
 }
 
 impl ClientSessionsCounter {
     fn new(metrics: Arc<Metrics>, protocol: Protocol) -> Self {
-        metrics
-            .client_sessions
-            .with_label_values(&[protocol.as_str()])
-            .inc();
+        metrics.report(|x| {
+            x.add_gauge(&CLIENT_SESSIONS, &[protocol.as_str()], 1);
+            x.add_counter(&CLIENT_SESSIONS_TOTAL, &[], 1);
+        });
 
         Self { metrics, protocol }
     }
@@ -125,36 +489,37 @@ impl ClientSessionsCounter {
 
 impl Drop for ClientSessionsCounter {
     fn drop(&mut self) {
+        let protocol = self.protocol.as_str();
         self.metrics
-            .client_sessions
-            .with_label_values(&[self.protocol.as_str()])
-            .dec();
+            .report(|x| x.add_gauge(&CLIENT_SESSIONS, &[protocol], -1));
     }
 }
 
 impl OutboundTcpSocketCounter {
     fn new(metrics: Arc<Metrics>) -> Self {
-        metrics.outbound_tcp_sockets.inc();
+        metrics.report(|x| x.add_gauge(&OUTBOUND_TCP_SOCKETS, &[], 1));
         Self { metrics }
     }
 }
 
 impl Drop for OutboundTcpSocketCounter {
     fn drop(&mut self) {
-        self.metrics.outbound_tcp_sockets.dec();
+        self.metrics
+            .report(|x| x.add_gauge(&OUTBOUND_TCP_SOCKETS, &[], -1));
     }
 }
 
 impl OutboundUdpSocketCounter {
     fn new(metrics: Arc<Metrics>) -> Self {
-        metrics.outbound_udp_sockets.inc();
+        metrics.report(|x| x.add_gauge(&OUTBOUND_UDP_SOCKETS, &[], 1));
         Self { metrics }
     }
 }
 
 impl Drop for OutboundUdpSocketCounter {
     fn drop(&mut self) {
-        self.metrics.outbound_udp_sockets.dec();
+        self.metrics
+            .report(|x| x.add_gauge(&OUTBOUND_UDP_SOCKETS, &[], -1));
     }
 }
 
@@ -189,21 +554,43 @@ async fn listen_inner(
 
     let next_id = AtomicU64::default();
     let listener = TcpListener::bind(settings.unwrap().address).await?;
+    context
+        .status_report
+        .listener_bound("metrics", "tcp", listener.local_addr()?);
+    let history = Arc::new(StatsHistory::new(settings.unwrap().stats_history));
 
-    loop {
-        let (stream, peer) = listener.accept().await?;
-        let log_id = log_chain.extended(log_utils::IdItem::new(
-            LOG_FMT,
-            next_id.fetch_add(1, Ordering::Relaxed),
-        ));
-        log_id!(trace, log_id, "New connection from {}", peer);
-        let context = context.clone();
-        tokio::spawn(async move { handle_request(context, stream, log_id).await });
+    let accept = async {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let log_id = log_chain.extended(log_utils::IdItem::new(
+                LOG_FMT,
+                next_id.fetch_add(1, Ordering::Relaxed),
+            ));
+            log_id!(trace, log_id, "New connection from {}", peer);
+            let context = context.clone();
+            let history = history.clone();
+            tokio::spawn(async move { handle_request(context, history, stream, log_id).await });
+        }
+    };
+
+    let capacity_stats = async {
+        if settings.unwrap().capacity_stats {
+            capacity::run(&context.metrics).await
+        } else {
+            futures::future::pending().await
+        }
+    };
+
+    tokio::select! {
+        x = accept => x,
+        _ = history.run(&context.metrics) => Ok(()),
+        _ = capacity_stats => Ok(()),
     }
 }
 
 async fn handle_request(
     context: Arc<core::Context>,
+    history: Arc<StatsHistory>,
     io: TcpStream,
     log_id: log_utils::IdChain<u64>,
 ) {
@@ -231,6 +618,10 @@ async fn handle_request(
             return;
         }
     };
+    let stream: Box<dyn http_codec::Stream> = Box::new(http_codec::HeaderInjectingStream {
+        inner: stream,
+        headers: instance_headers(&context.instance),
+    });
 
     let dispatch = async {
         match codec.listen().await {
@@ -246,10 +637,46 @@ async fn handle_request(
     };
 
     let handle = async {
-        let path = stream.request().request().uri.path();
+        let request = stream.request().request();
+        let path = request.uri.path();
+        if is_admin_request(&request.method, path) && !is_admin_authorized(&context, request) {
+            log_id!(
+                debug,
+                log_id,
+                "Unauthorized request: {} {}",
+                request.method,
+                path
+            );
+            let respond = stream.split().1;
+            if let Err(e) = respond.send_bad_response(
+                http::status::StatusCode::UNAUTHORIZED,
+                vec![(
+                    http::header::WWW_AUTHENTICATE.to_string(),
+                    "Bearer".to_string(),
+                )],
+            ) {
+                log_id!(debug, log_id, "Failed to send response: {}", e);
+            }
+            return;
+        }
+
         let result = match path {
             HEALTH_CHECK_PATH => handle_health_check(stream),
-            METRICS_PATH => handle_metrics_collect(&context.metrics, stream).await,
+            METRICS_PATH => handle_metrics_collect(&context, stream).await,
+            REBALANCE_PATH => handle_rebalance(&context, stream, &log_id).await,
+            SESSIONS_PATH => handle_sessions(&context, stream, &log_id).await,
+            STATS_PATH => handle_stats(&history, stream, &log_id).await,
+            EVENTS_PATH => handle_events(&context, stream, &log_id).await,
+            CACHE_PURGE_PATH => handle_cache_purge(&context, stream, &log_id).await,
+            AUTH_INVALIDATE_PATH => handle_auth_invalidate(&context, stream, &log_id).await,
+            CREDENTIALS_PATH => handle_credentials(&context, stream, &log_id).await,
+            REVOCATIONS_PATH => handle_revocations(&context, stream, &log_id).await,
+            LOG_LEVELS_PATH => handle_log_levels(stream, &log_id).await,
+            TRACE_RULES_PATH => handle_trace_rules(stream, &log_id).await,
+            MAINTENANCE_PATH => handle_maintenance(&context, stream, &log_id).await,
+            SCHEDULE_PATH => handle_schedule(&context, stream, &log_id).await,
+            RULES_PATH => handle_rules(&context, stream, &log_id).await,
+            RULES_EXPLAIN_PATH => handle_rules_explain(&context, stream, &log_id).await,
             x => {
                 log_id!(debug, log_id, "Unexpected path: {}", x);
                 let respond = stream.split().1;
@@ -277,31 +704,1278 @@ async fn handle_request(
     }
 }
 
+/// Whether the request changes the endpoint state or exposes the clients or the policy,
+/// e.g., the usernames failed to authenticate or the revoked ones, so it is served only
+/// with the configured admin token
+fn is_admin_request(method: &http::Method, path: &str) -> bool {
+    method != http::Method::GET
+        || [
+            CREDENTIALS_PATH,
+            EVENTS_PATH,
+            REVOCATIONS_PATH,
+            RULES_PATH,
+            RULES_EXPLAIN_PATH,
+        ]
+        .contains(&path)
+}
+
+fn is_admin_authorized(context: &core::Context, request: &http_codec::RequestHeaders) -> bool {
+    let token = context
+        .settings
+        .metrics
+        .as_ref()
+        .and_then(|x| x.admin_token.as_deref());
+    let Some(token) = token else {
+        return false;
+    };
+
+    request
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| is_same_secret(x, token))
+}
+
+/// Compare the digests of the secrets, so that the time taken tells nothing of the match
+pub(crate) fn is_same_secret(a: &str, b: &str) -> bool {
+    let digest = |x: &str| ring::digest::digest(&ring::digest::SHA256, x.as_bytes());
+    digest(a)
+        .as_ref()
+        .iter()
+        .zip(digest(b).as_ref())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Read the form of the request body. The body must carry its length, as the connection
+/// stays open after it. Returns [`None`] if it does not or the form is too big.
+async fn read_form_body(
+    request: Box<dyn http_codec::PendingRequest>,
+) -> io::Result<Option<String>> {
+    let length = request
+        .request()
+        .headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|x| *x <= MAX_FORM_BODY_SIZE);
+    let Some(length) = length else {
+        return Ok(None);
+    };
+
+    let mut body = request.into_body();
+    let mut content = Vec::with_capacity(length);
+    while content.len() < length {
+        match body.read(length - content.len()).await? {
+            Some(x) => {
+                content.extend_from_slice(&x);
+                body.release(x.len())?;
+            }
+            None => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    Ok(String::from_utf8(content).ok())
+}
+
 fn handle_health_check(stream: Box<dyn http_codec::Stream>) -> io::Result<()> {
     stream.split().1.send_ok_response(true).map(|_| ())
 }
 
 async fn handle_metrics_collect(
-    metrics: &Metrics,
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+) -> io::Result<()> {
+    context
+        .metrics
+        .update_credential_store_up(context.authenticator.as_deref());
+    let (content_type, content) = context.metrics.collect();
+    send_content(stream, content_type, content).await
+}
+
+/// Handle `POST /sessions/rebalance?count=N&order=longest_lived|most_loaded`.
+/// Responds with the number of the sessions asked to shut down.
+async fn handle_rebalance(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let params = match (request.method == http::Method::POST)
+        .then(|| parse_rebalance_query(request.uri.query().unwrap_or_default()))
+        .flatten()
+    {
+        Some(x) => x,
+        None => {
+            log_id!(debug, log_id, "Bad rebalance request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let n = context.sessions.rebalance(params.0, params.1);
+    log_id!(
+        info,
+        log_id,
+        "Asked {} sessions to drain ({:?})",
+        n,
+        params.1
+    );
+    send_content(
+        stream,
+        "text/plain".to_string(),
+        Bytes::from(format!("{}\n", n)),
+    )
+    .await
+}
+
+/// Handle `POST /cache/purge?path=PREFIX&host=HOST`.
+/// Responds with the number of the dropped reverse proxy cache entries.
+async fn handle_cache_purge(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let cache = match context.response_cache.as_ref() {
+        Some(x) => x,
+        None => {
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![])
+        }
+    };
+    let (host, path) = match (request.method == http::Method::POST)
+        .then(|| parse_cache_purge_query(request.uri.query().unwrap_or_default()))
+        .flatten()
+    {
+        Some(x) => x,
+        None => {
+            log_id!(debug, log_id, "Bad cache purge request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let n = cache.purge(host.as_deref(), &path);
+    log_id!(info, log_id, "Purged {} cache entries", n);
+    send_content(
+        stream,
+        "text/plain".to_string(),
+        Bytes::from(format!("{}\n", n)),
+    )
+    .await
+}
+
+/// Handle `POST /auth/invalidate?username=NAME`.
+/// Responds with the number of the dropped authentication results.
+async fn handle_auth_invalidate(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let authenticator = match context.authenticator.as_ref() {
+        Some(x) => x,
+        None => {
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![])
+        }
+    };
+    let username = match (request.method == http::Method::POST)
+        .then(|| parse_auth_invalidate_query(request.uri.query().unwrap_or_default()))
+        .flatten()
+    {
+        Some(x) => x,
+        None => {
+            log_id!(
+                debug,
+                log_id,
+                "Bad authentication invalidation request: {}",
+                request.uri
+            );
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let n = authenticator.invalidate(username.as_deref());
+    log_id!(info, log_id, "Dropped {} authentication results", n);
+    send_content(
+        stream,
+        "text/plain".to_string(),
+        Bytes::from(format!("{}\n", n)),
+    )
+    .await
+}
+
+/// Handle `GET /credentials`, `POST /credentials` with the `username=U&FIELD=VALUE...`
+/// or the `username=U&disabled=BOOL` form in the body, and `DELETE /credentials?username=U`.
+/// Responds with the client entries of the credentials file, without the secrets.
+async fn handle_credentials(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let store = match context.credentials.as_ref() {
+        Some(x) => x,
+        None => {
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![])
+        }
+    };
+    let request = stream.request().request();
+    let (method, version) = (request.method.clone(), request.version);
+    let query = request.uri.query().unwrap_or_default().to_string();
+    let (request, respond) = stream.split();
+    let change = match method {
+        http::Method::GET if query.is_empty() => Ok(None),
+        // The secrets are taken from the body, as a request URI may end up in the logs
+        http::Method::POST if query.is_empty() => {
+            let timeout = context.settings.metrics.as_ref().unwrap().request_timeout;
+            let form = tokio::time::timeout(timeout, read_form_body(request))
+                .await
+                .map_err(|_| io::Error::from(ErrorKind::TimedOut))??;
+            form.and_then(|x| parse_credentials_form(&method, &x.replace('+', "%20")))
+                .map(Some)
+                .ok_or(())
+        }
+        http::Method::DELETE => parse_credentials_form(&method, &query).map(Some).ok_or(()),
+        _ => Err(()),
+    };
+    let result = match change {
+        Ok(None) => Ok(()),
+        Ok(Some(change)) => {
+            let username = change.username().to_string();
+            let result = match change {
+                CredentialsChange::Upsert(x) => store.upsert(&x),
+                CredentialsChange::SetDisabled(x, disabled) => {
+                    store.set_disabled(&x, disabled).map(|_| ())
+                }
+                CredentialsChange::Remove(x) => store.remove(&x).map(|_| ()),
+            };
+            if result.is_ok() {
+                log_id!(info, log_id, "Changed credentials of {}", username);
+                // The cached outcomes would outlive an entry which is disabled or removed
+                if let Some(x) = context.authenticator.as_ref() {
+                    x.invalidate(Some(&username));
+                }
+            }
+            result
+        }
+        Err(()) => {
+            log_id!(debug, log_id, "Bad credentials request: {}", method);
+            return respond.send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+    let entries = result.and_then(|_| store.list());
+    let entries = match entries {
+        Ok(x) => x,
+        Err(e) => {
+            log_id!(info, log_id, "Credentials change refused: {}", e);
+            let status = match e {
+                CredentialsStoreError::Invalid(_) => http::status::StatusCode::BAD_REQUEST,
+                CredentialsStoreError::NotFound(_) => http::status::StatusCode::NOT_FOUND,
+                CredentialsStoreError::Io(_) => http::status::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return respond.send_bad_response(status, vec![]);
+        }
+    };
+
+    respond_content(
+        respond,
+        version,
+        "application/json".to_string(),
+        Bytes::from(format!("{}\n", credentials_to_json(&entries))),
+    )
+    .await
+}
+
+/// Handle `GET /revocations`, `POST /revocations?username=U|token_id=T`,
+/// and `DELETE /revocations?username=U|token_id=T`.
+/// Responds with the revoked usernames and token IDs.
+async fn handle_revocations(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let Some(list) = context.revocations.as_ref() else {
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![]);
+    };
+    let query = request.uri.query().unwrap_or_default();
+    let change = match request.method {
+        http::Method::GET if query.is_empty() => Ok(None),
+        http::Method::POST | http::Method::DELETE => {
+            parse_revocations_query(&request.method, query)
+                .map(Some)
+                .ok_or(())
+        }
+        _ => Err(()),
+    };
+    match change {
+        Ok(None) => (),
+        Ok(Some(change)) => {
+            log_id!(info, log_id, "Changing revocation list: {:?}", change);
+            match list.change(change) {
+                Ok(x) => revocation::close_sessions(context, &x),
+                Err(e) => {
+                    log_id!(info, log_id, "Revocation list change failed: {}", e);
+                    return stream.split().1.send_bad_response(
+                        http::status::StatusCode::INTERNAL_SERVER_ERROR,
+                        vec![],
+                    );
+                }
+            }
+        }
+        Err(()) => {
+            log_id!(debug, log_id, "Bad revocations request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    }
+
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(format!("{}\n", list.list().to_json())),
+    )
+    .await
+}
+
+/// Handle `GET /maintenance` and `POST /maintenance?enabled=BOOL`.
+/// Responds with the state of the reverse proxy maintenance mode.
+async fn handle_maintenance(
+    context: &core::Context,
     stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
 ) -> io::Result<()> {
-    let (content_type, mut content) = metrics.collect();
+    let request = stream.request().request();
+    if context.settings.reverse_proxy.is_none() {
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![]);
+    }
+    let query = request.uri.query().unwrap_or_default();
+    let is_valid = match request.method {
+        http::Method::GET => query.is_empty(),
+        http::Method::POST => match parse_maintenance_query(query) {
+            Some(x) => {
+                context.maintenance.store(x, Ordering::Relaxed);
+                log_id!(info, log_id, "Maintenance mode is {}", on_off(x));
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    };
+    if !is_valid {
+        log_id!(debug, log_id, "Bad maintenance request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    send_content(
+        stream,
+        "text/plain".to_string(),
+        Bytes::from(format!(
+            "{}\n",
+            on_off(context.maintenance.load(Ordering::Relaxed))
+        )),
+    )
+    .await
+}
+
+/// Handle `GET /schedule` and `POST /schedule?override=auto|drain|accept`.
+/// Responds with the state of the scheduled maintenance.
+async fn handle_schedule(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let Some(schedule) = context.schedule.as_ref() else {
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::NOT_FOUND, vec![]);
+    };
+    let query = request.uri.query().unwrap_or_default();
+    let is_valid = match request.method {
+        http::Method::GET => query.is_empty(),
+        http::Method::POST => match parse_schedule_query(query) {
+            Some(x) => {
+                schedule::set_override(context, x);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    };
+    if !is_valid {
+        log_id!(debug, log_id, "Bad schedule request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(schedule_to_json(&schedule.state())),
+    )
+    .await
+}
+
+/// Handle `GET /rules`, `POST /rules?list=rule|route&...` and
+/// `DELETE /rules?list=rule|route&position=N`.
+/// A `POST` request with `from` and `to` moves a rule, otherwise it inserts the rule made of
+/// the query at `position`, the top by default. With `persist=true`, the changed rules are
+/// written to the rules file. Responds with the rules in effect and their hit counters.
+async fn handle_rules(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let query = request.uri.query().unwrap_or_default();
+    let change = match request.method {
+        http::Method::GET if query.is_empty() => Ok(None),
+        http::Method::POST | http::Method::DELETE => parse_rules_query(&request.method, query)
+            .map(Some)
+            .ok_or(()),
+        _ => Err(()),
+    };
+    let rules = match change {
+        Ok(None) => context.rules.current(),
+        Ok(Some((change, persist))) => {
+            let description = format!("{:?}", change);
+            match context.rules.update(change, persist) {
+                Ok(x) => {
+                    log_id!(info, log_id, "Changed rules: {}", description);
+                    x
+                }
+                Err(e) => {
+                    log_id!(info, log_id, "Rules change refused: {}", e);
+                    let status = match e {
+                        RulesUpdateError::Invalid(_) => http::status::StatusCode::BAD_REQUEST,
+                        RulesUpdateError::Persist(_) => {
+                            http::status::StatusCode::INTERNAL_SERVER_ERROR
+                        }
+                    };
+                    return stream.split().1.send_bad_response(status, vec![]);
+                }
+            }
+        }
+        Err(()) => {
+            log_id!(debug, log_id, "Bad rules request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let content = rules_to_json(&rules)?;
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(format!("{}\n", content)),
+    )
+    .await
+}
+
+/// Handle `GET /rules/explain?client_ip=A&client_random=R&destination=H&profile=P`.
+/// Responds with the rules a connection of the client to the destination would hit,
+/// without counting the hits.
+async fn handle_rules_explain(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let query = match request.method {
+        http::Method::GET => parse_explain_query(request.uri.query().unwrap_or_default()),
+        _ => None,
+    };
+    let profile = query
+        .as_ref()
+        .and_then(|x| x.profile.as_deref())
+        .map(|x| context.profiles.get(x))
+        .transpose();
+    let (query, profile) = match (query, profile) {
+        (Some(x), Ok(profile)) => (x, profile),
+        (_, Err(e)) => {
+            log_id!(debug, log_id, "Bad rules explain request: {}", e);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+        (None, _) => {
+            log_id!(debug, log_id, "Bad rules explain request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let explanation = context.rules.current().explain(
+        profile.as_deref(),
+        &query.client_ip,
+        query.client_random.as_deref(),
+        query.destination.as_deref(),
+    );
+    let content = serde_json::to_string(&explanation)
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(format!("{}\n", content)),
+    )
+    .await
+}
+
+/// Encode the rules with the number of the connections each one has decided on
+fn rules_to_json(rules: &RulesEngine) -> io::Result<String> {
+    let mut value = serde_json::to_value(rules.config())
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    for (key, list) in [("rule", RuleList::Rule), ("route", RuleList::Route)] {
+        if let Some(x) = value[key].as_array_mut() {
+            for (x, hits) in x.iter_mut().zip(rules.hits(list)) {
+                x["hits"] = hits.into();
+            }
+        }
+    }
+    value["unmatched_hits"] = rules.unmatched_hits().into();
+    Ok(value.to_string())
+}
+
+fn on_off(x: bool) -> &'static str {
+    match x {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// Handle `GET /log-levels`, `POST /log-levels?module=M&level=L&duration_secs=N`
+/// and `DELETE /log-levels?module=M`.
+/// Responds with the global log level and the module overrides in effect.
+async fn handle_log_levels(
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let query = request.uri.query().unwrap_or_default();
+    let is_valid = match request.method {
+        http::Method::GET => query.is_empty(),
+        http::Method::POST => match parse_log_level_query(query) {
+            Some((module, Some(level), duration)) => {
+                log_utils::set_module_level(&module, level, duration);
+                if let Some(x) = duration {
+                    log_utils::schedule_expiration(x);
+                }
+                log_id!(info, log_id, "Set log level of {} to {}", module, level);
+                true
+            }
+            _ => false,
+        },
+        http::Method::DELETE => match parse_log_level_query(query) {
+            Some((module, None, None)) => {
+                if log_utils::reset_module_level(&module) {
+                    log_id!(info, log_id, "Reset log level of {}", module);
+                }
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+    if !is_valid {
+        log_id!(debug, log_id, "Bad log levels request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(log_levels_to_json()),
+    )
+    .await
+}
+
+/// Handle `GET /trace-rules`, `POST /trace-rules?identity=U&destination=H&duration_secs=N`
+/// and `DELETE /trace-rules?id=N`.
+/// Responds with the trace rules in effect.
+async fn handle_trace_rules(
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let query = request.uri.query().unwrap_or_default();
+    let is_valid = match request.method {
+        http::Method::GET => query.is_empty(),
+        http::Method::POST => match parse_trace_rule_query(query) {
+            Some((identity, destination, duration)) => {
+                let id = log_utils::add_trace_rule(identity, destination, duration);
+                if let Some(x) = duration {
+                    log_utils::schedule_expiration(x);
+                }
+                log_id!(info, log_id, "Added trace rule {}", id);
+                true
+            }
+            None => false,
+        },
+        http::Method::DELETE => match query.strip_prefix("id=").map(str::parse) {
+            Some(Ok(id)) => {
+                if log_utils::remove_trace_rule(id) {
+                    log_id!(info, log_id, "Removed trace rule {}", id);
+                }
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+    if !is_valid {
+        log_id!(debug, log_id, "Bad trace rules request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(trace_rules_to_json()),
+    )
+    .await
+}
+
+/// Handle `GET /sessions`.
+/// Responds with the list of the active client sessions.
+async fn handle_sessions(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    if request.method != http::Method::GET {
+        log_id!(debug, log_id, "Bad sessions request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(sessions::to_json(&context.sessions.list())),
+    )
+    .await
+}
+
+/// Handle `GET /stats?last=N`.
+/// Responds with up to `N` latest stats samples, or with the whole history if not specified.
+async fn handle_stats(
+    history: &StatsHistory,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    let last = match (request.method == http::Method::GET)
+        .then(|| parse_stats_query(request.uri.query().unwrap_or_default()))
+        .flatten()
+    {
+        Some(x) => x,
+        None => {
+            log_id!(debug, log_id, "Bad stats request: {}", request.uri);
+            return stream
+                .split()
+                .1
+                .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+        }
+    };
+
+    let samples = history.last(last);
+    send_content(
+        stream,
+        "application/json".to_string(),
+        Bytes::from(stats_history::to_json(&samples)),
+    )
+    .await
+}
+
+/// Handle `GET /events`, which is an admin request.
+/// Streams the endpoint activity events in the server-sent events format until
+/// the subscriber disconnects.
+async fn handle_events(
+    context: &core::Context,
+    stream: Box<dyn http_codec::Stream>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let request = stream.request().request();
+    if request.method != http::Method::GET {
+        log_id!(debug, log_id, "Bad events request: {}", request.uri);
+        return stream
+            .split()
+            .1
+            .send_bad_response(http::status::StatusCode::BAD_REQUEST, vec![]);
+    }
+
+    let mut rx = context.events.subscribe();
     let response = http::Response::builder()
-        .version(stream.request().request().version)
+        .version(request.version)
         .status(http::status::StatusCode::OK)
-        .header(http::header::CONTENT_TYPE, content_type)
-        .header(http::header::CONTENT_LENGTH, content.len())
+        .header(http::header::CONTENT_TYPE, "text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .header(http::header::CONNECTION, "close")
         .body(())
         .unwrap()
         .into_parts()
         .0;
-
     let mut sink = stream
         .split()
         .1
         .send_response(response, false)?
         .into_pipe_sink();
 
+    let mut keepalive = tokio::time::interval(EVENTS_KEEPALIVE_INTERVAL);
+    loop {
+        let mut message = tokio::select! {
+            x = rx.recv() => match x {
+                Ok(x) => Bytes::from(x.to_sse()),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log_id!(debug, log_id, "Event subscriber lagged by {} events", n);
+                    Bytes::from(format!(": dropped {} events\n\n", n))
+                }
+                Err(broadcast::error::RecvError::Closed) => return sink.eof(),
+            },
+            _ = keepalive.tick() => Bytes::from_static(b": keepalive\n\n"),
+        };
+
+        while !message.is_empty() {
+            message = sink.write(message)?;
+            sink.wait_writable().await?;
+        }
+    }
+}
+
+fn parse_stats_query(query: &str) -> Option<usize> {
+    let mut last = usize::MAX;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("last", x) => last = x.parse().ok()?,
+            _ => return None,
+        }
+    }
+
+    Some(last)
+}
+
+fn parse_rebalance_query(query: &str) -> Option<(usize, RebalanceOrder)> {
+    let mut count = 1;
+    let mut order = RebalanceOrder::LongestLived;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("count", x) => count = x.parse().ok()?,
+            ("order", "longest_lived") => order = RebalanceOrder::LongestLived,
+            ("order", "most_loaded") => order = RebalanceOrder::MostLoaded,
+            _ => return None,
+        }
+    }
+
+    Some((count, order))
+}
+
+fn parse_maintenance_query(query: &str) -> Option<bool> {
+    let mut enabled = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("enabled", x) => enabled = Some(x.parse().ok()?),
+            _ => return None,
+        }
+    }
+
+    enabled
+}
+
+fn parse_schedule_query(query: &str) -> Option<schedule::Override> {
+    let mut mode = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("override", x) => mode = Some(schedule::Override::parse(x)?),
+            _ => return None,
+        }
+    }
+
+    mode
+}
+
+/// Parse the query of a rules change, along with whether it is to be persisted
+fn parse_rules_query(method: &http::Method, query: &str) -> Option<(RulesChange, bool)> {
+    let decode = |x: &str| {
+        String::from_utf8(static_files::percent_decode(x)?)
+            .ok()
+            .filter(|x| !x.is_empty())
+    };
+
+    let mut list = None;
+    let mut position = None;
+    let mut moving = (None, None);
+    let mut persist = false;
+    let mut rule = Rule {
+        cidr: None,
+        client_random_prefix: None,
+        action: RuleAction::Deny,
+    };
+    let mut action = None;
+    let mut route = RouteRule {
+        destination: String::new(),
+        cidr: None,
+        override_sni: None,
+        override_host: None,
+        race_uplinks: vec![],
+    };
+    let mut has_route_fields = false;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("list", "rule") => list = Some(RuleList::Rule),
+            ("list", "route") => list = Some(RuleList::Route),
+            ("position", x) => position = Some(x.parse().ok()?),
+            ("from", x) => moving.0 = Some(x.parse().ok()?),
+            ("to", x) => moving.1 = Some(x.parse().ok()?),
+            ("persist", x) => persist = x.parse().ok()?,
+            ("cidr", x) => {
+                rule.cidr = Some(decode(x)?);
+                route.cidr = rule.cidr.clone();
+            }
+            ("client_random_prefix", x) => rule.client_random_prefix = Some(decode(x)?),
+            ("action", "allow") => action = Some(RuleAction::Allow),
+            ("action", "deny") => action = Some(RuleAction::Deny),
+            ("destination", x) => {
+                route.destination = decode(x)?;
+                has_route_fields = true;
+            }
+            ("override_sni", x) => {
+                route.override_sni = Some(decode(x)?);
+                has_route_fields = true;
+            }
+            ("override_host", x) => {
+                route.override_host = Some(decode(x)?);
+                has_route_fields = true;
+            }
+            ("race_uplinks", x) => {
+                route.race_uplinks = decode(x)?.split(',').map(String::from).collect();
+                has_route_fields = true;
+            }
+            _ => return None,
+        }
+    }
+
+    let has_rule_fields = action.is_some() || rule.client_random_prefix.is_some();
+    let has_fields = has_rule_fields || has_route_fields || rule.cidr.is_some();
+    let change = match (method, list?, moving) {
+        (&http::Method::DELETE, list, (None, None)) if !has_fields => {
+            RulesChange::Remove(list, position?)
+        }
+        (&http::Method::POST, list, (Some(from), Some(to))) if !has_fields => {
+            if position.is_some() {
+                return None;
+            }
+            RulesChange::Move(list, from, to)
+        }
+        (&http::Method::POST, RuleList::Rule, (None, None)) if !has_route_fields => {
+            rule.action = action?;
+            RulesChange::AddRule(position.unwrap_or_default(), rule)
+        }
+        (&http::Method::POST, RuleList::Route, (None, None)) if !has_rule_fields => {
+            if route.destination.is_empty() {
+                return None;
+            }
+            RulesChange::AddRoute(position.unwrap_or_default(), route)
+        }
+        _ => return None,
+    };
+
+    Some((change, persist))
+}
+
+/// The hypothetical connection of a rules explain request
+struct ExplainQuery {
+    client_ip: IpAddr,
+    client_random: Option<Vec<u8>>,
+    destination: Option<String>,
+    profile: Option<String>,
+}
+
+fn parse_explain_query(query: &str) -> Option<ExplainQuery> {
+    let decode = |x: &str| {
+        String::from_utf8(static_files::percent_decode(x)?)
+            .ok()
+            .filter(|x| !x.is_empty())
+    };
+
+    let mut client_ip = None;
+    let mut client_random = None;
+    let mut destination = None;
+    let mut profile = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("client_ip", x) => client_ip = Some(decode(x)?.parse().ok()?),
+            ("client_random", x) => client_random = Some(hex::decode(x).ok()?),
+            ("destination", x) => destination = Some(decode(x)?),
+            ("profile", x) => profile = Some(decode(x)?),
+            _ => return None,
+        }
+    }
+
+    Some(ExplainQuery {
+        client_ip: client_ip?,
+        client_random,
+        destination,
+        profile,
+    })
+}
+
+fn parse_cache_purge_query(query: &str) -> Option<(Option<String>, String)> {
+    let mut host = None;
+    let mut path = "/".to_string();
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("host", x) if !x.is_empty() => host = Some(x.to_string()),
+            ("path", x) if x.starts_with('/') => path = x.to_string(),
+            _ => return None,
+        }
+    }
+
+    Some((host, path))
+}
+
+fn parse_auth_invalidate_query(query: &str) -> Option<Option<String>> {
+    let mut username = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("username", x) if !x.is_empty() => username = Some(x.to_string()),
+            _ => return None,
+        }
+    }
+
+    Some(username)
+}
+
+enum CredentialsChange {
+    Upsert(Box<ClientEntry>),
+    SetDisabled(String, bool),
+    Remove(String),
+}
+
+impl CredentialsChange {
+    fn username(&self) -> &str {
+        match self {
+            Self::Upsert(x) => &x.username,
+            Self::SetDisabled(x, _) | Self::Remove(x) => x,
+        }
+    }
+}
+
+fn parse_credentials_form(method: &http::Method, form: &str) -> Option<CredentialsChange> {
+    let decode = |x: &str| {
+        String::from_utf8(static_files::percent_decode(x)?)
+            .ok()
+            .filter(|x| !x.is_empty())
+    };
+
+    let mut entry = ClientEntry::default();
+    let mut disabled = None;
+    let mut has_fields = false;
+    for pair in form.split('&').filter(|x| !x.is_empty()) {
+        let (key, x) = pair.split_once('=')?;
+        has_fields |= key != "username" && key != "disabled";
+        match key {
+            "username" => entry.username = decode(x)?,
+            "password" => entry.password = Some(decode(x)?),
+            "password_hash" => entry.password_hash = Some(decode(x)?),
+            "certificate_fingerprint" => entry.certificate_fingerprint = Some(decode(x)?),
+            "certificate_san" => entry.certificate_san = Some(decode(x)?),
+            "totp_secret" => entry.totp_secret = Some(decode(x)?),
+            "valid_till" => entry.valid_till = Some(x.parse().ok()?),
+            "tier" => entry.tier = Some(decode(x)?),
+            "egress_address" => entry.egress_address = Some(decode(x)?.parse().ok()?),
+            "max_connections" => entry.max_connections = Some(x.parse().ok()?),
+            "max_streams" => entry.max_streams = Some(x.parse().ok()?),
+            "data_quota_bytes" => entry.data_quota_bytes = Some(x.parse().ok()?),
+            "data_quota_period_days" => entry.data_quota_period_days = Some(x.parse().ok()?),
+            "profile" => entry.profile = Some(decode(x)?),
+            "allowed_destinations" => {
+                entry.allowed_destinations = decode(x)?.split(',').map(str::to_string).collect()
+            }
+            "blocked_destinations" => {
+                entry.blocked_destinations = decode(x)?.split(',').map(str::to_string).collect()
+            }
+            "disabled" => disabled = Some(x.parse().ok()?),
+            _ => return None,
+        }
+    }
+    if entry.username.is_empty() {
+        return None;
+    }
+
+    match (method, has_fields, disabled) {
+        (&http::Method::DELETE, false, None) => Some(CredentialsChange::Remove(entry.username)),
+        (&http::Method::POST, false, Some(x)) => {
+            Some(CredentialsChange::SetDisabled(entry.username, x))
+        }
+        (&http::Method::POST, true, x) => {
+            entry.disabled = x.unwrap_or_default();
+            Some(CredentialsChange::Upsert(Box::new(entry)))
+        }
+        _ => None,
+    }
+}
+
+fn parse_revocations_query(method: &http::Method, query: &str) -> Option<RevocationChange> {
+    let mut credential = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let (key, x) = pair.split_once('=')?;
+        let x = String::from_utf8(static_files::percent_decode(x)?)
+            .ok()
+            .filter(|x| !x.is_empty())?;
+        let x = match key {
+            "username" => Credential::Username(x),
+            "token_id" => Credential::TokenId(x),
+            _ => return None,
+        };
+        if credential.replace(x).is_some() {
+            return None;
+        }
+    }
+
+    match *method {
+        http::Method::POST => credential.map(RevocationChange::Revoke),
+        http::Method::DELETE => credential.map(RevocationChange::Restore),
+        _ => None,
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn parse_log_level_query(
+    query: &str,
+) -> Option<(String, Option<log::LevelFilter>, Option<Duration>)> {
+    let mut module = None;
+    let mut level = None;
+    let mut duration = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("module", x) if is_valid_module_path(x) => module = Some(x.to_string()),
+            ("level", x) => level = Some(x.parse().ok()?),
+            ("duration_secs", x) => duration = Some(Duration::from_secs(x.parse().ok()?)),
+            _ => return None,
+        }
+    }
+
+    Some((module?, level, duration))
+}
+
+fn is_valid_module_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'_' || x == b':')
+}
+
+#[allow(clippy::type_complexity)]
+fn parse_trace_rule_query(
+    query: &str,
+) -> Option<(Option<String>, Option<String>, Option<Duration>)> {
+    let decode = |x: &str| {
+        String::from_utf8(static_files::percent_decode(x)?)
+            .ok()
+            .filter(|x| {
+                !x.is_empty() && !x.contains(|c: char| c == '"' || c == '\\' || c.is_control())
+            })
+    };
+
+    let mut identity = None;
+    let mut destination = None;
+    let mut duration = None;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        match pair.split_once('=')? {
+            ("identity", x) => identity = Some(decode(x)?),
+            ("destination", x) => destination = Some(decode(x)?.to_lowercase()),
+            ("duration_secs", x) => duration = Some(Duration::from_secs(x.parse().ok()?)),
+            _ => return None,
+        }
+    }
+
+    (identity.is_some() || destination.is_some()).then_some((identity, destination, duration))
+}
+
+fn trace_rules_to_json() -> String {
+    let now = std::time::Instant::now();
+    let string_or_null = |x: &Option<String>| match x {
+        None => "null".to_string(),
+        Some(x) => format!("\"{}\"", x),
+    };
+
+    let mut out = String::from("{\"rules\":[");
+    for (i, x) in log_utils::trace_rules().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"identity\":{},\"destination\":{},\"expires_in_secs\":{}}}",
+            x.id,
+            string_or_null(&x.identity),
+            string_or_null(&x.destination),
+            x.expires_at
+                .map(|x| x.saturating_duration_since(now).as_secs().to_string())
+                .unwrap_or_else(|| "null".to_string()),
+        );
+    }
+    out.push_str("]}\n");
+    out
+}
+
+/// Encode the client entries, telling only whether a password or a TOTP secret is set
+fn credentials_to_json(entries: &[ClientEntry]) -> String {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|x| {
+            let mut value = serde_json::json!({
+                "username": x.username,
+                "password": x.password.is_some() || x.password_hash.is_some(),
+                "totp": x.totp_secret.is_some(),
+            });
+            for (key, field) in [
+                (
+                    "certificate_fingerprint",
+                    serde_json::json!(x.certificate_fingerprint),
+                ),
+                ("certificate_san", serde_json::json!(x.certificate_san)),
+                ("valid_till", serde_json::json!(x.valid_till)),
+                ("tier", serde_json::json!(x.tier)),
+                ("egress_address", serde_json::json!(x.egress_address)),
+                ("max_connections", serde_json::json!(x.max_connections)),
+                ("max_streams", serde_json::json!(x.max_streams)),
+                ("data_quota_bytes", serde_json::json!(x.data_quota_bytes)),
+                (
+                    "data_quota_period_days",
+                    serde_json::json!(x.data_quota_period_days),
+                ),
+                ("profile", serde_json::json!(x.profile)),
+            ] {
+                if !field.is_null() {
+                    value[key] = field;
+                }
+            }
+            for (key, field) in [
+                ("allowed_destinations", &x.allowed_destinations),
+                ("blocked_destinations", &x.blocked_destinations),
+            ] {
+                if !field.is_empty() {
+                    value[key] = serde_json::json!(field);
+                }
+            }
+            value["disabled"] = x.disabled.into();
+            value
+        })
+        .collect();
+    serde_json::json!({ "clients": entries }).to_string()
+}
+
+fn schedule_to_json(state: &schedule::State) -> String {
+    let window = match state.occurrence {
+        None => "null".to_string(),
+        Some(x) => format!("{{\"start\":{},\"end\":{}}}", x.start, x.end),
+    };
+    format!(
+        "{{\"draining\":{},\"override\":\"{}\",\"window\":{}}}\n",
+        state.draining,
+        state.mode.as_str(),
+        window,
+    )
+}
+
+fn log_levels_to_json() -> String {
+    let now = std::time::Instant::now();
+    let mut out = format!(
+        "{{\"level\":\"{}\",\"modules\":[",
+        log_utils::level().as_str().to_lowercase()
+    );
+    for (i, x) in log_utils::module_levels().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"module\":\"{}\",\"level\":\"{}\",\"expires_in_secs\":{}}}",
+            x.module,
+            x.level.as_str().to_lowercase(),
+            x.expires_at
+                .map(|x| x.saturating_duration_since(now).as_secs().to_string())
+                .unwrap_or_else(|| "null".to_string()),
+        );
+    }
+    out.push_str("]}\n");
+    out
+}
+
+async fn send_content(
+    stream: Box<dyn http_codec::Stream>,
+    content_type: String,
+    content: Bytes,
+) -> io::Result<()> {
+    let version = stream.request().request().version;
+    respond_content(stream.split().1, version, content_type, content).await
+}
+
+async fn respond_content(
+    respond: Box<dyn http_codec::PendingRespond>,
+    version: http::Version,
+    content_type: String,
+    mut content: Bytes,
+) -> io::Result<()> {
+    let response = http::Response::builder()
+        .version(version)
+        .status(http::status::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::CONTENT_LENGTH, content.len())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+
+    let mut sink = respond.send_response(response, false)?.into_pipe_sink();
+
     while !content.is_empty() {
         content = sink.write(content)?;
         sink.wait_writable().await?;
@@ -310,9 +1984,41 @@ async fn handle_metrics_collect(
     sink.eof()
 }
 
+/// The headers telling the instance the response comes from
+fn instance_headers(instance: &Instance) -> http::HeaderMap {
+    let mut headers = http::HeaderMap::new();
+    if let Ok(x) = http::HeaderValue::from_str(&instance.id) {
+        headers.insert(INSTANCE_ID_HEADER.clone(), x);
+    }
+    headers.insert(INSTANCE_EPOCH_HEADER.clone(), instance.epoch.into());
+    headers
+}
+
 fn prometheus_to_io_error(e: prometheus::Error) -> io::Error {
     match e {
         prometheus::Error::Io(e) => e,
         e => io::Error::new(ErrorKind::Other, e.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_requests() {
+        for path in [
+            CREDENTIALS_PATH,
+            EVENTS_PATH,
+            REVOCATIONS_PATH,
+            RULES_PATH,
+            RULES_EXPLAIN_PATH,
+        ] {
+            assert!(is_admin_request(&http::Method::GET, path), "{}", path);
+        }
+        for path in [HEALTH_CHECK_PATH, METRICS_PATH, SESSIONS_PATH, STATS_PATH] {
+            assert!(!is_admin_request(&http::Method::GET, path), "{}", path);
+            assert!(is_admin_request(&http::Method::POST, path), "{}", path);
+        }
+    }
+}
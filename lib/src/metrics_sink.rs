@@ -0,0 +1,267 @@
+//! The extension point for the embedders bridging the endpoint metrics into their own
+//! telemetry, like metrics-rs, StatsD or a custom collector. A [`MetricsSink`] passed to
+//! [`crate::core::Core::with_metrics_sink`] gets every metric update along with
+//! the built-in Prometheus registry, which keeps serving the admin listener.
+
+use std::collections::HashMap;
+
+/// The part of the endpoint a metric belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    /// The client sessions, the authentication and the certificates
+    Core,
+    /// The data relayed between the clients and the peers
+    Pipe,
+    /// The connections to the peers and the upstream hops
+    Forwarder,
+    /// The client facing transport, like QUIC
+    Codec,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricKind {
+    /// Only goes up
+    Counter,
+    /// Goes up and down
+    Gauge,
+    /// Counts the observed values falling into the buckets,
+    /// which are the inclusive upper bounds in ascending order
+    Histogram { buckets: &'static [f64] },
+}
+
+/// The description of a metric the endpoint reports
+#[derive(Debug, PartialEq)]
+pub struct MetricDesc {
+    pub subsystem: Subsystem,
+    /// The name in the Prometheus conventions, like `outbound_traffic_bytes`
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    /// The names of the labels, the values of which come with each update in the same order
+    pub labels: &'static [&'static str],
+}
+
+/// A backend the endpoint reports its metrics to.
+/// The updates come from the hot paths of the endpoint, so they are not to block.
+pub trait MetricsSink: Send + Sync {
+    /// Declare the metric, called once for each metric before any of its updates
+    fn register(&self, _metric: &'static MetricDesc) {}
+
+    /// Add `n` to a counter
+    fn add_counter(&self, metric: &'static MetricDesc, labels: &[&str], n: u64);
+
+    /// Add `delta` to a gauge, which is negative for a decrease
+    fn add_gauge(&self, metric: &'static MetricDesc, labels: &[&str], delta: i64);
+
+    /// Set a gauge to `value`
+    fn set_gauge(&self, metric: &'static MetricDesc, labels: &[&str], value: i64);
+
+    /// Observe a value of a histogram
+    fn observe_histogram(&self, metric: &'static MetricDesc, labels: &[&str], value: f64);
+
+    /// Drop all the label values of the metric, e.g., the ones of the certificates
+    /// which are no longer in effect
+    fn reset(&self, _metric: &'static MetricDesc) {}
+}
+
+/// The default sink keeping the metrics in a Prometheus registry
+pub(crate) struct PrometheusSink {
+    registry: prometheus::Registry,
+    collectors: HashMap<&'static str, Collector>,
+}
+
+enum Collector {
+    Counter(prometheus::IntCounterVec),
+    Gauge(prometheus::IntGaugeVec),
+    Histogram(prometheus::HistogramVec),
+}
+
+impl PrometheusSink {
+    pub fn new(metrics: &[&'static MetricDesc]) -> prometheus::Result<Self> {
+        let registry = prometheus::Registry::new();
+        let mut collectors = HashMap::with_capacity(metrics.len());
+        for x in metrics {
+            let opts = prometheus::Opts::new(x.name, x.help);
+            let collector = match x.kind {
+                MetricKind::Counter => {
+                    let c = prometheus::IntCounterVec::new(opts, x.labels)?;
+                    registry.register(Box::new(c.clone()))?;
+                    Collector::Counter(c)
+                }
+                MetricKind::Gauge => {
+                    let c = prometheus::IntGaugeVec::new(opts, x.labels)?;
+                    registry.register(Box::new(c.clone()))?;
+                    Collector::Gauge(c)
+                }
+                MetricKind::Histogram { buckets } => {
+                    let c = prometheus::HistogramVec::new(
+                        prometheus::HistogramOpts::from(opts).buckets(buckets.to_vec()),
+                        x.labels,
+                    )?;
+                    registry.register(Box::new(c.clone()))?;
+                    Collector::Histogram(c)
+                }
+            };
+            // An unlabelled metric is exported from the start, as a plain Prometheus one is
+            if x.labels.is_empty() {
+                const NO_LABELS: &[&str] = &[];
+                match &collector {
+                    Collector::Counter(c) => {
+                        c.with_label_values(NO_LABELS);
+                    }
+                    Collector::Gauge(c) => {
+                        c.with_label_values(NO_LABELS);
+                    }
+                    Collector::Histogram(c) => {
+                        c.with_label_values(NO_LABELS);
+                    }
+                }
+            }
+            collectors.insert(x.name, collector);
+        }
+        Ok(Self {
+            registry,
+            collectors,
+        })
+    }
+
+    pub fn registry(&self) -> &prometheus::Registry {
+        &self.registry
+    }
+
+    /// Get the current value of a counter, 0 if the metric is not a registered counter
+    pub fn counter(&self, metric: &MetricDesc, labels: &[&str]) -> u64 {
+        match self.collectors.get(metric.name) {
+            Some(Collector::Counter(x)) => x.with_label_values(labels).get(),
+            _ => 0,
+        }
+    }
+
+    /// Get the current value of a gauge, 0 if the metric is not a registered gauge
+    pub fn gauge(&self, metric: &MetricDesc, labels: &[&str]) -> i64 {
+        match self.collectors.get(metric.name) {
+            Some(Collector::Gauge(x)) => x.with_label_values(labels).get(),
+            _ => 0,
+        }
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn add_counter(&self, metric: &'static MetricDesc, labels: &[&str], n: u64) {
+        if let Some(Collector::Counter(x)) = self.collectors.get(metric.name) {
+            x.with_label_values(labels).inc_by(n);
+        }
+    }
+
+    fn add_gauge(&self, metric: &'static MetricDesc, labels: &[&str], delta: i64) {
+        if let Some(Collector::Gauge(x)) = self.collectors.get(metric.name) {
+            x.with_label_values(labels).add(delta);
+        }
+    }
+
+    fn set_gauge(&self, metric: &'static MetricDesc, labels: &[&str], value: i64) {
+        if let Some(Collector::Gauge(x)) = self.collectors.get(metric.name) {
+            x.with_label_values(labels).set(value);
+        }
+    }
+
+    fn observe_histogram(&self, metric: &'static MetricDesc, labels: &[&str], value: f64) {
+        if let Some(Collector::Histogram(x)) = self.collectors.get(metric.name) {
+            x.with_label_values(labels).observe(value);
+        }
+    }
+
+    fn reset(&self, metric: &'static MetricDesc) {
+        match self.collectors.get(metric.name) {
+            Some(Collector::Counter(x)) => x.reset(),
+            Some(Collector::Gauge(x)) => x.reset(),
+            Some(Collector::Histogram(x)) => x.reset(),
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{self, Metrics};
+    use crate::tls_demultiplexer::Protocol;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        registered: Mutex<Vec<&'static str>>,
+        updates: Mutex<Vec<(&'static str, Vec<String>, i64)>>,
+    }
+
+    impl Recorder {
+        fn record(&self, metric: &MetricDesc, labels: &[&str], value: i64) {
+            self.updates.lock().unwrap().push((
+                metric.name,
+                labels.iter().map(|x| x.to_string()).collect(),
+                value,
+            ));
+        }
+    }
+
+    impl MetricsSink for Recorder {
+        fn register(&self, metric: &'static MetricDesc) {
+            self.registered.lock().unwrap().push(metric.name);
+        }
+
+        fn add_counter(&self, metric: &'static MetricDesc, labels: &[&str], n: u64) {
+            self.record(metric, labels, n as i64);
+        }
+
+        fn add_gauge(&self, metric: &'static MetricDesc, labels: &[&str], delta: i64) {
+            self.record(metric, labels, delta);
+        }
+
+        fn set_gauge(&self, metric: &'static MetricDesc, labels: &[&str], value: i64) {
+            self.record(metric, labels, value);
+        }
+
+        fn observe_histogram(&self, metric: &'static MetricDesc, labels: &[&str], value: f64) {
+            self.record(metric, labels, value as i64);
+        }
+    }
+
+    #[test]
+    fn reports_to_sinks() {
+        let recorder = Arc::new(Recorder::default());
+        let mut metrics = Metrics::new().unwrap();
+        Arc::get_mut(&mut metrics)
+            .unwrap()
+            .add_sink(recorder.clone());
+        assert!(recorder
+            .registered
+            .lock()
+            .unwrap()
+            .contains(&metrics::OUTBOUND_TRAFFIC.name));
+        assert!(metrics
+            .gather()
+            .iter()
+            .any(|x| x.name() == metrics::FAILED_TUNNEL_REQUESTS.name));
+
+        drop(metrics.clone().client_sessions_counter(Protocol::Http3));
+        metrics.add_outbound_bytes(Protocol::Http2, 100);
+        metrics.set_upstream_hop_up("hop-1", true);
+
+        let label = |x: &str| vec![x.to_string()];
+        assert_eq!(
+            vec![
+                ("client_sessions", label("HTTP3"), 1),
+                ("client_sessions_total", vec![], 1),
+                ("client_sessions", label("HTTP3"), -1),
+                ("outbound_traffic_bytes", label("HTTP2"), 100),
+                ("upstream_hop_up", label("hop-1"), 1),
+            ],
+            *recorder.updates.lock().unwrap()
+        );
+        // The built-in registry keeps getting the updates
+        let snapshot = metrics.snapshot();
+        assert_eq!(0, snapshot.active_sessions);
+        assert_eq!(1, snapshot.total_sessions);
+        assert_eq!(100, snapshot.outbound_bytes);
+    }
+}
@@ -7,6 +7,7 @@ extern "C" {
     ) -> libc::c_int;
 }
 
+use crate::host_patterns;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
@@ -51,10 +52,12 @@ pub(crate) enum Channel {
     ReverseProxy,
 }
 
-pub(crate) type HostnamePort = (String, u16);
+/// A host name with a port number
+pub type HostnamePort = (String, u16);
 
+/// The destination of a TCP connection requested by a client
 #[derive(Debug, Clone)]
-pub(crate) enum TcpDestination {
+pub enum TcpDestination {
     Address(SocketAddr),
     HostName(HostnamePort),
 }
@@ -105,6 +108,29 @@ pub(crate) const fn http3_data_frame_overhead(payload_size: usize) -> usize {
     HTTP3_DATA_FRAME_TYPE_WIRE_LENGTH + varint_len(payload_size)
 }
 
+/// Append `x` encoded as a variable-length integer,
+/// see https://www.rfc-editor.org/rfc/rfc9000.html#section-16
+pub(crate) fn put_varint(buffer: &mut BytesMut, x: u64) {
+    match varint_len(x as usize) {
+        1 => buffer.put_u8(x as u8),
+        2 => buffer.put_u16(0x4000 | x as u16),
+        4 => buffer.put_u32(0x8000_0000 | x as u32),
+        _ => buffer.put_u64(0xc000_0000_0000_0000 | x),
+    }
+}
+
+/// Decode a variable-length integer at the beginning of `bytes`.
+/// Returns the value and its wire length, or [`None`] if `bytes` is too short.
+pub(crate) fn get_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    // The 2 most significant bits encode the length
+    let length = 1usize << (bytes.first()? >> 6);
+    let x = bytes
+        .get(..length)?
+        .iter()
+        .fold(0, |acc, x| (acc << 8) | u64::from(*x));
+    Some((x & (u64::MAX >> (64 - 8 * length + 2)), length))
+}
+
 pub(crate) fn get_fixed_size_ip(bytes: &mut Bytes) -> IpAddr {
     let ip = bytes.split_to(IPV6_WIRE_LENGTH);
     if ip[..IPV4_PADDING_WIRE_LENGTH].iter().all(|x| *x == 0) {
@@ -191,6 +217,101 @@ pub(crate) fn set_socket_ttl(fd: libc::c_int, is_ipv4: bool, ttl: u8) -> io::Res
     Ok(())
 }
 
+pub(crate) fn set_tcp_max_segment_size(fd: libc::c_int, mss: u16) -> io::Result<()> {
+    unsafe {
+        let mss = mss as libc::c_int;
+        let r = libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mss as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&mss) as _,
+        );
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Make the kernel honor the transmission times attached to the datagrams sent by [`send_to_at`]
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_transmission_time(fd: libc::c_int) -> io::Result<()> {
+    unsafe {
+        let config = libc::sock_txtime {
+            clockid: libc::CLOCK_MONOTONIC,
+            flags: 0,
+        };
+        let r = libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &config as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&config) as _,
+        );
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Send a datagram over `fd` not earlier than at `at`.
+/// The socket must be set up with [`enable_transmission_time`].
+#[cfg(target_os = "linux")]
+pub(crate) fn send_to_at(
+    fd: libc::c_int,
+    data: &[u8],
+    peer: &SocketAddr,
+    at: std::time::Instant,
+) -> io::Result<usize> {
+    unsafe {
+        let mut now = std::mem::zeroed::<libc::timespec>();
+        if libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let txtime = (now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64).saturating_add(
+            at.saturating_duration_since(std::time::Instant::now())
+                .as_nanos() as u64,
+        );
+
+        let (mut addr, addr_len) = socket_addr_to_libc(peer);
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // `u64` keeps the buffer aligned for `cmsghdr`
+        let mut control = [0_u64; 4];
+        let control_len = libc::CMSG_SPACE(std::mem::size_of::<u64>() as _) as usize;
+        debug_assert!(control_len <= std::mem::size_of_val(&control));
+
+        let mut msg = std::mem::zeroed::<libc::msghdr>();
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = addr_len;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control_len as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_TXTIME;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u64>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, txtime);
+
+        let r = libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT);
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(r as usize)
+    }
+}
+
 pub(crate) fn socket_addr_to_libc(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
     unsafe {
         let mut storage = std::mem::zeroed();
@@ -461,6 +582,152 @@ pub(crate) const fn is_global_ip(ip: &IpAddr) -> bool {
     }
 }
 
+/// The addresses of the cloud instance metadata services, e.g., of AWS, GCP and Azure
+const METADATA_ADDRESSES: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)),
+];
+const METADATA_HOST_NAMES: [&str; 1] = ["metadata.google.internal"];
+
+/// Returns [`true`] if the address is the one of a cloud instance metadata service,
+/// including its IPv4-mapped form
+pub(crate) fn is_metadata_address(ip: &IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+        IpAddr::V4(_) => *ip,
+    };
+    METADATA_ADDRESSES.contains(&ip)
+}
+
+/// Returns [`true`] if the destination is a cloud instance metadata service
+pub(crate) fn is_metadata_destination(destination: &TcpDestination) -> bool {
+    match destination {
+        TcpDestination::Address(x) => is_metadata_address(&x.ip()),
+        TcpDestination::HostName((x, _)) => match x.parse::<IpAddr>() {
+            Ok(ip) => is_metadata_address(&ip),
+            Err(_) => {
+                let name = x.trim_end_matches('.');
+                METADATA_HOST_NAMES
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(name))
+            }
+        },
+    }
+}
+
+/// Bring the destination requested by a client to the form the policies are checked against
+/// and the connections are logged with, so that the equivalent destinations written
+/// differently are treated the same:
+/// * the IP literals in the host name position, including the IPv4 forms like `0x7f.1`,
+///   become addresses,
+/// * the IPv4-mapped IPv6 addresses become IPv4 ones,
+/// * the host names become lowercase ASCII without the trailing dot, with the
+///   internationalized names converted into punycode.
+///
+/// Fails on a host name which is not valid.
+pub(crate) fn canonicalize_destination(destination: TcpDestination) -> io::Result<TcpDestination> {
+    match destination {
+        TcpDestination::Address(x) => Ok(TcpDestination::Address(SocketAddr::new(
+            canonicalize_ip(x.ip()),
+            x.port(),
+        ))),
+        TcpDestination::HostName((host, port)) => {
+            if let Some(ip) = parse_ip_literal(&host) {
+                return Ok(TcpDestination::Address(SocketAddr::new(ip, port)));
+            }
+            let name = canonicalize_host_name(&host).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid destination host name: {}", host),
+                )
+            })?;
+            // The mapping may turn a name into an address, e.g., the one of fullwidth digits
+            Ok(match parse_ip_literal(&name) {
+                Some(ip) => TcpDestination::Address(SocketAddr::new(ip, port)),
+                None => TcpDestination::HostName((name, port)),
+            })
+        }
+    }
+}
+
+/// Bring the host name to lowercase ASCII without the trailing dot.
+/// Returns [`None`] if it is not a valid (internationalized) domain name.
+pub(crate) fn canonicalize_host_name(host: &str) -> Option<String> {
+    let host = idna::domain_to_ascii(host).ok()?;
+    let host = host.strip_suffix('.').unwrap_or(&host);
+    let is_valid = |label: &str| {
+        !label.is_empty()
+            && label
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+    };
+    host.split('.').all(is_valid).then(|| host.to_string())
+}
+
+/// Bring the destination pattern of the settings, like `*.example.org`, to the form of
+/// the canonical host names it is matched against. A regular expression is left as is.
+pub(crate) fn canonicalize_host_pattern(pattern: &str) -> String {
+    if host_patterns::is_regex(pattern) {
+        return pattern.to_string();
+    }
+    let (prefix, name) = match (pattern.strip_prefix("*."), pattern.strip_prefix('.')) {
+        (Some(x), _) => ("*.", x),
+        (None, Some(x)) => (".", x),
+        (None, None) => ("", pattern),
+    };
+    match canonicalize_host_name(name) {
+        Some(x) => format!("{}{}", prefix, x),
+        None => pattern.to_ascii_lowercase(),
+    }
+}
+
+fn canonicalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Parse the IP address written in the host name position: an IPv6 address, bracketed
+/// or not, or an IPv4 one of up to 4 decimal, octal (`0` prefixed) or hexadecimal
+/// (`0x` prefixed) parts, where the last part fills the remaining bytes
+fn parse_ip_literal(host: &str) -> Option<IpAddr> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(x) = unbracketed.parse::<Ipv6Addr>() {
+        return Some(canonicalize_ip(IpAddr::V6(x)));
+    }
+
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let parts = host
+        .split('.')
+        .map(|x| {
+            let x = x.to_ascii_lowercase();
+            match x.strip_prefix("0x") {
+                Some("") => Some(0),
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None if x.len() > 1 && x.starts_with('0') => u32::from_str_radix(&x[1..], 8).ok(),
+                None => x.parse::<u32>().ok(),
+            }
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    let (last, init) = parts.split_last()?;
+    if init.len() > 3 || init.iter().any(|x| *x > 0xff) {
+        return None;
+    }
+    let last_bits = 8 * (4 - init.len() as u32);
+    if last_bits < 32 && *last >> last_bits != 0 {
+        return None;
+    }
+    let address = init
+        .iter()
+        .enumerate()
+        .fold(*last, |acc, (i, x)| acc | (x << (24 - 8 * i)));
+    Some(IpAddr::V4(Ipv4Addr::from(address)))
+}
+
 /// Returns HTTP request with sensitive fields removed.
 #[inline]
 pub(crate) fn scrub_request(request: &http::request::Parts) -> http::request::Parts {
@@ -494,10 +761,33 @@ pub(crate) fn scrub_sni(mut sni: String) -> String {
 #[cfg(test)]
 mod tests {
     use crate::net_utils::{
-        libc_to_socket_addr, scrub_request, scrub_sni, socket_addr_to_libc, SCRUBBED_PLACEHOLDER,
+        canonicalize_destination, canonicalize_host_pattern, get_varint, is_metadata_destination,
+        libc_to_socket_addr, put_varint, scrub_request, scrub_sni, socket_addr_to_libc,
+        TcpDestination, SCRUBBED_PLACEHOLDER,
     };
+    use bytes::BytesMut;
     use http::uri;
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    #[test]
+    fn varint() {
+        // The examples from https://www.rfc-editor.org/rfc/rfc9000.html#appendix-A.1
+        for (x, encoded) in [
+            (
+                151_288_809_941_952_652,
+                &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c][..],
+            ),
+            (494_878_333, &[0x9d, 0x7f, 0x3e, 0x7d]),
+            (15_293, &[0x7b, 0xbd]),
+            (37, &[0x25]),
+        ] {
+            let mut buffer = BytesMut::new();
+            put_varint(&mut buffer, x);
+            assert_eq!(encoded, &buffer[..]);
+            assert_eq!(Some((x, encoded.len())), get_varint(encoded));
+            assert_eq!(None, get_varint(&encoded[..encoded.len() - 1]));
+        }
+    }
 
     #[test]
     fn sockaddr_conversion_v4() {
@@ -533,6 +823,85 @@ mod tests {
         assert_eq!(sa.port(), port);
     }
 
+    #[test]
+    fn metadata_destinations() {
+        for x in [
+            "169.254.169.254:80",
+            "[fd00:ec2::254]:80",
+            "[::ffff:169.254.169.254]:80",
+        ] {
+            let address: SocketAddr = x.parse().unwrap();
+            assert!(
+                is_metadata_destination(&TcpDestination::Address(address)),
+                "{}",
+                x
+            );
+        }
+        for x in [
+            "metadata.google.internal",
+            "Metadata.Google.Internal.",
+            "169.254.169.254",
+        ] {
+            assert!(
+                is_metadata_destination(&TcpDestination::HostName((x.into(), 80))),
+                "{}",
+                x
+            );
+        }
+        assert!(!is_metadata_destination(&TcpDestination::Address(
+            "169.254.169.253:80".parse().unwrap()
+        )));
+        assert!(!is_metadata_destination(&TcpDestination::HostName((
+            "metadata.example.org".into(),
+            80
+        ))));
+    }
+
+    #[test]
+    fn canonical_destinations() {
+        let canonical =
+            |x: &str| match canonicalize_destination(TcpDestination::HostName((x.into(), 443))) {
+                Ok(TcpDestination::Address(x)) => x.ip().to_string(),
+                Ok(TcpDestination::HostName((x, _))) => x,
+                Err(_) => "invalid".to_string(),
+            };
+
+        assert_eq!("example.org", canonical("Example.ORG."));
+        assert_eq!("xn--bcher-kva.example", canonical("Bücher.example"));
+        assert_eq!("xn--bcher-kva.example", canonical("xn--bcher-kva.example"));
+        // a fullwidth full stop is a label separator too
+        assert_eq!("a.example", canonical("a\u{ff0e}example"));
+        for x in [
+            "10.0.0.1",
+            "10.1",
+            "0xa.0.0.1",
+            "012.0.0.1",
+            "167772161",
+            "10.0.0.1.",
+        ] {
+            assert_eq!("10.0.0.1", canonical(x), "{}", x);
+        }
+        assert_eq!("10.0.0.1", canonical("::ffff:10.0.0.1"));
+        assert_eq!("2001:db8::1", canonical("[2001:DB8::1]"));
+        assert_eq!("10.0.1.0", canonical("10.256"));
+        assert_eq!("10.0.0.1", canonical("\u{ff11}\u{ff10}.0.0.1"));
+        assert_eq!("10.0.0.256", canonical("10.0.0.256"));
+        assert_eq!("1.2.3.4.5", canonical("1.2.3.4.5"));
+        assert_eq!("invalid", canonical("a b.example"));
+        assert_eq!("invalid", canonical("."));
+
+        assert!(matches!(
+            canonicalize_destination(TcpDestination::Address("[::ffff:10.0.0.1]:80".parse().unwrap())),
+            Ok(TcpDestination::Address(x)) if x == "10.0.0.1:80".parse().unwrap()
+        ));
+        assert_eq!(
+            "*.xn--bcher-kva.example",
+            canonicalize_host_pattern("*.BÜCHER.example.")
+        );
+        assert_eq!(".example.org", canonicalize_host_pattern(".Example.org"));
+        assert_eq!(r"~CDN\d+", canonicalize_host_pattern(r"~CDN\d+"));
+    }
+
     #[test]
     fn scrubbing_of_sni() {
         assert_eq!("one", scrub_sni("one".to_string()));
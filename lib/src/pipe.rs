@@ -1,12 +1,29 @@
-use crate::{log_id, log_utils};
+//! The byte stream interfaces the tunneled connections are relayed through.
+//!
+//! A [`Source`] and a [`Sink`] stand for the receiving and the transmitting halves of
+//! a stream with the explicit flow control. The adapters below connect them to the tokio I/O
+//! and to the [`futures::Stream`] of chunks, so that a custom transport may be plugged in
+//! as a pipe, and a pipe may be driven as a regular socket.
+
+use crate::capacity::Category;
+use crate::{capacity, log_id, log_utils};
 use async_trait::async_trait;
 use bytes::Bytes;
 use future::Either;
-use futures::{future, FutureExt};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{future, FutureExt, StreamExt, TryStreamExt};
+use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 
 macro_rules! log_dir {
@@ -18,7 +35,7 @@ macro_rules! log_dir {
     };
 }
 
-pub(crate) enum Data {
+pub enum Data {
     /// Data chunk
     Chunk(Bytes),
     /// No more data will be transmitted in that direction
@@ -27,7 +44,7 @@ pub(crate) enum Data {
 
 /// An abstract interface for a receiver implementation
 #[async_trait]
-pub(crate) trait Source: Send {
+pub trait Source: Send {
     /// Get the request ID for logging
     fn id(&self) -> log_utils::IdChain<u64>;
 
@@ -41,7 +58,7 @@ pub(crate) trait Source: Send {
 
 /// An abstract interface for a transmitter implementation
 #[async_trait]
-pub(crate) trait Sink: Send {
+pub trait Sink: Send {
     /// Get the request ID for logging
     fn id(&self) -> log_utils::IdChain<u64>;
 
@@ -62,6 +79,19 @@ pub(crate) trait Sink: Send {
         Ok(())
     }
 
+    /// Wait for the connection to be writable and reserve the capacity for the next
+    /// [`Self::write()`] call.
+    ///
+    /// # Return
+    ///
+    /// The number of bytes up to `size` the connection is ready to take. By default,
+    /// the whole `size` is granted once the sink is writable, so a sink keeping no account
+    /// of the peer capacity may still return an unsent portion from [`Self::write()`].
+    async fn reserve(&mut self, size: usize) -> io::Result<usize> {
+        self.wait_writable().await?;
+        Ok(size)
+    }
+
     /// Indicate that no more data will be sent to the sink
     fn eof(&mut self) -> io::Result<()>;
 
@@ -90,6 +120,8 @@ pub(crate) struct SimplexPipe<F> {
     sink: Box<dyn Sink>,
     update_metrics: F,
     pending_chunk: Option<Data>,
+    /// Accounts the size of [`Self::pending_chunk`]
+    pending_buffer: capacity::Buffer,
     direction: SimplexDirection,
     last_activity: Instant,
 }
@@ -134,6 +166,7 @@ impl<F: Fn(SimplexDirection, usize) + Send> SimplexPipe<F> {
             sink,
             update_metrics,
             pending_chunk: Default::default(),
+            pending_buffer: capacity::Buffer::new(Category::Pipe),
             direction,
             last_activity: Instant::now(),
         }
@@ -158,6 +191,7 @@ impl<F: Fn(SimplexDirection, usize) + Send> SimplexPipe<F> {
                     let pending = self.pending_chunk.take().ok_or_else(|| {
                         io::Error::new(ErrorKind::Other, "Pending chunk is unexpectedly absent")
                     })?;
+                    self.pending_buffer.set(0);
                     log_dir!(
                         trace,
                         self.sink.id(),
@@ -195,6 +229,7 @@ impl<F: Fn(SimplexDirection, usize) + Send> SimplexPipe<F> {
                             "Unsent: {} bytes",
                             unsent_data.len()
                         );
+                        self.pending_buffer.set(unsent_data.len());
                         self.pending_chunk = Some(Data::Chunk(unsent_data));
                     }
                 }
@@ -236,8 +271,8 @@ impl<F: Fn(SimplexDirection, usize) + Send + Clone> DuplexPipe<F> {
                 ExchangeOnceStatus::Finished(()) => break Ok(()),
                 ExchangeOnceStatus::TimedOut(()) => {
                     let expiration_deadline = Instant::now() - timeout;
-                    if self.left_pipe.last_activity < expiration_deadline
-                        && self.right_pipe.last_activity < expiration_deadline
+                    if self.left_pipe.last_activity <= expiration_deadline
+                        && self.right_pipe.last_activity <= expiration_deadline
                     {
                         break Err(ErrorKind::TimedOut.into());
                     }
@@ -291,3 +326,601 @@ impl<F: Fn(SimplexDirection, usize) + Send + Clone> DuplexPipe<F> {
 fn io_to_pipe_error<T>(id: T, io: io::Error) -> Error<T> {
     Error { id, io }
 }
+
+/// Make a pipe of an I/O object, like a socket of a custom transport
+pub fn from_io<S>(stream: S, id: log_utils::IdChain<u64>) -> (Box<dyn Source>, Box<dyn Sink>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (rx, tx) = tokio::io::split(stream);
+    let (chunks_tx, chunks_rx) = mpsc::channel(1);
+    (
+        Box::new(IoSource { rx, id: id.clone() }),
+        Box::new(IoSink {
+            tx: Some(chunks_tx),
+            writer: Some(tokio::spawn(write_io(tx, chunks_rx))),
+            id,
+        }),
+    )
+}
+
+/// Turn a pipe into an I/O object, for example, to run a tokio based protocol
+/// implementation through a tunnel
+pub fn into_io(source: Box<dyn Source>, sink: Box<dyn Sink>) -> PipeIo {
+    PipeIo {
+        source: Some(source),
+        reading: None,
+        chunk: Bytes::new(),
+        is_eof: false,
+        sink: Some(sink),
+        waiting: None,
+        is_shut_down: false,
+    }
+}
+
+/// Make a source of the chunks of a stream. As a stream has no flow control,
+/// the next chunk is not taken until the previous one is read.
+pub fn from_stream<S>(stream: S, id: log_utils::IdChain<u64>) -> Box<dyn Source>
+where
+    S: futures::Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    Box::new(StreamSource {
+        stream: stream.boxed(),
+        id,
+    })
+}
+
+/// Turn a source into a stream of the received chunks.
+/// A chunk is consumed as soon as it is yielded.
+pub fn into_stream(source: Box<dyn Source>) -> BoxStream<'static, io::Result<Bytes>> {
+    futures::stream::try_unfold(source, |mut source| async move {
+        match source.read().await? {
+            Data::Chunk(x) => {
+                source.consume(x.len())?;
+                Ok(Some((x, source)))
+            }
+            Data::Eof => Ok(None),
+        }
+    })
+    .boxed()
+}
+
+/// Read the source while waiting for `until` to complete, so that the data received meanwhile
+/// is not held up by the flow control. The data is read up to `limit` bytes and is replayed by
+/// the returned source before the rest of the stream.
+pub(crate) async fn read_ahead<F: Future>(
+    source: Box<dyn Source>,
+    limit: usize,
+    until: F,
+) -> io::Result<(F::Output, Box<dyn Source>)> {
+    let mut source = ReadAheadSource {
+        inner: source,
+        buffered: VecDeque::new(),
+        unconsumed: 0,
+    };
+
+    let read = async {
+        while source.unconsumed < limit {
+            match source.inner.read().await? {
+                Data::Chunk(x) => {
+                    source.inner.consume(x.len())?;
+                    source.unconsumed += x.len();
+                    source.buffered.push_back(Data::Chunk(x));
+                }
+                Data::Eof => {
+                    source.buffered.push_back(Data::Eof);
+                    break;
+                }
+            }
+        }
+        io::Result::Ok(())
+    };
+
+    tokio::pin!(until);
+    let output = tokio::select! {
+        x = &mut until => x,
+        r = read => {
+            r?;
+            until.await
+        }
+    };
+
+    if source.buffered.is_empty() {
+        Ok((output, source.inner))
+    } else {
+        Ok((output, Box::new(source)))
+    }
+}
+
+/// Run the `exchange` for at most `lifetime`, if it is set
+pub(crate) async fn with_lifetime<F: Future<Output = io::Result<()>>>(
+    lifetime: Option<Duration>,
+    exchange: F,
+) -> io::Result<()> {
+    match lifetime {
+        None => exchange.await,
+        Some(x) => tokio::time::timeout(x, exchange)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::TimedOut, "Lifetime exceeded"))),
+    }
+}
+
+struct IoSource<S> {
+    rx: ReadHalf<S>,
+    id: log_utils::IdChain<u64>,
+}
+
+/// An I/O object cannot be written without awaiting, so the chunks
+/// are passed to a writer task
+struct IoSink {
+    /// Sends the chunks to [`write_io`], [`None`] after the eof
+    tx: Option<mpsc::Sender<Bytes>>,
+    writer: Option<JoinHandle<io::Result<()>>>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct StreamSource {
+    stream: BoxStream<'static, io::Result<Bytes>>,
+    id: log_utils::IdChain<u64>,
+}
+
+/// Made by [`read_ahead`]
+struct ReadAheadSource {
+    inner: Box<dyn Source>,
+    /// The read ahead data, already consumed on [`Self::inner`]
+    buffered: VecDeque<Data>,
+    /// The number of the read ahead bytes not yet consumed by a caller
+    unconsumed: usize,
+}
+
+/// A read in progress, giving the source back on completion
+type Reading = BoxFuture<'static, (Box<dyn Source>, io::Result<Data>)>;
+/// A wait of the sink state in progress, giving the sink back on completion
+type Waiting = BoxFuture<'static, (Box<dyn Sink>, io::Result<()>)>;
+
+/// A pipe driven through [`AsyncRead`] and [`AsyncWrite`], made by [`into_io`]
+pub struct PipeIo {
+    /// [`None`] while the source is owned by [`Self::reading`]
+    source: Option<Box<dyn Source>>,
+    reading: Option<Reading>,
+    /// The not yet read part of the last received chunk
+    chunk: Bytes,
+    is_eof: bool,
+    /// [`None`] while the sink is owned by [`Self::waiting`]
+    sink: Option<Box<dyn Sink>>,
+    waiting: Option<(SinkWait, Waiting)>,
+    is_shut_down: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum SinkWait {
+    Writable,
+    Flushed,
+}
+
+async fn write_io<S: AsyncWrite>(
+    mut tx: WriteHalf<S>,
+    mut chunks: mpsc::Receiver<Bytes>,
+) -> io::Result<()> {
+    while let Some(x) = chunks.recv().await {
+        tx.write_all(&x).await?;
+        tx.flush().await?;
+    }
+    tx.shutdown().await
+}
+
+#[async_trait]
+impl<S: AsyncRead + Send> Source for IoSource<S> {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<Data> {
+        const READ_CHUNK_SIZE: usize = 64 * 1024;
+        let mut buffer = Vec::with_capacity(READ_CHUNK_SIZE);
+
+        match self.rx.read_buf(&mut buffer).await? {
+            0 => Ok(Data::Eof),
+            _ => Ok(Data::Chunk(Bytes::from(buffer))),
+        }
+    }
+
+    fn consume(&mut self, _size: usize) -> io::Result<()> {
+        // do nothing
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for IoSink {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    fn write(&mut self, data: Bytes) -> io::Result<Bytes> {
+        match self
+            .tx
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Already shut down"))?
+            .try_send(data)
+        {
+            Ok(_) => Ok(Bytes::new()),
+            Err(mpsc::error::TrySendError::Full(unsent)) => Ok(unsent),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        // The writer task shuts the stream down once the queued chunks are written
+        self.tx = None;
+        Ok(())
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        match self
+            .tx
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Already shut down"))?
+            .reserve()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.tx.is_some() {
+            return self.wait_writable().await;
+        }
+
+        match self.writer.take() {
+            Some(x) => x
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for StreamSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<Data> {
+        while let Some(x) = self.stream.try_next().await? {
+            if !x.is_empty() {
+                return Ok(Data::Chunk(x));
+            }
+        }
+        Ok(Data::Eof)
+    }
+
+    fn consume(&mut self, _size: usize) -> io::Result<()> {
+        // do nothing
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Source for ReadAheadSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.inner.id()
+    }
+
+    async fn read(&mut self) -> io::Result<Data> {
+        match self.buffered.pop_front() {
+            Some(x) => Ok(x),
+            None => self.inner.read().await,
+        }
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        let replayed = size.min(self.unconsumed);
+        self.unconsumed -= replayed;
+        if size > replayed {
+            self.inner.consume(size - replayed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl PipeIo {
+    /// Wait for the sink to reach the `wait` state, finishing the previously started wait first
+    fn poll_sink(&mut self, cx: &mut Context<'_>, wait: SinkWait) -> Poll<io::Result<()>> {
+        loop {
+            if let Some((started, future)) = self.waiting.as_mut() {
+                let started = *started;
+                let (sink, result) = futures::ready!(future.poll_unpin(cx));
+                self.waiting = None;
+                self.sink = Some(sink);
+                result?;
+                if started == wait {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let mut sink = self.sink.take().ok_or_else(closed_pipe_error)?;
+            let future = async move {
+                let result = match wait {
+                    SinkWait::Writable => sink.wait_writable().await,
+                    SinkWait::Flushed => sink.flush().await,
+                };
+                (sink, result)
+            };
+            self.waiting = Some((wait, future.boxed()));
+        }
+    }
+}
+
+impl AsyncRead for PipeIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.chunk.is_empty() {
+                let n = buf.remaining().min(self.chunk.len());
+                buf.put_slice(&self.chunk.split_to(n));
+                self.source
+                    .as_mut()
+                    .ok_or_else(closed_pipe_error)?
+                    .consume(n)?;
+                return Poll::Ready(Ok(()));
+            }
+            if self.is_eof || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut future = match self.reading.take() {
+                Some(x) => x,
+                None => {
+                    let mut source = self.source.take().ok_or_else(closed_pipe_error)?;
+                    async move {
+                        let result = source.read().await;
+                        (source, result)
+                    }
+                    .boxed()
+                }
+            };
+            let (source, result) = match future.poll_unpin(cx) {
+                Poll::Ready(x) => x,
+                Poll::Pending => {
+                    self.reading = Some(future);
+                    return Poll::Pending;
+                }
+            };
+            self.source = Some(source);
+            match result? {
+                Data::Chunk(x) => self.chunk = x,
+                Data::Eof => self.is_eof = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PipeIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if self.waiting.is_none() {
+                let sink = self.sink.as_mut().ok_or_else(closed_pipe_error)?;
+                let unsent = sink.write(Bytes::copy_from_slice(buf))?;
+                if unsent.len() < buf.len() {
+                    return Poll::Ready(Ok(buf.len() - unsent.len()));
+                }
+            }
+            futures::ready!(self.poll_sink(cx, SinkWait::Writable))?;
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_sink(cx, SinkWait::Flushed)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.is_shut_down {
+            // The eof must not overtake the pending operation
+            if let Some((wait, _)) = &self.waiting {
+                let wait = *wait;
+                futures::ready!(self.poll_sink(cx, wait))?;
+            }
+            self.sink.as_mut().ok_or_else(closed_pipe_error)?.eof()?;
+            self.is_shut_down = true;
+        }
+        self.poll_sink(cx, SinkWait::Flushed)
+    }
+}
+
+fn closed_pipe_error() -> io::Error {
+    io::Error::new(ErrorKind::Other, "Pipe half is unexpectedly absent")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn make_pipe() -> (
+        DuplexPipe<impl Fn(SimplexDirection, usize) + Send + Clone>,
+        sim::StreamEnd,
+        sim::StreamEnd,
+    ) {
+        let ((client_rx, client_tx), (left_rx, left_tx)) = sim::duplex(1024);
+        let ((peer_rx, peer_tx), (right_rx, right_tx)) = sim::duplex(1024);
+        let pipe = DuplexPipe::new(
+            (SimplexDirection::Outgoing, left_rx, right_tx),
+            (SimplexDirection::Incoming, right_rx, left_tx),
+            |_, _| (),
+        );
+        (pipe, (client_rx, client_tx), (peer_rx, peer_tx))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_pipe_times_out() {
+        let (mut pipe, _client, _peer) = make_pipe();
+        let started = Instant::now();
+        let error = pipe.exchange(TIMEOUT).await.unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        assert_eq!(TIMEOUT, started.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_in_one_direction_keeps_pipe() {
+        let (mut pipe, (_client_rx, mut client_tx), (mut peer_rx, _peer_tx)) = make_pipe();
+        let started = Instant::now();
+        let client = async {
+            for _ in 0..3 {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                client_tx
+                    .write_all(Bytes::from_static(b"ping"))
+                    .await
+                    .unwrap();
+            }
+            client_tx.eof().unwrap();
+        };
+        let peer = async {
+            while let Data::Chunk(x) = peer_rx.read().await.unwrap() {
+                peer_rx.consume(x.len()).unwrap();
+            }
+        };
+
+        let (result, ..) = tokio::join!(pipe.exchange(TIMEOUT), client, peer);
+        // The upload keeps the pipe past the first timeout,
+        // and the idle download times out once the upload is finished
+        assert_eq!(ErrorKind::TimedOut, result.unwrap_err().kind());
+        assert_eq!(2 * TIMEOUT, started.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifetime_limits_active_pipe() {
+        let (mut pipe, (_client_rx, mut client_tx), (mut peer_rx, _peer_tx)) = make_pipe();
+        let started = Instant::now();
+        let client = async {
+            loop {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                if client_tx
+                    .write_all(Bytes::from_static(b"ping"))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        };
+        let peer = async {
+            while let Data::Chunk(x) = peer_rx.read().await.unwrap() {
+                peer_rx.consume(x.len()).unwrap();
+            }
+        };
+
+        let result = tokio::select! {
+            x = with_lifetime(Some(3 * TIMEOUT), pipe.exchange(TIMEOUT)) => x,
+            _ = client => unreachable!(),
+            _ = peer => unreachable!(),
+        };
+        assert_eq!(ErrorKind::TimedOut, result.unwrap_err().kind());
+        assert_eq!(3 * TIMEOUT, started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn read_ahead_replays_data() {
+        let ((_left_rx, mut left_tx), (right_rx, _right_tx)) = sim::duplex(16);
+        let data = Bytes::from(vec![7; 40]);
+        // Cannot complete unless the data is read past the flow control window
+        let write = async {
+            left_tx.write_all(data.clone()).await.unwrap();
+            left_tx.eof().unwrap();
+        };
+
+        let ((), mut source) = read_ahead(right_rx, 1024, write).await.unwrap();
+        let mut received = Vec::new();
+        while let Data::Chunk(x) = source.read().await.unwrap() {
+            source.consume(x.len()).unwrap();
+            received.extend_from_slice(&x);
+        }
+        assert_eq!(data, received);
+    }
+
+    #[tokio::test]
+    async fn io_adapters() {
+        let (local, mut remote) = tokio::io::duplex(64);
+        let (source, sink) = from_io(local, log_utils::IdChain::empty());
+        // Smaller than the written data to go through the flow control
+        let ((pipe_rx, pipe_tx), (mut peer_rx, mut peer_tx)) = sim::duplex(16);
+        let mut relay = DuplexPipe::new(
+            (SimplexDirection::Outgoing, source, pipe_tx),
+            (SimplexDirection::Incoming, pipe_rx, sink),
+            |_, _| (),
+        );
+        let data = vec![7; 1000];
+
+        let peer = async {
+            let mut received = Vec::new();
+            while let Data::Chunk(x) = peer_rx.read().await.unwrap() {
+                peer_rx.consume(x.len()).unwrap();
+                received.extend_from_slice(&x);
+            }
+            peer_tx.write_all(Bytes::from(received)).await.unwrap();
+            peer_tx.eof().unwrap();
+        };
+        let remote = async {
+            remote.write_all(&data).await.unwrap();
+            remote.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            remote.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+
+        let (relayed, (), echoed) = tokio::join!(relay.exchange(TIMEOUT), peer, remote);
+        relayed.unwrap();
+        assert_eq!(data, echoed);
+
+        let ((pipe_rx, pipe_tx), (mut peer_rx, mut peer_tx)) = sim::duplex(16);
+        let mut io = into_io(pipe_rx, pipe_tx);
+        let peer = async {
+            let mut received = Vec::new();
+            while let Data::Chunk(x) = peer_rx.read().await.unwrap() {
+                peer_rx.consume(x.len()).unwrap();
+                received.extend_from_slice(&x);
+            }
+            peer_tx.write_all(Bytes::from(received)).await.unwrap();
+            peer_tx.eof().unwrap();
+        };
+        let local = async {
+            io.write_all(&data).await.unwrap();
+            io.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            io.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+
+        let ((), echoed) = tokio::join!(peer, local);
+        assert_eq!(data, echoed);
+    }
+
+    #[tokio::test]
+    async fn stream_adapters() {
+        let chunks = [&b"ping"[..], b"", b"pong"].map(|x| Ok(Bytes::from_static(x)));
+        let source = from_stream(futures::stream::iter(chunks), log_utils::IdChain::empty());
+
+        let received: Vec<_> = into_stream(source).try_collect().await.unwrap();
+        assert_eq!(
+            vec![Bytes::from_static(b"ping"), Bytes::from_static(b"pong")],
+            received
+        );
+    }
+}
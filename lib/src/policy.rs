@@ -0,0 +1,121 @@
+//! The message of the day and the terms of use. The message is sent with the successful
+//! tunnel responses. The tunnel requests of an identity which has not acknowledged
+//! the current terms are answered with `403 Forbidden` carrying the terms, and the client
+//! acknowledges them by repeating the request with the terms version.
+//! The clients whose credentials expire soon are warned with the expiration time in
+//! the successful tunnel responses.
+
+use crate::authentication;
+use crate::settings::PolicySettings;
+use crate::state_store::StateStore;
+
+pub(crate) const MOTD_HEADER: &str = "x-trusttunnel-motd";
+pub(crate) const TERMS_HEADER: &str = "x-trusttunnel-terms";
+pub(crate) const TERMS_VERSION_HEADER: &str = "x-trusttunnel-terms-version";
+/// Carries the version of the terms a client acknowledges
+pub(crate) const TERMS_ACK_HEADER: &str = "x-trusttunnel-terms-ack";
+/// Carries the UNIX time the credentials of a client expire at
+pub(crate) const CREDENTIALS_EXPIRY_HEADER: &str = "x-trusttunnel-credentials-expiry";
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The state store namespace of the acknowledged terms versions keyed by identity
+const ACK_NAMESPACE: &str = "terms_ack";
+
+/// Check the identity has acknowledged the current terms.
+/// The acknowledgment `ack` carried by the request is recorded if it is of the current version.
+pub(crate) fn is_acknowledged(
+    settings: &PolicySettings,
+    store: &StateStore,
+    identity: &str,
+    ack: Option<&str>,
+) -> bool {
+    if settings.terms.is_none() {
+        return true;
+    }
+    if store.get(ACK_NAMESPACE, identity).as_deref() == Some(settings.terms_version.as_str()) {
+        return true;
+    }
+    if ack == Some(settings.terms_version.as_str()) {
+        store.set(
+            ACK_NAMESPACE,
+            identity,
+            settings.terms_version.clone(),
+            None,
+        );
+        return true;
+    }
+    false
+}
+
+/// Check the credentials valid till `valid_till` are to be warned about at `now`
+pub(crate) fn is_expiring(settings: &PolicySettings, valid_till: u64, now: u64) -> bool {
+    settings
+        .expiry_warning_days
+        .is_some_and(|days| valid_till <= now.saturating_add(days as u64 * SECS_PER_DAY))
+}
+
+/// Get the identity the acknowledgments of an authenticated client are kept under
+pub(crate) fn identity(
+    authenticator: &dyn authentication::Authenticator,
+    source: &authentication::Source<'_>,
+) -> Option<String> {
+    match source {
+        authentication::Source::Sni(x) => Some(x.to_string()),
+        authentication::Source::ProxyBasic(_)
+        | authentication::Source::ProxyBearer(_)
+        | authentication::Source::ProxyDigest(_)
+        | authentication::Source::ClientCert(_) => authenticator.username(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acknowledgment_is_kept_per_version() {
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-policy-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = StateStore::open(&path).unwrap();
+        let v1 = PolicySettings::builder()
+            .terms("https://example.org/terms", "1")
+            .build()
+            .unwrap();
+
+        assert!(!is_acknowledged(&v1, &store, "alice", None));
+        assert!(!is_acknowledged(&v1, &store, "alice", Some("0")));
+        assert!(is_acknowledged(&v1, &store, "alice", Some("1")));
+        assert!(is_acknowledged(&v1, &store, "alice", None));
+        assert!(!is_acknowledged(&v1, &store, "bob", None));
+
+        let v2 = PolicySettings::builder()
+            .terms("https://example.org/terms", "2")
+            .build()
+            .unwrap();
+        assert!(!is_acknowledged(&v2, &store, "alice", None));
+
+        let no_terms = PolicySettings::builder().motd("Hello").build().unwrap();
+        assert!(is_acknowledged(&no_terms, &store, "bob", None));
+    }
+
+    #[test]
+    fn expiry_warning() {
+        let now = 1_700_000_000;
+        let settings = PolicySettings::builder()
+            .expiry_warning_days(7)
+            .build()
+            .unwrap();
+        assert!(is_expiring(&settings, now + 3 * SECS_PER_DAY, now));
+        assert!(is_expiring(&settings, now + 7 * SECS_PER_DAY, now));
+        assert!(!is_expiring(&settings, now + 8 * SECS_PER_DAY, now));
+
+        let no_warning = PolicySettings::builder().motd("Hello").build().unwrap();
+        assert!(!is_expiring(&no_warning, now + 1, now));
+        assert!(PolicySettings::builder()
+            .expiry_warning_days(0)
+            .build()
+            .is_err());
+    }
+}
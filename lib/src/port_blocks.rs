@@ -0,0 +1,260 @@
+use crate::settings::PortBlockSettings;
+use ring::rand::SecureRandom;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Partitions the source port range of the outgoing connections between the clients.
+/// A client keeps its block while it has at least one open connection from the address,
+/// and the assignments are logged, so that a source address and port seen by a destination
+/// can be attributed to the client.
+pub(crate) struct PortBlocks {
+    first_port: u16,
+    block_size: u16,
+    blocks_num: u16,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The assigned blocks keyed by the source address and the username
+    assigned: HashMap<(Option<IpAddr>, String), Assignment>,
+    /// The assigned block indices of each source address
+    used: HashMap<Option<IpAddr>, BTreeSet<u16>>,
+}
+
+struct Assignment {
+    block: u16,
+    /// The number of the leases holding the block
+    holders: usize,
+}
+
+/// Keeps the block assigned to the client until dropped
+pub(crate) struct PortLease {
+    blocks: Arc<PortBlocks>,
+    source: Option<IpAddr>,
+    username: String,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl PortBlocks {
+    pub fn new(settings: &PortBlockSettings) -> Self {
+        Self {
+            first_port: settings.first_port,
+            block_size: settings.block_size,
+            blocks_num: ((settings.last_port - settings.first_port) as u32 + 1)
+                .checked_div(settings.block_size as u32)
+                .unwrap_or_default() as u16,
+            state: Default::default(),
+        }
+    }
+
+    /// Get the block of the client on the source address, assigning one if the client
+    /// has none. [`None`] if all the blocks of the address are taken.
+    pub fn lease(self: &Arc<Self>, source: Option<IpAddr>, username: &str) -> Option<PortLease> {
+        let mut state = self.state.lock().unwrap();
+        let key = (source, username.to_string());
+        let block = match state.assigned.get_mut(&key) {
+            Some(x) => {
+                x.holders += 1;
+                x.block
+            }
+            None => {
+                let used = state.used.entry(source).or_default();
+                let block = (0..self.blocks_num).find(|x| !used.contains(x))?;
+                used.insert(block);
+                state.assigned.insert(key, Assignment { block, holders: 1 });
+                let ports = self.ports_of(block);
+                info!(
+                    "Assigned source ports {}-{} of {} to {}",
+                    ports.start(),
+                    ports.end(),
+                    display_source(source),
+                    username
+                );
+                block
+            }
+        };
+
+        Some(PortLease {
+            blocks: self.clone(),
+            source,
+            username: username.to_string(),
+            ports: self.ports_of(block),
+        })
+    }
+
+    fn ports_of(&self, block: u16) -> RangeInclusive<u16> {
+        let first = self.first_port + block * self.block_size;
+        first..=first + (self.block_size - 1)
+    }
+
+    fn release(&self, source: Option<IpAddr>, username: &str) {
+        let mut state = self.state.lock().unwrap();
+        let key = (source, username.to_string());
+        let block = match state.assigned.get_mut(&key) {
+            Some(x) if x.holders > 1 => {
+                x.holders -= 1;
+                return;
+            }
+            Some(x) => x.block,
+            None => return,
+        };
+
+        state.assigned.remove(&key);
+        if let Some(x) = state.used.get_mut(&source) {
+            x.remove(&block);
+            if x.is_empty() {
+                state.used.remove(&source);
+            }
+        }
+        let ports = self.ports_of(block);
+        info!(
+            "Released source ports {}-{} of {} from {}",
+            ports.start(),
+            ports.end(),
+            display_source(source),
+            username
+        );
+    }
+}
+
+impl PortLease {
+    /// Iterate over the ports of the block starting from a random one,
+    /// so that the recently closed connections are not reused first
+    fn candidates(&self) -> impl Iterator<Item = u16> {
+        let mut x = [0; 2];
+        ring::rand::SystemRandom::new().fill(&mut x).unwrap();
+        let first = *self.ports.start();
+        let size = (*self.ports.end() - first) as u32 + 1;
+        let offset = u16::from_be_bytes(x) as u32 % size;
+        (0..size).map(move |i| first + ((offset + i) % size) as u16)
+    }
+
+    /// Connect to the peer from any free port of the block
+    pub async fn connect_tcp(
+        &self,
+        peer: SocketAddr,
+        prepare: impl Fn(&TcpSocket) -> io::Result<()>,
+    ) -> io::Result<TcpStream> {
+        let ip = self.source.unwrap_or(match peer {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+        });
+        for port in self.candidates() {
+            let socket = match peer {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            // The ports of the closed connections are still in the TIME-WAIT state
+            socket.set_reuseaddr(true)?;
+            prepare(&socket)?;
+            match socket.bind((ip, port).into()) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+            match socket.connect(peer).await {
+                Ok(x) => return Ok(x),
+                // The same port is already connected to the peer
+                Err(e)
+                    if e.kind() == ErrorKind::AddrInUse
+                        || e.kind() == ErrorKind::AddrNotAvailable =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            "No free port in the source port block",
+        ))
+    }
+
+    /// Bind a UDP socket to any free port of the block
+    pub fn bind_udp(&self, peer: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let ip = self.source.unwrap_or(match peer {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+        });
+        for port in self.candidates() {
+            match std::net::UdpSocket::bind((ip, port)) {
+                Ok(x) => return Ok(x),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            "No free port in the source port block",
+        ))
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.blocks.release(self.source, &self.username);
+    }
+}
+
+fn display_source(source: Option<IpAddr>) -> String {
+    source
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "the default address".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_blocks() -> Arc<PortBlocks> {
+        Arc::new(PortBlocks::new(
+            &PortBlockSettings::builder()
+                .port_range(10000, 10299)
+                .block_size(100)
+                .build()
+                .unwrap(),
+        ))
+    }
+
+    #[test]
+    fn blocks_are_kept_while_leased() {
+        let blocks = make_blocks();
+        let a = blocks.lease(None, "alice").unwrap();
+        let b = blocks.lease(None, "bob").unwrap();
+        assert_eq!(10000..=10099, a.ports);
+        assert_eq!(10100..=10199, b.ports);
+
+        let a2 = blocks.lease(None, "alice").unwrap();
+        assert_eq!(a.ports, a2.ports);
+        drop(a);
+        assert_eq!(a2.ports, blocks.lease(None, "alice").unwrap().ports);
+        drop(a2);
+
+        // The released block goes to the next client
+        let c = blocks.lease(None, "carol").unwrap();
+        assert_eq!(10000..=10099, c.ports);
+        let _d = blocks.lease(None, "dave").unwrap();
+        assert!(blocks.lease(None, "eve").is_none());
+
+        // The blocks of the addresses are independent
+        let ip = Some("192.0.2.1".parse().unwrap());
+        assert_eq!(10000..=10099, blocks.lease(ip, "eve").unwrap().ports);
+    }
+
+    #[test]
+    fn candidates_cover_block() {
+        let blocks = make_blocks();
+        let lease = blocks.lease(None, "alice").unwrap();
+        let mut ports: Vec<_> = lease.candidates().collect();
+        ports.sort_unstable();
+        assert_eq!(lease.ports.clone().collect::<Vec<_>>(), ports);
+    }
+}
@@ -0,0 +1,193 @@
+//! The destination profiles the clients are assigned to through the credentials store.
+//! A profile is defined once in the settings, and is resolved at the authentication of
+//! a tunnel request into the policy of its connections: the destinations they are let to
+//! and the routing rules they are subject to.
+
+use crate::host_patterns::HostPatterns;
+use crate::net_utils;
+use crate::rules;
+use crate::rules::{RouteRule, RuleAction};
+use crate::settings::ProfileSettings;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// The profiles of the settings keyed by name
+pub(crate) struct ProfileRegistry {
+    profiles: HashMap<String, Arc<Profile>>,
+}
+
+pub(crate) struct Profile {
+    destinations: Vec<(Matcher, RuleAction)>,
+    /// The compiled host name patterns of the destination rules
+    hosts: HostPatterns,
+    default_action: RuleAction,
+    routes: Vec<RouteRule>,
+    /// The compiled destination patterns of the routing rules
+    route_patterns: HostPatterns,
+}
+
+enum Matcher {
+    /// Matched through [`Profile::hosts`]
+    Host,
+    Network(IpNet),
+}
+
+#[derive(Debug)]
+pub(crate) struct UnknownProfile(String);
+
+impl Display for UnknownProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown profile {}", self.0)
+    }
+}
+
+impl ProfileRegistry {
+    pub fn new(profiles: &HashMap<String, ProfileSettings>) -> Self {
+        Self {
+            profiles: profiles
+                .iter()
+                .map(|(name, x)| (name.clone(), Arc::new(Profile::new(x))))
+                .collect(),
+        }
+    }
+
+    /// Get the profile a client is assigned to
+    pub fn get(&self, name: &str) -> Result<Arc<Profile>, UnknownProfile> {
+        self.profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| UnknownProfile(name.to_string()))
+    }
+}
+
+impl Profile {
+    fn new(settings: &ProfileSettings) -> Self {
+        let mut hosts = vec![];
+        let destinations = settings
+            .destinations
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let matcher = match x.destination.parse() {
+                    Ok(network) => Matcher::Network(network),
+                    Err(_) => {
+                        hosts.push((i, net_utils::canonicalize_host_pattern(&x.destination)));
+                        Matcher::Host
+                    }
+                };
+                (matcher, x.action.clone())
+            })
+            .collect();
+        let routes: Vec<RouteRule> = settings
+            .route
+            .iter()
+            .cloned()
+            .map(|mut x| {
+                x.destination = net_utils::canonicalize_host_pattern(&x.destination);
+                x
+            })
+            .collect();
+        Self {
+            hosts: HostPatterns::new(hosts.iter().map(|(i, x)| (*i, x.as_str()))),
+            destinations,
+            default_action: settings.default_action.clone(),
+            route_patterns: rules::compile_routes(&routes),
+            routes,
+        }
+    }
+
+    /// Check if the clients of the profile are let to the destination, which is either
+    /// a host name or an IP address
+    pub fn is_allowed(&self, destination: &str) -> bool {
+        *self.find_destination(destination).1 == RuleAction::Allow
+    }
+
+    /// Find the position of the destination rule matching the destination along with
+    /// the action on it, [`None`] if none matches and the action is the default one
+    pub fn find_destination(&self, destination: &str) -> (Option<usize>, &RuleAction) {
+        let found = match destination.parse::<IpAddr>() {
+            Ok(address) => self
+                .destinations
+                .iter()
+                .position(|(matcher, _)| match matcher {
+                    Matcher::Network(x) => x.contains(&address),
+                    Matcher::Host => false,
+                }),
+            Err(_) => self.hosts.find(destination, |_| true),
+        };
+        found.map_or((None, &self.default_action), |i| {
+            (Some(i), &self.destinations[i].1)
+        })
+    }
+
+    /// Find the routing rule for a tunneled connection to the destination host
+    /// along with its position
+    pub fn route(&self, client_ip: &IpAddr, destination: &str) -> Option<(usize, &RouteRule)> {
+        rules::find_route(&self.routes, &self.route_patterns, client_ip, destination)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destinations() {
+        let settings = ProfileSettings::builder()
+            .destination("admin.example.org", RuleAction::Deny)
+            .destination("*.example.org", RuleAction::Allow)
+            .destination("10.0.0.0/8", RuleAction::Allow)
+            .default_action(RuleAction::Deny)
+            .build()
+            .unwrap();
+        let registry = ProfileRegistry::new(&HashMap::from([("staff".to_string(), settings)]));
+        let profile = registry.get("staff").unwrap();
+
+        assert!(profile.is_allowed("git.example.org"));
+        assert!(!profile.is_allowed("admin.example.org"));
+        assert!(!profile.is_allowed("example.com"));
+        assert!(profile.is_allowed("10.1.2.3"));
+        assert!(!profile.is_allowed("192.0.2.1"));
+        assert!(registry.get("contractors").is_err());
+    }
+
+    #[test]
+    fn destination_patterns() {
+        let settings = ProfileSettings::builder()
+            .destination(r"~build-[0-9]+\.ci\.example\.org", RuleAction::Allow)
+            .destination(".ci.example.org", RuleAction::Deny)
+            .destination("*.example.org", RuleAction::Allow)
+            .build()
+            .unwrap();
+        let profile = Profile::new(&settings);
+
+        assert_eq!(
+            (Some(0), &RuleAction::Allow),
+            profile.find_destination("build-12.ci.example.org")
+        );
+        assert_eq!(
+            (Some(1), &RuleAction::Deny),
+            profile.find_destination("ci.example.org")
+        );
+        assert_eq!(
+            (Some(1), &RuleAction::Deny),
+            profile.find_destination("web.ci.example.org")
+        );
+        assert_eq!(
+            (Some(2), &RuleAction::Allow),
+            profile.find_destination("git.example.org")
+        );
+        assert_eq!(
+            (None, &RuleAction::Allow),
+            profile.find_destination("example.org")
+        );
+
+        assert!(ProfileSettings::builder()
+            .destination("~(unclosed", RuleAction::Deny)
+            .build()
+            .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! The [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) header
+//! which the L4 load balancers prepend to the TCP connections to convey the address
+//! of the original client hidden behind them. Both the human-readable version 1 and
+//! the binary version 2 are accepted.
+//!
+//! The header is only expected from the sources listed in
+//! [`ProxyProtocolSettings::trusted_sources`], so that the clients connecting directly
+//! cannot forge their addresses. The other connections are served as is.
+//!
+//! The reverse proxy may prepend a version 2 header to the origin server connections
+//! in turn, so that the origin server sees the address of the client instead of
+//! the endpoint's.
+
+use crate::settings::ProxyProtocolSettings;
+use ipnet::IpNet;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest version 1 header including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x2;
+/// The connection is established by the balancer itself, e.g., for a health check
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+
+pub(crate) struct ProxyProtocol {
+    trusted_sources: Vec<IpNet>,
+    header_timeout: Duration,
+}
+
+impl ProxyProtocol {
+    pub fn new(settings: &ProxyProtocolSettings) -> Self {
+        Self {
+            trusted_sources: settings
+                .trusted_sources
+                .iter()
+                .filter_map(|x| x.parse().ok())
+                .collect(),
+            header_timeout: settings.header_timeout,
+        }
+    }
+
+    /// Check whether the connections from the `ip` start with the header
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_sources.iter().any(|x| x.contains(&ip))
+    }
+
+    /// Read the header off the `stream`, leaving the data following it intact.
+    /// Returns the address of the original client, or [`None`] if the header does not
+    /// convey one, e.g., for the health checks of the balancer.
+    pub async fn read_header<S: AsyncRead + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> io::Result<Option<SocketAddr>> {
+        tokio::time::timeout(self.header_timeout, read_header(stream))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+    }
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than the signature, so it can be read at once
+    let mut head = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut head).await?;
+
+    if head == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(fixed[0], fixed[1], &addresses)
+    } else if head.starts_with(V1_PREFIX) {
+        // Read byte by byte not to consume the data following the header
+        let mut line = head.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LENGTH {
+                return Err(invalid("Version 1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(invalid(
+            "Connection does not start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// Parse a version 1 header line, e.g., `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|x| x.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("Malformed version 1 header"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let src: IpAddr = src
+                .parse()
+                .map_err(|_| invalid("Malformed version 1 source address"))?;
+            dst.parse::<IpAddr>()
+                .map_err(|_| invalid("Malformed version 1 destination address"))?;
+            let src_port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid("Malformed version 1 source port"))?;
+            dst_port
+                .parse::<u16>()
+                .map_err(|_| invalid("Malformed version 1 destination port"))?;
+            if src.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("Version 1 address does not match the protocol"));
+            }
+            Ok(Some(SocketAddr::new(src, src_port)))
+        }
+        _ => Err(invalid("Malformed version 1 header")),
+    }
+}
+
+/// Parse the part of a version 2 header following the signature
+fn parse_v2(
+    version_command: u8,
+    family_protocol: u8,
+    addresses: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != V2_VERSION {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => (),
+        _ => return Err(invalid("Unknown version 2 command")),
+    }
+
+    // The type-length-value fields following the addresses are ignored
+    match family_protocol {
+        V2_TCP_OVER_IPV4 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        V2_TCP_OVER_IPV6 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        V2_TCP_OVER_IPV4 | V2_TCP_OVER_IPV6 => Err(invalid("Version 2 addresses are truncated")),
+        // The unspecified and the other transports do not identify a TCP client
+        _ => Ok(None),
+    }
+}
+
+/// Encode a version 2 header of a TCP connection from the `source` to the `destination`.
+/// The addresses of the different families are both encoded as IPv6 ones.
+pub(crate) fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (family_protocol, source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            (V2_TCP_OVER_IPV4, s.octets().to_vec(), d.octets().to_vec())
+        }
+        (s, d) => (
+            V2_TCP_OVER_IPV6,
+            to_ipv6(s).octets().to_vec(),
+            to_ipv6(d).octets().to_vec(),
+        ),
+    };
+    let length = (source_ip.len() + destination_ip.len() + 4) as u16;
+
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 4 + length as usize);
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push((V2_VERSION << 4) | V2_COMMAND_PROXY);
+    header.push(family_protocol);
+    header.extend_from_slice(&length.to_be_bytes());
+    header.extend_from_slice(&source_ip);
+    header.extend_from_slice(&destination_ip);
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(x) => x.to_ipv6_mapped(),
+        IpAddr::V6(x) => x,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let result = read_header(&mut data).await;
+        (result, data)
+    }
+
+    #[tokio::test]
+    async fn version_1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n\x16\x03";
+        let (result, tail) = read(header).await;
+        assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), result.unwrap());
+        assert_eq!(b"\x16\x03", tail);
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(
+            Some("[2001:db8::1]:56324".parse().unwrap()),
+            result.unwrap()
+        );
+
+        let (result, tail) = read(b"PROXY UNKNOWN\r\n\x16").await;
+        assert_eq!(None, result.unwrap());
+        assert_eq!(b"\x16", tail);
+
+        for x in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00",
+        ] {
+            assert!(read(x).await.0.is_err(), "{:?}", x);
+        }
+
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(200, b'x');
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn version_2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, V2_TCP_OVER_IPV4, 0, 15]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        // A type-length-value field
+        header.extend_from_slice(&[0x04, 0x00, 0x00]);
+        header.push(0x16);
+        let (result, tail) = read(&header).await;
+        assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), result.unwrap());
+        assert_eq!(b"\x16", tail);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, V2_TCP_OVER_IPV6, 0, 36]);
+        header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        header.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        header.extend_from_slice(&[0x01, 0xbb, 0x01, 0xbb]);
+        let (result, _) = read(&header).await;
+        assert_eq!(Some("[::1]:443".parse().unwrap()), result.unwrap());
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(None, read(&header).await.0.unwrap());
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, V2_TCP_OVER_IPV4, 0, 4, 192, 0, 2, 1]);
+        assert!(read(&header).await.0.is_err());
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x11, V2_TCP_OVER_IPV4, 0, 0]);
+        assert!(read(&header).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn encoded_version_2() {
+        let source = "192.0.2.1:56324".parse().unwrap();
+        let header = encode_v2(source, "198.51.100.1:443".parse().unwrap());
+        assert_eq!(V2_SIGNATURE.len() + 4 + 12, header.len());
+        assert_eq!(Some(source), read(&header).await.0.unwrap());
+
+        let header = encode_v2(source, "[2001:db8::1]:443".parse().unwrap());
+        assert_eq!(V2_TCP_OVER_IPV6, header[13]);
+        assert_eq!(
+            Some("[::ffff:192.0.2.1]:56324".parse().unwrap()),
+            read(&header).await.0.unwrap()
+        );
+    }
+
+    #[test]
+    fn trusted_sources() {
+        let proxy_protocol = ProxyProtocol::new(
+            &ProxyProtocolSettings::builder()
+                .trusted_sources(vec!["10.0.0.0/8".into(), "2001:db8::1/128".into()])
+                .build()
+                .unwrap(),
+        );
+        assert!(proxy_protocol.is_trusted("10.1.2.3".parse().unwrap()));
+        assert!(proxy_protocol.is_trusted("2001:db8::1".parse().unwrap()));
+        assert!(!proxy_protocol.is_trusted("192.0.2.1".parse().unwrap()));
+        assert!(!proxy_protocol.is_trusted("2001:db8::2".parse().unwrap()));
+    }
+}
@@ -1,8 +1,13 @@
+use crate::accept_rate::AcceptRateLimiter;
+use crate::http_codec;
 use crate::http_codec::{RequestHeaders, ResponseHeaders};
-use crate::settings::Settings;
+use crate::metrics::Metrics;
+use crate::settings::{CongestionControl, QuicSettings, Settings};
+use crate::tiers::TierRegistry;
 use crate::tls_demultiplexer::TlsDemux;
+use crate::tls_info::TlsInfo;
 use crate::utils::Either;
-use crate::{log_id, log_utils, net_utils, tls_demultiplexer, utils};
+use crate::{datagram_pipe, log_id, log_utils, net_utils, tls_demultiplexer, utils};
 use boring::ssl::{NameType, SelectCertError, SslContextBuilder, SslMethod, SslRef};
 use bytes::{Buf, Bytes, BytesMut};
 use http::header::InvalidHeaderName;
@@ -12,7 +17,7 @@ use quiche::h3::NameValue;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -26,6 +31,18 @@ const SOCKET_ID_FMT: &str = "QSOCK={}";
 
 const QUIC_CONNECTION_CLOSE_CODE: u64 = 0x42;
 
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// `SETTINGS_ENABLE_WEBTRANSPORT` of the WebTransport over HTTP/3 draft 02, which the browsers
+/// still look for
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b603742;
+/// `SETTINGS_WT_MAX_SESSIONS` of the later drafts
+const SETTINGS_WT_MAX_SESSIONS: u64 = 0xc671706a;
+/// The type a WebTransport bidirectional stream starts with, followed by its session ID
+const WEBTRANSPORT_STREAM_TYPE: u64 = 0x41;
+/// `H3_REQUEST_REJECTED`
+const H3_REQUEST_REJECTED: u64 = 0x10b;
+
 type QuicConnection = quiche::Connection;
 
 pub(crate) struct QuicMultiplexer {
@@ -42,6 +59,11 @@ pub(crate) struct QuicMultiplexer {
     token_prefix: [u8; TOKEN_PREFIX_SIZE],
     id: log_utils::IdChain<u64>,
     next_socket_id: Arc<AtomicU64>,
+    /// Whether the packets are sent at the times scheduled by the pacer
+    pacing: bool,
+    metrics: Arc<Metrics>,
+    accept_rate: Option<Arc<AcceptRateLimiter>>,
+    tiers: Arc<TierRegistry>,
 }
 
 pub(crate) struct QuicSocket {
@@ -51,6 +73,8 @@ pub(crate) struct QuicSocket {
     mux_tx: Arc<std::sync::Mutex<mpsc::Sender<SocketMessage>>>,
     peer: SocketAddr,
     udp_socket: Arc<UdpSocket>,
+    /// See [`QuicMultiplexer.pacing`]
+    pacing: bool,
     quic_conn: Arc<std::sync::Mutex<QuicConnection>>,
     h3_conn: Arc<std::sync::Mutex<h3::Connection>>,
     waiting_writable_streams: std::sync::Mutex<HashSet<u64>>,
@@ -58,6 +82,33 @@ pub(crate) struct QuicSocket {
     tls_connection_meta: tls_demultiplexer::ConnectionMeta,
     /// TLS client_random extracted from QUIC handshake
     client_random: Vec<u8>,
+    /// The parameters of the TLS handshake of the connection
+    tls_info: TlsInfo,
+    max_header_field_size: usize,
+    max_uri_length: usize,
+    /// Whether the WebTransport sessions are accepted on the connection
+    webtransport_enabled: bool,
+    webtransport: std::sync::Mutex<WebTransport>,
+}
+
+/// The client streams taken over from the HTTP/3 layer for the WebTransport sessions.
+/// The layer handles every readable client bidirectional stream as a request one, so once
+/// a session is accepted, the connection is dedicated to WebTransport: the streams opened
+/// after the last request are read here and never reach the layer.
+#[derive(Default)]
+struct WebTransport {
+    /// Whether a session has been accepted on the connection
+    dedicated: bool,
+    /// The request stream IDs of the open sessions
+    sessions: HashSet<u64>,
+    /// The session ID of each taken over stream
+    streams: HashMap<u64, u64>,
+    /// The bytes read so far of the signals of the new streams
+    pending: HashMap<u64, Vec<u8>>,
+    /// The taken over streams reported readable and not read out since
+    notified: HashSet<u64>,
+    /// The ID of the latest stream the HTTP/3 layer has parsed a request from
+    last_request_stream_id: Option<u64>,
 }
 
 pub(crate) enum QuicSocketEvent {
@@ -65,6 +116,12 @@ pub(crate) enum QuicSocketEvent {
     Readable(/* stream id */ u64),
     Writable(Vec</* stream id */ u64>),
     Close(/* stream id */ u64),
+    /// The client has reset a request stream, e.g., to cancel the request mid-response
+    Reset(/* stream id */ u64, /* error code */ u64),
+    /// An HTTP datagram received in a QUIC DATAGRAM frame
+    Datagram(/* stream id */ u64, Bytes),
+    /// The client has opened a bidirectional stream in an accepted WebTransport session
+    WebTransportStream(/* session id */ u64, /* stream id */ u64),
 }
 
 /// Messages sent by [`QuicMultiplexer`] to [`QuicSocket`]s
@@ -83,12 +140,14 @@ struct HandshakingConnection {
     quic_conn: Arc<std::sync::Mutex<QuicConnection>>,
     local_address: SocketAddr,
     tls_connection_meta: tls_demultiplexer::ConnectionMeta,
+    congestion_control: CongestionControl,
 }
 
 struct EstablishedConnection {
     /// Sends messages to [`QuicSocket.conn_rx`]
     socket_tx: mpsc::Sender<MultiplexerMessage>,
     quic_conn: Arc<std::sync::Mutex<QuicConnection>>,
+    congestion_control: CongestionControl,
 }
 
 enum Connection {
@@ -127,15 +186,29 @@ impl QuicMultiplexer {
         socket: UdpSocket,
         tls_demux: Arc<std::sync::RwLock<TlsDemux>>,
         next_socket_id: Arc<AtomicU64>,
+        metrics: Arc<Metrics>,
+        accept_rate: Option<Arc<AcceptRateLimiter>>,
+        tiers: Arc<TierRegistry>,
     ) -> io::Result<Self> {
-        let queue_cap = core_settings
-            .listen_protocols
-            .quic
-            .as_ref()
-            .unwrap()
-            .message_queue_capacity;
+        let quic_settings = core_settings.listen_protocols.quic.as_ref().unwrap();
+        let queue_cap = quic_settings.message_queue_capacity;
         let (tx, rx) = mpsc::channel(queue_cap);
 
+        #[cfg(target_os = "linux")]
+        let pacing = quic_settings.enable_pacing;
+        #[cfg(target_os = "linux")]
+        if pacing {
+            use std::os::unix::io::AsRawFd;
+            net_utils::enable_transmission_time(socket.as_raw_fd())?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let pacing = {
+            if quic_settings.enable_pacing {
+                warn!("Packet pacing is supported only on Linux, ignoring");
+            }
+            false
+        };
+
         Ok(Self {
             core_settings,
             socket: Arc::new(socket),
@@ -150,6 +223,10 @@ impl QuicMultiplexer {
                 .expose(),
             id: log_utils::IdChain::from(log_utils::IdItem::new(MUX_ID_FMT, 0)),
             next_socket_id,
+            pacing,
+            metrics,
+            accept_rate,
+            tiers,
         })
     }
 
@@ -286,8 +363,13 @@ impl QuicMultiplexer {
                 self.update_connection_deadline(conn_id, timeout);
             }
 
-            if let Err(e) = flush_pending_data(&mut quic_conn, &self.socket, &entry.peer, &self.id)
-            {
+            if let Err(e) = flush_pending_data(
+                &mut quic_conn,
+                &self.socket,
+                &entry.peer,
+                self.pacing,
+                &self.id,
+            ) {
                 log_id!(debug, self.id, "Failed to flush QUIC connection: {}", e);
             }
         }
@@ -471,10 +553,12 @@ impl QuicMultiplexer {
         odcid: Option<&quiche::ConnectionId<'a>>,
         peer: &SocketAddr,
         packet: &mut [u8],
+        congestion_control: CongestionControl,
     ) -> io::Result<QuicConnection> {
         let local_address = self.core_settings.listen_address;
         let mut quic_config =
             make_quic_config_with_domain_contexts(&self.core_settings, self.tls_demux.clone())?;
+        quic_config.set_cc_algorithm(congestion_control.into());
         let mut quic_conn = quiche::accept(scid, odcid, local_address, *peer, &mut quic_config)
             .map_err(|e| {
                 io::Error::new(
@@ -503,6 +587,7 @@ impl QuicMultiplexer {
         peer: &SocketAddr,
     ) -> Result<QuicSocket, (io::Error, Arc<std::sync::Mutex<QuicConnection>>)> {
         let quic_conn = conn.quic_conn;
+        let quic_settings = self.core_settings.listen_protocols.quic.as_ref().unwrap();
 
         let sni = quic_conn
             .lock()
@@ -526,9 +611,21 @@ impl QuicMultiplexer {
             );
         }
 
+        let webtransport_enabled = quic_settings.webtransport
+            && conn.tls_connection_meta.channel == net_utils::Channel::Tunnel;
         let h3_conn = {
             let mut quic = quic_conn.lock().unwrap();
-            let h3_config = h3::Config::new().unwrap();
+            let mut h3_config = h3::Config::new().unwrap();
+            h3_config.set_max_field_section_size(quic_settings.max_header_list_size);
+            h3_config.enable_extended_connect(quic_settings.connect_udp || webtransport_enabled);
+            if webtransport_enabled {
+                h3_config
+                    .set_additional_settings(vec![
+                        (SETTINGS_ENABLE_WEBTRANSPORT, 1),
+                        (SETTINGS_WT_MAX_SESSIONS, 1),
+                    ])
+                    .unwrap();
+            }
             let h3_conn = match h3::Connection::with_transport(&mut quic, &h3_config) {
                 Ok(x) => x,
                 Err(e) => {
@@ -547,13 +644,14 @@ impl QuicMultiplexer {
             Arc::new(std::sync::Mutex::new(h3_conn))
         };
 
-        // Extract client_random from QUIC after handshake is complete
-        let extracted_client_random = {
+        // Extract client_random and the handshake parameters from QUIC
+        // after handshake is complete
+        let (extracted_client_random, tls_info) = {
             let mut quic = quic_conn.lock().unwrap();
             let ssl: &mut SslRef = quic.as_mut();
             let mut client_random = [0u8; 32];
             ssl.client_random(&mut client_random);
-            client_random.to_vec()
+            (client_random.to_vec(), TlsInfo::from_boring(ssl))
         };
 
         let (tx, rx) = mpsc::channel(1);
@@ -562,6 +660,7 @@ impl QuicMultiplexer {
             Connection::Established(EstablishedConnection {
                 socket_tx: tx,
                 quic_conn: quic_conn.clone(),
+                congestion_control: conn.congestion_control,
             }),
         );
 
@@ -570,6 +669,7 @@ impl QuicMultiplexer {
             mux_tx: self.mux_tx.clone(),
             peer: *peer,
             udp_socket: self.socket.clone(),
+            pacing: self.pacing,
             quic_conn,
             h3_conn,
             waiting_writable_streams: Default::default(),
@@ -579,6 +679,11 @@ impl QuicMultiplexer {
             )),
             tls_connection_meta: conn.tls_connection_meta,
             client_random: extracted_client_random,
+            tls_info,
+            max_header_field_size: quic_settings.max_header_field_size,
+            max_uri_length: quic_settings.max_uri_length,
+            webtransport_enabled,
+            webtransport: Default::default(),
         })
     }
 
@@ -600,6 +705,29 @@ impl QuicMultiplexer {
                 )
             })?;
 
+        // Checked once the address is validated, so that a spoofed one is not limited
+        if let Some(x) = self
+            .accept_rate
+            .as_ref()
+            .and_then(|x| x.accept(peer.ip(), Instant::now().into_std()).err())
+        {
+            self.metrics.add_rate_limited_connection(x);
+            return Err((
+                io::Error::new(
+                    ErrorKind::Other,
+                    format!("Connection rate limit reached: {:?}", x),
+                ),
+                None,
+            ));
+        }
+
+        if self.tiers.is_overloaded() {
+            return Err((
+                io::Error::new(ErrorKind::Other, "Shedding connection due to high load"),
+                None,
+            ));
+        }
+
         log_id!(
             debug,
             self.id,
@@ -608,9 +736,14 @@ impl QuicMultiplexer {
             utils::hex_dump(&header.scid)
         );
 
+        let congestion_control = select_congestion_control(
+            self.core_settings.listen_protocols.quic.as_ref().unwrap(),
+            &peer.ip(),
+        );
+
         // Create QUIC connection - TLS callback will handle certificate selection automatically
         let quic_conn = self
-            .accept_quic_connection(&header.dcid, Some(&odcid), peer, packet)
+            .accept_quic_connection(&header.dcid, Some(&odcid), peer, packet, congestion_control)
             .map_err(|e| (e, None))?;
 
         // Get connection metadata after handshake (SNI will be available)
@@ -628,6 +761,7 @@ impl QuicMultiplexer {
             quic_conn: quic_conn.clone(),
             local_address: self.core_settings.listen_address,
             tls_connection_meta,
+            congestion_control,
         };
 
         if is_established {
@@ -702,7 +836,9 @@ impl QuicMultiplexer {
     fn on_socket_message(&mut self, message: SocketMessage) -> io::Result<()> {
         match message {
             SocketMessage::Close(conn_id) => {
-                self.connections.remove(&conn_id);
+                if let Some(Connection::Established(c)) = self.connections.remove(&conn_id) {
+                    self.record_connection_stats(&c);
+                }
                 Ok(())
             }
         }
@@ -737,10 +873,28 @@ impl QuicMultiplexer {
         for conn_id in closed {
             self.deadlines.remove(&conn_id);
             if let Some(Connection::Established(c)) = self.connections.remove(&conn_id) {
+                self.record_connection_stats(&c);
                 let _ = c.socket_tx.try_send(MultiplexerMessage::Close);
             }
         }
     }
+
+    fn record_connection_stats(&self, conn: &EstablishedConnection) {
+        let quic_conn = conn.quic_conn.lock().unwrap();
+        let stats = quic_conn.stats();
+        let (rtt, delivery_rate) = quic_conn
+            .path_stats()
+            .find(|x| x.active)
+            .map(|x| (x.rtt, x.delivery_rate))
+            .unwrap_or_default();
+        self.metrics.add_quic_connection_stats(
+            conn.congestion_control.as_str(),
+            rtt,
+            delivery_rate,
+            stats.sent_bytes,
+            stats.lost_bytes,
+        );
+    }
 }
 
 impl QuicSocket {
@@ -760,23 +914,17 @@ impl QuicSocket {
         self.client_random.clone()
     }
 
+    pub fn tls_info(&self) -> &TlsInfo {
+        &self.tls_info
+    }
+
     pub fn send_response(
         &self,
         stream_id: u64,
         response: ResponseHeaders,
         fin: bool,
     ) -> io::Result<()> {
-        let response: Vec<_> = std::iter::once(h3::HeaderRef::new(
-            b":status",
-            response.status.as_str().as_bytes(),
-        ))
-        .chain(
-            response
-                .headers
-                .iter()
-                .map(|(n, v)| h3::HeaderRef::new(n.as_ref(), v.as_ref())),
-        )
-        .collect();
+        let response = h3_headers(Some(&response.status), &response.headers);
 
         self.h3_conn
             .lock()
@@ -792,9 +940,34 @@ impl QuicSocket {
         self.flush_pending_data()
     }
 
+    /// Send a header section after the first response head: either the next response
+    /// head following an informational one, or the trailers if `status` is [`None`]
+    pub fn send_additional_headers(
+        &self,
+        stream_id: u64,
+        status: Option<http::StatusCode>,
+        headers: &http::HeaderMap,
+        fin: bool,
+    ) -> io::Result<()> {
+        let section = h3_headers(status.as_ref(), headers);
+
+        self.h3_conn
+            .lock()
+            .unwrap()
+            .send_additional_headers(
+                &mut self.quic_conn.lock().unwrap(),
+                stream_id,
+                section.as_slice(),
+                status.is_none(),
+                fin,
+            )
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+
+        self.flush_pending_data()
+    }
+
     pub fn read(&self, stream_id: u64) -> io::Result<Option<Bytes>> {
         let chunk = {
-            const READ_CHUNK_SIZE: usize = 64 * 1024;
             let mut bytes = BytesMut::zeroed(READ_CHUNK_SIZE);
 
             let mut read_offset = 0;
@@ -840,6 +1013,54 @@ impl QuicSocket {
         self.flush_pending_data().map(|_| data)
     }
 
+    /// Read the data of a stream taken over for WebTransport
+    pub fn read_raw(&self, stream_id: u64) -> io::Result<Option<Bytes>> {
+        let chunk = {
+            let mut webtransport = self.webtransport.lock().unwrap();
+            let mut bytes = BytesMut::zeroed(READ_CHUNK_SIZE);
+            match self
+                .quic_conn
+                .lock()
+                .unwrap()
+                .stream_recv(stream_id, &mut bytes)
+            {
+                Ok((0, _)) => None,
+                Ok((n, _)) => {
+                    bytes.truncate(n);
+                    Some(bytes.freeze())
+                }
+                Err(quiche::Error::Done) => {
+                    // Report the stream again once new data arrives
+                    webtransport.notified.remove(&stream_id);
+                    None
+                }
+                Err(quiche::Error::StreamReset(code)) => {
+                    return Err(http_codec::Cancellation::error(code))
+                }
+                Err(e) => return Err(io::Error::new(ErrorKind::Other, e.to_string())),
+            }
+        };
+
+        self.flush_pending_data()?;
+        Ok(chunk)
+    }
+
+    /// Write the data to a stream taken over for WebTransport, returning the unsent part
+    pub fn write_raw(&self, stream_id: u64, mut data: Bytes) -> io::Result<Bytes> {
+        match self
+            .quic_conn
+            .lock()
+            .unwrap()
+            .stream_send(stream_id, data.as_ref(), false)
+        {
+            Ok(n) => data.advance(n),
+            Err(quiche::Error::Done) => (),
+            Err(e) => return Err(io::Error::new(ErrorKind::Other, e.to_string())),
+        }
+
+        self.flush_pending_data().map(|_| data)
+    }
+
     pub fn stream_capacity(&self, stream_id: u64) -> io::Result<usize> {
         self.quic_conn
             .lock()
@@ -852,6 +1073,53 @@ impl QuicSocket {
         self.quic_conn.lock().unwrap().stream_finished(stream_id)
     }
 
+    /// Whether the HTTP datagrams may be exchanged in the QUIC DATAGRAM frames
+    pub fn datagrams_enabled(&self) -> bool {
+        let quic_conn = self.quic_conn.lock().unwrap();
+        quic_conn.dgram_max_writable_len().is_some()
+            && self
+                .h3_conn
+                .lock()
+                .unwrap()
+                .dgram_enabled_by_peer(&quic_conn)
+    }
+
+    /// Send an HTTP datagram of the request `stream_id` in a QUIC DATAGRAM frame.
+    /// A datagram not fitting in a frame or in the send queue is dropped.
+    pub fn send_datagram(
+        &self,
+        stream_id: u64,
+        payload: &[u8],
+    ) -> io::Result<datagram_pipe::SendStatus> {
+        // The requests are identified by the quarter stream ID (RFC 9297)
+        let quarter_stream_id = stream_id / 4;
+        let mut frame = BytesMut::with_capacity(
+            net_utils::varint_len(quarter_stream_id as usize) + payload.len(),
+        );
+        net_utils::put_varint(&mut frame, quarter_stream_id);
+        frame.extend_from_slice(payload);
+
+        {
+            let mut quic_conn = self.quic_conn.lock().unwrap();
+            if quic_conn
+                .dgram_max_writable_len()
+                .is_none_or(|x| frame.len() > x)
+            {
+                return Ok(datagram_pipe::SendStatus::Dropped);
+            }
+            match quic_conn.dgram_send(&frame) {
+                Ok(()) => (),
+                Err(quiche::Error::Done) | Err(quiche::Error::BufferTooShort) => {
+                    return Ok(datagram_pipe::SendStatus::Dropped)
+                }
+                Err(e) => return Err(io::Error::new(ErrorKind::Other, e.to_string())),
+            }
+        }
+
+        self.flush_pending_data()
+            .map(|_| datagram_pipe::SendStatus::Sent)
+    }
+
     pub fn notify_stream_waiting_writable(&self, stream_id: u64) {
         self.waiting_writable_streams
             .lock()
@@ -880,6 +1148,49 @@ impl QuicSocket {
         let _ = self.flush_pending_data();
     }
 
+    /// Abort both directions of a stream with the error code
+    pub fn reset_stream(&self, stream_id: u64, error_code: u64) {
+        {
+            let mut quic_conn = self.quic_conn.lock().unwrap();
+            let _ = quic_conn.stream_shutdown(stream_id, quiche::Shutdown::Read, error_code);
+            let _ = quic_conn.stream_shutdown(stream_id, quiche::Shutdown::Write, error_code);
+        }
+        let _ = self.flush_pending_data();
+    }
+
+    /// Whether the WebTransport sessions are accepted on the connection
+    pub fn webtransport_enabled(&self) -> bool {
+        self.webtransport_enabled
+    }
+
+    /// Take over the streams the client opens after the accepted session request `stream_id`
+    pub fn accept_webtransport_session(&self, stream_id: u64) {
+        let mut webtransport = self.webtransport.lock().unwrap();
+        webtransport.dedicated = true;
+        webtransport.sessions.insert(stream_id);
+    }
+
+    /// Forget a closed WebTransport session or stream. The streams the client opens in
+    /// a closed session are rejected.
+    pub fn close_webtransport_stream(&self, stream_id: u64) {
+        let mut webtransport = self.webtransport.lock().unwrap();
+        webtransport.sessions.remove(&stream_id);
+        webtransport.streams.remove(&stream_id);
+        webtransport.notified.remove(&stream_id);
+    }
+
+    /// Send the HTTP/3 GOAWAY frame telling a client not to open streams with IDs
+    /// starting from `stream_id`
+    pub fn send_goaway(&self, stream_id: u64) -> io::Result<()> {
+        self.h3_conn
+            .lock()
+            .unwrap()
+            .send_goaway(&mut self.quic_conn.lock().unwrap(), stream_id)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+
+        self.flush_pending_data()
+    }
+
     pub fn graceful_shutdown(&self) -> io::Result<()> {
         {
             let mut quic_conn = self.quic_conn.lock().unwrap();
@@ -922,6 +1233,9 @@ impl QuicSocket {
                         if !writable_streams.is_empty() {
                             break Some(QuicSocketEvent::Writable(writable_streams));
                         }
+                        if let Some(x) = self.recv_datagram() {
+                            break Some(x);
+                        }
                     }
                     Some(event) => break Some(event),
                 }
@@ -961,55 +1275,187 @@ impl QuicSocket {
             &mut self.quic_conn.lock().unwrap(),
             &self.udp_socket,
             &self.peer,
+            self.pacing,
             &self.id,
         )
     }
 
+    /// Take an HTTP datagram received in a QUIC DATAGRAM frame, if any
+    fn recv_datagram(&self) -> Option<QuicSocketEvent> {
+        let mut quic_conn = self.quic_conn.lock().unwrap();
+        loop {
+            let mut frame = Bytes::from(quic_conn.dgram_recv_vec().ok()?);
+            match net_utils::get_varint(&frame).and_then(|(x, n)| Some((x.checked_mul(4)?, n))) {
+                Some((stream_id, n)) => {
+                    frame.advance(n);
+                    return Some(QuicSocketEvent::Datagram(stream_id, frame));
+                }
+                None => log_id!(trace, self.id, "Dropping malformed HTTP datagram"),
+            }
+        }
+    }
+
     fn poll_h3_connection(&self) -> h3::Result<(u64, h3::Event)> {
-        self.h3_conn
-            .lock()
-            .unwrap()
-            .poll(&mut self.quic_conn.lock().unwrap())
+        let webtransport = self.webtransport.lock().unwrap();
+        let mut h3_conn = self.h3_conn.lock().unwrap();
+        let mut quic_conn = self.quic_conn.lock().unwrap();
+        // The layer would parse the taken over streams as the request ones, so its events
+        // wait for them to be read out
+        if webtransport.dedicated && quic_conn.readable().any(|x| webtransport.owns(x)) {
+            return Err(h3::Error::Done);
+        }
+        h3_conn.poll(&mut quic_conn)
     }
 
-    fn process_pending_h3_events(&self) -> io::Result<Option<QuicSocketEvent>> {
-        match self.poll_h3_connection() {
-            Ok((stream_id, h3::Event::Headers { list, .. })) => {
-                match self.on_request(stream_id, list) {
-                    Ok(x) => Ok(Some(x)),
-                    Err(e) => {
-                        let _ = self.send_response(
-                            stream_id,
-                            http::Response::builder()
-                                .status(http::StatusCode::BAD_REQUEST)
-                                .body(())
-                                .unwrap()
-                                .into_parts()
-                                .0,
-                            true,
-                        );
-                        Err(e)
+    /// Take over the new client streams of a connection dedicated to WebTransport,
+    /// and report the taken over streams which have become readable
+    fn poll_webtransport(&self) -> io::Result<Option<QuicSocketEvent>> {
+        let mut guard = self.webtransport.lock().unwrap();
+        let webtransport = &mut *guard;
+        if !webtransport.dedicated {
+            return Ok(None);
+        }
+
+        let mut rejected = false;
+        {
+            let mut quic_conn = self.quic_conn.lock().unwrap();
+            for stream_id in quic_conn.readable() {
+                if webtransport.streams.contains_key(&stream_id) {
+                    if webtransport.notified.insert(stream_id) {
+                        return Ok(Some(QuicSocketEvent::Readable(stream_id)));
                     }
+                    continue;
                 }
-            }
-            Ok((stream_id, h3::Event::Data)) => Ok(Some(QuicSocketEvent::Readable(stream_id))),
-            Ok((stream_id, h3::Event::Finished)) => Ok(Some(QuicSocketEvent::Close(stream_id))),
-            Ok((stream_id, h3::Event::Reset(err))) => {
-                log_id!(
-                    trace,
-                    self.id,
-                    "Stream reset by client: id={}, err={}",
+                if !webtransport.owns(stream_id) {
+                    continue;
+                }
+
+                let signal = read_webtransport_signal(
+                    &mut quic_conn,
                     stream_id,
-                    err
+                    webtransport.pending.entry(stream_id).or_default(),
                 );
-                Ok(Some(QuicSocketEvent::Close(stream_id)))
-            }
-            Ok((_, h3::Event::PriorityUpdate)) => Ok(None),
-            Ok((_, h3::Event::GoAway)) => {
-                Err(io::Error::new(ErrorKind::UnexpectedEof, "Received GOAWAY"))
+                match signal {
+                    Ok(None) => continue,
+                    Ok(Some((WEBTRANSPORT_STREAM_TYPE, session_id)))
+                        if webtransport.sessions.contains(&session_id) =>
+                    {
+                        webtransport.pending.remove(&stream_id);
+                        webtransport.streams.insert(stream_id, session_id);
+                        return Ok(Some(QuicSocketEvent::WebTransportStream(
+                            session_id, stream_id,
+                        )));
+                    }
+                    r => {
+                        log_id!(
+                            debug,
+                            self.id,
+                            "Rejecting stream of WebTransport connection: id={}, signal={:?}",
+                            stream_id,
+                            r
+                        );
+                        webtransport.pending.remove(&stream_id);
+                        let _ = quic_conn.stream_shutdown(
+                            stream_id,
+                            quiche::Shutdown::Read,
+                            H3_REQUEST_REJECTED,
+                        );
+                        let _ = quic_conn.stream_shutdown(
+                            stream_id,
+                            quiche::Shutdown::Write,
+                            H3_REQUEST_REJECTED,
+                        );
+                        rejected = true;
+                    }
+                }
             }
-            Err(h3::Error::Done) => Ok(None),
-            Err(e) => Err(io::Error::new(ErrorKind::Other, e.to_string())),
+        }
+
+        drop(guard);
+        if rejected {
+            self.flush_pending_data()?;
+        }
+        Ok(None)
+    }
+
+    fn process_pending_h3_events(&self) -> io::Result<Option<QuicSocketEvent>> {
+        if let Some(x) = self.poll_webtransport()? {
+            return Ok(Some(x));
+        }
+
+        loop {
+            break match self.poll_h3_connection() {
+                Ok((stream_id, h3::Event::Headers { list, .. })) => {
+                    {
+                        let mut webtransport = self.webtransport.lock().unwrap();
+                        webtransport.last_request_stream_id =
+                            webtransport.last_request_stream_id.max(Some(stream_id));
+                    }
+                    match self.on_request(stream_id, list) {
+                        Ok(QuicSocketEvent::Request(stream_id, request)) => {
+                            match http_codec::check_request_limits(
+                                &request,
+                                self.max_header_field_size,
+                                self.max_uri_length,
+                            ) {
+                                None => Ok(Some(QuicSocketEvent::Request(stream_id, request))),
+                                Some(status) => {
+                                    log_id!(
+                                        debug,
+                                        self.id,
+                                        "H3 request refused: id={}, status={}",
+                                        stream_id,
+                                        status
+                                    );
+                                    let _ = self.send_response(
+                                        stream_id,
+                                        http::Response::builder()
+                                            .status(status)
+                                            .body(())
+                                            .unwrap()
+                                            .into_parts()
+                                            .0,
+                                        true,
+                                    );
+                                    continue;
+                                }
+                            }
+                        }
+                        Ok(x) => Ok(Some(x)),
+                        Err(e) => {
+                            let _ = self.send_response(
+                                stream_id,
+                                http::Response::builder()
+                                    .status(http::StatusCode::BAD_REQUEST)
+                                    .body(())
+                                    .unwrap()
+                                    .into_parts()
+                                    .0,
+                                true,
+                            );
+                            Err(e)
+                        }
+                    }
+                }
+                Ok((stream_id, h3::Event::Data)) => Ok(Some(QuicSocketEvent::Readable(stream_id))),
+                Ok((stream_id, h3::Event::Finished)) => Ok(Some(QuicSocketEvent::Close(stream_id))),
+                Ok((stream_id, h3::Event::Reset(err))) => {
+                    log_id!(
+                        trace,
+                        self.id,
+                        "Stream reset by client: id={}, err={}",
+                        stream_id,
+                        err
+                    );
+                    Ok(Some(QuicSocketEvent::Reset(stream_id, err)))
+                }
+                Ok((_, h3::Event::PriorityUpdate)) => Ok(None),
+                Ok((_, h3::Event::GoAway)) => {
+                    Err(io::Error::new(ErrorKind::UnexpectedEof, "Received GOAWAY"))
+                }
+                Err(h3::Error::Done) => Ok(None),
+                Err(e) => Err(io::Error::new(ErrorKind::Other, e.to_string())),
+            };
         }
     }
 
@@ -1020,6 +1466,11 @@ impl QuicSocket {
         for h in headers {
             match h.name() {
                 b":method" => request_builder = request_builder.method(h.value()),
+                b":protocol" => {
+                    request_builder = request_builder.extension(http_codec::ConnectProtocol(
+                        String::from_utf8_lossy(h.value()).into_owned(),
+                    ))
+                }
                 b":scheme" => uri_builder = uri_builder.scheme(h.value()),
                 b":authority" => uri_builder = uri_builder.authority(h.value()),
                 b":path" => uri_builder = uri_builder.path_and_query(h.value()),
@@ -1047,6 +1498,15 @@ impl QuicSocket {
     }
 }
 
+impl WebTransport {
+    /// Whether the stream is taken over from the HTTP/3 layer, i.e., it is a client
+    /// bidirectional stream opened after the last request
+    fn owns(&self, stream_id: u64) -> bool {
+        self.streams.contains_key(&stream_id)
+            || (stream_id % 4 == 0 && self.last_request_stream_id.is_none_or(|x| stream_id > x))
+    }
+}
+
 impl HandshakingConnection {
     fn proceed_handshake(
         &self,
@@ -1084,16 +1544,44 @@ impl HandshakingConnection {
     }
 }
 
+/// Read the type and the session ID a WebTransport stream starts with. The stream is read
+/// byte by byte, not to consume the data following them.
+fn read_webtransport_signal(
+    quic_conn: &mut QuicConnection,
+    stream_id: u64,
+    buffer: &mut Vec<u8>,
+) -> quiche::Result<Option<(u64, u64)>> {
+    loop {
+        if let Some((stream_type, n)) = net_utils::get_varint(buffer) {
+            if let Some((session_id, _)) = net_utils::get_varint(&buffer[n..]) {
+                return Ok(Some((stream_type, session_id)));
+            }
+        }
+
+        let mut byte = [0; 1];
+        match quic_conn.stream_recv(stream_id, &mut byte) {
+            Ok((1, _)) => buffer.push(byte[0]),
+            // Finished before the signal
+            Ok(_) => return Err(quiche::Error::InvalidStreamState(stream_id)),
+            Err(quiche::Error::Done) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 fn flush_pending_data(
     quic_conn: &mut quiche::Connection,
     udp_socket: &UdpSocket,
     peer: &SocketAddr,
+    pacing: bool,
     id: &log_utils::IdChain<u64>,
 ) -> io::Result<()> {
     let mut out = [0; net_utils::MAX_UDP_PAYLOAD_SIZE];
     loop {
         match quic_conn.send(&mut out) {
-            Ok((n, _)) => udp_socket_send_to(udp_socket, &out[..n], peer, id)?,
+            Ok((n, info)) => {
+                udp_socket_send_to(udp_socket, &out[..n], peer, pacing.then_some(info.at), id)?
+            }
             Err(quiche::Error::Done) => break,
             Err(e) => return Err(io::Error::new(ErrorKind::Other, e.to_string())),
         }
@@ -1106,9 +1594,18 @@ fn udp_socket_send_to(
     socket: &UdpSocket,
     data: &[u8],
     peer: &SocketAddr,
+    send_at: Option<std::time::Instant>,
     id: &log_utils::IdChain<u64>,
 ) -> io::Result<()> {
-    match socket.try_send_to(data, *peer) {
+    let result = match send_at {
+        #[cfg(target_os = "linux")]
+        Some(at) => socket.try_io(tokio::io::Interest::WRITABLE, || {
+            use std::os::unix::io::AsRawFd;
+            net_utils::send_to_at(socket.as_raw_fd(), data, peer, at)
+        }),
+        _ => socket.try_send_to(data, *peer),
+    };
+    match result {
         Ok(_) => Ok(()),
         Err(e) if e.kind() == ErrorKind::WouldBlock || e.raw_os_error() == Some(libc::ENOBUFS) => {
             log_id!(
@@ -1223,12 +1720,83 @@ fn make_quic_config_with_domain_contexts(
     cfg.set_max_connection_window(quic_settings.max_connection_window);
     cfg.set_max_stream_window(quic_settings.max_stream_window);
     cfg.set_disable_active_migration(quic_settings.disable_active_migration);
+    cfg.discover_pmtu(quic_settings.discover_path_mtu);
+    cfg.enable_pacing(quic_settings.enable_pacing);
+    if let Some(x) = quic_settings.max_pacing_rate {
+        cfg.set_max_pacing_rate(x);
+    }
     if quic_settings.enable_early_data {
         cfg.enable_early_data();
     }
+    // The HTTP/3 layer advertises the HTTP datagram support once the transport enables them
+    cfg.enable_dgram(
+        quic_settings.enable_datagrams,
+        quic_settings.datagram_queue_length,
+        quic_settings.datagram_queue_length,
+    );
     Ok(cfg)
 }
 
+/// Choose the congestion control algorithm of a new connection.
+/// A client stays in the same cohort across its connections, as long as its address does not
+/// change. The IPv6 addresses are grouped by the /64 prefix to keep a client with the temporary
+/// addresses in one cohort.
+fn select_congestion_control(settings: &QuicSettings, client_ip: &IpAddr) -> CongestionControl {
+    let experiment = match settings.congestion_control_experiment.as_ref() {
+        None => return settings.congestion_control,
+        Some(x) => x,
+    };
+
+    let digest = match client_ip {
+        IpAddr::V4(x) => ring::digest::digest(&ring::digest::SHA256, &x.octets()),
+        IpAddr::V6(x) => ring::digest::digest(&ring::digest::SHA256, &x.octets()[..8]),
+    };
+    let bucket = u16::from_be_bytes([digest.as_ref()[0], digest.as_ref()[1]]) % 100;
+    if bucket < experiment.cohort_percentage as u16 {
+        experiment.algorithm
+    } else {
+        settings.congestion_control
+    }
+}
+
+impl CongestionControl {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CongestionControl::Reno => "reno",
+            CongestionControl::Cubic => "cubic",
+            CongestionControl::Bbr => "bbr",
+            CongestionControl::Bbr2 => "bbr2",
+        }
+    }
+}
+
+impl From<CongestionControl> for quiche::CongestionControlAlgorithm {
+    fn from(x: CongestionControl) -> Self {
+        match x {
+            CongestionControl::Reno => quiche::CongestionControlAlgorithm::Reno,
+            CongestionControl::Cubic => quiche::CongestionControlAlgorithm::CUBIC,
+            CongestionControl::Bbr => quiche::CongestionControlAlgorithm::BBR,
+            CongestionControl::Bbr2 => quiche::CongestionControlAlgorithm::BBR2,
+        }
+    }
+}
+
+/// Make the HTTP/3 header section, with the `:status` pseudo-header if `status` is set
+fn h3_headers<'a>(
+    status: Option<&'a http::StatusCode>,
+    headers: &'a http::HeaderMap,
+) -> Vec<h3::HeaderRef<'a>> {
+    status
+        .map(|x| h3::HeaderRef::new(b":status", x.as_str().as_bytes()))
+        .into_iter()
+        .chain(
+            headers
+                .iter()
+                .map(|(n, v)| h3::HeaderRef::new(n.as_ref(), v.as_ref())),
+        )
+        .collect()
+}
+
 fn socket_addr_to_vec(addr: &SocketAddr) -> Vec<u8> {
     match addr.ip() {
         std::net::IpAddr::V4(a) => a
@@ -1269,3 +1837,43 @@ fn validate_token<'a>(
         .and_then(|token| token.strip_prefix(socket_addr_to_vec(peer).as_slice()))
         .map(quiche::ConnectionId::from_ref)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn congestion_control_cohorts() {
+        let all = QuicSettings::builder()
+            .congestion_control_experiment(CongestionControl::Bbr2, 100)
+            .build();
+        let none = QuicSettings::builder()
+            .congestion_control_experiment(CongestionControl::Bbr2, 0)
+            .build();
+        let half = QuicSettings::builder()
+            .congestion_control_experiment(CongestionControl::Bbr2, 50)
+            .build();
+
+        let ips: Vec<IpAddr> = (0..=255).map(|x| [10, 0, 0, x].into()).collect();
+        assert!(ips
+            .iter()
+            .all(|x| select_congestion_control(&all, x) == CongestionControl::Bbr2));
+        assert!(ips
+            .iter()
+            .all(|x| select_congestion_control(&none, x) == CongestionControl::Cubic));
+        let experimental = ips
+            .iter()
+            .filter(|x| select_congestion_control(&half, x) == CongestionControl::Bbr2)
+            .count();
+        assert!((64..192).contains(&experimental), "{}", experimental);
+
+        for i in 0..16 {
+            let a: IpAddr = format!("2001:db8:0:{:x}::1", i).parse().unwrap();
+            let b: IpAddr = format!("2001:db8:0:{:x}:abcd::2", i).parse().unwrap();
+            assert_eq!(
+                select_congestion_control(&half, &a),
+                select_congestion_control(&half, &b)
+            );
+        }
+    }
+}
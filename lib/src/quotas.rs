@@ -0,0 +1,309 @@
+//! The data transfer quotas of the authenticated clients. The bytes the tunnels of a client
+//! transfer in both directions are accounted per day, the tunnel requests of a client which
+//! has used up its quota in the current period are rejected, and its open tunnels are closed.
+//! With the state store configured, the usage survives the endpoint restarts.
+
+use crate::authentication::{DataQuota, QuotaPeriod};
+use crate::state_store::StateStore;
+use chrono::Datelike;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// The state store namespace of the usage keyed by identity
+const NAMESPACE: &str = "data_usage";
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// The amount of data a tunnel transfers before it is accounted to the client usage
+const FLUSH_BYTES: u64 = 64 * 1024;
+/// The amount of the client usage which is not yet saved to the state store at most
+const SAVE_BYTES: u64 = 1024 * 1024;
+
+/// Keeps track of the data transferred by the clients with a quota
+pub(crate) struct QuotaTracker {
+    users: Mutex<HashMap<String, Arc<Usage>>>,
+    store: Option<Arc<StateStore>>,
+}
+
+struct Usage {
+    identity: String,
+    state: Mutex<UsageState>,
+    /// Whether the quota of the current period is used up
+    exceeded: watch::Sender<bool>,
+    store: Option<Arc<StateStore>>,
+}
+
+#[derive(Default)]
+struct UsageState {
+    /// The transferred bytes keyed by the number of days since the UNIX epoch
+    days: BTreeMap<u64, u64>,
+    /// The quota the latest tunnel of the client was admitted with
+    quota: Option<DataQuota>,
+    unsaved: u64,
+}
+
+/// Accounts the data of a tunnel to the client usage
+pub(crate) struct QuotaSession {
+    usage: Arc<Usage>,
+    pending: AtomicU64,
+}
+
+#[derive(Debug)]
+pub(crate) struct QuotaError {
+    identity: String,
+    quota: DataQuota,
+}
+
+impl Display for QuotaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Data quota of {} bytes used up by {}",
+            self.quota.bytes, self.identity
+        )
+    }
+}
+
+impl QuotaTracker {
+    pub fn new(store: Option<Arc<StateStore>>) -> Self {
+        let users = store
+            .iter()
+            .flat_map(|x| x.entries(NAMESPACE))
+            .map(|(identity, x)| {
+                let usage = Usage::new(identity.clone(), parse_days(&x), store.clone());
+                (identity, Arc::new(usage))
+            })
+            .collect();
+
+        Self {
+            users: Mutex::new(users),
+            store,
+        }
+    }
+
+    /// Start accounting a tunnel of the `identity` in case the quota is not used up
+    pub fn admit(
+        &self,
+        identity: String,
+        quota: DataQuota,
+    ) -> Result<Arc<QuotaSession>, QuotaError> {
+        let usage = self
+            .users
+            .lock()
+            .unwrap()
+            .entry(identity.clone())
+            .or_insert_with(|| {
+                Arc::new(Usage::new(
+                    identity.clone(),
+                    Default::default(),
+                    self.store.clone(),
+                ))
+            })
+            .clone();
+
+        let today = unix_now() / SECS_PER_DAY;
+        let mut state = usage.state.lock().unwrap();
+        state.quota = Some(quota);
+        let first_day = first_day(quota.period, today);
+        state.days.retain(|day, _| *day >= first_day);
+        let exceeded = state.days.values().sum::<u64>() >= quota.bytes;
+        usage.exceeded.send_replace(exceeded);
+        drop(state);
+
+        if exceeded {
+            return Err(QuotaError { identity, quota });
+        }
+        Ok(Arc::new(QuotaSession {
+            usage,
+            pending: Default::default(),
+        }))
+    }
+}
+
+impl Usage {
+    fn new(identity: String, days: BTreeMap<u64, u64>, store: Option<Arc<StateStore>>) -> Self {
+        Self {
+            identity,
+            state: Mutex::new(UsageState {
+                days,
+                ..Default::default()
+            }),
+            exceeded: watch::Sender::new(false),
+            store,
+        }
+    }
+
+    fn account(&self, n: u64) {
+        let today = unix_now() / SECS_PER_DAY;
+        let mut state = self.state.lock().unwrap();
+        *state.days.entry(today).or_default() += n;
+        state.unsaved += n;
+
+        if let Some(quota) = state.quota {
+            let first_day = first_day(quota.period, today);
+            let used: u64 = state.days.range(first_day..).map(|(_, x)| x).sum();
+            if used >= quota.bytes {
+                self.exceeded
+                    .send_if_modified(|x| !std::mem::replace(x, true));
+            }
+        }
+        if state.unsaved >= SAVE_BYTES {
+            self.save(&mut state);
+        }
+    }
+
+    fn save(&self, state: &mut UsageState) {
+        state.unsaved = 0;
+        let Some(store) = &self.store else {
+            return;
+        };
+        let days = match state.quota.map(|x| x.period) {
+            Some(QuotaPeriod::Rolling(n)) => n as u64,
+            _ => 31,
+        };
+        store.set(
+            NAMESPACE,
+            &self.identity,
+            format_days(&state.days),
+            Some(Duration::from_secs(days * SECS_PER_DAY)),
+        );
+    }
+}
+
+impl QuotaSession {
+    /// Account the data transferred by the tunnel
+    pub fn add(&self, n: usize) {
+        let pending = self.pending.fetch_add(n as u64, Ordering::AcqRel) + n as u64;
+        if pending >= FLUSH_BYTES {
+            self.usage.account(self.pending.swap(0, Ordering::AcqRel));
+        }
+    }
+
+    /// Wait until the quota of the client is used up.
+    /// Never completes for the tunnels without a session.
+    pub async fn exceeded(session: Option<&Self>) {
+        if let Some(x) = session {
+            let mut exceeded = x.usage.exceeded.subscribe();
+            if exceeded.wait_for(|x| *x).await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+impl Drop for QuotaSession {
+    fn drop(&mut self) {
+        let pending = *self.pending.get_mut();
+        if pending > 0 {
+            self.usage.account(pending);
+        }
+        let mut state = self.usage.state.lock().unwrap();
+        if state.unsaved > 0 {
+            self.usage.save(&mut state);
+        }
+    }
+}
+
+/// Get the first day of the period the `today` belongs to
+fn first_day(period: QuotaPeriod, today: u64) -> u64 {
+    match period {
+        QuotaPeriod::Monthly => chrono::DateTime::from_timestamp((today * SECS_PER_DAY) as i64, 0)
+            .map_or(today, |x| today + 1 - x.day() as u64),
+        QuotaPeriod::Rolling(n) => (today + 1).saturating_sub(n as u64),
+    }
+}
+
+fn format_days(days: &BTreeMap<u64, u64>) -> String {
+    days.iter()
+        .map(|(day, n)| format!("{}:{}", day, n))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_days(x: &str) -> BTreeMap<u64, u64> {
+    x.split(',')
+        .filter_map(|x| {
+            let (day, n) = x.split_once(':')?;
+            Some((day.parse().ok()?, n.parse().ok()?))
+        })
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods() {
+        // 2024-03-15
+        let today = 19797;
+        assert_eq!(19783, first_day(QuotaPeriod::Monthly, today));
+        assert_eq!(today, first_day(QuotaPeriod::Rolling(1), today));
+        assert_eq!(today - 29, first_day(QuotaPeriod::Rolling(30), today));
+    }
+
+    #[tokio::test]
+    async fn quota_is_enforced() {
+        let tracker = QuotaTracker::new(None);
+        let quota = DataQuota {
+            bytes: 2 * FLUSH_BYTES,
+            period: QuotaPeriod::Monthly,
+        };
+
+        let session = tracker.admit("alice".into(), quota).unwrap();
+        let _bob = tracker.admit("bob".into(), quota).unwrap();
+        session.add(FLUSH_BYTES as usize);
+        assert!(tracker.admit("alice".into(), quota).is_ok());
+
+        let exceeded = tokio::spawn({
+            let session = session.clone();
+            async move { QuotaSession::exceeded(Some(&session)).await }
+        });
+        session.add(FLUSH_BYTES as usize);
+        tokio::time::timeout(Duration::from_secs(1), exceeded)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(tracker.admit("alice".into(), quota).is_err());
+        assert!(tracker.admit("bob".into(), quota).is_ok());
+
+        // a bigger quota lets the client in again
+        let quota = DataQuota {
+            bytes: 3 * FLUSH_BYTES,
+            ..quota
+        };
+        assert!(tracker.admit("alice".into(), quota).is_ok());
+    }
+
+    #[test]
+    fn usage_is_persisted() {
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-quotas-{}.db", std::process::id()));
+        let store = Arc::new(StateStore::open(&path).unwrap());
+        let quota = DataQuota {
+            bytes: 1000,
+            period: QuotaPeriod::Rolling(7),
+        };
+
+        let session = QuotaTracker::new(Some(store.clone()))
+            .admit("alice".into(), quota)
+            .unwrap();
+        session.add(1000);
+        drop(session);
+
+        assert!(QuotaTracker::new(Some(store))
+            .admit("alice".into(), quota)
+            .is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
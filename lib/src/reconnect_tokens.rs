@@ -0,0 +1,309 @@
+//! The reconnect tokens. The successful tunnel responses to the requests authenticated with
+//! the proxy authorization carry a token, and a client presents it instead of its credentials
+//! in the requests of its next session, e.g., in the QUIC 0-RTT data of a reconnect after
+//! a network blip, so the authenticator backend is not asked again.
+//!
+//! A token is the expiration time and the credentials of the client sealed with
+//! ChaCha20-Poly1305 under the key of the endpoint, so redeeming it needs no state
+//! but the nonces of the redeemed tokens. A token is bound to the session it is first redeemed
+//! in: as the 0-RTT data may be replayed by anyone on the path, the token presented in another
+//! session is refused. The tokens issued in a session authenticated with a token expire along
+//! with it, so the credentials are passed by the authenticator again at least once per TTL,
+//! e.g., the token of a removed client is not renewed forever.
+
+use crate::authentication::Source;
+use crate::settings::ReconnectTokenSettings;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_ENGINE;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+/// The response header carrying a token, and the request header presenting it
+pub(crate) const RECONNECT_TOKEN_HEADER: &str = "x-trusttunnel-reconnect-token";
+/// Keeps the tokens from being taken for anything else sealed with the same key
+const AAD: &[u8] = b"trusttunnel-reconnect-token-v1";
+/// Keeps the key derived from the configured secret apart from the other uses of the secret
+const KEY_INFO: &[u8] = b"trusttunnel-reconnect-token-key";
+const KIND_BASIC: u8 = 1;
+const KIND_BEARER: u8 = 2;
+
+pub(crate) struct ReconnectTokens {
+    settings: ReconnectTokenSettings,
+    key: LessSafeKey,
+    random: SystemRandom,
+    /// The redeemed tokens keyed by their nonces
+    redeemed: Mutex<HashMap<[u8; NONCE_LEN], Redemption>>,
+}
+
+struct Redemption {
+    session: u64,
+    expires_at: u64,
+}
+
+/// A redeemed token
+#[derive(Debug, PartialEq)]
+pub(crate) struct Redeemed {
+    /// The credentials the token stands for
+    pub source: Source<'static>,
+    /// The UNIX time the token expires at, which the tokens issued to the session
+    /// it is redeemed in do not outlive
+    pub expires_at: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RedeemError {
+    /// The token is malformed, forged or sealed with another key
+    Invalid,
+    Expired,
+    /// The token has been redeemed in another session
+    Replayed,
+    /// There is no room to track the token
+    Full,
+}
+
+impl ReconnectTokens {
+    pub fn new(settings: &ReconnectTokenSettings) -> Self {
+        let random = SystemRandom::new();
+        let key = match &settings.secret {
+            Some(x) => hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+                .extract(x.as_bytes())
+                .expand(&[KEY_INFO], &CHACHA20_POLY1305)
+                .expect("Key length matches algorithm")
+                .into(),
+            None => {
+                let mut key = [0; 32];
+                random
+                    .fill(&mut key)
+                    .expect("Failed to generate reconnect token key");
+                UnboundKey::new(&CHACHA20_POLY1305, &key).expect("Key length matches algorithm")
+            }
+        };
+        Self {
+            settings: settings.clone(),
+            key: LessSafeKey::new(key),
+            random,
+            redeemed: Default::default(),
+        }
+    }
+
+    /// Seal the credentials into a token valid for [`ReconnectTokenSettings::ttl`] since `now`,
+    /// but not after `valid_till`, the expiration time of the credentials, or the expiration
+    /// time of the token the session is authenticated with, see [`Redeemed::expires_at`].
+    /// [`None`] if the credentials are not the proxy authorization.
+    pub fn issue(&self, source: &Source<'_>, valid_till: Option<u64>, now: u64) -> Option<String> {
+        let (kind, credentials) = match source {
+            Source::ProxyBasic(x) => (KIND_BASIC, x),
+            Source::ProxyBearer(x) => (KIND_BEARER, x),
+            // A digest is bound to the nonce, so it can't be presented again
+            Source::Sni(_) | Source::ProxyDigest(_) | Source::ClientCert(_) => return None,
+        };
+        let expires_at = now
+            .saturating_add(self.settings.ttl.as_secs())
+            .min(valid_till.unwrap_or(u64::MAX));
+
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+        let mut data = Vec::with_capacity(NONCE_LEN + 9 + credentials.len() + 16);
+        data.extend_from_slice(&expires_at.to_be_bytes());
+        data.push(kind);
+        data.extend_from_slice(credentials.as_bytes());
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(AAD),
+                &mut data,
+            )
+            .ok()?;
+
+        let mut token = nonce.to_vec();
+        token.append(&mut data);
+        Some(BASE64_ENGINE.encode(token))
+    }
+
+    /// Get the credentials the token stands for, binding the token to the `session`
+    pub fn redeem(&self, token: &str, session: u64, now: u64) -> Result<Redeemed, RedeemError> {
+        let mut data = BASE64_ENGINE
+            .decode(token)
+            .map_err(|_| RedeemError::Invalid)?;
+        if data.len() < NONCE_LEN {
+            return Err(RedeemError::Invalid);
+        }
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = data.try_into().map_err(|_| RedeemError::Invalid)?;
+        let plain = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(AAD),
+                &mut sealed,
+            )
+            .map_err(|_| RedeemError::Invalid)?;
+        if plain.len() < 9 {
+            return Err(RedeemError::Invalid);
+        }
+        let (expires_at, rest) = plain.split_at(8);
+        let expires_at = u64::from_be_bytes(expires_at.try_into().unwrap());
+        if now > expires_at {
+            return Err(RedeemError::Expired);
+        }
+        let credentials =
+            String::from_utf8(rest[1..].to_vec()).map_err(|_| RedeemError::Invalid)?;
+        let source = match rest[0] {
+            KIND_BASIC => Source::ProxyBasic(Cow::Owned(credentials)),
+            KIND_BEARER => Source::ProxyBearer(Cow::Owned(credentials)),
+            _ => return Err(RedeemError::Invalid),
+        };
+
+        let source = Redeemed { source, expires_at };
+
+        let mut redeemed = self.redeemed.lock().unwrap();
+        match redeemed.get(&nonce) {
+            Some(x) if x.session == session => return Ok(source),
+            Some(_) => return Err(RedeemError::Replayed),
+            None => (),
+        }
+        if redeemed.len() >= self.settings.max_redeemed {
+            redeemed.retain(|_, x| now <= x.expires_at);
+            if redeemed.len() >= self.settings.max_redeemed {
+                return Err(RedeemError::Full);
+            }
+        }
+        redeemed.insert(
+            nonce,
+            Redemption {
+                session,
+                expires_at,
+            },
+        );
+        Ok(source)
+    }
+}
+
+impl Display for RedeemError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "Invalid token"),
+            Self::Expired => write!(f, "Token has expired"),
+            Self::Replayed => write!(f, "Token is redeemed in another session"),
+            Self::Full => write!(f, "Too many redeemed tokens"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn tokens(settings: ReconnectTokenSettings) -> ReconnectTokens {
+        ReconnectTokens::new(&settings)
+    }
+
+    #[test]
+    fn redeems_once_per_session() {
+        let tokens = tokens(
+            ReconnectTokenSettings::builder()
+                .ttl(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        let source = Source::ProxyBasic(Cow::Borrowed("YWxpY2U6c2VjcmV0"));
+        let token = tokens.issue(&source, None, NOW).unwrap();
+        assert!(!token.contains("YWxpY2U6c2VjcmV0"));
+
+        assert_eq!(
+            Ok(Redeemed {
+                source: source.clone().into_owned(),
+                expires_at: NOW + 60,
+            }),
+            tokens.redeem(&token, 1, NOW + 10)
+        );
+        // The requests of the same session present the token again
+        assert!(tokens.redeem(&token, 1, NOW + 20).is_ok());
+        assert_eq!(
+            Err(RedeemError::Replayed),
+            tokens.redeem(&token, 2, NOW + 20)
+        );
+        assert_eq!(
+            Err(RedeemError::Expired),
+            tokens.redeem(&tokens.issue(&source, None, NOW).unwrap(), 3, NOW + 61)
+        );
+
+        let mut forged = BASE64_ENGINE.decode(&token).unwrap();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Err(RedeemError::Invalid),
+            tokens.redeem(&BASE64_ENGINE.encode(forged), 4, NOW)
+        );
+        assert_eq!(Err(RedeemError::Invalid), tokens.redeem("AAAA", 4, NOW));
+        assert_eq!(
+            None,
+            tokens.issue(&Source::Sni(Cow::Borrowed("alice")), None, NOW)
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let with_secret = || {
+            tokens(
+                ReconnectTokenSettings::builder()
+                    .secret("0123456789abcdef".to_string())
+                    .max_redeemed(1)
+                    .build()
+                    .unwrap(),
+            )
+        };
+        let source = Source::ProxyBearer(Cow::Borrowed("header.payload.signature"));
+        // The credentials expiring earlier cut the token validity short
+        let token = with_secret().issue(&source, Some(NOW + 5), NOW).unwrap();
+
+        let other_instance = with_secret();
+        assert!(other_instance.redeem(&token, 1, NOW).is_ok());
+        assert_eq!(
+            Err(RedeemError::Full),
+            other_instance.redeem(&with_secret().issue(&source, None, NOW).unwrap(), 2, NOW)
+        );
+        // The expired redemptions make room for the new ones
+        assert!(other_instance
+            .redeem(
+                &with_secret().issue(&source, None, NOW).unwrap(),
+                2,
+                NOW + 6
+            )
+            .is_ok());
+        assert_eq!(
+            Err(RedeemError::Invalid),
+            tokens(ReconnectTokenSettings::builder().build().unwrap()).redeem(&token, 1, NOW)
+        );
+    }
+
+    #[test]
+    fn redeemed_token_is_not_renewed() {
+        let tokens = tokens(
+            ReconnectTokenSettings::builder()
+                .ttl(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        // The client is removed since, so the authenticator knows no expiration time of it
+        let source = Source::ProxyBasic(Cow::Borrowed("YWxpY2U6c2VjcmV0"));
+        let mut token = tokens.issue(&source, None, NOW).unwrap();
+        for (session, now) in [(1, NOW + 20), (2, NOW + 40)] {
+            let redeemed = tokens.redeem(&token, session, now).unwrap();
+            assert_eq!(NOW + 60, redeemed.expires_at);
+            token = tokens
+                .issue(&redeemed.source, Some(redeemed.expires_at), now)
+                .unwrap();
+        }
+        assert_eq!(
+            Err(RedeemError::Expired),
+            tokens.redeem(&token, 3, NOW + 61)
+        );
+    }
+}
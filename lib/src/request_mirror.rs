@@ -0,0 +1,215 @@
+use crate::forwarder::TcpConnector;
+use crate::net_utils::TcpDestination;
+use crate::settings::RequestMirrorSettings;
+use crate::tcp_forwarder::TcpForwarder;
+use crate::{core, forwarder, http1_codec, http_codec, log_id, log_utils, pipe};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use ring::rand::SecureRandom;
+use std::io;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+/// Check whether a request falls into the mirrored share
+pub(crate) fn is_sampled(settings: &RequestMirrorSettings) -> bool {
+    let mut x = [0; 1];
+    ring::rand::SystemRandom::new().fill(&mut x).unwrap();
+    sample(settings.percentage, x[0])
+}
+
+fn sample(percentage: u8, random: u8) -> bool {
+    (random as u32 * 100) < (percentage as u32 * 256)
+}
+
+/// Copies the request written into the origin server connection,
+/// and sends the copy to the mirror server once the request is complete.
+/// The origin server connection is not affected by the mirror in any way.
+struct MirroringSink {
+    sink: Box<dyn pipe::Sink>,
+    context: Arc<core::Context>,
+    /// The copy of the request, [`None`] if the request is too large to be mirrored
+    request: Option<BytesMut>,
+}
+
+/// Start mirroring the request with the encoded head `head`.
+/// Returns the sink which must be used to forward the request body to the origin server.
+pub(crate) fn wrap(
+    context: Arc<core::Context>,
+    sink: Box<dyn pipe::Sink>,
+    request: &http_codec::RequestHeaders,
+    head: &Bytes,
+) -> Box<dyn pipe::Sink> {
+    let settings = context
+        .settings
+        .reverse_proxy
+        .as_ref()
+        .and_then(|x| x.mirror.as_ref())
+        .unwrap();
+    if head.len() > settings.max_request_size {
+        log_id!(trace, sink.id(), "Request is too large to be mirrored");
+        return sink;
+    }
+
+    if !has_body(request) {
+        tokio::spawn(send(context.clone(), head.clone(), sink.id()));
+        return sink;
+    }
+
+    Box::new(MirroringSink {
+        context,
+        request: Some(BytesMut::from(head.as_ref())),
+        sink,
+    })
+}
+
+/// Figure out whether the request head is followed by a body
+fn has_body(request: &http_codec::RequestHeaders) -> bool {
+    request
+        .headers
+        .contains_key(http::header::TRANSFER_ENCODING)
+        || request
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<u64>().ok())
+            .is_some_and(|x| x > 0)
+}
+
+impl MirroringSink {
+    fn copy(&mut self, data: &[u8]) {
+        let max_size = self
+            .context
+            .settings
+            .reverse_proxy
+            .as_ref()
+            .and_then(|x| x.mirror.as_ref())
+            .unwrap()
+            .max_request_size;
+        if let Some(buffer) = self.request.as_mut() {
+            if buffer.len() + data.len() > max_size {
+                log_id!(trace, self.sink.id(), "Request is too large to be mirrored");
+                self.request = None;
+            } else {
+                buffer.put_slice(data);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl pipe::Sink for MirroringSink {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.sink.id()
+    }
+
+    fn write(&mut self, mut data: Bytes) -> io::Result<Bytes> {
+        let unsent = self.sink.write(data.clone())?;
+        let written = data.split_to(data.len() - unsent.len());
+        self.copy(&written);
+        Ok(unsent)
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        if let Some(x) = self.request.take() {
+            tokio::spawn(send(self.context.clone(), x.freeze(), self.id()));
+        }
+        self.sink.eof()
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        self.sink.wait_writable().await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush().await
+    }
+}
+
+/// Send the request copy to the mirror server and discard the response
+async fn send(context: Arc<core::Context>, request: Bytes, log_id: log_utils::IdChain<u64>) {
+    let timeout = context.settings.tcp_connections_timeout;
+    match tokio::time::timeout(timeout, exchange(context, request, &log_id)).await {
+        Ok(Ok(status)) => log_id!(trace, log_id, "Mirror server responded: {}", status),
+        Ok(Err(e)) => log_id!(debug, log_id, "Failed to mirror request: {}", e),
+        Err(_elapsed) => log_id!(debug, log_id, "Mirror server did not respond in time"),
+    }
+}
+
+async fn exchange(
+    context: Arc<core::Context>,
+    mut request: Bytes,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<http::StatusCode> {
+    let settings = context.settings.reverse_proxy.as_ref().unwrap();
+    let server_address = settings.mirror.as_ref().unwrap().server_address;
+    let forwarder = Box::new(TcpForwarder::new(context.clone()));
+    let (mut source, mut sink) = forwarder
+        .connect(
+            log_id.clone(),
+            forwarder::TcpConnectionMeta {
+                client_address: Ipv4Addr::UNSPECIFIED.into(),
+                destination: TcpDestination::Address(server_address),
+                auth: None,
+                tls_domain: Default::default(),
+                user_agent: None,
+                destination_acl: None,
+                race_uplinks: vec![],
+            },
+        )
+        .await
+        .map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))?;
+
+    while !request.is_empty() {
+        sink.wait_writable().await?;
+        request = sink.write(request)?;
+    }
+
+    let mut buffer = BytesMut::new();
+    loop {
+        match source.read().await? {
+            pipe::Data::Chunk(chunk) => {
+                source.consume(chunk.len())?;
+                buffer.put(chunk);
+            }
+            pipe::Data::Eof => return Err(ErrorKind::UnexpectedEof.into()),
+        }
+
+        match http1_codec::decode_response(
+            buffer,
+            http1_codec::MAX_HEADERS_NUM,
+            http1_codec::MAX_RAW_HEADERS_SIZE,
+        )? {
+            http1_codec::DecodeStatus::Partial(b) => buffer = b,
+            http1_codec::DecodeStatus::Complete(h, _) => break Ok(h.status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        assert!((0..=u8::MAX).all(|x| !sample(0, x)));
+        assert!((0..=u8::MAX).all(|x| sample(100, x)));
+        assert_eq!(128, (0..=u8::MAX).filter(|x| sample(50, *x)).count());
+    }
+
+    #[test]
+    fn request_bodies() {
+        let request = |headers: &[(&str, &str)]| {
+            let mut builder = http::Request::post("/");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(()).unwrap().into_parts().0
+        };
+
+        assert!(!has_body(&request(&[])));
+        assert!(!has_body(&request(&[("content-length", "0")])));
+        assert!(has_body(&request(&[("content-length", "10")])));
+        assert!(has_body(&request(&[("transfer-encoding", "chunked")])));
+    }
+}
@@ -0,0 +1,710 @@
+use crate::http_codec::{RequestHeaders, ResponseHeaders};
+use crate::settings::ResponseCacheSettings;
+use crate::{http1_codec, utils};
+use bytes::{Bytes, BytesMut};
+use ring::digest;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DISK_ENTRY_MAGIC: &str = "TTCACHE1";
+const DISK_MAX_HEADERS_NUM: usize = 128;
+const DISK_MAX_RAW_HEADERS_SIZE: usize = 64 * 1024;
+
+/// An HTTP cache of the reverse-proxied `GET` responses.
+///
+/// The responses are kept in memory and, optionally, on the disk. Both tiers are bounded
+/// in size and evict the least recently used entries first. A response evicted from the memory
+/// is still served from the disk tier.
+pub(crate) struct ResponseCache {
+    max_entry_size: usize,
+    memory: Mutex<Lru<Arc<CachedResponse>>>,
+    disk: Option<DiskTier>,
+}
+
+/// A stored response
+#[derive(Debug)]
+pub(crate) struct CachedResponse {
+    host: String,
+    path: String,
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Bytes,
+    stored_at: SystemTime,
+    /// The freshness lifetime of the response
+    freshness: Duration,
+}
+
+struct DiskTier {
+    dir: PathBuf,
+    /// The hosts and the paths of the stored responses keyed by the file names
+    index: Mutex<Lru<(String, String)>>,
+}
+
+/// The least recently used entries tracker
+struct Lru<V> {
+    entries: HashMap<String, LruEntry<V>>,
+    order: BTreeMap<u64, String>,
+    size: usize,
+    capacity: usize,
+    next_tick: u64,
+}
+
+struct LruEntry<V> {
+    value: V,
+    size: usize,
+    tick: u64,
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl ResponseCache {
+    pub fn new(settings: &ResponseCacheSettings) -> Self {
+        let disk = settings.disk_path.as_ref().map(|x| {
+            let dir = PathBuf::from(x);
+            let mut index = Lru::new(settings.max_disk_size);
+            if let Err(e) = load_disk_index(&dir, &mut index) {
+                warn!(
+                    "Failed to load response cache from {}: {}",
+                    dir.display(),
+                    e
+                );
+            }
+            DiskTier {
+                dir,
+                index: Mutex::new(index),
+            }
+        });
+
+        Self {
+            max_entry_size: settings.max_entry_size,
+            memory: Mutex::new(Lru::new(settings.max_memory_size)),
+            disk,
+        }
+    }
+
+    /// Get the key of the request in case its response may be served from the cache
+    pub fn cache_key(request: &RequestHeaders) -> Option<String> {
+        if request.method != http::Method::GET
+            || request.headers.contains_key(http::header::AUTHORIZATION)
+            || parse_cache_control(&request.headers).no_store
+        {
+            return None;
+        }
+
+        Some(format!(
+            "{} {} {}",
+            request_host(request),
+            request
+                .uri
+                .path_and_query()
+                .map(http::uri::PathAndQuery::as_str)
+                .unwrap_or("/"),
+            request
+                .headers
+                .get(http::header::ACCEPT_ENCODING)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default(),
+        ))
+    }
+
+    /// Check whether the client demands the stored response to be validated by the origin
+    /// server before using
+    pub fn must_revalidate(request: &RequestHeaders) -> bool {
+        parse_cache_control(&request.headers).no_cache
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        if let Some(x) = self.memory.lock().unwrap().get(key) {
+            return Some(x.clone());
+        }
+
+        let disk = self.disk.as_ref()?;
+        let name = file_name(key);
+        disk.index.lock().unwrap().get(&name)?;
+        let path = disk.dir.join(&name);
+        let entry = match tokio::task::spawn_blocking(move || read_disk_entry(&path)).await {
+            Ok(Ok(x)) => Arc::new(x),
+            Ok(Err(e)) => {
+                debug!("Dropping unreadable cache entry: {}", e);
+                disk.index.lock().unwrap().remove(&name);
+                return None;
+            }
+            Err(_) => return None,
+        };
+
+        let size = entry.size();
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), entry.clone(), size);
+        Some(entry)
+    }
+
+    /// Check whether the response may be stored.
+    ///
+    /// # Return
+    ///
+    /// The freshness lifetime of the response
+    pub fn storable(&self, response: &ResponseHeaders) -> Option<Duration> {
+        if response.status != http::StatusCode::OK
+            || response.headers.contains_key(http::header::SET_COOKIE)
+        {
+            return None;
+        }
+        if response
+            .headers
+            .get_all(http::header::VARY)
+            .iter()
+            .flat_map(|x| x.to_str().unwrap_or("*").split(','))
+            .any(|x| !x.trim().eq_ignore_ascii_case("accept-encoding"))
+        {
+            return None;
+        }
+        if content_length(&response.headers).is_none_or(|x| x > self.max_entry_size) {
+            return None;
+        }
+
+        freshness(&response.headers)
+    }
+
+    pub fn insert(&self, key: String, response: CachedResponse) -> Arc<CachedResponse> {
+        let response = Arc::new(response);
+        let size = response.size();
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.clone(), response.clone(), size);
+
+        if let Some(disk) = &self.disk {
+            let name = file_name(&key);
+            let evicted = disk.index.lock().unwrap().insert(
+                name.clone(),
+                (response.host.clone(), response.path.clone()),
+                size,
+            );
+            let paths = evicted
+                .iter()
+                .map(|(x, _)| disk.dir.join(x))
+                .collect::<Vec<_>>();
+            let path = disk.dir.join(name);
+            let response = response.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = write_disk_entry(&path, &response) {
+                    debug!("Failed to write cache entry: {}", e);
+                }
+                paths.iter().for_each(|x| {
+                    let _ = fs::remove_file(x);
+                });
+            });
+        }
+
+        response
+    }
+
+    /// Drop the entries with the path starting with `path_prefix` and, if specified,
+    /// with the matching host.
+    ///
+    /// # Return
+    ///
+    /// The number of the dropped entries
+    pub fn purge(&self, host: Option<&str>, path_prefix: &str) -> usize {
+        let matches = |h: &str, p: &str| {
+            host.is_none_or(|x| x.eq_ignore_ascii_case(h)) && p.starts_with(path_prefix)
+        };
+
+        let mut purged = self
+            .memory
+            .lock()
+            .unwrap()
+            .remove_if(|x| matches(&x.host, &x.path))
+            .len();
+
+        if let Some(disk) = &self.disk {
+            let removed = disk.index.lock().unwrap().remove_if(|(h, p)| matches(h, p));
+            purged = purged.max(removed.len());
+            for (name, _) in removed {
+                let _ = fs::remove_file(disk.dir.join(name));
+            }
+        }
+
+        purged
+    }
+}
+
+impl CachedResponse {
+    pub fn new(
+        request: &RequestHeaders,
+        status: http::StatusCode,
+        mut headers: http::HeaderMap,
+        body: Bytes,
+        freshness: Duration,
+    ) -> Self {
+        let freshness = freshness.saturating_sub(age_header(&headers));
+        headers.remove(http::header::AGE);
+        Self {
+            host: request_host(request).to_string(),
+            path: request.uri.path().to_string(),
+            status,
+            headers,
+            body,
+            stored_at: SystemTime::now(),
+            freshness,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.freshness
+    }
+
+    pub fn etag(&self) -> Option<&http::HeaderValue> {
+        self.headers.get(http::header::ETAG)
+    }
+
+    /// Check whether the client already has the actual version of the response
+    pub fn is_not_modified_for(&self, request: &RequestHeaders) -> bool {
+        let etag = match self.etag().and_then(|x| x.to_str().ok()) {
+            Some(x) => x.trim_start_matches("W/"),
+            None => return false,
+        };
+        request
+            .headers
+            .get_all(http::header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(|x| x.trim().trim_start_matches("W/"))
+            .any(|x| x == "*" || x == etag)
+    }
+
+    pub fn body(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    /// Make the response headers to be sent to a client
+    pub fn response(&self, version: http::Version) -> ResponseHeaders {
+        let mut response = http::Response::builder()
+            .version(version)
+            .status(self.status)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        response.headers = self.headers.clone();
+        response
+            .headers
+            .insert(http::header::AGE, self.age().as_secs().into());
+        response
+    }
+
+    /// Make a copy of the response validated by the origin server with the `not_modified`
+    /// response
+    pub fn revalidated(&self, not_modified: &ResponseHeaders) -> Self {
+        let mut headers = self.headers.clone();
+        for name in [
+            http::header::CACHE_CONTROL,
+            http::header::DATE,
+            http::header::ETAG,
+            http::header::EXPIRES,
+        ] {
+            if let Some(x) = not_modified.headers.get(&name) {
+                headers.insert(name, x.clone());
+            }
+        }
+
+        Self {
+            host: self.host.clone(),
+            path: self.path.clone(),
+            status: self.status,
+            freshness: freshness(&headers).unwrap_or(self.freshness),
+            headers,
+            body: self.body.clone(),
+            stored_at: SystemTime::now(),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.stored_at)
+            .unwrap_or_default()
+    }
+
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.as_str().len() + v.len())
+                .sum::<usize>()
+    }
+}
+
+impl<V> Lru<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            order: Default::default(),
+            size: 0,
+            capacity,
+            next_tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        let tick = self.next_tick;
+        let entry = self.entries.get_mut(key)?;
+        self.next_tick += 1;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.to_string());
+        entry.tick = tick;
+        Some(&entry.value)
+    }
+
+    /// Insert the entry evicting the least recently used ones in case the capacity is exceeded.
+    /// An entry larger than the capacity is not inserted.
+    ///
+    /// # Return
+    ///
+    /// The evicted entries
+    fn insert(&mut self, key: String, value: V, size: usize) -> Vec<(String, V)> {
+        self.remove(&key);
+        if size > self.capacity {
+            return vec![];
+        }
+
+        let mut evicted = vec![];
+        while self.size + size > self.capacity {
+            let (_, oldest) = match self.order.pop_first() {
+                None => break,
+                Some(x) => x,
+            };
+            if let Some(x) = self.entries.remove(&oldest) {
+                self.size -= x.size;
+                evicted.push((oldest, x.value));
+            }
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, LruEntry { value, size, tick });
+        self.size += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+
+    fn remove_if<F: Fn(&V) -> bool>(&mut self, predicate: F) -> Vec<(String, V)> {
+        let keys = self
+            .entries
+            .iter()
+            .filter(|(_, x)| predicate(&x.value))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|k| self.remove(&k).map(|v| (k, v)))
+            .collect()
+    }
+}
+
+fn parse_cache_control(headers: &http::HeaderMap) -> CacheControl {
+    let mut result = CacheControl::default();
+    for directive in headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+    {
+        let (name, value) = match directive.split_once('=') {
+            Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" => result.no_store = true,
+            "no-cache" => result.no_cache = true,
+            "private" => result.private = true,
+            "max-age" => result.max_age = value.and_then(|x| x.parse().ok()),
+            "s-maxage" => result.s_maxage = value.and_then(|x| x.parse().ok()),
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Get the freshness lifetime of the response according to its headers.
+/// A response without an explicit lifetime is stored only in case it can be revalidated.
+fn freshness(headers: &http::HeaderMap) -> Option<Duration> {
+    let cache_control = parse_cache_control(headers);
+    if cache_control.no_store || cache_control.private {
+        return None;
+    }
+
+    let lifetime = if cache_control.no_cache {
+        None
+    } else {
+        cache_control.s_maxage.or(cache_control.max_age)
+    };
+    match lifetime {
+        Some(x) => Some(Duration::from_secs(x)),
+        None if headers.contains_key(http::header::ETAG) => Some(Duration::ZERO),
+        None => None,
+    }
+}
+
+/// Get the name of the file the response is stored in on the disk.
+/// The disk tier is keyed by these names.
+fn file_name(key: &str) -> String {
+    utils::hex_dump(digest::digest(&digest::SHA256, key.as_bytes()).as_ref())
+}
+
+fn request_host(request: &RequestHeaders) -> &str {
+    request
+        .uri
+        .host()
+        .or_else(|| {
+            request
+                .headers
+                .get(http::header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.split(':').next().unwrap_or(x))
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn content_length(headers: &http::HeaderMap) -> Option<usize> {
+    headers
+        .get(http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn age_header(headers: &http::HeaderMap) -> Duration {
+    headers
+        .get(http::header::AGE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
+
+fn unix_secs(x: SystemTime) -> u64 {
+    x.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+fn write_disk_entry(path: &Path, response: &CachedResponse) -> io::Result<()> {
+    let head = http1_codec::encode_response(response.response(http::Version::HTTP_11));
+    let mut content = format!(
+        "{} {} {} {} {}\n",
+        DISK_ENTRY_MAGIC,
+        unix_secs(response.stored_at),
+        response.freshness.as_secs(),
+        response.host,
+        response.path,
+    )
+    .into_bytes();
+    content.extend_from_slice(&head);
+    content.extend_from_slice(&response.body);
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn read_disk_entry(path: &Path) -> io::Result<CachedResponse> {
+    let content = fs::read(path)?;
+    let invalid = |x: &str| io::Error::new(ErrorKind::InvalidData, x.to_string());
+
+    let newline = content
+        .iter()
+        .position(|x| *x == b'\n')
+        .ok_or_else(|| invalid("No meta line"))?;
+    let meta = std::str::from_utf8(&content[..newline]).map_err(|_| invalid("Bad meta line"))?;
+    let mut fields = meta.split(' ');
+    if fields.next() != Some(DISK_ENTRY_MAGIC) {
+        return Err(invalid("Bad magic"));
+    }
+    let mut next_number = || -> io::Result<u64> {
+        fields
+            .next()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| invalid("Bad meta line"))
+    };
+    let stored_at = UNIX_EPOCH + Duration::from_secs(next_number()?);
+    let freshness = Duration::from_secs(next_number()?);
+    let (host, path) = match (fields.next(), fields.next()) {
+        (Some(h), Some(p)) => (h.to_string(), p.to_string()),
+        _ => return Err(invalid("Bad meta line")),
+    };
+
+    let (mut head, body) = match http1_codec::decode_response(
+        BytesMut::from(&content[newline + 1..]),
+        DISK_MAX_HEADERS_NUM,
+        DISK_MAX_RAW_HEADERS_SIZE,
+    )? {
+        http1_codec::DecodeStatus::Complete(h, b) => (h, b.freeze()),
+        http1_codec::DecodeStatus::Partial(_) => return Err(invalid("Truncated headers")),
+    };
+    if content_length(&head.headers) != Some(body.len()) {
+        return Err(invalid("Truncated body"));
+    }
+    head.headers.remove(http::header::AGE);
+
+    Ok(CachedResponse {
+        host,
+        path,
+        status: head.status,
+        headers: head.headers,
+        body,
+        stored_at,
+        freshness,
+    })
+}
+
+fn load_disk_index(dir: &Path, index: &mut Lru<(String, String)>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let mut entries = vec![];
+    for x in fs::read_dir(dir)? {
+        let x = x?;
+        let path = x.path();
+        match read_disk_entry(&path) {
+            Ok(response) => {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                entries.push((response.stored_at, name, response));
+            }
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    entries.sort_by_key(|(stored_at, ..)| *stored_at);
+    for (_, name, response) in entries {
+        let size = response.size();
+        index.insert(name, (response.host, response.path), size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_response(headers: &[(&str, &str)]) -> ResponseHeaders {
+        let mut builder = http::Response::builder().status(http::StatusCode::OK);
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn response_freshness() {
+        let cache = ResponseCache::new(&ResponseCacheSettings::builder().build().unwrap());
+        let storable = |x: &[(&str, &str)]| cache.storable(&make_response(x));
+
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            storable(&[
+                ("content-length", "10"),
+                ("cache-control", "public, max-age=10, s-maxage=60")
+            ])
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            storable(&[("content-length", "10"), ("etag", "\"x\"")])
+        );
+        assert_eq!(
+            None,
+            storable(&[
+                ("content-length", "10"),
+                ("cache-control", "private, max-age=60")
+            ])
+        );
+        assert_eq!(None, storable(&[("cache-control", "max-age=60")]));
+        assert_eq!(
+            None,
+            storable(&[
+                ("content-length", "10"),
+                ("cache-control", "max-age=60"),
+                ("vary", "cookie")
+            ])
+        );
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(10);
+        lru.insert("a".into(), 1, 4);
+        lru.insert("b".into(), 2, 4);
+        lru.get("a");
+
+        let evicted = lru.insert("c".into(), 3, 4);
+        assert_eq!(vec![("b".to_string(), 2)], evicted);
+        assert!(lru.get("a").is_some());
+        assert_eq!(8, lru.size);
+
+        assert!(lru.insert("d".into(), 4, 11).is_empty());
+        assert!(lru.get("d").is_none());
+    }
+
+    #[tokio::test]
+    async fn disk_tier_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("trusttunnel-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = ResponseCacheSettings::builder()
+            .disk_path(dir.to_str().unwrap())
+            .build()
+            .unwrap();
+
+        let request = http::Request::get("https://example.org/static/app.js")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let key = ResponseCache::cache_key(&request).unwrap();
+        let response = make_response(&[("content-length", "5"), ("cache-control", "max-age=60")]);
+        write_disk_entry(
+            &dir.join(file_name(&key)),
+            &CachedResponse::new(
+                &request,
+                response.status,
+                response.headers,
+                Bytes::from_static(b"hello"),
+                Duration::from_secs(60),
+            ),
+        )
+        .unwrap();
+
+        let cache = ResponseCache::new(&settings);
+        let cached = cache.get(&key).await.unwrap();
+        assert!(cached.is_fresh());
+        assert_eq!(Bytes::from_static(b"hello"), cached.body());
+
+        assert_eq!(1, cache.purge(Some("example.org"), "/static"));
+        assert!(cache.get(&key).await.is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
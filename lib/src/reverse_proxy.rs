@@ -1,25 +1,47 @@
 use crate::forwarder::TcpConnector;
-use crate::http_codec::HttpCodec;
+use crate::http_codec::{BodyReader, BodyWriter, Cancellation, HeaderInjectingRespond, HttpCodec};
 use crate::net_utils::TcpDestination;
 use crate::pipe::DuplexPipe;
+use crate::response_cache::{CachedResponse, ResponseCache};
+use crate::settings::ReverseProxySettings;
 use crate::tcp_forwarder::TcpForwarder;
 use crate::tls_demultiplexer::Protocol;
-use crate::{core, forwarder, http1_codec, http_codec, log_id, log_utils, pipe, tunnel};
-use bytes::{BufMut, BytesMut};
+use crate::{
+    core, forwarder, http1_codec, http_codec, http_forwarded_stream, log_id, log_utils, net_utils,
+    pipe, proxy_protocol, request_mirror, response_cache, static_files, tunnel, upstream_tls,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
 
 static ORIGINAL_PROTOCOL_HEADER: http::HeaderName =
     http::HeaderName::from_static("x-original-protocol");
+static X_FORWARDED_FOR_HEADER: http::HeaderName = http::HeaderName::from_static("x-forwarded-for");
 
 #[derive(Default)]
 struct SessionManager {
     active_streams_num: AtomicUsize,
 }
 
+/// The way a proxied request has ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CloseReason {
+    /// The client has got the response, be it an error one
+    Completed,
+    /// The client has cancelled the request, e.g., by resetting the HTTP/3 stream
+    ClientCancelled,
+    /// The origin server or the client has been idle for too long
+    TimedOut,
+    Failed,
+}
+
 pub(crate) async fn listen(
     context: Arc<core::Context>,
     mut codec: Box<dyn HttpCodec>,
@@ -53,7 +75,11 @@ async fn listen_inner(
     log_id: &log_utils::IdChain<u64>,
 ) {
     let manager = Arc::new(SessionManager::default());
-    let timeout = context.settings.connection_establishment_timeout;
+    let timeout = context
+        .settings
+        .timeouts(codec.protocol())
+        .request_header
+        .unwrap_or(context.settings.connection_establishment_timeout);
     loop {
         match tokio::time::timeout(timeout, codec.listen()).await {
             Ok(Ok(Some(x))) => {
@@ -65,9 +91,14 @@ async fn listen_inner(
                     let log_id = log_id.clone();
                     async move {
                         manager.active_streams_num.fetch_add(1, Ordering::AcqRel);
-                        if let Err(e) = handle_stream(context, x, protocol, sni, &log_id).await {
+                        let result =
+                            handle_stream(context.clone(), x, protocol, sni, &log_id).await;
+                        if let Err(e) = &result {
                             log_id!(debug, log_id, "Request failed: {}", e);
                         }
+                        context
+                            .metrics
+                            .add_reverse_proxy_request(CloseReason::of(&result));
                         manager.active_streams_num.fetch_sub(1, Ordering::AcqRel);
                     }
                 });
@@ -109,31 +140,43 @@ async fn handle_stream(
 ) -> io::Result<()> {
     let (request, respond) = stream.split();
     log_id!(trace, log_id, "Received request: {:?}", request.request());
+    let client_ip = request.client_address()?;
+    // The origin server connection is closed as soon as the client cancels the request,
+    // instead of waiting out the timeouts
+    let mut cancellation = respond.cancellation();
 
-    let forwarder = Box::new(TcpForwarder::new(context.clone()));
     let settings = context.settings.reverse_proxy.as_ref().unwrap();
-    let (mut server_source, mut server_sink) = forwarder
-        .connect(
-            log_id.clone(),
-            forwarder::TcpConnectionMeta {
-                client_address: Ipv4Addr::UNSPECIFIED.into(),
-                destination: TcpDestination::Address(settings.server_address),
-                auth: None,
-                tls_domain: sni,
-                user_agent: None,
-            },
-        )
-        .await
-        .map_err(|e| match e {
-            tunnel::ConnectionError::Io(e) => e,
-            _ => io::Error::new(ErrorKind::Other, format!("{}", e)),
-        })?;
+    let timeouts = context.settings.timeouts(protocol);
+    let extra_headers = settings.response_headers_for(&sni);
+    let mut respond: Box<dyn http_codec::PendingRespond> = if extra_headers.is_empty() {
+        respond
+    } else {
+        Box::new(HeaderInjectingRespond {
+            inner: respond,
+            headers: extra_headers,
+        })
+    };
+    if let Some(x) = &settings.static_files {
+        return static_files::serve(x, request.request(), respond, log_id).await;
+    }
 
     let mut request_headers = request.clone_request();
     let original_version = request_headers.version;
+    if context.maintenance.load(Ordering::Relaxed) {
+        log_id!(trace, log_id, "Responding with maintenance page");
+        return send_error_page(
+            settings,
+            respond,
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            original_version,
+            log_id,
+        )
+        .await;
+    }
+
     match protocol {
         Protocol::Http1 => (),
-        Protocol::Http2 => unreachable!(),
+        Protocol::Http2 => request_headers.version = http::Version::HTTP_11,
         Protocol::Http3 => {
             request_headers.version = http::Version::HTTP_11;
             if settings.h3_backward_compatibility
@@ -144,10 +187,56 @@ async fn handle_stream(
             }
         }
     }
+
+    let cache = context
+        .response_cache
+        .as_ref()
+        .and_then(|c| ResponseCache::cache_key(&request_headers).map(|k| (c, k)));
+    let mut stale = None;
+    if let Some((cache, key)) = &cache {
+        match cache.get(key).await {
+            Some(x) if x.is_fresh() && !ResponseCache::must_revalidate(&request_headers) => {
+                log_id!(trace, log_id, "Serving cached response");
+                return send_cached(respond, &x, &request_headers, original_version).await;
+            }
+            Some(x) => {
+                if let (Some(etag), false) = (
+                    x.etag(),
+                    request_headers
+                        .headers
+                        .contains_key(http::header::IF_NONE_MATCH),
+                ) {
+                    request_headers
+                        .headers
+                        .insert(http::header::IF_NONE_MATCH, etag.clone());
+                    stale = Some(x);
+                }
+            }
+            None => (),
+        }
+    }
+
+    let (mut server_source, mut server_sink) =
+        match connect_origin(&context, sni, client_ip, timeouts.upstream_connect, log_id).await {
+            Ok(x) => x,
+            Err(e) => {
+                log_id!(debug, log_id, "Failed to connect to origin server: {}", e);
+                let status = match e {
+                    tunnel::ConnectionError::Timeout => http::StatusCode::GATEWAY_TIMEOUT,
+                    tunnel::ConnectionError::Io(e) if e.kind() == ErrorKind::TimedOut => {
+                        http::StatusCode::GATEWAY_TIMEOUT
+                    }
+                    _ => http::StatusCode::BAD_GATEWAY,
+                };
+                return send_error_page(settings, respond, status, original_version, log_id).await;
+            }
+        };
+
     request_headers.headers.insert(
         &ORIGINAL_PROTOCOL_HEADER,
         http::HeaderValue::from_static(protocol.as_str()),
     );
+    set_forwarded_headers(&mut request_headers.headers, client_ip);
 
     let encoded = http1_codec::encode_request(&request_headers);
     log_id!(
@@ -156,32 +245,109 @@ async fn handle_stream(
         "Sending translated request: {:?}",
         request_headers
     );
-    server_sink.write_all(encoded).await?;
+    if let Err(e) = server_sink.write_all(encoded.clone()).await {
+        log_id!(
+            debug,
+            log_id,
+            "Failed to send request to origin server: {}",
+            e
+        );
+        return send_error_page(
+            settings,
+            respond,
+            http::StatusCode::BAD_GATEWAY,
+            original_version,
+            log_id,
+        )
+        .await;
+    }
+    if settings
+        .mirror
+        .as_ref()
+        .is_some_and(request_mirror::is_sampled)
+    {
+        server_sink =
+            request_mirror::wrap(context.clone(), server_sink, &request_headers, &encoded);
+    }
 
-    let mut buffer = BytesMut::new();
-    let (response, chunk) = loop {
-        match server_source.read().await? {
-            pipe::Data::Chunk(chunk) => {
-                server_source.consume(chunk.len())?;
-                buffer.put(chunk);
-            }
-            pipe::Data::Eof => return Err(ErrorKind::UnexpectedEof.into()),
+    let (mut response, chunk) = match unless_cancelled(
+        &mut cancellation,
+        tokio::time::timeout(
+            timeouts.idle,
+            read_response(server_source.as_mut(), respond.as_mut(), original_version),
+        ),
+    )
+    .await?
+    {
+        Ok(Ok(x)) => x,
+        Ok(Err(e)) => {
+            log_id!(
+                debug,
+                log_id,
+                "Failed to receive response from origin server: {}",
+                e
+            );
+            return send_error_page(
+                settings,
+                respond,
+                http::StatusCode::BAD_GATEWAY,
+                original_version,
+                log_id,
+            )
+            .await;
         }
-
-        match http1_codec::decode_response(
-            buffer,
-            http1_codec::MAX_HEADERS_NUM,
-            http1_codec::MAX_RAW_HEADERS_SIZE,
-        )? {
-            http1_codec::DecodeStatus::Partial(b) => buffer = b,
-            http1_codec::DecodeStatus::Complete(mut h, tail) => {
-                h.version = original_version; // restore the version in case it was not the same
-                break (h, tail.freeze());
-            }
+        Err(_elapsed) => {
+            log_id!(debug, log_id, "Origin server did not respond in time");
+            return send_error_page(
+                settings,
+                respond,
+                http::StatusCode::GATEWAY_TIMEOUT,
+                original_version,
+                log_id,
+            )
+            .await;
         }
     };
 
-    let mut client_sink = respond.send_response(response, false)?.into_pipe_sink();
+    if let Some((cache, key)) = cache {
+        if let Some(x) = stale.filter(|_| response.status == http::StatusCode::NOT_MODIFIED) {
+            log_id!(trace, log_id, "Cached response is still valid");
+            let x = cache.insert(key, x.revalidated(&response));
+            return send_cached(respond, &x, &request_headers, original_version).await;
+        }
+        if let Some(freshness) = cache.storable(&response) {
+            let status = response.status;
+            let headers = response.headers.clone();
+            let client_body = respond.send_response(response, false)?.into_body_writer();
+            let body = unless_cancelled(
+                &mut cancellation,
+                forward_body(
+                    server_source,
+                    client_body,
+                    chunk,
+                    response_cache::content_length(&headers).unwrap_or_default(),
+                    timeouts.idle,
+                ),
+            )
+            .await??;
+            log_id!(trace, log_id, "Storing response in cache");
+            cache.insert(
+                key,
+                CachedResponse::new(&request_headers, status, headers, body, freshness),
+            );
+            return Ok(());
+        }
+    }
+
+    let mut client_sink = if protocol != Protocol::Http1 && is_chunked(&response.headers) {
+        // HTTP/2 and HTTP/3 frame the body on their own, so the origin server encoding is
+        // stripped, and its trailers are sent as the trailer section of the response
+        response.headers.remove(http::header::TRANSFER_ENCODING);
+        let sink = respond.send_response(response, false)?.into_body_sink();
+        http_forwarded_stream::decode_chunked(sink, log_id.clone())
+    } else {
+        respond.send_response(response, false)?.into_pipe_sink()
+    };
     let chunk_len = chunk.len();
     client_sink.write_all(chunk).await?;
     server_source.consume(chunk_len)?;
@@ -196,6 +362,325 @@ async fn handle_stream(
         |_, _| (),
     );
 
-    pipe.exchange(context.settings.tcp_connections_timeout)
-        .await
+    unless_cancelled(
+        &mut cancellation,
+        pipe::with_lifetime(timeouts.total, pipe.exchange(timeouts.idle)),
+    )
+    .await?
+}
+
+/// Run `f` unless the client cancels the request first
+async fn unless_cancelled<T>(
+    cancellation: &mut Cancellation,
+    f: impl Future<Output = T>,
+) -> io::Result<T> {
+    tokio::select! {
+        x = f => Ok(x),
+        e = cancellation.cancelled() => Err(e),
+    }
+}
+
+impl CloseReason {
+    fn of(result: &io::Result<()>) -> Self {
+        match result {
+            Ok(_) => Self::Completed,
+            Err(e) if Cancellation::is_cancelled(e) => Self::ClientCancelled,
+            Err(e) if e.kind() == ErrorKind::TimedOut => Self::TimedOut,
+            Err(_) => Self::Failed,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::ClientCancelled => "client_cancelled",
+            Self::TimedOut => "timed_out",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Read the final response head of the origin server.
+/// The informational responses preceding it, like Early Hints, are passed to the client.
+async fn read_response(
+    source: &mut dyn pipe::Source,
+    respond: &mut dyn http_codec::PendingRespond,
+    version: http::Version,
+) -> io::Result<(http_codec::ResponseHeaders, Bytes)> {
+    let mut buffer = BytesMut::new();
+    loop {
+        match http1_codec::decode_response(
+            buffer,
+            http1_codec::MAX_HEADERS_NUM,
+            http1_codec::MAX_RAW_HEADERS_SIZE,
+        )? {
+            http1_codec::DecodeStatus::Partial(b) => buffer = b,
+            http1_codec::DecodeStatus::Complete(mut h, tail) => {
+                h.version = version; // restore the version in case it was not the same
+                                     // The connection is passed through as is after switching the protocols
+                if !h.status.is_informational() || h.status == http::StatusCode::SWITCHING_PROTOCOLS
+                {
+                    break Ok((h, tail.freeze()));
+                }
+                // HTTP/1.0 clients do not expect the informational responses
+                if version != http::Version::HTTP_10 {
+                    respond.send_intermediate_response(h)?;
+                }
+                buffer = tail;
+                continue;
+            }
+        }
+
+        match source.read().await? {
+            pipe::Data::Chunk(chunk) => {
+                source.consume(chunk.len())?;
+                buffer.put(chunk);
+            }
+            pipe::Data::Eof => return Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Check if the body is sent in the chunked transfer coding, which is always the last one
+fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .last()
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.rsplit(',').next())
+        .is_some_and(|x| x.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Replace the forwarding headers of a request with the ones telling the address
+/// of the client, as the endpoint is the first proxy on its way
+fn set_forwarded_headers(headers: &mut http::HeaderMap, client_ip: IpAddr) {
+    let node = match client_ip {
+        IpAddr::V4(x) => x.to_string(),
+        IpAddr::V6(x) => format!("\"[{}]\"", x),
+    };
+    if let Ok(x) = http::HeaderValue::try_from(format!("for={};proto=https", node)) {
+        headers.insert(http::header::FORWARDED, x);
+    }
+    if let Ok(x) = http::HeaderValue::try_from(client_ip.to_string()) {
+        headers.insert(&X_FORWARDED_FOR_HEADER, x);
+    }
+}
+
+/// Respond with the configured page of the error status,
+/// or with the bare status if there is no page
+/// Connect to the origin server, over TLS if configured
+async fn connect_origin(
+    context: &Arc<core::Context>,
+    sni: String,
+    client_ip: IpAddr,
+    timeout: Duration,
+    log_id: &log_utils::IdChain<u64>,
+) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+    let settings = context.settings.reverse_proxy.as_ref().unwrap();
+    let server_address = settings.server_address;
+    // The codecs do not keep the client port
+    let proxy_header = settings.proxy_protocol.then(|| {
+        proxy_protocol::encode_v2(
+            SocketAddr::new(client_ip, 0),
+            context.settings.listen_address,
+        )
+    });
+    let tls = match &context.reverse_proxy_tls {
+        Some(x) => x,
+        None => {
+            let connect = async {
+                let (source, mut sink) = Box::new(TcpForwarder::new(context.clone()))
+                    .connect(
+                        log_id.clone(),
+                        forwarder::TcpConnectionMeta {
+                            client_address: Ipv4Addr::UNSPECIFIED.into(),
+                            destination: TcpDestination::Address(server_address),
+                            auth: None,
+                            tls_domain: sni,
+                            user_agent: None,
+                            destination_acl: None,
+                            race_uplinks: vec![],
+                        },
+                    )
+                    .await?;
+                if let Some(x) = proxy_header {
+                    sink.write_all(Bytes::from(x))
+                        .await
+                        .map_err(tunnel::ConnectionError::Io)?;
+                }
+                Ok::<_, tunnel::ConnectionError>((source, sink))
+            };
+            return tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or(Err(tunnel::ConnectionError::Timeout));
+        }
+    };
+
+    if !context.settings.allow_metadata_endpoint_connections
+        && net_utils::is_metadata_address(&server_address.ip())
+    {
+        return Err(tunnel::ConnectionError::MetadataEndpoint);
+    }
+    let connect = async {
+        let mut stream = TcpStream::connect(server_address).await?;
+        // The header goes ahead of the TLS handshake
+        if let Some(x) = &proxy_header {
+            stream.write_all(x).await?;
+        }
+        tls.connect(stream, server_address).await
+    };
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(stream)) => Ok(upstream_tls::pipe_from_stream(
+            stream,
+            log_id.clone(),
+            context.metrics.clone().outbound_tcp_socket_counter(),
+        )),
+        Ok(Err(e)) => Err(tunnel::ConnectionError::Io(e)),
+        Err(_elapsed) => Err(tunnel::ConnectionError::Timeout),
+    }
+}
+
+async fn send_error_page(
+    settings: &ReverseProxySettings,
+    respond: Box<dyn http_codec::PendingRespond>,
+    status: http::StatusCode,
+    version: http::Version,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let path = match settings
+        .error_pages
+        .as_ref()
+        .and_then(|x| x.page_of(status))
+    {
+        Some(x) => x,
+        None => return respond.send_bad_response(status, vec![]),
+    };
+    let page = match tokio::fs::read(path).await {
+        Ok(x) => Bytes::from(x),
+        Err(e) => {
+            log_id!(debug, log_id, "Failed to read error page: {}: {}", path, e);
+            return respond.send_bad_response(status, vec![]);
+        }
+    };
+
+    let response = http::Response::builder()
+        .version(version)
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(http::header::CONTENT_LENGTH, page.len())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    let mut sink = respond.send_response(response, false)?.into_pipe_sink();
+    sink.write_all(page).await?;
+    sink.eof()
+}
+
+async fn send_cached(
+    respond: Box<dyn http_codec::PendingRespond>,
+    cached: &CachedResponse,
+    request: &http_codec::RequestHeaders,
+    version: http::Version,
+) -> io::Result<()> {
+    let mut response = cached.response(version);
+    if cached.is_not_modified_for(request) {
+        response.status = http::StatusCode::NOT_MODIFIED;
+        response.headers.remove(http::header::CONTENT_LENGTH);
+        return respond.send_response(response, true).map(|_| ());
+    }
+
+    let mut sink = respond.send_response(response, false)?.into_pipe_sink();
+    sink.write_all(cached.body()).await?;
+    sink.eof()
+}
+
+/// Forward exactly `length` bytes of the response body to the client, starting with
+/// the already received `chunk`
+///
+/// # Return
+///
+/// The forwarded body
+async fn forward_body(
+    mut server_source: Box<dyn pipe::Source>,
+    mut client_body: BodyWriter,
+    mut chunk: Bytes,
+    length: usize,
+    timeout: Duration,
+) -> io::Result<Bytes> {
+    let mut body = BytesMut::with_capacity(length);
+    let chunk_len = chunk.len();
+    chunk.truncate(length);
+    body.put_slice(&chunk);
+    client_body.write(chunk).await?;
+    server_source.consume(chunk_len)?;
+
+    let mut server_body = BodyReader::new(server_source);
+    while body.len() < length {
+        let chunk = match tokio::time::timeout(timeout, server_body.read(length - body.len())).await
+        {
+            Ok(Ok(Some(x))) => x,
+            Ok(Ok(None)) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(ErrorKind::TimedOut.into()),
+        };
+        let chunk_len = chunk.len();
+        body.put_slice(&chunk);
+        client_body.write(chunk).await?;
+        server_body.release(chunk_len)?;
+    }
+
+    client_body.finish()?;
+    Ok(body.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::ReverseProxySettings;
+
+    #[test]
+    fn host_response_headers_override_common_ones() {
+        let settings = ReverseProxySettings::builder()
+            .server_address("127.0.0.1:8080")
+            .unwrap()
+            .path_mask("/".into())
+            .response_header("*", "Strict-Transport-Security", "max-age=300")
+            .response_header("*", "X-Content-Type-Options", "nosniff")
+            .response_header("example.org", "strict-transport-security", "max-age=600")
+            .build()
+            .unwrap();
+
+        let headers = settings.response_headers_for("example.org");
+        assert_eq!(2, headers.len());
+        assert_eq!(
+            "max-age=600",
+            headers[http::header::STRICT_TRANSPORT_SECURITY]
+        );
+        assert_eq!(
+            "max-age=300",
+            settings.response_headers_for("other.org")[http::header::STRICT_TRANSPORT_SECURITY]
+        );
+    }
+
+    #[test]
+    fn forwarded_headers_replace_client_ones() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::FORWARDED, "for=203.0.113.1".parse().unwrap());
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+
+        super::set_forwarded_headers(&mut headers, "2001:db8::1".parse().unwrap());
+        assert_eq!(
+            "for=\"[2001:db8::1]\";proto=https",
+            headers[http::header::FORWARDED]
+        );
+        assert_eq!("2001:db8::1", headers["x-forwarded-for"]);
+
+        super::set_forwarded_headers(&mut headers, "192.0.2.1".parse().unwrap());
+        assert_eq!(
+            "for=192.0.2.1;proto=https",
+            headers[http::header::FORWARDED]
+        );
+        assert_eq!(1, headers.get_all("x-forwarded-for").iter().count());
+    }
 }
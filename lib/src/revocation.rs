@@ -0,0 +1,354 @@
+//! The revocation of the client credentials with immediate effect. A revoked username,
+//! or a bearer token with a revoked ID, is refused on authentication whatever the authenticator
+//! says, so there is no cache to wait out, and the sessions already authenticated with it
+//! are closed once the grace period is over. The list is kept in a file, which is checked
+//! for changes periodically, and is changed through the admin listener.
+
+use crate::authentication::{Authenticator, Source};
+use crate::settings::RevocationSettings;
+use crate::{core, policy};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use toml_edit::{Array, Document, Item, Value};
+
+const USERNAMES_KEY: &str = "usernames";
+const TOKEN_IDS_KEY: &str = "token_ids";
+
+pub(crate) struct RevocationList {
+    settings: RevocationSettings,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    revoked: Revoked,
+    /// The modification time and the size of the file as it was last loaded or written
+    stamp: Option<(SystemTime, u64)>,
+}
+
+/// The revoked credentials
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Revoked {
+    pub usernames: BTreeSet<String>,
+    pub token_ids: BTreeSet<String>,
+}
+
+/// A change of the list made through the admin listener
+#[derive(Debug, PartialEq)]
+pub(crate) enum RevocationChange {
+    Revoke(Credential),
+    Restore(Credential),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Credential {
+    Username(String),
+    TokenId(String),
+}
+
+impl RevocationList {
+    /// Load the list from the file, if any. A missing file makes an empty list.
+    pub fn new(settings: &RevocationSettings) -> io::Result<Self> {
+        let list = Self {
+            settings: settings.clone(),
+            state: Default::default(),
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Check if the credentials are revoked. The identity and the token ID of the credentials
+    /// are the ones the authenticator has verified.
+    pub fn is_revoked(&self, authenticator: &dyn Authenticator, source: &Source<'_>) -> bool {
+        let identity = policy::identity(authenticator, source);
+        let token_id = authenticator.token_id(source);
+        let state = self.state.lock().unwrap();
+        identity.is_some_and(|x| state.revoked.usernames.contains(&x))
+            || token_id.is_some_and(|x| state.revoked.token_ids.contains(&x))
+    }
+
+    pub fn list(&self) -> Revoked {
+        self.state.lock().unwrap().revoked.clone()
+    }
+
+    /// Apply the change, writing the list to the file, if any
+    ///
+    /// # Return
+    ///
+    /// The credentials revoked by the change
+    pub fn change(&self, change: RevocationChange) -> io::Result<Revoked> {
+        // The file may have been changed since it was last loaded
+        let previous = self.list();
+        self.reload()?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut revoked = state.revoked.clone();
+        let (credential, is_revoked) = match change {
+            RevocationChange::Revoke(x) => (x, true),
+            RevocationChange::Restore(x) => (x, false),
+        };
+        let (set, x) = match credential {
+            Credential::Username(x) => (&mut revoked.usernames, x),
+            Credential::TokenId(x) => (&mut revoked.token_ids, x),
+        };
+        match is_revoked {
+            true => set.insert(x),
+            false => set.remove(&x),
+        };
+
+        if let Some(path) = &self.settings.path {
+            write(path, &revoked)?;
+            state.stamp = stamp(path);
+        }
+        let added = revoked.added_since(&previous);
+        state.revoked = revoked;
+        Ok(added)
+    }
+
+    /// Load the list from the file again in case it has changed
+    ///
+    /// # Return
+    ///
+    /// The credentials revoked since the previous load, if the file has changed
+    fn reload(&self) -> io::Result<Option<Revoked>> {
+        let Some(path) = &self.settings.path else {
+            return Ok(None);
+        };
+        let stamp = stamp(path);
+        let mut state = self.state.lock().unwrap();
+        if stamp.is_some() && stamp == state.stamp {
+            return Ok(None);
+        }
+
+        let revoked = match fs::read_to_string(path) {
+            Ok(x) => parse(&x)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Revoked::default(),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        };
+        let added = revoked.added_since(&state.revoked);
+        state.revoked = revoked;
+        state.stamp = stamp;
+        Ok(Some(added))
+    }
+}
+
+impl Revoked {
+    fn added_since(&self, previous: &Self) -> Self {
+        Self {
+            usernames: &self.usernames - &previous.usernames,
+            token_ids: &self.token_ids - &previous.token_ids,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.usernames.is_empty() && self.token_ids.is_empty()
+    }
+
+    /// Serialize the list into a JSON document
+    pub fn to_json(&self) -> String {
+        let array = |x: &BTreeSet<String>| {
+            let mut out = String::from("[");
+            for (i, x) in x.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{}", serde_json::Value::from(x.as_str()));
+            }
+            out.push(']');
+            out
+        };
+        format!(
+            "{{\"usernames\":{},\"token_ids\":{}}}",
+            array(&self.usernames),
+            array(&self.token_ids)
+        )
+    }
+}
+
+/// Close the sessions authenticated with the newly revoked credentials
+/// once the grace period is over
+pub(crate) fn close_sessions(context: &core::Context, added: &Revoked) {
+    let Some(list) = context.revocations.as_ref() else {
+        return;
+    };
+    if added.is_empty() {
+        return;
+    }
+    let n = context.sessions.revoke(
+        &added.usernames,
+        &added.token_ids,
+        list.settings.grace_period,
+    );
+    if n > 0 {
+        info!(
+            "Closing {} sessions of revoked credentials in {:?}",
+            n, list.settings.grace_period
+        );
+    }
+}
+
+/// Check the file of the list for changes until the endpoint shuts down
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let Some(list) = context.revocations.as_ref() else {
+        return Ok(());
+    };
+    if list.settings.path.is_none() {
+        return Ok(());
+    }
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+    let mut interval = tokio::time::interval(list.settings.reload_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let reload = async {
+        loop {
+            interval.tick().await;
+            match list.reload() {
+                Ok(Some(x)) => close_sessions(&context, &x),
+                Ok(None) => (),
+                // The file may be read while it is being written, in which case it is
+                // loaded again once the writing is complete
+                Err(e) => warn!("Failed to reload revocation list, keeping previous: {}", e),
+            }
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = reload => Ok(()),
+    }
+}
+
+fn parse(content: &str) -> Result<Revoked, String> {
+    let doc: Document = content.parse().map_err(|e| format!("{}", e))?;
+    let strings = |key: &str| -> Result<BTreeSet<String>, String> {
+        let Some(x) = doc.get(key) else {
+            return Ok(Default::default());
+        };
+        x.as_array()
+            .and_then(|x| x.iter().map(|x| x.as_str().map(String::from)).collect())
+            .ok_or_else(|| format!("{} is not an array of strings", key))
+    };
+    Ok(Revoked {
+        usernames: strings(USERNAMES_KEY)?,
+        token_ids: strings(TOKEN_IDS_KEY)?,
+    })
+}
+
+/// Replace the file with the list, the readers never see a partially written file
+fn write(path: &str, revoked: &Revoked) -> io::Result<()> {
+    let array = |x: &BTreeSet<String>| Item::Value(Value::Array(x.iter().collect::<Array>()));
+    let mut doc = Document::new();
+    doc[USERNAMES_KEY] = array(&revoked.usernames);
+    doc[TOKEN_IDS_KEY] = array(&revoked.token_ids);
+
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    if let Ok(x) = fs::metadata(path) {
+        file.set_permissions(x.permissions())?;
+    }
+    file.write_all(doc.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::Status;
+    use crate::log_utils;
+    use base64::Engine;
+    use std::borrow::Cow;
+
+    /// Passes everyone naming the bearer tokens of the `<subject>:<token ID>` form
+    struct Tokens;
+
+    impl Authenticator for Tokens {
+        fn authenticate(&self, _: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            Status::Pass
+        }
+
+        fn username(&self, source: &Source<'_>) -> Option<String> {
+            match source {
+                Source::ProxyBearer(x) => x.split(':').next().map(String::from),
+                _ => source.username(),
+            }
+        }
+
+        fn token_id(&self, source: &Source<'_>) -> Option<String> {
+            match source {
+                Source::ProxyBearer(x) => x.split_once(':').map(|(_, x)| x.to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    fn bearer(token: &str) -> Source<'static> {
+        Source::ProxyBearer(Cow::Owned(token.to_string()))
+    }
+
+    fn basic(username: &str) -> Source<'static> {
+        Source::ProxyBasic(Cow::Owned(
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:secret", username)),
+        ))
+    }
+
+    #[test]
+    fn revokes_credentials() {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-revocation-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "usernames = [\"alice\"]\n").unwrap();
+        let settings = RevocationSettings::builder()
+            .path(path.to_str().unwrap().to_string())
+            .build()
+            .unwrap();
+        let list = RevocationList::new(&settings).unwrap();
+        assert!(list.is_revoked(&Tokens, &basic("alice")));
+        assert!(!list.is_revoked(&Tokens, &basic("bob")));
+
+        let added = list
+            .change(RevocationChange::Revoke(Credential::TokenId(
+                "token-1".to_string(),
+            )))
+            .unwrap();
+        assert_eq!(BTreeSet::from(["token-1".to_string()]), added.token_ids);
+        assert!(added.usernames.is_empty());
+        assert!(list.is_revoked(&Tokens, &bearer("bob:token-1")));
+        assert!(!list.is_revoked(&Tokens, &bearer("bob:token-2")));
+        // The subject of a token is checked against the usernames
+        assert!(list.is_revoked(&Tokens, &bearer("alice")));
+
+        list.change(RevocationChange::Restore(Credential::Username(
+            "alice".to_string(),
+        )))
+        .unwrap();
+        assert!(!list.is_revoked(&Tokens, &basic("alice")));
+        // The change made through the admin listener is not taken for a change of the file
+        assert_eq!(None, list.reload().unwrap());
+
+        let reloaded = RevocationList::new(&settings).unwrap().list();
+        std::fs::write(&path, "usernames = \"carol\"\n").unwrap();
+        let malformed = list.reload();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            "{\"usernames\":[],\"token_ids\":[\"token-1\"]}",
+            reloaded.to_json()
+        );
+        assert!(malformed.is_err());
+        assert_eq!(reloaded, list.list());
+    }
+}
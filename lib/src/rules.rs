@@ -1,6 +1,16 @@
+use crate::host_patterns::HostPatterns;
+use crate::profiles::Profile;
+use crate::{host_patterns, net_utils};
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Write};
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
 
 /// Action to take when a rule matches
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -28,17 +38,99 @@ pub struct Rule {
     pub action: RuleAction,
 }
 
+/// Routing rule applied to the tunneled TCP connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRule {
+    /// Destination host name pattern to match against, see [`host_patterns`]
+    pub destination: String,
+
+    /// CIDR range to match against client IP
+    #[serde(default)]
+    pub cidr: Option<String>,
+
+    /// The server name replacing the one in the TLS ClientHello sent by a client
+    #[serde(default)]
+    pub override_sni: Option<String>,
+
+    /// The value replacing the `Host` header of the plain HTTP request sent by a client
+    #[serde(default)]
+    pub override_host: Option<String>,
+
+    /// The egress uplinks the connections are raced across, see
+    /// [`crate::settings::EgressUplinksSettings`]. If empty, the system routes the connections.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub race_uplinks: Vec<String>,
+}
+
 /// Rules configuration
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RulesConfig {
     /// List of filter rules
     #[serde(default)]
     pub rule: Vec<Rule>,
+
+    /// List of routing rules
+    #[serde(default)]
+    pub route: Vec<RouteRule>,
 }
 
 /// Rule evaluation engine
+#[derive(Clone)]
 pub struct RulesEngine {
     rules: RulesConfig,
+    /// The rules file the rules are loaded from
+    path: Option<String>,
+    hits: HitCounters,
+    /// The compiled destination patterns of the routing rules
+    routes: Arc<HostPatterns>,
+}
+
+/// The numbers of the connections the rules have decided on. The counters follow the rules
+/// through the runtime changes and are shared by the copies of the engine, so the connections
+/// evaluated against the replaced rules still count.
+#[derive(Clone, Default)]
+struct HitCounters {
+    rule: Vec<Arc<AtomicU64>>,
+    route: Vec<Arc<AtomicU64>>,
+    /// The connections allowed as no filter rule matches them
+    unmatched: Arc<AtomicU64>,
+}
+
+/// The rules in effect, which may be changed at runtime through the admin interface.
+/// A change is made to a copy of the rules, which replaces them at once, so the connections
+/// are never evaluated against a partially applied change.
+pub(crate) struct LiveRules {
+    engine: RwLock<Arc<RulesEngine>>,
+    /// Keeps the concurrent changes from losing each other
+    update: Mutex<()>,
+}
+
+/// The list of the rules a change applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RuleList {
+    Rule,
+    Route,
+}
+
+/// A change of the rules in effect
+#[derive(Debug, Clone)]
+pub(crate) enum RulesChange {
+    /// Insert the filter rule at the position
+    AddRule(usize, Rule),
+    /// Insert the routing rule at the position
+    AddRoute(usize, RouteRule),
+    /// Remove the rule at the position
+    Remove(RuleList, usize),
+    /// Move the rule from the first position to the second one
+    Move(RuleList, usize, usize),
+}
+
+#[derive(Debug)]
+pub(crate) enum RulesUpdateError {
+    /// The change is not applicable, or makes the rules invalid
+    Invalid(String),
+    /// The changed rules failed to be written to the rules file
+    Persist(io::Error),
 }
 
 /// Result of rule evaluation
@@ -48,6 +140,46 @@ pub enum RuleEvaluation {
     Deny,
 }
 
+/// The way the filter rules decide on a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FilterMatch {
+    /// By the rule at the position
+    Rule(usize),
+    /// Denied, as the client random is unknown while some rules match it
+    NoClientRandom,
+    /// Allowed, as no rule matches
+    Unmatched,
+}
+
+/// What the rules make of a hypothetical connection, see [`RulesEngine::explain`]
+#[derive(Debug, Serialize)]
+pub(crate) struct Explanation {
+    /// The decision of the filter rules on the client connection
+    pub filter: Decision,
+    /// The decision of the profile on the destination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Decision>,
+    /// The routing rule of the tunneled connections to the destination
+    pub route: Option<RouteMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Decision {
+    pub action: RuleAction,
+    /// The position of the deciding rule, [`None`] if no rule decides
+    pub rule: Option<usize>,
+    /// `rule`, `default`, or `no_client_random` if the filter rules need the client random
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RouteMatch {
+    /// Whether the rule is one of the profile ones, which take precedence
+    pub profile: bool,
+    pub position: usize,
+    pub rule: RouteRule,
+}
+
 impl Rule {
     /// Check if this rule matches the given connection parameters
     pub fn matches(&self, client_ip: &IpAddr, client_random: Option<&[u8]>) -> bool {
@@ -115,22 +247,110 @@ impl Rule {
     }
 }
 
+impl RouteRule {
+    /// Check if this rule matches the given tunnel request parameters
+    pub fn matches(&self, client_ip: &IpAddr, destination: &str) -> bool {
+        host_patterns::matches(&self.destination, destination) && self.matches_client(client_ip)
+    }
+
+    fn matches_client(&self, client_ip: &IpAddr) -> bool {
+        match &self.cidr {
+            None => true,
+            Some(x) => x
+                .parse::<IpNet>()
+                .is_ok_and(|cidr| cidr.contains(client_ip)),
+        }
+    }
+}
+
+/// Find the first routing rule for a tunneled connection to the destination host.
+/// The patterns are the compiled destinations of the rules.
+pub(crate) fn find_route<'a>(
+    routes: &'a [RouteRule],
+    patterns: &HostPatterns,
+    client_ip: &IpAddr,
+    destination: &str,
+) -> Option<(usize, &'a RouteRule)> {
+    patterns
+        .find(destination, |i| routes[*i].matches_client(client_ip))
+        .map(|i| (i, &routes[i]))
+}
+
+/// Compile the destination patterns of the routing rules
+pub(crate) fn compile_routes(routes: &[RouteRule]) -> HostPatterns {
+    HostPatterns::new(routes.iter().map(|x| x.destination.as_str()).enumerate())
+}
+
 impl RulesEngine {
     /// Create a new rules engine from rules config
-    pub fn from_config(rules: RulesConfig) -> Self {
-        Self { rules }
+    pub fn from_config(mut rules: RulesConfig) -> Self {
+        for x in &mut rules.route {
+            x.destination = net_utils::canonicalize_host_pattern(&x.destination);
+        }
+        let hits = HitCounters {
+            rule: rules.rule.iter().map(|_| Default::default()).collect(),
+            route: rules.route.iter().map(|_| Default::default()).collect(),
+            unmatched: Default::default(),
+        };
+        Self {
+            routes: Arc::new(compile_routes(&rules.route)),
+            rules,
+            path: None,
+            hits,
+        }
     }
 
     /// Create a default rules engine that allows all connections
     pub fn default_allow() -> Self {
         Self {
-            rules: RulesConfig { rule: vec![] },
+            rules: RulesConfig {
+                rule: vec![],
+                route: vec![],
+            },
+            path: None,
+            hits: Default::default(),
+            routes: Default::default(),
         }
     }
 
+    /// Remember the rules file, so that the runtime changes are able to be written to it
+    pub(crate) fn with_path(mut self, path: String) -> Self {
+        self.path = Some(path);
+        self
+    }
+
     /// Evaluate connection against all rules
     /// Returns the action from the first matching rule, or Allow if no rules match
     pub fn evaluate(&self, client_ip: &IpAddr, client_random: Option<&[u8]>) -> RuleEvaluation {
+        self.evaluate_traced(client_ip, client_random).0
+    }
+
+    /// Evaluate connection against all rules, counting the hit of the deciding one.
+    /// Returns the action along with the way it is decided.
+    pub(crate) fn evaluate_traced(
+        &self,
+        client_ip: &IpAddr,
+        client_random: Option<&[u8]>,
+    ) -> (RuleEvaluation, FilterMatch) {
+        let found = self.find_rule(client_ip, client_random);
+        let evaluation = match found {
+            FilterMatch::Rule(i) => {
+                self.hits.rule[i].fetch_add(1, Ordering::Relaxed);
+                match self.rules.rule[i].action {
+                    RuleAction::Allow => RuleEvaluation::Allow,
+                    RuleAction::Deny => RuleEvaluation::Deny,
+                }
+            }
+            FilterMatch::NoClientRandom => RuleEvaluation::Deny,
+            FilterMatch::Unmatched => {
+                self.hits.unmatched.fetch_add(1, Ordering::Relaxed);
+                RuleEvaluation::Allow
+            }
+        };
+        (evaluation, found)
+    }
+
+    fn find_rule(&self, client_ip: &IpAddr, client_random: Option<&[u8]>) -> FilterMatch {
         if client_random.is_none()
             && self
                 .rules
@@ -138,20 +358,104 @@ impl RulesEngine {
                 .iter()
                 .any(|r| r.client_random_prefix.is_some())
         {
-            return RuleEvaluation::Deny;
+            return FilterMatch::NoClientRandom;
         }
 
-        for rule in &self.rules.rule {
-            if rule.matches(client_ip, client_random) {
-                return match rule.action {
-                    RuleAction::Allow => RuleEvaluation::Allow,
-                    RuleAction::Deny => RuleEvaluation::Deny,
-                };
-            }
+        self.rules
+            .rule
+            .iter()
+            .position(|rule| rule.matches(client_ip, client_random))
+            .map_or(FilterMatch::Unmatched, FilterMatch::Rule)
+    }
+
+    /// Find the routing rule for a tunneled connection to the destination host
+    pub fn route(&self, client_ip: &IpAddr, destination: &str) -> Option<&RouteRule> {
+        self.route_traced(client_ip, destination).map(|(_, x)| x)
+    }
+
+    /// Find the routing rule for a tunneled connection to the destination host
+    /// along with its position, counting the hit
+    pub(crate) fn route_traced(
+        &self,
+        client_ip: &IpAddr,
+        destination: &str,
+    ) -> Option<(usize, &RouteRule)> {
+        let (i, rule) = find_route(&self.rules.route, &self.routes, client_ip, destination)?;
+        self.hits.route[i].fetch_add(1, Ordering::Relaxed);
+        Some((i, rule))
+    }
+
+    /// Explain what the rules make of a connection of the client to the destination,
+    /// without counting the hits. The profile is the one the client is assigned to.
+    pub(crate) fn explain(
+        &self,
+        profile: Option<&Profile>,
+        client_ip: &IpAddr,
+        client_random: Option<&[u8]>,
+        destination: Option<&str>,
+    ) -> Explanation {
+        let filter = match self.find_rule(client_ip, client_random) {
+            FilterMatch::Rule(i) => Decision {
+                action: self.rules.rule[i].action.clone(),
+                rule: Some(i),
+                reason: "rule",
+            },
+            FilterMatch::NoClientRandom => Decision {
+                action: RuleAction::Deny,
+                rule: None,
+                reason: "no_client_random",
+            },
+            FilterMatch::Unmatched => Decision {
+                action: RuleAction::Allow,
+                rule: None,
+                reason: "default",
+            },
+        };
+
+        let Some(destination) = destination else {
+            return Explanation {
+                filter,
+                profile: None,
+                route: None,
+            };
+        };
+        let route_match = |profile, (position, rule): (usize, &RouteRule)| RouteMatch {
+            profile,
+            position,
+            rule: rule.clone(),
+        };
+        Explanation {
+            filter,
+            profile: profile.map(|x| {
+                let (rule, action) = x.find_destination(destination);
+                Decision {
+                    action: action.clone(),
+                    rule,
+                    reason: if rule.is_some() { "rule" } else { "default" },
+                }
+            }),
+            route: profile
+                .and_then(|x| x.route(client_ip, destination))
+                .map(|x| route_match(true, x))
+                .or_else(|| {
+                    find_route(&self.rules.route, &self.routes, client_ip, destination)
+                        .map(|x| route_match(false, x))
+                }),
         }
+    }
+
+    /// Get the numbers of the connections each rule of the list has decided on
+    pub(crate) fn hits(&self, list: RuleList) -> Vec<u64> {
+        let counters = match list {
+            RuleList::Rule => &self.hits.rule,
+            RuleList::Route => &self.hits.route,
+        };
+        counters.iter().map(|x| x.load(Ordering::Relaxed)).collect()
+    }
 
-        // Default action if no rules match: allow
-        RuleEvaluation::Allow
+    /// Get the number of the connections allowed as no filter rule matches them
+    pub(crate) fn unmatched_hits(&self) -> u64 {
+        self.hits.unmatched.load(Ordering::Relaxed)
     }
 
     /// Get a reference to the rules configuration
@@ -160,6 +464,227 @@ impl RulesEngine {
     }
 }
 
+impl RulesConfig {
+    /// Check the rules are well-formed. Unlike the rules file, where a malformed rule
+    /// never matches, a runtime change with one is refused.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let is_cidr = |x: &Option<String>| x.as_ref().is_none_or(|x| x.parse::<IpNet>().is_ok());
+        for (i, x) in self.rule.iter().enumerate() {
+            if !is_cidr(&x.cidr) {
+                return Err(format!("Rule #{}: invalid CIDR", i));
+            }
+            let is_hex = |x: &str| !x.is_empty() && hex::decode(x).is_ok();
+            let is_prefix =
+                x.client_random_prefix
+                    .as_ref()
+                    .is_none_or(|x| match x.split_once('/') {
+                        Some((prefix, mask)) => is_hex(prefix) && is_hex(mask),
+                        None => is_hex(x),
+                    });
+            if !is_prefix {
+                return Err(format!("Rule #{}: invalid client random prefix", i));
+            }
+        }
+        for (i, x) in self.route.iter().enumerate() {
+            if x.destination.is_empty() {
+                return Err(format!("Route #{}: empty destination", i));
+            }
+            host_patterns::validate(&x.destination).map_err(|e| format!("Route #{}: {}", i, e))?;
+            if !is_cidr(&x.cidr) {
+                return Err(format!("Route #{}: invalid CIDR", i));
+            }
+            let is_override = |x: &Option<String>| {
+                x.as_ref()
+                    .is_none_or(|x| !x.is_empty() && !x.contains(|c: char| c.is_whitespace()))
+            };
+            if !is_override(&x.override_sni) || !is_override(&x.override_host) {
+                return Err(format!("Route #{}: invalid override", i));
+            }
+            for (j, uplink) in x.race_uplinks.iter().enumerate() {
+                if uplink.is_empty() || x.race_uplinks[..j].contains(uplink) {
+                    return Err(format!("Route #{}: empty or duplicate uplink", i));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RulesChange {
+    /// Apply the change to the rules, moving their hit counters along
+    fn apply(self, engine: &mut RulesEngine) -> Result<(), String> {
+        fn insert<T>(list: &mut Vec<T>, position: usize, x: T) -> Result<(), String> {
+            if position > list.len() {
+                return Err(format!("Position {} is out of range", position));
+            }
+            list.insert(position, x);
+            Ok(())
+        }
+        fn reorder<T>(list: &mut Vec<T>, from: usize, to: Option<usize>) -> Result<(), String> {
+            let n = list.len();
+            if from >= n || to.is_some_and(|x| x >= n) {
+                return Err(format!("Position is out of range of {} rules", n));
+            }
+            let x = list.remove(from);
+            if let Some(to) = to {
+                list.insert(to, x);
+            }
+            Ok(())
+        }
+
+        let (rules, hits) = (&mut engine.rules, &mut engine.hits);
+        match self {
+            Self::AddRule(position, x) => {
+                insert(&mut rules.rule, position, x)?;
+                insert(&mut hits.rule, position, Default::default())
+            }
+            Self::AddRoute(position, mut x) => {
+                x.destination = net_utils::canonicalize_host_pattern(&x.destination);
+                insert(&mut rules.route, position, x)?;
+                insert(&mut hits.route, position, Default::default())
+            }
+            Self::Remove(RuleList::Rule, i) => {
+                reorder(&mut rules.rule, i, None)?;
+                reorder(&mut hits.rule, i, None)
+            }
+            Self::Remove(RuleList::Route, i) => {
+                reorder(&mut rules.route, i, None)?;
+                reorder(&mut hits.route, i, None)
+            }
+            Self::Move(RuleList::Rule, from, to) => {
+                reorder(&mut rules.rule, from, Some(to))?;
+                reorder(&mut hits.rule, from, Some(to))
+            }
+            Self::Move(RuleList::Route, from, to) => {
+                reorder(&mut rules.route, from, Some(to))?;
+                reorder(&mut hits.route, from, Some(to))
+            }
+        }
+    }
+}
+
+impl LiveRules {
+    pub fn new(engine: Option<&RulesEngine>) -> Self {
+        Self {
+            engine: RwLock::new(Arc::new(
+                engine.cloned().unwrap_or_else(RulesEngine::default_allow),
+            )),
+            update: Default::default(),
+        }
+    }
+
+    /// Get the rules in effect
+    pub fn current(&self) -> Arc<RulesEngine> {
+        self.engine.read().unwrap().clone()
+    }
+
+    /// Apply the change to the rules in effect. With `persist`, the changed rules are
+    /// written to the rules file before they take effect, and the rules stay unchanged
+    /// if that fails.
+    pub fn update(
+        &self,
+        change: RulesChange,
+        persist: bool,
+    ) -> Result<Arc<RulesEngine>, RulesUpdateError> {
+        let _guard = self.update.lock().unwrap();
+        let mut engine = RulesEngine::clone(&self.current());
+        change
+            .apply(&mut engine)
+            .and_then(|_| engine.rules.validate())
+            .map_err(RulesUpdateError::Invalid)?;
+        engine.routes = Arc::new(compile_routes(&engine.rules.route));
+
+        if persist {
+            let path = engine
+                .path
+                .as_deref()
+                .ok_or_else(|| RulesUpdateError::Invalid("Rules file is not configured".into()))?;
+            write_rules_file(path, &engine.rules).map_err(RulesUpdateError::Persist)?;
+        }
+
+        let engine = Arc::new(engine);
+        *self.engine.write().unwrap() = engine.clone();
+        Ok(engine)
+    }
+}
+
+impl Display for RulesUpdateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(x) => write!(f, "Invalid change: {}", x),
+            Self::Persist(x) => write!(f, "Failed to write rules file: {}", x),
+        }
+    }
+}
+
+/// Replace the rules in the rules file, keeping the rest of its content
+fn write_rules_file(path: &str, rules: &RulesConfig) -> io::Result<()> {
+    let mut doc = match fs::read_to_string(path) {
+        Ok(x) => x
+            .parse::<Document>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Document::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut tables = vec![];
+    for x in &rules.rule {
+        let mut table = Table::new();
+        if let Some(x) = &x.cidr {
+            table["cidr"] = value(x);
+        }
+        if let Some(x) = &x.client_random_prefix {
+            table["client_random_prefix"] = value(x);
+        }
+        table["action"] = value(match x.action {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        });
+        tables.push(table);
+    }
+    replace_tables(&mut doc, "rule", tables);
+
+    let mut tables = vec![];
+    for x in &rules.route {
+        let mut table = Table::new();
+        table["destination"] = value(&x.destination);
+        for (key, x) in [
+            ("cidr", &x.cidr),
+            ("override_sni", &x.override_sni),
+            ("override_host", &x.override_host),
+        ] {
+            if let Some(x) = x {
+                table[key] = value(x);
+            }
+        }
+        if !x.race_uplinks.is_empty() {
+            table["race_uplinks"] = value(x.race_uplinks.iter().collect::<toml_edit::Array>());
+        }
+        tables.push(table);
+    }
+    replace_tables(&mut doc, "route", tables);
+
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(doc.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Replace the array of tables under the key, keeping the comments above its first table
+fn replace_tables(doc: &mut Document, key: &str, tables: Vec<Table>) {
+    let prefix = doc
+        .remove(key)
+        .and_then(|x| x.as_array_of_tables()?.get(0)?.decor().prefix().cloned());
+    let mut tables: ArrayOfTables = tables.into_iter().collect();
+    if let (Some(prefix), Some(x)) = (prefix, tables.get_mut(0)) {
+        x.decor_mut().set_prefix(prefix);
+    }
+    if !tables.is_empty() {
+        doc[key] = Item::ArrayOfTables(tables);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -238,6 +763,7 @@ mod tests {
                     action: RuleAction::Deny, // Catch-all deny
                 },
             ],
+            route: vec![],
         };
 
         let engine = RulesEngine::from_config(rules);
@@ -259,6 +785,7 @@ mod tests {
                 client_random_prefix: Some("aabbcc".to_string()),
                 action: RuleAction::Allow,
             }],
+            route: vec![],
         };
 
         let engine = RulesEngine::from_config(rules);
@@ -329,4 +856,260 @@ mod tests {
         // Should not match due to invalid format
         assert!(!rule.matches(&ip, Some(&client_random)));
     }
+
+    #[test]
+    fn test_route_rule_matching() {
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            route: vec![
+                RouteRule {
+                    destination: "*.example.org".to_string(),
+                    cidr: Some("10.0.0.0/8".to_string()),
+                    override_sni: Some("front.example.net".to_string()),
+                    override_host: None,
+                    race_uplinks: vec![],
+                },
+                RouteRule {
+                    destination: "example.org".to_string(),
+                    cidr: None,
+                    override_sni: None,
+                    override_host: Some("internal.example.org".to_string()),
+                    race_uplinks: vec![],
+                },
+            ],
+        });
+
+        let ip_match = IpAddr::from_str("10.1.2.3").unwrap();
+        let ip_no_match = IpAddr::from_str("192.168.1.1").unwrap();
+
+        let sni = |ip, host| {
+            engine
+                .route(ip, host)
+                .and_then(|r| r.override_sni.as_deref())
+        };
+        assert_eq!(sni(&ip_match, "a.example.org"), Some("front.example.net"));
+        assert_eq!(
+            sni(&ip_match, "A.B.Example.Org."),
+            Some("front.example.net")
+        );
+        assert_eq!(sni(&ip_no_match, "a.example.org"), None);
+        assert_eq!(sni(&ip_match, "aexample.org"), None);
+
+        let host = |ip, host| {
+            engine
+                .route(ip, host)
+                .and_then(|r| r.override_host.as_deref())
+        };
+        assert_eq!(
+            host(&ip_no_match, "example.org"),
+            Some("internal.example.org")
+        );
+        assert_eq!(host(&ip_match, "example.com"), None);
+    }
+
+    #[test]
+    fn test_route_patterns() {
+        let route = |destination: &str, cidr: Option<&str>, sni: &str| RouteRule {
+            destination: destination.to_string(),
+            cidr: cidr.map(str::to_string),
+            override_sni: Some(sni.to_string()),
+            override_host: None,
+            race_uplinks: vec![],
+        };
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            route: vec![
+                route(r"~cdn[0-9]+\.example\.org", Some("10.0.0.0/8"), "inner"),
+                route(".example.org", None, "suffix"),
+                route("~(unclosed", None, "malformed"),
+            ],
+        });
+        let inner = IpAddr::from_str("10.1.2.3").unwrap();
+        let outer = IpAddr::from_str("192.168.1.1").unwrap();
+        let sni = |ip, host| {
+            engine
+                .route(ip, host)
+                .and_then(|r| r.override_sni.as_deref())
+        };
+
+        assert_eq!(sni(&inner, "CDN7.example.org"), Some("inner"));
+        // The next matching rule is taken if the client does not match the first one
+        assert_eq!(sni(&outer, "cdn7.example.org"), Some("suffix"));
+        assert_eq!(sni(&inner, "example.org"), Some("suffix"));
+        assert_eq!(sni(&inner, "(unclosed"), None);
+
+        let live = LiveRules::new(Some(&engine));
+        // A runtime change is refused while a malformed rule is left
+        live.update(RulesChange::Remove(RuleList::Route, 2), false)
+            .unwrap();
+        let change = |x| live.update(RulesChange::AddRoute(0, route(x, None, "new")), false);
+        assert!(matches!(
+            change("~[z-a]"),
+            Err(RulesUpdateError::Invalid(_))
+        ));
+        change("~www\\..*").unwrap();
+        let sni = |host| {
+            let x = live.current();
+            x.route(&inner, host).and_then(|r| r.override_sni.clone())
+        };
+        assert_eq!(sni("www.example.org").as_deref(), Some("new"));
+        assert_eq!(sni("cdn7.example.org").as_deref(), Some("inner"));
+    }
+
+    #[test]
+    fn test_live_rules_changes() {
+        let path = std::env::temp_dir()
+            .join(format!("trusttunnel-rules-{}.toml", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let rule = |cidr: &str, action| Rule {
+            cidr: Some(cidr.to_string()),
+            client_random_prefix: None,
+            action,
+        };
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![rule("10.0.0.0/8", RuleAction::Allow)],
+            route: vec![],
+        })
+        .with_path(path.clone());
+        let rules = LiveRules::new(Some(&engine));
+        let ip = IpAddr::from_str("10.1.2.3").unwrap();
+
+        let before = rules.current();
+        rules
+            .update(
+                RulesChange::AddRule(0, rule("10.1.0.0/16", RuleAction::Deny)),
+                false,
+            )
+            .unwrap();
+        assert_eq!(RuleEvaluation::Deny, rules.current().evaluate(&ip, None));
+        assert_eq!(RuleEvaluation::Allow, before.evaluate(&ip, None));
+
+        // the invalid changes leave the rules as they are
+        let invalid = RulesChange::AddRule(0, rule("10.1.0.0/33", RuleAction::Allow));
+        assert!(matches!(
+            rules.update(invalid, false),
+            Err(RulesUpdateError::Invalid(_))
+        ));
+        assert!(rules
+            .update(RulesChange::Remove(RuleList::Rule, 2), false)
+            .is_err());
+        assert_eq!(2, rules.current().config().rule.len());
+
+        rules
+            .update(RulesChange::Move(RuleList::Rule, 0, 1), false)
+            .unwrap();
+        assert_eq!(RuleEvaluation::Allow, rules.current().evaluate(&ip, None));
+
+        let route = RouteRule {
+            destination: "*.Example.ORG".to_string(),
+            cidr: None,
+            override_sni: Some("front.example.net".to_string()),
+            override_host: None,
+            race_uplinks: vec![],
+        };
+        fs::write(
+            &path,
+            "# Managed by the admin interface\n[[rule]]\naction = \"allow\"\n",
+        )
+        .unwrap();
+        rules.update(RulesChange::AddRoute(0, route), true).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let doc = content.parse::<Document>().unwrap();
+        assert!(content.starts_with("# Managed by the admin interface"));
+        let rule = doc["rule"].as_array_of_tables().unwrap();
+        assert_eq!(
+            ["10.0.0.0/8", "10.1.0.0/16"].as_slice(),
+            rule.iter()
+                .map(|x| x["cidr"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some("deny"), rule.get(1).unwrap()["action"].as_str());
+        let route = doc["route"].as_array_of_tables().unwrap();
+        assert_eq!(
+            Some("*.example.org"),
+            route.get(0).unwrap()["destination"].as_str()
+        );
+    }
+
+    #[test]
+    fn test_rule_hits_and_explain() {
+        let rule = |cidr: &str, action| Rule {
+            cidr: Some(cidr.to_string()),
+            client_random_prefix: None,
+            action,
+        };
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![
+                rule("10.1.0.0/16", RuleAction::Deny),
+                rule("10.0.0.0/8", RuleAction::Allow),
+            ],
+            route: vec![RouteRule {
+                destination: "*.example.org".to_string(),
+                cidr: None,
+                override_sni: Some("front.example.net".to_string()),
+                override_host: None,
+                race_uplinks: vec![],
+            }],
+        });
+        let rules = LiveRules::new(Some(&engine));
+        let ip = IpAddr::from_str("10.1.2.3").unwrap();
+        let other_ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        let current = rules.current();
+        assert_eq!(
+            (RuleEvaluation::Deny, FilterMatch::Rule(0)),
+            current.evaluate_traced(&ip, None)
+        );
+        current.evaluate(&ip, None);
+        current.evaluate(&other_ip, None);
+        assert!(current.route(&ip, "git.example.org").is_some());
+        assert_eq!(vec![2, 0], current.hits(RuleList::Rule));
+        assert_eq!(vec![1], current.hits(RuleList::Route));
+        assert_eq!(1, current.unmatched_hits());
+
+        // the counters follow the rules they belong to
+        rules
+            .update(
+                RulesChange::AddRule(0, rule("192.0.2.0/24", RuleAction::Deny)),
+                false,
+            )
+            .unwrap();
+        rules
+            .update(RulesChange::Move(RuleList::Rule, 1, 2), false)
+            .unwrap();
+        assert_eq!(vec![0, 0, 2], rules.current().hits(RuleList::Rule));
+
+        let explanation = rules
+            .current()
+            .explain(None, &ip, None, Some("git.example.org"));
+        assert_eq!(RuleAction::Allow, explanation.filter.action);
+        assert_eq!(Some(1), explanation.filter.rule);
+        assert!(explanation.profile.is_none());
+        let route = explanation.route.unwrap();
+        assert!(!route.profile && route.position == 0);
+        // explaining does not count
+        assert_eq!(vec![0, 0, 2], rules.current().hits(RuleList::Rule));
+
+        let settings = crate::settings::ProfileSettings::builder()
+            .destination("*.example.org", RuleAction::Deny)
+            .build()
+            .unwrap();
+        let registry = crate::profiles::ProfileRegistry::new(&std::collections::HashMap::from([(
+            "staff".to_string(),
+            settings,
+        )]));
+        let profile = registry.get("staff").unwrap();
+        let explanation =
+            rules
+                .current()
+                .explain(Some(&profile), &other_ip, None, Some("example.com"));
+        assert_eq!(RuleAction::Deny, explanation.filter.action);
+        assert_eq!(Some(0), explanation.filter.rule);
+        let profile = explanation.profile.unwrap();
+        assert_eq!((None, "default"), (profile.rule, profile.reason));
+        assert!(explanation.route.is_none());
+    }
 }
@@ -0,0 +1,317 @@
+//! The scheduled maintenance windows. During a window the endpoint drains: the multiplexed
+//! sessions are asked to shut down gracefully, so that the clients reconnect to another
+//! endpoint of a cluster, and the new tunnel requests are refused with `503 Service Unavailable`.
+//! The clients are notified of an upcoming window in the successful tunnel responses.
+//! The administration interface may override the schedule in either direction.
+
+use crate::core::{self, RebalanceOrder};
+use crate::settings::ScheduleSettings;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Carries the upcoming window as `<start>-<end>` UNIX timestamps in seconds
+pub(crate) const MAINTENANCE_HEADER: &str = "x-trusttunnel-maintenance";
+
+pub(crate) const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+pub(crate) const WEEK: Duration = Duration::from_secs(7 * DAY);
+const DAY: u64 = 24 * 60 * 60;
+/// The UNIX epoch is Thursday
+const EPOCH_WEEKDAY: u64 = 3;
+/// Limits the sleep of the scheduler in case the system clock jumps
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Override {
+    /// Follow the schedule
+    Auto,
+    /// Drain regardless of the schedule
+    Drain,
+    /// Accept the tunnels regardless of the schedule
+    Accept,
+}
+
+impl Override {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Drain => "drain",
+            Self::Accept => "accept",
+        }
+    }
+
+    pub fn parse(x: &str) -> Option<Self> {
+        match x {
+            "auto" => Some(Self::Auto),
+            "drain" => Some(Self::Drain),
+            "accept" => Some(Self::Accept),
+            _ => None,
+        }
+    }
+}
+
+/// A maintenance window occurrence, the UNIX timestamps in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Occurrence {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct State {
+    pub mode: Override,
+    pub draining: bool,
+    /// The window in progress or the upcoming one
+    pub occurrence: Option<Occurrence>,
+}
+
+impl State {
+    /// How long the drain is expected to last, if it follows the schedule
+    pub fn retry_after(&self) -> Option<Duration> {
+        match (self.mode, self.draining, self.occurrence) {
+            (Override::Auto, true, Some(x)) => {
+                Some(Duration::from_secs(x.end.saturating_sub(unix_now())))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Schedule {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State {
+                mode: Override::Auto,
+                draining: false,
+                occurrence: None,
+            }),
+            changed: Notify::new(),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+}
+
+/// Override the schedule and apply the resulting state right away
+pub(crate) fn set_override(context: &core::Context, mode: Override) {
+    let (Some(schedule), Some(settings)) = (&context.schedule, &context.settings.schedule) else {
+        return;
+    };
+    schedule.state.lock().unwrap().mode = mode;
+    info!("Maintenance schedule override is {}", mode.as_str());
+    update(context, schedule, settings, unix_now());
+    schedule.changed.notify_one();
+}
+
+/// Drive the endpoint state according to the schedule
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let (Some(schedule), Some(settings)) = (&context.schedule, &context.settings.schedule) else {
+        return Ok(());
+    };
+
+    loop {
+        let now = unix_now();
+        let state = update(&context, schedule, settings, now);
+        let wake_at = state
+            .occurrence
+            .map(|x| if x.start <= now { x.end } else { x.start });
+        let delay = wake_at.map_or(MAX_SLEEP, |x| {
+            Duration::from_secs(x.saturating_sub(now)).min(MAX_SLEEP)
+        });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = schedule.changed.notified() => (),
+        }
+    }
+}
+
+/// Make the header notifying a client of the upcoming window, if any
+pub(crate) fn notice_header(context: &core::Context) -> Option<(String, String)> {
+    let (Some(schedule), Some(settings)) = (&context.schedule, &context.settings.schedule) else {
+        return None;
+    };
+    let state = schedule.state();
+    let occurrence = state.occurrence?;
+    let now = unix_now();
+    (state.mode == Override::Auto
+        && now < occurrence.start
+        && occurrence.start - now <= settings.notice.as_secs())
+    .then(|| {
+        (
+            MAINTENANCE_HEADER.to_string(),
+            format!("{}-{}", occurrence.start, occurrence.end),
+        )
+    })
+}
+
+/// Find the window in progress at `now`, or else the closest upcoming one
+pub(crate) fn next_occurrence(settings: &ScheduleSettings, now: u64) -> Option<Occurrence> {
+    let today = now / DAY;
+    let mut current: Option<Occurrence> = None;
+    let mut upcoming: Option<Occurrence> = None;
+    for window in &settings.windows {
+        let Some(start_secs) = parse_time_of_day(&window.start) else {
+            continue;
+        };
+        // A window lasts at most a week, so the one in progress started during the past week
+        for day in today.saturating_sub(7)..=today + 7 {
+            let weekday = DAYS[((day + EPOCH_WEEKDAY) % 7) as usize];
+            if !window.days.is_empty() && !window.days.iter().any(|x| x == weekday) {
+                continue;
+            }
+            let start = day * DAY + start_secs;
+            let x = Occurrence {
+                start,
+                end: start + window.duration.as_secs(),
+            };
+            if x.end <= now {
+                continue;
+            }
+            if start <= now {
+                if current.is_none_or(|c| c.end < x.end) {
+                    current = Some(x);
+                }
+            } else if upcoming.is_none_or(|u| x.start < u.start) {
+                upcoming = Some(x);
+            }
+        }
+    }
+
+    current.or(upcoming)
+}
+
+/// Parse the `HH:MM` time into the seconds since midnight
+pub(crate) fn parse_time_of_day(x: &str) -> Option<u64> {
+    let (h, m) = x.split_once(':')?;
+    if h.len() != 2 || m.len() != 2 {
+        return None;
+    }
+    let (h, m) = (h.parse::<u64>().ok()?, m.parse::<u64>().ok()?);
+    (h < 24 && m < 60).then_some(h * 60 * 60 + m * 60)
+}
+
+fn update(
+    context: &core::Context,
+    schedule: &Schedule,
+    settings: &ScheduleSettings,
+    now: u64,
+) -> State {
+    let occurrence = next_occurrence(settings, now);
+    let (state, was_draining) = {
+        let mut state = schedule.state.lock().unwrap();
+        let was_draining = state.draining;
+        state.occurrence = occurrence;
+        state.draining = match state.mode {
+            Override::Auto => occurrence.is_some_and(|x| x.start <= now),
+            Override::Drain => true,
+            Override::Accept => false,
+        };
+        (*state, was_draining)
+    };
+
+    match (was_draining, state.draining) {
+        (false, true) => {
+            let n = context
+                .sessions
+                .rebalance(usize::MAX, RebalanceOrder::LongestLived);
+            info!("Entered maintenance, asked {} sessions to drain", n);
+        }
+        (true, false) => info!("Left maintenance, accepting tunnels"),
+        _ => (),
+    }
+
+    state
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MaintenanceWindowSettings;
+
+    /// 2024-01-06 00:00:00 UTC, Saturday
+    const SATURDAY: u64 = 1_704_499_200;
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn finds_occurrences() {
+        let settings = ScheduleSettings::builder()
+            .window(
+                MaintenanceWindowSettings::builder("02:30", Duration::from_secs(2 * HOUR))
+                    .days(vec!["sat".into(), "sun".into()])
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let saturday = Occurrence {
+            start: SATURDAY + 2 * HOUR + 1800,
+            end: SATURDAY + 4 * HOUR + 1800,
+        };
+        let sunday = Occurrence {
+            start: saturday.start + DAY,
+            end: saturday.end + DAY,
+        };
+
+        assert_eq!(Some(saturday), next_occurrence(&settings, SATURDAY));
+        assert_eq!(Some(saturday), next_occurrence(&settings, saturday.start));
+        assert_eq!(Some(saturday), next_occurrence(&settings, saturday.end - 1));
+        assert_eq!(Some(sunday), next_occurrence(&settings, saturday.end));
+        // The next weekend
+        assert_eq!(
+            Some(Occurrence {
+                start: saturday.start + 7 * DAY,
+                end: saturday.end + 7 * DAY,
+            }),
+            next_occurrence(&settings, sunday.end)
+        );
+        assert_eq!(
+            None,
+            next_occurrence(&ScheduleSettings::builder().build().unwrap(), SATURDAY)
+        );
+    }
+
+    #[test]
+    fn window_spans_midnight() {
+        let settings = ScheduleSettings::builder()
+            .window(
+                MaintenanceWindowSettings::builder("23:00", Duration::from_secs(2 * HOUR))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(
+            Some(Occurrence {
+                start: SATURDAY - HOUR,
+                end: SATURDAY + HOUR,
+            }),
+            next_occurrence(&settings, SATURDAY)
+        );
+    }
+
+    #[test]
+    fn parses_time_of_day() {
+        assert_eq!(Some(0), parse_time_of_day("00:00"));
+        assert_eq!(Some(23 * HOUR + 59 * 60), parse_time_of_day("23:59"));
+        for x in ["24:00", "12:60", "1:00", "12:5", "1200", ""] {
+            assert_eq!(None, parse_time_of_day(x), "{}", x);
+        }
+    }
+}
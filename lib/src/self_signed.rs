@@ -0,0 +1,113 @@
+//! The development mode certificate. The TLS hosts configured without a certificate are served
+//! with a self-signed one generated on start (see [`TlsHostsSettings::self_signed`]), so that
+//! an endpoint is brought up locally or in a test harness without issuing one beforehand.
+//!
+//! [`TlsHostsSettings::self_signed`]: crate::settings::TlsHostsSettings
+
+use crate::settings::{SelfSignedSettings, TlsHostInfo};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Generate a certificate for the hosts and point them to it. The certificate is issued for
+/// the hostnames and the allowed SNIs of the hosts, and the configured subject alternative names.
+/// The QUIC stack loads a certificate by path, so the certificate and its key are written
+/// to a file in the temporary directory, which is replaced on each generation.
+pub(crate) fn generate(
+    settings: &SelfSignedSettings,
+    hosts: Vec<&mut TlsHostInfo>,
+) -> io::Result<()> {
+    if hosts.is_empty() {
+        return Ok(());
+    }
+
+    let mut names: Vec<String> = vec![];
+    for x in hosts
+        .iter()
+        .flat_map(|x| std::iter::once(&x.hostname).chain(&x.allowed_sni))
+        .chain(&settings.subject_alt_names)
+    {
+        if !names.contains(x) {
+            names.push(x.clone());
+        }
+    }
+
+    let pem = certificate_pem(names.clone())
+        .map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to generate: {}", e)))?;
+    let path = std::env::temp_dir().join(format!(
+        "trusttunnel-self-signed-{}.pem",
+        std::process::id()
+    ));
+    write(&path, pem.as_bytes())?;
+    let path = path.to_string_lossy().to_string();
+    warn!(
+        "Serving [{}] with generated self-signed certificate {}, MUST NOT be used in production",
+        names.join(", "),
+        path
+    );
+
+    for x in hosts {
+        x.cert_chain_path = path.clone();
+        x.private_key_path = path.clone();
+    }
+    Ok(())
+}
+
+/// Get the certificate and its private key in PEM format
+fn certificate_pem(names: Vec<String>) -> Result<String, rcgen::Error> {
+    let common_name = names.first().cloned().unwrap_or_default();
+    let mut params = rcgen::CertificateParams::new(names)?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, common_name);
+    let key = rcgen::KeyPair::generate()?;
+    let certificate = params.self_signed(&key)?;
+    Ok(format!("{}{}", certificate.pem(), key.serialize_pem()))
+}
+
+/// Replace the file at once with the one only the current user is able to read
+fn write(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("pem.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(content)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    #[test]
+    fn generates_certificate() {
+        let settings = SelfSignedSettings {
+            subject_alt_names: vec!["127.0.0.1".into(), "localhost".into()],
+        };
+        let mut host = TlsHostInfo {
+            hostname: "localhost".into(),
+            allowed_sni: vec!["vpn.localhost".into()],
+            ..Default::default()
+        };
+
+        generate(&settings, vec![&mut host]).unwrap();
+        assert_eq!(host.cert_chain_path, host.private_key_path);
+        let chain = utils::load_certs(&host.cert_chain_path).unwrap();
+        utils::load_private_key(&host.private_key_path).unwrap();
+        let (_, x) = x509_parser::parse_x509_certificate(&chain[0].0).unwrap();
+        let names: Vec<String> = x
+            .subject_alternative_name()
+            .unwrap()
+            .unwrap()
+            .value
+            .general_names
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        // The duplicate `localhost` is dropped
+        assert_eq!(3, names.len(), "{:?}", names);
+        std::fs::remove_file(&host.cert_chain_path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! The [`Server-Timing`](https://www.w3.org/TR/server-timing/) header of the tunnel
+//! establishment and the ping responses. It lets the client applications tell the time
+//! the endpoint spends on a request from the time the request and the response spend
+//! on the path, e.g., `server-timing: auth;dur=12.5, upstream_connect;dur=48.1, total;dur=61.0`.
+//! The durations are in milliseconds, the phases are named after the
+//! [stage timeouts](crate::settings::TimeoutSettings).
+
+use std::time::{Duration, Instant};
+
+pub(crate) const HEADER: &str = "server-timing";
+/// The authentication of a tunnel request, including the admission checks
+pub(crate) const AUTH: &str = "auth";
+/// The connection to the peer of a tunneled TCP connection
+pub(crate) const UPSTREAM_CONNECT: &str = "upstream_connect";
+/// The processing of a request up to the response
+const TOTAL: &str = "total";
+
+/// The durations of the phases of the processing of a request
+#[derive(Clone)]
+pub(crate) struct ServerTiming {
+    started: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl ServerTiming {
+    /// Start timing a request received just now
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// The time since the request is received
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Record the duration of a phase
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        self.phases.push((name, duration));
+    }
+
+    /// Format the header value of the recorded phases followed by the total processing
+    /// time so far
+    pub fn header_value(&self) -> String {
+        self.phases
+            .iter()
+            .chain(std::iter::once(&(TOTAL, self.elapsed())))
+            .map(|(name, x)| format!("{};dur={:.1}", name, x.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_value() {
+        let mut timing = ServerTiming::start();
+        assert!(timing.header_value().starts_with("total;dur="));
+
+        timing.record(AUTH, Duration::from_micros(12_460));
+        timing.record(UPSTREAM_CONNECT, Duration::from_secs(2));
+        let value = timing.header_value();
+        assert!(
+            value.starts_with("auth;dur=12.5, upstream_connect;dur=2000.0, total;dur="),
+            "{}",
+            value
+        );
+    }
+}
@@ -0,0 +1,699 @@
+use crate::bandwidth::{Estimate, Estimator};
+use crate::core::RebalanceOrder;
+use crate::settings::{DuplicateSessionAction, DuplicateSessionSettings};
+use crate::tls_demultiplexer::Protocol;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Keeps track of the active client tunnels
+#[derive(Default)]
+pub(crate) struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<u64, Session>>>,
+    next_id: AtomicU64,
+    /// The number of the active streams of the authenticated identities summed across
+    /// their sessions. The identities without active streams are not kept.
+    identity_streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+struct Session {
+    protocol: Protocol,
+    started_at: Instant,
+    state: Arc<SessionState>,
+    /// The identities the tunnel requests of the session are authenticated with
+    identities: Vec<String>,
+    /// The IDs of the tokens the tunnel requests of the session are authenticated with
+    token_ids: Vec<String>,
+    bandwidth: Estimator,
+}
+
+#[derive(Default)]
+struct SessionState {
+    active_streams: AtomicUsize,
+    draining: AtomicBool,
+    drain: Notify,
+    /// Whether a newer session of the same identity has taken the place of the session
+    replaced: AtomicBool,
+    replace: Notify,
+    /// Whether the credentials the session is authenticated with are revoked
+    revoked: AtomicBool,
+    revoke: Notify,
+    /// Whether the lifetime of the session is limited
+    limited: AtomicBool,
+    expire: Notify,
+    inbound_bytes: AtomicU64,
+    outbound_bytes: AtomicU64,
+}
+
+/// The point-in-time view of an active session
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SessionInfo {
+    pub id: u64,
+    pub protocol: Protocol,
+    pub age: Duration,
+    pub active_streams: usize,
+    pub draining: bool,
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+    pub bandwidth: Estimate,
+}
+
+/// Keeps a session registered until dropped
+pub(crate) struct SessionHandle {
+    id: u64,
+    sessions: Arc<Mutex<HashMap<u64, Session>>>,
+    state: Arc<SessionState>,
+}
+
+/// Gets notified once the session is asked to drain
+pub(crate) struct DrainSignal {
+    state: Arc<SessionState>,
+}
+
+/// Gets notified once the session is replaced by a newer one of the same identity
+pub(crate) struct ReplaceSignal {
+    state: Arc<SessionState>,
+}
+
+/// Gets notified once the session is to be closed for its revoked credentials
+pub(crate) struct RevokeSignal {
+    state: Arc<SessionState>,
+}
+
+/// Gets notified once the session has reached its maximum lifetime
+pub(crate) struct ExpireSignal {
+    state: Arc<SessionState>,
+}
+
+#[derive(Debug)]
+pub(crate) struct DuplicateSessionError {
+    identity: String,
+    limit: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct StreamLimitError {
+    identity: String,
+    limit: usize,
+}
+
+/// Accounts a stream in the session load, and in the streams of its identity
+/// once admitted, until dropped
+pub(crate) struct StreamGuard {
+    state: Arc<SessionState>,
+    /// The identity the stream is accounted to, along with the streams of the identities
+    identity: Option<(String, Arc<Mutex<HashMap<String, usize>>>)>,
+}
+
+/// Accounts the traffic transferred through the session
+#[derive(Clone)]
+pub(crate) struct TrafficCounter {
+    state: Arc<SessionState>,
+}
+
+impl SessionRegistry {
+    pub fn register(&self, protocol: Protocol) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(SessionState::default());
+        self.sessions.lock().unwrap().insert(
+            id,
+            Session {
+                protocol,
+                started_at: Instant::now(),
+                state: state.clone(),
+                identities: Default::default(),
+                token_ids: Default::default(),
+                bandwidth: Default::default(),
+            },
+        );
+
+        SessionHandle {
+            id,
+            sessions: self.sessions.clone(),
+            state,
+        }
+    }
+
+    /// Ask up to `count` multiplexed sessions selected according to `order` to drain.
+    ///
+    /// # Return
+    ///
+    /// The number of the sessions asked to drain
+    pub fn rebalance(&self, count: usize, order: RebalanceOrder) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut candidates: Vec<&Session> = sessions
+            .values()
+            .filter(|x| x.protocol != Protocol::Http1)
+            .filter(|x| !x.state.draining.load(Ordering::Relaxed))
+            .collect();
+
+        match order {
+            RebalanceOrder::LongestLived => candidates.sort_by_key(|x| x.started_at),
+            RebalanceOrder::MostLoaded => {
+                candidates.sort_by_key(|x| Reverse(x.state.active_streams.load(Ordering::Relaxed)))
+            }
+        }
+
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|x| {
+                x.state.draining.store(true, Ordering::Relaxed);
+                x.state.drain.notify_one();
+            })
+            .count()
+    }
+
+    /// Account the session `id` to the authenticated `identity`. In case the identity
+    /// already has the maximum number of the sessions, either the session is refused,
+    /// or the oldest ones are asked to close, depending on the settings.
+    pub fn bind(
+        &self,
+        id: u64,
+        identity: &str,
+        settings: &DuplicateSessionSettings,
+    ) -> Result<(), DuplicateSessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let is_bound = |x: &Session| x.identities.iter().any(|x| x == identity);
+        if sessions.get(&id).is_none_or(is_bound) {
+            return Ok(());
+        }
+
+        let mut others: Vec<(&u64, &Session)> = sessions
+            .iter()
+            .filter(|(x, _)| **x != id)
+            .filter(|(_, x)| !x.state.replaced.load(Ordering::Relaxed) && is_bound(x))
+            .collect();
+        if others.len() >= settings.max_sessions {
+            match settings.action {
+                DuplicateSessionAction::RejectNew => {
+                    return Err(DuplicateSessionError {
+                        identity: identity.to_string(),
+                        limit: settings.max_sessions,
+                    })
+                }
+                DuplicateSessionAction::ReplaceOldest => {
+                    others.sort_by_key(|(_, x)| x.started_at);
+                    let n = others.len() + 1 - settings.max_sessions;
+                    for (x, session) in others.into_iter().take(n) {
+                        debug!("Replacing session {} of {}", x, identity);
+                        session.state.replaced.store(true, Ordering::Relaxed);
+                        session.state.replace.notify_one();
+                    }
+                }
+            }
+        }
+
+        if let Some(x) = sessions.get_mut(&id) {
+            x.identities.push(identity.to_string());
+        }
+        Ok(())
+    }
+
+    /// Account the `stream` to the authenticated `identity` allowed to have up to `limit`
+    /// active streams across all its sessions. A stream accounted already is let through.
+    pub fn admit_stream(
+        &self,
+        stream: &mut StreamGuard,
+        identity: String,
+        limit: usize,
+    ) -> Result<(), StreamLimitError> {
+        if stream.identity.is_some() {
+            return Ok(());
+        }
+        let mut streams = self.identity_streams.lock().unwrap();
+        let n = streams.entry(identity.clone()).or_default();
+        if *n >= limit {
+            if *n == 0 {
+                streams.remove(&identity);
+            }
+            return Err(StreamLimitError { identity, limit });
+        }
+        *n += 1;
+        stream.identity = Some((identity, self.identity_streams.clone()));
+        Ok(())
+    }
+
+    /// Record the identity and the token ID the session `id` is authenticated with,
+    /// so that the session is found on revoking them. Unlike [`Self::bind`], does not account
+    /// the session to the identity.
+    pub fn identify(&self, id: u64, identity: &str, token_id: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&id) else {
+            return;
+        };
+        if !session.identities.iter().any(|x| x == identity) {
+            session.identities.push(identity.to_string());
+        }
+        if let Some(x) = token_id.filter(|x| !session.token_ids.contains(x)) {
+            session.token_ids.push(x);
+        }
+    }
+
+    /// Close the sessions authenticated with any of the revoked identities or token IDs
+    /// once the `grace_period` is over.
+    ///
+    /// # Return
+    ///
+    /// The number of the sessions to be closed
+    pub fn revoke(
+        &self,
+        identities: &BTreeSet<String>,
+        token_ids: &BTreeSet<String>,
+        grace_period: Duration,
+    ) -> usize {
+        let states: Vec<Arc<SessionState>> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, x)| {
+                x.identities.iter().any(|x| identities.contains(x))
+                    || x.token_ids.iter().any(|x| token_ids.contains(x))
+            })
+            .filter(|(_, x)| !x.state.revoked.swap(true, Ordering::Relaxed))
+            .map(|(id, x)| {
+                debug!(
+                    "Closing session {} of revoked credentials in {:?}",
+                    id, grace_period
+                );
+                x.state.clone()
+            })
+            .collect();
+        let n = states.len();
+        if n > 0 {
+            tokio::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                for x in states {
+                    x.revoke.notify_one();
+                }
+            });
+        }
+        n
+    }
+
+    /// Close the session `id` once it has lived for `max_duration` since it started.
+    /// The first limit set for the session stays in effect.
+    pub fn limit_lifetime(&self, id: u64, max_duration: Duration) {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get(&id) else {
+            return;
+        };
+        if session.state.limited.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let (state, deadline) = (session.state.clone(), session.started_at + max_duration);
+        tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            state.expire.notify_one();
+        });
+    }
+
+    /// Get the active sessions ordered by identifier
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let mut list: Vec<SessionInfo> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, x)| SessionInfo {
+                id: *id,
+                protocol: x.protocol,
+                age: now.saturating_duration_since(x.started_at),
+                active_streams: x.state.active_streams.load(Ordering::Relaxed),
+                draining: x.state.draining.load(Ordering::Relaxed),
+                inbound_bytes: x.state.inbound_bytes.load(Ordering::Relaxed),
+                outbound_bytes: x.state.outbound_bytes.load(Ordering::Relaxed),
+                bandwidth: x.bandwidth.estimate(),
+            })
+            .collect();
+        list.sort_by_key(|x| x.id);
+        list
+    }
+
+    /// Take a sample of the traffic of each session for the bandwidth estimation
+    pub fn sample_bandwidth(&self, window: Duration) {
+        let now = Instant::now();
+        for x in self.sessions.lock().unwrap().values_mut() {
+            x.bandwidth.sample(
+                now,
+                window,
+                x.state.inbound_bytes.load(Ordering::Relaxed),
+                x.state.outbound_bytes.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    /// Get the estimated bandwidth of the session `id`
+    pub fn bandwidth(&self, id: u64) -> Option<Estimate> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|x| x.bandwidth.estimate())
+    }
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn stream_guard(&self) -> StreamGuard {
+        self.state.active_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard {
+            state: self.state.clone(),
+            identity: None,
+        }
+    }
+
+    pub fn drain_signal(&self) -> DrainSignal {
+        DrainSignal {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn replace_signal(&self) -> ReplaceSignal {
+        ReplaceSignal {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn revoke_signal(&self) -> RevokeSignal {
+        RevokeSignal {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn expire_signal(&self) -> ExpireSignal {
+        ExpireSignal {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn traffic_counter(&self) -> TrafficCounter {
+        TrafficCounter {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl TrafficCounter {
+    pub fn add_inbound_bytes(&self, n: usize) {
+        self.state
+            .inbound_bytes
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_outbound_bytes(&self, n: usize) {
+        self.state
+            .outbound_bytes
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl DrainSignal {
+    /// Wait for the session to be asked to drain
+    pub async fn wait(&self) {
+        self.state.drain.notified().await
+    }
+}
+
+impl ReplaceSignal {
+    /// Wait for the session to be replaced
+    pub async fn wait(&self) {
+        self.state.replace.notified().await
+    }
+}
+
+impl RevokeSignal {
+    /// Wait for the grace period of the revoked credentials of the session to be over
+    pub async fn wait(&self) {
+        self.state.revoke.notified().await
+    }
+}
+
+impl ExpireSignal {
+    /// Wait for the session to reach its maximum lifetime
+    pub async fn wait(&self) {
+        self.state.expire.notified().await
+    }
+}
+
+impl Display for DuplicateSessionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sessions limit of {} reached for {}",
+            self.limit, self.identity
+        )
+    }
+}
+
+impl Display for StreamLimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Streams limit of {} reached for {}",
+            self.limit, self.identity
+        )
+    }
+}
+
+/// Serialize the sessions list into a JSON document
+pub(crate) fn to_json(sessions: &[SessionInfo]) -> String {
+    let mut out = String::from("{\"sessions\":[");
+    for (i, x) in sessions.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"session\":{},\"protocol\":\"{}\",\"age_secs\":{},\"active_streams\":{},\
+            \"draining\":{},\"inbound_bytes\":{},\"outbound_bytes\":{},\
+            \"estimated_inbound_bps\":{},\"estimated_outbound_bps\":{}}}",
+            x.id,
+            x.protocol.as_str(),
+            x.age.as_secs(),
+            x.active_streams,
+            x.draining,
+            x.inbound_bytes,
+            x.outbound_bytes,
+            json_number(x.bandwidth.inbound_bps),
+            json_number(x.bandwidth.outbound_bps),
+        );
+    }
+    out.push_str("]}\n");
+    out
+}
+
+fn json_number(x: Option<u64>) -> String {
+    x.map_or_else(|| "null".to_string(), |x| x.to_string())
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.state.active_streams.fetch_sub(1, Ordering::Relaxed);
+        let Some((identity, streams)) = &self.identity else {
+            return;
+        };
+        let mut streams = streams.lock().unwrap();
+        if let Some(n) = streams.get_mut(identity) {
+            *n -= 1;
+            if *n == 0 {
+                streams.remove(identity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebalance_most_loaded_multiplexed_sessions() {
+        let registry = SessionRegistry::default();
+        let _h1 = registry.register(Protocol::Http1);
+        let idle = registry.register(Protocol::Http2);
+        let loaded = registry.register(Protocol::Http3);
+        let _streams = [loaded.stream_guard(), loaded.stream_guard()];
+
+        assert_eq!(1, registry.rebalance(1, RebalanceOrder::MostLoaded));
+        assert!(loaded.state.draining.load(Ordering::Relaxed));
+        assert!(!idle.state.draining.load(Ordering::Relaxed));
+
+        // the draining and HTTP/1 sessions are not selected again
+        assert_eq!(1, registry.rebalance(10, RebalanceOrder::LongestLived));
+        assert_eq!(0, registry.rebalance(10, RebalanceOrder::LongestLived));
+    }
+
+    #[tokio::test]
+    async fn drain_signal_is_kept_until_awaited() {
+        let registry = SessionRegistry::default();
+        let handle = registry.register(Protocol::Http2);
+        registry.rebalance(1, RebalanceOrder::LongestLived);
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            handle.drain_signal().wait(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn list_reports_traffic() {
+        let registry = SessionRegistry::default();
+        let handle = registry.register(Protocol::Http2);
+        let counter = handle.traffic_counter();
+        counter.add_inbound_bytes(10);
+        counter.add_outbound_bytes(20);
+
+        let list = registry.list();
+        assert_eq!(1, list.len());
+        assert_eq!((10, 20), (list[0].inbound_bytes, list[0].outbound_bytes));
+    }
+
+    #[test]
+    fn duplicate_sessions() {
+        let registry = SessionRegistry::default();
+        let reject = DuplicateSessionSettings::builder()
+            .max_sessions(2)
+            .build()
+            .unwrap();
+        let first = registry.register(Protocol::Http2);
+        let second = registry.register(Protocol::Http3);
+        let third = registry.register(Protocol::Http2);
+        assert!(registry.bind(first.id(), "alice", &reject).is_ok());
+        assert!(registry.bind(second.id(), "alice", &reject).is_ok());
+        // the session bound already is not accounted twice
+        assert!(registry.bind(second.id(), "alice", &reject).is_ok());
+        assert!(registry.bind(third.id(), "alice", &reject).is_err());
+        assert!(registry.bind(third.id(), "bob", &reject).is_ok());
+
+        let replace = DuplicateSessionSettings::builder()
+            .max_sessions(2)
+            .action(DuplicateSessionAction::ReplaceOldest)
+            .build()
+            .unwrap();
+        assert!(registry.bind(third.id(), "alice", &replace).is_ok());
+        assert!(first.state.replaced.load(Ordering::Relaxed));
+        assert!(!second.state.replaced.load(Ordering::Relaxed));
+
+        // the oldest of the rest is replaced next
+        drop(first);
+        let fourth = registry.register(Protocol::Http3);
+        assert!(registry.bind(fourth.id(), "alice", &replace).is_ok());
+        assert!(second.state.replaced.load(Ordering::Relaxed));
+        assert!(!third.state.replaced.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn streams_limit_spans_sessions() {
+        let registry = SessionRegistry::default();
+        let h2 = registry.register(Protocol::Http2);
+        let h3 = registry.register(Protocol::Http3);
+        let identity_streams = |x: &str| registry.identity_streams.lock().unwrap().get(x).copied();
+
+        let mut first = h2.stream_guard();
+        let mut second = h3.stream_guard();
+        let mut third = h2.stream_guard();
+        assert!(registry.admit_stream(&mut first, "alice".into(), 2).is_ok());
+        // the stream admitted already is not accounted twice
+        assert!(registry.admit_stream(&mut first, "alice".into(), 2).is_ok());
+        assert!(registry
+            .admit_stream(&mut second, "alice".into(), 2)
+            .is_ok());
+        assert!(registry
+            .admit_stream(&mut third, "alice".into(), 2)
+            .is_err());
+        // the limit is per identity
+        assert!(registry.admit_stream(&mut third, "bob".into(), 2).is_ok());
+        assert_eq!(Some(2), identity_streams("alice"));
+        assert_eq!(2, registry.list()[0].active_streams);
+
+        drop(second);
+        assert_eq!(Some(1), identity_streams("alice"));
+        let mut fourth = h3.stream_guard();
+        assert!(registry
+            .admit_stream(&mut fourth, "alice".into(), 2)
+            .is_ok());
+
+        drop((first, third, fourth));
+        assert!(registry.identity_streams.lock().unwrap().is_empty());
+        let mut fifth = h2.stream_guard();
+        assert!(registry
+            .admit_stream(&mut fifth, "carol".into(), 0)
+            .is_err());
+        assert_eq!(None, identity_streams("carol"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn revoked_sessions_are_closed_after_grace_period() {
+        let registry = SessionRegistry::default();
+        let alice = registry.register(Protocol::Http2);
+        let token = registry.register(Protocol::Http3);
+        let bob = registry.register(Protocol::Http2);
+        registry.identify(alice.id(), "alice", None);
+        registry.identify(token.id(), "carol", Some("token-1".to_string()));
+        registry.identify(bob.id(), "bob", None);
+
+        let identities = BTreeSet::from(["alice".to_string()]);
+        let token_ids = BTreeSet::from(["token-1".to_string()]);
+        let grace_period = Duration::from_secs(30);
+        assert_eq!(2, registry.revoke(&identities, &token_ids, grace_period));
+        // the sessions to be closed already are not counted again
+        assert_eq!(0, registry.revoke(&identities, &token_ids, grace_period));
+
+        let signal = alice.revoke_signal();
+        assert!(tokio::time::timeout(Duration::from_secs(29), signal.wait())
+            .await
+            .is_err());
+        tokio::time::timeout(Duration::from_secs(2), signal.wait())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), token.revoke_signal().wait())
+            .await
+            .unwrap();
+        assert!(!bob.state.revoked.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limited_session_expires() {
+        let registry = SessionRegistry::default();
+        let session = registry.register(Protocol::Http3);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        registry.limit_lifetime(session.id(), Duration::from_secs(60));
+        // the first limit stays in effect
+        registry.limit_lifetime(session.id(), Duration::from_secs(600));
+
+        // the lifetime is counted since the session has started
+        let signal = session.expire_signal();
+        assert!(tokio::time::timeout(Duration::from_secs(49), signal.wait())
+            .await
+            .is_err());
+        tokio::time::timeout(Duration::from_secs(2), signal.wait())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn dropped_session_is_unregistered() {
+        let registry = SessionRegistry::default();
+        drop(registry.register(Protocol::Http2));
+        assert!(registry.sessions.lock().unwrap().is_empty());
+    }
+}
@@ -1,13 +1,15 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::io;
 use std::io::ErrorKind;
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::time::Duration;
 
-use crate::{authentication, rules, utils};
+use crate::tls_demultiplexer::Protocol;
+use crate::{authentication, host_patterns, interception, rules, schedule, self_signed, utils};
 use authentication::registry_based::Client;
+use base64::Engine;
 #[cfg(feature = "rt_doc")]
 use macros::{Getter, RuntimeDoc};
 use serde::{Deserialize, Serialize};
@@ -15,15 +17,37 @@ use toml_edit::{Document, Item};
 
 pub type Socks5BuilderResult<T> = Result<T, Socks5Error>;
 
+const MIN_QUIC_UDP_PAYLOAD_SIZE: usize = 1200;
+/// The minimum MSS every IPv4 host must accept
+const MIN_TCP_MAX_SEGMENT_SIZE: u16 = 536;
+/// The IPv4 packet size limit less the headers
+const MAX_TCP_MAX_SEGMENT_SIZE: u16 = 65495;
+
 pub enum ValidationError {
     /// [`Settings.listen_address`] is not set
     ListenAddressNotSet,
+    /// Invalid [`Settings.tcp_max_segment_size`]
+    TcpMaxSegmentSize(u16),
+    /// Invalid [`Settings.egress_addresses`]
+    EgressAddresses(String),
+    /// Invalid [`Settings.egress_port_blocks`]
+    EgressPortBlocks(String),
+    /// Invalid [`Settings.egress_connect_rate`]
+    EgressConnectRate(String),
+    /// Invalid [`Settings.egress_uplinks`]
+    EgressUplinks(String),
+    /// Invalid [`DirectForwarderSettings.upstream_socks5`]
+    UpstreamSocks5(String),
+    /// Invalid [`DirectForwarderSettings.upstream_http`]
+    UpstreamHttpProxy(String),
     /// Invalid [`TlsHostsSettings.main_hosts`]
     MainTlsHostInfo(String),
     /// Invalid [`TlsHostsSettings.ping_hosts`]
     PingTlsHostInfo(String),
     /// Invalid [`TlsHostsSettings.speedtest_hosts`]
     SpeedTlsHostInfo(String),
+    /// Invalid [`TlsHostsSettings.self_signed`]
+    SelfSigned(String),
     /// Invalid [`Settings.reverse_proxy`]
     ReverseProxy(String),
     /// Invalid [`Settings.listen_protocols`]
@@ -32,6 +56,86 @@ pub enum ValidationError {
     RulesFile(String),
     /// No credentials configured while listening on a public address
     NoCredentialsOnPublicAddress,
+    /// More than one of [`Settings.ldap`], [`Settings.database`], [`Settings.redis`],
+    /// [`Settings.jwt`], [`Settings.pam`] and [`Settings.introspection`] is set
+    /// without [`Settings.auth_chain`]
+    ConflictingAuthenticators,
+    /// Invalid [`Settings.tiers`]
+    Tiers(String),
+    /// Invalid [`Settings.profiles`]
+    Profiles(String),
+    /// Invalid [`Settings.state_store`]
+    StateStore(String),
+    /// Invalid [`Settings.certificate_expiry`]
+    CertificateExpiry(String),
+    /// Invalid [`Settings.http_redirect`]
+    HttpRedirect(String),
+    /// Invalid [`Settings.triage`]
+    Triage(String),
+    /// Invalid [`Settings.accept_rate`]
+    AcceptRate(String),
+    /// Invalid [`Settings.proxy_protocol`]
+    ProxyProtocol(String),
+    /// Invalid [`Settings.grpc_admin`]
+    GrpcAdmin(String),
+    /// Invalid [`Settings.metrics`]
+    Metrics(String),
+    /// Invalid [`Settings.statsd`]
+    Statsd(String),
+    /// Invalid [`Settings.exit_policy`]
+    ExitPolicy(String),
+    /// Invalid TLS settings of an upstream hop
+    UpstreamTls(String),
+    /// Invalid upstream hop set, e.g., [`Socks5ForwarderSettings.fallback_addresses`]
+    UpstreamHops(String),
+    /// Invalid [`Settings.impairments`]
+    Impairments(String),
+    /// Invalid [`Settings.ldap`]
+    Ldap(String),
+    /// Invalid [`Settings.database`]
+    Database(String),
+    /// Invalid [`Settings.redis`]
+    Redis(String),
+    /// Invalid [`Settings.jwt`]
+    Jwt(String),
+    /// Invalid [`Settings.pam`]
+    Pam(String),
+    /// Invalid [`Settings.introspection`]
+    Introspection(String),
+    /// Invalid [`Settings.auth_cache`]
+    AuthCache(String),
+    /// Invalid [`Settings.auth_chain`]
+    AuthChain(String),
+    /// Invalid [`Settings.auth_lockout`]
+    AuthLockout(String),
+    /// Invalid [`Settings.audit_log`]
+    AuditLog(String),
+    /// Invalid rotation settings of a log file
+    LogRotation(String),
+    /// Invalid [`Settings.revocation`]
+    Revocation(String),
+    /// Invalid [`Settings.reconnect_tokens`]
+    ReconnectTokens(String),
+    /// Invalid [`Settings.guest`]
+    Guest(String),
+    /// Invalid [`Settings.digest_auth`]
+    DigestAuth(String),
+    /// Invalid [`Settings.duplicate_sessions`]
+    DuplicateSessions(String),
+    /// Invalid [`Settings.bandwidth_estimation`]
+    BandwidthEstimation(String),
+    /// Invalid [`Settings.client_auth`]
+    ClientAuth(String),
+    /// Invalid [`Settings.affinity`]
+    Affinity(String),
+    /// Invalid [`Settings.policy`]
+    Policy(String),
+    /// Invalid [`Settings.schedule`]
+    Schedule(String),
+    /// Invalid [`Settings.timeouts`]
+    Timeouts(String),
+    /// Invalid [`Settings.interception`]
+    Interception(String),
 }
 
 impl Settings {
@@ -46,6 +150,70 @@ impl Settings {
             Some(&self.clients.path)
         }
     }
+
+    pub fn ldap(&self) -> Option<&LdapSettings> {
+        self.ldap.as_ref()
+    }
+
+    pub fn database(&self) -> Option<&DatabaseSettings> {
+        self.database.as_ref()
+    }
+
+    pub fn redis(&self) -> Option<&RedisSettings> {
+        self.redis.as_ref()
+    }
+
+    pub fn jwt(&self) -> Option<&JwtSettings> {
+        self.jwt.as_ref()
+    }
+
+    pub fn pam(&self) -> Option<&PamSettings> {
+        self.pam.as_ref()
+    }
+
+    pub fn introspection(&self) -> Option<&IntrospectionSettings> {
+        self.introspection.as_ref()
+    }
+
+    pub fn auth_cache(&self) -> Option<&AuthCacheSettings> {
+        self.auth_cache.as_ref()
+    }
+
+    pub fn auth_chain(&self) -> Option<&AuthChainSettings> {
+        self.auth_chain.as_ref()
+    }
+
+    pub fn auth_lockout(&self) -> Option<&AuthLockoutSettings> {
+        self.auth_lockout.as_ref()
+    }
+
+    pub fn audit_log(&self) -> Option<&AuditLogSettings> {
+        self.audit_log.as_ref()
+    }
+
+    pub fn revocation(&self) -> Option<&RevocationSettings> {
+        self.revocation.as_ref()
+    }
+
+    pub fn reconnect_tokens(&self) -> Option<&ReconnectTokenSettings> {
+        self.reconnect_tokens.as_ref()
+    }
+
+    pub fn guest(&self) -> Option<&GuestSettings> {
+        self.guest.as_ref()
+    }
+
+    pub fn digest_auth(&self) -> Option<&DigestAuthSettings> {
+        self.digest_auth.as_ref()
+    }
+
+    pub fn duplicate_sessions(&self) -> Option<&DuplicateSessionSettings> {
+        self.duplicate_sessions.as_ref()
+    }
+
+    pub fn bandwidth_estimation(&self) -> Option<&BandwidthEstimationSettings> {
+        self.bandwidth_estimation.as_ref()
+    }
 }
 
 impl Debug for ValidationError {
@@ -57,12 +225,72 @@ impl Debug for ValidationError {
             Self::SpeedTlsHostInfo(x) => write!(f, "Invalid speedtest TLS hosts: {}", x),
             Self::ReverseProxy(x) => write!(f, "Invalid reverse proxy settings: {}", x),
             Self::ListenProtocols(x) => write!(f, "Invalid listen protocols settings: {}", x),
+            Self::TcpMaxSegmentSize(x) => write!(f, "Invalid TCP maximum segment size: {}", x),
+            Self::EgressAddresses(x) => write!(f, "Invalid egress addresses: {}", x),
+            Self::EgressPortBlocks(x) => write!(f, "Invalid egress port blocks settings: {}", x),
+            Self::EgressConnectRate(x) => {
+                write!(f, "Invalid egress connect rate settings: {}", x)
+            }
+            Self::EgressUplinks(x) => write!(f, "Invalid egress uplinks settings: {}", x),
+            Self::UpstreamSocks5(x) => write!(f, "Invalid upstream SOCKS5 proxy: {}", x),
+            Self::UpstreamHttpProxy(x) => write!(f, "Invalid upstream HTTP proxy: {}", x),
             Self::RulesFile(x) => write!(f, "Invalid rules file: {}", x),
             Self::NoCredentialsOnPublicAddress => write!(
                 f,
-                "No credentials configured (credentials_file is missing) while listening on a public address. \
+                "No credentials configured (none of credentials_file, ldap, database, redis, jwt, pam and introspection is set) while listening on a public address. \
                 This is a security risk. Either configure credentials or use a loopback address (127.0.0.1 or ::1)"
             ),
+            Self::ConflictingAuthenticators => {
+                write!(
+                    f,
+                    "Only one of ldap, database, redis, jwt, pam and introspection may be set unless auth_chain is set"
+                )
+            }
+            Self::Tiers(x) => write!(f, "Invalid tiers settings: {}", x),
+            Self::Profiles(x) => write!(f, "Invalid profiles settings: {}", x),
+            Self::StateStore(x) => write!(f, "Invalid state store settings: {}", x),
+            Self::CertificateExpiry(x) => {
+                write!(f, "Invalid certificate expiry settings: {}", x)
+            }
+            Self::HttpRedirect(x) => write!(f, "Invalid HTTP redirect settings: {}", x),
+            Self::Triage(x) => write!(f, "Invalid triage settings: {}", x),
+            Self::AcceptRate(x) => write!(f, "Invalid accept rate settings: {}", x),
+            Self::ProxyProtocol(x) => write!(f, "Invalid PROXY protocol settings: {}", x),
+            Self::GrpcAdmin(x) => write!(f, "Invalid gRPC admin settings: {}", x),
+            Self::Metrics(x) => write!(f, "Invalid metrics settings: {}", x),
+            Self::Statsd(x) => write!(f, "Invalid statsd settings: {}", x),
+            Self::ExitPolicy(x) => write!(f, "Invalid exit policy settings: {}", x),
+            Self::UpstreamTls(x) => write!(f, "Invalid upstream TLS settings: {}", x),
+            Self::UpstreamHops(x) => write!(f, "Invalid upstream hops settings: {}", x),
+            Self::Impairments(x) => write!(f, "Invalid impairments settings: {}", x),
+            Self::Ldap(x) => write!(f, "Invalid LDAP settings: {}", x),
+            Self::Database(x) => write!(f, "Invalid database settings: {}", x),
+            Self::Redis(x) => write!(f, "Invalid Redis settings: {}", x),
+            Self::Jwt(x) => write!(f, "Invalid JWT settings: {}", x),
+            Self::Pam(x) => write!(f, "Invalid PAM settings: {}", x),
+            Self::Introspection(x) => write!(f, "Invalid token introspection settings: {}", x),
+            Self::AuthCache(x) => write!(f, "Invalid authentication cache settings: {}", x),
+            Self::AuthChain(x) => write!(f, "Invalid authentication chain settings: {}", x),
+            Self::AuthLockout(x) => write!(f, "Invalid authentication lockout settings: {}", x),
+            Self::AuditLog(x) => write!(f, "Invalid audit log settings: {}", x),
+            Self::LogRotation(x) => write!(f, "Invalid log rotation settings: {}", x),
+            Self::Revocation(x) => write!(f, "Invalid revocation settings: {}", x),
+            Self::ReconnectTokens(x) => write!(f, "Invalid reconnect token settings: {}", x),
+            Self::Guest(x) => write!(f, "Invalid guest settings: {}", x),
+            Self::DigestAuth(x) => write!(f, "Invalid digest authentication settings: {}", x),
+            Self::DuplicateSessions(x) => {
+                write!(f, "Invalid duplicate sessions settings: {}", x)
+            }
+            Self::BandwidthEstimation(x) => {
+                write!(f, "Invalid bandwidth estimation settings: {}", x)
+            }
+            Self::ClientAuth(x) => write!(f, "Invalid client authentication settings: {}", x),
+            Self::SelfSigned(x) => write!(f, "Invalid self-signed certificate settings: {}", x),
+            Self::Affinity(x) => write!(f, "Invalid affinity settings: {}", x),
+            Self::Policy(x) => write!(f, "Invalid policy settings: {}", x),
+            Self::Schedule(x) => write!(f, "Invalid schedule settings: {}", x),
+            Self::Timeouts(x) => write!(f, "Invalid timeouts settings: {}", x),
+            Self::Interception(x) => write!(f, "Invalid interception settings: {}", x),
         }
     }
 }
@@ -92,6 +320,11 @@ pub struct Settings {
     /// Whether connections to private network of the endpoint are allowed
     #[serde(default = "Settings::default_allow_private_network_connections")]
     pub(crate) allow_private_network_connections: bool,
+    /// Whether connections to the cloud instance metadata services are allowed.
+    /// The services hand out the credentials of the host, so the connections to them are
+    /// refused by default, even if the private network connections are allowed.
+    #[serde(default)]
+    pub(crate) allow_metadata_endpoint_connections: bool,
     /// Timeout of an incoming TLS handshake
     #[serde(default = "Settings::default_tls_handshake_timeout")]
     #[serde(rename = "tls_handshake_timeout_secs")]
@@ -133,6 +366,38 @@ pub struct Settings {
         serialize_with = "serialize_duration_secs"
     )]
     pub(crate) udp_connections_timeout: Duration,
+    /// The timeouts of the client connection stages, overriding the ones above
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutSettings,
+    /// Whether the tunnel establishment and the ping responses carry the `Server-Timing`
+    /// header with the time the endpoint has spent on the request
+    #[serde(default)]
+    pub(crate) server_timing: bool,
+    /// The maximum segment size of the outgoing TCP connections.
+    /// Clamping it below the path MTU prevents the stalls of the tunneled connections
+    /// on the paths where the ICMP "fragmentation needed" messages are dropped.
+    /// If not set, the system default is used.
+    #[serde(default)]
+    pub(crate) tcp_max_segment_size: Option<u16>,
+    /// The pool of the source addresses of the outgoing connections.
+    /// Each client is assigned one of the addresses of the destination family by the hash
+    /// of its username, so that it keeps exiting from the same address.
+    /// A client with an explicitly assigned address in the credentials file uses that one.
+    /// If empty, the system chooses the source addresses.
+    #[serde(default)]
+    pub(crate) egress_addresses: Vec<IpAddr>,
+    /// The partitioning of the source ports of the outgoing connections between the clients.
+    /// If not set, the system chooses the source ports.
+    #[serde(default)]
+    pub(crate) egress_port_blocks: Option<PortBlockSettings>,
+    /// The pacing of the outgoing TCP connections to the same destination address.
+    /// If not set, the connections are made right away.
+    #[serde(default)]
+    pub(crate) egress_connect_rate: Option<ConnectRateSettings>,
+    /// The egress uplinks of a multi-uplink host the routing rules may race
+    /// the tunneled TCP connections across. Not set by default.
+    #[serde(default)]
+    pub(crate) egress_uplinks: Option<EgressUplinksSettings>,
     /// The set of connection forwarder settings
     #[serde(default)]
     pub(crate) forward_protocol: ForwardProtocolSettings,
@@ -156,6 +421,20 @@ pub struct Settings {
     /// [[client]]
     /// username = "a"
     /// password = "b"
+    /// # optional
+    /// tier = "paid"
+    /// # optional
+    /// egress_address = "203.0.113.7"
+    /// # optional
+    /// max_connections = 16
+    /// # optional
+    /// max_streams = 64
+    /// # optional
+    /// data_quota_bytes = 107374182400
+    /// # optional, the calendar month if not set
+    /// data_quota_period_days = 30
+    /// # optional
+    /// profile = "contractors"
     ///
     /// [[client]]
     /// ...
@@ -165,6 +444,76 @@ pub struct Settings {
     #[serde(rename(deserialize = "credentials_file"))]
     #[serde(deserialize_with = "deserialize_clients")]
     pub(crate) clients: Credentials,
+    /// The LDAP or Active Directory server the clients are authenticated against
+    /// instead of the credentials file
+    #[serde(default)]
+    pub(crate) ldap: Option<LdapSettings>,
+    /// The PostgreSQL database the client credentials are looked up in
+    /// instead of the credentials file
+    #[serde(default)]
+    pub(crate) database: Option<DatabaseSettings>,
+    /// The Redis server the client credentials are looked up in
+    /// instead of the credentials file
+    #[serde(default)]
+    pub(crate) redis: Option<RedisSettings>,
+    /// The keys the JSON Web Tokens presented by the clients are verified with
+    /// instead of the credentials file
+    #[serde(default)]
+    pub(crate) jwt: Option<JwtSettings>,
+    /// The PAM stack of the host the clients are authenticated with
+    /// instead of the credentials file
+    #[serde(default)]
+    pub(crate) pam: Option<PamSettings>,
+    /// The introspection endpoint of the OAuth 2.0 authorization server the access tokens
+    /// presented by the clients are checked against instead of the credentials file
+    #[serde(default)]
+    pub(crate) introspection: Option<IntrospectionSettings>,
+    /// The cache of the authentication results in front of the authenticator.
+    /// If not set, each tunnel request is authenticated through the authenticator.
+    #[serde(default)]
+    pub(crate) auth_cache: Option<AuthCacheSettings>,
+    /// The order the configured authenticators are asked in.
+    /// If not set, only one of [`Settings::ldap`], [`Settings::database`], [`Settings::redis`],
+    /// [`Settings::jwt`], [`Settings::pam`] and [`Settings::introspection`] may be configured,
+    /// and it takes precedence over the credentials file.
+    #[serde(default)]
+    pub(crate) auth_chain: Option<AuthChainSettings>,
+    /// The protection of the authenticator against the password guessing.
+    /// If set, the client addresses and the usernames failing to authenticate too many times
+    /// in a row are locked out for a while.
+    #[serde(default)]
+    pub(crate) auth_lockout: Option<AuthLockoutSettings>,
+    /// The audit trail of the authentication attempts.
+    /// If set, a record of each attempt is written to the configured sink, regardless of
+    /// the logging level.
+    #[serde(default)]
+    pub(crate) audit_log: Option<AuditLogSettings>,
+    /// The revocation of the client credentials.
+    /// If set, the revoked usernames and token IDs are refused on authentication, and
+    /// the established sessions authenticated with them are closed.
+    #[serde(default)]
+    pub(crate) revocation: Option<RevocationSettings>,
+    /// The tokens letting a client reconnect without presenting its credentials again.
+    /// If set, the successful tunnel responses to the requests authenticated with
+    /// the proxy authorization carry a token, which stands for the credentials in
+    /// the requests of a following session, e.g., in the QUIC 0-RTT data.
+    #[serde(default)]
+    pub(crate) reconnect_tokens: Option<ReconnectTokenSettings>,
+    /// The guest access.
+    /// If set, the tunnel requests carrying no credentials are let through with
+    /// the restricted settings instead of being refused.
+    #[serde(default)]
+    pub(crate) guest: Option<GuestSettings>,
+    /// The digest access authentication of the tunnel requests.
+    /// If set, the 407 responses challenge the clients to the digest scheme
+    /// along with the basic one.
+    #[serde(default)]
+    pub(crate) digest_auth: Option<DigestAuthSettings>,
+    /// The TLS client certificate authentication settings.
+    /// If set, the tunnel connections over HTTP/1.1 and HTTP/2 are asked for a client
+    /// certificate, and the connections presenting one are authenticated by it.
+    #[serde(default)]
+    pub(crate) client_auth: Option<ClientAuthSettings>,
     /// The reverse proxy settings.
     /// With this one set up the endpoint does TLS termination on such connections and
     /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
@@ -173,7 +522,7 @@ pub struct Settings {
     /// ```(client) TLS(HTTP/x) <--(endpoint)--> (server) HTTP/1.1```
     ///
     /// The translated HTTP/1.1 requests have the custom header `X-Original-Protocol`
-    /// appended. For now, its value can be either `HTTP1`, or `HTTP3`.
+    /// appended. Its value is either `HTTP1`, `HTTP2`, or `HTTP3`.
     /// TLS hosts for the reverse proxy channel are configured through [`TlsHostsSettings`].
     pub(crate) reverse_proxy: Option<ReverseProxySettings>,
     /// The ICMP forwarding settings.
@@ -181,6 +530,10 @@ pub struct Settings {
     pub(crate) icmp: Option<IcmpSettings>,
     /// The metrics gathering request handler settings
     pub(crate) metrics: Option<MetricsSettings>,
+    /// The statsd exporter settings.
+    /// If set, the metrics are pushed to a statsd server in addition to being
+    /// served by the metrics listener.
+    pub(crate) statsd: Option<StatsdSettings>,
     /// Path to the rules file for connection filtering.
     /// If not specified or file doesn't exist, all connections are allowed by default.
     #[serde(default)]
@@ -193,6 +546,119 @@ pub struct Settings {
     #[serde(default = "Settings::default_speedtest_enable")]
     pub(crate) speedtest_enable: bool,
 
+    /// The quality of service tiers keyed by name.
+    /// A client is assigned to a tier through the `tier` attribute of its entry in the
+    /// credentials file. Clients without the attribute fall into the tier named `default`
+    /// in case it is configured, otherwise they are not restricted. The entries of the
+    /// credentials file assigned to an unknown tier are refused, while the unknown tiers
+    /// of the other authenticators are treated like the absent attribute.
+    #[serde(default)]
+    pub(crate) tiers: HashMap<String, TierSettings>,
+
+    /// The destination profiles keyed by name.
+    /// A client is assigned to a profile through the `profile` attribute of its entry in the
+    /// credentials file. The tunnel requests of a client assigned to an unknown profile
+    /// are rejected, and the clients without the attribute are not restricted.
+    #[serde(default)]
+    pub(crate) profiles: HashMap<String, ProfileSettings>,
+
+    /// The maximum number of the concurrent tunneled connections of an authenticated client.
+    /// The tunnel requests of a client above the limit are rejected. The `max_connections`
+    /// attribute of a client entry in the credentials file overrides it.
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub(crate) max_connections_per_user: Option<usize>,
+
+    /// The maximum number of the concurrent tunnel streams of an authenticated client summed
+    /// across all its sessions, counted by the session registry. The tunnel requests of
+    /// a client above the limit are rejected. The `max_streams` attribute of a client entry
+    /// in the credentials file overrides it.
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub(crate) max_streams_per_user: Option<usize>,
+
+    /// What happens to a tunnel session authenticating with the identity which already has
+    /// active sessions, e.g., to enforce the single-seat licenses.
+    /// An identity may have any number of the sessions if not set.
+    #[serde(default)]
+    pub(crate) duplicate_sessions: Option<DuplicateSessionSettings>,
+
+    /// The estimation of the bandwidth available to each client session.
+    /// If set, the estimates are listed along with the sessions, and may be hinted to
+    /// the clients as the pacing rate.
+    #[serde(default)]
+    pub(crate) bandwidth_estimation: Option<BandwidthEstimationSettings>,
+
+    /// The persistent state store settings.
+    /// If set, the state like quota counters, dynamic bans, leases and resumption tokens
+    /// survives the endpoint restarts.
+    pub(crate) state_store: Option<StateStoreSettings>,
+
+    /// The path of the status file in JSON format.
+    /// If set, the endpoint keeps the file listing its bound listeners, the loaded certificates,
+    /// the enabled subsystems and the compiled in features, so the orchestration is able to
+    /// verify the instance came up as intended.
+    #[serde(default)]
+    pub(crate) status_file: Option<String>,
+
+    /// The monitoring of the expiration of the loaded certificates.
+    /// If set, the endpoint warns of the certificates approaching their expiration.
+    #[serde(default)]
+    pub(crate) certificate_expiry: Option<CertificateExpirySettings>,
+
+    /// The session affinity settings of an endpoint instance behind a load balancer.
+    /// If set, the successful tunnel responses carry a signed token naming the instance,
+    /// which the balancer routes the following requests of the client by.
+    pub(crate) affinity: Option<AffinitySettings>,
+
+    /// The message of the day and the terms of use the clients have to acknowledge
+    pub(crate) policy: Option<PolicySettings>,
+
+    /// The scheduled maintenance windows.
+    /// If set, the endpoint drains during each window and accepts the tunnels again
+    /// once it is over.
+    pub(crate) schedule: Option<ScheduleSettings>,
+
+    /// The plain HTTP listener settings.
+    /// If set, the endpoint redirects the plain HTTP requests to HTTPS.
+    pub(crate) http_redirect: Option<HttpRedirectSettings>,
+
+    /// The triage of the accepted TCP connections before the TLS handshake.
+    /// If set, the connections which do not start with a TLS record are classified
+    /// and handled according to the settings instead of failing the handshake.
+    pub(crate) triage: Option<TriageSettings>,
+
+    /// The limiting of the rate of the new client connections per address and per subnet.
+    /// If set, the connections over the limit are dropped before the TLS handshake.
+    pub(crate) accept_rate: Option<AcceptRateSettings>,
+
+    /// The acceptance of the PROXY protocol header on the TCP listener.
+    /// If set, the connections from the trusted load balancers are expected to start
+    /// with the header, and the client address it conveys is used instead of the balancer's.
+    pub(crate) proxy_protocol: Option<ProxyProtocolSettings>,
+
+    /// The gRPC administration service settings.
+    /// The service is available only if the endpoint is built with the `grpc` feature.
+    pub(crate) grpc_admin: Option<GrpcAdminSettings>,
+
+    /// The exit policy settings.
+    /// If set, the ping hosts publish a machine-readable document describing
+    /// what the endpoint lets the clients connect to.
+    pub(crate) exit_policy: Option<ExitPolicySettings>,
+
+    /// The TLS interception settings.
+    /// If set, the TLS connections to the configured destinations are terminated
+    /// by the endpoint, which MUST be explicitly sanctioned by the clients,
+    /// as they have to trust the interception CA.
+    pub(crate) interception: Option<InterceptionSettings>,
+
+    /// The synthetic network impairments of the tunneled traffic.
+    /// A tunnel is impaired according to the first entry matching it.
+    /// Intended for reproducing the client network conditions in testing environments,
+    /// the impairments MUST NOT be configured in production.
+    #[serde(default)]
+    pub(crate) impairments: Vec<ImpairmentSettings>,
+
     /// Whether an instance was built through a [`SettingsBuilder`].
     /// This flag is a workaround for absence of the ability to validate
     /// the deserialized structure.
@@ -217,14 +683,15 @@ pub struct TlsHostInfo {
     /// MUST remain valid until [`crate::core::Core::listen()`] or
     /// [`crate::core::Core::listen_async()`] is running, or
     /// until the next [`crate::core::Core::reload_tls_hosts_settings()`] call.
-    #[serde(deserialize_with = "deserialize_file_path")]
+    /// May be omitted along with `private_key_path` if [`TlsHostsSettings::self_signed`] is set.
+    #[serde(default, deserialize_with = "deserialize_file_path")]
     pub cert_chain_path: String,
     /// Path to a file containing the private key.
     /// May be equal to `cert_chain_path` if it contains both of them.
     /// MUST remain valid until [`crate::core::Core::listen()`] or
     /// [`crate::core::Core::listen_async()`] is running, or
     /// until the next [`crate::core::Core::reload_tls_hosts_settings()`] call.
-    #[serde(deserialize_with = "deserialize_file_path")]
+    #[serde(default, deserialize_with = "deserialize_file_path")]
     pub private_key_path: String,
     /// List of alternative SNIs that should be accepted for this host.
     /// When a client sends one of these SNIs, the connection will use this host's certificate.
@@ -233,7 +700,7 @@ pub struct TlsHostInfo {
 }
 
 #[derive(Serialize, Deserialize)]
-#[cfg_attr(test, derive(Default))]
+#[cfg_attr(any(test, fuzzing), derive(Default))]
 #[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
 pub struct TlsHostsSettings {
     /// Еhe main TLS hosts.
@@ -259,6 +726,12 @@ pub struct TlsHostsSettings {
     /// Only makes sense if the reverse proxy is set up, otherwise it is ignored.
     #[serde(default)]
     pub(crate) reverse_proxy_hosts: Vec<TlsHostInfo>,
+    /// The development mode certificate.
+    /// If set, a self-signed certificate is generated on start for the hosts configured
+    /// without the certificate and key paths. MUST NOT be used in production, as
+    /// the clients are able to connect only skipping the certificate verification.
+    #[serde(default)]
+    pub(crate) self_signed: Option<SelfSignedSettings>,
 
     /// Whether an instance was built through a [`TlsSettingsBuilder`].
     /// This flag is a workaround for absence of the ability to validate
@@ -268,10 +741,22 @@ pub struct TlsHostsSettings {
     built: bool,
 }
 
+/// The self-signed certificate generated for the TLS hosts lacking the configured one
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct SelfSignedSettings {
+    /// The DNS names and IP addresses the certificate is issued for in addition to
+    /// the hostnames and the allowed SNIs of the hosts
+    #[serde(default)]
+    pub(crate) subject_alt_names: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
 pub struct ReverseProxySettings {
-    /// The origin server address
+    /// The origin server address.
+    /// May be omitted if [`ReverseProxySettings.static_files`] is set.
+    #[serde(default = "ReverseProxySettings::default_server_address")]
     pub(crate) server_address: SocketAddr,
     /// Connections to [the main hosts](TlsHostsSettings.main_hosts) with
     /// paths starting with this mask are routed to the reverse proxy server.
@@ -282,6 +767,214 @@ pub struct ReverseProxySettings {
     /// and its path is `/` or matches [`ReverseProxySettings.path_mask`]
     #[serde(default)]
     pub(crate) h3_backward_compatibility: bool,
+    /// With this one set to `true`, the requests to [the main hosts](TlsHostsSettings.main_hosts)
+    /// which are not tunnel requests (e.g., a plain `GET` of a page) are routed to the reverse
+    /// proxy instead of being rejected. The endpoint then looks like a regular website while
+    /// still serving the tunnels.
+    #[serde(default)]
+    pub(crate) serve_non_tunnel_requests: bool,
+    /// The path prefixes of the requests [`ReverseProxySettings.serve_non_tunnel_requests`]
+    /// applies to, matched by whole segments, e.g., `/blog` matches `/blog` and `/blog/a`,
+    /// but not `/blogs`. The requests with the other paths are treated as tunnel requests.
+    /// Applies to all the paths if empty.
+    #[serde(default)]
+    pub(crate) non_tunnel_path_prefixes: Vec<String>,
+    /// The headers set on the responses, keyed by the TLS host name.
+    /// The headers of the `*` entry are set for all the hosts. The host specific entries
+    /// override them. The headers replace the ones received from the origin server.
+    #[serde(default)]
+    pub(crate) response_headers: HashMap<String, HashMap<String, String>>,
+    /// The cache of the origin server responses.
+    /// If not set, every request is forwarded to the origin server.
+    #[serde(default)]
+    pub(crate) cache: Option<ResponseCacheSettings>,
+    /// Serve the requests with the files from a local directory instead of
+    /// forwarding them to the origin server
+    #[serde(default)]
+    pub(crate) static_files: Option<StaticFilesSettings>,
+    /// Copy a share of the requests forwarded to the origin server to a secondary server.
+    /// The responses of the secondary server are discarded.
+    #[serde(default)]
+    pub(crate) mirror: Option<RequestMirrorSettings>,
+    /// Respond to the requests with `503 Service Unavailable` instead of forwarding them
+    /// to the origin server. Can be toggled at runtime through the administration interfaces.
+    #[serde(default)]
+    pub(crate) maintenance: bool,
+    /// The pages sent to the clients instead of the bare error responses
+    #[serde(default)]
+    pub(crate) error_pages: Option<ErrorPagesSettings>,
+    /// Connect to the origin server over TLS.
+    /// If not set, the requests are forwarded in plain HTTP/1.1.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// Prepend the PROXY protocol version 2 header with the client address
+    /// to the origin server connections. The origin server must expect it.
+    #[serde(default)]
+    pub(crate) proxy_protocol: bool,
+}
+
+/// The reverse proxy error pages settings.
+/// The values are paths to HTML files.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ErrorPagesSettings {
+    /// Sent with `502 Bad Gateway` if the origin server cannot be reached
+    #[serde(default)]
+    pub(crate) bad_gateway: Option<String>,
+    /// Sent with `503 Service Unavailable` in the maintenance mode
+    #[serde(default)]
+    pub(crate) service_unavailable: Option<String>,
+    /// Sent with `504 Gateway Timeout` if the origin server does not respond in time
+    #[serde(default)]
+    pub(crate) gateway_timeout: Option<String>,
+}
+
+/// The reverse proxy request mirroring settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct RequestMirrorSettings {
+    /// The secondary server address
+    pub(crate) server_address: SocketAddr,
+    /// The share of the mirrored requests, in percent
+    #[serde(default = "RequestMirrorSettings::default_percentage")]
+    pub(crate) percentage: u8,
+    /// The requests larger than this, including the head, are not mirrored (bytes)
+    #[serde(default = "RequestMirrorSettings::default_max_request_size")]
+    pub(crate) max_request_size: usize,
+}
+
+/// The settings of the TLS connections to an upstream hop, e.g., a parent proxy
+/// or an origin server
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct UpstreamTlsSettings {
+    /// The name sent in the SNI extension and checked against the hop certificate.
+    /// If not set, the certificate is checked against the IP address of the hop.
+    #[serde(default)]
+    pub(crate) server_name: Option<String>,
+    /// The path to a PEM file with the trusted CA certificates.
+    /// If not set, the system trust store is used.
+    #[serde(default)]
+    pub(crate) ca_bundle_path: Option<String>,
+    /// The base64 encoded SHA-256 digests of the trusted public keys (SubjectPublicKeyInfo).
+    /// If set, a certificate of the hop chain must have one of the keys,
+    /// in addition to the regular verification.
+    #[serde(default)]
+    pub(crate) spki_pins: Vec<String>,
+    /// Accept any certificate of the hop, except for a mismatching pin.
+    /// Meant for lab setups only, the connections are open to interception.
+    #[serde(default)]
+    pub(crate) insecure_skip_verify: bool,
+}
+
+/// The settings of the TLS interception of the connections to the configured destinations.
+/// The endpoint completes the TLS handshake with a client using a certificate issued
+/// on the fly by the interception CA, and opens its own TLS connection to the destination,
+/// so that the plaintext goes through the endpoint.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct InterceptionSettings {
+    /// Path to the PEM file with the CA certificate the intercepted connections
+    /// are presented certificates issued by
+    pub(crate) ca_cert_path: String,
+    /// Path to the PEM file with the PKCS #8 private key of the CA
+    pub(crate) ca_key_path: String,
+    /// The intercepted destination host names or IP addresses.
+    /// A pattern starting with `*.` matches the subdomains of the following domain.
+    pub(crate) destinations: Vec<String>,
+    /// The intercepted destination ports
+    #[serde(default = "InterceptionSettings::default_ports")]
+    pub(crate) ports: Vec<u16>,
+    /// The settings of the TLS connections to the destinations.
+    /// The destination certificate is checked against the name requested by the client,
+    /// so the server name may not be set.
+    #[serde(default)]
+    pub(crate) upstream_tls: UpstreamTlsSettings,
+}
+
+/// The static file server settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct StaticFilesSettings {
+    /// The directory the files are served from
+    pub(crate) root: String,
+    /// The files looked up in order when a directory is requested
+    #[serde(default = "StaticFilesSettings::default_index_files")]
+    pub(crate) index_files: Vec<String>,
+}
+
+/// The source port partitioning settings.
+/// Each authenticated client gets a block of the ports on each source address it connects
+/// from, and the assignments are logged, so that a source address and port seen by
+/// a destination can be attributed to the client.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct PortBlockSettings {
+    /// The first port of the partitioned range
+    #[serde(default = "PortBlockSettings::default_first_port")]
+    pub(crate) first_port: u16,
+    /// The last port of the partitioned range
+    #[serde(default = "PortBlockSettings::default_last_port")]
+    pub(crate) last_port: u16,
+    /// The number of the ports in a block
+    #[serde(default = "PortBlockSettings::default_block_size")]
+    pub(crate) block_size: u16,
+}
+
+/// The settings of the pacing of the outgoing TCP connections per destination address.
+/// Each destination has a bucket of [`ConnectRateSettings::burst`] connections refilled
+/// at [`ConnectRateSettings::connects_per_sec`], and a connection finding it empty waits
+/// for its turn.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ConnectRateSettings {
+    /// The sustained rate of the connections to a destination address
+    #[serde(default = "ConnectRateSettings::default_connects_per_sec")]
+    pub(crate) connects_per_sec: u32,
+    /// The number of the connections to a destination address made at once
+    /// before the rate applies
+    #[serde(default = "ConnectRateSettings::default_burst")]
+    pub(crate) burst: u32,
+    /// The longest a connection waits for its turn. The connections which would wait
+    /// longer are refused.
+    #[serde(default = "ConnectRateSettings::default_max_wait")]
+    #[serde(rename = "max_wait_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) max_wait: Duration,
+    /// The maximum random delay added to each connection, so that the connections
+    /// released at once do not reach the destination at once
+    #[serde(default)]
+    #[serde(rename = "jitter_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) jitter: Duration,
+    /// The maximum number of the tracked destination addresses
+    #[serde(default = "ConnectRateSettings::default_max_destinations")]
+    pub(crate) max_destinations: usize,
+}
+
+/// The reverse proxy response cache settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ResponseCacheSettings {
+    /// The maximum total size of the responses kept in memory (bytes)
+    #[serde(default = "ResponseCacheSettings::default_max_memory_size")]
+    pub(crate) max_memory_size: usize,
+    /// Responses larger than this are not cached (bytes)
+    #[serde(default = "ResponseCacheSettings::default_max_entry_size")]
+    pub(crate) max_entry_size: usize,
+    /// The directory to keep the cached responses in across restarts.
+    /// If not set, the responses are kept in memory only.
+    #[serde(default)]
+    pub(crate) disk_path: Option<String>,
+    /// The maximum total size of the responses kept on the disk (bytes)
+    #[serde(default = "ResponseCacheSettings::default_max_disk_size")]
+    pub(crate) max_disk_size: usize,
 }
 
 /// The set of connection forwarder settings
@@ -295,8 +988,86 @@ pub enum ForwardProtocolSettings {
     Socks5(Socks5ForwarderSettings),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct DirectForwarderSettings {}
+#[derive(Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DirectForwarderSettings {
+    /// The SOCKS5 proxy the outgoing TCP connections are made through, e.g., an egress proxy
+    /// of the network or a Tor client.
+    /// If not set, the connections are made to the destinations directly.
+    #[serde(default)]
+    pub(crate) upstream_socks5: Option<UpstreamSocks5Settings>,
+    /// The HTTP proxy the outgoing TCP connections are tunneled through with
+    /// the `CONNECT` requests, e.g., where the direct egress is firewalled.
+    /// If not set, the connections are made to the destinations directly.
+    #[serde(default)]
+    pub(crate) upstream_http: Option<UpstreamHttpProxySettings>,
+}
+
+/// The settings of the egress uplinks of a multi-uplink host, e.g., the links of two ISPs.
+/// A tunneled TCP connection matching a routing rule with
+/// [`rules::RouteRule::race_uplinks`] is attempted over the uplinks of the rule, the healthiest
+/// first and each next one [`EgressUplinksSettings::race_delay`] later, and the first
+/// established connection is kept.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct EgressUplinksSettings {
+    /// The uplinks
+    #[serde(default)]
+    pub(crate) uplink: Vec<UplinkSettings>,
+    /// How long an attempt is given before the attempt over the next uplink is started
+    /// in parallel
+    #[serde(default = "EgressUplinksSettings::default_race_delay")]
+    #[serde(rename = "race_delay_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) race_delay: Duration,
+}
+
+/// An egress uplink
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct UplinkSettings {
+    /// The name the routing rules refer to the uplink by
+    pub(crate) name: String,
+    /// The network interface the connections over the uplink are bound to
+    pub(crate) interface: String,
+}
+
+/// The upstream HTTP proxy settings of the direct forwarder
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct UpstreamHttpProxySettings {
+    /// The address of the proxy
+    pub(crate) address: SocketAddr,
+    /// Connect to the proxy over TLS.
+    /// If not set, the connections to the proxy are not encrypted.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The username to authenticate to the proxy with the basic scheme.
+    /// If not set, the requests carry no `Proxy-Authorization` header.
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    /// The password to authenticate to the proxy with
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+}
+
+/// The upstream SOCKS5 proxy settings of the direct forwarder
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct UpstreamSocks5Settings {
+    /// The address of the proxy
+    pub(crate) address: SocketAddr,
+    /// The username to authenticate to the proxy with.
+    /// If not set, the proxy is connected without authentication.
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    /// The password to authenticate to the proxy with
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+}
 
 #[derive(Serialize, Deserialize)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
@@ -306,6 +1077,40 @@ pub struct Socks5ForwarderSettings {
     /// Whether the extended authentication is enabled
     #[serde(default)]
     pub(crate) extended_auth: bool,
+    /// Connect to the proxy over TLS.
+    /// If not set, the connections to the proxy are not encrypted.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The proxies used while the main one is down, in the order of preference
+    #[serde(default)]
+    pub(crate) fallback_addresses: Vec<SocketAddr>,
+    /// The periodic probing of the proxies.
+    /// If not set, a proxy is considered down after a failed connection attempt,
+    /// and is tried again after [`HopHealthCheckSettings::DOWN_RETRY_INTERVAL`].
+    #[serde(default)]
+    pub(crate) health_check: Option<HopHealthCheckSettings>,
+}
+
+/// The upstream hop probing settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct HopHealthCheckSettings {
+    /// The period of probing every hop
+    #[serde(default = "HopHealthCheckSettings::default_interval")]
+    #[serde(rename = "interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) interval: Duration,
+    /// A hop is considered down if it does not accept a connection in this time
+    #[serde(default = "HopHealthCheckSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
 }
 
 pub struct Socks5ForwarderSettingsBuilder {
@@ -351,6 +1156,148 @@ pub struct IcmpSettings {
     pub(crate) recv_message_queue_capacity: usize,
 }
 
+/// The plain HTTP to HTTPS redirecting listener settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct HttpRedirectSettings {
+    /// The address to listen on for plain HTTP requests
+    #[serde(default = "HttpRedirectSettings::default_listen_address")]
+    pub(crate) listen_address: SocketAddr,
+    /// The port the requests are redirected to.
+    /// If not set, the port of [`Settings.listen_address`] is used.
+    #[serde(default)]
+    pub(crate) https_port: Option<u16>,
+    /// The directory with the ACME HTTP-01 challenge responses.
+    /// If set, the requests to `/.well-known/acme-challenge/<token>` are served
+    /// with the contents of the `<token>` file instead of being redirected.
+    #[serde(default)]
+    pub(crate) acme_challenge_dir: Option<String>,
+    /// Timeout of a plain HTTP request
+    #[serde(default = "HttpRedirectSettings::default_request_timeout")]
+    #[serde(rename = "request_timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) request_timeout: Duration,
+}
+
+/// The handling of the accepted TCP connections which do not start with a TLS record
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct TriageSettings {
+    /// What to do with a connection starting with a plain HTTP request
+    #[serde(default)]
+    pub(crate) plain_http: TriageAction,
+    /// What to do with a connection starting with neither a TLS record nor an HTTP request
+    #[serde(default)]
+    pub(crate) garbage: TriageAction,
+    /// How long a connection is held in the tarpit
+    #[serde(default = "TriageSettings::default_tarpit_duration")]
+    #[serde(rename = "tarpit_duration_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) tarpit_duration: Duration,
+    /// The maximum number of the connections held in the tarpit at once.
+    /// The connections over the limit are reset.
+    #[serde(default = "TriageSettings::default_max_tarpit_connections")]
+    pub(crate) max_tarpit_connections: usize,
+}
+
+/// The handling of a triaged connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum TriageAction {
+    /// Close the connection
+    #[default]
+    Close,
+    /// Close the connection with a TCP reset
+    Reset,
+    /// Redirect the request to HTTPS, for the plain HTTP connections only
+    Redirect,
+    /// Respond with `400 Bad Request` like a regular web server would
+    Decoy,
+    /// Hold the connection open without responding
+    Tarpit,
+}
+
+/// The settings of the limiting of the rate of the new client connections.
+/// Each client address has a bucket of [`AcceptRateSettings::burst`] connections refilled
+/// at [`AcceptRateSettings::connects_per_sec`], and each subnet has a bucket of
+/// [`AcceptRateSettings::subnet_burst`] connections refilled at
+/// [`AcceptRateSettings::subnet_connects_per_sec`]. A connection finding either bucket
+/// empty is dropped.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AcceptRateSettings {
+    /// The sustained rate of the connections from a client address
+    #[serde(default = "AcceptRateSettings::default_connects_per_sec")]
+    pub(crate) connects_per_sec: u32,
+    /// The number of the connections from a client address accepted at once
+    /// before the rate applies
+    #[serde(default = "AcceptRateSettings::default_burst")]
+    pub(crate) burst: u32,
+    /// The sustained rate of the connections from all the addresses of a subnet
+    #[serde(default = "AcceptRateSettings::default_subnet_connects_per_sec")]
+    pub(crate) subnet_connects_per_sec: u32,
+    /// The number of the connections from all the addresses of a subnet accepted at once
+    /// before the rate applies
+    #[serde(default = "AcceptRateSettings::default_subnet_burst")]
+    pub(crate) subnet_burst: u32,
+    /// The prefix length of the IPv4 subnets
+    #[serde(default = "AcceptRateSettings::default_ipv4_prefix_len")]
+    pub(crate) ipv4_prefix_len: u8,
+    /// The prefix length of the IPv6 subnets
+    #[serde(default = "AcceptRateSettings::default_ipv6_prefix_len")]
+    pub(crate) ipv6_prefix_len: u8,
+    /// The number of the connections of a client address refused in a row
+    /// after which the address is banned. If not set, the addresses are not banned.
+    #[serde(default)]
+    pub(crate) ban_after: Option<u32>,
+    /// How long a client address is banned for
+    #[serde(default = "AcceptRateSettings::default_ban_duration")]
+    #[serde(rename = "ban_duration_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) ban_duration: Duration,
+    /// The maximum number of the tracked client addresses and subnets
+    #[serde(default = "AcceptRateSettings::default_max_entries")]
+    pub(crate) max_entries: usize,
+}
+
+/// The settings of the acceptance of the PROXY protocol header
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ProxyProtocolSettings {
+    /// The networks of the load balancers, in the CIDR notation, whose connections start
+    /// with the header. The connections from the other addresses are served as is.
+    pub(crate) trusted_sources: Vec<String>,
+    /// How long to wait for the header before closing the connection
+    #[serde(default = "ProxyProtocolSettings::default_header_timeout")]
+    #[serde(rename = "header_timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) header_timeout: Duration,
+}
+
+/// The gRPC administration service settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct GrpcAdminSettings {
+    /// The address to listen on for gRPC requests.
+    /// The requests must carry [`MetricsSettings.admin_token`] in the `authorization`
+    /// metadata as `Bearer <token>`, and the service is refused without the token.
+    #[serde(default = "GrpcAdminSettings::default_listen_address")]
+    pub(crate) listen_address: SocketAddr,
+}
+
 /// The metrics gathering request handler settings
 #[derive(Serialize, Deserialize)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
@@ -366,969 +1313,7134 @@ pub struct MetricsSettings {
         serialize_with = "serialize_duration_secs"
     )]
     pub(crate) request_timeout: Duration,
+    /// The period the per-second stats history is kept for.
+    /// The history is served via the `/stats` path. Zero disables it.
+    #[serde(default = "MetricsSettings::default_stats_history")]
+    #[serde(rename = "stats_history_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) stats_history: Duration,
+    /// Whether the CPU time and the buffer memory are attributed to the subsystems
+    /// (TLS, QUIC, pipe, codec) and exported for the capacity planning.
+    /// Costs a system call per poll of the measured tasks.
+    #[serde(default)]
+    pub(crate) capacity_stats: bool,
+    /// The token the administration requests must carry in the `Authorization: Bearer`
+    /// header. These are the requests changing the endpoint state, the `/credentials` ones
+    /// and the `/events` stream. If not set, they are refused.
+    /// The gRPC administration service requires it for all its calls.
+    #[serde(default)]
+    pub(crate) admin_token: Option<String>,
 }
 
-/// The set of HTTP/1.1 listener codec settings
+/// The settings of the client authentication against an LDAP server.
+/// The client either binds with the DN made of its username, or the DN is looked up
+/// by the username first.
 #[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
-pub struct Http1Settings {
-    /// Buffer size for outgoing traffic
-    #[serde(default = "Http1Settings::default_upload_buffer_size")]
-    pub(crate) upload_buffer_size: usize,
+pub struct LdapSettings {
+    /// The address of the server in the `host:port` form
+    pub(crate) address: String,
+    /// The TLS settings of the server connections (LDAPS).
+    /// If not set, the passwords are sent in plain text.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The DN a client binds with, `{username}` is replaced with the client username,
+    /// e.g., `uid={username},ou=people,dc=example,dc=org`, or `{username}@corp.example.org`
+    /// for Active Directory.
+    /// Mutually exclusive with [`LdapSettings.search_base_dn`].
+    #[serde(default)]
+    pub(crate) bind_dn: Option<String>,
+    /// The base DN of the subtree searched for the client entry, which the client then
+    /// binds with. Mutually exclusive with [`LdapSettings.bind_dn`].
+    #[serde(default)]
+    pub(crate) search_base_dn: Option<String>,
+    /// The attribute holding the username, e.g., `uid` or `sAMAccountName`
+    #[serde(default = "LdapSettings::default_search_attribute")]
+    pub(crate) search_attribute: String,
+    /// The DN of the service account doing the search.
+    /// If not set, the search is done anonymously.
+    #[serde(default)]
+    pub(crate) search_bind_dn: Option<String>,
+    /// The password of the service account
+    #[serde(default)]
+    pub(crate) search_bind_password: Option<String>,
+    /// Timeout of a server connection and of each request
+    #[serde(default = "LdapSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
 }
 
-/// The set of HTTP/2 listener codec settings
+/// The settings of the client authentication against the credentials stored in a database
 #[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
-pub struct Http2Settings {
-    /// The initial window size (in octets) for connection-level flow control for received data
-    #[serde(default = "Http2Settings::default_initial_connection_window_size")]
-    pub(crate) initial_connection_window_size: u32,
-    /// The initial window size (in octets) for stream-level flow control for received data
-    #[serde(default = "Http2Settings::default_initial_stream_window_size")]
-    pub(crate) initial_stream_window_size: u32,
-    /// The number of streams that the sender permits the receiver to create
-    #[serde(default = "Http2Settings::default_max_concurrent_streams")]
-    pub(crate) max_concurrent_streams: u32,
-    /// The size (in octets) of the largest HTTP/2 frame payload that we are able to accept
-    #[serde(default = "Http2Settings::default_max_frame_size")]
-    pub(crate) max_frame_size: u32,
-    /// The max size of received header frames
-    #[serde(default = "Http2Settings::default_header_table_size")]
-    pub(crate) header_table_size: u32,
+pub struct DatabaseSettings {
+    /// The database server
+    #[serde(default)]
+    pub(crate) driver: DatabaseDriver,
+    /// The address of the server in the `host:port` form. Not used by SQLite.
+    #[serde(default)]
+    pub(crate) address: String,
+    /// The TLS settings of the server connections.
+    /// If not set, the connections are not encrypted. Not used by SQLite.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The database name, or the path to the database file for SQLite
+    pub(crate) database: String,
+    /// The database user. Not used by SQLite.
+    #[serde(default)]
+    pub(crate) user: String,
+    /// The password of the database user. Not used by SQLite.
+    #[serde(default)]
+    pub(crate) password: String,
+    /// The query looking up a client, the client username is bound to its parameter,
+    /// `$1` for PostgreSQL and SQLite or `?` for MySQL.
+    /// The first column of a result row is the password, and the optional second one is
+    /// the UNIX timestamp the client is valid till.
+    /// If not set, [`DatabaseSettings::default_query`] of the driver is used.
+    #[serde(default)]
+    pub(crate) query: Option<String>,
+    /// The maximum number of the server connections, i.e., of the concurrent lookups
+    #[serde(default = "DatabaseSettings::default_pool_size")]
+    pub(crate) pool_size: usize,
+    /// Timeout of a server connection and of each query
+    #[serde(default = "DatabaseSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
 }
 
-/// The set of QUIC listener codec settings
+/// The database servers [`DatabaseSettings`] may look the clients up in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum DatabaseDriver {
+    /// PostgreSQL
+    #[default]
+    Postgres,
+    /// MySQL or MariaDB
+    Mysql,
+    /// SQLite
+    Sqlite,
+}
+
+/// The settings of the client authentication against the credentials stored in Redis.
+/// A client password is the value of the key made of its username, so a client expires
+/// along with the key.
 #[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
-pub struct QuicSettings {
-    /// The size of UDP payloads that the endpoint is willing to receive. UDP datagrams with
-    /// payloads larger than this limit are not likely to be processed.
-    #[serde(default = "QuicSettings::default_recv_udp_payload_size")]
-    pub(crate) recv_udp_payload_size: usize,
-    /// The size of UDP payloads that the endpoint is willing to send
-    #[serde(default = "QuicSettings::default_send_udp_payload_size")]
-    pub(crate) send_udp_payload_size: usize,
-    /// The initial value for the maximum amount of data that can be sent on the connection
-    #[serde(default = "QuicSettings::default_initial_max_data")]
-    pub(crate) initial_max_data: u64,
-    /// The initial flow control limit for locally initiated bidirectional streams
-    #[serde(default = "QuicSettings::default_initial_max_stream_data_bidi_local")]
-    #[serde(alias = "max_stream_data_bidi_local")]
-    pub(crate) initial_max_stream_data_bidi_local: u64,
-    /// The initial flow control limit for peer-initiated bidirectional streams
-    #[serde(default = "QuicSettings::default_initial_max_stream_data_bidi_remote")]
-    #[serde(alias = "max_stream_data_bidi_remote")]
-    pub(crate) initial_max_stream_data_bidi_remote: u64,
-    /// The initial flow control limit for unidirectional streams
-    #[serde(default = "QuicSettings::default_initial_max_stream_data_uni")]
-    #[serde(alias = "max_stream_data_uni")]
-    pub(crate) initial_max_stream_data_uni: u64,
-    /// The initial maximum number of bidirectional streams the endpoint that receives this
-    /// transport parameter is permitted to initiate
-    #[serde(default = "QuicSettings::default_initial_max_streams_bidi")]
-    #[serde(alias = "max_streams_bidi")]
-    pub(crate) initial_max_streams_bidi: u64,
-    /// The initial maximum number of unidirectional streams the endpoint that receives this
-    /// transport parameter is permitted to initiate
-    #[serde(default = "QuicSettings::default_initial_max_streams_uni")]
-    #[serde(alias = "max_streams_uni")]
-    pub(crate) initial_max_streams_uni: u64,
-    /// The maximum size of the connection window
-    #[serde(default = "QuicSettings::default_max_connection_window")]
-    pub(crate) max_connection_window: u64,
-    /// The maximum size of the stream window
-    #[serde(default = "QuicSettings::default_max_stream_window")]
-    pub(crate) max_stream_window: u64,
-    /// Disable active connection migration on the address being used during the handshake
-    #[serde(default = "QuicSettings::default_disable_active_migration")]
-    pub(crate) disable_active_migration: bool,
-    /// Enable sending or receiving early data
-    #[serde(default = "QuicSettings::default_enable_early_data")]
-    pub(crate) enable_early_data: bool,
-    /// The capacity of the QUIC multiplexer message queue.
-    /// Decreasing it may cause packet dropping in case the multiplexer cannot keep up the pace.
-    /// Increasing it may lead to high memory consumption.
-    // @todo: separate values for incoming and outgoing?
-    #[serde(default = "QuicSettings::default_message_queue_capacity")]
-    pub(crate) message_queue_capacity: usize,
+pub struct RedisSettings {
+    /// The address of the server in the `host:port` form
+    pub(crate) address: String,
+    /// The TLS settings of the server connections.
+    /// If not set, the connections are not encrypted.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The ACL user of the server connections. If not set, the `default` user is assumed.
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    /// The password of the server connections.
+    /// If not set, the connections are not authenticated.
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+    /// The logical database index
+    #[serde(default)]
+    pub(crate) database: u32,
+    /// The prefix of the keys, the key of a client is the prefix followed by its username
+    #[serde(default = "RedisSettings::default_key_prefix")]
+    pub(crate) key_prefix: String,
+    /// The maximum number of the idle server connections kept for the next lookups
+    #[serde(default = "RedisSettings::default_pool_size")]
+    pub(crate) pool_size: usize,
+    /// Timeout of a server connection and of each command
+    #[serde(default = "RedisSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
 }
 
-pub struct SettingsBuilder {
-    settings: Settings,
+/// The settings of the client authentication with the JSON Web Tokens.
+/// A client presents a token in the Proxy bearer authorization or in place of the SNI
+/// credentials, so its credentials are rotated by issuing it a new token.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct JwtSettings {
+    /// The secret of the tokens signed with HMAC (`HS256`, `HS384` and `HS512`)
+    #[serde(default)]
+    pub(crate) secret: Option<String>,
+    /// Path to the JSON Web Key Set with the public keys of the tokens signed
+    /// with RSA (`RS*` and `PS*`), ECDSA (`ES256` and `ES384`) or Ed25519 (`EdDSA`)
+    #[serde(default)]
+    pub(crate) jwks_file: Option<String>,
+    /// The audience a token must be issued for.
+    /// If not set, the `aud` claim is not checked.
+    #[serde(default)]
+    pub(crate) audience: Option<String>,
+    /// The issuer a token must be issued by.
+    /// If not set, the `iss` claim is not checked.
+    #[serde(default)]
+    pub(crate) issuer: Option<String>,
+    /// The tolerated difference between the clocks of the endpoint and the issuer
+    #[serde(default = "JwtSettings::default_leeway")]
+    #[serde(rename = "leeway_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) leeway: Duration,
 }
 
-pub struct TlsSettingsBuilder {
-    settings: TlsHostsSettings,
+/// The settings of the client authentication against the PAM stack of the host,
+/// so that the system accounts are able to connect.
+/// Is only available if the endpoint is built with the `pam` feature on Unix.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct PamSettings {
+    /// The PAM service the clients are authenticated as, i.e., the name of the file
+    /// in `/etc/pam.d` configuring the modules
+    #[serde(default = "PamSettings::default_service")]
+    pub(crate) service: String,
+    /// Whether an authenticated account is also checked to be usable, e.g., not expired
+    #[serde(default = "PamSettings::default_check_account")]
+    pub(crate) check_account: bool,
 }
 
-pub struct Http1SettingsBuilder {
-    settings: Http1Settings,
+/// The settings of the client authentication with the OAuth 2.0 access tokens checked
+/// against the introspection endpoint of the authorization server
+/// ([RFC 7662](https://www.rfc-editor.org/rfc/rfc7662)), e.g., Keycloak.
+/// A client presents a token in the Proxy bearer authorization. An active token is cached
+/// until it expires, so the server is asked once per token.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct IntrospectionSettings {
+    /// The URL of the introspection endpoint, either `http` or `https`
+    pub(crate) url: String,
+    /// The ID of the client the endpoint is registered as at the authorization server
+    pub(crate) client_id: String,
+    /// The secret of the client the endpoint is registered as at the authorization server
+    pub(crate) client_secret: String,
+    /// The TLS settings of the server connections of an `https` URL.
+    /// If not set, the server certificate is checked against the host of the URL
+    /// with the system trust store.
+    #[serde(default)]
+    pub(crate) tls: Option<UpstreamTlsSettings>,
+    /// The audience a token must be issued for.
+    /// If not set, the `aud` member is not checked.
+    #[serde(default)]
+    pub(crate) audience: Option<String>,
+    /// Timeout of a server connection and of each request
+    #[serde(default = "IntrospectionSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
+    /// The maximum number of the cached active tokens, the tokens beyond are checked
+    /// at each tunnel request. Zero disables the cache.
+    #[serde(default = "IntrospectionSettings::default_cache_size")]
+    pub(crate) cache_size: usize,
+    /// The longest time an active token is cached for, i.e., the delay of a revocation
+    /// taking effect. A token expiring earlier is cached until it expires.
+    #[serde(default = "IntrospectionSettings::default_max_cache_ttl")]
+    #[serde(rename = "max_cache_ttl_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) max_cache_ttl: Duration,
 }
 
-pub struct Http2SettingsBuilder {
-    settings: Http2Settings,
+/// The settings of the authentication results cache.
+/// The cache saves the exchanges with the authentication servers on the repeated requests
+/// of the same client, at the cost of the credential changes taking effect with a delay
+/// up to the TTL, unless the entries are dropped through the metrics endpoint.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AuthCacheSettings {
+    /// The period a successful authentication result is kept for
+    #[serde(default = "AuthCacheSettings::default_ttl")]
+    #[serde(rename = "ttl_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) ttl: Duration,
+    /// The period a rejection is kept for.
+    /// Zero means the rejected credentials are checked by the authenticator each time.
+    #[serde(default = "AuthCacheSettings::default_negative_ttl")]
+    #[serde(rename = "negative_ttl_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) negative_ttl: Duration,
+    /// The maximum number of the kept results
+    #[serde(default = "AuthCacheSettings::default_max_entries")]
+    pub(crate) max_entries: usize,
 }
 
-pub struct QuicSettingsBuilder {
-    settings: QuicSettings,
+/// The settings of the lockout of the clients failing to authenticate.
+/// The failures are counted per client address, grouping the IPv6 ones by the /64 prefix,
+/// and per username. The tunnel requests of a locked out client are rejected without asking
+/// the authenticator.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AuthLockoutSettings {
+    /// The number of the failed authentications in a row a client is locked out after
+    #[serde(default = "AuthLockoutSettings::default_max_failures")]
+    pub(crate) max_failures: u32,
+    /// The period of the first lockout. Each following one is twice as long.
+    #[serde(default = "AuthLockoutSettings::default_lockout")]
+    #[serde(rename = "lockout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) lockout: Duration,
+    /// The longest period of a lockout
+    #[serde(default = "AuthLockoutSettings::default_max_lockout")]
+    #[serde(rename = "max_lockout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) max_lockout: Duration,
+    /// The period without failures the failures and the lockouts of a client
+    /// are forgotten after
+    #[serde(default = "AuthLockoutSettings::default_reset_after")]
+    #[serde(rename = "reset_after_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) reset_after: Duration,
+    /// Whether the usernames are locked out besides the client addresses.
+    /// Note that anyone knowing a username is able to lock it out.
+    #[serde(default = "AuthLockoutSettings::default_lock_usernames")]
+    pub(crate) lock_usernames: bool,
+    /// The maximum number of the tracked client addresses and usernames
+    #[serde(default = "AuthLockoutSettings::default_max_entries")]
+    pub(crate) max_entries: usize,
 }
 
-pub struct ReverseProxySettingsBuilder {
-    settings: ReverseProxySettings,
+/// The settings of the authentication audit log. Each record is a JSON object on its own
+/// line, or in its own datagram.
+/// Exactly one of [`AuditLogSettings::path`] and [`AuditLogSettings::address`] must be set.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AuditLogSettings {
+    /// The file the records are appended to
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// The address of the UDP server the records are sent to, e.g., a log collector
+    #[serde(default)]
+    pub(crate) address: Option<SocketAddr>,
+    /// The rotation of the file at [`AuditLogSettings::path`].
+    /// If not set, the file grows until it is rotated outside the endpoint.
+    #[serde(default)]
+    pub(crate) rotation: Option<LogRotationSettings>,
 }
 
-pub struct IcmpSettingsBuilder {
-    settings: IcmpSettings,
+/// The settings of the rotation of a log file. On rotation, the file is renamed after
+/// the time of the rotation, e.g., `audit.log.20240315T000000.000Z`, optionally compressed,
+/// and a new file is started. At least one of [`LogRotationSettings::period`] and
+/// [`LogRotationSettings::max_size_bytes`] must be set.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct LogRotationSettings {
+    /// Rotate the file at the start of each period in UTC, unless it is empty
+    #[serde(default)]
+    pub(crate) period: Option<RotationPeriod>,
+    /// Rotate the file as soon as it has grown to the size
+    #[serde(default)]
+    pub(crate) max_size_bytes: Option<u64>,
+    /// The compression of the rotated files
+    #[serde(default)]
+    pub(crate) compression: LogCompression,
+    /// The number of the rotated files kept at most, the older ones are removed
+    #[serde(default = "LogRotationSettings::default_max_files")]
+    pub(crate) max_files: usize,
+    /// The program run on each rotation with the path of the rotated file as its argument,
+    /// e.g., to ship the file elsewhere. The endpoint does not wait for it to complete.
+    #[serde(default)]
+    pub(crate) on_rotate: Option<String>,
 }
 
-pub struct MetricsSettingsBuilder {
-    settings: MetricsSettings,
+/// The periods of the scheduled rotation of a log file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum RotationPeriod {
+    /// At the start of each hour
+    Hourly,
+    /// At midnight
+    Daily,
 }
 
-impl Settings {
-    pub fn builder() -> SettingsBuilder {
-        SettingsBuilder::new()
-    }
+/// The compression of the rotated log files
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum LogCompression {
+    /// The files are kept as is
+    #[default]
+    None,
+    /// The files are compressed with gzip and get the `.gz` extension
+    Gzip,
+}
 
-    pub(crate) fn is_built(&self) -> bool {
-        self.built
-    }
+/// The settings of the revocation list of the client credentials. A revoked username, or
+/// the ID of a revoked token (the `jti` claim of a JWT), is refused on authentication
+/// whatever the authenticator says, and the sessions authenticated with it are closed
+/// once [`RevocationSettings::grace_period`] is over.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct RevocationSettings {
+    /// The file the list is kept in, with the `usernames` and the `token_ids` arrays.
+    /// The file is checked for changes periodically, and the changes made through
+    /// the admin listener are written to it. If not set, the list is kept in memory.
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// The period the sessions authenticated with the revoked credentials are let to live
+    #[serde(default)]
+    #[serde(rename = "grace_period_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) grace_period: Duration,
+    /// The interval of checking the file for changes
+    #[serde(default = "RevocationSettings::default_reload_interval")]
+    #[serde(rename = "reload_interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) reload_interval: Duration,
+}
 
-    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
-        if self.listen_address.ip().is_unspecified() && self.listen_address.port() == 0 {
-            return Err(ValidationError::ListenAddressNotSet);
-        }
+/// The settings of the reconnect tokens. A token is the credentials of a client sealed with
+/// the key of the endpoint, so the endpoint needs no state to redeem it, and it is redeemed
+/// without asking the authenticator. A token is bound to the session it is first redeemed in:
+/// presented in another one, e.g., within replayed 0-RTT data, it is refused.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ReconnectTokenSettings {
+    /// How long an issued token stays valid. A token never outlives the credentials
+    /// it is issued for.
+    #[serde(default = "ReconnectTokenSettings::default_ttl")]
+    #[serde(rename = "ttl_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) ttl: Duration,
+    /// The secret the sealing key is derived from, at least 16 bytes long.
+    /// If not set, a random key is generated on start, so the tokens do not survive
+    /// a restart.
+    #[serde(default)]
+    pub(crate) secret: Option<String>,
+    /// The maximum number of the tracked redeemed tokens. A token is refused while
+    /// there is no room to track it.
+    #[serde(default = "ReconnectTokenSettings::default_max_redeemed")]
+    pub(crate) max_redeemed: usize,
+}
 
-        self.reverse_proxy
-            .as_ref()
-            .map(ReverseProxySettings::validate)
-            .transpose()?;
+/// The settings of the guest access. The clients presenting no credentials get through,
+/// but with no identity of their own, so they are restricted by the guest settings alone
+/// instead of the ones of a client.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct GuestSettings {
+    /// The tier the guest sessions are assigned to, e.g., one with a low rate limit and
+    /// a few sessions. The `default` tier, if any, is applied if not set.
+    #[serde(default)]
+    pub(crate) tier: Option<String>,
+    /// The destination profile the guests are restricted to.
+    /// The guests may reach any destination if not set.
+    #[serde(default)]
+    pub(crate) profile: Option<String>,
+    /// The maximum lifetime of a guest session. The session is closed once it is over,
+    /// so a guest has to reconnect to go on.
+    #[serde(default = "GuestSettings::default_max_session_duration")]
+    #[serde(rename = "max_session_duration_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) max_session_duration: Duration,
+}
+
+/// The settings of the digest access authentication
+/// ([RFC 7616](https://datatracker.ietf.org/doc/html/rfc7616)). A digest is computed over
+/// the password, so only the authenticators knowing the passwords verify it, like
+/// the credentials file one for the clients with the plain text passwords.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DigestAuthSettings {
+    /// The realm of the challenges, which the digests are computed over
+    #[serde(default = "DigestAuthSettings::default_realm")]
+    pub(crate) realm: String,
+    /// How long a nonce of a challenge is accepted since issued.
+    /// A client presenting an older one is challenged again.
+    #[serde(default = "DigestAuthSettings::default_nonce_lifetime")]
+    #[serde(rename = "nonce_lifetime_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) nonce_lifetime: Duration,
+}
+
+/// The settings of the concurrent tunnel sessions of an authenticated identity.
+/// A session is a client connection to the endpoint, and it belongs to the identities
+/// its tunnel requests are authenticated with.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DuplicateSessionSettings {
+    /// The number of the active sessions an identity is let to have
+    #[serde(default = "DuplicateSessionSettings::default_max_sessions")]
+    pub(crate) max_sessions: usize,
+    /// What happens to a session of an identity which has the maximum number of them
+    #[serde(default)]
+    pub(crate) action: DuplicateSessionAction,
+}
+
+/// The ways a session exceeding the limit of its identity is handled
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum DuplicateSessionAction {
+    /// The tunnel requests of the new session are rejected
+    #[default]
+    RejectNew,
+    /// The oldest session of the identity is closed to make room for the new one
+    ReplaceOldest,
+}
+
+/// The settings of the session bandwidth estimation.
+/// The traffic of each session is sampled periodically, and the estimate is the maximum
+/// delivery rate of the samples within the window.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct BandwidthEstimationSettings {
+    /// The period of sampling the traffic of the sessions
+    #[serde(default = "BandwidthEstimationSettings::default_sample_interval")]
+    #[serde(rename = "sample_interval_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) sample_interval: Duration,
+    /// The period the samples are taken into account for
+    #[serde(default = "BandwidthEstimationSettings::default_window")]
+    #[serde(rename = "window_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) window: Duration,
+    /// Whether the responses to the tunnel requests hint the clients the pacing rate
+    #[serde(default)]
+    pub(crate) hints: bool,
+    /// The share of the estimated bandwidth hinted as the pacing rate, in (0, 1].
+    /// Pacing below the estimate keeps the queues inside the tunnel short.
+    #[serde(default = "BandwidthEstimationSettings::default_pacing_gain")]
+    pub(crate) pacing_gain: f64,
+}
+
+/// The settings of the chain of the authenticators a client is checked with
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AuthChainSettings {
+    /// The authenticators in the order they are asked in.
+    /// Each of them must be configured.
+    pub(crate) backends: Vec<AuthBackend>,
+    /// How the results of the authenticators are combined
+    #[serde(default)]
+    pub(crate) mode: AuthChainMode,
+}
+
+/// The authenticators which may be chained
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum AuthBackend {
+    /// [`Settings::clients`]
+    CredentialsFile,
+    /// [`Settings::ldap`]
+    Ldap,
+    /// [`Settings::database`]
+    Database,
+    /// [`Settings::redis`]
+    Redis,
+    /// [`Settings::jwt`]
+    Jwt,
+    /// [`Settings::pam`]
+    Pam,
+    /// [`Settings::introspection`]
+    Introspection,
+}
+
+/// The ways the results of the chained authenticators are combined
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum AuthChainMode {
+    /// The first authenticator which passes a client wins,
+    /// and a rejection falls through to the next one
+    #[default]
+    FirstPass,
+    /// A client has to be passed by each authenticator,
+    /// and the first rejection is final
+    All,
+}
+
+/// The TLS client certificate authentication settings.
+/// A verified client certificate is passed to the authenticator as
+/// [`authentication::Source::ClientCert`](crate::authentication::Source::ClientCert).
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ClientAuthSettings {
+    /// Path to the PEM file with the certificate authorities the client certificates
+    /// are verified against
+    pub(crate) ca_bundle_path: String,
+    /// Whether the connections without a client certificate are refused during
+    /// the TLS handshake.
+    /// Otherwise, such connections are authenticated with the SNI or the proxy authorization.
+    #[serde(default)]
+    pub(crate) required: bool,
+}
+
+/// The statsd exporter settings
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct StatsdSettings {
+    /// The address of the statsd server
+    pub(crate) address: SocketAddr,
+    /// The prefix prepended to the metric names, separated with a dot
+    #[serde(default = "StatsdSettings::default_prefix")]
+    pub(crate) prefix: String,
+    /// The period of pushing the metrics
+    #[serde(default = "StatsdSettings::default_interval")]
+    #[serde(rename = "interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) interval: Duration,
+}
+
+/// The published exit policy settings
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ExitPolicySettings {
+    /// The contact for the abuse reports, e.g., an email address
+    #[serde(default)]
+    pub(crate) abuse_contact: Option<String>,
+    /// The traffic categories the operator does not permit, e.g., `smtp` or `torrent`.
+    /// They are published as is and are not enforced by the endpoint.
+    #[serde(default)]
+    pub(crate) blocked_categories: Vec<String>,
+}
+
+/// A synthetic network impairment, like the one of `tc netem`
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ImpairmentSettings {
+    /// The listener protocols the impairment applies to: `http1`, `http2`, or `http3`.
+    /// Applies to all of them if empty.
+    #[serde(default)]
+    pub(crate) protocols: Vec<String>,
+    /// The usernames of the clients the impairment applies to.
+    /// Applies to all the clients, including the anonymous ones, if empty.
+    #[serde(default)]
+    pub(crate) usernames: Vec<String>,
+    /// The time each chunk of data or datagram is held for
+    #[serde(default)]
+    #[serde(rename = "delay_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) delay: Duration,
+    /// The maximum random deviation from the [`ImpairmentSettings.delay`]
+    #[serde(default)]
+    #[serde(rename = "jitter_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_millis",
+        serialize_with = "serialize_duration_millis"
+    )]
+    pub(crate) jitter: Duration,
+    /// The probability of losing a chunk of data or a datagram, in percent.
+    /// A lost datagram is dropped. As the tunneled TCP streams cannot lose data,
+    /// a lost chunk of a stream is held for an extra [`ImpairmentSettings::RETRANSMISSION_DELAY`]
+    /// instead, like on a retransmission.
+    #[serde(default)]
+    pub(crate) loss_percent: f64,
+    /// The maximum transfer rate of a tunnel in each direction (bytes per second).
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub(crate) max_bytes_per_sec: Option<u64>,
+}
+
+/// The destination profile settings: which destinations the clients of the profile
+/// are let to, and how their connections are routed
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ProfileSettings {
+    /// The destination rules checked in order, the first matching one decides
+    #[serde(default)]
+    #[serde(rename = "destination")]
+    pub(crate) destinations: Vec<DestinationRule>,
+    /// The action on the destinations no rule matches
+    #[serde(default = "ProfileSettings::default_action")]
+    pub(crate) default_action: rules::RuleAction,
+    /// The routing rules of the tunneled TCP connections,
+    /// checked before the ones of the rules file
+    #[serde(default)]
+    pub(crate) route: Vec<rules::RouteRule>,
+}
+
+/// A destination rule of a profile
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DestinationRule {
+    /// A host name pattern like `*.example.org`, `.example.org` or `~regex`, matching the
+    /// destinations requested by name, or an IP network like `10.0.0.0/8`, matching the ones
+    /// requested by address
+    pub(crate) destination: String,
+    /// The action on the matching destinations
+    pub(crate) action: rules::RuleAction,
+}
+
+/// The quality of service tier settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct TierSettings {
+    /// The maximum transfer rate of the tunneled TCP connections of a client in each direction
+    /// (bytes per second), shared by all the sessions of the client. The sessions of a client
+    /// without identity, like a guest, are limited each on its own. Unlimited if not set.
+    #[serde(default)]
+    pub(crate) max_bytes_per_sec: Option<u64>,
+    /// The maximum number of concurrent sessions of the tier. Unlimited if not set.
+    #[serde(default)]
+    pub(crate) max_sessions: Option<usize>,
+    /// The shedding threshold. New sessions of the tier are rejected while the total number
+    /// of the endpoint sessions is not below this value, so the tiers with lower thresholds
+    /// are shed first. The tier is never shed if not set.
+    #[serde(default)]
+    pub(crate) shed_above_sessions: Option<usize>,
+}
+
+/// The persistent state store settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct StateStoreSettings {
+    /// Path to the SQLite database the state is kept in. The database is created
+    /// if it does not exist.
+    pub(crate) path: String,
+    /// The interval between writing the state changes to the database
+    #[serde(default = "StateStoreSettings::default_checkpoint_interval")]
+    #[serde(rename = "checkpoint_interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) checkpoint_interval: Duration,
+}
+
+/// The certificate expiration monitoring settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct CertificateExpirySettings {
+    /// The period before the expiration of a certificate the warnings start at.
+    /// The warnings escalate to errors in the last quarter of the period.
+    #[serde(default = "CertificateExpirySettings::default_warning_threshold")]
+    #[serde(rename = "warning_threshold_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) warning_threshold: Duration,
+    /// The interval between the checks of the certificates
+    #[serde(default = "CertificateExpirySettings::default_check_interval")]
+    #[serde(rename = "check_interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) check_interval: Duration,
+    /// Whether the endpoint refuses to start with an expired certificate
+    /// instead of failing the TLS handshakes with it
+    #[serde(default)]
+    pub(crate) refuse_expired: bool,
+}
+
+/// The session affinity settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AffinitySettings {
+    /// The identifier of the instance the balancer knows it by, e.g., the backend server name.
+    /// Consists of the ASCII letters, digits, `-` and `_`.
+    pub(crate) instance_id: String,
+    /// The key the tokens are signed with, shared by all the balanced instances.
+    /// At least 16 bytes long.
+    pub(crate) secret: String,
+    /// The name of the header carrying the token in the responses and the requests
+    #[serde(default = "AffinitySettings::default_header_name")]
+    pub(crate) header_name: String,
+    /// How long an issued token stays valid
+    #[serde(default = "AffinitySettings::default_ttl")]
+    #[serde(rename = "ttl_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) ttl: Duration,
+}
+
+/// The message of the day, the terms of use and the credentials expiry warning settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct PolicySettings {
+    /// The message sent to the clients with the successful tunnel responses
+    #[serde(default)]
+    pub(crate) motd: Option<String>,
+    /// The terms of use, or a link to them, an identity has to acknowledge before
+    /// its tunnels are let through. Requires [`Settings.state_store`] to keep
+    /// the acknowledgments.
+    #[serde(default)]
+    pub(crate) terms: Option<String>,
+    /// The version of the terms. Changing it makes every identity acknowledge
+    /// the terms anew.
+    #[serde(default = "PolicySettings::default_terms_version")]
+    pub(crate) terms_version: String,
+    /// The number of days before the credentials of a client expire, starting from which
+    /// its successful tunnel responses carry the expiration time, so that the client
+    /// application is able to prompt the user to renew them. No warning is sent if not set.
+    #[serde(default)]
+    pub(crate) expiry_warning_days: Option<u32>,
+}
+
+/// The timeouts of the client connection stages
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct TimeoutSettings {
+    /// The timeouts applied to the connections of all the listener protocols
+    #[serde(flatten)]
+    pub(crate) stages: StageTimeoutSettings,
+    /// The timeouts of the HTTP/1.1 connections, overriding [`TimeoutSettings.stages`]
+    #[serde(default)]
+    pub(crate) http1: StageTimeoutSettings,
+    /// The timeouts of the HTTP/2 connections, overriding [`TimeoutSettings.stages`]
+    #[serde(default)]
+    pub(crate) http2: StageTimeoutSettings,
+    /// The timeouts of the HTTP/3 connections, overriding [`TimeoutSettings.stages`]
+    #[serde(default)]
+    pub(crate) http3: StageTimeoutSettings,
+}
+
+/// The timeouts of the stages of a client connection. An unset stage falls back to
+/// the less specific setting.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct StageTimeoutSettings {
+    /// Timeout of an incoming TLS handshake.
+    /// Overrides [`Settings.tls_handshake_timeout`].
+    #[serde(default, rename = "tls_handshake_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) tls_handshake: Option<Duration>,
+    /// Timeout of a call to the authenticator. A call taking longer is treated
+    /// as a rejection. Not limited by default.
+    #[serde(default, rename = "auth_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) auth: Option<Duration>,
+    /// Timeout of a connection to a peer or to the reverse proxy origin server.
+    /// Overrides [`Settings.connection_establishment_timeout`].
+    #[serde(default, rename = "upstream_connect_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) upstream_connect: Option<Duration>,
+    /// How long a ping, speedtest, or reverse proxy client may take to send
+    /// the next request header. Defaults to [`Settings.tls_handshake_timeout`] for the ping
+    /// and speedtest requests, and to [`Settings.connection_establishment_timeout`] for
+    /// the reverse proxy ones.
+    #[serde(default, rename = "request_header_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) request_header: Option<Duration>,
+    /// Idle timeout of the tunneled TCP connections and the reverse proxy exchanges.
+    /// Overrides [`Settings.tcp_connections_timeout`].
+    #[serde(default, rename = "idle_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) idle: Option<Duration>,
+    /// The maximum lifetime of a tunneled TCP connection or a reverse proxy exchange.
+    /// Not limited by default.
+    #[serde(default, rename = "total_secs")]
+    #[serde(
+        deserialize_with = "deserialize_optional_duration_secs",
+        serialize_with = "serialize_optional_duration_secs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) total: Option<Duration>,
+}
+
+/// The timeouts in effect for the connections of a listener protocol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Timeouts {
+    pub tls_handshake: Duration,
+    pub auth: Option<Duration>,
+    pub upstream_connect: Duration,
+    /// Not set if the legacy timeout of the request path applies
+    pub request_header: Option<Duration>,
+    pub idle: Duration,
+    pub total: Option<Duration>,
+}
+
+/// The scheduled maintenance settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct ScheduleSettings {
+    /// The maintenance windows. During a window the multiplexed sessions are asked
+    /// to shut down gracefully and the new tunnel requests are refused with
+    /// `503 Service Unavailable`.
+    #[serde(default)]
+    pub(crate) windows: Vec<MaintenanceWindowSettings>,
+    /// How long before a window the clients are notified of it
+    #[serde(default = "ScheduleSettings::default_notice")]
+    #[serde(rename = "notice_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) notice: Duration,
+}
+
+/// A weekly recurring maintenance window
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct MaintenanceWindowSettings {
+    /// The days of the week the window starts on: `mon`, `tue`, `wed`, `thu`, `fri`,
+    /// `sat`, or `sun`. The window starts every day if empty.
+    #[serde(default)]
+    pub(crate) days: Vec<String>,
+    /// The time of the day the window starts at, `HH:MM` in UTC
+    pub(crate) start: String,
+    /// How long the window lasts, at most a week
+    #[serde(rename = "duration_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) duration: Duration,
+}
+
+/// The set of HTTP/1.1 listener codec settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct Http1Settings {
+    /// Buffer size for outgoing traffic
+    #[serde(default = "Http1Settings::default_upload_buffer_size")]
+    pub(crate) upload_buffer_size: usize,
+    /// Respond to a CONNECT request right after the authentication while connecting to the peer
+    /// in parallel, saving a round trip of the tunnel setup.
+    /// The client data sent meanwhile waits in the stream flow control window.
+    /// A failed connection is then reported by closing the stream instead of an error status.
+    #[serde(default)]
+    pub(crate) fast_connect_ack: bool,
+    /// Refuse the requests deviating from what the clients send to the tunnel host
+    /// on this listener. Not set by default.
+    #[serde(default)]
+    pub(crate) strict_mode: Option<StrictModeSettings>,
+    /// Accept the tunnels carried over the WebSocket connections. Not set by default.
+    #[serde(default)]
+    pub(crate) websocket: Option<WebSocketSettings>,
+}
+
+/// The settings of the tunnels carried over the WebSocket connections, e.g., to operate
+/// behind a CDN passing nothing but the WebSocket traffic. A client upgrades an HTTP/1.1
+/// request to [`WebSocketSettings::path`] and speaks HTTP/2 over the binary messages
+/// of the connection, so the HTTP/2 listener settings are required.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct WebSocketSettings {
+    /// The path of the upgrade requests
+    #[serde(default = "WebSocketSettings::default_path")]
+    pub(crate) path: String,
+}
+
+/// The set of HTTP/2 listener codec settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct Http2Settings {
+    /// The initial window size (in octets) for connection-level flow control for received data
+    #[serde(default = "Http2Settings::default_initial_connection_window_size")]
+    pub(crate) initial_connection_window_size: u32,
+    /// The initial window size (in octets) for stream-level flow control for received data
+    #[serde(default = "Http2Settings::default_initial_stream_window_size")]
+    pub(crate) initial_stream_window_size: u32,
+    /// The number of streams that the sender permits the receiver to create
+    #[serde(default = "Http2Settings::default_max_concurrent_streams")]
+    pub(crate) max_concurrent_streams: u32,
+    /// The size (in octets) of the largest HTTP/2 frame payload that we are able to accept
+    #[serde(default = "Http2Settings::default_max_frame_size")]
+    pub(crate) max_frame_size: u32,
+    /// The maximum size of the header list of a request, advertised to the clients.
+    /// A request with a bigger one is refused with `431 Request Header Fields Too Large`.
+    #[serde(default = "Http2Settings::default_header_table_size")]
+    #[serde(alias = "max_header_list_size")]
+    pub(crate) header_table_size: u32,
+    /// The maximum size of a header field of a request, the name and the value together.
+    /// A request with a bigger one is refused with `431 Request Header Fields Too Large`.
+    #[serde(default = "Http2Settings::default_max_header_field_size")]
+    pub(crate) max_header_field_size: usize,
+    /// The maximum length of the URI of a request.
+    /// A request with a longer one is refused with `414 URI Too Long`.
+    #[serde(default = "Http2Settings::default_max_uri_length")]
+    pub(crate) max_uri_length: usize,
+    /// Respond to a CONNECT request right after the authentication while connecting to the peer
+    /// in parallel, saving a round trip of the tunnel setup.
+    /// The client data sent meanwhile waits in the stream flow control window.
+    /// A failed connection is then reported by closing the stream instead of an error status.
+    #[serde(default)]
+    pub(crate) fast_connect_ack: bool,
+    /// Refuse the requests deviating from what the clients send to the tunnel host
+    /// on this listener. Not set by default.
+    #[serde(default)]
+    pub(crate) strict_mode: Option<StrictModeSettings>,
+    /// Accept the tunnels carried over the gRPC streaming calls. Not set by default.
+    #[serde(default)]
+    pub(crate) grpc: Option<GrpcTransportSettings>,
+}
+
+/// The settings of the tunnels carried over the bidirectional streaming calls of a gRPC
+/// service, e.g., to operate behind a gRPC-aware load balancer. A client calls the method
+/// at [`GrpcTransportSettings::path`] and speaks HTTP/2 over the messages of the call.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct GrpcTransportSettings {
+    /// The path of the calls, `/<package>.<service>/<method>`
+    #[serde(default = "GrpcTransportSettings::default_path")]
+    pub(crate) path: String,
+}
+
+/// The set of QUIC listener codec settings
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct QuicSettings {
+    /// The size of UDP payloads that the endpoint is willing to receive. UDP datagrams with
+    /// payloads larger than this limit are not likely to be processed.
+    #[serde(default = "QuicSettings::default_recv_udp_payload_size")]
+    pub(crate) recv_udp_payload_size: usize,
+    /// The size of UDP payloads that the endpoint is willing to send
+    #[serde(default = "QuicSettings::default_send_udp_payload_size")]
+    pub(crate) send_udp_payload_size: usize,
+    /// The initial value for the maximum amount of data that can be sent on the connection
+    #[serde(default = "QuicSettings::default_initial_max_data")]
+    pub(crate) initial_max_data: u64,
+    /// The initial flow control limit for locally initiated bidirectional streams
+    #[serde(default = "QuicSettings::default_initial_max_stream_data_bidi_local")]
+    #[serde(alias = "max_stream_data_bidi_local")]
+    pub(crate) initial_max_stream_data_bidi_local: u64,
+    /// The initial flow control limit for peer-initiated bidirectional streams
+    #[serde(default = "QuicSettings::default_initial_max_stream_data_bidi_remote")]
+    #[serde(alias = "max_stream_data_bidi_remote")]
+    pub(crate) initial_max_stream_data_bidi_remote: u64,
+    /// The initial flow control limit for unidirectional streams
+    #[serde(default = "QuicSettings::default_initial_max_stream_data_uni")]
+    #[serde(alias = "max_stream_data_uni")]
+    pub(crate) initial_max_stream_data_uni: u64,
+    /// The initial maximum number of bidirectional streams the endpoint that receives this
+    /// transport parameter is permitted to initiate
+    #[serde(default = "QuicSettings::default_initial_max_streams_bidi")]
+    #[serde(alias = "max_streams_bidi")]
+    pub(crate) initial_max_streams_bidi: u64,
+    /// The initial maximum number of unidirectional streams the endpoint that receives this
+    /// transport parameter is permitted to initiate
+    #[serde(default = "QuicSettings::default_initial_max_streams_uni")]
+    #[serde(alias = "max_streams_uni")]
+    pub(crate) initial_max_streams_uni: u64,
+    /// The maximum size of the connection window
+    #[serde(default = "QuicSettings::default_max_connection_window")]
+    pub(crate) max_connection_window: u64,
+    /// The maximum size of the stream window
+    #[serde(default = "QuicSettings::default_max_stream_window")]
+    pub(crate) max_stream_window: u64,
+    /// Disable active connection migration on the address being used during the handshake
+    #[serde(default = "QuicSettings::default_disable_active_migration")]
+    pub(crate) disable_active_migration: bool,
+    /// Enable sending or receiving early data
+    #[serde(default = "QuicSettings::default_enable_early_data")]
+    pub(crate) enable_early_data: bool,
+    /// Enable the path MTU discovery.
+    /// If enabled, the size of the sent packets is raised from the minimum
+    /// up to [`QuicSettings.send_udp_payload_size`] as long as the probes get through.
+    #[serde(default)]
+    pub(crate) discover_path_mtu: bool,
+    /// Enable the pacing of the sent packets.
+    /// If enabled, the packets are spread over the round trip instead of being sent in bursts,
+    /// which reduces the queueing on the bufferbloated links.
+    /// Works only on Linux with the `fq` queueing discipline, which honors the scheduled
+    /// transmission times of the packets.
+    #[serde(default)]
+    pub(crate) enable_pacing: bool,
+    /// The maximum pacing rate of a connection in bytes per second.
+    /// If not set, the rate is limited only by the congestion controller.
+    #[serde(default)]
+    pub(crate) max_pacing_rate: Option<u64>,
+    /// The congestion control algorithm of the connections
+    #[serde(default)]
+    pub(crate) congestion_control: CongestionControl,
+    /// Run another congestion control algorithm for a cohort of the clients
+    /// to compare them on the real traffic.
+    /// The per-algorithm statistics of the connections are exported with the metrics.
+    #[serde(default)]
+    pub(crate) congestion_control_experiment: Option<CongestionControlExperimentSettings>,
+    /// The capacity of the QUIC multiplexer message queue.
+    /// Decreasing it may cause packet dropping in case the multiplexer cannot keep up the pace.
+    /// Increasing it may lead to high memory consumption.
+    // @todo: separate values for incoming and outgoing?
+    #[serde(default = "QuicSettings::default_message_queue_capacity")]
+    pub(crate) message_queue_capacity: usize,
+    /// The maximum size of the header list of a request, advertised to the clients.
+    /// A request with a bigger one closes the connection with the `H3_EXCESSIVE_LOAD` error,
+    /// as HTTP/3 decodes the headers of all the streams of a connection in one context.
+    #[serde(default = "QuicSettings::default_max_header_list_size")]
+    pub(crate) max_header_list_size: u64,
+    /// The maximum size of a header field of a request, the name and the value together.
+    /// A request with a bigger one is refused with `431 Request Header Fields Too Large`.
+    #[serde(default = "QuicSettings::default_max_header_field_size")]
+    pub(crate) max_header_field_size: usize,
+    /// The maximum length of the URI of a request.
+    /// A request with a longer one is refused with `414 URI Too Long`.
+    #[serde(default = "QuicSettings::default_max_uri_length")]
+    pub(crate) max_uri_length: usize,
+    /// Respond to a CONNECT request right after the authentication while connecting to the peer
+    /// in parallel, saving a round trip of the tunnel setup.
+    /// The client data sent meanwhile waits in the stream flow control window.
+    /// A failed connection is then reported by closing the stream instead of an error status.
+    #[serde(default)]
+    pub(crate) fast_connect_ack: bool,
+    /// Refuse the requests deviating from what the clients send to the tunnel host
+    /// on this listener. Not set by default.
+    #[serde(default)]
+    pub(crate) strict_mode: Option<StrictModeSettings>,
+    /// Accept the CONNECT-UDP requests (RFC 9298), relaying a UDP flow to the target
+    /// in the DATAGRAM capsules of the request stream.
+    /// Advertises the extended CONNECT support in the HTTP/3 settings.
+    #[serde(default)]
+    pub(crate) connect_udp: bool,
+    /// Accept the WebTransport sessions (the extended CONNECT requests with the `webtransport`
+    /// protocol) on the tunnel connections, so that the browser based clients may open
+    /// the tunnels in the bidirectional streams of a session.
+    /// A connection is dedicated to its session once one is accepted.
+    #[serde(default)]
+    pub(crate) webtransport: bool,
+    /// Enable the QUIC DATAGRAM frames (RFC 9221) and the HTTP datagrams (RFC 9297) in them.
+    /// If enabled, the UDP payloads of the CONNECT-UDP requests are exchanged in the frames
+    /// with the clients supporting them, bypassing the request streams.
+    #[serde(default)]
+    pub(crate) enable_datagrams: bool,
+    /// The maximum number of the received and of the unsent QUIC DATAGRAM frames
+    /// queued per connection. The frames over it are dropped.
+    #[serde(default = "QuicSettings::default_datagram_queue_length")]
+    pub(crate) datagram_queue_length: usize,
+}
+
+/// The QUIC congestion control algorithms
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub enum CongestionControl {
+    /// Reno
+    Reno,
+    /// CUBIC
+    #[default]
+    Cubic,
+    /// BBR
+    Bbr,
+    /// BBRv2
+    Bbr2,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct CongestionControlExperimentSettings {
+    /// The congestion control algorithm of the experiment cohort
+    pub(crate) algorithm: CongestionControl,
+    /// The share of the clients in the experiment cohort, in percent.
+    /// The clients are assigned to the cohorts by their IP addresses (IPv6 ones by the /64
+    /// prefix), as the algorithm has to be chosen before a client authenticates itself.
+    pub(crate) cohort_percentage: u8,
+}
+
+/// The strictness policy of a listener. The tunnel requests are CONNECT ones, so
+/// accepting anything else only widens the parser surface exposed to the internet.
+/// The requests which are not CONNECT ones are refused with `405 Method Not Allowed`,
+/// except for the methods exempted for the decoy channels, i.e., the reverse proxy,
+/// the ping and the speedtest ones. In particular, plain HTTP requests are not proxied.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct StrictModeSettings {
+    /// Refuse the HTTP/1.0 requests with `505 HTTP Version Not Supported`
+    #[serde(default = "StrictModeSettings::default_reject_http10")]
+    pub(crate) reject_http10: bool,
+    /// The methods other than CONNECT the decoy channels accept
+    #[serde(default = "StrictModeSettings::default_decoy_methods")]
+    pub(crate) decoy_methods: Vec<String>,
+    /// Refuse the tunnel requests which target is not in the authority form with
+    /// an explicit port, i.e., `host:port`, with `400 Bad Request`
+    #[serde(default = "StrictModeSettings::default_require_authority_form")]
+    pub(crate) require_authority_form: bool,
+}
+
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+pub struct TlsSettingsBuilder {
+    settings: TlsHostsSettings,
+}
+
+pub struct Http1SettingsBuilder {
+    settings: Http1Settings,
+}
+
+pub struct Http2SettingsBuilder {
+    settings: Http2Settings,
+}
+
+pub struct QuicSettingsBuilder {
+    settings: QuicSettings,
+}
+
+pub struct StrictModeSettingsBuilder {
+    settings: StrictModeSettings,
+}
+
+pub struct WebSocketSettingsBuilder {
+    settings: WebSocketSettings,
+}
+
+pub struct GrpcTransportSettingsBuilder {
+    settings: GrpcTransportSettings,
+}
+
+pub struct ReverseProxySettingsBuilder {
+    settings: ReverseProxySettings,
+}
+
+pub struct IcmpSettingsBuilder {
+    settings: IcmpSettings,
+}
+
+pub struct MetricsSettingsBuilder {
+    settings: MetricsSettings,
+}
+
+pub struct StatsdSettingsBuilder {
+    settings: StatsdSettings,
+}
+
+pub struct LdapSettingsBuilder {
+    settings: LdapSettings,
+}
+
+pub struct DatabaseSettingsBuilder {
+    settings: DatabaseSettings,
+}
+
+pub struct RedisSettingsBuilder {
+    settings: RedisSettings,
+}
+
+pub struct JwtSettingsBuilder {
+    settings: JwtSettings,
+}
+
+pub struct PamSettingsBuilder {
+    settings: PamSettings,
+}
+
+pub struct IntrospectionSettingsBuilder {
+    settings: IntrospectionSettings,
+}
+
+pub struct AuthCacheSettingsBuilder {
+    settings: AuthCacheSettings,
+}
+
+pub struct AuthChainSettingsBuilder {
+    settings: AuthChainSettings,
+}
+
+pub struct AuthLockoutSettingsBuilder {
+    settings: AuthLockoutSettings,
+}
+
+pub struct AuditLogSettingsBuilder {
+    settings: AuditLogSettings,
+}
+
+pub struct LogRotationSettingsBuilder {
+    settings: LogRotationSettings,
+}
+
+pub struct RevocationSettingsBuilder {
+    settings: RevocationSettings,
+}
+
+pub struct ReconnectTokenSettingsBuilder {
+    settings: ReconnectTokenSettings,
+}
+
+pub struct GuestSettingsBuilder {
+    settings: GuestSettings,
+}
+
+pub struct DigestAuthSettingsBuilder {
+    settings: DigestAuthSettings,
+}
+
+pub struct DuplicateSessionSettingsBuilder {
+    settings: DuplicateSessionSettings,
+}
+
+pub struct BandwidthEstimationSettingsBuilder {
+    settings: BandwidthEstimationSettings,
+}
+
+pub struct ClientAuthSettingsBuilder {
+    settings: ClientAuthSettings,
+}
+
+pub struct SelfSignedSettingsBuilder {
+    settings: SelfSignedSettings,
+}
+
+pub struct InterceptionSettingsBuilder {
+    settings: InterceptionSettings,
+}
+
+pub struct ExitPolicySettingsBuilder {
+    settings: ExitPolicySettings,
+}
+
+pub struct StaticFilesSettingsBuilder {
+    settings: StaticFilesSettings,
+}
+
+pub struct ResponseCacheSettingsBuilder {
+    settings: ResponseCacheSettings,
+}
+
+pub struct RequestMirrorSettingsBuilder {
+    settings: RequestMirrorSettings,
+}
+
+pub struct UpstreamTlsSettingsBuilder {
+    settings: UpstreamTlsSettings,
+}
+
+pub struct HopHealthCheckSettingsBuilder {
+    settings: HopHealthCheckSettings,
+}
+
+pub struct DirectForwarderSettingsBuilder {
+    settings: DirectForwarderSettings,
+}
+
+pub struct UpstreamSocks5SettingsBuilder {
+    settings: UpstreamSocks5Settings,
+}
+
+pub struct UpstreamHttpProxySettingsBuilder {
+    settings: UpstreamHttpProxySettings,
+}
+
+pub struct ErrorPagesSettingsBuilder {
+    settings: ErrorPagesSettings,
+}
+
+pub struct PortBlockSettingsBuilder {
+    settings: PortBlockSettings,
+}
+
+pub struct ConnectRateSettingsBuilder {
+    settings: ConnectRateSettings,
+}
+
+pub struct EgressUplinksSettingsBuilder {
+    settings: EgressUplinksSettings,
+}
+
+pub struct StateStoreSettingsBuilder {
+    settings: StateStoreSettings,
+}
+
+pub struct CertificateExpirySettingsBuilder {
+    settings: CertificateExpirySettings,
+}
+
+pub struct AffinitySettingsBuilder {
+    settings: AffinitySettings,
+}
+
+pub struct PolicySettingsBuilder {
+    settings: PolicySettings,
+}
+
+pub struct TimeoutSettingsBuilder {
+    settings: TimeoutSettings,
+}
+
+pub struct StageTimeoutSettingsBuilder {
+    settings: StageTimeoutSettings,
+}
+
+pub struct ScheduleSettingsBuilder {
+    settings: ScheduleSettings,
+}
+
+pub struct MaintenanceWindowSettingsBuilder {
+    settings: MaintenanceWindowSettings,
+}
+
+pub struct HttpRedirectSettingsBuilder {
+    settings: HttpRedirectSettings,
+}
+
+pub struct TriageSettingsBuilder {
+    settings: TriageSettings,
+}
+
+pub struct AcceptRateSettingsBuilder {
+    settings: AcceptRateSettings,
+}
+
+pub struct ProxyProtocolSettingsBuilder {
+    settings: ProxyProtocolSettings,
+}
+
+pub struct GrpcAdminSettingsBuilder {
+    settings: GrpcAdminSettings,
+}
+
+pub struct TierSettingsBuilder {
+    settings: TierSettings,
+}
+
+pub struct ProfileSettingsBuilder {
+    settings: ProfileSettings,
+}
+
+pub struct ImpairmentSettingsBuilder {
+    settings: ImpairmentSettings,
+}
+
+impl Settings {
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder::new()
+    }
+
+    pub(crate) fn is_built(&self) -> bool {
+        self.built
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.listen_address.ip().is_unspecified() && self.listen_address.port() == 0 {
+            return Err(ValidationError::ListenAddressNotSet);
+        }
+
+        self.reverse_proxy
+            .as_ref()
+            .map(ReverseProxySettings::validate)
+            .transpose()?;
+
+        if self.listen_protocols.http1.is_none()
+            && self.listen_protocols.http2.is_none()
+            && self.listen_protocols.quic.is_none()
+        {
+            return Err(ValidationError::ListenProtocols("Not set".into()));
+        }
+        if let Some(x) = &self.listen_protocols.quic {
+            // The QUIC packets carrying the client's Initial are required to be at least that big
+            if x.recv_udp_payload_size < MIN_QUIC_UDP_PAYLOAD_SIZE
+                || x.send_udp_payload_size < MIN_QUIC_UDP_PAYLOAD_SIZE
+            {
+                return Err(ValidationError::ListenProtocols(format!(
+                    "QUIC UDP payload size is less than {}",
+                    MIN_QUIC_UDP_PAYLOAD_SIZE
+                )));
+            }
+            if x.congestion_control_experiment
+                .as_ref()
+                .is_some_and(|x| x.cohort_percentage > 100)
+            {
+                return Err(ValidationError::ListenProtocols(
+                    "QUIC congestion control experiment cohort percentage is greater than 100"
+                        .into(),
+                ));
+            }
+        }
+        for x in [Protocol::Http1, Protocol::Http2, Protocol::Http3] {
+            self.strict_mode(x)
+                .map(StrictModeSettings::validate)
+                .transpose()?;
+        }
+        if let Some(x) = self
+            .listen_protocols
+            .http1
+            .as_ref()
+            .and_then(|x| x.websocket.as_ref())
+        {
+            x.validate()?;
+            if self.listen_protocols.http2.is_none() {
+                return Err(ValidationError::ListenProtocols(
+                    "WebSocket transport requires HTTP/2 settings".into(),
+                ));
+            }
+        }
+        self.listen_protocols
+            .http2
+            .as_ref()
+            .and_then(|x| x.grpc.as_ref())
+            .map(GrpcTransportSettings::validate)
+            .transpose()?;
+
+        if let Some(x) = self.tcp_max_segment_size {
+            if !(MIN_TCP_MAX_SEGMENT_SIZE..=MAX_TCP_MAX_SEGMENT_SIZE).contains(&x) {
+                return Err(ValidationError::TcpMaxSegmentSize(x));
+            }
+        }
+
+        self.egress_port_blocks
+            .as_ref()
+            .map(PortBlockSettings::validate)
+            .transpose()?;
+        self.egress_connect_rate
+            .as_ref()
+            .map(ConnectRateSettings::validate)
+            .transpose()?;
+        self.egress_uplinks
+            .as_ref()
+            .map(EgressUplinksSettings::validate)
+            .transpose()?;
+        let routes = self
+            .rules_engine
+            .iter()
+            .flat_map(|x| &x.config().route)
+            .chain(self.profiles.values().flat_map(|x| &x.route));
+        for name in routes.flat_map(|x| &x.race_uplinks) {
+            if !self
+                .egress_uplinks
+                .as_ref()
+                .is_some_and(|x| x.uplink.iter().any(|x| &x.name == name))
+            {
+                return Err(ValidationError::EgressUplinks(format!(
+                    "Routing rule refers to unknown uplink: {}",
+                    name
+                )));
+            }
+        }
+
+        for (i, x) in self.egress_addresses.iter().enumerate() {
+            if x.is_unspecified() || x.is_multicast() {
+                return Err(ValidationError::EgressAddresses(format!(
+                    "Not a unicast address: {}",
+                    x
+                )));
+            }
+            if self.egress_addresses[..i].contains(x) {
+                return Err(ValidationError::EgressAddresses(format!(
+                    "Duplicate address: {}",
+                    x
+                )));
+            }
+        }
+
+        self.ldap.as_ref().map(LdapSettings::validate).transpose()?;
+        self.database
+            .as_ref()
+            .map(DatabaseSettings::validate)
+            .transpose()?;
+        self.redis
+            .as_ref()
+            .map(RedisSettings::validate)
+            .transpose()?;
+        self.jwt.as_ref().map(JwtSettings::validate).transpose()?;
+        self.pam.as_ref().map(PamSettings::validate).transpose()?;
+        self.introspection
+            .as_ref()
+            .map(IntrospectionSettings::validate)
+            .transpose()?;
+        self.auth_cache
+            .as_ref()
+            .map(AuthCacheSettings::validate)
+            .transpose()?;
+        self.auth_lockout
+            .as_ref()
+            .map(AuthLockoutSettings::validate)
+            .transpose()?;
+        self.audit_log
+            .as_ref()
+            .map(AuditLogSettings::validate)
+            .transpose()?;
+        self.revocation
+            .as_ref()
+            .map(RevocationSettings::validate)
+            .transpose()?;
+        self.reconnect_tokens
+            .as_ref()
+            .map(ReconnectTokenSettings::validate)
+            .transpose()?;
+        if let Some(x) = &self.guest {
+            x.validate()?;
+            if x.tier.as_ref().is_some_and(|x| !self.tiers.contains_key(x)) {
+                return Err(ValidationError::Guest("Unknown tier".into()));
+            }
+            if x.profile
+                .as_ref()
+                .is_some_and(|x| !self.profiles.contains_key(x))
+            {
+                return Err(ValidationError::Guest("Unknown profile".into()));
+            }
+        }
+        self.digest_auth
+            .as_ref()
+            .map(DigestAuthSettings::validate)
+            .transpose()?;
+        self.duplicate_sessions
+            .as_ref()
+            .map(DuplicateSessionSettings::validate)
+            .transpose()?;
+        self.bandwidth_estimation
+            .as_ref()
+            .map(BandwidthEstimationSettings::validate)
+            .transpose()?;
+        self.client_auth
+            .as_ref()
+            .map(ClientAuthSettings::validate)
+            .transpose()?;
+        let authenticators = [
+            self.ldap.is_some(),
+            self.database.is_some(),
+            self.redis.is_some(),
+            self.jwt.is_some(),
+            self.pam.is_some(),
+            self.introspection.is_some(),
+        ];
+        if let Some(chain) = &self.auth_chain {
+            chain.validate()?;
+            let configured = |x: &AuthBackend| match x {
+                AuthBackend::CredentialsFile => !self.clients.path.is_empty(),
+                AuthBackend::Ldap => self.ldap.is_some(),
+                AuthBackend::Database => self.database.is_some(),
+                AuthBackend::Redis => self.redis.is_some(),
+                AuthBackend::Jwt => self.jwt.is_some(),
+                AuthBackend::Pam => self.pam.is_some(),
+                AuthBackend::Introspection => self.introspection.is_some(),
+            };
+            if let Some(x) = chain.backends.iter().find(|x| !configured(x)) {
+                return Err(ValidationError::AuthChain(format!(
+                    "Backend {:?} is not configured",
+                    x
+                )));
+            }
+        } else if authenticators.into_iter().filter(|x| *x).count() > 1 {
+            return Err(ValidationError::ConflictingAuthenticators);
+        }
+
+        // Do not start the endpoint without credentials on a public address
+        if self.clients.path.is_empty()
+            && self.clients.clients.is_empty()
+            && self.ldap.is_none()
+            && self.database.is_none()
+            && self.redis.is_none()
+            && self.jwt.is_none()
+            && self.pam.is_none()
+            && self.introspection.is_none()
+            && !self.listen_address.ip().is_loopback()
+        {
+            return Err(ValidationError::NoCredentialsOnPublicAddress);
+        }
+
+        for (name, tier) in &self.tiers {
+            tier.validate()
+                .map_err(|e| ValidationError::Tiers(format!("{}: {}", name, e)))?;
+        }
+        for (name, profile) in &self.profiles {
+            profile
+                .validate()
+                .map_err(|e| ValidationError::Profiles(format!("{}: {}", name, e)))?;
+        }
+        if let Some(x) = self.clients.clients.iter().find(|x| {
+            x.profile
+                .as_ref()
+                .is_some_and(|x| !self.profiles.contains_key(x))
+        }) {
+            return Err(ValidationError::Profiles(format!(
+                "Client {} is assigned to unknown profile",
+                x.username
+            )));
+        }
+        if let Some(x) = self
+            .clients
+            .clients
+            .iter()
+            .find(|x| x.tier.as_ref().is_some_and(|x| !self.tiers.contains_key(x)))
+        {
+            return Err(ValidationError::Tiers(format!(
+                "Client {} is assigned to unknown tier",
+                x.username
+            )));
+        }
+
+        self.state_store
+            .as_ref()
+            .map(StateStoreSettings::validate)
+            .transpose()?;
+        self.certificate_expiry
+            .as_ref()
+            .map(CertificateExpirySettings::validate)
+            .transpose()?;
+        self.affinity
+            .as_ref()
+            .map(AffinitySettings::validate)
+            .transpose()?;
+        if let Some(x) = &self.policy {
+            x.validate()?;
+            if x.terms.is_some() && self.state_store.is_none() {
+                return Err(ValidationError::Policy(
+                    "Terms acknowledgments require the state store".into(),
+                ));
+            }
+        }
+        self.schedule
+            .as_ref()
+            .map(ScheduleSettings::validate)
+            .transpose()?;
+        self.timeouts.validate()?;
+
+        if let Some(x) = &self.http_redirect {
+            x.validate()?;
+            if x.listen_address == self.listen_address {
+                return Err(ValidationError::HttpRedirect(
+                    "Listen address clashes with the main one".into(),
+                ));
+            }
+        }
+
+        self.triage
+            .as_ref()
+            .map(TriageSettings::validate)
+            .transpose()?;
+        self.accept_rate
+            .as_ref()
+            .map(AcceptRateSettings::validate)
+            .transpose()?;
+        self.proxy_protocol
+            .as_ref()
+            .map(ProxyProtocolSettings::validate)
+            .transpose()?;
+        self.statsd
+            .as_ref()
+            .map(StatsdSettings::validate)
+            .transpose()?;
+        self.metrics
+            .as_ref()
+            .map(MetricsSettings::validate)
+            .transpose()?;
+
+        if let Some(x) = &self.grpc_admin {
+            x.validate()?;
+            if x.listen_address == self.listen_address {
+                return Err(ValidationError::GrpcAdmin(
+                    "Listen address clashes with the main one".into(),
+                ));
+            }
+            if self
+                .metrics
+                .as_ref()
+                .and_then(|x| x.admin_token.as_ref())
+                .is_none()
+            {
+                return Err(ValidationError::GrpcAdmin(
+                    "Admin token of the metrics settings is not set".into(),
+                ));
+            }
+        }
+
+        self.exit_policy
+            .as_ref()
+            .map(ExitPolicySettings::validate)
+            .transpose()?;
+
+        match &self.forward_protocol {
+            ForwardProtocolSettings::Direct(x) => x.validate()?,
+            ForwardProtocolSettings::Socks5(x) => x.validate()?,
+        }
+
+        self.interception
+            .as_ref()
+            .map(InterceptionSettings::validate)
+            .transpose()?;
+
+        for x in &self.impairments {
+            x.validate()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn default_listen_address() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443))
+    }
+
+    pub fn default_ipv6_available() -> bool {
+        true
+    }
+
+    pub fn default_allow_private_network_connections() -> bool {
+        false
+    }
+
+    pub fn default_tls_handshake_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_client_listener_timeout() -> Duration {
+        Duration::from_secs(10 * 60)
+    }
+
+    pub fn default_connection_establishment_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn default_tcp_connections_timeout() -> Duration {
+        Duration::from_secs(604800) // 1 week (match client tcpip module)
+    }
+
+    pub fn default_udp_connections_timeout() -> Duration {
+        Duration::from_secs(300) // 5 minutes (match client tcpip module)
+    }
+
+    pub fn default_speedtest_enable() -> bool {
+        false
+    }
+
+    /// Resolve the timeouts of the connections of `protocol`
+    pub(crate) fn timeouts(&self, protocol: Protocol) -> Timeouts {
+        let specific = match protocol {
+            Protocol::Http1 => &self.timeouts.http1,
+            Protocol::Http2 => &self.timeouts.http2,
+            Protocol::Http3 => &self.timeouts.http3,
+        };
+        let pick = |f: fn(&StageTimeoutSettings) -> Option<Duration>| {
+            f(specific).or_else(|| f(&self.timeouts.stages))
+        };
+
+        Timeouts {
+            tls_handshake: pick(|x| x.tls_handshake).unwrap_or(self.tls_handshake_timeout),
+            auth: pick(|x| x.auth),
+            upstream_connect: pick(|x| x.upstream_connect)
+                .unwrap_or(self.connection_establishment_timeout),
+            request_header: pick(|x| x.request_header),
+            idle: pick(|x| x.idle).unwrap_or(self.tcp_connections_timeout),
+            total: pick(|x| x.total),
+        }
+    }
+
+    /// The strictness policy of the listener of `protocol`, if any
+    pub(crate) fn strict_mode(&self, protocol: Protocol) -> Option<&StrictModeSettings> {
+        match protocol {
+            Protocol::Http1 => self.listen_protocols.http1.as_ref()?.strict_mode.as_ref(),
+            Protocol::Http2 => self.listen_protocols.http2.as_ref()?.strict_mode.as_ref(),
+            Protocol::Http3 => self.listen_protocols.quic.as_ref()?.strict_mode.as_ref(),
+        }
+    }
+
+    /// The time the ping and speedtest handlers wait for a request for
+    pub(crate) fn handler_request_timeout(&self, protocol: Protocol) -> Duration {
+        self.timeouts(protocol)
+            .request_header
+            .unwrap_or(self.tls_handshake_timeout)
+    }
+}
+
+#[cfg(any(test, fuzzing))]
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            listen_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            ipv6_available: false,
+            allow_private_network_connections: true,
+            allow_metadata_endpoint_connections: false,
+            tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
+            client_listener_timeout: Settings::default_client_listener_timeout(),
+            connection_establishment_timeout: Settings::default_connection_establishment_timeout(),
+            tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
+            udp_connections_timeout: Settings::default_udp_connections_timeout(),
+            timeouts: Default::default(),
+            server_timing: false,
+            tcp_max_segment_size: None,
+            egress_addresses: Default::default(),
+            egress_port_blocks: None,
+            egress_connect_rate: None,
+            egress_uplinks: None,
+            forward_protocol: Default::default(),
+            clients: Default::default(),
+            ldap: None,
+            database: None,
+            redis: None,
+            jwt: None,
+            pam: None,
+            introspection: None,
+            auth_cache: None,
+            auth_chain: None,
+            auth_lockout: None,
+            audit_log: None,
+            revocation: None,
+            reconnect_tokens: None,
+            guest: None,
+            digest_auth: None,
+            client_auth: None,
+            listen_protocols: ListenProtocolSettings {
+                http1: Some(Http1Settings::builder().build()),
+                http2: Some(Http2Settings::builder().build()),
+                quic: Some(QuicSettings::builder().build()),
+            },
+            reverse_proxy: None,
+            icmp: None,
+            metrics: Default::default(),
+            statsd: None,
+            rules_engine: Some(rules::RulesEngine::default_allow()),
+            speedtest_enable: false,
+            tiers: Default::default(),
+            profiles: Default::default(),
+            max_connections_per_user: None,
+            max_streams_per_user: None,
+            duplicate_sessions: None,
+            bandwidth_estimation: None,
+            state_store: None,
+            status_file: None,
+            certificate_expiry: None,
+            affinity: None,
+            policy: None,
+            schedule: None,
+            http_redirect: None,
+            triage: None,
+            accept_rate: None,
+            proxy_protocol: None,
+            grpc_admin: None,
+            exit_policy: None,
+            interception: None,
+            impairments: Default::default(),
+            built: false,
+        }
+    }
+}
+
+impl TlsHostsSettings {
+    pub fn builder() -> TlsSettingsBuilder {
+        TlsSettingsBuilder::new()
+    }
+
+    pub(crate) fn is_built(&self) -> bool {
+        self.built
+    }
+
+    /// Point the hosts configured without a certificate to the generated self-signed one,
+    /// see [`TlsHostsSettings::self_signed`]
+    pub(crate) fn generate_self_signed(&mut self) -> Result<(), ValidationError> {
+        let Some(settings) = &self.self_signed else {
+            return Ok(());
+        };
+        let hosts = self
+            .main_hosts
+            .iter_mut()
+            .chain(self.ping_hosts.iter_mut())
+            .chain(self.speedtest_hosts.iter_mut())
+            .chain(self.reverse_proxy_hosts.iter_mut())
+            .filter(|x| x.cert_chain_path.is_empty() && x.private_key_path.is_empty())
+            .collect();
+        self_signed::generate(settings, hosts)
+            .map_err(|e| ValidationError::SelfSigned(e.to_string()))
+    }
+
+    fn validate_tls_hosts<'a, Iter>(
+        hosts: Iter,
+        mut unique_hosts: HashSet<&'a str>,
+    ) -> Result<HashSet<&'a str>, String>
+    where
+        Iter: Iterator<Item = &'a TlsHostInfo>,
+    {
+        for h in hosts {
+            if h.cert_chain_path.is_empty() {
+                return Err(format!(
+                    "Certificate chain is not set: hostname='{}'",
+                    h.hostname
+                ));
+            }
+            utils::load_certs(&h.cert_chain_path).map_err(|e| {
+                format!(
+                    "Invalid cert chain: path='{}', error='{}'",
+                    h.cert_chain_path, e
+                )
+            })?;
+
+            utils::load_private_key(&h.private_key_path).map_err(|e| {
+                format!("Invalid key: path='{}', error='{}'", h.private_key_path, e)
+            })?;
+
+            if !unique_hosts.insert(&h.hostname) {
+                return Err(format!("Hostname must be unique: {}", h.hostname));
+            }
+        }
+
+        Ok(unique_hosts)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.main_hosts.is_empty() {
+            return Err(ValidationError::MainTlsHostInfo("Not set".to_string()));
+        }
+
+        let hosts = Self::validate_tls_hosts(self.main_hosts.iter(), HashSet::new())
+            .map_err(ValidationError::MainTlsHostInfo)?;
+        let hosts = Self::validate_tls_hosts(self.ping_hosts.iter(), hosts)
+            .map_err(ValidationError::PingTlsHostInfo)?;
+        let hosts = Self::validate_tls_hosts(self.speedtest_hosts.iter(), hosts)
+            .map_err(ValidationError::SpeedTlsHostInfo)?;
+        Self::validate_tls_hosts(self.reverse_proxy_hosts.iter(), hosts)
+            .map_err(ValidationError::ReverseProxy)?;
+        self.self_signed
+            .as_ref()
+            .map(SelfSignedSettings::validate)
+            .transpose()?;
+
+        Ok(())
+    }
+}
+
+impl DirectForwarderSettings {
+    pub fn builder() -> DirectForwarderSettingsBuilder {
+        DirectForwarderSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        self.upstream_socks5
+            .as_ref()
+            .map(UpstreamSocks5Settings::validate)
+            .transpose()?;
+        self.upstream_http
+            .as_ref()
+            .map(UpstreamHttpProxySettings::validate)
+            .transpose()?;
+        if self.upstream_socks5.is_some() && self.upstream_http.is_some() {
+            return Err(ValidationError::UpstreamHttpProxy(
+                "Upstream SOCKS5 and HTTP proxies are mutually exclusive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl UpstreamSocks5Settings {
+    pub fn builder() -> UpstreamSocks5SettingsBuilder {
+        UpstreamSocks5SettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.ip().is_unspecified() || self.address.port() == 0 {
+            return Err(ValidationError::UpstreamSocks5(format!(
+                "Invalid proxy address: {}",
+                self.address
+            )));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(ValidationError::UpstreamSocks5(
+                "Username and password must be set together".into(),
+            ));
+        }
+        // https://datatracker.ietf.org/doc/html/rfc1929#section-2
+        let is_valid = |x: &Option<String>| x.as_ref().is_none_or(|x| (1..=255).contains(&x.len()));
+        if !is_valid(&self.username) || !is_valid(&self.password) {
+            return Err(ValidationError::UpstreamSocks5(
+                "Username and password must be 1 to 255 bytes long".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl UpstreamHttpProxySettings {
+    pub fn builder() -> UpstreamHttpProxySettingsBuilder {
+        UpstreamHttpProxySettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.ip().is_unspecified() || self.address.port() == 0 {
+            return Err(ValidationError::UpstreamHttpProxy(format!(
+                "Invalid proxy address: {}",
+                self.address
+            )));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(ValidationError::UpstreamHttpProxy(
+                "Username and password must be set together".into(),
+            ));
+        }
+        // https://datatracker.ietf.org/doc/html/rfc7617#section-2
+        if self.username.as_ref().is_some_and(|x| x.contains(':')) {
+            return Err(ValidationError::UpstreamHttpProxy(
+                "Username must not contain a colon".into(),
+            ));
+        }
+        self.tls
+            .as_ref()
+            .map(UpstreamTlsSettings::validate)
+            .transpose()?;
+
+        Ok(())
+    }
+}
+
+impl Socks5ForwarderSettings {
+    pub fn builder() -> Socks5ForwarderSettingsBuilder {
+        Socks5ForwarderSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        self.tls
+            .as_ref()
+            .map(UpstreamTlsSettings::validate)
+            .transpose()?;
+
+        for (i, x) in self.fallback_addresses.iter().enumerate() {
+            if x.ip().is_unspecified() || x.port() == 0 {
+                return Err(ValidationError::UpstreamHops(format!(
+                    "Invalid fallback proxy address: {}",
+                    x
+                )));
+            }
+            if *x == self.address || self.fallback_addresses[..i].contains(x) {
+                return Err(ValidationError::UpstreamHops(format!(
+                    "Duplicate proxy address: {}",
+                    x
+                )));
+            }
+        }
+
+        self.health_check
+            .as_ref()
+            .map(HopHealthCheckSettings::validate)
+            .transpose()?;
+
+        Ok(())
+    }
+}
+
+impl HopHealthCheckSettings {
+    /// The period after which a hop which failed a connection attempt is tried again
+    /// if the probing is not configured
+    pub const DOWN_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub fn builder() -> HopHealthCheckSettingsBuilder {
+        HopHealthCheckSettingsBuilder::new()
+    }
+
+    pub fn default_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.interval.is_zero() {
+            return Err(ValidationError::UpstreamHops(
+                "Health check interval is zero".into(),
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err(ValidationError::UpstreamHops(
+                "Health check timeout is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Http1Settings {
+    pub fn builder() -> Http1SettingsBuilder {
+        Http1SettingsBuilder::new()
+    }
+
+    pub fn default_upload_buffer_size() -> usize {
+        32 * 1024
+    }
+}
+
+impl Http2Settings {
+    pub fn builder() -> Http2SettingsBuilder {
+        Http2SettingsBuilder::new()
+    }
+
+    pub fn default_initial_connection_window_size() -> u32 {
+        8 * 1024 * 1024
+    }
+
+    pub fn default_initial_stream_window_size() -> u32 {
+        128 * 1024 // Chrome constant
+    }
+
+    pub fn default_max_concurrent_streams() -> u32 {
+        1000 // Chrome constant
+    }
+
+    pub fn default_max_frame_size() -> u32 {
+        1 << 14 // Firefox constant
+    }
+
+    pub fn default_header_table_size() -> u32 {
+        65536
+    }
+
+    pub fn default_max_header_field_size() -> usize {
+        16 * 1024
+    }
+
+    pub fn default_max_uri_length() -> usize {
+        8 * 1024
+    }
+}
+
+impl QuicSettings {
+    pub fn builder() -> QuicSettingsBuilder {
+        QuicSettingsBuilder::new()
+    }
+
+    pub fn default_recv_udp_payload_size() -> usize {
+        1350
+    }
+
+    pub fn default_send_udp_payload_size() -> usize {
+        1350
+    }
+
+    pub fn default_initial_max_data() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    pub fn default_initial_max_stream_data_bidi_local() -> u64 {
+        1024 * 1024
+    }
+
+    pub fn default_initial_max_stream_data_bidi_remote() -> u64 {
+        1024 * 1024
+    }
+
+    pub fn default_initial_max_stream_data_uni() -> u64 {
+        1024 * 1024
+    }
+
+    pub fn default_initial_max_streams_bidi() -> u64 {
+        4 * 1024
+    }
+
+    pub fn default_initial_max_streams_uni() -> u64 {
+        4 * 1024
+    }
+
+    pub fn default_max_connection_window() -> u64 {
+        24 * 1024 * 1024
+    }
+
+    pub fn default_max_stream_window() -> u64 {
+        16 * 1024 * 1024
+    }
+
+    pub fn default_disable_active_migration() -> bool {
+        true
+    }
+
+    pub fn default_enable_early_data() -> bool {
+        true
+    }
+
+    pub fn default_message_queue_capacity() -> usize {
+        4 * 1024
+    }
+
+    pub fn default_max_header_list_size() -> u64 {
+        65536
+    }
+
+    pub fn default_max_header_field_size() -> usize {
+        16 * 1024
+    }
+
+    pub fn default_max_uri_length() -> usize {
+        8 * 1024
+    }
+
+    pub fn default_datagram_queue_length() -> usize {
+        1024
+    }
+}
+
+impl StrictModeSettings {
+    pub fn builder() -> StrictModeSettingsBuilder {
+        StrictModeSettingsBuilder::new()
+    }
+
+    pub fn default_reject_http10() -> bool {
+        true
+    }
+
+    pub fn default_decoy_methods() -> Vec<String> {
+        vec![
+            http::Method::GET.to_string(),
+            http::Method::HEAD.to_string(),
+        ]
+    }
+
+    pub fn default_require_authority_form() -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(x) = self
+            .decoy_methods
+            .iter()
+            .find(|x| http::Method::from_bytes(x.as_bytes()).is_err())
+        {
+            return Err(ValidationError::ListenProtocols(format!(
+                "Invalid strict mode decoy method: {}",
+                x
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl WebSocketSettings {
+    pub fn builder() -> WebSocketSettingsBuilder {
+        WebSocketSettingsBuilder::new()
+    }
+
+    pub fn default_path() -> String {
+        "/ws".to_string()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if !self.path.starts_with('/') {
+            return Err(ValidationError::ListenProtocols(format!(
+                "WebSocket path is not absolute: {}",
+                self.path
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl GrpcTransportSettings {
+    pub fn builder() -> GrpcTransportSettingsBuilder {
+        GrpcTransportSettingsBuilder::new()
+    }
+
+    pub fn default_path() -> String {
+        "/trusttunnel.Tunnel/Stream".to_string()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if !self.path.starts_with('/') {
+            return Err(ValidationError::ListenProtocols(format!(
+                "gRPC path is not absolute: {}",
+                self.path
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ReverseProxySettings {
+    pub fn builder() -> ReverseProxySettingsBuilder {
+        ReverseProxySettingsBuilder::new()
+    }
+
+    pub fn default_server_address() -> SocketAddr {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    }
+
+    /// Get the headers to be set on the responses for the TLS host
+    pub(crate) fn response_headers_for(&self, host: &str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        for key in ["*", host] {
+            for (name, value) in self.response_headers.get(key).into_iter().flatten() {
+                if let (Ok(n), Ok(v)) = (
+                    http::HeaderName::from_bytes(name.as_bytes()),
+                    http::HeaderValue::from_str(value),
+                ) {
+                    headers.insert(n, v);
+                }
+            }
+        }
+        headers
+    }
+
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.static_files.is_none() && self.server_address.port() == 0 {
+            return Err(ValidationError::ReverseProxy(
+                "Server address is not set".to_string(),
+            ));
+        }
+
+        if self.path_mask.is_empty() || !self.path_mask.starts_with('/') {
+            return Err(ValidationError::ReverseProxy(format!(
+                "Invalid path mask: {}",
+                self.path_mask
+            )));
+        }
+        if let Some(x) = self
+            .non_tunnel_path_prefixes
+            .iter()
+            .find(|x| !x.starts_with('/'))
+        {
+            return Err(ValidationError::ReverseProxy(format!(
+                "Invalid non-tunnel path prefix: {}",
+                x
+            )));
+        }
+
+        for (host, headers) in &self.response_headers {
+            for (name, value) in headers {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || http::HeaderValue::from_str(value).is_err()
+                {
+                    return Err(ValidationError::ReverseProxy(format!(
+                        "Invalid response header for {}: {}: {}",
+                        host, name, value
+                    )));
+                }
+            }
+        }
+
+        self.cache
+            .as_ref()
+            .map(ResponseCacheSettings::validate)
+            .transpose()?;
+        self.static_files
+            .as_ref()
+            .map(StaticFilesSettings::validate)
+            .transpose()?;
+        self.mirror
+            .as_ref()
+            .map(RequestMirrorSettings::validate)
+            .transpose()?;
+        self.error_pages
+            .as_ref()
+            .map(ErrorPagesSettings::validate)
+            .transpose()?;
+        self.tls
+            .as_ref()
+            .map(UpstreamTlsSettings::validate)
+            .transpose()?;
+
+        Ok(())
+    }
+}
+
+impl StaticFilesSettings {
+    pub fn builder<P: ToString>(root: P) -> StaticFilesSettingsBuilder {
+        StaticFilesSettingsBuilder::new(root.to_string())
+    }
+
+    pub fn default_index_files() -> Vec<String> {
+        vec!["index.html".to_string()]
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.root.is_empty() {
+            return Err(ValidationError::ReverseProxy(
+                "Static files root is not set".into(),
+            ));
+        }
+        if self
+            .index_files
+            .iter()
+            .any(|x| x.is_empty() || x.contains('/'))
+        {
+            return Err(ValidationError::ReverseProxy(format!(
+                "Invalid index files: {:?}",
+                self.index_files
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ResponseCacheSettings {
+    pub fn builder() -> ResponseCacheSettingsBuilder {
+        ResponseCacheSettingsBuilder::new()
+    }
+
+    pub fn default_max_memory_size() -> usize {
+        64 * 1024 * 1024
+    }
+
+    pub fn default_max_entry_size() -> usize {
+        1024 * 1024
+    }
+
+    pub fn default_max_disk_size() -> usize {
+        1024 * 1024 * 1024
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.max_entry_size == 0 || self.max_entry_size > self.max_memory_size {
+            return Err(ValidationError::ReverseProxy(format!(
+                "Cache entry size limit must be positive and not exceed the memory limit: {}",
+                self.max_entry_size
+            )));
+        }
+        if self.disk_path.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::ReverseProxy(
+                "Cache disk path is empty".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl RequestMirrorSettings {
+    pub fn builder(server_address: SocketAddr) -> RequestMirrorSettingsBuilder {
+        RequestMirrorSettingsBuilder::new(server_address)
+    }
+
+    pub fn default_percentage() -> u8 {
+        100
+    }
+
+    pub fn default_max_request_size() -> usize {
+        1024 * 1024
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.server_address.port() == 0 {
+            return Err(ValidationError::ReverseProxy(
+                "Mirror server port is not set".into(),
+            ));
+        }
+        if self.percentage > 100 {
+            return Err(ValidationError::ReverseProxy(format!(
+                "Mirrored requests percentage is greater than 100: {}",
+                self.percentage
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl UpstreamTlsSettings {
+    pub fn builder() -> UpstreamTlsSettingsBuilder {
+        UpstreamTlsSettingsBuilder::new()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(x) = &self.server_name {
+            if rustls::ServerName::try_from(x.as_str()).is_err() {
+                return Err(ValidationError::UpstreamTls(format!(
+                    "Invalid server name: {}",
+                    x
+                )));
+            }
+        }
+        if self.ca_bundle_path.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::UpstreamTls(
+                "CA bundle path is empty".into(),
+            ));
+        }
+        for x in &self.spki_pins {
+            if !base64::engine::general_purpose::STANDARD
+                .decode(x)
+                .is_ok_and(|x| x.len() == 32)
+            {
+                return Err(ValidationError::UpstreamTls(format!(
+                    "Not a base64 encoded SHA-256 digest: {}",
+                    x
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl InterceptionSettings {
+    pub fn builder<S: ToString>(ca_cert_path: S, ca_key_path: S) -> InterceptionSettingsBuilder {
+        InterceptionSettingsBuilder::new(ca_cert_path.to_string(), ca_key_path.to_string())
+    }
+
+    pub fn default_ports() -> Vec<u16> {
+        vec![443]
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.ca_cert_path.is_empty() {
+            return Err(ValidationError::Interception(
+                "CA certificate path is empty".into(),
+            ));
+        }
+        if self.ca_key_path.is_empty() {
+            return Err(ValidationError::Interception("CA key path is empty".into()));
+        }
+        if self.destinations.is_empty() {
+            return Err(ValidationError::Interception("No destinations".into()));
+        }
+        if let Some(x) = self
+            .destinations
+            .iter()
+            .find(|x| !interception::is_valid_pattern(x))
+        {
+            return Err(ValidationError::Interception(format!(
+                "Invalid destination pattern: {}",
+                x
+            )));
+        }
+        if self.ports.is_empty() || self.ports.contains(&0) {
+            return Err(ValidationError::Interception(format!(
+                "Invalid ports: {:?}",
+                self.ports
+            )));
+        }
+        if self.upstream_tls.server_name.is_some() {
+            return Err(ValidationError::Interception(
+                "Upstream server name is taken from the intercepted connection".into(),
+            ));
+        }
+        self.upstream_tls.validate()
+    }
+}
+
+impl PortBlockSettings {
+    pub fn builder() -> PortBlockSettingsBuilder {
+        PortBlockSettingsBuilder::new()
+    }
+
+    pub fn default_first_port() -> u16 {
+        1024
+    }
+
+    pub fn default_last_port() -> u16 {
+        65535
+    }
+
+    pub fn default_block_size() -> u16 {
+        512
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.first_port == 0 || self.first_port > self.last_port {
+            return Err(ValidationError::EgressPortBlocks(format!(
+                "Invalid port range: {}-{}",
+                self.first_port, self.last_port
+            )));
+        }
+        if self.block_size == 0
+            || self.block_size as u32 > (self.last_port - self.first_port) as u32 + 1
+        {
+            return Err(ValidationError::EgressPortBlocks(format!(
+                "Block size does not fit the port range: {}",
+                self.block_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ConnectRateSettings {
+    pub fn builder() -> ConnectRateSettingsBuilder {
+        ConnectRateSettingsBuilder::new()
+    }
+
+    pub fn default_connects_per_sec() -> u32 {
+        10
+    }
+
+    pub fn default_burst() -> u32 {
+        20
+    }
+
+    pub fn default_max_wait() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn default_max_destinations() -> usize {
+        100000
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.connects_per_sec == 0 {
+            return Err(ValidationError::EgressConnectRate(
+                "Connects per second is zero".into(),
+            ));
+        }
+        if self.burst == 0 {
+            return Err(ValidationError::EgressConnectRate("Burst is zero".into()));
+        }
+        if self.max_destinations == 0 {
+            return Err(ValidationError::EgressConnectRate(
+                "Maximum destinations is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl EgressUplinksSettings {
+    pub fn builder() -> EgressUplinksSettingsBuilder {
+        EgressUplinksSettingsBuilder::new()
+    }
+
+    pub fn default_race_delay() -> Duration {
+        Duration::from_millis(250)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.uplink.is_empty() {
+            return Err(ValidationError::EgressUplinks("No uplinks".into()));
+        }
+        for (i, x) in self.uplink.iter().enumerate() {
+            if x.name.is_empty() || x.interface.is_empty() {
+                return Err(ValidationError::EgressUplinks(format!(
+                    "Uplink #{}: empty name or interface",
+                    i
+                )));
+            }
+            if self.uplink[..i].iter().any(|y| y.name == x.name) {
+                return Err(ValidationError::EgressUplinks(format!(
+                    "Duplicate uplink: {}",
+                    x.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ErrorPagesSettings {
+    pub fn builder() -> ErrorPagesSettingsBuilder {
+        ErrorPagesSettingsBuilder::new()
+    }
+
+    /// Get the page path of the error response status
+    pub(crate) fn page_of(&self, status: http::StatusCode) -> Option<&str> {
+        match status {
+            http::StatusCode::BAD_GATEWAY => self.bad_gateway.as_deref(),
+            http::StatusCode::SERVICE_UNAVAILABLE => self.service_unavailable.as_deref(),
+            http::StatusCode::GATEWAY_TIMEOUT => self.gateway_timeout.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if [
+            &self.bad_gateway,
+            &self.service_unavailable,
+            &self.gateway_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        .any(String::is_empty)
+        {
+            return Err(ValidationError::ReverseProxy(
+                "Error page path is empty".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl IcmpSettings {
+    pub fn builder() -> IcmpSettingsBuilder {
+        IcmpSettingsBuilder::new()
+    }
+
+    pub fn default_interface_name() -> String {
+        if cfg!(target_os = "linux") {
+            "eth0"
+        } else {
+            "en0"
+        }
+        .into()
+    }
+
+    pub fn default_request_timeout() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    pub fn default_message_queue_capacity() -> usize {
+        256
+    }
+}
+
+impl MetricsSettings {
+    pub fn builder() -> MetricsSettingsBuilder {
+        MetricsSettingsBuilder::new()
+    }
+
+    pub fn default_listen_address() -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, 1987).into()
+    }
+
+    pub fn default_request_timeout() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    pub fn default_stats_history() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.admin_token.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::Metrics("Admin token is empty".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl ProfileSettings {
+    pub fn builder() -> ProfileSettingsBuilder {
+        ProfileSettingsBuilder::new()
+    }
+
+    pub fn default_action() -> rules::RuleAction {
+        rules::RuleAction::Allow
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for x in &self.destinations {
+            if x.destination.contains('/') {
+                x.destination
+                    .parse::<ipnet::IpNet>()
+                    .map_err(|e| format!("Invalid destination {}: {}", x.destination, e))?;
+            } else {
+                host_patterns::validate(&x.destination)?;
+            }
+        }
+        for x in &self.route {
+            host_patterns::validate(&x.destination)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TierSettings {
+    pub fn builder() -> TierSettingsBuilder {
+        TierSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_bytes_per_sec == Some(0) {
+            return Err("Rate limit must be positive".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl HttpRedirectSettings {
+    pub fn builder() -> HttpRedirectSettingsBuilder {
+        HttpRedirectSettingsBuilder::new()
+    }
+
+    pub fn default_listen_address() -> SocketAddr {
+        (Ipv4Addr::UNSPECIFIED, 80).into()
+    }
+
+    pub fn default_request_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.listen_address.port() == 0 {
+            return Err(ValidationError::HttpRedirect(
+                "Listen port is not set".into(),
+            ));
+        }
+        if self.https_port == Some(0) {
+            return Err(ValidationError::HttpRedirect("Invalid HTTPS port".into()));
+        }
+        if self
+            .acme_challenge_dir
+            .as_ref()
+            .is_some_and(String::is_empty)
+        {
+            return Err(ValidationError::HttpRedirect(
+                "ACME challenge directory is empty".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl TriageSettings {
+    pub fn builder() -> TriageSettingsBuilder {
+        TriageSettingsBuilder::new()
+    }
+
+    pub fn default_tarpit_duration() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn default_max_tarpit_connections() -> usize {
+        1024
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.garbage == TriageAction::Redirect {
+            return Err(ValidationError::Triage(
+                "Only plain HTTP connections may be redirected".into(),
+            ));
+        }
+        if (self.plain_http == TriageAction::Tarpit || self.garbage == TriageAction::Tarpit)
+            && (self.tarpit_duration.is_zero() || self.max_tarpit_connections == 0)
+        {
+            return Err(ValidationError::Triage(
+                "Tarpit duration or maximum connections is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl AcceptRateSettings {
+    pub fn builder() -> AcceptRateSettingsBuilder {
+        AcceptRateSettingsBuilder::new()
+    }
+
+    pub fn default_connects_per_sec() -> u32 {
+        5
+    }
+
+    pub fn default_burst() -> u32 {
+        20
+    }
+
+    pub fn default_subnet_connects_per_sec() -> u32 {
+        50
+    }
+
+    pub fn default_subnet_burst() -> u32 {
+        200
+    }
+
+    pub fn default_ipv4_prefix_len() -> u8 {
+        24
+    }
+
+    pub fn default_ipv6_prefix_len() -> u8 {
+        48
+    }
+
+    pub fn default_ban_duration() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    pub fn default_max_entries() -> usize {
+        100000
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.connects_per_sec == 0 || self.subnet_connects_per_sec == 0 {
+            return Err(ValidationError::AcceptRate(
+                "Connects per second is zero".into(),
+            ));
+        }
+        if self.burst == 0 || self.subnet_burst == 0 {
+            return Err(ValidationError::AcceptRate("Burst is zero".into()));
+        }
+        if self.ipv4_prefix_len > 32 || self.ipv6_prefix_len > 128 {
+            return Err(ValidationError::AcceptRate(
+                "Subnet prefix length is out of range".into(),
+            ));
+        }
+        if self.ban_after == Some(0) || (self.ban_after.is_some() && self.ban_duration.is_zero()) {
+            return Err(ValidationError::AcceptRate(
+                "Ban threshold or duration is zero".into(),
+            ));
+        }
+        if self.max_entries == 0 {
+            return Err(ValidationError::AcceptRate(
+                "Maximum entries is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl LdapSettings {
+    pub fn builder<S: ToString>(address: S) -> LdapSettingsBuilder {
+        LdapSettingsBuilder::new(address.to_string())
+    }
+
+    pub fn default_search_attribute() -> String {
+        "uid".into()
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.is_empty() {
+            return Err(ValidationError::Ldap("Server address is not set".into()));
+        }
+        match (&self.bind_dn, &self.search_base_dn) {
+            (Some(_), Some(_)) => {
+                return Err(ValidationError::Ldap(
+                    "Both bind DN and search base DN are set".into(),
+                ))
+            }
+            (None, None) => {
+                return Err(ValidationError::Ldap(
+                    "Neither bind DN nor search base DN is set".into(),
+                ))
+            }
+            (Some(x), None) if !x.contains("{username}") => {
+                return Err(ValidationError::Ldap(
+                    "Bind DN has no {username} placeholder".into(),
+                ))
+            }
+            _ => (),
+        }
+        if self.search_attribute.is_empty() {
+            return Err(ValidationError::Ldap("Search attribute is empty".into()));
+        }
+        if self.search_bind_dn.is_some() != self.search_bind_password.is_some() {
+            return Err(ValidationError::Ldap(
+                "Search bind DN and password must be set together".into(),
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err(ValidationError::Ldap("Timeout is zero".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl DatabaseSettings {
+    pub fn builder<S1: ToString, S2: ToString, S3: ToString>(
+        address: S1,
+        database: S2,
+        user: S3,
+    ) -> DatabaseSettingsBuilder {
+        DatabaseSettingsBuilder::new(address.to_string(), database.to_string(), user.to_string())
+    }
+
+    pub fn default_query(driver: DatabaseDriver) -> &'static str {
+        match driver {
+            DatabaseDriver::Postgres | DatabaseDriver::Sqlite => {
+                "SELECT password, valid_till FROM clients WHERE username = $1"
+            }
+            DatabaseDriver::Mysql => "SELECT password, valid_till FROM clients WHERE username = ?",
+        }
+    }
+
+    /// The query looking up a client, either the configured or the default one
+    pub fn lookup_query(&self) -> &str {
+        self.query
+            .as_deref()
+            .unwrap_or(Self::default_query(self.driver))
+    }
+
+    pub fn default_pool_size() -> usize {
+        4
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.database.is_empty() {
+            return Err(ValidationError::Database("Database is not set".into()));
+        }
+        match self.driver {
+            DatabaseDriver::Postgres | DatabaseDriver::Mysql => {
+                if self.address.is_empty() {
+                    return Err(ValidationError::Database(
+                        "Server address is not set".into(),
+                    ));
+                }
+                if self.user.is_empty() {
+                    return Err(ValidationError::Database("User is not set".into()));
+                }
+            }
+            DatabaseDriver::Sqlite => {
+                if self.tls.is_some() {
+                    return Err(ValidationError::Database("TLS is set for SQLite".into()));
+                }
+            }
+        }
+        if let Some(x) = &self.tls {
+            // The drivers verify the server certificates by themselves
+            if x.server_name.is_some() || !x.spki_pins.is_empty() {
+                return Err(ValidationError::Database(
+                    "TLS server name and SPKI pins are not supported".into(),
+                ));
+            }
+        }
+        let parameter = match self.driver {
+            DatabaseDriver::Postgres | DatabaseDriver::Sqlite => "$1",
+            DatabaseDriver::Mysql => "?",
+        };
+        if !self.lookup_query().contains(parameter) {
+            return Err(ValidationError::Database(format!(
+                "Query has no {} parameter",
+                parameter
+            )));
+        }
+        if self.pool_size == 0 {
+            return Err(ValidationError::Database("Pool size is zero".into()));
+        }
+        if self.timeout.is_zero() {
+            return Err(ValidationError::Database("Timeout is zero".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl RedisSettings {
+    pub fn builder<S: ToString>(address: S) -> RedisSettingsBuilder {
+        RedisSettingsBuilder::new(address.to_string())
+    }
+
+    pub fn default_key_prefix() -> String {
+        "trusttunnel:client:".into()
+    }
+
+    pub fn default_pool_size() -> usize {
+        4
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.is_empty() {
+            return Err(ValidationError::Redis("Server address is not set".into()));
+        }
+        if self.username.is_some() && self.password.is_none() {
+            return Err(ValidationError::Redis(
+                "Username is set without password".into(),
+            ));
+        }
+        if self.pool_size == 0 {
+            return Err(ValidationError::Redis("Pool size is zero".into()));
+        }
+        if self.timeout.is_zero() {
+            return Err(ValidationError::Redis("Timeout is zero".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl JwtSettings {
+    pub fn builder() -> JwtSettingsBuilder {
+        JwtSettingsBuilder::new()
+    }
+
+    pub fn default_leeway() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.secret.is_none() && self.jwks_file.is_none() {
+            return Err(ValidationError::Jwt(
+                "Neither secret nor JWKS file is set".into(),
+            ));
+        }
+        // RFC 7518 requires the HMAC key to be at least as long as the hash output
+        if self.secret.as_ref().is_some_and(|x| x.len() < 32) {
+            return Err(ValidationError::Jwt(
+                "Secret is shorter than 32 bytes".into(),
+            ));
+        }
+        if self.jwks_file.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::Jwt("JWKS file path is empty".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl PamSettings {
+    pub fn builder() -> PamSettingsBuilder {
+        PamSettingsBuilder::new()
+    }
+
+    pub fn default_service() -> String {
+        "trusttunnel".into()
+    }
+
+    pub fn default_check_account() -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if !cfg!(all(unix, feature = "pam")) {
+            return Err(ValidationError::Pam(
+                "The endpoint is built without the `pam` feature".into(),
+            ));
+        }
+        if self.service.is_empty() || self.service.contains(['/', '\0']) {
+            return Err(ValidationError::Pam(format!(
+                "Invalid service name: {}",
+                self.service
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl IntrospectionSettings {
+    pub fn builder<S: ToString>(
+        url: S,
+        client_id: S,
+        client_secret: S,
+    ) -> IntrospectionSettingsBuilder {
+        IntrospectionSettingsBuilder::new(
+            url.to_string(),
+            client_id.to_string(),
+            client_secret.to_string(),
+        )
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn default_cache_size() -> usize {
+        10000
+    }
+
+    pub fn default_max_cache_ttl() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        let url = self
+            .url
+            .parse::<http::Uri>()
+            .ok()
+            .filter(|x| x.host().is_some_and(|x| !x.is_empty()))
+            .ok_or_else(|| ValidationError::Introspection(format!("Invalid URL: {}", self.url)))?;
+        match url.scheme_str() {
+            Some("http") if self.tls.is_some() => {
+                return Err(ValidationError::Introspection(
+                    "TLS settings are set for an http URL".into(),
+                ))
+            }
+            Some("http") | Some("https") => (),
+            _ => {
+                return Err(ValidationError::Introspection(format!(
+                    "Unsupported URL scheme: {}",
+                    self.url
+                )))
+            }
+        }
+        self.tls
+            .as_ref()
+            .map(UpstreamTlsSettings::validate)
+            .transpose()?;
+        if self.client_id.is_empty() || self.client_secret.is_empty() {
+            return Err(ValidationError::Introspection(
+                "Client credentials are not set".into(),
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err(ValidationError::Introspection("Timeout is zero".into()));
+        }
+        if self.max_cache_ttl.is_zero() {
+            return Err(ValidationError::Introspection(
+                "Maximum cache TTL is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl AuthCacheSettings {
+    pub fn builder() -> AuthCacheSettingsBuilder {
+        AuthCacheSettingsBuilder::new()
+    }
+
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn default_negative_ttl() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn default_max_entries() -> usize {
+        10000
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.ttl.is_zero() {
+            return Err(ValidationError::AuthCache("TTL is zero".into()));
+        }
+        if self.max_entries == 0 {
+            return Err(ValidationError::AuthCache("Maximum entries is zero".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl AuthLockoutSettings {
+    pub fn builder() -> AuthLockoutSettingsBuilder {
+        AuthLockoutSettingsBuilder::new()
+    }
+
+    pub fn default_max_failures() -> u32 {
+        5
+    }
+
+    pub fn default_lockout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn default_max_lockout() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    pub fn default_reset_after() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    pub fn default_lock_usernames() -> bool {
+        true
+    }
+
+    pub fn default_max_entries() -> usize {
+        100000
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.max_failures == 0 {
+            return Err(ValidationError::AuthLockout(
+                "Maximum failures is zero".into(),
+            ));
+        }
+        if self.lockout.is_zero() {
+            return Err(ValidationError::AuthLockout(
+                "Lockout period is zero".into(),
+            ));
+        }
+        if self.max_lockout < self.lockout {
+            return Err(ValidationError::AuthLockout(
+                "Maximum lockout period is shorter than the first one".into(),
+            ));
+        }
+        if self.max_entries == 0 {
+            return Err(ValidationError::AuthLockout(
+                "Maximum entries is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl AuditLogSettings {
+    pub fn builder() -> AuditLogSettingsBuilder {
+        AuditLogSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        match (&self.path, &self.address) {
+            (Some(x), None) if x.is_empty() => {
+                return Err(ValidationError::AuditLog("Path is empty".into()))
+            }
+            (Some(_), None) => (),
+            (None, Some(_)) if self.rotation.is_some() => {
+                return Err(ValidationError::AuditLog(
+                    "Rotation is set without path".into(),
+                ))
+            }
+            (None, Some(_)) => (),
+            _ => {
+                return Err(ValidationError::AuditLog(
+                    "Exactly one of path and address must be set".into(),
+                ))
+            }
+        }
+        self.rotation
+            .as_ref()
+            .map_or(Ok(()), LogRotationSettings::validate)
+    }
+}
+
+impl RevocationSettings {
+    pub fn builder() -> RevocationSettingsBuilder {
+        RevocationSettingsBuilder::new()
+    }
+
+    pub fn default_reload_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.path.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::Revocation("Path is empty".into()));
+        }
+        if self.reload_interval.is_zero() {
+            return Err(ValidationError::Revocation(
+                "Reload interval must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ReconnectTokenSettings {
+    pub fn builder() -> ReconnectTokenSettingsBuilder {
+        ReconnectTokenSettingsBuilder::new()
+    }
+
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    pub fn default_max_redeemed() -> usize {
+        100000
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.ttl.is_zero() {
+            return Err(ValidationError::ReconnectTokens(
+                "TTL must be positive".into(),
+            ));
+        }
+        if self.secret.as_ref().is_some_and(|x| x.len() < 16) {
+            return Err(ValidationError::ReconnectTokens(
+                "Secret must be at least 16 bytes long".into(),
+            ));
+        }
+        if self.max_redeemed == 0 {
+            return Err(ValidationError::ReconnectTokens(
+                "Maximum number of redeemed tokens must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl GuestSettings {
+    pub fn builder() -> GuestSettingsBuilder {
+        GuestSettingsBuilder::new()
+    }
+
+    pub fn default_max_session_duration() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.max_session_duration.is_zero() {
+            return Err(ValidationError::Guest(
+                "Maximum session duration must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl DigestAuthSettings {
+    pub fn builder() -> DigestAuthSettingsBuilder {
+        DigestAuthSettingsBuilder::new()
+    }
+
+    pub fn default_realm() -> String {
+        "Authorization Required".to_string()
+    }
+
+    pub fn default_nonce_lifetime() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.realm.is_empty() || self.realm.contains(['"', '\\']) {
+            return Err(ValidationError::DigestAuth(
+                "Realm must be non-empty and free of quotes and backslashes".into(),
+            ));
+        }
+        if self.nonce_lifetime.is_zero() {
+            return Err(ValidationError::DigestAuth(
+                "Nonce lifetime must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl LogRotationSettings {
+    pub fn builder() -> LogRotationSettingsBuilder {
+        LogRotationSettingsBuilder::new()
+    }
+
+    pub fn default_max_files() -> usize {
+        7
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.period.is_none() && self.max_size_bytes.is_none() {
+            return Err(ValidationError::LogRotation(
+                "At least one of period and max_size_bytes must be set".into(),
+            ));
+        }
+        if self.max_size_bytes == Some(0) {
+            return Err(ValidationError::LogRotation(
+                "Maximum size must be positive".into(),
+            ));
+        }
+        if self.max_files == 0 {
+            return Err(ValidationError::LogRotation(
+                "Maximum number of files must be positive".into(),
+            ));
+        }
+        if self.on_rotate.as_ref().is_some_and(String::is_empty) {
+            return Err(ValidationError::LogRotation(
+                "Rotation program is empty".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl DuplicateSessionSettings {
+    pub fn builder() -> DuplicateSessionSettingsBuilder {
+        DuplicateSessionSettingsBuilder::new()
+    }
+
+    pub fn default_max_sessions() -> usize {
+        1
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.max_sessions == 0 {
+            return Err(ValidationError::DuplicateSessions(
+                "Maximum sessions is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl BandwidthEstimationSettings {
+    pub fn builder() -> BandwidthEstimationSettingsBuilder {
+        BandwidthEstimationSettingsBuilder::new()
+    }
+
+    pub fn default_sample_interval() -> Duration {
+        Duration::from_millis(250)
+    }
+
+    pub fn default_window() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_pacing_gain() -> f64 {
+        0.9
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.sample_interval.is_zero() {
+            return Err(ValidationError::BandwidthEstimation(
+                "Sample interval is zero".into(),
+            ));
+        }
+        if self.window < self.sample_interval {
+            return Err(ValidationError::BandwidthEstimation(
+                "Window is shorter than sample interval".into(),
+            ));
+        }
+        if !(self.pacing_gain > 0.0 && self.pacing_gain <= 1.0) {
+            return Err(ValidationError::BandwidthEstimation(format!(
+                "Pacing gain must be in (0, 1]: {}",
+                self.pacing_gain
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl AuthChainSettings {
+    pub fn builder(backends: Vec<AuthBackend>) -> AuthChainSettingsBuilder {
+        AuthChainSettingsBuilder::new(backends)
+    }
+
+    pub fn backends(&self) -> &[AuthBackend] {
+        &self.backends
+    }
+
+    pub fn mode(&self) -> AuthChainMode {
+        self.mode
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.backends.is_empty() {
+            return Err(ValidationError::AuthChain("No backends".into()));
+        }
+        let mut seen = HashSet::new();
+        if let Some(x) = self.backends.iter().find(|x| !seen.insert(**x)) {
+            return Err(ValidationError::AuthChain(format!(
+                "Backend {:?} is listed more than once",
+                x
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientAuthSettings {
+    pub fn builder<P: ToString>(ca_bundle_path: P) -> ClientAuthSettingsBuilder {
+        ClientAuthSettingsBuilder::new(ca_bundle_path.to_string())
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.ca_bundle_path.is_empty() {
+            return Err(ValidationError::ClientAuth(
+                "CA bundle path is not set".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl SelfSignedSettings {
+    pub fn builder() -> SelfSignedSettingsBuilder {
+        SelfSignedSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.subject_alt_names.iter().any(String::is_empty) {
+            return Err(ValidationError::SelfSigned(
+                "Empty subject alternative name".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl StatsdSettings {
+    pub fn builder(address: SocketAddr) -> StatsdSettingsBuilder {
+        StatsdSettingsBuilder::new(address)
+    }
+
+    pub fn default_prefix() -> String {
+        "trusttunnel".into()
+    }
+
+    pub fn default_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.port() == 0 {
+            return Err(ValidationError::Statsd("Server port is not set".into()));
+        }
+        if self.interval.is_zero() {
+            return Err(ValidationError::Statsd("Interval is zero".into()));
+        }
+        if self.prefix.contains([':', '|', '@']) {
+            return Err(ValidationError::Statsd(format!(
+                "Invalid prefix: {}",
+                self.prefix
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ExitPolicySettings {
+    pub fn builder() -> ExitPolicySettingsBuilder {
+        ExitPolicySettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        // The values are embedded into the JSON document as is
+        let is_valid = |x: &String| {
+            !x.is_empty() && !x.contains(|c: char| c == '"' || c == '\\' || c.is_control())
+        };
+        if let Some(x) = self.abuse_contact.as_ref().filter(|x| !is_valid(x)) {
+            return Err(ValidationError::ExitPolicy(format!(
+                "Invalid abuse contact: {:?}",
+                x
+            )));
+        }
+        if let Some(x) = self.blocked_categories.iter().find(|x| !is_valid(x)) {
+            return Err(ValidationError::ExitPolicy(format!(
+                "Invalid blocked category: {:?}",
+                x
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ImpairmentSettings {
+    /// The retransmission timeout a lost chunk of a TCP stream is held for,
+    /// which is the minimum one of the Linux TCP stack
+    pub const RETRANSMISSION_DELAY: Duration = Duration::from_millis(200);
+
+    /// The listener protocols which may be impaired
+    const PROTOCOLS: [&'static str; 3] = ["http1", "http2", "http3"];
+
+    pub fn builder() -> ImpairmentSettingsBuilder {
+        ImpairmentSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(x) = self
+            .protocols
+            .iter()
+            .find(|x| !Self::PROTOCOLS.contains(&x.as_str()))
+        {
+            return Err(ValidationError::Impairments(format!(
+                "Unknown protocol: {}",
+                x
+            )));
+        }
+        if !(0.0..=100.0).contains(&self.loss_percent) {
+            return Err(ValidationError::Impairments(format!(
+                "Loss percentage is out of range: {}",
+                self.loss_percent
+            )));
+        }
+        if self.max_bytes_per_sec == Some(0) {
+            return Err(ValidationError::Impairments(
+                "Maximum transfer rate must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl ProxyProtocolSettings {
+    pub fn builder() -> ProxyProtocolSettingsBuilder {
+        ProxyProtocolSettingsBuilder::new()
+    }
+
+    pub fn default_header_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.trusted_sources.is_empty() {
+            return Err(ValidationError::ProxyProtocol("No trusted sources".into()));
+        }
+        for x in &self.trusted_sources {
+            x.parse::<ipnet::IpNet>().map_err(|e| {
+                ValidationError::ProxyProtocol(format!("Invalid trusted source {}: {}", x, e))
+            })?;
+        }
+        if self.header_timeout.is_zero() {
+            return Err(ValidationError::ProxyProtocol(
+                "Header timeout is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl GrpcAdminSettings {
+    pub fn builder() -> GrpcAdminSettingsBuilder {
+        GrpcAdminSettingsBuilder::new()
+    }
+
+    pub fn default_listen_address() -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, 1988).into()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.listen_address.port() == 0 {
+            return Err(ValidationError::GrpcAdmin("Listen port is not set".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl StateStoreSettings {
+    pub fn builder<P: ToString>(path: P) -> StateStoreSettingsBuilder {
+        StateStoreSettingsBuilder::new(path.to_string())
+    }
+
+    pub fn default_checkpoint_interval() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.path.is_empty() {
+            return Err(ValidationError::StateStore("Path is not set".into()));
+        }
+        if self.checkpoint_interval.is_zero() {
+            return Err(ValidationError::StateStore(
+                "Checkpoint interval must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl CertificateExpirySettings {
+    pub fn builder() -> CertificateExpirySettingsBuilder {
+        CertificateExpirySettingsBuilder::new()
+    }
+
+    pub fn default_warning_threshold() -> Duration {
+        Duration::from_secs(30 * 24 * 60 * 60)
+    }
+
+    pub fn default_check_interval() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.warning_threshold.is_zero() {
+            return Err(ValidationError::CertificateExpiry(
+                "Warning threshold must be positive".into(),
+            ));
+        }
+        if self.check_interval.is_zero() {
+            return Err(ValidationError::CertificateExpiry(
+                "Check interval must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl AffinitySettings {
+    pub fn builder<S1: ToString, S2: ToString>(
+        instance_id: S1,
+        secret: S2,
+    ) -> AffinitySettingsBuilder {
+        AffinitySettingsBuilder::new(instance_id.to_string(), secret.to_string())
+    }
+
+    pub fn default_header_name() -> String {
+        "x-trusttunnel-affinity".into()
+    }
+
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.instance_id.is_empty()
+            || !self
+                .instance_id
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+        {
+            return Err(ValidationError::Affinity(format!(
+                "Invalid instance ID: {}",
+                self.instance_id
+            )));
+        }
+        if self.secret.len() < 16 {
+            return Err(ValidationError::Affinity(
+                "Secret is shorter than 16 bytes".into(),
+            ));
+        }
+        if http::HeaderName::from_bytes(self.header_name.as_bytes()).is_err() {
+            return Err(ValidationError::Affinity(format!(
+                "Invalid header name: {}",
+                self.header_name
+            )));
+        }
+        if self.ttl.is_zero() {
+            return Err(ValidationError::Affinity("TTL is zero".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl PolicySettings {
+    pub fn builder() -> PolicySettingsBuilder {
+        PolicySettingsBuilder::new()
+    }
+
+    pub fn default_terms_version() -> String {
+        "1".into()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        // Both are sent in the response headers
+        for (name, x) in [("Message of the day", &self.motd), ("Terms", &self.terms)] {
+            if x.as_ref()
+                .is_some_and(|x| http::HeaderValue::from_str(x).is_err())
+            {
+                return Err(ValidationError::Policy(format!(
+                    "{} is not a valid header value",
+                    name
+                )));
+            }
+        }
+        if self.terms_version.is_empty()
+            || http::HeaderValue::from_str(&self.terms_version).is_err()
+        {
+            return Err(ValidationError::Policy(format!(
+                "Invalid terms version: {}",
+                self.terms_version
+            )));
+        }
+        if self.expiry_warning_days == Some(0) {
+            return Err(ValidationError::Policy(
+                "Expiry warning days is zero".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl TimeoutSettings {
+    pub fn builder() -> TimeoutSettingsBuilder {
+        TimeoutSettingsBuilder::new()
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        for (name, x) in [
+            ("", &self.stages),
+            ("http1.", &self.http1),
+            ("http2.", &self.http2),
+            ("http3.", &self.http3),
+        ] {
+            for (stage, x) in [
+                ("tls_handshake", x.tls_handshake),
+                ("auth", x.auth),
+                ("upstream_connect", x.upstream_connect),
+                ("request_header", x.request_header),
+                ("idle", x.idle),
+                ("total", x.total),
+            ] {
+                if x.is_some_and(|x| x.is_zero()) {
+                    return Err(ValidationError::Timeouts(format!(
+                        "{}{} is zero",
+                        name, stage
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StageTimeoutSettings {
+    pub fn builder() -> StageTimeoutSettingsBuilder {
+        StageTimeoutSettingsBuilder::new()
+    }
+}
+
+impl ScheduleSettings {
+    pub fn builder() -> ScheduleSettingsBuilder {
+        ScheduleSettingsBuilder::new()
+    }
+
+    pub fn default_notice() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        for x in &self.windows {
+            x.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MaintenanceWindowSettings {
+    pub fn builder<S: ToString>(start: S, duration: Duration) -> MaintenanceWindowSettingsBuilder {
+        MaintenanceWindowSettingsBuilder::new(start.to_string(), duration)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(x) = self
+            .days
+            .iter()
+            .find(|x| !schedule::DAYS.contains(&x.as_str()))
+        {
+            return Err(ValidationError::Schedule(format!("Unknown day: {}", x)));
+        }
+        if schedule::parse_time_of_day(&self.start).is_none() {
+            return Err(ValidationError::Schedule(format!(
+                "Invalid start time: {}",
+                self.start
+            )));
+        }
+        if self.duration.is_zero() || self.duration > schedule::WEEK {
+            return Err(ValidationError::Schedule(format!(
+                "Duration is out of range: {:?}",
+                self.duration
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            address: MetricsSettings::default_listen_address(),
+            request_timeout: MetricsSettings::default_request_timeout(),
+            stats_history: MetricsSettings::default_stats_history(),
+            capacity_stats: false,
+            admin_token: None,
+        }
+    }
+}
+
+impl SettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Settings {
+                listen_address: Settings::default_listen_address(),
+                ipv6_available: Settings::default_ipv6_available(),
+                allow_private_network_connections:
+                    Settings::default_allow_private_network_connections(),
+                allow_metadata_endpoint_connections: false,
+                tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
+                client_listener_timeout: Settings::default_client_listener_timeout(),
+                connection_establishment_timeout:
+                    Settings::default_connection_establishment_timeout(),
+                tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
+                udp_connections_timeout: Settings::default_udp_connections_timeout(),
+                timeouts: Default::default(),
+                server_timing: false,
+                tcp_max_segment_size: None,
+                egress_addresses: Default::default(),
+                egress_port_blocks: None,
+                egress_connect_rate: None,
+                egress_uplinks: None,
+                forward_protocol: Default::default(),
+                listen_protocols: Default::default(),
+                clients: Default::default(),
+                ldap: None,
+                database: None,
+                redis: None,
+                jwt: None,
+                pam: None,
+                introspection: None,
+                auth_cache: None,
+                auth_chain: None,
+                auth_lockout: None,
+                audit_log: None,
+                revocation: None,
+                reconnect_tokens: None,
+                guest: None,
+                digest_auth: None,
+                client_auth: None,
+                reverse_proxy: None,
+                icmp: None,
+                metrics: Default::default(),
+                statsd: None,
+                rules_engine: Some(rules::RulesEngine::default_allow()),
+                speedtest_enable: Settings::default_speedtest_enable(),
+                tiers: Default::default(),
+                profiles: Default::default(),
+                max_connections_per_user: None,
+                max_streams_per_user: None,
+                duplicate_sessions: None,
+                bandwidth_estimation: None,
+                state_store: None,
+                status_file: None,
+                certificate_expiry: None,
+                affinity: None,
+                policy: None,
+                schedule: None,
+                http_redirect: None,
+                triage: None,
+                accept_rate: None,
+                proxy_protocol: None,
+                grpc_admin: None,
+                exit_policy: None,
+                interception: None,
+                impairments: Default::default(),
+                built: true,
+            },
+        }
+    }
+
+    /// Finalize [`Settings`]
+    pub fn build(self) -> Result<Settings, ValidationError> {
+        self.settings.validate()?;
+
+        Ok(self.settings)
+    }
+
+    /// Set the address to listen on
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.listen_address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the reverse proxy settings.
+    /// With this one set up the endpoint does TLS termination on such connections and
+    /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
+    /// into original HTTP/x towards the client. Like this:
+    ///
+    /// ```(client) TLS(HTTP/x) <--(endpoint)--> (server) HTTP/1.1```
+    ///
+    /// The translated HTTP/1.1 requests have the custom header `X-Original-Protocol`
+    /// appended. Its value is either `HTTP1`, `HTTP2`, or `HTTP3`.
+    /// TLS hosts for the reverse proxy channel are configured through [`TlsHostsSettings`].
+    pub fn reverse_proxy(mut self, settings: ReverseProxySettings) -> Self {
+        self.settings.reverse_proxy = Some(settings);
+        self
+    }
+
+    /// Set IPv6 availability
+    pub fn ipv6_available(mut self, v: bool) -> Self {
+        self.settings.ipv6_available = v;
+        self
+    }
+
+    /// Allow/disallow connections to private network of the endpoint
+    pub fn allow_private_network_connections(mut self, v: bool) -> Self {
+        self.settings.allow_private_network_connections = v;
+        self
+    }
+
+    /// Allow/disallow connections to the cloud instance metadata services
+    pub fn allow_metadata_endpoint_connections(mut self, v: bool) -> Self {
+        self.settings.allow_metadata_endpoint_connections = v;
+        self
+    }
+
+    /// Set timeout of TLS handshake
+    pub fn tls_handshake_timeout(mut self, v: Duration) -> Self {
+        self.settings.tls_handshake_timeout = v;
+        self
+    }
+
+    /// Set timeout of client listener
+    pub fn client_listener_timeout(mut self, v: Duration) -> Self {
+        self.settings.client_listener_timeout = v;
+        self
+    }
+
+    /// Set timeout of outgoing connection establishment.
+    /// For example, it is related to client's connection requests.
+    pub fn connection_establishment_timeout(mut self, v: Duration) -> Self {
+        self.settings.connection_establishment_timeout = v;
+        self
+    }
+
+    /// Set timeout of tunneled TCP connections
+    pub fn tcp_connections_timeout(mut self, v: Duration) -> Self {
+        self.settings.tcp_connections_timeout = v;
+        self
+    }
+
+    /// Set timeout of tunneled UDP "connections"
+    pub fn udp_connections_timeout(mut self, v: Duration) -> Self {
+        self.settings.udp_connections_timeout = v;
+        self
+    }
+
+    /// Set the timeouts of the client connection stages
+    pub fn timeouts(mut self, x: TimeoutSettings) -> Self {
+        self.settings.timeouts = x;
+        self
+    }
+
+    /// Set whether the tunnel establishment and the ping responses carry
+    /// the `Server-Timing` header
+    pub fn server_timing(mut self, v: bool) -> Self {
+        self.settings.server_timing = v;
+        self
+    }
+
+    /// Set the maximum segment size of the outgoing TCP connections
+    pub fn tcp_max_segment_size(mut self, v: u16) -> Self {
+        self.settings.tcp_max_segment_size = Some(v);
+        self
+    }
+
+    /// Set the pool of the source addresses of the outgoing connections
+    pub fn egress_addresses(mut self, v: Vec<IpAddr>) -> Self {
+        self.settings.egress_addresses = v;
+        self
+    }
+
+    /// Set the source port partitioning settings
+    pub fn egress_port_blocks(mut self, v: PortBlockSettings) -> Self {
+        self.settings.egress_port_blocks = Some(v);
+        self
+    }
+
+    /// Set the pacing of the outgoing TCP connections per destination address
+    pub fn egress_connect_rate(mut self, v: ConnectRateSettings) -> Self {
+        self.settings.egress_connect_rate = Some(v);
+        self
+    }
+
+    /// Set the egress uplinks the routing rules may race the connections across
+    pub fn egress_uplinks(mut self, v: EgressUplinksSettings) -> Self {
+        self.settings.egress_uplinks = Some(v);
+        self
+    }
+
+    /// Set the forwarder codec settings
+    pub fn forwarder_settings(mut self, settings: ForwardProtocolSettings) -> Self {
+        self.settings.forward_protocol = settings;
+        self
+    }
+
+    /// Set the listener codec settings
+    pub fn listen_protocols(mut self, settings: ListenProtocolSettings) -> Self {
+        self.settings.listen_protocols = settings;
+        self
+    }
+
+    /// Set the client authenticator
+    pub fn clients(mut self, x: Vec<Client>) -> Self {
+        self.settings.clients.clients = x;
+        self
+    }
+
+    /// Set the ICMP forwarder settings
+    pub fn icmp(mut self, x: IcmpSettings) -> Self {
+        self.settings.icmp = Some(x);
+        self
+    }
+
+    /// Set the metrics request listener settings
+    pub fn metrics(mut self, x: MetricsSettings) -> Self {
+        self.settings.metrics = Some(x);
+        self
+    }
+
+    /// Set the statsd exporter settings
+    pub fn statsd(mut self, x: StatsdSettings) -> Self {
+        self.settings.statsd = Some(x);
+        self
+    }
+
+    /// Set the LDAP server the clients are authenticated against
+    pub fn ldap(mut self, x: LdapSettings) -> Self {
+        self.settings.ldap = Some(x);
+        self
+    }
+
+    /// Set the database the client credentials are looked up in
+    pub fn database(mut self, x: DatabaseSettings) -> Self {
+        self.settings.database = Some(x);
+        self
+    }
+
+    /// Set the Redis server the client credentials are looked up in
+    pub fn redis(mut self, x: RedisSettings) -> Self {
+        self.settings.redis = Some(x);
+        self
+    }
+
+    /// Set the keys the JSON Web Tokens of the clients are verified with
+    pub fn jwt(mut self, x: JwtSettings) -> Self {
+        self.settings.jwt = Some(x);
+        self
+    }
+
+    /// Set the PAM stack the clients are authenticated with
+    pub fn pam(mut self, x: PamSettings) -> Self {
+        self.settings.pam = Some(x);
+        self
+    }
+
+    /// Set the introspection endpoint the access tokens of the clients are checked against
+    pub fn introspection(mut self, x: IntrospectionSettings) -> Self {
+        self.settings.introspection = Some(x);
+        self
+    }
+
+    /// Set the cache of the authentication results
+    pub fn auth_cache(mut self, x: AuthCacheSettings) -> Self {
+        self.settings.auth_cache = Some(x);
+        self
+    }
+
+    /// Set the order the configured authenticators are asked in
+    pub fn auth_chain(mut self, x: AuthChainSettings) -> Self {
+        self.settings.auth_chain = Some(x);
+        self
+    }
+
+    /// Set the lockout of the clients failing to authenticate
+    pub fn auth_lockout(mut self, x: AuthLockoutSettings) -> Self {
+        self.settings.auth_lockout = Some(x);
+        self
+    }
+
+    /// Set the audit log of the authentication attempts
+    pub fn audit_log(mut self, x: AuditLogSettings) -> Self {
+        self.settings.audit_log = Some(x);
+        self
+    }
+
+    /// Set the revocation of the client credentials
+    pub fn revocation(mut self, x: RevocationSettings) -> Self {
+        self.settings.revocation = Some(x);
+        self
+    }
+
+    /// Set the tokens letting a client reconnect without presenting its credentials again
+    pub fn reconnect_tokens(mut self, x: ReconnectTokenSettings) -> Self {
+        self.settings.reconnect_tokens = Some(x);
+        self
+    }
+
+    /// Set the guest access letting the clients without credentials through
+    pub fn guest(mut self, x: GuestSettings) -> Self {
+        self.settings.guest = Some(x);
+        self
+    }
+
+    /// Set the digest access authentication of the tunnel requests
+    pub fn digest_auth(mut self, x: DigestAuthSettings) -> Self {
+        self.settings.digest_auth = Some(x);
+        self
+    }
+
+    /// Set the TLS client certificate authentication settings
+    pub fn client_auth(mut self, x: ClientAuthSettings) -> Self {
+        self.settings.client_auth = Some(x);
+        self
+    }
+
+    /// Set the rules engine for connection filtering
+    pub fn rules_engine(mut self, x: rules::RulesEngine) -> Self {
+        self.settings.rules_engine = Some(x);
+        self
+    }
+
+    /// Set whether speedtest is available
+    pub fn speedtest_enable(mut self, x: bool) -> Self {
+        self.settings.speedtest_enable = x;
+        self
+    }
+
+    /// Add a quality of service tier
+    pub fn tier<S: ToString>(mut self, name: S, x: TierSettings) -> Self {
+        self.settings.tiers.insert(name.to_string(), x);
+        self
+    }
+
+    /// Add a destination profile
+    pub fn profile<S: ToString>(mut self, name: S, x: ProfileSettings) -> Self {
+        self.settings.profiles.insert(name.to_string(), x);
+        self
+    }
+
+    /// Set the maximum number of the concurrent tunneled connections of a client
+    pub fn max_connections_per_user(mut self, x: usize) -> Self {
+        self.settings.max_connections_per_user = Some(x);
+        self
+    }
+
+    /// Set the maximum number of the concurrent tunnel streams of a client
+    /// across all its sessions
+    pub fn max_streams_per_user(mut self, x: usize) -> Self {
+        self.settings.max_streams_per_user = Some(x);
+        self
+    }
+
+    /// Set what happens to the duplicate sessions of an identity
+    pub fn duplicate_sessions(mut self, x: DuplicateSessionSettings) -> Self {
+        self.settings.duplicate_sessions = Some(x);
+        self
+    }
+
+    /// Set the session bandwidth estimation settings
+    pub fn bandwidth_estimation(mut self, x: BandwidthEstimationSettings) -> Self {
+        self.settings.bandwidth_estimation = Some(x);
+        self
+    }
+
+    /// Set the persistent state store settings
+    pub fn state_store(mut self, x: StateStoreSettings) -> Self {
+        self.settings.state_store = Some(x);
+        self
+    }
+
+    /// Set the path of the status file
+    pub fn status_file<S: ToString>(mut self, x: S) -> Self {
+        self.settings.status_file = Some(x.to_string());
+        self
+    }
+
+    /// Set the monitoring of the expiration of the loaded certificates
+    pub fn certificate_expiry(mut self, x: CertificateExpirySettings) -> Self {
+        self.settings.certificate_expiry = Some(x);
+        self
+    }
+
+    /// Set the session affinity settings
+    pub fn affinity(mut self, x: AffinitySettings) -> Self {
+        self.settings.affinity = Some(x);
+        self
+    }
+
+    /// Set the message of the day and the terms of use settings
+    pub fn policy(mut self, x: PolicySettings) -> Self {
+        self.settings.policy = Some(x);
+        self
+    }
+
+    /// Set the scheduled maintenance settings
+    pub fn schedule(mut self, x: ScheduleSettings) -> Self {
+        self.settings.schedule = Some(x);
+        self
+    }
+
+    /// Set the plain HTTP redirecting listener settings
+    pub fn http_redirect(mut self, x: HttpRedirectSettings) -> Self {
+        self.settings.http_redirect = Some(x);
+        self
+    }
+
+    /// Set the triage of the accepted TCP connections
+    pub fn triage(mut self, x: TriageSettings) -> Self {
+        self.settings.triage = Some(x);
+        self
+    }
+
+    /// Set the limiting of the rate of the new client connections
+    pub fn accept_rate(mut self, x: AcceptRateSettings) -> Self {
+        self.settings.accept_rate = Some(x);
+        self
+    }
+
+    /// Set the acceptance of the PROXY protocol header on the TCP listener
+    pub fn proxy_protocol(mut self, x: ProxyProtocolSettings) -> Self {
+        self.settings.proxy_protocol = Some(x);
+        self
+    }
+
+    /// Set the gRPC administration service settings
+    pub fn grpc_admin(mut self, x: GrpcAdminSettings) -> Self {
+        self.settings.grpc_admin = Some(x);
+        self
+    }
+
+    /// Set the published exit policy settings
+    pub fn exit_policy(mut self, x: ExitPolicySettings) -> Self {
+        self.settings.exit_policy = Some(x);
+        self
+    }
+
+    /// Set the TLS interception settings
+    pub fn interception(mut self, x: InterceptionSettings) -> Self {
+        self.settings.interception = Some(x);
+        self
+    }
+
+    /// Set the synthetic network impairments
+    pub fn impairments(mut self, x: Vec<ImpairmentSettings>) -> Self {
+        self.settings.impairments = x;
+        self
+    }
+}
+
+impl TlsSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: TlsHostsSettings {
+                main_hosts: Default::default(),
+                ping_hosts: Default::default(),
+                speedtest_hosts: Default::default(),
+                reverse_proxy_hosts: Default::default(),
+                self_signed: None,
+                built: true,
+            },
+        }
+    }
+
+    /// Finalize [`TlsHostsSettings`]
+    pub fn build(mut self) -> Result<TlsHostsSettings, ValidationError> {
+        self.settings.generate_self_signed()?;
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+
+    /// Set the main TLS hosts.
+    /// Used for traffic tunneling and service requests handling.
+    pub fn main_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
+        self.settings.main_hosts = hosts;
+        self
+    }
+
+    /// Set the TLS hosts for HTTPS pinging.
+    /// With this one set up the endpoint responds with `200 OK` to HTTPS `GET` requests
+    /// to the specified domains.
+    pub fn ping_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
+        self.settings.ping_hosts = hosts;
+        self
+    }
+
+    /// Set the TLS hosts for speed testing.
+    /// With this one set up the endpoint accepts connections to the specified hosts and
+    /// handles HTTP requests in the following way:
+    ///     * `GET` requests with `/Nmb.bin` path (where `N` is 1 to 100, e.g. `/100mb.bin`)
+    ///       are considered as download speedtest transferring `N` megabytes to a client
+    ///     * `POST` requests with `/upload.html` path and `Content-Length: N`
+    ///       are considered as upload speedtest receiving `N` bytes from a client,
+    ///       where `N` is up to 120 * 1024 * 1024 bytes
+    pub fn speedtest_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
+        self.settings.speedtest_hosts = hosts;
+        self
+    }
+
+    /// The TLS hosts for the connections must be forwarded to the reverse proxy
+    /// (see [`self::Settings::reverse_proxy`]). Only makes sense if the reverse proxy
+    /// is set up, otherwise it is ignored.
+    pub fn reverse_proxy_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
+        self.settings.reverse_proxy_hosts = hosts;
+        self
+    }
+
+    /// Set the self-signed certificate generated for the hosts without
+    /// the certificate and key paths
+    pub fn self_signed(mut self, x: SelfSignedSettings) -> Self {
+        self.settings.self_signed = Some(x);
+        self
+    }
+}
+
+impl DirectForwarderSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the SOCKS5 proxy the outgoing TCP connections are made through
+    pub fn upstream_socks5(mut self, v: UpstreamSocks5Settings) -> Self {
+        self.settings.upstream_socks5 = Some(v);
+        self
+    }
+
+    /// Set the HTTP proxy the outgoing TCP connections are tunneled through
+    pub fn upstream_http(mut self, v: UpstreamHttpProxySettings) -> Self {
+        self.settings.upstream_http = Some(v);
+        self
+    }
+
+    /// Finalize [`DirectForwarderSettings`]
+    pub fn build(self) -> Result<DirectForwarderSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl UpstreamSocks5SettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: UpstreamSocks5Settings {
+                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                username: None,
+                password: None,
+            },
+        }
+    }
+
+    /// Set the address of the proxy
+    pub fn address(mut self, v: SocketAddr) -> Self {
+        self.settings.address = v;
+        self
+    }
+
+    /// Set the credentials to authenticate to the proxy with
+    pub fn credentials<U: ToString, P: ToString>(mut self, username: U, password: P) -> Self {
+        self.settings.username = Some(username.to_string());
+        self.settings.password = Some(password.to_string());
+        self
+    }
+
+    /// Finalize [`UpstreamSocks5Settings`]
+    pub fn build(self) -> Result<UpstreamSocks5Settings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl UpstreamHttpProxySettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: UpstreamHttpProxySettings {
+                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                tls: None,
+                username: None,
+                password: None,
+            },
+        }
+    }
+
+    /// Set the address of the proxy
+    pub fn address(mut self, v: SocketAddr) -> Self {
+        self.settings.address = v;
+        self
+    }
+
+    /// Set the TLS settings of the proxy connections
+    pub fn tls(mut self, v: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(v);
+        self
+    }
+
+    /// Set the credentials to authenticate to the proxy with
+    pub fn credentials<U: ToString, P: ToString>(mut self, username: U, password: P) -> Self {
+        self.settings.username = Some(username.to_string());
+        self.settings.password = Some(password.to_string());
+        self
+    }
+
+    /// Finalize [`UpstreamHttpProxySettings`]
+    pub fn build(self) -> Result<UpstreamHttpProxySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl Socks5ForwarderSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Socks5ForwarderSettings {
+                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                extended_auth: false,
+                tls: None,
+                fallback_addresses: vec![],
+                health_check: None,
+            },
+        }
+    }
+
+    /// Finalize [`Socks5ForwarderSettings`]
+    pub fn build(self) -> Socks5BuilderResult<Socks5ForwarderSettings> {
+        if self.settings.address.ip().is_unspecified() {
+            return Err(Socks5Error::AddressNotSet);
+        }
+
+        Ok(self.settings)
+    }
+
+    /// Set the SOCKS proxy address
+    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
+        self.settings.address = v
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Enable/disable extended authentication.
+    /// See README for details.
+    pub fn extended_auth(mut self, v: bool) -> Self {
+        self.settings.extended_auth = v;
+        self
+    }
+
+    /// Set the TLS settings of the proxy connections
+    pub fn tls(mut self, v: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(v);
+        self
+    }
+
+    /// Set the proxies used while the main one is down
+    pub fn fallback_addresses(mut self, v: Vec<SocketAddr>) -> Self {
+        self.settings.fallback_addresses = v;
+        self
+    }
+
+    /// Set the proxy probing settings
+    pub fn health_check(mut self, v: HopHealthCheckSettings) -> Self {
+        self.settings.health_check = Some(v);
+        self
+    }
+}
+
+impl Http1SettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Http1Settings {
+                upload_buffer_size: Http1Settings::default_upload_buffer_size(),
+                fast_connect_ack: false,
+                strict_mode: None,
+                websocket: None,
+            },
+        }
+    }
+
+    /// Finalize [`Http1Settings`]
+    pub fn build(self) -> Http1Settings {
+        self.settings
+    }
+
+    /// Set whether a CONNECT request is responded before the peer connection is established
+    pub fn fast_connect_ack(mut self, v: bool) -> Self {
+        self.settings.fast_connect_ack = v;
+        self
+    }
+
+    /// Set the strictness policy of the listener
+    pub fn strict_mode(mut self, v: StrictModeSettings) -> Self {
+        self.settings.strict_mode = Some(v);
+        self
+    }
+
+    /// Set the tunnels carried over the WebSocket connections to be accepted
+    pub fn websocket(mut self, v: WebSocketSettings) -> Self {
+        self.settings.websocket = Some(v);
+        self
+    }
+}
+
+impl Http2SettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Http2Settings {
+                initial_connection_window_size:
+                    Http2Settings::default_initial_connection_window_size(),
+                initial_stream_window_size: Http2Settings::default_initial_stream_window_size(),
+                max_concurrent_streams: Http2Settings::default_max_concurrent_streams(),
+                max_frame_size: Http2Settings::default_max_frame_size(),
+                header_table_size: Http2Settings::default_header_table_size(),
+                max_header_field_size: Http2Settings::default_max_header_field_size(),
+                max_uri_length: Http2Settings::default_max_uri_length(),
+                fast_connect_ack: false,
+                strict_mode: None,
+                grpc: None,
+            },
+        }
+    }
+
+    /// Finalize [`Http2Settings`]
+    pub fn build(self) -> Http2Settings {
+        self.settings
+    }
+
+    /// Set the initial window size (in octets) for connection-level flow control for received data
+    pub fn initial_connection_window_size(mut self, v: u32) -> Self {
+        self.settings.initial_connection_window_size = v;
+        self
+    }
+
+    /// Set the initial window size (in octets) for stream-level flow control for received data
+    pub fn initial_stream_window_size(mut self, v: u32) -> Self {
+        self.settings.initial_stream_window_size = v;
+        self
+    }
+
+    /// Set the maximum number of concurrent streams
+    pub fn max_concurrent_streams(mut self, v: u32) -> Self {
+        self.settings.max_concurrent_streams = v;
+        self
+    }
+
+    /// Set the size (in octets) of the largest HTTP/2 frame payload that we are able to accept
+    pub fn max_frame_size(mut self, v: u32) -> Self {
+        self.settings.max_frame_size = v;
+        self
+    }
+
+    /// Set the maximum size of the header list of a request
+    pub fn header_table_size(mut self, v: u32) -> Self {
+        self.settings.header_table_size = v;
+        self
+    }
+
+    /// Set the maximum size of a header field of a request
+    pub fn max_header_field_size(mut self, v: usize) -> Self {
+        self.settings.max_header_field_size = v;
+        self
+    }
+
+    /// Set the maximum length of the URI of a request
+    pub fn max_uri_length(mut self, v: usize) -> Self {
+        self.settings.max_uri_length = v;
+        self
+    }
+
+    /// Set whether a CONNECT request is responded before the peer connection is established
+    pub fn fast_connect_ack(mut self, v: bool) -> Self {
+        self.settings.fast_connect_ack = v;
+        self
+    }
+
+    /// Set the strictness policy of the listener
+    pub fn strict_mode(mut self, v: StrictModeSettings) -> Self {
+        self.settings.strict_mode = Some(v);
+        self
+    }
+
+    /// Set the tunnels carried over the gRPC streaming calls to be accepted
+    pub fn grpc(mut self, v: GrpcTransportSettings) -> Self {
+        self.settings.grpc = Some(v);
+        self
+    }
+}
+
+impl QuicSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: QuicSettings {
+                recv_udp_payload_size: QuicSettings::default_recv_udp_payload_size(),
+                send_udp_payload_size: QuicSettings::default_send_udp_payload_size(),
+                initial_max_data: QuicSettings::default_initial_max_data(),
+                initial_max_stream_data_bidi_local:
+                    QuicSettings::default_initial_max_stream_data_bidi_local(),
+                initial_max_stream_data_bidi_remote:
+                    QuicSettings::default_initial_max_stream_data_bidi_remote(),
+                initial_max_stream_data_uni: QuicSettings::default_initial_max_stream_data_uni(),
+                initial_max_streams_bidi: QuicSettings::default_initial_max_streams_bidi(),
+                initial_max_streams_uni: QuicSettings::default_initial_max_streams_uni(),
+                max_connection_window: QuicSettings::default_max_connection_window(),
+                max_stream_window: QuicSettings::default_max_stream_window(),
+                disable_active_migration: QuicSettings::default_disable_active_migration(),
+                enable_early_data: QuicSettings::default_enable_early_data(),
+                discover_path_mtu: false,
+                enable_pacing: false,
+                max_pacing_rate: None,
+                congestion_control: Default::default(),
+                congestion_control_experiment: None,
+                message_queue_capacity: QuicSettings::default_message_queue_capacity(),
+                max_header_list_size: QuicSettings::default_max_header_list_size(),
+                max_header_field_size: QuicSettings::default_max_header_field_size(),
+                max_uri_length: QuicSettings::default_max_uri_length(),
+                fast_connect_ack: false,
+                strict_mode: None,
+                connect_udp: false,
+                webtransport: false,
+                enable_datagrams: false,
+                datagram_queue_length: QuicSettings::default_datagram_queue_length(),
+            },
+        }
+    }
+
+    /// Finalize [`QuicSettings`]
+    pub fn build(self) -> QuicSettings {
+        self.settings
+    }
+
+    /// Set the `max_udp_payload_size transport` parameter
+    pub fn recv_udp_payload_size(mut self, v: usize) -> Self {
+        self.settings.recv_udp_payload_size = v;
+        self
+    }
+
+    /// Set the maximum outgoing UDP payload size
+    pub fn send_udp_payload_size(mut self, v: usize) -> Self {
+        self.settings.send_udp_payload_size = v;
+        self
+    }
+
+    /// Set the `initial_max_data` transport parameter
+    pub fn initial_max_data(mut self, v: u64) -> Self {
+        self.settings.initial_max_data = v;
+        self
+    }
+
+    /// Set the `initial_max_stream_data_bidi_local` transport parameter
+    pub fn max_stream_data_bidi_local(mut self, v: u64) -> Self {
+        self.settings.initial_max_stream_data_bidi_local = v;
+        self
+    }
+
+    /// Set the `initial_max_stream_data_bidi_remote` transport parameter
+    pub fn max_stream_data_bidi_remote(mut self, v: u64) -> Self {
+        self.settings.initial_max_stream_data_bidi_remote = v;
+        self
+    }
+
+    /// Set the `initial_max_stream_data_uni` transport parameter
+    pub fn max_stream_data_uni(mut self, v: u64) -> Self {
+        self.settings.initial_max_stream_data_uni = v;
+        self
+    }
+
+    /// Set the `initial_max_streams_bidi` transport parameter
+    pub fn max_streams_bidi(mut self, v: u64) -> Self {
+        self.settings.initial_max_streams_bidi = v;
+        self
+    }
+
+    /// Set the `initial_max_streams_uni` transport parameter
+    pub fn max_streams_uni(mut self, v: u64) -> Self {
+        self.settings.initial_max_streams_uni = v;
+        self
+    }
+
+    /// Set the maximum size of the connection window
+    pub fn max_connection_window(mut self, v: u64) -> Self {
+        self.settings.max_connection_window = v;
+        self
+    }
+
+    /// Set the maximum size of the stream window
+    pub fn max_stream_window(mut self, v: u64) -> Self {
+        self.settings.max_stream_window = v;
+        self
+    }
+
+    /// Set the `disable_active_migration` transport parameter
+    pub fn disable_active_migration(mut self, v: bool) -> Self {
+        self.settings.disable_active_migration = v;
+        self
+    }
+
+    /// Enable receiving early data
+    pub fn enable_early_data(mut self, v: bool) -> Self {
+        self.settings.enable_early_data = v;
+        self
+    }
+
+    /// Enable the path MTU discovery
+    pub fn discover_path_mtu(mut self, v: bool) -> Self {
+        self.settings.discover_path_mtu = v;
+        self
+    }
+
+    /// Enable the pacing of the sent packets
+    pub fn enable_pacing(mut self, v: bool) -> Self {
+        self.settings.enable_pacing = v;
+        self
+    }
+
+    /// Set the maximum pacing rate of a connection in bytes per second
+    pub fn max_pacing_rate(mut self, v: u64) -> Self {
+        self.settings.max_pacing_rate = Some(v);
+        self
+    }
+
+    /// Set the congestion control algorithm of the connections
+    pub fn congestion_control(mut self, v: CongestionControl) -> Self {
+        self.settings.congestion_control = v;
+        self
+    }
+
+    /// Run `algorithm` for `cohort_percentage` percent of the clients
+    pub fn congestion_control_experiment(
+        mut self,
+        algorithm: CongestionControl,
+        cohort_percentage: u8,
+    ) -> Self {
+        self.settings.congestion_control_experiment = Some(CongestionControlExperimentSettings {
+            algorithm,
+            cohort_percentage,
+        });
+        self
+    }
+
+    /// Set the capacity of the QUIC multiplexer message queue
+    pub fn message_queue_capacity(mut self, v: usize) -> Self {
+        self.settings.message_queue_capacity = v;
+        self
+    }
+
+    /// Set the maximum size of the header list of a request
+    pub fn max_header_list_size(mut self, v: u64) -> Self {
+        self.settings.max_header_list_size = v;
+        self
+    }
+
+    /// Set the maximum size of a header field of a request
+    pub fn max_header_field_size(mut self, v: usize) -> Self {
+        self.settings.max_header_field_size = v;
+        self
+    }
+
+    /// Set the maximum length of the URI of a request
+    pub fn max_uri_length(mut self, v: usize) -> Self {
+        self.settings.max_uri_length = v;
+        self
+    }
+
+    /// Set whether a CONNECT request is responded before the peer connection is established
+    pub fn fast_connect_ack(mut self, v: bool) -> Self {
+        self.settings.fast_connect_ack = v;
+        self
+    }
+
+    /// Set whether the CONNECT-UDP requests are accepted
+    pub fn connect_udp(mut self, v: bool) -> Self {
+        self.settings.connect_udp = v;
+        self
+    }
+
+    /// Set whether the WebTransport sessions are accepted
+    pub fn webtransport(mut self, v: bool) -> Self {
+        self.settings.webtransport = v;
+        self
+    }
+
+    /// Set whether the QUIC DATAGRAM frames are enabled
+    pub fn enable_datagrams(mut self, v: bool) -> Self {
+        self.settings.enable_datagrams = v;
+        self
+    }
+
+    /// Set the maximum number of the QUIC DATAGRAM frames queued per connection
+    pub fn datagram_queue_length(mut self, v: usize) -> Self {
+        self.settings.datagram_queue_length = v;
+        self
+    }
+
+    /// Set the strictness policy of the listener
+    pub fn strict_mode(mut self, v: StrictModeSettings) -> Self {
+        self.settings.strict_mode = Some(v);
+        self
+    }
+}
+
+impl StrictModeSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: StrictModeSettings {
+                reject_http10: StrictModeSettings::default_reject_http10(),
+                decoy_methods: StrictModeSettings::default_decoy_methods(),
+                require_authority_form: StrictModeSettings::default_require_authority_form(),
+            },
+        }
+    }
+
+    /// Set whether the HTTP/1.0 requests are refused
+    pub fn reject_http10(mut self, v: bool) -> Self {
+        self.settings.reject_http10 = v;
+        self
+    }
+
+    /// Set the methods other than CONNECT the decoy channels accept
+    pub fn decoy_methods(mut self, v: Vec<String>) -> Self {
+        self.settings.decoy_methods = v;
+        self
+    }
+
+    /// Set whether the tunnel requests are required to target `host:port`
+    pub fn require_authority_form(mut self, v: bool) -> Self {
+        self.settings.require_authority_form = v;
+        self
+    }
+
+    /// Finalize [`StrictModeSettings`]
+    pub fn build(self) -> Result<StrictModeSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl WebSocketSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: WebSocketSettings {
+                path: WebSocketSettings::default_path(),
+            },
+        }
+    }
+
+    /// Set the path of the upgrade requests
+    pub fn path(mut self, v: String) -> Self {
+        self.settings.path = v;
+        self
+    }
+
+    /// Finalize [`WebSocketSettings`]
+    pub fn build(self) -> Result<WebSocketSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl GrpcTransportSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: GrpcTransportSettings {
+                path: GrpcTransportSettings::default_path(),
+            },
+        }
+    }
+
+    /// Set the path of the calls
+    pub fn path(mut self, v: String) -> Self {
+        self.settings.path = v;
+        self
+    }
+
+    /// Finalize [`GrpcTransportSettings`]
+    pub fn build(self) -> Result<GrpcTransportSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ReverseProxySettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: ReverseProxySettings {
+                server_address: ReverseProxySettings::default_server_address(),
+                path_mask: Default::default(),
+                h3_backward_compatibility: false,
+                serve_non_tunnel_requests: false,
+                non_tunnel_path_prefixes: Default::default(),
+                response_headers: Default::default(),
+                cache: None,
+                static_files: None,
+                mirror: None,
+                maintenance: false,
+                error_pages: None,
+                tls: None,
+                proxy_protocol: false,
+            },
+        }
+    }
+
+    /// Finalize [`ReverseProxySettings`]
+    pub fn build(self) -> Result<ReverseProxySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+
+    /// Set the proxy server address
+    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
+        self.settings.server_address = v
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Connections to [the main hosts](TlsHostsSettings.main_hosts) with
+    /// paths starting with this mask are routed to the reverse proxy server.
+    /// MUST start with slash.
+    pub fn path_mask(mut self, v: String) -> Self {
+        self.settings.path_mask = v;
+        self
+    }
+
+    /// With this one set to `true` the endpoint overrides the HTTP method while
+    /// translating an HTTP3 request to HTTP1 in case the request has the `GET` method
+    /// and its path is `/` or matches [`ReverseProxySettings.path_mask`]
+    pub fn h3_backward_compatibility(mut self, v: bool) -> Self {
+        self.settings.h3_backward_compatibility = v;
+        self
+    }
+
+    /// Set a header on the responses for the TLS host. The `*` host matches all the hosts.
+    pub fn response_header<S: ToString>(mut self, host: S, name: S, value: S) -> Self {
+        self.settings
+            .response_headers
+            .entry(host.to_string())
+            .or_default()
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Route the requests to the main hosts which are not tunnel requests to the reverse proxy
+    pub fn serve_non_tunnel_requests(mut self, v: bool) -> Self {
+        self.settings.serve_non_tunnel_requests = v;
+        self
+    }
+
+    /// Add a path prefix of the requests to the main hosts routed to the reverse proxy
+    /// with [`Self::serve_non_tunnel_requests`]. MUST start with slash.
+    pub fn non_tunnel_path_prefix<S: ToString>(mut self, v: S) -> Self {
+        self.settings.non_tunnel_path_prefixes.push(v.to_string());
+        self
+    }
+
+    /// Set the response cache settings
+    pub fn cache(mut self, v: ResponseCacheSettings) -> Self {
+        self.settings.cache = Some(v);
+        self
+    }
+
+    /// Serve the files from a local directory instead of forwarding the requests
+    pub fn static_files(mut self, v: StaticFilesSettings) -> Self {
+        self.settings.static_files = Some(v);
+        self
+    }
+
+    /// Set the request mirroring settings
+    pub fn mirror(mut self, v: RequestMirrorSettings) -> Self {
+        self.settings.mirror = Some(v);
+        self
+    }
+
+    /// Set whether the reverse proxy starts in the maintenance mode
+    pub fn maintenance(mut self, v: bool) -> Self {
+        self.settings.maintenance = v;
+        self
+    }
+
+    /// Set the error pages settings
+    pub fn error_pages(mut self, v: ErrorPagesSettings) -> Self {
+        self.settings.error_pages = Some(v);
+        self
+    }
+
+    /// Set the TLS settings of the origin server connections
+    pub fn tls(mut self, v: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(v);
+        self
+    }
+
+    /// Set whether the origin server connections start with the PROXY protocol header
+    pub fn proxy_protocol(mut self, v: bool) -> Self {
+        self.settings.proxy_protocol = v;
+        self
+    }
+}
+
+impl StaticFilesSettingsBuilder {
+    fn new(root: String) -> Self {
+        Self {
+            settings: StaticFilesSettings {
+                root,
+                index_files: StaticFilesSettings::default_index_files(),
+            },
+        }
+    }
+
+    /// Set the files looked up in order when a directory is requested
+    pub fn index_files(mut self, v: Vec<String>) -> Self {
+        self.settings.index_files = v;
+        self
+    }
+
+    /// Finalize [`StaticFilesSettings`]
+    pub fn build(self) -> Result<StaticFilesSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl RequestMirrorSettingsBuilder {
+    fn new(server_address: SocketAddr) -> Self {
+        Self {
+            settings: RequestMirrorSettings {
+                server_address,
+                percentage: RequestMirrorSettings::default_percentage(),
+                max_request_size: RequestMirrorSettings::default_max_request_size(),
+            },
+        }
+    }
+
+    /// Set the share of the mirrored requests, in percent
+    pub fn percentage(mut self, v: u8) -> Self {
+        self.settings.percentage = v;
+        self
+    }
+
+    /// Set the size limit of the mirrored requests
+    pub fn max_request_size(mut self, v: usize) -> Self {
+        self.settings.max_request_size = v;
+        self
+    }
+
+    /// Finalize [`RequestMirrorSettings`]
+    pub fn build(self) -> Result<RequestMirrorSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl HopHealthCheckSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: HopHealthCheckSettings {
+                interval: HopHealthCheckSettings::default_interval(),
+                timeout: HopHealthCheckSettings::default_timeout(),
+            },
+        }
+    }
+
+    /// Set the period of probing every hop
+    pub fn interval(mut self, v: Duration) -> Self {
+        self.settings.interval = v;
+        self
+    }
+
+    /// Set the timeout of a probe
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.settings.timeout = v;
+        self
+    }
+
+    /// Finalize [`HopHealthCheckSettings`]
+    pub fn build(self) -> Result<HopHealthCheckSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl UpstreamTlsSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the name the hop certificate is checked against
+    pub fn server_name<S: ToString>(mut self, v: S) -> Self {
+        self.settings.server_name = Some(v.to_string());
+        self
+    }
+
+    /// Set the path to the trusted CA certificates
+    pub fn ca_bundle_path<P: ToString>(mut self, v: P) -> Self {
+        self.settings.ca_bundle_path = Some(v.to_string());
+        self
+    }
+
+    /// Set the digests of the trusted public keys
+    pub fn spki_pins(mut self, v: Vec<String>) -> Self {
+        self.settings.spki_pins = v;
+        self
+    }
+
+    /// Enable/disable the hop certificate verification bypass
+    pub fn insecure_skip_verify(mut self, v: bool) -> Self {
+        self.settings.insecure_skip_verify = v;
+        self
+    }
+
+    /// Finalize [`UpstreamTlsSettings`]
+    pub fn build(self) -> Result<UpstreamTlsSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl InterceptionSettingsBuilder {
+    fn new(ca_cert_path: String, ca_key_path: String) -> Self {
+        Self {
+            settings: InterceptionSettings {
+                ca_cert_path,
+                ca_key_path,
+                destinations: Default::default(),
+                ports: InterceptionSettings::default_ports(),
+                upstream_tls: Default::default(),
+            },
+        }
+    }
+
+    /// Add an intercepted destination pattern
+    pub fn destination<S: ToString>(mut self, v: S) -> Self {
+        self.settings.destinations.push(v.to_string());
+        self
+    }
+
+    /// Set the intercepted destination ports
+    pub fn ports(mut self, v: Vec<u16>) -> Self {
+        self.settings.ports = v;
+        self
+    }
+
+    /// Set the settings of the TLS connections to the destinations
+    pub fn upstream_tls(mut self, v: UpstreamTlsSettings) -> Self {
+        self.settings.upstream_tls = v;
+        self
+    }
+
+    /// Finalize [`InterceptionSettings`]
+    pub fn build(self) -> Result<InterceptionSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ExitPolicySettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the contact for the abuse reports
+    pub fn abuse_contact<S: ToString>(mut self, v: S) -> Self {
+        self.settings.abuse_contact = Some(v.to_string());
+        self
+    }
+
+    /// Set the traffic categories the operator does not permit
+    pub fn blocked_categories(mut self, v: Vec<String>) -> Self {
+        self.settings.blocked_categories = v;
+        self
+    }
+
+    /// Finalize [`ExitPolicySettings`]
+    pub fn build(self) -> Result<ExitPolicySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ImpairmentSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the listener protocols the impairment applies to
+    pub fn protocols(mut self, v: Vec<String>) -> Self {
+        self.settings.protocols = v;
+        self
+    }
+
+    /// Set the usernames of the clients the impairment applies to
+    pub fn usernames(mut self, v: Vec<String>) -> Self {
+        self.settings.usernames = v;
+        self
+    }
+
+    /// Set the time each chunk of data or datagram is held for
+    pub fn delay(mut self, v: Duration) -> Self {
+        self.settings.delay = v;
+        self
+    }
+
+    /// Set the maximum random deviation from the delay
+    pub fn jitter(mut self, v: Duration) -> Self {
+        self.settings.jitter = v;
+        self
+    }
+
+    /// Set the probability of losing a chunk of data or a datagram, in percent
+    pub fn loss_percent(mut self, v: f64) -> Self {
+        self.settings.loss_percent = v;
+        self
+    }
+
+    /// Set the maximum transfer rate in each direction
+    pub fn max_bytes_per_sec(mut self, v: u64) -> Self {
+        self.settings.max_bytes_per_sec = Some(v);
+        self
+    }
+
+    /// Finalize [`ImpairmentSettings`]
+    pub fn build(self) -> Result<ImpairmentSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl PortBlockSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: PortBlockSettings {
+                first_port: PortBlockSettings::default_first_port(),
+                last_port: PortBlockSettings::default_last_port(),
+                block_size: PortBlockSettings::default_block_size(),
+            },
+        }
+    }
+
+    /// Set the partitioned port range
+    pub fn port_range(mut self, first: u16, last: u16) -> Self {
+        self.settings.first_port = first;
+        self.settings.last_port = last;
+        self
+    }
+
+    /// Set the number of the ports in a block
+    pub fn block_size(mut self, v: u16) -> Self {
+        self.settings.block_size = v;
+        self
+    }
+
+    /// Finalize [`PortBlockSettings`]
+    pub fn build(self) -> Result<PortBlockSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ConnectRateSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: ConnectRateSettings {
+                connects_per_sec: ConnectRateSettings::default_connects_per_sec(),
+                burst: ConnectRateSettings::default_burst(),
+                max_wait: ConnectRateSettings::default_max_wait(),
+                jitter: Duration::ZERO,
+                max_destinations: ConnectRateSettings::default_max_destinations(),
+            },
+        }
+    }
+
+    /// Set the sustained rate of the connections to a destination address
+    pub fn connects_per_sec(mut self, v: u32) -> Self {
+        self.settings.connects_per_sec = v;
+        self
+    }
+
+    /// Set the number of the connections to a destination address made at once
+    pub fn burst(mut self, v: u32) -> Self {
+        self.settings.burst = v;
+        self
+    }
+
+    /// Set the longest a connection waits for its turn
+    pub fn max_wait(mut self, v: Duration) -> Self {
+        self.settings.max_wait = v;
+        self
+    }
+
+    /// Set the maximum random delay added to each connection
+    pub fn jitter(mut self, v: Duration) -> Self {
+        self.settings.jitter = v;
+        self
+    }
+
+    /// Set the maximum number of the tracked destination addresses
+    pub fn max_destinations(mut self, v: usize) -> Self {
+        self.settings.max_destinations = v;
+        self
+    }
+
+    /// Finalize [`ConnectRateSettings`]
+    pub fn build(self) -> Result<ConnectRateSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl EgressUplinksSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: EgressUplinksSettings {
+                uplink: vec![],
+                race_delay: EgressUplinksSettings::default_race_delay(),
+            },
+        }
+    }
+
+    /// Add an uplink over the network interface
+    pub fn uplink<S: ToString>(mut self, name: S, interface: S) -> Self {
+        self.settings.uplink.push(UplinkSettings {
+            name: name.to_string(),
+            interface: interface.to_string(),
+        });
+        self
+    }
+
+    /// Set how long an attempt is given before the one over the next uplink is started
+    pub fn race_delay(mut self, v: Duration) -> Self {
+        self.settings.race_delay = v;
+        self
+    }
+
+    /// Finalize [`EgressUplinksSettings`]
+    pub fn build(self) -> Result<EgressUplinksSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ErrorPagesSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the page sent with `502 Bad Gateway`
+    pub fn bad_gateway<P: ToString>(mut self, v: P) -> Self {
+        self.settings.bad_gateway = Some(v.to_string());
+        self
+    }
+
+    /// Set the page sent with `503 Service Unavailable`
+    pub fn service_unavailable<P: ToString>(mut self, v: P) -> Self {
+        self.settings.service_unavailable = Some(v.to_string());
+        self
+    }
+
+    /// Set the page sent with `504 Gateway Timeout`
+    pub fn gateway_timeout<P: ToString>(mut self, v: P) -> Self {
+        self.settings.gateway_timeout = Some(v.to_string());
+        self
+    }
+
+    /// Finalize [`ErrorPagesSettings`]
+    pub fn build(self) -> Result<ErrorPagesSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ResponseCacheSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: ResponseCacheSettings {
+                max_memory_size: ResponseCacheSettings::default_max_memory_size(),
+                max_entry_size: ResponseCacheSettings::default_max_entry_size(),
+                disk_path: None,
+                max_disk_size: ResponseCacheSettings::default_max_disk_size(),
+            },
+        }
+    }
+
+    /// Set the maximum total size of the responses kept in memory
+    pub fn max_memory_size(mut self, v: usize) -> Self {
+        self.settings.max_memory_size = v;
+        self
+    }
+
+    /// Set the maximum size of a cached response
+    pub fn max_entry_size(mut self, v: usize) -> Self {
+        self.settings.max_entry_size = v;
+        self
+    }
+
+    /// Set the directory to keep the cached responses in
+    pub fn disk_path<P: ToString>(mut self, v: P) -> Self {
+        self.settings.disk_path = Some(v.to_string());
+        self
+    }
+
+    /// Set the maximum total size of the responses kept on the disk
+    pub fn max_disk_size(mut self, v: usize) -> Self {
+        self.settings.max_disk_size = v;
+        self
+    }
+
+    /// Finalize [`ResponseCacheSettings`]
+    pub fn build(self) -> Result<ResponseCacheSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl IcmpSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: IcmpSettings {
+                interface_name: Default::default(),
+                request_timeout: IcmpSettings::default_request_timeout(),
+                recv_message_queue_capacity: IcmpSettings::default_message_queue_capacity(),
+            },
+        }
+    }
+
+    /// Set the interface name to bind the socket to
+    pub fn interface_name<S: ToString>(mut self, v: S) -> Self {
+        self.settings.interface_name = v.to_string();
+        self
+    }
+
+    /// Set the ICMP request timeout
+    pub fn request_timeout(mut self, v: Duration) -> Self {
+        self.settings.request_timeout = v;
+        self
+    }
+
+    /// Set the capacity of the ICMP multiplexer received messages queue
+    pub fn recv_message_queue_capacity(mut self, v: usize) -> Self {
+        self.settings.recv_message_queue_capacity = v;
+        self
+    }
+
+    /// Finalize [`IcmpSettings`]
+    pub fn build(self) -> Result<IcmpSettings, ValidationError> {
+        Ok(self.settings)
+    }
+}
+
+impl MetricsSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the address to listen on for settings export requests
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the metrics request timeout
+    pub fn request_timeout(mut self, v: Duration) -> Self {
+        self.settings.request_timeout = v;
+        self
+    }
+
+    /// Set the period the per-second stats history is kept for
+    pub fn stats_history(mut self, v: Duration) -> Self {
+        self.settings.stats_history = v;
+        self
+    }
+
+    /// Set whether the CPU time and the buffer memory are attributed to the subsystems
+    pub fn capacity_stats(mut self, v: bool) -> Self {
+        self.settings.capacity_stats = v;
+        self
+    }
+
+    /// Set the token the administration requests must carry
+    pub fn admin_token(mut self, v: String) -> Self {
+        self.settings.admin_token = Some(v);
+        self
+    }
+
+    /// Finalize [`MetricsSettings`]
+    pub fn build(self) -> Result<MetricsSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl HttpRedirectSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: HttpRedirectSettings {
+                listen_address: HttpRedirectSettings::default_listen_address(),
+                https_port: None,
+                acme_challenge_dir: None,
+                request_timeout: HttpRedirectSettings::default_request_timeout(),
+            },
+        }
+    }
+
+    /// Set the address to listen on for plain HTTP requests
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.listen_address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the port the requests are redirected to
+    pub fn https_port(mut self, v: u16) -> Self {
+        self.settings.https_port = Some(v);
+        self
+    }
+
+    /// Set the directory with the ACME HTTP-01 challenge responses
+    pub fn acme_challenge_dir<P: ToString>(mut self, v: P) -> Self {
+        self.settings.acme_challenge_dir = Some(v.to_string());
+        self
+    }
+
+    /// Set the timeout of a plain HTTP request
+    pub fn request_timeout(mut self, v: Duration) -> Self {
+        self.settings.request_timeout = v;
+        self
+    }
+
+    /// Finalize [`HttpRedirectSettings`]
+    pub fn build(self) -> Result<HttpRedirectSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl TriageSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: TriageSettings {
+                plain_http: Default::default(),
+                garbage: Default::default(),
+                tarpit_duration: TriageSettings::default_tarpit_duration(),
+                max_tarpit_connections: TriageSettings::default_max_tarpit_connections(),
+            },
+        }
+    }
+
+    /// Set what to do with a connection starting with a plain HTTP request
+    pub fn plain_http(mut self, v: TriageAction) -> Self {
+        self.settings.plain_http = v;
+        self
+    }
+
+    /// Set what to do with a connection starting with an unrecognized data
+    pub fn garbage(mut self, v: TriageAction) -> Self {
+        self.settings.garbage = v;
+        self
+    }
+
+    /// Set how long a connection is held in the tarpit
+    pub fn tarpit_duration(mut self, v: Duration) -> Self {
+        self.settings.tarpit_duration = v;
+        self
+    }
+
+    /// Set the maximum number of the connections held in the tarpit at once
+    pub fn max_tarpit_connections(mut self, v: usize) -> Self {
+        self.settings.max_tarpit_connections = v;
+        self
+    }
+
+    /// Finalize [`TriageSettings`]
+    pub fn build(self) -> Result<TriageSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl AcceptRateSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: AcceptRateSettings {
+                connects_per_sec: AcceptRateSettings::default_connects_per_sec(),
+                burst: AcceptRateSettings::default_burst(),
+                subnet_connects_per_sec: AcceptRateSettings::default_subnet_connects_per_sec(),
+                subnet_burst: AcceptRateSettings::default_subnet_burst(),
+                ipv4_prefix_len: AcceptRateSettings::default_ipv4_prefix_len(),
+                ipv6_prefix_len: AcceptRateSettings::default_ipv6_prefix_len(),
+                ban_after: None,
+                ban_duration: AcceptRateSettings::default_ban_duration(),
+                max_entries: AcceptRateSettings::default_max_entries(),
+            },
+        }
+    }
+
+    /// Set the sustained rate of the connections from a client address
+    pub fn connects_per_sec(mut self, v: u32) -> Self {
+        self.settings.connects_per_sec = v;
+        self
+    }
+
+    /// Set the number of the connections from a client address accepted at once
+    pub fn burst(mut self, v: u32) -> Self {
+        self.settings.burst = v;
+        self
+    }
+
+    /// Set the sustained rate of the connections from all the addresses of a subnet
+    pub fn subnet_connects_per_sec(mut self, v: u32) -> Self {
+        self.settings.subnet_connects_per_sec = v;
+        self
+    }
+
+    /// Set the number of the connections from all the addresses of a subnet accepted at once
+    pub fn subnet_burst(mut self, v: u32) -> Self {
+        self.settings.subnet_burst = v;
+        self
+    }
+
+    /// Set the prefix length of the IPv4 subnets
+    pub fn ipv4_prefix_len(mut self, v: u8) -> Self {
+        self.settings.ipv4_prefix_len = v;
+        self
+    }
+
+    /// Set the prefix length of the IPv6 subnets
+    pub fn ipv6_prefix_len(mut self, v: u8) -> Self {
+        self.settings.ipv6_prefix_len = v;
+        self
+    }
+
+    /// Set the number of the refused connections in a row after which an address is banned
+    pub fn ban_after(mut self, v: u32) -> Self {
+        self.settings.ban_after = Some(v);
+        self
+    }
+
+    /// Set how long a client address is banned for
+    pub fn ban_duration(mut self, v: Duration) -> Self {
+        self.settings.ban_duration = v;
+        self
+    }
+
+    /// Set the maximum number of the tracked client addresses and subnets
+    pub fn max_entries(mut self, v: usize) -> Self {
+        self.settings.max_entries = v;
+        self
+    }
+
+    /// Finalize [`AcceptRateSettings`]
+    pub fn build(self) -> Result<AcceptRateSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl ProxyProtocolSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: ProxyProtocolSettings {
+                trusted_sources: Default::default(),
+                header_timeout: ProxyProtocolSettings::default_header_timeout(),
+            },
+        }
+    }
+
+    /// Set the networks of the load balancers whose connections start with the header
+    pub fn trusted_sources(mut self, v: Vec<String>) -> Self {
+        self.settings.trusted_sources = v;
+        self
+    }
+
+    /// Set how long to wait for the header
+    pub fn header_timeout(mut self, v: Duration) -> Self {
+        self.settings.header_timeout = v;
+        self
+    }
+
+    /// Finalize [`ProxyProtocolSettings`]
+    pub fn build(self) -> Result<ProxyProtocolSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl StatsdSettingsBuilder {
+    fn new(address: SocketAddr) -> Self {
+        Self {
+            settings: StatsdSettings {
+                address,
+                prefix: StatsdSettings::default_prefix(),
+                interval: StatsdSettings::default_interval(),
+            },
+        }
+    }
+
+    /// Set the prefix prepended to the metric names
+    pub fn prefix<S: ToString>(mut self, v: S) -> Self {
+        self.settings.prefix = v.to_string();
+        self
+    }
+
+    /// Set the period of pushing the metrics
+    pub fn interval(mut self, v: Duration) -> Self {
+        self.settings.interval = v;
+        self
+    }
+
+    /// Finalize [`StatsdSettings`]
+    pub fn build(self) -> Result<StatsdSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl LdapSettingsBuilder {
+    fn new(address: String) -> Self {
+        Self {
+            settings: LdapSettings {
+                address,
+                tls: None,
+                bind_dn: None,
+                search_base_dn: None,
+                search_attribute: LdapSettings::default_search_attribute(),
+                search_bind_dn: None,
+                search_bind_password: None,
+                timeout: LdapSettings::default_timeout(),
+            },
+        }
+    }
+
+    /// Set the TLS settings of the server connections
+    pub fn tls(mut self, x: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(x);
+        self
+    }
+
+    /// Set the DN template a client binds with
+    pub fn bind_dn<S: ToString>(mut self, v: S) -> Self {
+        self.settings.bind_dn = Some(v.to_string());
+        self
+    }
+
+    /// Set the base DN of the client entry search and the attribute holding the username
+    pub fn search<S1: ToString, S2: ToString>(mut self, base_dn: S1, attribute: S2) -> Self {
+        self.settings.search_base_dn = Some(base_dn.to_string());
+        self.settings.search_attribute = attribute.to_string();
+        self
+    }
+
+    /// Set the credentials of the service account doing the search
+    pub fn search_bind<S1: ToString, S2: ToString>(mut self, dn: S1, password: S2) -> Self {
+        self.settings.search_bind_dn = Some(dn.to_string());
+        self.settings.search_bind_password = Some(password.to_string());
+        self
+    }
+
+    /// Set the timeout of a server connection and of each request
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.settings.timeout = v;
+        self
+    }
+
+    /// Finalize [`LdapSettings`]
+    pub fn build(self) -> Result<LdapSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl DatabaseSettingsBuilder {
+    fn new(address: String, database: String, user: String) -> Self {
+        Self {
+            settings: DatabaseSettings {
+                driver: Default::default(),
+                address,
+                tls: None,
+                database,
+                user,
+                password: Default::default(),
+                query: None,
+                pool_size: DatabaseSettings::default_pool_size(),
+                timeout: DatabaseSettings::default_timeout(),
+            },
+        }
+    }
+
+    /// Set the database server
+    pub fn driver(mut self, x: DatabaseDriver) -> Self {
+        self.settings.driver = x;
+        self
+    }
+
+    /// Set the TLS settings of the server connections
+    pub fn tls(mut self, x: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(x);
+        self
+    }
+
+    /// Set the password of the database user
+    pub fn password<S: ToString>(mut self, v: S) -> Self {
+        self.settings.password = v.to_string();
+        self
+    }
 
-        if self.listen_protocols.http1.is_none()
-            && self.listen_protocols.http2.is_none()
-            && self.listen_protocols.quic.is_none()
-        {
-            return Err(ValidationError::ListenProtocols("Not set".into()));
-        }
+    /// Set the query looking up a client
+    pub fn query<S: ToString>(mut self, v: S) -> Self {
+        self.settings.query = Some(v.to_string());
+        self
+    }
 
-        // Do not start the endpoint without credentials on a public address
-        if self.clients.path.is_empty()
-            && self.clients.clients.is_empty()
-            && !self.listen_address.ip().is_loopback()
-        {
-            return Err(ValidationError::NoCredentialsOnPublicAddress);
-        }
+    /// Set the maximum number of the server connections
+    pub fn pool_size(mut self, v: usize) -> Self {
+        self.settings.pool_size = v;
+        self
+    }
 
-        Ok(())
+    /// Set the timeout of a server connection and of each query
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.settings.timeout = v;
+        self
     }
 
-    pub fn default_listen_address() -> SocketAddr {
-        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443))
+    /// Finalize [`DatabaseSettings`]
+    pub fn build(self) -> Result<DatabaseSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub fn default_ipv6_available() -> bool {
-        true
+impl RedisSettingsBuilder {
+    fn new(address: String) -> Self {
+        Self {
+            settings: RedisSettings {
+                address,
+                tls: None,
+                username: None,
+                password: None,
+                database: 0,
+                key_prefix: RedisSettings::default_key_prefix(),
+                pool_size: RedisSettings::default_pool_size(),
+                timeout: RedisSettings::default_timeout(),
+            },
+        }
     }
 
-    pub fn default_allow_private_network_connections() -> bool {
-        false
+    /// Set the TLS settings of the server connections
+    pub fn tls(mut self, x: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(x);
+        self
     }
 
-    pub fn default_tls_handshake_timeout() -> Duration {
-        Duration::from_secs(10)
+    /// Set the credentials of the server connections, `username` being the ACL user
+    pub fn credentials<S: ToString>(mut self, username: Option<S>, password: S) -> Self {
+        self.settings.username = username.map(|x| x.to_string());
+        self.settings.password = Some(password.to_string());
+        self
     }
 
-    pub fn default_client_listener_timeout() -> Duration {
-        Duration::from_secs(10 * 60)
+    /// Set the logical database index
+    pub fn database(mut self, v: u32) -> Self {
+        self.settings.database = v;
+        self
     }
 
-    pub fn default_connection_establishment_timeout() -> Duration {
-        Duration::from_secs(30)
+    /// Set the prefix of the client keys
+    pub fn key_prefix<S: ToString>(mut self, v: S) -> Self {
+        self.settings.key_prefix = v.to_string();
+        self
     }
 
-    pub fn default_tcp_connections_timeout() -> Duration {
-        Duration::from_secs(604800) // 1 week (match client tcpip module)
+    /// Set the maximum number of the idle server connections
+    pub fn pool_size(mut self, v: usize) -> Self {
+        self.settings.pool_size = v;
+        self
     }
 
-    pub fn default_udp_connections_timeout() -> Duration {
-        Duration::from_secs(300) // 5 minutes (match client tcpip module)
+    /// Set the timeout of a server connection and of each command
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.settings.timeout = v;
+        self
     }
 
-    pub fn default_speedtest_enable() -> bool {
-        false
+    /// Finalize [`RedisSettings`]
+    pub fn build(self) -> Result<RedisSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-#[cfg(test)]
-impl Default for Settings {
-    fn default() -> Self {
+impl JwtSettingsBuilder {
+    fn new() -> Self {
         Self {
-            listen_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
-            ipv6_available: false,
-            allow_private_network_connections: true,
-            tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
-            client_listener_timeout: Settings::default_client_listener_timeout(),
-            connection_establishment_timeout: Settings::default_connection_establishment_timeout(),
-            tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
-            udp_connections_timeout: Settings::default_udp_connections_timeout(),
-            forward_protocol: Default::default(),
-            clients: Default::default(),
-            listen_protocols: ListenProtocolSettings {
-                http1: Some(Http1Settings::builder().build()),
-                http2: Some(Http2Settings::builder().build()),
-                quic: Some(QuicSettings::builder().build()),
+            settings: JwtSettings {
+                secret: None,
+                jwks_file: None,
+                audience: None,
+                issuer: None,
+                leeway: JwtSettings::default_leeway(),
             },
-            reverse_proxy: None,
-            icmp: None,
-            metrics: Default::default(),
-            rules_engine: Some(rules::RulesEngine::default_allow()),
-            speedtest_enable: false,
-            built: false,
         }
     }
-}
 
-impl TlsHostsSettings {
-    pub fn builder() -> TlsSettingsBuilder {
-        TlsSettingsBuilder::new()
+    /// Set the secret of the tokens signed with HMAC
+    pub fn secret<S: ToString>(mut self, x: S) -> Self {
+        self.settings.secret = Some(x.to_string());
+        self
     }
 
-    pub(crate) fn is_built(&self) -> bool {
-        self.built
+    /// Set the path to the JSON Web Key Set with the public keys
+    pub fn jwks_file<S: ToString>(mut self, x: S) -> Self {
+        self.settings.jwks_file = Some(x.to_string());
+        self
     }
 
-    fn validate_tls_hosts<'a, Iter>(
-        hosts: Iter,
-        mut unique_hosts: HashSet<&'a str>,
-    ) -> Result<HashSet<&'a str>, String>
-    where
-        Iter: Iterator<Item = &'a TlsHostInfo>,
-    {
-        for h in hosts {
-            utils::load_certs(&h.cert_chain_path).map_err(|e| {
-                format!(
-                    "Invalid cert chain: path='{}', error='{}'",
-                    h.cert_chain_path, e
-                )
-            })?;
+    /// Set the audience a token must be issued for
+    pub fn audience<S: ToString>(mut self, x: S) -> Self {
+        self.settings.audience = Some(x.to_string());
+        self
+    }
 
-            utils::load_private_key(&h.private_key_path).map_err(|e| {
-                format!("Invalid key: path='{}', error='{}'", h.private_key_path, e)
-            })?;
+    /// Set the issuer a token must be issued by
+    pub fn issuer<S: ToString>(mut self, x: S) -> Self {
+        self.settings.issuer = Some(x.to_string());
+        self
+    }
 
-            if !unique_hosts.insert(&h.hostname) {
-                return Err(format!("Hostname must be unique: {}", h.hostname));
-            }
-        }
+    /// Set the tolerated difference between the clocks of the endpoint and the issuer
+    pub fn leeway(mut self, x: Duration) -> Self {
+        self.settings.leeway = x;
+        self
+    }
 
-        Ok(unique_hosts)
+    /// Finalize [`JwtSettings`]
+    pub fn build(self) -> Result<JwtSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
-        if self.main_hosts.is_empty() {
-            return Err(ValidationError::MainTlsHostInfo("Not set".to_string()));
+impl PamSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: PamSettings {
+                service: PamSettings::default_service(),
+                check_account: PamSettings::default_check_account(),
+            },
         }
+    }
 
-        let hosts = Self::validate_tls_hosts(self.main_hosts.iter(), HashSet::new())
-            .map_err(ValidationError::MainTlsHostInfo)?;
-        let hosts = Self::validate_tls_hosts(self.ping_hosts.iter(), hosts)
-            .map_err(ValidationError::PingTlsHostInfo)?;
-        let hosts = Self::validate_tls_hosts(self.speedtest_hosts.iter(), hosts)
-            .map_err(ValidationError::SpeedTlsHostInfo)?;
-        Self::validate_tls_hosts(self.reverse_proxy_hosts.iter(), hosts)
-            .map_err(ValidationError::ReverseProxy)?;
+    /// Set the PAM service the clients are authenticated as
+    pub fn service<S: ToString>(mut self, x: S) -> Self {
+        self.settings.service = x.to_string();
+        self
+    }
 
-        Ok(())
+    /// Set whether an authenticated account is also checked to be usable
+    pub fn check_account(mut self, x: bool) -> Self {
+        self.settings.check_account = x;
+        self
     }
-}
 
-impl Socks5ForwarderSettings {
-    pub fn builder() -> Socks5ForwarderSettingsBuilder {
-        Socks5ForwarderSettingsBuilder::new()
+    /// Finalize [`PamSettings`]
+    pub fn build(self) -> Result<PamSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl Http1Settings {
-    pub fn builder() -> Http1SettingsBuilder {
-        Http1SettingsBuilder::new()
+impl IntrospectionSettingsBuilder {
+    fn new(url: String, client_id: String, client_secret: String) -> Self {
+        Self {
+            settings: IntrospectionSettings {
+                url,
+                client_id,
+                client_secret,
+                tls: None,
+                audience: None,
+                timeout: IntrospectionSettings::default_timeout(),
+                cache_size: IntrospectionSettings::default_cache_size(),
+                max_cache_ttl: IntrospectionSettings::default_max_cache_ttl(),
+            },
+        }
     }
 
-    pub fn default_upload_buffer_size() -> usize {
-        32 * 1024
+    /// Set the TLS settings of the server connections
+    pub fn tls(mut self, x: UpstreamTlsSettings) -> Self {
+        self.settings.tls = Some(x);
+        self
     }
-}
 
-impl Http2Settings {
-    pub fn builder() -> Http2SettingsBuilder {
-        Http2SettingsBuilder::new()
+    /// Set the audience a token must be issued for
+    pub fn audience<S: ToString>(mut self, x: S) -> Self {
+        self.settings.audience = Some(x.to_string());
+        self
     }
 
-    pub fn default_initial_connection_window_size() -> u32 {
-        8 * 1024 * 1024
+    /// Set the timeout of a server connection and of each request
+    pub fn timeout(mut self, x: Duration) -> Self {
+        self.settings.timeout = x;
+        self
     }
 
-    pub fn default_initial_stream_window_size() -> u32 {
-        128 * 1024 // Chrome constant
+    /// Set the maximum number of the cached active tokens
+    pub fn cache_size(mut self, x: usize) -> Self {
+        self.settings.cache_size = x;
+        self
     }
 
-    pub fn default_max_concurrent_streams() -> u32 {
-        1000 // Chrome constant
+    /// Set the longest time an active token is cached for
+    pub fn max_cache_ttl(mut self, x: Duration) -> Self {
+        self.settings.max_cache_ttl = x;
+        self
     }
 
-    pub fn default_max_frame_size() -> u32 {
-        1 << 14 // Firefox constant
+    /// Finalize [`IntrospectionSettings`]
+    pub fn build(self) -> Result<IntrospectionSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub fn default_header_table_size() -> u32 {
-        65536
+impl AuthCacheSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: AuthCacheSettings {
+                ttl: AuthCacheSettings::default_ttl(),
+                negative_ttl: AuthCacheSettings::default_negative_ttl(),
+                max_entries: AuthCacheSettings::default_max_entries(),
+            },
+        }
     }
-}
 
-impl QuicSettings {
-    pub fn builder() -> QuicSettingsBuilder {
-        QuicSettingsBuilder::new()
+    /// Set the period a successful authentication result is kept for
+    pub fn ttl(mut self, x: Duration) -> Self {
+        self.settings.ttl = x;
+        self
     }
 
-    pub fn default_recv_udp_payload_size() -> usize {
-        1350
+    /// Set the period a rejection is kept for
+    pub fn negative_ttl(mut self, x: Duration) -> Self {
+        self.settings.negative_ttl = x;
+        self
     }
 
-    pub fn default_send_udp_payload_size() -> usize {
-        1350
+    /// Set the maximum number of the kept results
+    pub fn max_entries(mut self, x: usize) -> Self {
+        self.settings.max_entries = x;
+        self
     }
 
-    pub fn default_initial_max_data() -> u64 {
-        100 * 1024 * 1024
+    /// Finalize [`AuthCacheSettings`]
+    pub fn build(self) -> Result<AuthCacheSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub fn default_initial_max_stream_data_bidi_local() -> u64 {
-        1024 * 1024
+impl AuthLockoutSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: AuthLockoutSettings {
+                max_failures: AuthLockoutSettings::default_max_failures(),
+                lockout: AuthLockoutSettings::default_lockout(),
+                max_lockout: AuthLockoutSettings::default_max_lockout(),
+                reset_after: AuthLockoutSettings::default_reset_after(),
+                lock_usernames: AuthLockoutSettings::default_lock_usernames(),
+                max_entries: AuthLockoutSettings::default_max_entries(),
+            },
+        }
     }
 
-    pub fn default_initial_max_stream_data_bidi_remote() -> u64 {
-        1024 * 1024
+    /// Set the number of the failed authentications in a row a client is locked out after
+    pub fn max_failures(mut self, x: u32) -> Self {
+        self.settings.max_failures = x;
+        self
     }
 
-    pub fn default_initial_max_stream_data_uni() -> u64 {
-        1024 * 1024
+    /// Set the period of the first lockout
+    pub fn lockout(mut self, x: Duration) -> Self {
+        self.settings.lockout = x;
+        self
     }
 
-    pub fn default_initial_max_streams_bidi() -> u64 {
-        4 * 1024
+    /// Set the longest period of a lockout
+    pub fn max_lockout(mut self, x: Duration) -> Self {
+        self.settings.max_lockout = x;
+        self
     }
 
-    pub fn default_initial_max_streams_uni() -> u64 {
-        4 * 1024
+    /// Set the period without failures the failures of a client are forgotten after
+    pub fn reset_after(mut self, x: Duration) -> Self {
+        self.settings.reset_after = x;
+        self
     }
 
-    pub fn default_max_connection_window() -> u64 {
-        24 * 1024 * 1024
+    /// Set whether the usernames are locked out besides the client addresses
+    pub fn lock_usernames(mut self, x: bool) -> Self {
+        self.settings.lock_usernames = x;
+        self
     }
 
-    pub fn default_max_stream_window() -> u64 {
-        16 * 1024 * 1024
+    /// Set the maximum number of the tracked client addresses and usernames
+    pub fn max_entries(mut self, x: usize) -> Self {
+        self.settings.max_entries = x;
+        self
     }
 
-    pub fn default_disable_active_migration() -> bool {
-        true
+    /// Finalize [`AuthLockoutSettings`]
+    pub fn build(self) -> Result<AuthLockoutSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub fn default_enable_early_data() -> bool {
-        true
+impl AuditLogSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
     }
 
-    pub fn default_message_queue_capacity() -> usize {
-        4 * 1024
+    /// Set the file the records are appended to
+    pub fn path(mut self, x: String) -> Self {
+        self.settings.path = Some(x);
+        self
     }
-}
 
-impl ReverseProxySettings {
-    pub fn builder() -> ReverseProxySettingsBuilder {
-        ReverseProxySettingsBuilder::new()
+    /// Set the address of the UDP server the records are sent to
+    pub fn address(mut self, x: SocketAddr) -> Self {
+        self.settings.address = Some(x);
+        self
     }
 
-    pub fn validate(&self) -> Result<(), ValidationError> {
-        if self.server_address.port() == 0 {
-            return Err(ValidationError::ReverseProxy(
-                "Server address is not set".to_string(),
-            ));
-        }
+    /// Set the rotation of the file the records are appended to
+    pub fn rotation(mut self, x: LogRotationSettings) -> Self {
+        self.settings.rotation = Some(x);
+        self
+    }
 
-        if self.path_mask.is_empty() || !self.path_mask.starts_with('/') {
-            return Err(ValidationError::ReverseProxy(format!(
-                "Invalid path mask: {}",
-                self.path_mask
-            )));
+    /// Finalize [`AuditLogSettings`]
+    pub fn build(self) -> Result<AuditLogSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl RevocationSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: RevocationSettings {
+                path: None,
+                grace_period: Duration::ZERO,
+                reload_interval: RevocationSettings::default_reload_interval(),
+            },
         }
+    }
+
+    /// Set the file the list is kept in
+    pub fn path(mut self, x: String) -> Self {
+        self.settings.path = Some(x);
+        self
+    }
 
-        Ok(())
+    /// Set the period the sessions authenticated with the revoked credentials are let to live
+    pub fn grace_period(mut self, x: Duration) -> Self {
+        self.settings.grace_period = x;
+        self
     }
-}
 
-impl IcmpSettings {
-    pub fn builder() -> IcmpSettingsBuilder {
-        IcmpSettingsBuilder::new()
+    /// Set the interval of checking the file for changes
+    pub fn reload_interval(mut self, x: Duration) -> Self {
+        self.settings.reload_interval = x;
+        self
     }
 
-    pub fn default_interface_name() -> String {
-        if cfg!(target_os = "linux") {
-            "eth0"
-        } else {
-            "en0"
-        }
-        .into()
+    /// Finalize [`RevocationSettings`]
+    pub fn build(self) -> Result<RevocationSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    pub fn default_request_timeout() -> Duration {
-        Duration::from_secs(3)
+impl ReconnectTokenSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: ReconnectTokenSettings {
+                ttl: ReconnectTokenSettings::default_ttl(),
+                secret: None,
+                max_redeemed: ReconnectTokenSettings::default_max_redeemed(),
+            },
+        }
     }
 
-    pub fn default_message_queue_capacity() -> usize {
-        256
+    /// Set how long an issued token stays valid
+    pub fn ttl(mut self, x: Duration) -> Self {
+        self.settings.ttl = x;
+        self
     }
-}
 
-impl MetricsSettings {
-    pub fn builder() -> MetricsSettingsBuilder {
-        MetricsSettingsBuilder::new()
+    /// Set the secret the sealing key is derived from
+    pub fn secret(mut self, x: String) -> Self {
+        self.settings.secret = Some(x);
+        self
     }
 
-    pub fn default_listen_address() -> SocketAddr {
-        (Ipv4Addr::LOCALHOST, 1987).into()
+    /// Set the maximum number of the tracked redeemed tokens
+    pub fn max_redeemed(mut self, x: usize) -> Self {
+        self.settings.max_redeemed = x;
+        self
     }
 
-    pub fn default_request_timeout() -> Duration {
-        Duration::from_secs(3)
+    /// Finalize [`ReconnectTokenSettings`]
+    pub fn build(self) -> Result<ReconnectTokenSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl Default for MetricsSettings {
-    fn default() -> Self {
+impl GuestSettingsBuilder {
+    fn new() -> Self {
         Self {
-            address: MetricsSettings::default_listen_address(),
-            request_timeout: MetricsSettings::default_request_timeout(),
+            settings: GuestSettings {
+                tier: None,
+                profile: None,
+                max_session_duration: GuestSettings::default_max_session_duration(),
+            },
         }
     }
+
+    /// Set the tier the guest sessions are assigned to
+    pub fn tier(mut self, x: String) -> Self {
+        self.settings.tier = Some(x);
+        self
+    }
+
+    /// Set the destination profile the guests are restricted to
+    pub fn profile(mut self, x: String) -> Self {
+        self.settings.profile = Some(x);
+        self
+    }
+
+    /// Set the maximum lifetime of a guest session
+    pub fn max_session_duration(mut self, x: Duration) -> Self {
+        self.settings.max_session_duration = x;
+        self
+    }
+
+    /// Finalize [`GuestSettings`].
+    /// The tier and the profile are checked against the endpoint settings on building them.
+    pub fn build(self) -> Result<GuestSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
 }
 
-impl SettingsBuilder {
+impl DigestAuthSettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: Settings {
-                listen_address: Settings::default_listen_address(),
-                ipv6_available: Settings::default_ipv6_available(),
-                allow_private_network_connections:
-                    Settings::default_allow_private_network_connections(),
-                tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
-                client_listener_timeout: Settings::default_client_listener_timeout(),
-                connection_establishment_timeout:
-                    Settings::default_connection_establishment_timeout(),
-                tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
-                udp_connections_timeout: Settings::default_udp_connections_timeout(),
-                forward_protocol: Default::default(),
-                listen_protocols: Default::default(),
-                clients: Default::default(),
-                reverse_proxy: None,
-                icmp: None,
-                metrics: Default::default(),
-                rules_engine: Some(rules::RulesEngine::default_allow()),
-                speedtest_enable: Settings::default_speedtest_enable(),
-                built: true,
+            settings: DigestAuthSettings {
+                realm: DigestAuthSettings::default_realm(),
+                nonce_lifetime: DigestAuthSettings::default_nonce_lifetime(),
             },
         }
     }
 
-    /// Finalize [`Settings`]
-    pub fn build(self) -> Result<Settings, ValidationError> {
-        self.settings.validate()?;
+    /// Set the realm of the challenges
+    pub fn realm(mut self, x: String) -> Self {
+        self.settings.realm = x;
+        self
+    }
+
+    /// Set how long a nonce of a challenge is accepted since issued
+    pub fn nonce_lifetime(mut self, x: Duration) -> Self {
+        self.settings.nonce_lifetime = x;
+        self
+    }
 
+    /// Finalize [`DigestAuthSettings`]
+    pub fn build(self) -> Result<DigestAuthSettings, ValidationError> {
+        self.settings.validate()?;
         Ok(self.settings)
     }
+}
 
-    /// Set the address to listen on
-    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
-        self.settings.listen_address = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
-        Ok(self)
+impl LogRotationSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: LogRotationSettings {
+                max_files: LogRotationSettings::default_max_files(),
+                ..Default::default()
+            },
+        }
     }
 
-    /// Set the reverse proxy settings.
-    /// With this one set up the endpoint does TLS termination on such connections and
-    /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
-    /// into original HTTP/x towards the client. Like this:
-    ///
-    /// ```(client) TLS(HTTP/x) <--(endpoint)--> (server) HTTP/1.1```
-    ///
-    /// The translated HTTP/1.1 requests have the custom header `X-Original-Protocol`
-    /// appended. For now, its value can be either `HTTP1`, or `HTTP3`.
-    /// TLS hosts for the reverse proxy channel are configured through [`TlsHostsSettings`].
-    pub fn reverse_proxy(mut self, settings: ReverseProxySettings) -> Self {
-        self.settings.reverse_proxy = Some(settings);
+    /// Rotate the file at the start of each period
+    pub fn period(mut self, x: RotationPeriod) -> Self {
+        self.settings.period = Some(x);
         self
     }
 
-    /// Set IPv6 availability
-    pub fn ipv6_available(mut self, v: bool) -> Self {
-        self.settings.ipv6_available = v;
+    /// Rotate the file as soon as it has grown to the size
+    pub fn max_size_bytes(mut self, x: u64) -> Self {
+        self.settings.max_size_bytes = Some(x);
         self
     }
 
-    /// Allow/disallow connections to private network of the endpoint
-    pub fn allow_private_network_connections(mut self, v: bool) -> Self {
-        self.settings.allow_private_network_connections = v;
+    /// Set the compression of the rotated files
+    pub fn compression(mut self, x: LogCompression) -> Self {
+        self.settings.compression = x;
         self
     }
 
-    /// Set timeout of TLS handshake
-    pub fn tls_handshake_timeout(mut self, v: Duration) -> Self {
-        self.settings.tls_handshake_timeout = v;
+    /// Set the number of the rotated files kept at most
+    pub fn max_files(mut self, x: usize) -> Self {
+        self.settings.max_files = x;
         self
     }
 
-    /// Set timeout of client listener
-    pub fn client_listener_timeout(mut self, v: Duration) -> Self {
-        self.settings.client_listener_timeout = v;
+    /// Set the program run on each rotation with the path of the rotated file
+    pub fn on_rotate(mut self, x: String) -> Self {
+        self.settings.on_rotate = Some(x);
         self
     }
 
-    /// Set timeout of outgoing connection establishment.
-    /// For example, it is related to client's connection requests.
-    pub fn connection_establishment_timeout(mut self, v: Duration) -> Self {
-        self.settings.connection_establishment_timeout = v;
-        self
+    /// Finalize [`LogRotationSettings`]
+    pub fn build(self) -> Result<LogRotationSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    /// Set timeout of tunneled TCP connections
-    pub fn tcp_connections_timeout(mut self, v: Duration) -> Self {
-        self.settings.tcp_connections_timeout = v;
-        self
+impl DuplicateSessionSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: DuplicateSessionSettings {
+                max_sessions: DuplicateSessionSettings::default_max_sessions(),
+                action: Default::default(),
+            },
+        }
     }
 
-    /// Set timeout of tunneled UDP "connections"
-    pub fn udp_connections_timeout(mut self, v: Duration) -> Self {
-        self.settings.udp_connections_timeout = v;
+    /// Set the number of the active sessions an identity is let to have
+    pub fn max_sessions(mut self, x: usize) -> Self {
+        self.settings.max_sessions = x;
         self
     }
 
-    /// Set the forwarder codec settings
-    pub fn forwarder_settings(mut self, settings: ForwardProtocolSettings) -> Self {
-        self.settings.forward_protocol = settings;
+    /// Set what happens to a session exceeding the limit
+    pub fn action(mut self, x: DuplicateSessionAction) -> Self {
+        self.settings.action = x;
         self
     }
 
-    /// Set the listener codec settings
-    pub fn listen_protocols(mut self, settings: ListenProtocolSettings) -> Self {
-        self.settings.listen_protocols = settings;
-        self
+    /// Finalize [`DuplicateSessionSettings`]
+    pub fn build(self) -> Result<DuplicateSessionSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    /// Set the client authenticator
-    pub fn clients(mut self, x: Vec<Client>) -> Self {
-        self.settings.clients.clients = x;
-        self
+impl BandwidthEstimationSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: BandwidthEstimationSettings {
+                sample_interval: BandwidthEstimationSettings::default_sample_interval(),
+                window: BandwidthEstimationSettings::default_window(),
+                hints: false,
+                pacing_gain: BandwidthEstimationSettings::default_pacing_gain(),
+            },
+        }
     }
 
-    /// Set the ICMP forwarder settings
-    pub fn icmp(mut self, x: IcmpSettings) -> Self {
-        self.settings.icmp = Some(x);
+    /// Set the period of sampling the traffic of the sessions
+    pub fn sample_interval(mut self, x: Duration) -> Self {
+        self.settings.sample_interval = x;
         self
     }
 
-    /// Set the metrics request listener settings
-    pub fn metrics(mut self, x: MetricsSettings) -> Self {
-        self.settings.metrics = Some(x);
+    /// Set the period the samples are taken into account for
+    pub fn window(mut self, x: Duration) -> Self {
+        self.settings.window = x;
         self
     }
 
-    /// Set the rules engine for connection filtering
-    pub fn rules_engine(mut self, x: rules::RulesEngine) -> Self {
-        self.settings.rules_engine = Some(x);
+    /// Set whether the clients are hinted the pacing rate
+    pub fn hints(mut self, x: bool) -> Self {
+        self.settings.hints = x;
         self
     }
 
-    /// Set whether speedtest is available
-    pub fn speedtest_enable(mut self, x: bool) -> Self {
-        self.settings.speedtest_enable = x;
+    /// Set the share of the estimated bandwidth hinted as the pacing rate
+    pub fn pacing_gain(mut self, x: f64) -> Self {
+        self.settings.pacing_gain = x;
         self
     }
+
+    /// Finalize [`BandwidthEstimationSettings`]
+    pub fn build(self) -> Result<BandwidthEstimationSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
 }
 
-impl TlsSettingsBuilder {
-    fn new() -> Self {
+impl AuthChainSettingsBuilder {
+    fn new(backends: Vec<AuthBackend>) -> Self {
         Self {
-            settings: TlsHostsSettings {
-                main_hosts: Default::default(),
-                ping_hosts: Default::default(),
-                speedtest_hosts: Default::default(),
-                reverse_proxy_hosts: Default::default(),
-                built: true,
+            settings: AuthChainSettings {
+                backends,
+                mode: Default::default(),
             },
         }
     }
 
-    /// Finalize [`TlsHostsSettings`]
-    pub fn build(self) -> Result<TlsHostsSettings, ValidationError> {
-        self.settings.validate()?;
-        Ok(self.settings)
+    /// Set how the results of the authenticators are combined
+    pub fn mode(mut self, x: AuthChainMode) -> Self {
+        self.settings.mode = x;
+        self
     }
 
-    /// Set the main TLS hosts.
-    /// Used for traffic tunneling and service requests handling.
-    pub fn main_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
-        self.settings.main_hosts = hosts;
-        self
+    /// Finalize [`AuthChainSettings`]
+    pub fn build(self) -> Result<AuthChainSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    /// Set the TLS hosts for HTTPS pinging.
-    /// With this one set up the endpoint responds with `200 OK` to HTTPS `GET` requests
-    /// to the specified domains.
-    pub fn ping_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
-        self.settings.ping_hosts = hosts;
-        self
+impl ClientAuthSettingsBuilder {
+    fn new(ca_bundle_path: String) -> Self {
+        Self {
+            settings: ClientAuthSettings {
+                ca_bundle_path,
+                required: false,
+            },
+        }
     }
 
-    /// Set the TLS hosts for speed testing.
-    /// With this one set up the endpoint accepts connections to the specified hosts and
-    /// handles HTTP requests in the following way:
-    ///     * `GET` requests with `/Nmb.bin` path (where `N` is 1 to 100, e.g. `/100mb.bin`)
-    ///       are considered as download speedtest transferring `N` megabytes to a client
-    ///     * `POST` requests with `/upload.html` path and `Content-Length: N`
-    ///       are considered as upload speedtest receiving `N` bytes from a client,
-    ///       where `N` is up to 120 * 1024 * 1024 bytes
-    pub fn speedtest_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
-        self.settings.speedtest_hosts = hosts;
+    /// Set whether the connections without a client certificate are refused
+    pub fn required(mut self, x: bool) -> Self {
+        self.settings.required = x;
         self
     }
 
-    /// The TLS hosts for the connections must be forwarded to the reverse proxy
-    /// (see [`self::Settings::reverse_proxy`]). Only makes sense if the reverse proxy
-    /// is set up, otherwise it is ignored.
-    pub fn reverse_proxy_hosts(mut self, hosts: Vec<TlsHostInfo>) -> Self {
-        self.settings.reverse_proxy_hosts = hosts;
-        self
+    /// Finalize [`ClientAuthSettings`]
+    pub fn build(self) -> Result<ClientAuthSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl Socks5ForwarderSettingsBuilder {
+impl SelfSignedSettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: Socks5ForwarderSettings {
-                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
-                extended_auth: false,
-            },
+            settings: SelfSignedSettings::default(),
         }
     }
 
-    /// Finalize [`Socks5ForwarderSettings`]
-    pub fn build(self) -> Socks5BuilderResult<Socks5ForwarderSettings> {
-        if self.settings.address.ip().is_unspecified() {
-            return Err(Socks5Error::AddressNotSet);
-        }
+    /// Set the DNS names and IP addresses the certificate is issued for in addition to
+    /// the hostnames of the hosts
+    pub fn subject_alt_names(mut self, x: Vec<String>) -> Self {
+        self.settings.subject_alt_names = x;
+        self
+    }
 
+    /// Finalize [`SelfSignedSettings`]
+    pub fn build(self) -> Result<SelfSignedSettings, ValidationError> {
+        self.settings.validate()?;
         Ok(self.settings)
     }
+}
 
-    /// Set the SOCKS proxy address
-    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
-        self.settings.address = v
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
-        Ok(self)
+impl GrpcAdminSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: GrpcAdminSettings {
+                listen_address: GrpcAdminSettings::default_listen_address(),
+            },
+        }
     }
 
-    /// Enable/disable extended authentication.
-    /// See README for details.
-    pub fn extended_auth(mut self, v: bool) -> Self {
-        self.settings.extended_auth = v;
-        self
+    /// Set the address to listen on for gRPC requests
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.listen_address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Finalize [`GrpcAdminSettings`]
+    pub fn build(self) -> Result<GrpcAdminSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl Http1SettingsBuilder {
-    fn new() -> Self {
+impl StateStoreSettingsBuilder {
+    fn new(path: String) -> Self {
         Self {
-            settings: Http1Settings {
-                upload_buffer_size: Http1Settings::default_upload_buffer_size(),
+            settings: StateStoreSettings {
+                path,
+                checkpoint_interval: StateStoreSettings::default_checkpoint_interval(),
             },
         }
     }
 
-    /// Finalize [`Http1Settings`]
-    pub fn build(self) -> Http1Settings {
-        self.settings
+    /// Set the interval between writing the state changes to the database
+    pub fn checkpoint_interval(mut self, v: Duration) -> Self {
+        self.settings.checkpoint_interval = v;
+        self
+    }
+
+    /// Finalize [`StateStoreSettings`]
+    pub fn build(self) -> Result<StateStoreSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl Http2SettingsBuilder {
+impl CertificateExpirySettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: Http2Settings {
-                initial_connection_window_size:
-                    Http2Settings::default_initial_connection_window_size(),
-                initial_stream_window_size: Http2Settings::default_initial_stream_window_size(),
-                max_concurrent_streams: Http2Settings::default_max_concurrent_streams(),
-                max_frame_size: Http2Settings::default_max_frame_size(),
-                header_table_size: Http2Settings::default_header_table_size(),
+            settings: CertificateExpirySettings {
+                warning_threshold: CertificateExpirySettings::default_warning_threshold(),
+                check_interval: CertificateExpirySettings::default_check_interval(),
+                refuse_expired: false,
             },
         }
     }
 
-    /// Finalize [`Http2Settings`]
-    pub fn build(self) -> Http2Settings {
-        self.settings
+    /// Set the period before the expiration of a certificate the warnings start at
+    pub fn warning_threshold(mut self, x: Duration) -> Self {
+        self.settings.warning_threshold = x;
+        self
     }
 
-    /// Set the initial window size (in octets) for connection-level flow control for received data
-    pub fn initial_connection_window_size(mut self, v: u32) -> Self {
-        self.settings.initial_connection_window_size = v;
+    /// Set the interval between the checks of the certificates
+    pub fn check_interval(mut self, x: Duration) -> Self {
+        self.settings.check_interval = x;
         self
     }
 
-    /// Set the initial window size (in octets) for stream-level flow control for received data
-    pub fn initial_stream_window_size(mut self, v: u32) -> Self {
-        self.settings.initial_stream_window_size = v;
+    /// Set whether the endpoint refuses to start with an expired certificate
+    pub fn refuse_expired(mut self, x: bool) -> Self {
+        self.settings.refuse_expired = x;
         self
     }
 
-    /// Set the maximum number of concurrent streams
-    pub fn max_concurrent_streams(mut self, v: u32) -> Self {
-        self.settings.max_concurrent_streams = v;
-        self
+    /// Finalize [`CertificateExpirySettings`]
+    pub fn build(self) -> Result<CertificateExpirySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    /// Set the size (in octets) of the largest HTTP/2 frame payload that we are able to accept
-    pub fn max_frame_size(mut self, v: u32) -> Self {
-        self.settings.max_frame_size = v;
+impl AffinitySettingsBuilder {
+    fn new(instance_id: String, secret: String) -> Self {
+        Self {
+            settings: AffinitySettings {
+                instance_id,
+                secret,
+                header_name: AffinitySettings::default_header_name(),
+                ttl: AffinitySettings::default_ttl(),
+            },
+        }
+    }
+
+    /// Set the name of the header carrying the token
+    pub fn header_name<S: ToString>(mut self, v: S) -> Self {
+        self.settings.header_name = v.to_string();
         self
     }
 
-    /// Set the max size of received header frames
-    pub fn header_table_size(mut self, v: u32) -> Self {
-        self.settings.header_table_size = v;
+    /// Set how long an issued token stays valid
+    pub fn ttl(mut self, v: Duration) -> Self {
+        self.settings.ttl = v;
         self
     }
+
+    /// Finalize [`AffinitySettings`]
+    pub fn build(self) -> Result<AffinitySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
 }
 
-impl QuicSettingsBuilder {
+impl PolicySettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: QuicSettings {
-                recv_udp_payload_size: QuicSettings::default_recv_udp_payload_size(),
-                send_udp_payload_size: QuicSettings::default_send_udp_payload_size(),
-                initial_max_data: QuicSettings::default_initial_max_data(),
-                initial_max_stream_data_bidi_local:
-                    QuicSettings::default_initial_max_stream_data_bidi_local(),
-                initial_max_stream_data_bidi_remote:
-                    QuicSettings::default_initial_max_stream_data_bidi_remote(),
-                initial_max_stream_data_uni: QuicSettings::default_initial_max_stream_data_uni(),
-                initial_max_streams_bidi: QuicSettings::default_initial_max_streams_bidi(),
-                initial_max_streams_uni: QuicSettings::default_initial_max_streams_uni(),
-                max_connection_window: QuicSettings::default_max_connection_window(),
-                max_stream_window: QuicSettings::default_max_stream_window(),
-                disable_active_migration: QuicSettings::default_disable_active_migration(),
-                enable_early_data: QuicSettings::default_enable_early_data(),
-                message_queue_capacity: QuicSettings::default_message_queue_capacity(),
+            settings: PolicySettings {
+                motd: None,
+                terms: None,
+                terms_version: PolicySettings::default_terms_version(),
+                expiry_warning_days: None,
             },
         }
     }
 
-    /// Finalize [`QuicSettings`]
-    pub fn build(self) -> QuicSettings {
-        self.settings
+    /// Set the message of the day
+    pub fn motd<S: ToString>(mut self, v: S) -> Self {
+        self.settings.motd = Some(v.to_string());
+        self
     }
 
-    /// Set the `max_udp_payload_size transport` parameter
-    pub fn recv_udp_payload_size(mut self, v: usize) -> Self {
-        self.settings.recv_udp_payload_size = v;
+    /// Set the terms of use and their version
+    pub fn terms<S1: ToString, S2: ToString>(mut self, terms: S1, version: S2) -> Self {
+        self.settings.terms = Some(terms.to_string());
+        self.settings.terms_version = version.to_string();
         self
     }
 
-    /// Set the maximum outgoing UDP payload size
-    pub fn send_udp_payload_size(mut self, v: usize) -> Self {
-        self.settings.send_udp_payload_size = v;
+    /// Set the number of days before the credentials expire to warn the clients from
+    pub fn expiry_warning_days(mut self, v: u32) -> Self {
+        self.settings.expiry_warning_days = Some(v);
         self
     }
 
-    /// Set the `initial_max_data` transport parameter
-    pub fn initial_max_data(mut self, v: u64) -> Self {
-        self.settings.initial_max_data = v;
-        self
+    /// Finalize [`PolicySettings`]
+    pub fn build(self) -> Result<PolicySettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
+}
 
-    /// Set the `initial_max_stream_data_bidi_local` transport parameter
-    pub fn max_stream_data_bidi_local(mut self, v: u64) -> Self {
-        self.settings.initial_max_stream_data_bidi_local = v;
+impl TimeoutSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the timeouts applied to the connections of all the listener protocols
+    pub fn stages(mut self, x: StageTimeoutSettings) -> Self {
+        self.settings.stages = x;
         self
     }
 
-    /// Set the `initial_max_stream_data_bidi_remote` transport parameter
-    pub fn max_stream_data_bidi_remote(mut self, v: u64) -> Self {
-        self.settings.initial_max_stream_data_bidi_remote = v;
+    /// Set the timeouts of the HTTP/1.1 connections
+    pub fn http1(mut self, x: StageTimeoutSettings) -> Self {
+        self.settings.http1 = x;
         self
     }
 
-    /// Set the `initial_max_stream_data_uni` transport parameter
-    pub fn max_stream_data_uni(mut self, v: u64) -> Self {
-        self.settings.initial_max_stream_data_uni = v;
+    /// Set the timeouts of the HTTP/2 connections
+    pub fn http2(mut self, x: StageTimeoutSettings) -> Self {
+        self.settings.http2 = x;
         self
     }
 
-    /// Set the `initial_max_streams_bidi` transport parameter
-    pub fn max_streams_bidi(mut self, v: u64) -> Self {
-        self.settings.initial_max_streams_bidi = v;
+    /// Set the timeouts of the HTTP/3 connections
+    pub fn http3(mut self, x: StageTimeoutSettings) -> Self {
+        self.settings.http3 = x;
         self
     }
 
-    /// Set the `initial_max_streams_uni` transport parameter
-    pub fn max_streams_uni(mut self, v: u64) -> Self {
-        self.settings.initial_max_streams_uni = v;
+    /// Finalize [`TimeoutSettings`]
+    pub fn build(self) -> Result<TimeoutSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl StageTimeoutSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set timeout of an incoming TLS handshake
+    pub fn tls_handshake(mut self, v: Duration) -> Self {
+        self.settings.tls_handshake = Some(v);
         self
     }
 
-    /// Set the maximum size of the connection window
-    pub fn max_connection_window(mut self, v: u64) -> Self {
-        self.settings.max_connection_window = v;
+    /// Set timeout of a call to the authenticator
+    pub fn auth(mut self, v: Duration) -> Self {
+        self.settings.auth = Some(v);
         self
     }
 
-    /// Set the maximum size of the stream window
-    pub fn max_stream_window(mut self, v: u64) -> Self {
-        self.settings.max_stream_window = v;
+    /// Set timeout of a connection to a peer or to the reverse proxy origin server
+    pub fn upstream_connect(mut self, v: Duration) -> Self {
+        self.settings.upstream_connect = Some(v);
         self
     }
 
-    /// Set the `disable_active_migration` transport parameter
-    pub fn disable_active_migration(mut self, v: bool) -> Self {
-        self.settings.disable_active_migration = v;
+    /// Set how long a client may take to send the next request header
+    pub fn request_header(mut self, v: Duration) -> Self {
+        self.settings.request_header = Some(v);
         self
     }
 
-    /// Enable receiving early data
-    pub fn enable_early_data(mut self, v: bool) -> Self {
-        self.settings.enable_early_data = v;
+    /// Set idle timeout of the tunneled TCP connections and the reverse proxy exchanges
+    pub fn idle(mut self, v: Duration) -> Self {
+        self.settings.idle = Some(v);
         self
     }
 
-    /// Set the capacity of the QUIC multiplexer message queue
-    pub fn message_queue_capacity(mut self, v: usize) -> Self {
-        self.settings.message_queue_capacity = v;
+    /// Set the maximum lifetime of a tunneled TCP connection or a reverse proxy exchange
+    pub fn total(mut self, v: Duration) -> Self {
+        self.settings.total = Some(v);
         self
     }
+
+    /// Finalize [`StageTimeoutSettings`]
+    pub fn build(self) -> StageTimeoutSettings {
+        self.settings
+    }
 }
 
-impl ReverseProxySettingsBuilder {
+impl ScheduleSettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: ReverseProxySettings {
-                server_address: (Ipv4Addr::UNSPECIFIED, 0).into(),
-                path_mask: Default::default(),
-                h3_backward_compatibility: false,
+            settings: ScheduleSettings {
+                windows: Default::default(),
+                notice: ScheduleSettings::default_notice(),
             },
         }
     }
 
-    /// Finalize [`ReverseProxySettings`]
-    pub fn build(self) -> Result<ReverseProxySettings, ValidationError> {
+    /// Add a maintenance window
+    pub fn window(mut self, x: MaintenanceWindowSettings) -> Self {
+        self.settings.windows.push(x);
+        self
+    }
+
+    /// Set how long before a window the clients are notified of it
+    pub fn notice(mut self, v: Duration) -> Self {
+        self.settings.notice = v;
+        self
+    }
+
+    /// Finalize [`ScheduleSettings`]
+    pub fn build(self) -> Result<ScheduleSettings, ValidationError> {
         self.settings.validate()?;
         Ok(self.settings)
     }
+}
 
-    /// Set the proxy server address
-    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
-        self.settings.server_address = v
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
-        Ok(self)
+impl MaintenanceWindowSettingsBuilder {
+    fn new(start: String, duration: Duration) -> Self {
+        Self {
+            settings: MaintenanceWindowSettings {
+                days: Default::default(),
+                start,
+                duration,
+            },
+        }
     }
 
-    /// Connections to [the main hosts](TlsHostsSettings.main_hosts) with
-    /// paths starting with this mask are routed to the reverse proxy server.
-    /// MUST start with slash.
-    pub fn path_mask(mut self, v: String) -> Self {
-        self.settings.path_mask = v;
+    /// Set the days of the week the window starts on
+    pub fn days(mut self, v: Vec<String>) -> Self {
+        self.settings.days = v;
         self
     }
 
-    /// With this one set to `true` the endpoint overrides the HTTP method while
-    /// translating an HTTP3 request to HTTP1 in case the request has the `GET` method
-    /// and its path is `/` or matches [`ReverseProxySettings.path_mask`]
-    pub fn h3_backward_compatibility(mut self, v: bool) -> Self {
-        self.settings.h3_backward_compatibility = v;
-        self
+    /// Finalize [`MaintenanceWindowSettings`]
+    pub fn build(self) -> Result<MaintenanceWindowSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
 
-impl IcmpSettingsBuilder {
+impl TierSettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: IcmpSettings {
-                interface_name: Default::default(),
-                request_timeout: IcmpSettings::default_request_timeout(),
-                recv_message_queue_capacity: IcmpSettings::default_message_queue_capacity(),
+            settings: TierSettings {
+                max_bytes_per_sec: None,
+                max_sessions: None,
+                shed_above_sessions: None,
             },
         }
     }
 
-    /// Set the interface name to bind the socket to
-    pub fn interface_name<S: ToString>(mut self, v: S) -> Self {
-        self.settings.interface_name = v.to_string();
+    /// Set the maximum transfer rate of the tunneled TCP connections of a client
+    /// in each direction
+    pub fn max_bytes_per_sec(mut self, v: u64) -> Self {
+        self.settings.max_bytes_per_sec = Some(v);
         self
     }
 
-    /// Set the ICMP request timeout
-    pub fn request_timeout(mut self, v: Duration) -> Self {
-        self.settings.request_timeout = v;
+    /// Set the maximum number of concurrent sessions of the tier
+    pub fn max_sessions(mut self, v: usize) -> Self {
+        self.settings.max_sessions = Some(v);
         self
     }
 
-    /// Set the capacity of the ICMP multiplexer received messages queue
-    pub fn recv_message_queue_capacity(mut self, v: usize) -> Self {
-        self.settings.recv_message_queue_capacity = v;
+    /// Set the total number of the endpoint sessions starting from which the tier is shed
+    pub fn shed_above_sessions(mut self, v: usize) -> Self {
+        self.settings.shed_above_sessions = Some(v);
         self
     }
 
-    /// Finalize [`IcmpSettings`]
-    pub fn build(self) -> Result<IcmpSettings, ValidationError> {
+    /// Finalize [`TierSettings`]
+    pub fn build(self) -> Result<TierSettings, ValidationError> {
+        self.settings.validate().map_err(ValidationError::Tiers)?;
         Ok(self.settings)
     }
 }
 
-impl MetricsSettingsBuilder {
+impl ProfileSettingsBuilder {
     fn new() -> Self {
         Self {
-            settings: Default::default(),
+            settings: ProfileSettings {
+                destinations: vec![],
+                default_action: ProfileSettings::default_action(),
+                route: vec![],
+            },
         }
     }
 
-    /// Set the address to listen on for settings export requests
-    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
-        self.settings.address = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
-        Ok(self)
+    /// Add a destination rule, checked after the previously added ones
+    pub fn destination<S: ToString>(mut self, destination: S, action: rules::RuleAction) -> Self {
+        self.settings.destinations.push(DestinationRule {
+            destination: destination.to_string(),
+            action,
+        });
+        self
     }
 
-    /// Set the metrics request timeout
-    pub fn request_timeout(mut self, v: Duration) -> Self {
-        self.settings.request_timeout = v;
+    /// Set the action on the destinations no rule matches
+    pub fn default_action(mut self, x: rules::RuleAction) -> Self {
+        self.settings.default_action = x;
         self
     }
 
-    /// Finalize [`MetricsSettings`]
-    pub fn build(self) -> Result<MetricsSettings, ValidationError> {
+    /// Add a routing rule, checked after the previously added ones
+    pub fn route(mut self, x: rules::RouteRule) -> Self {
+        self.settings.route.push(x);
+        self
+    }
+
+    /// Finalize [`ProfileSettings`]
+    pub fn build(self) -> Result<ProfileSettings, ValidationError> {
+        self.settings
+            .validate()
+            .map_err(ValidationError::Profiles)?;
         Ok(self.settings)
     }
 }
 
 impl Default for ForwardProtocolSettings {
     fn default() -> Self {
-        ForwardProtocolSettings::Direct(DirectForwarderSettings {})
+        ForwardProtocolSettings::Direct(Default::default())
     }
 }
 
@@ -1340,6 +8452,20 @@ fn validate_file_path(path: &str) -> io::Result<()> {
 }
 
 fn deserialize_duration_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_unsigned(deserializer).map(Duration::from_secs)
+}
+
+fn deserialize_duration_millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_unsigned(deserializer).map(Duration::from_millis)
+}
+
+fn deserialize_unsigned<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
@@ -1363,8 +8489,7 @@ where
         }
     }
 
-    let x = deserializer.deserialize_u64(Visitor)?;
-    Ok(Duration::from_secs(x))
+    deserializer.deserialize_u64(Visitor)
 }
 
 fn serialize_duration_secs<S>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error>
@@ -1374,6 +8499,33 @@ where
     serializer.serialize_u64(x.as_secs())
 }
 
+fn deserialize_optional_duration_secs<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_duration_secs(deserializer).map(Some)
+}
+
+fn serialize_optional_duration_secs<S>(
+    x: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::ser::Serializer,
+{
+    match x {
+        Some(x) => serialize_duration_secs(x, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_duration_millis<S>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::ser::Serializer,
+{
+    serializer.serialize_u64(x.as_millis() as u64)
+}
+
 fn deserialize_file_path<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: serde::de::Deserializer<'de>,
@@ -1437,7 +8589,17 @@ where
         .enumerate()
         .map(|(idx, x)| {
             let username = demangle_toml_string(x["username"].to_string());
-            let password = demangle_toml_string(x["password"].to_string());
+            let password = x
+                .get("password")
+                .map(|x| demangle_toml_string(x.to_string()))
+                .unwrap_or_default();
+            let password_hash = x
+                .get("password_hash")
+                .and_then(Item::as_str)
+                .map(str::to_string);
+            // The clients authorized by a client certificate may go without a password
+            let has_certificate =
+                x.get("certificate_fingerprint").is_some() || x.get("certificate_san").is_some();
 
             if username.is_empty() {
                 return Err(serde::de::Error::custom(format!(
@@ -1445,21 +8607,131 @@ where
                     idx + 1
                 )));
             }
-            if password.is_empty() {
-                return Err(serde::de::Error::custom(format!(
-                    "Client #{}: password cannot be empty",
-                    idx + 1
-                )));
+            match &password_hash {
+                None if password.is_empty() && !has_certificate => {
+                    return Err(serde::de::Error::custom(format!(
+                        "Client #{}: password cannot be empty",
+                        idx + 1
+                    )))
+                }
+                None => (),
+                Some(_) if !password.is_empty() => {
+                    return Err(serde::de::Error::custom(format!(
+                        "Client #{}: only one of password and password_hash may be set",
+                        idx + 1
+                    )))
+                }
+                Some(x) => authentication::password_hash::validate(x).map_err(|e| {
+                    serde::de::Error::custom(format!(
+                        "Client #{}: invalid password hash: {}",
+                        idx + 1,
+                        e
+                    ))
+                })?,
             }
 
-            Ok(Client { username, password })
+            let totp_secret = x
+                .get("totp_secret")
+                .and_then(Item::as_str)
+                .map(|x| {
+                    authentication::totp::validate(x)
+                        .map(|_| x.to_string())
+                        .map_err(|e| {
+                            serde::de::Error::custom(format!(
+                                "Client #{}: invalid TOTP secret: {}",
+                                idx + 1,
+                                e
+                            ))
+                        })
+                })
+                .transpose()?;
+            let tier = x.get("tier").and_then(Item::as_str).map(str::to_string);
+            let egress_address = x
+                .get("egress_address")
+                .and_then(Item::as_str)
+                .map(|x| {
+                    x.parse::<IpAddr>().map_err(|e| {
+                        serde::de::Error::custom(format!(
+                            "Client #{}: invalid egress address: {}",
+                            idx + 1,
+                            e
+                        ))
+                    })
+                })
+                .transpose()?;
+            let max_connections = x
+                .get("max_connections")
+                .map(|x| {
+                    x.as_integer()
+                        .and_then(|x| usize::try_from(x).ok())
+                        .ok_or_else(|| {
+                            serde::de::Error::custom(format!(
+                                "Client #{}: max_connections must be a non-negative integer",
+                                idx + 1
+                            ))
+                        })
+                })
+                .transpose()?;
+            let max_streams = x
+                .get("max_streams")
+                .map(|x| {
+                    x.as_integer()
+                        .and_then(|x| usize::try_from(x).ok())
+                        .ok_or_else(|| {
+                            serde::de::Error::custom(format!(
+                                "Client #{}: max_streams must be a non-negative integer",
+                                idx + 1
+                            ))
+                        })
+                })
+                .transpose()?;
+            let data_quota_bytes = x
+                .get("data_quota_bytes")
+                .map(|x| {
+                    x.as_integer()
+                        .and_then(|x| u64::try_from(x).ok())
+                        .ok_or_else(|| {
+                            serde::de::Error::custom(format!(
+                                "Client #{}: data_quota_bytes must be a non-negative integer",
+                                idx + 1
+                            ))
+                        })
+                })
+                .transpose()?;
+            let data_quota_period_days = x
+                .get("data_quota_period_days")
+                .map(|x| {
+                    x.as_integer()
+                        .and_then(|x| u32::try_from(x).ok())
+                        .filter(|x| (1..=authentication::DataQuota::MAX_PERIOD_DAYS).contains(x))
+                        .ok_or_else(|| {
+                            serde::de::Error::custom(format!(
+                                "Client #{}: data_quota_period_days must be in range [1, {}]",
+                                idx + 1,
+                                authentication::DataQuota::MAX_PERIOD_DAYS
+                            ))
+                        })
+                })
+                .transpose()?;
+            let profile = x.get("profile").and_then(Item::as_str).map(str::to_string);
+
+            Ok(Client {
+                username,
+                password,
+                password_hash,
+                totp_secret,
+                tier,
+                egress_address,
+                max_connections,
+                max_streams,
+                data_quota_bytes,
+                data_quota_period_days,
+                profile,
+            })
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(Credentials {
-        path,
-        clients: res,
-    })
+    Ok(Credentials { path, clients: res })
 }
 
 fn deserialize_rules<'de, D>(deserializer: D) -> Result<Option<rules::RulesEngine>, D::Error>
@@ -1482,7 +8754,7 @@ where
                 "Warning: Could not read rules file '{}': {}. Defaulting to allow all connections.",
                 path, e
             );
-            return Ok(Some(rules::RulesEngine::default_allow()));
+            return Ok(Some(rules::RulesEngine::default_allow().with_path(path)));
         }
     };
 
@@ -1494,7 +8766,7 @@ where
         }
     };
 
-    let rules_config = match rules_doc.get("rule").and_then(Item::as_array_of_tables) {
+    let rule = match rules_doc.get("rule").and_then(Item::as_array_of_tables) {
         Some(rules_array) => {
             let rules: Vec<rules::Rule> = rules_array
                 .iter()
@@ -1526,15 +8798,49 @@ where
                 })
                 .collect();
 
-            rules::RulesConfig { rule: rules }
+            rules
         }
         None => {
             // No rules array found, create empty config
-            rules::RulesConfig { rule: vec![] }
+            vec![]
         }
     };
 
-    Ok(Some(rules::RulesEngine::from_config(rules_config)))
+    let route = match rules_doc.get("route").and_then(Item::as_array_of_tables) {
+        Some(routes_array) => routes_array
+            .iter()
+            .filter_map(|route_table| {
+                let get_string = |key| {
+                    route_table
+                        .get(key)
+                        .and_then(Item::as_str)
+                        .map(|s| s.to_string())
+                };
+
+                Some(rules::RouteRule {
+                    destination: get_string("destination")?,
+                    cidr: get_string("cidr"),
+                    override_sni: get_string("override_sni"),
+                    override_host: get_string("override_host"),
+                    race_uplinks: route_table
+                        .get("race_uplinks")
+                        .and_then(Item::as_array)
+                        .map(|x| {
+                            x.iter()
+                                .filter_map(|x| x.as_str())
+                                .map(String::from)
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                })
+            })
+            .collect(),
+        None => vec![],
+    };
+
+    Ok(Some(
+        rules::RulesEngine::from_config(rules::RulesConfig { rule, route }).with_path(path),
+    ))
 }
 
 fn demangle_toml_string(x: String) -> String {
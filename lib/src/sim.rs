@@ -0,0 +1,180 @@
+//! The building blocks of the deterministic simulation tests.
+//!
+//! The timing logic, like the connection timeouts and the idle timers, is tested on the paused
+//! tokio clock: `#[tokio::test(start_paused = true)]` or [`tokio::time::pause`]. The virtual time
+//! jumps to the next timer deadline as soon as the runtime has nothing else to do, so a test
+//! waiting for a timeout of several minutes finishes instantly and always observes the same
+//! timings. The in-memory transports below stand in for the real sockets, which would let
+//! the wall clock leak into the tests.
+//!
+//! The module is internal to the crate: the tests of the modules use it directly, and
+//! the `bench` feature builds it for the [benchmark workloads](crate::benchmarking).
+
+use crate::{log_utils, pipe};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// The receiving end of an in-memory stream
+pub(crate) struct MemorySource {
+    shared: Arc<Shared>,
+    id: log_utils::IdChain<u64>,
+}
+
+/// The sending end of an in-memory stream
+pub(crate) struct MemorySink {
+    shared: Arc<Shared>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct Shared {
+    /// The maximum amount of the written data not yet consumed by the receiver,
+    /// like the flow control window of a real stream
+    window: usize,
+    state: Mutex<State>,
+    readable: Notify,
+    writable: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    chunks: VecDeque<Bytes>,
+    unconsumed: usize,
+    eof: bool,
+    is_source_dropped: bool,
+    is_sink_dropped: bool,
+}
+
+/// Make a one-way in-memory stream with the flow control window of `window` bytes
+pub(crate) fn stream(window: usize) -> (MemorySink, MemorySource) {
+    let shared = Arc::new(Shared {
+        window,
+        state: Default::default(),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        MemorySink {
+            shared: shared.clone(),
+            id: log_utils::IdChain::empty(),
+        },
+        MemorySource {
+            shared,
+            id: log_utils::IdChain::empty(),
+        },
+    )
+}
+
+/// An end of a two-way stream
+pub(crate) type StreamEnd = (Box<dyn pipe::Source>, Box<dyn pipe::Sink>);
+
+/// Make a pair of the connected in-memory stream ends, each one reading what the other writes
+pub(crate) fn duplex(window: usize) -> (StreamEnd, StreamEnd) {
+    let (a_tx, b_rx) = stream(window);
+    let (b_tx, a_rx) = stream(window);
+    (
+        (Box::new(a_rx), Box::new(a_tx)),
+        (Box::new(b_rx), Box::new(b_tx)),
+    )
+}
+
+#[async_trait]
+impl pipe::Source for MemorySource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(x) = state.chunks.pop_front() {
+                    return Ok(pipe::Data::Chunk(x));
+                }
+                if state.eof {
+                    return Ok(pipe::Data::Eof);
+                }
+                if state.is_sink_dropped {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.unconsumed = state
+            .unconsumed
+            .checked_sub(size)
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Consumed more than received"))?;
+        self.shared.writable.notify_one();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl pipe::Sink for MemorySink {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    fn write(&mut self, mut data: Bytes) -> io::Result<Bytes> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.is_source_dropped {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        if state.eof {
+            return Err(io::Error::new(ErrorKind::Other, "Already shut down"));
+        }
+
+        let n = data
+            .len()
+            .min(self.shared.window.saturating_sub(state.unconsumed));
+        if n > 0 {
+            state.chunks.push_back(data.split_to(n));
+            state.unconsumed += n;
+            self.shared.readable.notify_one();
+        }
+        Ok(data)
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        self.shared.state.lock().unwrap().eof = true;
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        loop {
+            {
+                let state = self.shared.state.lock().unwrap();
+                if state.is_source_dropped {
+                    return Err(ErrorKind::BrokenPipe.into());
+                }
+                if state.unconsumed < self.shared.window {
+                    return Ok(());
+                }
+            }
+            self.shared.writable.notified().await;
+        }
+    }
+}
+
+impl Drop for MemorySource {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().is_source_dropped = true;
+        self.shared.writable.notify_one();
+    }
+}
+
+impl Drop for MemorySink {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().is_sink_dropped = true;
+        self.shared.readable.notify_one();
+    }
+}
@@ -107,10 +107,21 @@ pub(crate) enum ExtendedAuthenticationValue<'this> {
     /// **MUST NOT** come together with the [`ExtendedAuthenticationValue::SniAuth`]
     /// in the same message.
     BasicProxyAuth(Cow<'this, str>),
+    /// The value of the `Proxy-Authorization` header sent by the VPN client,
+    /// with the `Bearer` keyword stripped.
+    /// The value is a JSON Web Token.
+    /// **MUST NOT** come together with the [`ExtendedAuthenticationValue::SniAuth`]
+    /// in the same message.
+    BearerProxyAuth(Cow<'this, str>),
     /// Used as a marker that the VPN client tries to authenticate using the TLS
     /// domain name.
     /// Has no value (the length is zero).
     SniAuth,
+    /// The SHA-256 fingerprint of the TLS client certificate of the VPN client.
+    /// The value is a lowercase hex string.
+    /// **MUST NOT** come together with the [`ExtendedAuthenticationValue::SniAuth`]
+    /// in the same message.
+    ClientCert(Cow<'this, str>),
 }
 
 impl ExtendedAuthenticationValue<'_> {
@@ -121,6 +132,8 @@ impl ExtendedAuthenticationValue<'_> {
             Self::UserAgent(_) => 0x03,
             Self::BasicProxyAuth(_) => 0x04,
             Self::SniAuth => 0x05,
+            Self::BearerProxyAuth(_) => 0x06,
+            Self::ClientCert(_) => 0x07,
         }
     }
 
@@ -135,6 +148,12 @@ impl ExtendedAuthenticationValue<'_> {
                 ExtendedAuthenticationValue::BasicProxyAuth(Cow::Owned(x.into_owned()))
             }
             Self::SniAuth => ExtendedAuthenticationValue::SniAuth,
+            Self::BearerProxyAuth(x) => {
+                ExtendedAuthenticationValue::BearerProxyAuth(Cow::Owned(x.into_owned()))
+            }
+            Self::ClientCert(x) => {
+                ExtendedAuthenticationValue::ClientCert(Cow::Owned(x.into_owned()))
+            }
         }
     }
 }
@@ -703,7 +722,9 @@ where
             put_u16(buf, x.len() as u16);
             buf.extend_from_slice(x.as_bytes());
         }
-        ExtendedAuthenticationValue::BasicProxyAuth(x) => {
+        ExtendedAuthenticationValue::BasicProxyAuth(x)
+        | ExtendedAuthenticationValue::BearerProxyAuth(x)
+        | ExtendedAuthenticationValue::ClientCert(x) => {
             if x.len() > u16::MAX as usize {
                 return Err(Error::Protocol("Too long Proxy-Authorization".to_string()));
             }
@@ -2,8 +2,8 @@ use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
 use crate::settings::{ForwardProtocolSettings, Settings, Socks5ForwarderSettings};
 use crate::tcp_forwarder::TcpForwarder;
 use crate::{
-    authentication, core, datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils, pipe,
-    socks5_client, tunnel,
+    authentication, core, datagram_pipe, downstream, forwarder, hop_health, log_id, log_utils,
+    net_utils, pipe, socks5_client, tunnel, upstream_tls,
 };
 use async_trait::async_trait;
 use base64::Engine;
@@ -16,7 +16,7 @@ use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
 
 pub(crate) struct Socks5Forwarder {
@@ -47,7 +47,12 @@ struct DatagramTransceiverShared {
     id: log_utils::IdChain<u64>,
 }
 
-type UdpAssociationSocket = socks5_client::UdpAssociation<TcpStream>;
+/// The control connection of a UDP association, encrypted if configured
+trait ProxyStream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> ProxyStream for T {}
+
+type UdpAssociationSocket = socks5_client::UdpAssociation<Box<dyn ProxyStream>>;
 
 struct UdpAssociation {
     socket: Arc<UdpAssociationSocket>,
@@ -80,7 +85,7 @@ impl forwarder::UdpDatagramPipeShared for DatagramTransceiverShared {
         }
 
         let socket = match socks5_client::connect(
-            TcpStream::connect(socks_settings(&self.context.settings).address).await?,
+            connect_proxy(&self.context).await?,
             self.auth.clone(),
             socks5_client::Request::UdpAssociate,
         )
@@ -226,63 +231,81 @@ impl forwarder::TcpConnector for TcpConnector {
             }
         };
 
-        let stream = match TcpStream::connect(socks_settings(&self.context.settings).address).await
-        {
-            Ok(s) => s,
-            Err(e) => return Err(tunnel::ConnectionError::Io(e)),
-        };
+        let auth = meta
+            .auth
+            .map(|x| {
+                if socks_settings(&self.context.settings).extended_auth {
+                    make_extended_auth(
+                        x,
+                        &meta.tls_domain,
+                        &meta.client_address,
+                        meta.user_agent.as_ref().map(|x| x.as_ref()),
+                    )
+                } else {
+                    make_auth(x)
+                }
+            })
+            .transpose()
+            .map_err(tunnel::ConnectionError::Other)?;
+        let request = socks5_client::Request::Connect(destination, port);
 
-        match socks5_client::connect(
-            stream,
-            meta.auth
-                .map(|x| {
-                    if socks_settings(&self.context.settings).extended_auth {
-                        make_extended_auth(
-                            x,
-                            &meta.tls_domain,
-                            &meta.client_address,
-                            meta.user_agent.as_ref().map(|x| x.as_ref()),
-                        )
-                    } else {
-                        make_auth(x)
-                    }
-                })
-                .transpose()
-                .map_err(tunnel::ConnectionError::Other)?,
-            socks5_client::Request::Connect(destination, port),
-        )
-        .await
-        {
-            Ok(socks5_client::ConnectResult::TcpConnection(stream)) => {
-                let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
-                Ok(TcpForwarder::pipe_from_stream(stream, id, metrics_guard))
-            }
-            Ok(socks5_client::ConnectResult::UdpAssociation(_)) => unreachable!(),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::HostUnreachable,
-            )) => Err(tunnel::ConnectionError::HostUnreachable),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::NetworkUnreachable,
-            )) => Err(tunnel::ConnectionError::HostUnreachable),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::ConnectionRefused,
-            )) => Err(tunnel::ConnectionError::Io(
+        let (stream, address) = hop_health::connect(&self.context)
+            .await
+            .map_err(tunnel::ConnectionError::Io)?;
+
+        let metrics_guard = || self.context.metrics.clone().outbound_tcp_socket_counter();
+        match &self.context.socks5_tls {
+            None => into_pipe(socks5_client::connect(stream, auth, request).await, |x| {
+                TcpForwarder::pipe_from_stream(x, id, metrics_guard(), None)
+            }),
+            Some(tls) => into_pipe(
+                socks5_client::connect(
+                    tls.connect(stream, address)
+                        .await
+                        .map_err(tunnel::ConnectionError::Io)?,
+                    auth,
+                    request,
+                )
+                .await,
+                |x| upstream_tls::pipe_from_stream(x, id, metrics_guard()),
+            ),
+        }
+    }
+}
+
+/// Convert the result of a SOCKS `CONNECT` request into the tunneled connection pipe
+#[allow(clippy::type_complexity)]
+pub(crate) fn into_pipe<IO>(
+    result: Result<socks5_client::ConnectResult<IO>, socks5_client::Error>,
+    make_pipe: impl FnOnce(IO) -> (Box<dyn pipe::Source>, Box<dyn pipe::Sink>),
+) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+    match result {
+        Ok(socks5_client::ConnectResult::TcpConnection(stream)) => Ok(make_pipe(stream)),
+        Ok(socks5_client::ConnectResult::UdpAssociation(_)) => unreachable!(),
+        Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::HostUnreachable)) => {
+            Err(tunnel::ConnectionError::HostUnreachable)
+        }
+        Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::NetworkUnreachable)) => {
+            Err(tunnel::ConnectionError::HostUnreachable)
+        }
+        Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::ConnectionRefused)) => {
+            Err(tunnel::ConnectionError::Io(
                 ErrorKind::ConnectionRefused.into(),
-            )),
-            Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::TtlExpired)) => {
-                Err(tunnel::ConnectionError::Timeout)
-            }
-            Ok(socks5_client::ConnectResult::Failure(x)) => Err(tunnel::ConnectionError::Other(
-                format!("SOCKS server replied with error code: {:?}", x),
-            )),
-            Err(socks5_client::Error::Io(x)) => Err(tunnel::ConnectionError::Io(x)),
-            Err(socks5_client::Error::Protocol(x)) => Err(tunnel::ConnectionError::Other(format!(
-                "SOCKS protocol error: {}",
-                x
-            ))),
-            Err(socks5_client::Error::Authentication(x)) => {
-                Err(tunnel::ConnectionError::Authentication(x))
-            }
+            ))
+        }
+        Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::TtlExpired)) => {
+            Err(tunnel::ConnectionError::Timeout)
+        }
+        Ok(socks5_client::ConnectResult::Failure(x)) => Err(tunnel::ConnectionError::Other(
+            format!("SOCKS server replied with error code: {:?}", x),
+        )),
+        Err(socks5_client::Error::Io(x)) => Err(tunnel::ConnectionError::Io(x)),
+        Err(socks5_client::Error::Protocol(x)) => Err(tunnel::ConnectionError::Other(format!(
+            "SOCKS protocol error: {}",
+            x
+        ))),
+        Err(socks5_client::Error::Authentication(x)) => {
+            Err(tunnel::ConnectionError::Authentication(x))
         }
     }
 }
@@ -297,7 +320,7 @@ impl forwarder::DatagramMultiplexerAuthenticator for DatagramMuxAuthenticator {
         user_agent: Option<&'_ str>,
     ) -> Result<(), tunnel::ConnectionError> {
         match socks5_client::connect(
-            TcpStream::connect(socks_settings(&self.context.settings).address)
+            connect_proxy(&self.context)
                 .await
                 .map_err(tunnel::ConnectionError::Io)?,
             Some(
@@ -502,9 +525,16 @@ impl datagram_pipe::Sink for DatagramSink {
 
 fn make_auth(auth: authentication::Source) -> Result<socks5_client::Authentication, String> {
     Ok(match auth {
-        authentication::Source::Sni(x) => {
+        authentication::Source::Sni(x) | authentication::Source::ProxyBearer(x) => {
             socks5_client::Authentication::UsernamePassword(x.clone(), x)
         }
+        authentication::Source::ClientCert(_) => {
+            let x: Cow<str> = Cow::Owned(client_cert_fingerprint(&auth)?);
+            socks5_client::Authentication::UsernamePassword(x.clone(), x)
+        }
+        authentication::Source::ProxyDigest(_) => {
+            return Err("Digest credentials carry no password to forward".to_string())
+        }
         authentication::Source::ProxyBasic(x) => {
             let credentials = base64::engine::general_purpose::STANDARD
                 .decode(x.as_ref())
@@ -549,11 +579,37 @@ fn make_extended_auth<'a>(
         authentication::Source::ProxyBasic(x) => values.push(
             socks5_client::ExtendedAuthenticationValue::BasicProxyAuth(x),
         ),
+        authentication::Source::ProxyBearer(x) => values.push(
+            socks5_client::ExtendedAuthenticationValue::BearerProxyAuth(x),
+        ),
+        authentication::Source::ClientCert(_) => {
+            values.push(socks5_client::ExtendedAuthenticationValue::ClientCert(
+                Cow::Owned(client_cert_fingerprint(&auth)?),
+            ))
+        }
+        authentication::Source::ProxyDigest(_) => {
+            return Err("Digest credentials can't be forwarded".to_string())
+        }
     }
 
     Ok(socks5_client::Authentication::Extended(values))
 }
 
+fn client_cert_fingerprint(auth: &authentication::Source) -> Result<String, String> {
+    auth.client_cert()
+        .map(authentication::client_cert::fingerprint)
+        .ok_or_else(|| "Empty client certificate chain".to_string())
+}
+
+/// Connect to the proxy, over TLS if configured
+async fn connect_proxy(context: &core::Context) -> io::Result<Box<dyn ProxyStream>> {
+    let (stream, address) = hop_health::connect(context).await?;
+    match &context.socks5_tls {
+        Some(x) => Ok(Box::new(x.connect(stream, address).await?)),
+        None => Ok(Box::new(stream)),
+    }
+}
+
 const fn socks_settings(settings: &Settings) -> &Socks5ForwarderSettings {
     match &settings.forward_protocol {
         ForwardProtocolSettings::Socks5(x) => x,
@@ -0,0 +1,348 @@
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+};
+use sqlx::Row;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CREATE_TABLE_QUERY: &str = "CREATE TABLE IF NOT EXISTS entries (\
+    namespace TEXT NOT NULL, \
+    key TEXT NOT NULL, \
+    value TEXT NOT NULL, \
+    expires_at INTEGER, \
+    PRIMARY KEY (namespace, key))";
+const SELECT_QUERY: &str = "SELECT namespace, key, value, expires_at FROM entries";
+const UPSERT_QUERY: &str =
+    "INSERT OR REPLACE INTO entries (namespace, key, value, expires_at) VALUES (?, ?, ?, ?)";
+const DELETE_QUERY: &str = "DELETE FROM entries WHERE namespace = ? AND key = ?";
+const PURGE_QUERY: &str = "DELETE FROM entries WHERE expires_at <= ?";
+
+/// An embedded key-value store keeping the endpoint state (quota counters, dynamic bans,
+/// leases, resumption tokens, etc.) across restarts.
+///
+/// The state is kept in an SQLite database and is served from memory. The changes are
+/// periodically checkpointed to the database: a checkpoint writes only the entries changed
+/// since the previous one, in a single transaction, so the database never holds a partially
+/// written state.
+pub struct StateStore {
+    pool: SqlitePool,
+    /// Drives the database connection, as the serving runtime may have no spare thread
+    /// to run a query while the calling one waits for it
+    runtime: Option<tokio::runtime::Runtime>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: BTreeMap<(String, String), Entry>,
+    /// The entries changed since the last checkpoint
+    changed: BTreeSet<(String, String)>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Entry {
+    value: String,
+    /// Seconds since the UNIX epoch
+    expires_at: Option<u64>,
+}
+
+impl StateStore {
+    /// Open the store kept in the database at `path`, creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("state-store")
+            .enable_all()
+            .build()?;
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Full);
+        let pool = {
+            // The pool spawns its maintenance tasks on the current runtime
+            let _guard = runtime.enter();
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_lazy_with(options)
+        };
+        let store = Self {
+            pool,
+            runtime: Some(runtime),
+            state: Default::default(),
+        };
+
+        let pool = store.pool.clone();
+        let rows = store.run(async move {
+            sqlx::query(CREATE_TABLE_QUERY).execute(&pool).await?;
+            sqlx::query(SELECT_QUERY).fetch_all(&pool).await
+        })?;
+        let mut state = store.state.lock().unwrap();
+        for row in rows {
+            let (namespace, key, entry) =
+                parse_row(&row).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            state.entries.insert((namespace, key), entry);
+        }
+        drop(state);
+
+        Ok(store)
+    }
+
+    /// Get the value of the `key` from the `namespace`
+    pub fn get(&self, namespace: &str, key: &str) -> Option<String> {
+        let now = unix_now();
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .get(&(namespace.to_string(), key.to_string()))
+            .filter(|x| !x.is_expired(now))
+            .map(|x| x.value.clone())
+    }
+
+    /// Set the value of the `key` in the `namespace`.
+    /// If `ttl` is set, the entry is dropped once it elapses.
+    pub fn set(&self, namespace: &str, key: &str, value: String, ttl: Option<Duration>) {
+        let entry = Entry {
+            value,
+            expires_at: ttl.map(|x| unix_now().saturating_add(x.as_secs())),
+        };
+        let id = (namespace.to_string(), key.to_string());
+        let mut state = self.state.lock().unwrap();
+        state.changed.insert(id.clone());
+        state.entries.insert(id, entry);
+    }
+
+    /// Remove the `key` from the `namespace`
+    pub fn remove(&self, namespace: &str, key: &str) -> Option<String> {
+        let now = unix_now();
+        let id = (namespace.to_string(), key.to_string());
+        let mut state = self.state.lock().unwrap();
+        let removed = state.entries.remove(&id);
+        state.changed.insert(id);
+        removed.filter(|x| !x.is_expired(now)).map(|x| x.value)
+    }
+
+    /// Add `delta` to the counter stored as the `key` in the `namespace`.
+    /// An absent or non-numeric value is treated as zero. The expiration time
+    /// of an existing entry is preserved.
+    ///
+    /// # Return
+    ///
+    /// The updated counter value
+    pub fn add_to_counter(&self, namespace: &str, key: &str, delta: u64) -> u64 {
+        let now = unix_now();
+        let id = (namespace.to_string(), key.to_string());
+        let mut state = self.state.lock().unwrap();
+        state.changed.insert(id.clone());
+        let entry = state.entries.entry(id).or_insert_with(|| Entry {
+            value: String::new(),
+            expires_at: None,
+        });
+        if entry.is_expired(now) {
+            entry.value.clear();
+            entry.expires_at = None;
+        }
+
+        let counter = entry
+            .value
+            .parse::<u64>()
+            .unwrap_or(0)
+            .saturating_add(delta);
+        entry.value = counter.to_string();
+        counter
+    }
+
+    /// Get all the alive entries of the `namespace`
+    pub fn entries(&self, namespace: &str) -> Vec<(String, String)> {
+        let now = unix_now();
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter(|((ns, _), entry)| ns == namespace && !entry.is_expired(now))
+            .map(|((_, key), entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    /// Write the entries changed since the last checkpoint to the database in case there are
+    /// any. The expired entries are purged.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let now = unix_now();
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            if state.changed.is_empty() {
+                return Ok(());
+            }
+            state.entries.retain(|_, x| !x.is_expired(now));
+            let changed = std::mem::take(&mut state.changed);
+            changed
+                .into_iter()
+                .map(|id| {
+                    let entry = state.entries.get(&id).cloned();
+                    (id, entry)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let pool = self.pool.clone();
+        let ids = changes.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        self.run(async move {
+            let mut transaction = pool.begin().await?;
+            for ((namespace, key), entry) in changes {
+                match entry {
+                    Some(x) => {
+                        sqlx::query(UPSERT_QUERY)
+                            .bind(namespace)
+                            .bind(key)
+                            .bind(x.value)
+                            .bind(x.expires_at.map(|x| x as i64))
+                            .execute(&mut *transaction)
+                            .await?
+                    }
+                    None => {
+                        sqlx::query(DELETE_QUERY)
+                            .bind(namespace)
+                            .bind(key)
+                            .execute(&mut *transaction)
+                            .await?
+                    }
+                };
+            }
+            sqlx::query(PURGE_QUERY)
+                .bind(now as i64)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await
+        })
+        .inspect_err(|_| {
+            // Retry the changes with the next checkpoint
+            self.state.lock().unwrap().changed.extend(ids);
+        })
+    }
+
+    /// Run a database exchange waiting for its completion
+    fn run<T, F>(&self, exchange: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<T, sqlx::Error>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.runtime.as_ref().unwrap().spawn(async move {
+            let _ = tx.send(exchange.await);
+        });
+        match rx.recv() {
+            Ok(Ok(x)) => Ok(x),
+            Ok(Err(e)) => Err(io::Error::new(ErrorKind::Other, e)),
+            Err(_) => Err(io::Error::new(ErrorKind::Other, "Query was dropped")),
+        }
+    }
+}
+
+impl Drop for StateStore {
+    fn drop(&mut self) {
+        // Dropping a runtime waits for its threads, which is not allowed on the serving ones
+        if let Some(x) = self.runtime.take() {
+            x.shutdown_background();
+        }
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+fn parse_row(row: &sqlx::sqlite::SqliteRow) -> Result<(String, String, Entry), sqlx::Error> {
+    let expires_at = row
+        .try_get::<Option<i64>, _>("expires_at")?
+        .map(|x| u64::try_from(x).unwrap_or_default());
+    Ok((
+        row.try_get("namespace")?,
+        row.try_get("key")?,
+        Entry {
+            value: row.try_get("value")?,
+            expires_at,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn make_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "trusttunnel-state-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut x = path.clone().into_os_string();
+            x.push(suffix);
+            let _ = fs::remove_file(x);
+        }
+        path
+    }
+
+    #[test]
+    fn state_survives_reopening() {
+        let path = make_path("reopen");
+        let store = StateStore::open(&path).unwrap();
+        store.set("bans", "1.2.3.4", "brute force".into(), None);
+        store.set("leases", "a", "10.0.0.2".into(), Some(Duration::ZERO));
+        assert_eq!(2, store.add_to_counter("quota", "alice", 2));
+        store.checkpoint().unwrap();
+        drop(store);
+
+        let store = StateStore::open(&path).unwrap();
+        assert_eq!(Some("brute force".into()), store.get("bans", "1.2.3.4"));
+        assert_eq!(None, store.get("leases", "a"));
+        assert_eq!(5, store.add_to_counter("quota", "alice", 3));
+    }
+
+    #[test]
+    fn checkpoint_writes_changes_only() {
+        let path = make_path("changes");
+        let store = StateStore::open(&path).unwrap();
+        store.set("bans", "x", "1".into(), None);
+        store.set("bans", "y", "2".into(), None);
+        store.checkpoint().unwrap();
+
+        store.remove("bans", "x");
+        store.set("bans", "z", "3".into(), None);
+        store.checkpoint().unwrap();
+        // Not checkpointed
+        store.set("bans", "y", "4".into(), None);
+        drop(store);
+
+        let store = StateStore::open(&path).unwrap();
+        assert_eq!(None, store.get("bans", "x"));
+        assert_eq!(Some("2".into()), store.get("bans", "y"));
+        assert_eq!(Some("3".into()), store.get("bans", "z"));
+    }
+
+    #[test]
+    fn unreadable_database_is_refused() {
+        let path = make_path("garbage");
+        fs::write(&path, "garbage".repeat(1024)).unwrap();
+
+        assert!(StateStore::open(&path).is_err());
+    }
+}
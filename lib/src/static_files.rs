@@ -0,0 +1,392 @@
+use crate::http_codec::{PendingRespond, RequestHeaders, ResponseHeaders};
+use crate::settings::StaticFilesSettings;
+use crate::{log_id, log_utils};
+use bytes::BytesMut;
+use std::io;
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Content types by file extensions
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("webmanifest", "application/manifest+json"),
+];
+
+/// Respond to the request with a file from [`StaticFilesSettings.root`]
+pub(crate) async fn serve(
+    settings: &StaticFilesSettings,
+    request: &RequestHeaders,
+    respond: Box<dyn PendingRespond>,
+    log_id: &log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    if request.method != http::Method::GET && request.method != http::Method::HEAD {
+        return respond.send_bad_response(
+            http::StatusCode::METHOD_NOT_ALLOWED,
+            vec![(http::header::ALLOW.to_string(), "GET, HEAD".to_string())],
+        );
+    }
+
+    let path = match resolve(settings, request.uri.path()).await {
+        Ok(x) => x,
+        Err(Resolved::Redirect(location)) => {
+            return respond.send_bad_response(
+                http::StatusCode::MOVED_PERMANENTLY,
+                vec![(http::header::LOCATION.to_string(), location)],
+            );
+        }
+        Err(Resolved::NotFound) => {
+            log_id!(debug, log_id, "File not found: {}", request.uri.path());
+            return respond.send_bad_response(http::StatusCode::NOT_FOUND, vec![]);
+        }
+    };
+
+    let mut file = tokio::fs::File::open(&path).await?;
+    let metadata = file.metadata().await?;
+    let length = metadata.len();
+    let etag = make_etag(length, metadata.modified().ok());
+    if request
+        .headers
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.split(',').any(|x| x.trim() == etag || x.trim() == "*"))
+    {
+        let response = make_response(request.version, http::StatusCode::NOT_MODIFIED, &etag);
+        return respond.send_response(response, true).map(|_| ());
+    }
+
+    let mut response = make_response(request.version, http::StatusCode::OK, &etag);
+    response.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(content_type(&path)),
+    );
+    if let Some(x) = metadata.modified().ok().and_then(format_http_date) {
+        response.headers.insert(http::header::LAST_MODIFIED, x);
+    }
+
+    let range = match request
+        .headers
+        .get(http::header::RANGE)
+        .and_then(|x| x.to_str().ok())
+        .filter(|_| is_range_applicable(request, &etag))
+        .map(|x| parse_range(x, length))
+    {
+        None | Some(Ok(None)) => 0..length,
+        Some(Ok(Some(x))) => {
+            response.status = http::StatusCode::PARTIAL_CONTENT;
+            set_header(
+                &mut response,
+                http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", x.start, x.end - 1, length),
+            );
+            x
+        }
+        Some(Err(())) => {
+            return respond.send_bad_response(
+                http::StatusCode::RANGE_NOT_SATISFIABLE,
+                vec![(
+                    http::header::CONTENT_RANGE.to_string(),
+                    format!("bytes */{}", length),
+                )],
+            );
+        }
+    };
+    set_header(
+        &mut response,
+        http::header::CONTENT_LENGTH,
+        (range.end - range.start).to_string(),
+    );
+
+    let is_head = request.method == http::Method::HEAD;
+    let mut body = respond.send_response(response, is_head)?.into_body_writer();
+    if is_head {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let mut remaining = range.end - range.start;
+    while remaining > 0 {
+        // Do not read ahead of what the client is ready to receive
+        let granted = body.grant(READ_CHUNK_SIZE.min(remaining as usize)).await?;
+        let mut buffer = BytesMut::zeroed(granted);
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        buffer.truncate(n);
+        body.write(buffer.freeze()).await?;
+        remaining -= n as u64;
+    }
+
+    body.finish()
+}
+
+enum Resolved {
+    NotFound,
+    /// A directory is requested without the trailing slash
+    Redirect(String),
+}
+
+/// Map the request path onto a file inside the root directory
+async fn resolve(settings: &StaticFilesSettings, request_path: &str) -> Result<PathBuf, Resolved> {
+    let root = tokio::fs::canonicalize(&settings.root)
+        .await
+        .map_err(|_| Resolved::NotFound)?;
+    let relative = to_relative_path(request_path).ok_or(Resolved::NotFound)?;
+
+    let mut path = tokio::fs::canonicalize(root.join(relative))
+        .await
+        .map_err(|_| Resolved::NotFound)?;
+    // Symbolic links must not lead out of the root directory
+    if !path.starts_with(&root) {
+        return Err(Resolved::NotFound);
+    }
+
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| Resolved::NotFound)?;
+    if metadata.is_dir() {
+        if !request_path.ends_with('/') {
+            return Err(Resolved::Redirect(directory_location(request_path)));
+        }
+        path = find_index_file(&path, &settings.index_files, &root)
+            .await
+            .ok_or(Resolved::NotFound)?;
+    }
+
+    Ok(path)
+}
+
+/// Make the location of a directory requested without the trailing slash.
+/// The leading slashes are collapsed, as the location starting with two of them is
+/// a reference to another host.
+fn directory_location(request_path: &str) -> String {
+    format!("/{}/", request_path.trim_start_matches('/'))
+}
+
+async fn find_index_file(dir: &Path, index_files: &[String], root: &Path) -> Option<PathBuf> {
+    for name in index_files {
+        let Ok(path) = tokio::fs::canonicalize(dir.join(name)).await else {
+            continue;
+        };
+        // Symbolic links must not lead out of the root directory
+        if path.starts_with(root) && tokio::fs::metadata(&path).await.is_ok_and(|x| x.is_file()) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// Decode the request path into a relative file system path.
+/// Returns `None` in case the path contains parent directory references or other
+/// suspicious components.
+fn to_relative_path(request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(request_path)
+        .decode_utf8()
+        .ok()?;
+    let mut path = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return None,
+            x if x.contains(['\\', '\0']) => return None,
+            x => path.push(x),
+        }
+    }
+    Some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .and_then(|ext| {
+            CONTENT_TYPES
+                .iter()
+                .find(|(x, _)| x.eq_ignore_ascii_case(ext))
+                .map(|(_, x)| *x)
+        })
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+}
+
+fn make_etag(length: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", length, modified)
+}
+
+fn format_http_date(x: SystemTime) -> Option<http::HeaderValue> {
+    chrono::DateTime::<chrono::Utc>::from(x)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+        .parse()
+        .ok()
+}
+
+fn make_response(version: http::Version, status: http::StatusCode, etag: &str) -> ResponseHeaders {
+    let mut response = http::Response::builder()
+        .version(version)
+        .status(status)
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    set_header(&mut response, http::header::ETAG, etag.to_string());
+    response
+}
+
+fn set_header(response: &mut ResponseHeaders, name: http::HeaderName, value: String) {
+    if let Ok(x) = value.parse() {
+        response.headers.insert(name, x);
+    }
+}
+
+/// The range is ignored in case `If-Range` does not match the current version of the file
+fn is_range_applicable(request: &RequestHeaders, etag: &str) -> bool {
+    request
+        .headers
+        .get(http::header::IF_RANGE)
+        .is_none_or(|x| x.to_str().is_ok_and(|x| x == etag))
+}
+
+/// Parse the `Range` header value against the file of `length` bytes.
+/// Only single byte ranges are supported, others are ignored with the full content served.
+///
+/// # Return
+///
+/// * `Ok(Some(range))` if the range is satisfiable
+/// * `Ok(None)` if the header should be ignored
+/// * `Err(())` if the range is not satisfiable
+fn parse_range(header: &str, length: u64) -> Result<Option<Range<u64>>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(x) if !x.contains(',') => x.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(length),
+        (Ok(start), Err(_)) if end.is_empty() => start..length,
+        (Err(_), Ok(suffix)) if start.is_empty() => length.saturating_sub(suffix)..length,
+        _ => return Ok(None),
+    };
+    if range.start >= length || range.is_empty() {
+        return Err(());
+    }
+
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(Ok(Some(0..100)), parse_range("bytes=0-99", 1000));
+        assert_eq!(Ok(Some(900..1000)), parse_range("bytes=900-", 1000));
+        assert_eq!(Ok(Some(950..1000)), parse_range("bytes=-50", 1000));
+        assert_eq!(Ok(Some(990..1000)), parse_range("bytes=990-2000", 1000));
+        assert_eq!(Err(()), parse_range("bytes=1000-", 1000));
+        assert_eq!(Ok(None), parse_range("bytes=0-1,5-6", 1000));
+        assert_eq!(Ok(None), parse_range("items=0-1", 1000));
+        assert_eq!(Ok(None), parse_range("bytes=5-1", 1000));
+    }
+
+    #[test]
+    fn request_paths() {
+        assert_eq!(
+            Some(PathBuf::from("a/b c.html")),
+            to_relative_path("/a/./b%20c.html")
+        );
+        assert_eq!(Some(PathBuf::new()), to_relative_path("/"));
+        assert_eq!(None, to_relative_path("/a/../../etc/passwd"));
+        assert_eq!(None, to_relative_path("/%2e%2e/etc/passwd"));
+        assert_eq!(None, to_relative_path("/a%5c..%5cb"));
+        assert_eq!(None, to_relative_path("/a%ff"));
+        // Not an escape sequence
+        assert_eq!(Some(PathBuf::from("a%zz")), to_relative_path("/a%zz"));
+    }
+
+    #[test]
+    fn directory_locations() {
+        assert_eq!("/a/b/", directory_location("/a/b"));
+        assert_eq!("/evil.example/", directory_location("//evil.example"));
+        assert_eq!("/evil.example/a/", directory_location("///evil.example/a"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn index_file_out_of_root_is_not_served() {
+        let base = std::env::temp_dir().join(format!("static-files-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        std::fs::create_dir_all(root.join("linked")).unwrap();
+        std::fs::create_dir_all(root.join("served")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret.html"), "secret").unwrap();
+        std::fs::write(root.join("served/index.html"), "index").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.html"), root.join("linked/index.html"))
+            .unwrap();
+
+        let settings = StaticFilesSettings::builder(root.to_str().unwrap())
+            .build()
+            .unwrap();
+        assert!(matches!(
+            resolve(&settings, "/linked/").await,
+            Err(Resolved::NotFound)
+        ));
+        assert_eq!(
+            resolve(&settings, "/served/").await.ok(),
+            std::fs::canonicalize(root.join("served/index.html")).ok()
+        );
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(
+            "text/html; charset=utf-8",
+            content_type(Path::new("index.HTML"))
+        );
+        assert_eq!("font/woff2", content_type(Path::new("a/b.woff2")));
+        assert_eq!(DEFAULT_CONTENT_TYPE, content_type(Path::new("Makefile")));
+    }
+}
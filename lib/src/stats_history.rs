@@ -0,0 +1,242 @@
+use crate::metrics::{Metrics, MetricsSnapshot};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The aggregation period of the samples
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the aggregates of the endpoint activity for the recent period
+pub(crate) struct StatsHistory {
+    samples: Mutex<VecDeque<StatsSample>>,
+    capacity: usize,
+}
+
+/// The endpoint activity over a single [`SAMPLE_INTERVAL`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct StatsSample {
+    /// The end of the interval (seconds since the UNIX epoch)
+    pub timestamp: u64,
+    /// The number of the active client sessions at the end of the interval
+    pub active_sessions: i64,
+    /// The number of the client sessions opened during the interval
+    pub new_sessions: u64,
+    /// The number of bytes uploaded by the clients during the interval
+    pub inbound_bytes: u64,
+    /// The number of bytes downloaded by the clients during the interval
+    pub outbound_bytes: u64,
+    /// The number of the tunnel requests failed during the interval
+    pub errors: u64,
+    /// The CPU time consumed by the process relative to the interval (may exceed
+    /// 100 on multi-core systems)
+    pub cpu_percent: f64,
+}
+
+impl StatsHistory {
+    /// Create a history of `period` length
+    pub fn new(period: Duration) -> Self {
+        let capacity = (period.as_secs() / SAMPLE_INTERVAL.as_secs()) as usize;
+        Self {
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&self, sample: StatsSample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        if self.capacity > 0 {
+            samples.push_back(sample);
+        }
+    }
+
+    /// Get up to `n` latest samples in chronological order
+    pub fn last(&self, n: usize) -> Vec<StatsSample> {
+        let samples = self.samples.lock().unwrap();
+        samples
+            .iter()
+            .skip(samples.len().saturating_sub(n))
+            .copied()
+            .collect()
+    }
+
+    /// Sample the metrics each [`SAMPLE_INTERVAL`] until cancelled
+    pub async fn run(&self, metrics: &Metrics) {
+        if !self.is_enabled() {
+            return futures::future::pending().await;
+        }
+
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+
+        let mut prev = metrics.snapshot();
+        let mut prev_cpu_time = process_cpu_time();
+        let mut prev_instant = tokio::time::Instant::now();
+        loop {
+            interval.tick().await;
+            let now = tokio::time::Instant::now();
+            let snapshot = metrics.snapshot();
+            let cpu_time = process_cpu_time();
+
+            self.push(make_sample(
+                &prev,
+                &snapshot,
+                cpu_time.saturating_sub(prev_cpu_time),
+                now.saturating_duration_since(prev_instant),
+            ));
+
+            prev = snapshot;
+            prev_cpu_time = cpu_time;
+            prev_instant = now;
+        }
+    }
+}
+
+impl StatsSample {
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "{{\"timestamp\":{},\"active_sessions\":{},\"new_sessions\":{},\
+            \"inbound_bytes\":{},\"outbound_bytes\":{},\"errors\":{},\"cpu_percent\":{:.1}}}",
+            self.timestamp,
+            self.active_sessions,
+            self.new_sessions,
+            self.inbound_bytes,
+            self.outbound_bytes,
+            self.errors,
+            self.cpu_percent,
+        );
+    }
+}
+
+/// Serialize the samples into a JSON document
+pub(crate) fn to_json(samples: &[StatsSample]) -> String {
+    let mut out = format!(
+        "{{\"interval_secs\":{},\"samples\":[",
+        SAMPLE_INTERVAL.as_secs()
+    );
+    for (i, x) in samples.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        x.write_json(&mut out);
+    }
+    out.push_str("]}\n");
+    out
+}
+
+fn make_sample(
+    prev: &MetricsSnapshot,
+    current: &MetricsSnapshot,
+    cpu_time: Duration,
+    elapsed: Duration,
+) -> StatsSample {
+    StatsSample {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default(),
+        active_sessions: current.active_sessions,
+        new_sessions: current.total_sessions.saturating_sub(prev.total_sessions),
+        inbound_bytes: current.inbound_bytes.saturating_sub(prev.inbound_bytes),
+        outbound_bytes: current.outbound_bytes.saturating_sub(prev.outbound_bytes),
+        errors: current.failed_requests.saturating_sub(prev.failed_requests),
+        cpu_percent: if elapsed.is_zero() {
+            0.0
+        } else {
+            100.0 * cpu_time.as_secs_f64() / elapsed.as_secs_f64()
+        },
+    }
+}
+
+/// The user and system CPU time consumed by the process
+pub(crate) fn process_cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `getrusage` only writes to the passed structure
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return Duration::ZERO;
+    }
+    // SAFETY: the structure is initialized on success
+    let usage = unsafe { usage.assume_init() };
+
+    let to_duration = |x: libc::timeval| Duration::new(x.tv_sec as u64, 1000 * x.tv_usec as u32);
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_samples_are_evicted() {
+        let history = StatsHistory::new(Duration::from_secs(3));
+        for i in 0..5 {
+            history.push(StatsSample {
+                timestamp: i,
+                ..Default::default()
+            });
+        }
+
+        let timestamps = |x: Vec<StatsSample>| x.iter().map(|x| x.timestamp).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3, 4], timestamps(history.last(10)));
+        assert_eq!(vec![3, 4], timestamps(history.last(2)));
+    }
+
+    #[test]
+    fn sample_is_difference_of_snapshots() {
+        let prev = MetricsSnapshot {
+            active_sessions: 3,
+            total_sessions: 10,
+            inbound_bytes: 100,
+            outbound_bytes: 1000,
+            failed_requests: 1,
+        };
+        let current = MetricsSnapshot {
+            active_sessions: 2,
+            total_sessions: 12,
+            inbound_bytes: 150,
+            outbound_bytes: 3000,
+            failed_requests: 1,
+        };
+
+        let sample = make_sample(
+            &prev,
+            &current,
+            Duration::from_millis(250),
+            Duration::from_secs(1),
+        );
+        assert_eq!(2, sample.active_sessions);
+        assert_eq!(2, sample.new_sessions);
+        assert_eq!(50, sample.inbound_bytes);
+        assert_eq!(2000, sample.outbound_bytes);
+        assert_eq!(0, sample.errors);
+        assert_eq!(25.0, sample.cpu_percent);
+    }
+
+    #[test]
+    fn json_output() {
+        let sample = StatsSample {
+            timestamp: 1,
+            active_sessions: 2,
+            new_sessions: 3,
+            inbound_bytes: 4,
+            outbound_bytes: 5,
+            errors: 6,
+            cpu_percent: 7.5,
+        };
+        assert_eq!(
+            "{\"interval_secs\":1,\"samples\":[{\"timestamp\":1,\"active_sessions\":2,\
+            \"new_sessions\":3,\"inbound_bytes\":4,\"outbound_bytes\":5,\"errors\":6,\
+            \"cpu_percent\":7.5}]}\n",
+            to_json(&[sample])
+        );
+    }
+}
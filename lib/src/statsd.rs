@@ -0,0 +1,191 @@
+use crate::core;
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Keeps a datagram within the minimal IPv6 MTU along with the headers
+const MAX_PACKET_SIZE: usize = 1232;
+
+/// Periodically pushes the endpoint metrics to a statsd server
+pub(crate) async fn run(context: Arc<core::Context>) -> io::Result<()> {
+    let settings = match context.settings.statsd.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let mut shutdown_notification = context.shutdown.lock().unwrap().notification_handler();
+
+    let bind_address: SocketAddr = match settings.address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(settings.address).await?;
+
+    let mut encoder = Encoder::new(&settings.prefix);
+    let mut interval = tokio::time::interval(settings.interval);
+    let export = async {
+        loop {
+            interval.tick().await;
+            context
+                .metrics
+                .update_credential_store_up(context.authenticator.as_deref());
+            for packet in encoder.encode(&context.metrics.gather()) {
+                // The server may be temporarily unavailable, which must not break the endpoint
+                if let Err(e) = socket.send(packet.as_bytes()).await {
+                    debug!("Failed to send metrics to statsd server: {}", e);
+                    break;
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
+        }
+        _ = export => Ok(()),
+    }
+}
+
+/// Converts the metrics to the statsd line format.
+/// A label value becomes a component of the metric name, e.g., the `inbound_traffic_bytes`
+/// counter with the `protocol_type="HTTP2"` label is sent as `<prefix>.inbound_traffic_bytes.HTTP2`.
+struct Encoder {
+    prefix: String,
+    /// The counter values sent last time, the statsd counters are sent as increments
+    counters: HashMap<String, f64>,
+}
+
+impl Encoder {
+    fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Make the datagrams to send
+    fn encode(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut packets = vec![];
+        let mut packet = String::new();
+        let mut line = String::new();
+
+        for family in families {
+            let (kind, is_counter) = match family.get_field_type() {
+                MetricType::COUNTER => ("c", true),
+                MetricType::GAUGE => ("g", false),
+                _ => continue,
+            };
+
+            for metric in family.get_metric() {
+                let mut name = self.prefix.clone();
+                for x in [family.name()]
+                    .into_iter()
+                    .chain(metric.get_label().iter().map(|x| x.value()))
+                {
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.extend(x.chars().map(sanitize));
+                }
+
+                let value = if is_counter {
+                    let value = metric.get_counter().value();
+                    let last = self.counters.insert(name.clone(), value).unwrap_or(0.0);
+                    // A counter can only go down on a restart of the exporter
+                    (value - last).max(0.0)
+                } else {
+                    metric.get_gauge().value()
+                };
+
+                line.clear();
+                let _ = write!(line, "{}:{}|{}", name, value, kind);
+                if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                    packets.push(std::mem::take(&mut packet));
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+        }
+
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+        packets
+    }
+}
+
+/// The statsd protocol reserves `:`, `|` and `@`, and `.` separates the name components
+fn sanitize(c: char) -> char {
+    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+        c
+    } else {
+        '_'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_families() -> (prometheus::Registry, prometheus::IntCounterVec) {
+        let registry = prometheus::Registry::new();
+        let counter = prometheus::register_int_counter_vec_with_registry!(
+            "traffic_bytes",
+            "-",
+            &["protocol_type"],
+            registry,
+        )
+        .unwrap();
+        let gauge =
+            prometheus::register_int_gauge_with_registry!("sockets", "-", registry).unwrap();
+        gauge.set(3);
+        (registry, counter)
+    }
+
+    #[test]
+    fn counters_are_sent_as_increments() {
+        let (registry, counter) = make_families();
+        let mut encoder = Encoder::new("tt");
+
+        counter.with_label_values(&["HTTP2"]).inc_by(10);
+        assert_eq!(
+            vec!["tt.sockets:3|g\ntt.traffic_bytes.HTTP2:10|c".to_string()],
+            encoder.encode(&registry.gather())
+        );
+
+        counter.with_label_values(&["HTTP2"]).inc_by(5);
+        counter.with_label_values(&["HTTP/3"]).inc_by(1);
+        assert_eq!(
+            vec![
+                "tt.sockets:3|g\ntt.traffic_bytes.HTTP_3:1|c\ntt.traffic_bytes.HTTP2:5|c"
+                    .to_string()
+            ],
+            encoder.encode(&registry.gather())
+        );
+    }
+
+    #[test]
+    fn packets_are_split() {
+        let (registry, counter) = make_families();
+        for i in 0..100 {
+            counter.with_label_values(&[&i.to_string()]).inc();
+        }
+
+        let packets = Encoder::new("").encode(&registry.gather());
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|x| x.len() <= MAX_PACKET_SIZE));
+        assert_eq!(
+            101,
+            packets.iter().map(|x| x.lines().count()).sum::<usize>()
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! The report of what an endpoint instance came up with: the bound listeners, the loaded
+//! certificates, the enabled subsystems and the compiled in features. The summary is logged
+//! on start, and the report is kept in the status file (see [`Settings::status_file`])
+//! in JSON format, so the orchestration is able to verify the instance is set up as intended.
+//! The file is rewritten once a listener is bound and once the TLS hosts are reloaded.
+//! The report starts with the [instance](crate::instance) identity, so the descriptors
+//! of the instances collected by the orchestration are told apart across the restarts.
+
+use crate::instance::Instance;
+use crate::settings::{ForwardProtocolSettings, Settings, TlsHostInfo, TlsHostsSettings};
+use crate::utils;
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) struct StatusReport {
+    path: Option<String>,
+    status: Mutex<Status>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    instance_id: String,
+    epoch: u64,
+    version: &'static str,
+    pid: u32,
+    started_at: u64,
+    updated_at: u64,
+    listeners: Vec<Listener>,
+    certificates: Vec<CertificateInfo>,
+    subsystems: Vec<&'static str>,
+    features: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Listener {
+    name: &'static str,
+    protocol: &'static str,
+    address: SocketAddr,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct CertificateInfo {
+    /// The section of the TLS hosts settings the host is listed in
+    pub role: &'static str,
+    pub hostname: String,
+    pub path: String,
+    /// The expiration time of the leaf certificate as a UNIX timestamp,
+    /// [`None`] if the chain could not be parsed
+    pub not_after: Option<i64>,
+}
+
+impl StatusReport {
+    pub fn new(settings: &Settings, tls_hosts: &TlsHostsSettings, instance: &Instance) -> Self {
+        let now = unix_now();
+        let status = Status {
+            instance_id: instance.id.clone(),
+            epoch: instance.epoch,
+            version: env!("CARGO_PKG_VERSION"),
+            pid: std::process::id(),
+            started_at: now,
+            updated_at: now,
+            listeners: Default::default(),
+            certificates: certificates(tls_hosts),
+            subsystems: subsystems(settings),
+            features: features(),
+        };
+        info!(
+            "Starting version {} as instance {} epoch {} with subsystems [{}] and features [{}]",
+            status.version,
+            status.instance_id,
+            status.epoch,
+            status.subsystems.join(", "),
+            status.features.join(", ")
+        );
+        log_certificates(&status.certificates);
+
+        Self {
+            path: settings.status_file.clone(),
+            status: Mutex::new(status),
+        }
+    }
+
+    /// Write the report to the status file if it is configured
+    pub fn flush(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.updated_at = unix_now();
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, &status) {
+                warn!("Failed to write status file {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Record the listener bound to the address
+    pub fn listener_bound(&self, name: &'static str, protocol: &'static str, address: SocketAddr) {
+        self.status.lock().unwrap().listeners.push(Listener {
+            name,
+            protocol,
+            address,
+        });
+        self.flush();
+    }
+
+    /// Get the certificates of the TLS hosts in effect
+    pub fn certificates(&self) -> Vec<CertificateInfo> {
+        self.status.lock().unwrap().certificates.clone()
+    }
+
+    /// Replace the certificates with the ones of the reloaded TLS hosts
+    pub fn tls_hosts_reloaded(&self, tls_hosts: &TlsHostsSettings) {
+        let certificates = certificates(tls_hosts);
+        log_certificates(&certificates);
+        self.status.lock().unwrap().certificates = certificates;
+        self.flush();
+    }
+}
+
+fn write(path: &str, status: &Status) -> io::Result<()> {
+    let content = serde_json::to_vec_pretty(status).map_err(io::Error::from)?;
+    // Replace the file at once, so a reader never observes a partially written one
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn certificates(tls_hosts: &TlsHostsSettings) -> Vec<CertificateInfo> {
+    let roles: [(&'static str, &[TlsHostInfo]); 4] = [
+        ("main", &tls_hosts.main_hosts),
+        ("ping", &tls_hosts.ping_hosts),
+        ("speedtest", &tls_hosts.speedtest_hosts),
+        ("reverse_proxy", &tls_hosts.reverse_proxy_hosts),
+    ];
+    roles
+        .into_iter()
+        .flat_map(|(role, hosts)| {
+            hosts.iter().map(move |x| CertificateInfo {
+                role,
+                hostname: x.hostname.clone(),
+                path: x.cert_chain_path.clone(),
+                not_after: not_after(&x.cert_chain_path),
+            })
+        })
+        .collect()
+}
+
+fn not_after(path: &str) -> Option<i64> {
+    let chain = utils::load_certs(path).ok()?;
+    let (_, x) = x509_parser::parse_x509_certificate(&chain.first()?.0).ok()?;
+    Some(x.validity().not_after.timestamp())
+}
+
+fn log_certificates(certificates: &[CertificateInfo]) {
+    for x in certificates {
+        match x
+            .not_after
+            .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+        {
+            Some(t) => info!(
+                "Loaded {} host {} certificate valid till {}",
+                x.role, x.hostname, t
+            ),
+            None => warn!(
+                "Loaded {} host {} certificate of unknown validity: {}",
+                x.role, x.hostname, x.path
+            ),
+        }
+    }
+}
+
+fn subsystems(settings: &Settings) -> Vec<&'static str> {
+    let forwarder = match settings.forward_protocol {
+        ForwardProtocolSettings::Direct(_) => "direct_forwarder",
+        ForwardProtocolSettings::Socks5(_) => "socks5_forwarder",
+    };
+    let reverse_proxy = settings.reverse_proxy.as_ref();
+    [
+        (settings.listen_protocols.http1.is_some(), "http1"),
+        (settings.listen_protocols.http2.is_some(), "http2"),
+        (settings.listen_protocols.quic.is_some(), "quic"),
+        (true, forwarder),
+        (!settings.clients.path.is_empty(), "credentials_file"),
+        (settings.ldap.is_some(), "ldap"),
+        (settings.database.is_some(), "database"),
+        (settings.redis.is_some(), "redis"),
+        (settings.jwt.is_some(), "jwt"),
+        (settings.pam.is_some(), "pam"),
+        (settings.introspection.is_some(), "introspection"),
+        (settings.auth_cache.is_some(), "auth_cache"),
+        (settings.auth_chain.is_some(), "auth_chain"),
+        (settings.auth_lockout.is_some(), "auth_lockout"),
+        (settings.audit_log.is_some(), "audit_log"),
+        (settings.revocation.is_some(), "revocation"),
+        (settings.reconnect_tokens.is_some(), "reconnect_tokens"),
+        (settings.guest.is_some(), "guest"),
+        (settings.client_auth.is_some(), "client_auth"),
+        (reverse_proxy.is_some(), "reverse_proxy"),
+        (
+            reverse_proxy.is_some_and(|x| x.cache.is_some()),
+            "response_cache",
+        ),
+        (settings.icmp.is_some(), "icmp"),
+        (settings.metrics.is_some(), "metrics"),
+        (settings.statsd.is_some(), "statsd"),
+        (settings.rules_engine.is_some(), "rules_engine"),
+        (settings.speedtest_enable, "speedtest"),
+        (!settings.tiers.is_empty(), "tiers"),
+        (!settings.profiles.is_empty(), "profiles"),
+        (settings.duplicate_sessions.is_some(), "duplicate_sessions"),
+        (
+            settings.bandwidth_estimation.is_some(),
+            "bandwidth_estimation",
+        ),
+        (settings.state_store.is_some(), "state_store"),
+        (settings.affinity.is_some(), "affinity"),
+        (settings.policy.is_some(), "policy"),
+        (settings.schedule.is_some(), "schedule"),
+        (settings.http_redirect.is_some(), "http_redirect"),
+        (settings.grpc_admin.is_some(), "grpc_admin"),
+        (settings.exit_policy.is_some(), "exit_policy"),
+        (settings.interception.is_some(), "interception"),
+        (!settings.impairments.is_empty(), "impairments"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, name)| enabled.then_some(name))
+    .collect()
+}
+
+fn features() -> Vec<&'static str> {
+    [
+        (cfg!(feature = "grpc"), "grpc"),
+        (cfg!(feature = "tracing"), "tracing"),
+        (cfg!(feature = "rt_doc"), "rt_doc"),
+        (cfg!(feature = "bench"), "bench"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, name)| enabled.then_some(name))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_status_file() {
+        let path =
+            std::env::temp_dir().join(format!("trusttunnel-status-{}.json", std::process::id()));
+        let mut settings = Settings::default();
+        settings.status_file = Some(path.to_str().unwrap().to_string());
+
+        let instance = Instance::new(None);
+        let report = StatusReport::new(&settings, &TlsHostsSettings::default(), &instance);
+        report.listener_bound("tunnel", "tcp", "127.0.0.1:443".parse().unwrap());
+
+        let status: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(status["pid"], std::process::id());
+        assert_eq!(status["instance_id"], instance.id.as_str());
+        assert_eq!(status["listeners"][0]["address"], "127.0.0.1:443");
+        assert!(status["subsystems"]
+            .as_array()
+            .unwrap()
+            .contains(&"http1".into()));
+    }
+}
@@ -1,16 +1,24 @@
 use crate::forwarder::TcpConnector;
 use crate::metrics::OutboundTcpSocketCounter;
 use crate::net_utils::TcpDestination;
-use crate::{core, forwarder, log_id, log_utils, net_utils, pipe, tunnel};
+use crate::port_blocks::PortLease;
+use crate::settings::{ForwardProtocolSettings, UpstreamHttpProxySettings, UpstreamSocks5Settings};
+use crate::{
+    authentication, core, egress, forwarder, http_connect_client, log_id, log_utils, net_utils,
+    pipe, socks5_client, socks5_forwarder, tunnel, upstream_tls,
+};
 use async_trait::async_trait;
+use base64::Engine;
 use bytes::{Buf, Bytes};
+use std::borrow::Cow;
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 
 pub(crate) struct TcpForwarder {
     context: Arc<core::Context>,
@@ -20,6 +28,7 @@ struct StreamRx {
     rx: OwnedReadHalf,
     id: log_utils::IdChain<u64>,
     _metrics_guard: OutboundTcpSocketCounter,
+    _port_lease: Option<PortLease>,
 }
 
 struct StreamTx {
@@ -41,6 +50,7 @@ impl TcpForwarder {
         stream: TcpStream,
         id: log_utils::IdChain<u64>,
         metrics_guard: OutboundTcpSocketCounter,
+        port_lease: Option<PortLease>,
     ) -> (Box<dyn pipe::Source>, Box<dyn pipe::Sink>) {
         let (rx, tx) = stream.into_split();
         (
@@ -48,6 +58,7 @@ impl TcpForwarder {
                 rx,
                 id: id.clone(),
                 _metrics_guard: metrics_guard,
+                _port_lease: port_lease,
             }),
             Box::new(StreamTx {
                 tx,
@@ -65,9 +76,23 @@ impl TcpConnector for TcpForwarder {
         id: log_utils::IdChain<u64>,
         meta: forwarder::TcpConnectionMeta,
     ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        let is_allowed = |host: Option<&str>, address: &SocketAddr| {
+            meta.destination_acl
+                .as_ref()
+                .is_none_or(|x| x.is_allowed(host, address.ip(), address.port()))
+        };
         let peer = match meta.destination {
             TcpDestination::Address(peer) => {
+                if !is_allowed(None, &peer) {
+                    log_id!(debug, id, "Destination {} denied by client ACL", peer);
+                    return Err(tunnel::ConnectionError::DestinationDenied);
+                }
                 let peer_ip = peer.ip();
+                if !self.context.settings.allow_metadata_endpoint_connections
+                    && net_utils::is_metadata_address(&peer_ip)
+                {
+                    return Err(tunnel::ConnectionError::MetadataEndpoint);
+                }
                 if !self.context.settings.allow_private_network_connections
                     && !net_utils::is_global_ip(&peer_ip)
                 {
@@ -89,6 +114,8 @@ impl TcpConnector for TcpForwarder {
                 enum SelectionStatus {
                     Loopback,
                     NonRoutable,
+                    Metadata,
+                    Denied,
                     Suitable(SocketAddr),
                 }
 
@@ -99,6 +126,19 @@ impl TcpConnector for TcpForwarder {
                         continue;
                     }
 
+                    if !is_allowed(Some(&peer.0), &a) {
+                        status.get_or_insert(SelectionStatus::Denied);
+                        continue;
+                    }
+
+                    // Any name may resolve to a metadata service address
+                    if !self.context.settings.allow_metadata_endpoint_connections
+                        && net_utils::is_metadata_address(&ip)
+                    {
+                        status.get_or_insert(SelectionStatus::Metadata);
+                        continue;
+                    }
+
                     if net_utils::is_global_ip(&ip)
                         || self.context.settings.allow_private_network_connections
                     {
@@ -127,6 +167,13 @@ impl TcpConnector for TcpForwarder {
                     Some(SelectionStatus::NonRoutable) => {
                         return Err(tunnel::ConnectionError::DnsNonroutable)
                     }
+                    Some(SelectionStatus::Metadata) => {
+                        return Err(tunnel::ConnectionError::MetadataEndpoint)
+                    }
+                    Some(SelectionStatus::Denied) => {
+                        log_id!(debug, id, "Destination {} denied by client ACL", peer.0);
+                        return Err(tunnel::ConnectionError::DestinationDenied);
+                    }
                     Some(SelectionStatus::Suitable(x)) => {
                         log_id!(trace, id, "Selected address: {}", x);
                         x
@@ -135,27 +182,254 @@ impl TcpConnector for TcpForwarder {
             }
         };
 
-        log_id!(trace, id, "Connecting to peer: {}", peer);
-        let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
-        TcpStream::connect(peer)
-            .await
-            .and_then(|s| {
-                s.set_nodelay(true)?;
-                Ok(s)
-            })
-            .map(|s| {
-                if let Ok(local_addr) = s.local_addr() {
-                    log_id!(
-                        trace,
-                        id,
-                        "Connection established, local port: {}",
-                        local_addr.port()
-                    );
+        if let Some(x) = &self.context.connect_rate {
+            match x.reserve(peer.ip(), std::time::Instant::now()) {
+                Ok(wait) if wait.is_zero() => (),
+                Ok(wait) => {
+                    log_id!(trace, id, "Pacing connection to {} for {:?}", peer, wait);
+                    tokio::time::sleep(wait).await;
                 }
-                TcpForwarder::pipe_from_stream(s, id, metrics_guard)
-            })
-            .map_err(io_to_connection_error)
+                Err(e) => {
+                    log_id!(debug, id, "{}", e);
+                    return Err(tunnel::ConnectionError::Other(e.to_string()));
+                }
+            }
+        }
+
+        if let ForwardProtocolSettings::Direct(x) = &self.context.settings.forward_protocol {
+            if let Some(proxy) = &x.upstream_socks5 {
+                return self.connect_through_socks5(id, proxy, peer).await;
+            }
+            if let Some(proxy) = &x.upstream_http {
+                return self.connect_through_http(id, proxy, peer).await;
+            }
+        }
+
+        // The uplinks take the place of the egress addresses and the source port blocks
+        let uplinks = self
+            .context
+            .uplinks
+            .as_ref()
+            .map(|x| (x, x.candidates(&meta.race_uplinks)))
+            .filter(|(_, candidates)| !candidates.is_empty());
+        if let Some((uplinks, candidates)) = uplinks {
+            let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
+            let (stream, uplink) = uplinks
+                .race(
+                    &self.context.metrics,
+                    &candidates,
+                    peer,
+                    self.context.settings.tcp_max_segment_size,
+                )
+                .await
+                .map_err(io_to_connection_error)?;
+            log_id!(trace, id, "Connection established over uplink {}", uplink);
+            stream.set_nodelay(true).map_err(io_to_connection_error)?;
+            return Ok(TcpForwarder::pipe_from_stream(
+                stream,
+                id,
+                metrics_guard,
+                None,
+            ));
+        }
+
+        let egress_address = egress::select(&self.context, meta.auth.as_ref(), peer.ip());
+        log_id!(
+            trace,
+            id,
+            "Connecting to peer: {} (source address: {:?})",
+            peer,
+            egress_address
+        );
+        let port_lease = match (
+            self.context.port_blocks.as_ref(),
+            meta.auth
+                .as_ref()
+                .and_then(authentication::Source::username),
+        ) {
+            (Some(blocks), Some(username)) => match blocks.lease(egress_address, &username) {
+                Some(x) => {
+                    log_id!(trace, id, "Source port block: {:?}", x.ports);
+                    Some(x)
+                }
+                None => {
+                    log_id!(debug, id, "No free source port block for {}", username);
+                    return Err(tunnel::ConnectionError::Other(
+                        "No free source port block".to_string(),
+                    ));
+                }
+            },
+            _ => None,
+        };
+        let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
+        connect(
+            peer,
+            egress_address,
+            port_lease.as_ref(),
+            self.context.settings.tcp_max_segment_size,
+        )
+        .await
+        .and_then(|s| {
+            s.set_nodelay(true)?;
+            Ok(s)
+        })
+        .map(|s| {
+            if let Ok(local_addr) = s.local_addr() {
+                log_id!(
+                    trace,
+                    id,
+                    "Connection established, local port: {}",
+                    local_addr.port()
+                );
+            }
+            TcpForwarder::pipe_from_stream(s, id, metrics_guard, port_lease)
+        })
+        .map_err(io_to_connection_error)
+    }
+}
+
+impl TcpForwarder {
+    /// Make the connection to the `peer` through the upstream SOCKS5 proxy.
+    /// The egress addresses and the source port blocks do not apply, as the connection
+    /// leaves from the proxy.
+    async fn connect_through_socks5(
+        &self,
+        id: log_utils::IdChain<u64>,
+        proxy: &UpstreamSocks5Settings,
+        peer: SocketAddr,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        log_id!(
+            trace,
+            id,
+            "Connecting to peer: {} through SOCKS5 proxy {}",
+            peer,
+            proxy.address
+        );
+        let stream = self.connect_proxy(proxy.address).await?;
+
+        let auth = proxy.username.as_deref().map(|x| {
+            socks5_client::Authentication::UsernamePassword(
+                Cow::Borrowed(x),
+                Cow::Borrowed(proxy.password.as_deref().unwrap_or_default()),
+            )
+        });
+        let request = socks5_client::Request::Connect(
+            socks5_client::Address::IpAddress(peer.ip()),
+            peer.port(),
+        );
+        let result = match socks5_client::connect(stream, auth, request).await {
+            // The credentials are the endpoint's own, not the client's
+            Err(socks5_client::Error::Authentication(x)) => {
+                log_id!(
+                    debug,
+                    id,
+                    "Upstream SOCKS5 proxy rejected credentials: {}",
+                    x
+                );
+                return Err(tunnel::ConnectionError::Other(
+                    "Upstream proxy authentication failed".to_string(),
+                ));
+            }
+            x => x,
+        };
+        let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
+        socks5_forwarder::into_pipe(result, |x| {
+            TcpForwarder::pipe_from_stream(x, id, metrics_guard, None)
+        })
+    }
+
+    /// Tunnel the connection to the `peer` through the upstream HTTP proxy with
+    /// a `CONNECT` request. The egress addresses and the source port blocks do not apply,
+    /// as the connection leaves from the proxy.
+    async fn connect_through_http(
+        &self,
+        id: log_utils::IdChain<u64>,
+        proxy: &UpstreamHttpProxySettings,
+        peer: SocketAddr,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        log_id!(
+            trace,
+            id,
+            "Connecting to peer: {} through HTTP proxy {}",
+            peer,
+            proxy.address
+        );
+        let stream = self.connect_proxy(proxy.address).await?;
+
+        let authorization = proxy.username.as_ref().map(|x| {
+            let credentials = format!("{}:{}", x, proxy.password.as_deref().unwrap_or_default());
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        });
+        let authority = peer.to_string();
+        let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
+        match &self.context.upstream_http_tls {
+            None => http_connect_client::connect(stream, &authority, authorization.as_deref())
+                .await
+                .map(|x| upstream_tls::pipe_from_stream(x, id.clone(), metrics_guard)),
+            Some(tls) => {
+                let stream = tls
+                    .connect(stream, proxy.address)
+                    .await
+                    .map_err(io_to_connection_error)?;
+                http_connect_client::connect(stream, &authority, authorization.as_deref())
+                    .await
+                    .map(|x| upstream_tls::pipe_from_stream(x, id.clone(), metrics_guard))
+            }
+        }
+        .map_err(|e| {
+            log_id!(debug, id, "Upstream HTTP proxy refused tunnel: {}", e);
+            http_connect_to_connection_error(e)
+        })
+    }
+
+    /// Make the connection to an upstream proxy of the forwarder
+    async fn connect_proxy(
+        &self,
+        address: SocketAddr,
+    ) -> Result<TcpStream, tunnel::ConnectionError> {
+        connect(
+            address,
+            None,
+            None,
+            self.context.settings.tcp_max_segment_size,
+        )
+        .await
+        .and_then(|s| {
+            s.set_nodelay(true)?;
+            Ok(s)
+        })
+        .map_err(io_to_connection_error)
+    }
+}
+
+async fn connect(
+    peer: SocketAddr,
+    source: Option<IpAddr>,
+    port_lease: Option<&PortLease>,
+    max_segment_size: Option<u16>,
+) -> io::Result<TcpStream> {
+    let prepare = |socket: &TcpSocket| {
+        if let Some(x) = max_segment_size {
+            net_utils::set_tcp_max_segment_size(socket.as_raw_fd(), x)?;
+        }
+        Ok(())
+    };
+    if let Some(x) = port_lease {
+        return x.connect_tcp(peer, prepare).await;
+    }
+
+    let socket = match peer {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(x) = source {
+        socket.bind((x, 0).into())?;
     }
+    prepare(&socket)?;
+    socket.connect(peer).await
 }
 
 #[async_trait]
@@ -251,9 +525,23 @@ fn io_to_connection_error(error: io::Error) -> tunnel::ConnectionError {
     tunnel::ConnectionError::Io(error)
 }
 
+fn http_connect_to_connection_error(error: http_connect_client::Error) -> tunnel::ConnectionError {
+    match error {
+        http_connect_client::Error::Io(x) => io_to_connection_error(x),
+        http_connect_client::Error::Status(502) => tunnel::ConnectionError::HostUnreachable,
+        http_connect_client::Error::Status(504) => tunnel::ConnectionError::Timeout,
+        // The credentials are the endpoint's own, not the client's
+        http_connect_client::Error::Status(407) => {
+            tunnel::ConnectionError::Other("Upstream proxy authentication failed".to_string())
+        }
+        x => tunnel::ConnectionError::Other(x.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::authentication::destination_acl::DestinationAcl;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
     fn make_test_context_disallow_private_network() -> Arc<core::Context> {
@@ -275,6 +563,8 @@ mod tests {
             auth: None,
             tls_domain: String::new(),
             user_agent: None,
+            destination_acl: None,
+            race_uplinks: vec![],
         };
 
         let err = match connector.connect(log_utils::IdChain::empty(), meta).await {
@@ -299,6 +589,8 @@ mod tests {
             auth: None,
             tls_domain: String::new(),
             user_agent: None,
+            destination_acl: None,
+            race_uplinks: vec![],
         };
 
         let err = match connector.connect(log_utils::IdChain::empty(), meta).await {
@@ -308,4 +600,124 @@ mod tests {
 
         assert!(matches!(err, tunnel::ConnectionError::DnsNonroutable));
     }
+
+    #[tokio::test]
+    async fn test_connect_denies_metadata_address_when_private_network_allowed() {
+        let context = Arc::new(core::Context::default());
+        assert!(context.settings.allow_private_network_connections);
+        let connector: Box<dyn TcpConnector> = Box::new(TcpForwarder::new(context));
+
+        let meta = forwarder::TcpConnectionMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            destination: TcpDestination::Address(SocketAddr::from((
+                Ipv4Addr::new(169, 254, 169, 254),
+                80,
+            ))),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+            destination_acl: None,
+            race_uplinks: vec![],
+        };
+
+        let err = match connector.connect(log_utils::IdChain::empty(), meta).await {
+            Ok(_) => panic!("Expected connection to be denied"),
+            Err(e) => e,
+        };
+
+        assert!(matches!(err, tunnel::ConnectionError::MetadataEndpoint));
+    }
+
+    #[tokio::test]
+    async fn test_connect_denies_destination_outside_client_acl() {
+        let context = Arc::new(core::Context::default());
+        let connector: Box<dyn TcpConnector> = Box::new(TcpForwarder::new(context));
+        let acl = DestinationAcl::new(&["10.0.0.0/8:80".to_string()], &[]).unwrap();
+
+        let meta = forwarder::TcpConnectionMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            destination: TcpDestination::Address(SocketAddr::from((
+                Ipv4Addr::new(192, 0, 2, 1),
+                80,
+            ))),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+            destination_acl: Some(Arc::new(acl)),
+            race_uplinks: vec![],
+        };
+
+        let err = match connector.connect(log_utils::IdChain::empty(), meta).await {
+            Ok(_) => panic!("Expected connection to be denied"),
+            Err(e) => e,
+        };
+
+        assert!(matches!(err, tunnel::ConnectionError::DestinationDenied));
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_upstream_socks5_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut ctx = core::Context::default();
+        Arc::get_mut(&mut ctx.settings).unwrap().forward_protocol = ForwardProtocolSettings::Direct(
+            crate::settings::DirectForwarderSettings::builder()
+                .upstream_socks5(
+                    UpstreamSocks5Settings::builder()
+                        .address(listener.local_addr().unwrap())
+                        .credentials("alice", "secret")
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap(),
+        );
+        let connector: Box<dyn TcpConnector> = Box::new(TcpForwarder::new(Arc::new(ctx)));
+
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&2));
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 1 + 1 + 5 + 1 + 6];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(b"\x01\x05alice\x06secret", &auth);
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!([5, 1, 0, 1, 192, 0, 2, 1, 0, 80], request);
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let meta = forwarder::TcpConnectionMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            destination: TcpDestination::Address(SocketAddr::from((
+                Ipv4Addr::new(192, 0, 2, 1),
+                80,
+            ))),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+            destination_acl: None,
+            race_uplinks: vec![],
+        };
+        let (mut source, _sink) = match connector.connect(log_utils::IdChain::empty(), meta).await {
+            Ok(x) => x,
+            Err(e) => panic!("Expected connection through proxy: {}", e),
+        };
+        proxy.await.unwrap();
+
+        match source.read().await.unwrap() {
+            pipe::Data::Chunk(x) => assert_eq!("hello", x),
+            pipe::Data::Eof => panic!("Unexpected EOF"),
+        }
+    }
 }
@@ -0,0 +1,507 @@
+use crate::settings::TierSettings;
+use crate::{log_utils, pipe};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The tier applied to the clients which are not assigned to any tier explicitly
+pub(crate) const DEFAULT_TIER_NAME: &str = "default";
+
+/// Keeps track of the active sessions of the quality of service tiers
+pub(crate) struct TierRegistry {
+    tiers: HashMap<String, Tier>,
+    total_sessions: Arc<AtomicUsize>,
+    /// The number of the sessions above which every tier is shed,
+    /// in case no client is left unrestricted
+    shed_all_above: Option<usize>,
+    /// The rate limits of the sessions keyed by the tier and the client identity
+    rate_limits: Mutex<HashMap<(String, String), Weak<RateLimits>>>,
+}
+
+struct Tier {
+    settings: TierSettings,
+    active_sessions: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+pub(crate) enum AdmissionError {
+    /// The tier has reached its concurrent sessions limit
+    SessionsLimit(String),
+    /// The endpoint is loaded enough for the tier to be shed
+    Shed(String),
+}
+
+/// Occupies a session slot until dropped
+pub(crate) struct SessionPermit {
+    total_sessions: Arc<AtomicUsize>,
+    tier_sessions: Option<Arc<AtomicUsize>>,
+    rate_limits: Option<Arc<RateLimits>>,
+    /// Whether the session is admitted to its tier already
+    admitted: bool,
+}
+
+/// The tier rate limit of a client in each direction, shared by the tunneled
+/// connections of all its sessions
+pub(crate) struct RateLimits {
+    outgoing: Mutex<RateLimiter>,
+    incoming: Mutex<RateLimiter>,
+}
+
+/// Applies the tier rate limit to the wrapped source
+struct ThrottledSource {
+    source: Box<dyn pipe::Source>,
+    rate_limits: Arc<RateLimits>,
+    direction: pipe::SimplexDirection,
+}
+
+/// A token bucket allowing bursts of up to one second worth of data
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl Display for AdmissionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SessionsLimit(x) => write!(f, "Sessions limit reached for tier {}", x),
+            Self::Shed(x) => write!(f, "Tier {} is shed due to high load", x),
+        }
+    }
+}
+
+impl TierRegistry {
+    pub fn new(tiers: &HashMap<String, TierSettings>) -> Self {
+        // The clients of no tier are not restricted in case the default one is absent
+        let shed_all_above = tiers
+            .contains_key(DEFAULT_TIER_NAME)
+            .then(|| {
+                tiers
+                    .values()
+                    .map(|x| x.shed_above_sessions)
+                    .collect::<Option<Vec<_>>>()
+            })
+            .flatten()
+            .and_then(|x| x.into_iter().max());
+
+        Self {
+            tiers: tiers
+                .iter()
+                .map(|(name, settings)| {
+                    (
+                        name.clone(),
+                        Tier {
+                            settings: settings.clone(),
+                            active_sessions: Default::default(),
+                        },
+                    )
+                })
+                .collect(),
+            total_sessions: Default::default(),
+            shed_all_above,
+            rate_limits: Default::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    pub fn contains(&self, tier: &str) -> bool {
+        self.tiers.contains_key(tier)
+    }
+
+    /// Check whether the endpoint is loaded enough for any new session to be shed,
+    /// so that the connections are refused before the handshake is spent on them
+    pub fn is_overloaded(&self) -> bool {
+        self.shed_all_above
+            .is_some_and(|x| self.total_sessions.load(Ordering::Acquire) >= x)
+    }
+
+    /// Occupy a session slot for a new session. The session is admitted to its tier
+    /// once its client is known, see [`Self::admit`].
+    pub fn open_session(&self) -> SessionPermit {
+        self.total_sessions.fetch_add(1, Ordering::AcqRel);
+        SessionPermit {
+            total_sessions: self.total_sessions.clone(),
+            tier_sessions: None,
+            rate_limits: None,
+            admitted: false,
+        }
+    }
+
+    /// Try to admit the session of a client of the `tier`. A session is admitted once,
+    /// the later calls keep the tier of the first successful one.
+    /// Unknown tiers are treated like the absent one.
+    /// The sessions of the same `identity` share the tier rate limit, while the ones of
+    /// a client without identity, like a guest, are limited each on its own.
+    pub fn admit(
+        &self,
+        permit: &mut SessionPermit,
+        tier: Option<&str>,
+        identity: Option<&str>,
+    ) -> Result<(), AdmissionError> {
+        if permit.admitted {
+            return Ok(());
+        }
+
+        let (name, tier) = match tier
+            .and_then(|x| self.tiers.get_key_value(x))
+            .or_else(|| self.tiers.get_key_value(DEFAULT_TIER_NAME))
+        {
+            None => {
+                permit.admitted = true;
+                return Ok(());
+            }
+            Some(x) => x,
+        };
+
+        // The session itself is not counted
+        let others = self
+            .total_sessions
+            .load(Ordering::Acquire)
+            .saturating_sub(1);
+        if tier
+            .settings
+            .shed_above_sessions
+            .is_some_and(|x| others >= x)
+        {
+            return Err(AdmissionError::Shed(name.clone()));
+        }
+
+        let active = tier.active_sessions.fetch_add(1, Ordering::AcqRel);
+        if tier.settings.max_sessions.is_some_and(|x| active >= x) {
+            tier.active_sessions.fetch_sub(1, Ordering::AcqRel);
+            return Err(AdmissionError::SessionsLimit(name.clone()));
+        }
+
+        permit.tier_sessions = Some(tier.active_sessions.clone());
+        permit.rate_limits = tier
+            .settings
+            .max_bytes_per_sec
+            .map(|x| self.client_rate_limits(name, identity, x));
+        permit.admitted = true;
+        Ok(())
+    }
+
+    fn client_rate_limits(
+        &self,
+        tier: &str,
+        identity: Option<&str>,
+        bytes_per_sec: u64,
+    ) -> Arc<RateLimits> {
+        let make = || Arc::new(RateLimits::new(bytes_per_sec, Instant::now()));
+        let Some(identity) = identity else {
+            return make();
+        };
+
+        let mut table = self.rate_limits.lock().unwrap();
+        let key = (tier.to_string(), identity.to_string());
+        if let Some(x) = table.get(&key).and_then(Weak::upgrade) {
+            return x;
+        }
+        // Forget the clients which have no sessions left
+        table.retain(|_, x| x.strong_count() > 0);
+        let x = make();
+        table.insert(key, Arc::downgrade(&x));
+        x
+    }
+}
+
+impl SessionPermit {
+    /// Check whether the session is admitted to its tier already
+    pub fn is_admitted(&self) -> bool {
+        self.admitted
+    }
+
+    /// The rate limits of the client of the session, if its tier sets any
+    pub fn rate_limits(&self) -> Option<&Arc<RateLimits>> {
+        self.rate_limits.as_ref()
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.total_sessions.fetch_sub(1, Ordering::AcqRel);
+        if let Some(x) = &self.tier_sessions {
+            x.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Wrap the source of a tunneled connection transferring data in the `direction`
+/// to apply the tier rate limit
+pub(crate) fn throttle(
+    source: Box<dyn pipe::Source>,
+    rate_limits: Option<&Arc<RateLimits>>,
+    direction: pipe::SimplexDirection,
+) -> Box<dyn pipe::Source> {
+    match rate_limits {
+        None => source,
+        Some(x) => Box::new(ThrottledSource {
+            source,
+            rate_limits: x.clone(),
+            direction,
+        }),
+    }
+}
+
+impl RateLimits {
+    fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Self {
+            outgoing: Mutex::new(RateLimiter::new(bytes_per_sec, now)),
+            incoming: Mutex::new(RateLimiter::new(bytes_per_sec, now)),
+        }
+    }
+
+    /// Take `n` bytes transferred in the `direction` out of the bucket.
+    ///
+    /// # Return
+    ///
+    /// The time a caller must wait before passing the data further
+    fn consume(&self, direction: pipe::SimplexDirection, n: usize, now: Instant) -> Duration {
+        let limiter = match direction {
+            pipe::SimplexDirection::Outgoing => &self.outgoing,
+            pipe::SimplexDirection::Incoming => &self.incoming,
+        };
+        limiter.lock().unwrap().consume(n, now)
+    }
+}
+
+#[async_trait]
+impl pipe::Source for ThrottledSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.source.id()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        let data = self.source.read().await?;
+        if let pipe::Data::Chunk(chunk) = &data {
+            let delay = self
+                .rate_limits
+                .consume(self.direction, chunk.len(), Instant::now());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        self.source.consume(size)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last_update: now,
+        }
+    }
+
+    /// Take `n` bytes out of the bucket.
+    ///
+    /// # Return
+    ///
+    /// The time a caller must wait before passing the data further
+    pub fn consume(&mut self, n: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.bytes_per_sec)
+            .min(self.bytes_per_sec)
+            - n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_registry(tiers: &[(&str, TierSettings)]) -> TierRegistry {
+        TierRegistry::new(
+            &tiers
+                .iter()
+                .map(|(name, x)| (name.to_string(), x.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn sessions_limit() {
+        let registry = make_registry(&[(
+            "free",
+            TierSettings::builder().max_sessions(1).build().unwrap(),
+        )]);
+
+        let mut permit = registry.open_session();
+        registry.admit(&mut permit, Some("free"), None).unwrap();
+        let mut second = registry.open_session();
+        assert!(matches!(
+            registry.admit(&mut second, Some("free"), None),
+            Err(AdmissionError::SessionsLimit(_))
+        ));
+        // untiered clients are not restricted
+        let mut untiered = registry.open_session();
+        registry.admit(&mut untiered, None, None).unwrap();
+
+        drop(permit);
+        assert!(registry.admit(&mut second, Some("free"), None).is_ok());
+    }
+
+    #[test]
+    fn session_is_admitted_once() {
+        let registry = make_registry(&[(
+            "free",
+            TierSettings::builder().max_sessions(1).build().unwrap(),
+        )]);
+
+        let mut permit = registry.open_session();
+        for _ in 0..3 {
+            registry.admit(&mut permit, Some("free"), None).unwrap();
+        }
+        assert!(permit.tier_sessions.is_some());
+
+        let mut other = registry.open_session();
+        assert!(registry.admit(&mut other, Some("free"), None).is_err());
+    }
+
+    #[test]
+    fn lower_threshold_tier_is_shed_first() {
+        let registry = make_registry(&[
+            (
+                "free",
+                TierSettings::builder()
+                    .shed_above_sessions(1)
+                    .build()
+                    .unwrap(),
+            ),
+            ("priority", TierSettings::builder().build().unwrap()),
+        ]);
+
+        let mut first = registry.open_session();
+        registry.admit(&mut first, Some("priority"), None).unwrap();
+        let mut second = registry.open_session();
+        assert!(matches!(
+            registry.admit(&mut second, Some("free"), None),
+            Err(AdmissionError::Shed(_))
+        ));
+        assert!(registry.admit(&mut second, Some("priority"), None).is_ok());
+    }
+
+    #[test]
+    fn overloaded_once_every_tier_is_shed() {
+        let shed_above = |x| {
+            TierSettings::builder()
+                .shed_above_sessions(x)
+                .build()
+                .unwrap()
+        };
+
+        let registry =
+            make_registry(&[(DEFAULT_TIER_NAME, shed_above(1)), ("paid", shed_above(2))]);
+        let first = registry.open_session();
+        assert!(!registry.is_overloaded());
+        let second = registry.open_session();
+        assert!(registry.is_overloaded());
+        drop((first, second));
+        assert!(!registry.is_overloaded());
+
+        // the clients of no tier are not restricted without the default one
+        let registry = make_registry(&[("free", shed_above(1))]);
+        let _sessions = [registry.open_session(), registry.open_session()];
+        assert!(!registry.is_overloaded());
+    }
+
+    #[test]
+    fn default_tier_applies_to_unknown_and_absent() {
+        let registry = make_registry(&[(
+            DEFAULT_TIER_NAME,
+            TierSettings::builder().max_sessions(1).build().unwrap(),
+        )]);
+
+        let mut permit = registry.open_session();
+        registry.admit(&mut permit, None, None).unwrap();
+        assert!(permit.tier_sessions.is_some());
+        let mut other = registry.open_session();
+        assert!(registry.admit(&mut other, Some("unknown"), None).is_err());
+    }
+
+    #[test]
+    fn rate_limit_is_shared_by_sessions_of_client() {
+        let registry = make_registry(&[(
+            "free",
+            TierSettings::builder()
+                .max_bytes_per_sec(1000)
+                .build()
+                .unwrap(),
+        )]);
+        let admit = |identity| {
+            let mut permit = registry.open_session();
+            registry.admit(&mut permit, Some("free"), identity).unwrap();
+            permit
+        };
+
+        let (first, second) = (admit(Some("alice")), admit(Some("alice")));
+        assert!(Arc::ptr_eq(
+            first.rate_limits().unwrap(),
+            second.rate_limits().unwrap()
+        ));
+        let other = admit(Some("bob"));
+        assert!(!Arc::ptr_eq(
+            first.rate_limits().unwrap(),
+            other.rate_limits().unwrap()
+        ));
+        let (guest, another_guest) = (admit(None), admit(None));
+        assert!(!Arc::ptr_eq(
+            guest.rate_limits().unwrap(),
+            another_guest.rate_limits().unwrap()
+        ));
+
+        // the directions are limited independently
+        let now = Instant::now();
+        let limits = first.rate_limits().unwrap();
+        assert_eq!(
+            limits.consume(pipe::SimplexDirection::Outgoing, 1000, now),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limits.consume(pipe::SimplexDirection::Incoming, 1000, now),
+            Duration::ZERO
+        );
+        assert_eq!(
+            second
+                .rate_limits()
+                .unwrap()
+                .consume(pipe::SimplexDirection::Outgoing, 500, now),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn rate_limiter_delays_excess() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(1000, start);
+
+        assert_eq!(limiter.consume(1000, start), Duration::ZERO);
+        assert_eq!(limiter.consume(500, start), Duration::from_millis(500));
+        // the debt is repaid in half a second, and then the bucket refills
+        assert_eq!(
+            limiter.consume(250, start + Duration::from_millis(1000)),
+            Duration::ZERO
+        );
+    }
+}
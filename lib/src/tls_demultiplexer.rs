@@ -126,19 +126,19 @@ impl TlsDemux {
         // false-positive
         #[allow(unused_variables)]
         let make_entry = |x: &settings::TlsHostInfo| -> io::Result<(String, Host)> {
-            let cert_chain = if cfg!(test) {
+            let cert_chain = if cfg!(any(test, fuzzing)) {
                 Default::default()
             } else {
                 utils::load_certs(&x.cert_chain_path)?
             };
 
-            let key = if cfg!(test) {
+            let key = if cfg!(any(test, fuzzing)) {
                 PrivateKey(Default::default())
             } else {
                 utils::load_private_key(&x.private_key_path)?
             };
 
-            let boring = if cfg!(test) {
+            let boring = if cfg!(any(test, fuzzing)) {
                 // Create dummy BoringIdentity for tests
                 let rsa = Rsa::generate(2048).unwrap();
                 let pkey = PKey::from_rsa(rsa).unwrap();
@@ -324,6 +324,19 @@ impl TlsDemux {
                 host,
                 None,
             )
+        } else if let Some((host, auth_creds)) = self.main_hosts.iter().find_map(|(name, host)| {
+            // The credentials spanning several labels, e.g., a JSON Web Token
+            sni.strip_suffix(name.as_str())?
+                .strip_suffix('.')
+                .filter(|x| !x.is_empty())
+                .map(|x| (host, x))
+        }) {
+            (
+                self.select_tunnel_channel_protocol(parsed_alpn.iter(), alpn)?,
+                Channel::Tunnel,
+                host,
+                Some(String::from(auth_creds)),
+            )
         } else {
             return Err(format!("Unexpected SNI {}", sni));
         };
@@ -386,6 +399,15 @@ mod tests {
             server_address: "0.0.0.0:0".to_socket_addrs().unwrap().next().unwrap(),
             path_mask: Default::default(),
             h3_backward_compatibility: Default::default(),
+            serve_non_tunnel_requests: false,
+            response_headers: Default::default(),
+            cache: None,
+            static_files: None,
+            mirror: None,
+            maintenance: false,
+            error_pages: None,
+            tls: None,
+            proxy_protocol: false,
         }
     }
 
@@ -699,6 +721,18 @@ mod tests {
             .unwrap();
         assert_eq!(meta.channel, Channel::Tunnel);
         assert_eq!(meta.sni_auth_creds.as_deref(), Some(CREDENTIALS));
+
+        let meta = demux
+            .select(
+                advertised_alpn.clone(),
+                format!("{CREDENTIALS}.{CREDENTIALS}.{TUNNEL_HOST}"),
+            )
+            .unwrap();
+        assert_eq!(meta.channel, Channel::Tunnel);
+        assert_eq!(
+            meta.sni_auth_creds,
+            Some(format!("{CREDENTIALS}.{CREDENTIALS}"))
+        );
     }
 
     #[test]
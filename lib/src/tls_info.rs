@@ -0,0 +1,129 @@
+//! The parameters the TLS handshake of a client connection has settled on. They are logged
+//! with the connection, recorded in the audit log, label the handshake metrics, and are
+//! shown to the authenticator through [`Authenticator::accept_tls`], so that, e.g., some
+//! clients may be required to come over TLS 1.3 only.
+//!
+//! [`Authenticator::accept_tls`]: crate::authentication::Authenticator::accept_tls
+
+use crate::authentication::client_cert;
+use boring::ssl::{NameType, SslRef};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+/// The negotiated parameters of the TLS handshake of a client connection
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TlsInfo {
+    /// The protocol version, e.g., `TLSv1.3`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The IANA name of the cipher suite, e.g., `TLS_AES_128_GCM_SHA256`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher_suite: Option<String>,
+    /// The application protocol selected through ALPN, e.g., `h2`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn: Option<String>,
+    /// The server name the client has sent in the client hello.
+    /// The credentials of the SNI authentication are scrubbed from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// The subject of the certificate the client has presented, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert_subject: Option<String>,
+}
+
+impl TlsInfo {
+    /// Take the parameters of a TCP connection handshake
+    pub(crate) fn from_rustls(conn: &rustls::ServerConnection) -> Self {
+        Self {
+            version: conn.protocol_version().map(|x| match x {
+                rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+                rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+                x => format!("{:?}", x),
+            }),
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .map(|x| iana_suite_name(&format!("{:?}", x.suite()))),
+            alpn: conn
+                .alpn_protocol()
+                .map(|x| String::from_utf8_lossy(x).into_owned()),
+            server_name: conn.server_name().map(str::to_string),
+            client_cert_subject: conn
+                .peer_certificates()
+                .and_then(|x| x.first())
+                .and_then(|x| client_cert::subject(&x.0)),
+        }
+    }
+
+    /// Take the parameters of a QUIC connection handshake
+    pub(crate) fn from_boring(ssl: &SslRef) -> Self {
+        Self {
+            version: Some(ssl.version_str().to_string()),
+            cipher_suite: ssl
+                .current_cipher()
+                .map(|x| x.standard_name().unwrap_or(x.name()).to_string()),
+            alpn: ssl
+                .selected_alpn_protocol()
+                .map(|x| String::from_utf8_lossy(x).into_owned()),
+            server_name: ssl.servername(NameType::HOST_NAME).map(str::to_string),
+            client_cert_subject: ssl
+                .peer_certificate()
+                .and_then(|x| x.to_der().ok())
+                .and_then(|x| client_cert::subject(&x)),
+        }
+    }
+}
+
+impl Display for TlsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let or_none = |x: &Option<String>| x.clone().unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "version={} cipher={} alpn={} sni={} client_cert={}",
+            or_none(&self.version),
+            or_none(&self.cipher_suite),
+            or_none(&self.alpn),
+            or_none(&self.server_name),
+            or_none(&self.client_cert_subject),
+        )
+    }
+}
+
+/// Rustls names the TLS 1.3 suites with its own prefix, e.g., `TLS13_AES_128_GCM_SHA256`
+fn iana_suite_name(x: &str) -> String {
+    match x.strip_prefix("TLS13_") {
+        Some(x) => format!("TLS_{}", x),
+        None => x.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suite_names() {
+        assert_eq!(
+            "TLS_AES_256_GCM_SHA384",
+            iana_suite_name("TLS13_AES_256_GCM_SHA384")
+        );
+        assert_eq!(
+            "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            iana_suite_name("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")
+        );
+    }
+
+    #[test]
+    fn display() {
+        let info = TlsInfo {
+            version: Some("TLSv1.3".to_string()),
+            cipher_suite: Some("TLS_AES_128_GCM_SHA256".to_string()),
+            alpn: Some("h2".to_string()),
+            server_name: Some("vpn.example.org".to_string()),
+            client_cert_subject: None,
+        };
+        assert_eq!(
+            "version=TLSv1.3 cipher=TLS_AES_128_GCM_SHA256 alpn=h2 sni=vpn.example.org client_cert=-",
+            info.to_string()
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use crate::{log_utils, net_utils, tls_demultiplexer};
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use crate::settings::ClientAuthSettings;
+use crate::{log_utils, net_utils, tls_demultiplexer, utils};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -23,8 +28,11 @@ impl TlsListener {
         Self {}
     }
 
-    pub async fn listen(&self, stream: TcpStream) -> io::Result<TlsAcceptor> {
-        let (stream, client_random) = Self::read_client_random_and_wrap_stream(stream).await?;
+    /// Start accepting a connection from the `peer`, which is the address conveyed
+    /// by the PROXY protocol header, if any, rather than the one of the `stream`
+    pub async fn listen(&self, stream: TcpStream, peer: SocketAddr) -> io::Result<TlsAcceptor> {
+        let (stream, client_random) =
+            Self::read_client_random_and_wrap_stream(stream, peer).await?;
 
         // Now let rustls handle the stream normally
         LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream)
@@ -37,6 +45,7 @@ impl TlsListener {
 
     async fn read_client_random_and_wrap_stream(
         mut stream: TcpStream,
+        peer: SocketAddr,
     ) -> io::Result<(PrebufferedTcpStream, Option<Vec<u8>>)> {
         let mut client_random = None;
         let mut prebuffer: Vec<u8> = Vec::new();
@@ -63,7 +72,10 @@ impl TlsListener {
             prebuffer.extend_from_slice(&tmp[..n]);
         }
 
-        Ok((PrebufferedTcpStream::new(prebuffer, stream), client_random))
+        Ok((
+            PrebufferedTcpStream::new(prebuffer, stream, peer),
+            client_random,
+        ))
     }
 
     fn extract_client_random(data: &[u8]) -> ClientRandomExtraction {
@@ -109,6 +121,7 @@ pub(crate) struct PrebufferedTcpStream {
     prebuffer: Vec<u8>,
     prebuffer_pos: usize,
     stream: TcpStream,
+    peer: SocketAddr,
 }
 
 impl std::fmt::Debug for PrebufferedTcpStream {
@@ -121,18 +134,19 @@ impl std::fmt::Debug for PrebufferedTcpStream {
 }
 
 impl PrebufferedTcpStream {
-    fn new(prebuffer: Vec<u8>, stream: TcpStream) -> Self {
+    fn new(prebuffer: Vec<u8>, stream: TcpStream, peer: SocketAddr) -> Self {
         Self {
             prebuffer,
             prebuffer_pos: 0,
             stream,
+            peer,
         }
     }
 }
 
 impl net_utils::PeerAddr for PrebufferedTcpStream {
-    fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
-        self.stream.peer_addr()
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
     }
 }
 
@@ -196,19 +210,21 @@ impl TlsAcceptor {
         protocol: tls_demultiplexer::Protocol,
         cert_chain: Vec<Certificate>,
         key: PrivateKey,
+        client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
         _log_id: &log_utils::IdChain<u64>,
     ) -> io::Result<TlsStream<PrebufferedTcpStream>> {
         let tls_config = {
-            let mut cfg = ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(cert_chain, key)
-                .map_err(|e| {
-                    io::Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to create TLS configuration: {}", e),
-                    )
-                })?;
+            let builder = ServerConfig::builder().with_safe_defaults();
+            let builder = match client_cert_verifier {
+                Some(x) => builder.with_client_cert_verifier(x),
+                None => builder.with_no_client_auth(),
+            };
+            let mut cfg = builder.with_single_cert(cert_chain, key).map_err(|e| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to create TLS configuration: {}", e),
+                )
+            })?;
 
             cfg.alpn_protocols = vec![protocol.as_alpn().as_bytes().to_vec()];
             Arc::new(cfg)
@@ -217,3 +233,27 @@ impl TlsAcceptor {
         self.inner.into_stream(tls_config).await
     }
 }
+
+/// Make the verifier of the client certificates issued by the configured authorities
+pub(crate) fn client_cert_verifier(
+    settings: &ClientAuthSettings,
+) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for x in utils::load_certs(&settings.ca_bundle_path)? {
+        roots
+            .add(&x)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "No certificates in CA bundle",
+        ));
+    }
+
+    Ok(if settings.required {
+        AllowAnyAuthenticatedClient::new(roots).boxed()
+    } else {
+        AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+    })
+}
@@ -0,0 +1,303 @@
+//! The triage of the accepted TCP connections before the TLS handshake. The scanners and
+//! the misconfigured clients often send a plain HTTP request or some garbage to the TLS port,
+//! which would only fail the handshake with a cryptic error. Instead, the first bytes of
+//! a connection are classified, and the connections which do not start with a TLS record
+//! are handled according to [`TriageSettings`].
+
+use crate::settings::{TriageAction, TriageSettings};
+use crate::{http_redirect, log_id, log_utils};
+use socket2::SockRef;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The content type of a TLS handshake record
+const TLS_HANDSHAKE_CONTENT_TYPE: u8 = 0x16;
+/// The longest method token of a request still taken for a plain HTTP one
+const MAX_METHOD_LENGTH: usize = 16;
+/// The longest request head read from a plain HTTP connection
+const MAX_REQUEST_HEAD_LENGTH: usize = 8 * 1024;
+const DECOY_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+    Content-Type: text/html\r\n\
+    Content-Length: 150\r\n\
+    Connection: close\r\n\
+    \r\n\
+    <html>\r\n\
+    <head><title>400 Bad Request</title></head>\r\n\
+    <body>\r\n\
+    <center><h1>400 Bad Request</h1></center>\r\n\
+    <hr><center>nginx</center>\r\n\
+    </body>\r\n\
+    </html>\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Class {
+    Tls,
+    PlainHttp,
+    Garbage,
+}
+
+pub(crate) struct Triage {
+    settings: TriageSettings,
+    /// The number of the connections held in the tarpit
+    tarpitted: AtomicUsize,
+}
+
+/// Classify a connection by its first bytes, which may be as few as one
+pub(crate) fn classify(data: &[u8]) -> Class {
+    if data.first() == Some(&TLS_HANDSHAKE_CONTENT_TYPE) {
+        return Class::Tls;
+    }
+
+    // A method token is followed by a space, e.g., `GET /`, or `PRI *` of the HTTP/2 preface
+    let method = data
+        .iter()
+        .position(|x| *x == b' ')
+        .map_or(data, |x| &data[..x]);
+    let is_method = !method.is_empty()
+        && method.len() <= MAX_METHOD_LENGTH
+        && method.iter().all(u8::is_ascii_uppercase)
+        && (method.len() < data.len() || data.len() < MAX_METHOD_LENGTH);
+    if is_method {
+        Class::PlainHttp
+    } else {
+        Class::Garbage
+    }
+}
+
+impl Triage {
+    pub fn new(settings: TriageSettings) -> Self {
+        Self {
+            settings,
+            tarpitted: Default::default(),
+        }
+    }
+
+    /// Wait for the first bytes of the connection for up to `timeout`.
+    /// Returns the stream back if it is a TLS one, otherwise handles it according to
+    /// the settings and returns [`None`].
+    pub async fn triage(
+        &self,
+        stream: TcpStream,
+        https_port: u16,
+        timeout: Duration,
+        id: &log_utils::IdChain<u64>,
+    ) -> Option<TcpStream> {
+        let mut head = [0; MAX_METHOD_LENGTH];
+        let n = match tokio::time::timeout(timeout, stream.peek(&mut head)).await {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                log_id!(trace, id, "Failed to peek connection: {}", e);
+                return None;
+            }
+            Err(_) => {
+                log_id!(trace, id, "Connection sent nothing in time");
+                return None;
+            }
+        };
+
+        let class = classify(&head[..n]);
+        let action = match class {
+            Class::Tls => return Some(stream),
+            Class::PlainHttp => self.settings.plain_http,
+            Class::Garbage => self.settings.garbage,
+        };
+        log_id!(
+            debug,
+            id,
+            "Non-TLS connection: class={:?}, action={:?}",
+            class,
+            action
+        );
+
+        if let Err(e) = self.handle(stream, action, https_port, timeout).await {
+            log_id!(debug, id, "Failed to handle non-TLS connection: {}", e);
+        }
+        None
+    }
+
+    async fn handle(
+        &self,
+        mut stream: TcpStream,
+        action: TriageAction,
+        https_port: u16,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        match action {
+            TriageAction::Close => Ok(()),
+            TriageAction::Reset => reset(&stream),
+            TriageAction::Redirect => {
+                let head = read_request_head(&mut stream, timeout).await?;
+                let response = match redirect_location(&head, https_port) {
+                    Some(x) => format!(
+                        "HTTP/1.1 301 Moved Permanently\r\n\
+                        Location: {}\r\n\
+                        Content-Length: 0\r\n\
+                        Connection: close\r\n\r\n",
+                        x
+                    )
+                    .into_bytes(),
+                    None => DECOY_RESPONSE.to_vec(),
+                };
+                stream.write_all(&response).await?;
+                stream.shutdown().await
+            }
+            TriageAction::Decoy => {
+                // Consume the request so that closing the connection does not reset it
+                read_request_head(&mut stream, timeout).await?;
+                stream.write_all(DECOY_RESPONSE).await?;
+                stream.shutdown().await
+            }
+            TriageAction::Tarpit => {
+                if self.tarpitted.fetch_add(1, Ordering::Relaxed)
+                    >= self.settings.max_tarpit_connections
+                {
+                    self.tarpitted.fetch_sub(1, Ordering::Relaxed);
+                    return reset(&stream);
+                }
+                let drain = async {
+                    let mut buffer = [0; 1024];
+                    while stream.read(&mut buffer).await? != 0 {}
+                    io::Result::Ok(())
+                };
+                let result = tokio::time::timeout(self.settings.tarpit_duration, drain).await;
+                self.tarpitted.fetch_sub(1, Ordering::Relaxed);
+                result.unwrap_or(Ok(()))
+            }
+        }
+    }
+}
+
+fn reset(stream: &TcpStream) -> io::Result<()> {
+    // Closing a socket with the zero linger timeout sends RST instead of FIN
+    SockRef::from(stream).set_linger(Some(Duration::ZERO))
+}
+
+/// Read the head of a plain HTTP request, or whatever is received in `timeout`
+async fn read_request_head(stream: &mut TcpStream, timeout: Duration) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let read = async {
+        let mut buffer = [0; 1024];
+        while !head.windows(4).any(|x| x == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD_LENGTH {
+            match stream.read(&mut buffer).await? {
+                0 => break,
+                n => head.extend_from_slice(&buffer[..n]),
+            }
+        }
+        io::Result::Ok(())
+    };
+    match tokio::time::timeout(timeout, read).await {
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(())) | Err(_) => Ok(head),
+    }
+}
+
+/// Make the HTTPS URL of the resource requested with the plain HTTP request `head`
+fn redirect_location(head: &[u8], https_port: u16) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    if !request.parse(head).ok()?.is_complete() {
+        return None;
+    }
+
+    let mut builder = http::Request::builder()
+        .method(request.method?)
+        .uri(request.path?);
+    for h in request.headers.iter() {
+        builder = builder.header(h.name, h.value);
+    }
+    http_redirect::make_location(&builder.body(()).ok()?.into_parts().0, https_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TriageSettings;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn classes() {
+        assert_eq!(Class::Tls, classify(&[0x16]));
+        assert_eq!(Class::Tls, classify(&[0x16, 0x03, 0x01, 0x02, 0x00]));
+        assert_eq!(Class::PlainHttp, classify(b"GET / HTTP/1.1\r\n"));
+        assert_eq!(Class::PlainHttp, classify(b"PRI * HTTP/2.0\r\n"));
+        assert_eq!(Class::PlainHttp, classify(b"OPT"));
+        assert_eq!(Class::Garbage, classify(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        assert_eq!(Class::Garbage, classify(b"get / HTTP/1.1\r\n"));
+        assert_eq!(Class::Garbage, classify(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"));
+        assert_eq!(Class::Garbage, classify(&[0x00, 0x01]));
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(
+            Some("https://vpn.example.org/index.html?x=1".to_string()),
+            redirect_location(
+                b"GET /index.html?x=1 HTTP/1.1\r\nHost: vpn.example.org\r\n\r\n",
+                443
+            )
+        );
+        assert_eq!(
+            Some("https://vpn.example.org:8443/".to_string()),
+            redirect_location(
+                b"GET / HTTP/1.1\r\nHost: vpn.example.org:8443\r\n\r\n",
+                8443
+            )
+        );
+        assert_eq!(None, redirect_location(b"GET / HTTP/1.1\r\n\r\n", 443));
+        assert_eq!(None, redirect_location(b"GET / HTTP/1.1\r\nHost: x", 443));
+    }
+
+    async fn exchange(settings: TriageSettings, request: &[u8]) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(request).await.unwrap();
+
+        let triage = Triage::new(settings);
+        assert!(triage
+            .triage(
+                server,
+                443,
+                Duration::from_secs(5),
+                &log_utils::IdChain::empty()
+            )
+            .await
+            .is_none());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn redirects_plain_http() {
+        let settings = TriageSettings::builder()
+            .plain_http(TriageAction::Redirect)
+            .build()
+            .unwrap();
+        let response = exchange(
+            settings,
+            b"GET /path HTTP/1.1\r\nHost: vpn.example.org\r\n\r\n",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: https://vpn.example.org/path\r\n"));
+    }
+
+    #[tokio::test]
+    async fn decoys_garbage() {
+        let settings = TriageSettings::builder()
+            .garbage(TriageAction::Decoy)
+            .build()
+            .unwrap();
+        assert_eq!(
+            DECOY_RESPONSE,
+            exchange(settings, b"\x00\x01\x02\r\n\r\n").await
+        );
+    }
+}
@@ -1,18 +1,40 @@
+use crate::authentication::destination_acl::DestinationAcl;
+use crate::authentication::digest::{self, NonceState};
 use crate::authentication::Status;
+use crate::capacity::Category;
+use crate::connection_limits::{ConnectionPermit, LimitError};
 use crate::downstream::{
     Downstream, PendingDatagramMultiplexerRequest, PendingDemultiplexedRequest,
     PendingTcpConnectRequest,
 };
+use crate::events::Event;
 use crate::forwarder::Forwarder;
+use crate::host_override::HostOverride;
+use crate::net_utils::TcpDestination;
 use crate::pipe::DuplexPipe;
+use crate::profiles::{Profile, UnknownProfile};
+use crate::quotas::{QuotaError, QuotaSession};
+use crate::schedule::Schedule;
+use crate::server_timing::ServerTiming;
+use crate::sessions::{DuplicateSessionError, SessionHandle, StreamGuard, StreamLimitError};
+use crate::settings::{GuestSettings, ImpairmentSettings, ListenProtocolSettings, Timeouts};
+use crate::tls_demultiplexer::Protocol;
+use crate::tls_info::TlsInfo;
 use crate::{
-    authentication, core, datagram_pipe, downstream, forwarder, log_id, log_utils, pipe, udp_pipe,
+    audit_log, authentication, bandwidth, capacity, core, datagram_pipe, downstream, forwarder,
+    host_override, impairment, log_id, log_utils, net_utils, pipe, policy, reconnect_tokens,
+    server_timing, tiers, udp_pipe,
 };
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The limit of the client data buffered while a peer connection is being established
+/// on a request with the fast acknowledgement
+const MAX_EARLY_DATA_SIZE: usize = 64 * 1024;
 
 #[derive(Clone)]
 pub(crate) enum AuthenticationPolicy<'this> {
@@ -28,6 +50,16 @@ pub(crate) struct Tunnel {
     downstream: Box<dyn Downstream>,
     forwarder: Arc<Mutex<Box<dyn Forwarder>>>,
     authentication_policy: AuthenticationPolicy<'static>,
+    session: SessionHandle,
+    /// The TLS handshake of the client connection
+    tls: Option<Arc<TlsInfo>>,
+    /// The credentials the session is established with. The later requests presenting
+    /// the same credentials are revalidated, so that the one-time codes are not checked
+    /// again, and the passwords are not hashed on each request.
+    established: Arc<Mutex<Option<authentication::Source<'static>>>>,
+    /// The session slot of the quality of service tiers, admitted to the tier
+    /// by the first authenticated request
+    session_permit: Arc<Mutex<tiers::SessionPermit>>,
     id: log_utils::IdChain<u64>,
 }
 
@@ -35,10 +67,34 @@ pub(crate) struct Tunnel {
 pub(crate) enum ConnectionError {
     Io(io::Error),
     Authentication(String),
+    /// The authentication has failed, and the client is challenged to the digest scheme
+    /// along with the basic one
+    DigestChallenge {
+        reason: String,
+        challenges: Vec<String>,
+    },
     Timeout,
     HostUnreachable,
     DnsNonroutable,
     DnsLoopback,
+    /// The destination is a cloud instance metadata service
+    MetadataEndpoint,
+    /// The destination is not allowed by the profile or the destination ACL of the client
+    DestinationDenied,
+    /// The identity has to acknowledge the terms of use first
+    TermsNotAcknowledged {
+        terms: String,
+        version: String,
+    },
+    /// The endpoint drains during a maintenance window expected to end in `retry_after`
+    Maintenance {
+        retry_after: Option<Duration>,
+    },
+    /// The client has failed to authenticate too many times, and is let to try again
+    /// in `retry_after`
+    AuthLockout {
+        retry_after: Duration,
+    },
     Other(String),
 }
 
@@ -46,11 +102,20 @@ impl Display for ConnectionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Io(x) => write!(f, "IO error: {}", x),
-            Self::Authentication(x) => write!(f, "Authentication error: {}", x),
+            Self::Authentication(x) | Self::DigestChallenge { reason: x, .. } => {
+                write!(f, "Authentication error: {}", x)
+            }
             Self::Timeout => write!(f, "Connection timed out"),
             Self::HostUnreachable => write!(f, "Remote host is unreachable"),
             Self::DnsNonroutable => write!(f, "DNS: resolved address in non-routable network"),
             Self::DnsLoopback => write!(f, "DNS: resolved address in loopback"),
+            Self::MetadataEndpoint => write!(f, "Cloud metadata endpoint is forbidden"),
+            Self::DestinationDenied => write!(f, "Destination is not allowed for client"),
+            Self::TermsNotAcknowledged { version, .. } => {
+                write!(f, "Terms of use version {} are not acknowledged", version)
+            }
+            Self::Maintenance { .. } => write!(f, "Endpoint is under maintenance"),
+            Self::AuthLockout { .. } => write!(f, "Too many failed authentications"),
             Self::Other(x) => write!(f, "{}", x),
         }
     }
@@ -62,6 +127,9 @@ impl Tunnel {
         downstream: Box<dyn Downstream>,
         forwarder: Box<dyn Forwarder>,
         authentication_policy: AuthenticationPolicy<'static>,
+        session: SessionHandle,
+        session_permit: tiers::SessionPermit,
+        tls: Option<TlsInfo>,
         id: log_utils::IdChain<u64>,
     ) -> Self {
         Self {
@@ -69,6 +137,10 @@ impl Tunnel {
             downstream,
             forwarder: Arc::new(Mutex::new(forwarder)),
             authentication_policy,
+            session,
+            tls: tls.map(Arc::new),
+            established: Default::default(),
+            session_permit: Arc::new(Mutex::new(session_permit)),
             id,
         }
     }
@@ -78,6 +150,10 @@ impl Tunnel {
             let shutdown = self.context.shutdown.lock().unwrap();
             (shutdown.notification_handler(), shutdown.completion_guard())
         };
+        let drain_signal = self.session.drain_signal();
+        let replace_signal = self.session.replace_signal();
+        let revoke_signal = self.session.revoke_signal();
+        let expire_signal = self.session.expire_signal();
         tokio::select! {
             x = shutdown_notification.wait() => {
                 match x {
@@ -85,6 +161,25 @@ impl Tunnel {
                     Err(e) => Err(io::Error::new(ErrorKind::Other, format!("{}", e))),
                 }
             }
+            _ = drain_signal.wait() => {
+                log_id!(debug, self.id, "Draining tunnel for rebalancing");
+                self.downstream.drain().await
+            }
+            _ = replace_signal.wait() => {
+                log_id!(debug, self.id, "Closing tunnel replaced by newer session");
+                Err(io::Error::new(
+                    ErrorKind::Other,
+                    "Replaced by newer session of same identity",
+                ))
+            }
+            _ = revoke_signal.wait() => {
+                log_id!(debug, self.id, "Closing tunnel of revoked credentials");
+                Err(io::Error::new(ErrorKind::Other, "Credentials are revoked"))
+            }
+            _ = expire_signal.wait() => {
+                log_id!(debug, self.id, "Closing tunnel on reaching maximum session lifetime");
+                Err(io::Error::new(ErrorKind::Other, "Session lifetime is over"))
+            }
             x = self.listen_inner() => x,
         }
     }
@@ -92,7 +187,7 @@ impl Tunnel {
     async fn listen_inner(&mut self) -> io::Result<()> {
         loop {
             log_id!(trace, self.id, "Tunnel waiting for request");
-            let request = match tokio::time::timeout(
+            let mut request = match tokio::time::timeout(
                 self.context.settings.client_listener_timeout,
                 self.downstream.listen(),
             )
@@ -119,14 +214,27 @@ impl Tunnel {
             let context = self.context.clone();
             let forwarder = self.forwarder.clone();
             let tls_domain = self.downstream.tls_domain().to_string();
+            let tls = self.tls.clone();
+            let established = self.established.clone();
+            let session_permit = self.session_permit.clone();
             let authentication_policy = self.authentication_policy.clone();
             let log_id = self.id.clone();
+            let stream_guard = self.session.stream_guard();
+            let session_id = self.session.id();
+            let protocol = self.downstream.protocol();
+            let timeouts = context.settings.timeouts(protocol);
             let update_metrics = {
                 let metrics = context.metrics.clone();
-                let protocol = self.downstream.protocol();
+                let traffic = self.session.traffic_counter();
                 move |direction, n| match direction {
-                    pipe::SimplexDirection::Incoming => metrics.add_inbound_bytes(protocol, n),
-                    pipe::SimplexDirection::Outgoing => metrics.add_outbound_bytes(protocol, n),
+                    pipe::SimplexDirection::Incoming => {
+                        metrics.add_inbound_bytes(protocol, n);
+                        traffic.add_inbound_bytes(n);
+                    }
+                    pipe::SimplexDirection::Outgoing => {
+                        metrics.add_outbound_bytes(protocol, n);
+                        traffic.add_outbound_bytes(n);
+                    }
                 }
             };
 
@@ -142,24 +250,179 @@ impl Tunnel {
                     }
                 }
 
+                let mut stream_guard = stream_guard;
                 let request_id = request.id();
                 log_id!(trace, request_id, "Processing tunnel request");
+                let mut timing = context.settings.server_timing.then(ServerTiming::start);
+                if let Some(state) = context
+                    .schedule
+                    .as_ref()
+                    .map(Schedule::state)
+                    .filter(|x| x.draining)
+                {
+                    let err = ConnectionError::Maintenance {
+                        retry_after: state.retry_after(),
+                    };
+                    log_id!(debug, request_id, "{}", err);
+                    context.metrics.add_failed_request();
+                    request.fail_request(err);
+                    return;
+                }
                 let auth_info = request
                     .auth_info()
                     .map(|x| x.map(authentication::Source::into_owned));
+                let client_address = request.client_address().ok();
+                let lockout = context.auth_lockout.as_ref().zip(client_address);
+                let audit = |source: Option<&authentication::Source<'_>>, outcome| {
+                    if let Some(x) = &context.audit_log {
+                        x.record(audit_log::Attempt {
+                            log_id: &request_id,
+                            client_ip: client_address,
+                            source,
+                            server_name: Some(&tls_domain),
+                            tls: tls.as_deref(),
+                            outcome,
+                        });
+                    }
+                };
+                let redeemed = match Self::redeem_reconnect_token(
+                    &context,
+                    request.reconnect_token(),
+                    session_id,
+                ) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // The client falls back to its credentials, if any
+                        log_id!(debug, request_id, "Ignoring reconnect token: {}", e);
+                        None
+                    }
+                };
+                let redeemed_till = redeemed.as_ref().map(|x| x.expires_at);
+                let redeemed = redeemed.map(|x| x.source);
                 let forwarder_auth = match (
                     auth_info,
                     authentication_policy,
                     context.authenticator.clone(),
                 ) {
+                    // The token stands for the credentials the authenticator has already passed
+                    (_, _, Some(_)) if redeemed.is_some() => {
+                        audit(redeemed.as_ref(), audit_log::Outcome::Pass);
+                        redeemed
+                    }
                     (Ok(Some(source)), _, Some(authenticator)) => {
-                        match authenticator.authenticate(&source, &log_id) {
-                            Status::Pass => Some(source),
+                        let username = source.username();
+                        if let Some(retry_after) =
+                            lockout.and_then(|(x, ip)| x.locked(ip, username.as_deref()))
+                        {
+                            let err = ConnectionError::AuthLockout { retry_after };
+                            log_id!(debug, request_id, "{}", err);
+                            audit(Some(&source), audit_log::Outcome::LockedOut);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::AuthFailure {
+                                session: session_id,
+                                username,
+                            });
+                            request.fail_request(err);
+                            return;
+                        }
+                        if context
+                            .revocations
+                            .as_ref()
+                            .is_some_and(|x| x.is_revoked(authenticator.as_ref(), &source))
+                        {
+                            let err = Self::authentication_error(
+                                &context,
+                                "Credentials are revoked",
+                                false,
+                            );
+                            log_id!(debug, request_id, "{}", err);
+                            audit(Some(&source), audit_log::Outcome::Revoked);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::AuthFailure {
+                                session: session_id,
+                                username,
+                            });
+                            request.fail_request(err);
+                            return;
+                        }
+                        let revalidate = established.lock().unwrap().as_ref() == Some(&source);
+                        let nonce = Self::digest_nonce(&context, &source, revalidate);
+                        if nonce != NonceState::Fresh {
+                            // A stale nonce is no failure, the client retries with a fresh one
+                            if let Some((x, ip)) = lockout.filter(|_| nonce == NonceState::Invalid)
+                            {
+                                x.failed(ip, username.as_deref());
+                            }
+                            let err = Self::authentication_error(
+                                &context,
+                                "Digest nonce is not accepted",
+                                nonce == NonceState::Stale,
+                            );
+                            log_id!(debug, request_id, "{}", err);
+                            audit(Some(&source), audit_log::Outcome::Reject);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::AuthFailure {
+                                session: session_id,
+                                username,
+                            });
+                            request.fail_request(err);
+                            return;
+                        }
+                        match Self::authenticate(
+                            authenticator,
+                            &source,
+                            revalidate,
+                            tls.as_deref(),
+                            &log_id,
+                            timeouts.auth,
+                        )
+                        .await
+                        {
+                            Status::Pass
+                                if !revalidate && !Self::use_digest_count(&context, &source) =>
+                            {
+                                // A concurrent request has presented the same credentials
+                                let err = Self::authentication_error(
+                                    &context,
+                                    "Digest nonce is not accepted",
+                                    true,
+                                );
+                                log_id!(debug, request_id, "{}", err);
+                                audit(Some(&source), audit_log::Outcome::Reject);
+                                context.metrics.add_failed_request();
+                                context.events.publish(Event::AuthFailure {
+                                    session: session_id,
+                                    username,
+                                });
+                                request.fail_request(err);
+                                return;
+                            }
+                            Status::Pass => {
+                                if let Some((x, _)) = lockout {
+                                    x.passed(username.as_deref());
+                                }
+                                if !revalidate {
+                                    *established.lock().unwrap() = Some(source.clone());
+                                }
+                                audit(Some(&source), audit_log::Outcome::Pass);
+                                Some(source)
+                            }
                             Status::Reject => {
-                                let err = ConnectionError::Authentication(
-                                    "Authentication failed".to_string(),
+                                if let Some((x, ip)) = lockout {
+                                    x.failed(ip, username.as_deref());
+                                }
+                                audit(Some(&source), audit_log::Outcome::Reject);
+                                let err = Self::authentication_error(
+                                    &context,
+                                    "Authentication failed",
+                                    false,
                                 );
                                 log_id!(debug, request_id, "{}", err);
+                                context.metrics.add_failed_request();
+                                context.events.publish(Event::AuthFailure {
+                                    session: session_id,
+                                    username: source.username(),
+                                });
                                 request.fail_request(err);
                                 return;
                             }
@@ -170,21 +433,204 @@ impl Tunnel {
                         AuthenticationPolicy::Default => None,
                         AuthenticationPolicy::Authenticated(y) => Some(y),
                     }),
+                    (Ok(None), AuthenticationPolicy::Default, Some(_))
+                        if context.settings.guest.is_some() =>
+                    {
+                        audit(None, audit_log::Outcome::Guest);
+                        None
+                    }
                     (Ok(None), AuthenticationPolicy::Default, Some(_)) => {
-                        let err = ConnectionError::Authentication(
-                            "Got request without authentication info on non-authenticated connection".to_string()
+                        let err = Self::authentication_error(
+                            &context,
+                            "Got request without authentication info on non-authenticated connection",
+                            false,
                         );
                         log_id!(debug, request_id, "{}", err);
+                        audit(None, audit_log::Outcome::Reject);
+                        context.metrics.add_failed_request();
+                        context.events.publish(Event::AuthFailure {
+                            session: session_id,
+                            username: None,
+                        });
                         request.fail_request(err);
                         return;
                     }
                     (Err(e), ..) => {
                         log_id!(debug, request_id, "Failed to get auth info: {}", e);
+                        context.metrics.add_failed_request();
+                        context.events.publish(Event::RequestFailed {
+                            session: session_id,
+                            reason: format!("Failed to get auth info: {}", e),
+                        });
                         request.fail_request(ConnectionError::Io(e));
                         return;
                     }
                 };
 
+                // With an authenticator, only the guests get through without credentials
+                let guest = context
+                    .settings
+                    .guest
+                    .as_ref()
+                    .filter(|_| forwarder_auth.is_none() && context.authenticator.is_some());
+                if let Some(x) = guest {
+                    log_id!(debug, request_id, "Letting guest through");
+                    context
+                        .sessions
+                        .limit_lifetime(session_id, x.max_session_duration);
+                }
+
+                if let Err(err) = Self::check_terms(
+                    &context,
+                    forwarder_auth.as_ref(),
+                    request.terms_acknowledgment().as_deref(),
+                ) {
+                    log_id!(debug, request_id, "{}", err);
+                    context.metrics.add_failed_request();
+                    context.events.publish(Event::RequestFailed {
+                        session: session_id,
+                        reason: err.to_string(),
+                    });
+                    request.fail_request(err);
+                    return;
+                }
+                if let Err(e) = Self::bind_session(&context, session_id, forwarder_auth.as_ref()) {
+                    log_id!(debug, request_id, "Session rejected: {}", e);
+                    context.metrics.add_failed_request();
+                    context.events.publish(Event::RequestFailed {
+                        session: session_id,
+                        reason: e.to_string(),
+                    });
+                    request.fail_request(ConnectionError::Other(e.to_string()));
+                    return;
+                }
+                if let Some(x) = Self::expiring_credentials(&context, forwarder_auth.as_ref()) {
+                    log_id!(debug, request_id, "Credentials expire soon at {}", x);
+                    request.add_ok_header(policy::CREDENTIALS_EXPIRY_HEADER, x.to_string());
+                }
+                if let Some(x) =
+                    Self::issue_reconnect_token(&context, forwarder_auth.as_ref(), redeemed_till)
+                {
+                    request.add_ok_header(reconnect_tokens::RECONNECT_TOKEN_HEADER, x);
+                }
+                if let Some(x) = Self::pacing_hint(&context, session_id) {
+                    log_id!(trace, request_id, "Pacing hint: {}", x);
+                    request.add_ok_header(bandwidth::PACING_HINT_HEADER, x);
+                }
+
+                let tier = match (&forwarder_auth, context.authenticator.as_ref()) {
+                    (Some(source), Some(authenticator)) if !context.tiers.is_empty() => {
+                        authenticator.tier(source)
+                    }
+                    _ => guest.and_then(|x| x.tier.clone()),
+                };
+                let admission = {
+                    let mut permit = session_permit.lock().unwrap();
+                    match tier.as_deref() {
+                        Some(x) if !permit.is_admitted() && !context.tiers.contains(x) => {
+                            log_id!(
+                                warn,
+                                request_id,
+                                "Unknown tier {}, applying the default one",
+                                x
+                            );
+                        }
+                        _ => (),
+                    }
+                    let identity = forwarder_auth.as_ref().and_then(|x| context.identity(x));
+                    context
+                        .tiers
+                        .admit(&mut permit, tier.as_deref(), identity.as_deref())
+                        .map(|_| permit.rate_limits().cloned())
+                };
+                let rate_limits = match admission {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log_id!(debug, request_id, "Session rejected: {}", e);
+                        context.metrics.add_failed_request();
+                        context.events.publish(Event::RequestFailed {
+                            session: session_id,
+                            reason: e.to_string(),
+                        });
+                        request.fail_request(ConnectionError::Other(e.to_string()));
+                        return;
+                    }
+                };
+                let _connection_permit =
+                    match Self::admit_connection(&context, forwarder_auth.as_ref()) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log_id!(debug, request_id, "Connection rejected: {}", e);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::RequestFailed {
+                                session: session_id,
+                                reason: e.to_string(),
+                            });
+                            request.fail_request(ConnectionError::Other(e.to_string()));
+                            return;
+                        }
+                    };
+                if let Err(e) =
+                    Self::admit_stream(&context, &mut stream_guard, forwarder_auth.as_ref())
+                {
+                    log_id!(debug, request_id, "Stream rejected: {}", e);
+                    context.metrics.add_failed_request();
+                    context.events.publish(Event::RequestFailed {
+                        session: session_id,
+                        reason: e.to_string(),
+                    });
+                    request.fail_request(ConnectionError::Other(e.to_string()));
+                    return;
+                }
+                let quota = match Self::admit_quota(&context, forwarder_auth.as_ref()) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log_id!(debug, request_id, "Tunnel rejected: {}", e);
+                        context.metrics.add_failed_request();
+                        context.events.publish(Event::RequestFailed {
+                            session: session_id,
+                            reason: e.to_string(),
+                        });
+                        request.fail_request(ConnectionError::Other(e.to_string()));
+                        return;
+                    }
+                };
+                let profile = match Self::resolve_profile(&context, forwarder_auth.as_ref(), guest)
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log_id!(debug, request_id, "Tunnel rejected: {}", e);
+                        context.metrics.add_failed_request();
+                        context.events.publish(Event::RequestFailed {
+                            session: session_id,
+                            reason: e.to_string(),
+                        });
+                        request.fail_request(ConnectionError::Other(e.to_string()));
+                        return;
+                    }
+                };
+                let update_metrics = {
+                    let quota = quota.clone();
+                    move |direction, n| {
+                        if let Some(x) = &quota {
+                            x.add(n);
+                        }
+                        update_metrics(direction, n)
+                    }
+                };
+
+                let impairment = impairment::find(
+                    &context.settings.impairments,
+                    protocol,
+                    forwarder_auth
+                        .as_ref()
+                        .and_then(authentication::Source::username)
+                        .as_deref(),
+                );
+
+                if let Some(x) = &mut timing {
+                    x.record(server_timing::AUTH, x.elapsed());
+                }
                 log_id!(
                     trace,
                     request_id,
@@ -196,18 +642,32 @@ impl Tunnel {
                     }
                     Ok(Some(PendingDemultiplexedRequest::TcpConnect(request))) => {
                         log_id!(trace, request_id, "Handling TCP connect request");
+                        let fast_ack =
+                            is_fast_connect_ack(&context.settings.listen_protocols, protocol);
                         if let Err((request, message, e)) = Tunnel::on_tcp_connect_request(
                             context.clone(),
                             forwarder,
                             request,
                             forwarder_auth,
                             tls_domain,
+                            rate_limits.as_ref(),
+                            impairment,
+                            quota.as_deref(),
+                            profile.as_deref(),
+                            fast_ack,
+                            timeouts,
+                            timing,
                             update_metrics,
                         )
                         .await
                         {
                             report_fatal_if_too_many_open_files(&context, &e);
                             log_id!(debug, request_id, "{}: {}", message, e);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::RequestFailed {
+                                session: session_id,
+                                reason: format!("{}: {}", message, e),
+                            });
                             if let Some(request) = request {
                                 request.fail_request(e);
                             }
@@ -221,12 +681,21 @@ impl Tunnel {
                             request,
                             forwarder_auth,
                             tls_domain,
+                            impairment,
+                            quota.as_deref(),
+                            profile,
+                            timing,
                             update_metrics,
                         )
                         .await
                         {
                             report_fatal_if_too_many_open_files(&context, &e);
                             log_id!(debug, request_id, "{}: {}", message, e);
+                            context.metrics.add_failed_request();
+                            context.events.publish(Event::RequestFailed {
+                                session: session_id,
+                                reason: format!("{}: {}", message, e),
+                            });
                             if let Some(request) = request {
                                 request.fail_request(e);
                             }
@@ -240,12 +709,335 @@ impl Tunnel {
         }
     }
 
+    /// Call the authenticator, treating a call taking longer than `timeout` as a rejection.
+    /// The credentials the session is established with are `revalidate`d instead.
+    /// The passed client is rejected still if the authenticator does not accept
+    /// the TLS handshake of the connection.
+    async fn authenticate(
+        authenticator: Arc<dyn authentication::Authenticator>,
+        source: &authentication::Source<'static>,
+        revalidate: bool,
+        tls: Option<&TlsInfo>,
+        id: &log_utils::IdChain<u64>,
+        timeout: Option<Duration>,
+    ) -> Status {
+        let call = move |authenticator: &dyn authentication::Authenticator,
+                         source: &authentication::Source<'_>,
+                         id: &log_utils::IdChain<u64>| {
+            if revalidate {
+                authenticator.revalidate(source, id)
+            } else {
+                authenticator.authenticate(source, id)
+            }
+        };
+        let status = match timeout {
+            None => call(authenticator.as_ref(), source, id),
+            Some(timeout) => {
+                let call = tokio::task::spawn_blocking({
+                    let authenticator = authenticator.clone();
+                    let source = source.clone();
+                    let id = id.clone();
+                    move || call(authenticator.as_ref(), &source, &id)
+                });
+                match tokio::time::timeout(timeout, call).await {
+                    Ok(Ok(x)) => x,
+                    Ok(Err(e)) => {
+                        log_id!(debug, id, "Authenticator call failed: {}", e);
+                        Status::Reject
+                    }
+                    Err(_) => {
+                        log_id!(debug, id, "Authenticator did not respond in time");
+                        Status::Reject
+                    }
+                }
+            }
+        };
+
+        match tls {
+            Some(x) if status == Status::Pass && !authenticator.accept_tls(source, x) => {
+                log_id!(debug, id, "TLS handshake is not accepted: {}", x);
+                Status::Reject
+            }
+            _ => status,
+        }
+    }
+
+    /// Check the nonce of the digest credentials is issued by the endpoint and is not outdated,
+    /// and its count is not used yet unless the session is established with the credentials.
+    /// The other credentials have no nonce to check.
+    fn digest_nonce(
+        context: &core::Context,
+        source: &authentication::Source<'_>,
+        revalidate: bool,
+    ) -> NonceState {
+        let authentication::Source::ProxyDigest(x) = source else {
+            return NonceState::Fresh;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        match (context.digest_auth.as_ref(), digest::Credentials::parse(x)) {
+            (Some(auth), Some(credentials)) => match auth.check(&credentials, now) {
+                NonceState::Fresh if !revalidate && auth.is_used(&credentials) => NonceState::Stale,
+                x => x,
+            },
+            _ => NonceState::Invalid,
+        }
+    }
+
+    /// Remember the nonce count of the accepted digest credentials, so that they are not
+    /// accepted once more. `false` if a concurrent request has used it.
+    fn use_digest_count(context: &core::Context, source: &authentication::Source<'_>) -> bool {
+        let authentication::Source::ProxyDigest(x) = source else {
+            return true;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        match (context.digest_auth.as_ref(), digest::Credentials::parse(x)) {
+            (Some(auth), Some(credentials)) => auth.use_count(&credentials, now),
+            _ => false,
+        }
+    }
+
+    /// Make the error of a failed authentication, which challenges the client
+    /// to the digest scheme in case it is enabled
+    fn authentication_error(context: &core::Context, reason: &str, stale: bool) -> ConnectionError {
+        let Some(auth) = &context.digest_auth else {
+            return ConnectionError::Authentication(reason.to_string());
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        ConnectionError::DigestChallenge {
+            reason: reason.to_string(),
+            challenges: auth.challenges(now, stale),
+        }
+    }
+
+    /// Occupy a connection slot of the authenticated identity in case its concurrent
+    /// connections are limited
+    fn admit_connection(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+    ) -> Result<Option<ConnectionPermit>, LimitError> {
+        let Some(source) = auth else {
+            return Ok(None);
+        };
+        let limit = context
+            .authenticator
+            .as_ref()
+            .and_then(|x| x.max_connections(source))
+            .or(context.settings.max_connections_per_user);
+        match (limit, context.identity(source)) {
+            (Some(limit), Some(identity)) => {
+                context.connection_limiter.admit(identity, limit).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Account the stream to the authenticated identity in case its concurrent streams
+    /// across the sessions are limited
+    fn admit_stream(
+        context: &core::Context,
+        stream: &mut StreamGuard,
+        auth: Option<&authentication::Source<'_>>,
+    ) -> Result<(), StreamLimitError> {
+        let Some(source) = auth else {
+            return Ok(());
+        };
+        let limit = context
+            .authenticator
+            .as_ref()
+            .and_then(|x| x.max_streams(source))
+            .or(context.settings.max_streams_per_user);
+        match (limit, context.identity(source)) {
+            (Some(limit), Some(identity)) => context.sessions.admit_stream(stream, identity, limit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start accounting the data of the authenticated identity in case it has a quota
+    fn admit_quota(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+    ) -> Result<Option<Arc<QuotaSession>>, QuotaError> {
+        let (Some(source), Some(authenticator)) = (auth, context.authenticator.as_ref()) else {
+            return Ok(None);
+        };
+        match (
+            authenticator.data_quota(source),
+            policy::identity(authenticator.as_ref(), source),
+        ) {
+            (Some(quota), Some(identity)) => context.quotas.admit(identity, quota).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the destination profile the authenticated client, or the guest, is assigned to
+    fn resolve_profile(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+        guest: Option<&GuestSettings>,
+    ) -> Result<Option<Arc<Profile>>, UnknownProfile> {
+        if let Some(x) = guest.and_then(|x| x.profile.as_ref()) {
+            return context.profiles.get(x).map(Some);
+        }
+        let (Some(source), Some(authenticator)) = (auth, context.authenticator.as_ref()) else {
+            return Ok(None);
+        };
+        authenticator
+            .profile(source)
+            .map(|x| context.profiles.get(&x))
+            .transpose()
+    }
+
+    /// Account the session to the authenticated identity in case its duplicate sessions
+    /// are restricted, and record the credentials in case they get revoked
+    fn bind_session(
+        context: &core::Context,
+        session_id: u64,
+        auth: Option<&authentication::Source<'_>>,
+    ) -> Result<(), DuplicateSessionError> {
+        let (Some(source), Some(identity)) = (auth, auth.and_then(|x| context.identity(x))) else {
+            return Ok(());
+        };
+        if let Some(settings) = &context.settings.duplicate_sessions {
+            context.sessions.bind(session_id, &identity, settings)?;
+        }
+        if context.revocations.is_some() {
+            let token_id = context
+                .authenticator
+                .as_ref()
+                .and_then(|x| x.token_id(source));
+            context.sessions.identify(session_id, &identity, token_id);
+        }
+        Ok(())
+    }
+
+    /// Get the expiration time of the credentials of the authenticated client
+    /// in case the client is to be warned about it
+    fn expiring_credentials(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+    ) -> Option<u64> {
+        let policy = context
+            .settings
+            .policy
+            .as_ref()
+            .filter(|x| x.expiry_warning_days.is_some())?;
+        let valid_till = context.authenticator.as_ref()?.valid_till(auth?)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        policy::is_expiring(policy, valid_till, now).then_some(valid_till)
+    }
+
+    /// Redeem the reconnect token presented with the request, unless the credentials
+    /// it stands for have been revoked since it was issued
+    fn redeem_reconnect_token(
+        context: &core::Context,
+        token: Option<String>,
+        session_id: u64,
+    ) -> Result<Option<reconnect_tokens::Redeemed>, reconnect_tokens::RedeemError> {
+        let (Some(tokens), Some(token)) = (context.reconnect_tokens.as_ref(), token) else {
+            return Ok(None);
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let redeemed = tokens.redeem(&token, session_id, now)?;
+        Ok(Some(redeemed).filter(|x| {
+            !context
+                .revocations
+                .as_ref()
+                .zip(context.authenticator.as_ref())
+                .is_some_and(|(y, authenticator)| y.is_revoked(authenticator.as_ref(), &x.source))
+        }))
+    }
+
+    /// Issue a reconnect token to the client authenticated with the proxy authorization.
+    /// In case the client is authenticated with a token expiring at `redeemed_till`,
+    /// the new one expires no later, so the tokens are not renewed without the authenticator.
+    fn issue_reconnect_token(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+        redeemed_till: Option<u64>,
+    ) -> Option<String> {
+        let tokens = context.reconnect_tokens.as_ref()?;
+        let auth = auth?;
+        let valid_till = context
+            .authenticator
+            .as_ref()
+            .and_then(|x| x.valid_till(auth));
+        let valid_till = match (valid_till, redeemed_till) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        tokens.issue(auth, valid_till, now)
+    }
+
+    /// Get the pacing rate the session is hinted in case the hints are enabled
+    fn pacing_hint(context: &core::Context, session_id: u64) -> Option<String> {
+        let settings = context
+            .settings
+            .bandwidth_estimation
+            .as_ref()
+            .filter(|x| x.hints)?;
+        context
+            .sessions
+            .bandwidth(session_id)?
+            .pacing_hint(settings)
+    }
+
+    /// Check the authenticated identity has acknowledged the current terms of use
+    fn check_terms(
+        context: &core::Context,
+        auth: Option<&authentication::Source<'_>>,
+        ack: Option<&str>,
+    ) -> Result<(), ConnectionError> {
+        let (Some(policy), Some(store)) = (&context.settings.policy, &context.state_store) else {
+            return Ok(());
+        };
+        let Some(identity) = auth.and_then(|x| context.identity(x)) else {
+            return Ok(());
+        };
+
+        match &policy.terms {
+            Some(terms) if !policy::is_acknowledged(policy, store, &identity, ack) => {
+                Err(ConnectionError::TermsNotAcknowledged {
+                    terms: terms.clone(),
+                    version: policy.terms_version.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn on_tcp_connect_request<F: Fn(pipe::SimplexDirection, usize) + Send + Clone>(
         context: Arc<core::Context>,
         forwarder: Arc<Mutex<Box<dyn Forwarder>>>,
-        request: Box<dyn PendingTcpConnectRequest>,
+        mut request: Box<dyn PendingTcpConnectRequest>,
         forwarder_auth: Option<authentication::Source<'static>>,
         tls_domain: String,
+        rate_limits: Option<&Arc<tiers::RateLimits>>,
+        impairment: Option<&ImpairmentSettings>,
+        quota: Option<&QuotaSession>,
+        profile: Option<&Profile>,
+        fast_ack: bool,
+        timeouts: Timeouts,
+        timing: Option<ServerTiming>,
         update_metrics: F,
     ) -> Result<
         (),
@@ -257,7 +1049,10 @@ impl Tunnel {
     > {
         let request_id = request.id();
         log_id!(trace, request_id, "TCP connect: extracting destination");
-        let destination = match request.destination() {
+        let destination = match request
+            .destination()
+            .and_then(net_utils::canonicalize_destination)
+        {
             Ok(d) => {
                 log_id!(trace, request_id, "TCP connect: destination={:?}", d);
                 d
@@ -271,84 +1066,276 @@ impl Tunnel {
             }
         };
 
+        let client_address = match request.client_address() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err((
+                    Some(request),
+                    "Failed to get client address",
+                    ConnectionError::Io(e),
+                ))
+            }
+        };
+
+        let host = match &destination {
+            TcpDestination::Address(x) => x.ip().to_string(),
+            TcpDestination::HostName((x, _)) => x.clone(),
+        };
+        let identity = forwarder_auth
+            .as_ref()
+            .and_then(authentication::Source::username);
+        let request_id = if log_utils::is_trace_requested(identity.as_deref(), Some(&host)) {
+            log_id!(info, request_id, "TCP connect: tracing request to {}", host);
+            request_id.traced()
+        } else {
+            request_id
+        };
+
+        // Checked here by name too, as a forwarder other than the direct one resolves
+        // the names elsewhere
+        if !context.settings.allow_metadata_endpoint_connections
+            && net_utils::is_metadata_destination(&destination)
+        {
+            log_id!(
+                debug,
+                request_id,
+                "TCP connect: metadata endpoint {} refused",
+                host
+            );
+            return Err((
+                Some(request),
+                "Connection to metadata endpoint",
+                ConnectionError::MetadataEndpoint,
+            ));
+        }
+
+        if profile.is_some_and(|x| !x.is_allowed(&host)) {
+            log_id!(debug, request_id, "TCP connect: {} denied by profile", host);
+            return Err((
+                Some(request),
+                "Destination denied by profile",
+                ConnectionError::DestinationDenied,
+            ));
+        }
+
+        let rules = context.rules.current();
+        let route = profile
+            .and_then(|x| x.route(&client_address, &host).map(|x| ("profile", x)))
+            .or_else(|| {
+                rules
+                    .route_traced(&client_address, &host)
+                    .map(|x| ("rules", x))
+            });
+        if let Some((list, (i, _))) = route {
+            log_id!(
+                debug,
+                request_id,
+                "TCP connect: {} matches {} route #{}",
+                host,
+                list,
+                i
+            );
+        }
+        let race_uplinks = route
+            .map(|(_, (_, rule))| rule.race_uplinks.clone())
+            .unwrap_or_default();
+        let host_override = route.map(|(_, (_, rule))| HostOverride {
+            sni: rule.override_sni.clone(),
+            host: rule.override_host.clone(),
+        });
+
+        // Enforced by the forwarder, which sees the addresses the names resolve into
+        let destination_acl = forwarder_auth
+            .as_ref()
+            .zip(context.authenticator.as_ref())
+            .and_then(|(source, x)| x.destination_acl(source));
         let meta = forwarder::TcpConnectionMeta {
-            client_address: match request.client_address() {
-                Ok(x) => x,
-                Err(e) => {
-                    return Err((
-                        Some(request),
-                        "Failed to get client address",
-                        ConnectionError::Io(e),
-                    ))
-                }
-            },
+            client_address,
             destination,
             tls_domain,
             auth: forwarder_auth,
             user_agent: request.user_agent(),
+            destination_acl,
+            race_uplinks,
         };
 
         log_id!(trace, request_id, "TCP connect: connecting to peer");
         let connector = forwarder.lock().unwrap().tcp_connector();
-        let (fwd_rx, fwd_tx) = match tokio::time::timeout(
-            context.settings.connection_establishment_timeout,
+        let connect_started = Instant::now();
+        let connect = tokio::time::timeout(
+            timeouts.upstream_connect,
             connector.connect(request_id.clone(), meta.clone()),
-        )
-        .await
-        .unwrap_or(Err(ConnectionError::Timeout))
-        {
-            Ok(x) => {
-                log_id!(
-                    trace,
-                    request_id,
-                    "TCP connect: peer connection established"
+        );
+
+        let ((fwd_rx, fwd_tx), (dstr_rx, dstr_tx)) = if fast_ack {
+            log_id!(
+                trace,
+                request_id,
+                "TCP connect: promoting downstream request before peer connection"
+            );
+            // The response goes before the connection, so it only has the time up to now
+            if let Some(x) = &timing {
+                request.add_ok_header(server_timing::HEADER, x.header_value());
+            }
+            let (dstr_rx, dstr_tx) = match request.promote_to_next_state() {
+                Ok(x) => x,
+                Err(e) => return Err((None, "Failed to complete request", ConnectionError::Io(e))),
+            };
+            // The client may speak first, e.g. send the TLS ClientHello right after the response,
+            // so its data is buffered to be sent as soon as the peer connection is up
+            let (connected, dstr_rx) = match pipe::read_ahead(dstr_rx, MAX_EARLY_DATA_SIZE, connect)
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => return Err((None, "Failed to read client data", ConnectionError::Io(e))),
+            };
+            match connected.unwrap_or(Err(ConnectionError::Timeout)) {
+                Ok(x) => {
+                    log_id!(
+                        trace,
+                        request_id,
+                        "TCP connect: peer connection established"
+                    );
+                    (x, (dstr_rx, dstr_tx))
+                }
+                Err(e) => return Err((None, "Connection to peer failed", e)),
+            }
+        } else {
+            let upstream = match connect.await.unwrap_or(Err(ConnectionError::Timeout)) {
+                Ok(x) => {
+                    log_id!(
+                        trace,
+                        request_id,
+                        "TCP connect: peer connection established"
+                    );
+                    x
+                }
+                Err(e) => return Err((Some(request), "Connection to peer failed", e)),
+            };
+            if let Some(mut x) = timing {
+                x.record(server_timing::UPSTREAM_CONNECT, connect_started.elapsed());
+                request.add_ok_header(server_timing::HEADER, x.header_value());
+            }
+
+            log_id!(
+                trace,
+                request_id,
+                "TCP connect: promoting downstream request"
+            );
+            match request.promote_to_next_state() {
+                Ok(x) => (upstream, x),
+                Err(e) => return Err((None, "Failed to complete request", ConnectionError::Io(e))),
+            }
+        };
+        log_id!(debug, request_id, "Successfully connected to {:?}", meta);
+
+        let interceptor = context
+            .interceptor
+            .as_ref()
+            .filter(|x| x.matches(&meta.destination));
+        let ((fwd_rx, fwd_tx), (dstr_rx, dstr_tx), host_override) = match interceptor {
+            None => ((fwd_rx, fwd_tx), (dstr_rx, dstr_tx), host_override),
+            Some(x) => {
+                log_id!(trace, request_id, "TCP connect: intercepting TLS");
+                let intercept = x.intercept(
+                    request_id.clone(),
+                    &meta,
+                    (dstr_rx, dstr_tx),
+                    (fwd_rx, fwd_tx),
+                    host_override.as_ref().and_then(|x| x.sni.as_deref()),
                 );
-                x
+                match tokio::time::timeout(timeouts.tls_handshake, intercept).await {
+                    // The destination handshake has been run with the overriding name already
+                    Ok(Ok((client, destination))) => (
+                        destination,
+                        client,
+                        host_override.map(|x| HostOverride { sni: None, ..x }),
+                    ),
+                    Ok(Err(e)) => {
+                        log_id!(info, request_id, "TLS interception failed: {}", e);
+                        return Err((None, "TLS interception failed", ConnectionError::Io(e)));
+                    }
+                    Err(_) => {
+                        log_id!(info, request_id, "TLS interception timed out");
+                        return Err((None, "TLS interception failed", ConnectionError::Timeout));
+                    }
+                }
             }
-            Err(e) => return Err((Some(request), "Connection to peer failed", e)),
         };
 
-        log_id!(debug, request_id, "Successfully connected to {:?}", meta);
         log_id!(
             trace,
             request_id,
-            "TCP connect: promoting downstream request"
+            "TCP connect: downstream ready, starting pipe"
         );
-        let (dstr_rx, dstr_tx) = match request.promote_to_next_state() {
-            Ok(x) => {
+
+        let fwd_tx = match host_override {
+            None => fwd_tx,
+            Some(x) => {
                 log_id!(
                     trace,
                     request_id,
-                    "TCP connect: downstream ready, starting pipe"
+                    "TCP connect: overriding host names: {:?}",
+                    x
                 );
-                x
+                host_override::wrap(fwd_tx, x)
             }
-            Err(e) => return Err((None, "Failed to complete request", ConnectionError::Io(e))),
         };
 
         let mut pipe = DuplexPipe::new(
-            (pipe::SimplexDirection::Outgoing, dstr_rx, fwd_tx),
-            (pipe::SimplexDirection::Incoming, fwd_rx, dstr_tx),
+            (
+                pipe::SimplexDirection::Outgoing,
+                impairment::impair_stream(
+                    tiers::throttle(dstr_rx, rate_limits, pipe::SimplexDirection::Outgoing),
+                    impairment,
+                ),
+                fwd_tx,
+            ),
+            (
+                pipe::SimplexDirection::Incoming,
+                impairment::impair_stream(
+                    tiers::throttle(fwd_rx, rate_limits, pipe::SimplexDirection::Incoming),
+                    impairment,
+                ),
+                dstr_tx,
+            ),
             update_metrics,
         );
 
         log_id!(trace, request_id, "TCP connect: pipe exchange started");
         let mut revalidate_interval = tokio::time::interval(Duration::from_secs(30));
-        let exchange = pipe.exchange(context.settings.tcp_connections_timeout);
+        let exchange = capacity::metered(Category::Pipe, pipe.exchange(timeouts.idle));
         tokio::pin!(exchange);
 
-        let exchange_result = loop {
-            tokio::select! {
-                res = &mut exchange => break res,
-                _ = revalidate_interval.tick() => {
-                    if let (Some(auth), Some(authenticator)) = (&meta.auth, context.authenticator.as_ref()) {
-                        if authenticator.authenticate(auth, &request_id) == Status::Reject {
-                            break Err(io::Error::new(ErrorKind::PermissionDenied, "Authentication revoked"));
+        let exchange_result = pipe::with_lifetime(timeouts.total, async {
+            loop {
+                tokio::select! {
+                    res = &mut exchange => break res,
+                    _ = revalidate_interval.tick() => {
+                        if let (Some(auth), Some(authenticator)) = (&meta.auth, context.authenticator.as_ref()) {
+                            if authenticator.revalidate(auth, &request_id) == Status::Reject {
+                                break Err(io::Error::new(ErrorKind::PermissionDenied, "Authentication revoked"));
+                            }
                         }
                     }
+                    _ = QuotaSession::exceeded(quota) => {
+                        break Err(io::Error::new(ErrorKind::PermissionDenied, "Data quota used up"));
+                    }
                 }
             }
-        };
+        })
+        .await;
+
+        if interceptor.is_some() {
+            log_id!(
+                info,
+                request_id,
+                "Intercepted TLS connection closed: {}",
+                exchange_result
+                    .as_ref()
+                    .map_or_else(ToString::to_string, |_| "gracefully".to_string())
+            );
+        }
 
         match exchange_result {
             Ok(_) => {
@@ -362,12 +1349,17 @@ impl Tunnel {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn on_datagram_mux_request<F: Fn(pipe::SimplexDirection, usize) + Send + Clone + Sync>(
         context: Arc<core::Context>,
         forwarder: Arc<Mutex<Box<dyn Forwarder>>>,
-        request: Box<dyn PendingDatagramMultiplexerRequest>,
+        mut request: Box<dyn PendingDatagramMultiplexerRequest>,
         forwarder_auth: Option<authentication::Source<'static>>,
         tls_domain: String,
+        impairment: Option<&ImpairmentSettings>,
+        quota: Option<&QuotaSession>,
+        profile: Option<Arc<Profile>>,
+        timing: Option<ServerTiming>,
         update_metrics: F,
     ) -> Result<
         (),
@@ -378,6 +1370,15 @@ impl Tunnel {
         ),
     > {
         let request_id = request.id();
+        let identity = forwarder_auth
+            .as_ref()
+            .and_then(authentication::Source::username);
+        let request_id = if log_utils::is_trace_requested(identity.as_deref(), None) {
+            log_id!(info, request_id, "Tracing datagram multiplexer request");
+            request_id.traced()
+        } else {
+            request_id
+        };
         let client_address = match request.client_address() {
             Ok(x) => x,
             Err(e) => {
@@ -405,6 +1406,29 @@ impl Tunnel {
             }
         }
 
+        let destination_acl = forwarder_auth
+            .as_ref()
+            .zip(context.authenticator.as_ref())
+            .and_then(|(source, x)| x.destination_acl(source));
+        // The target is checked before the request is answered, as a TCP destination is
+        if let Some(target) = request.connect_udp_target() {
+            match Self::check_connect_udp_target(
+                &context,
+                &request_id,
+                target,
+                profile.as_deref(),
+                destination_acl.as_deref(),
+            )
+            .await
+            {
+                Ok(x) => request.set_connect_udp_destination(x),
+                Err(e) => return Err((Some(request), "CONNECT-UDP target refused", e)),
+            }
+        }
+
+        if let Some(x) = &timing {
+            request.add_ok_header(server_timing::HEADER, x.header_value());
+        }
         let mut pipe: Box<dyn datagram_pipe::DuplexPipe> = match request.promote_to_next_state() {
             Ok(downstream::DatagramPipeHalves::Udp(dstr_source, dstr_sink)) => {
                 let meta = forwarder::UdpMultiplexerMeta {
@@ -429,10 +1453,19 @@ impl Tunnel {
                 };
 
                 Box::new(udp_pipe::DuplexPipe::new(
-                    (dstr_source, dstr_sink),
-                    (fwd_shared, fwd_source, fwd_sink),
+                    (
+                        impairment::impair_datagrams(dstr_source, impairment),
+                        dstr_sink,
+                    ),
+                    (
+                        fwd_shared,
+                        impairment::impair_datagrams(fwd_source, impairment),
+                        fwd_sink,
+                    ),
                     update_metrics,
                     context.settings.udp_connections_timeout,
+                    profile,
+                    destination_acl,
                 ))
             }
             Ok(downstream::DatagramPipeHalves::Icmp(dstr_source, dstr_sink)) => {
@@ -459,8 +1492,16 @@ impl Tunnel {
                 };
 
                 Box::new(datagram_pipe::GenericDuplexPipe::new(
-                    (pipe::SimplexDirection::Outgoing, dstr_source, fwd_sink),
-                    (pipe::SimplexDirection::Incoming, fwd_source, dstr_sink),
+                    (
+                        pipe::SimplexDirection::Outgoing,
+                        impairment::impair_datagrams(dstr_source, impairment),
+                        fwd_sink,
+                    ),
+                    (
+                        pipe::SimplexDirection::Incoming,
+                        impairment::impair_datagrams(fwd_source, impairment),
+                        dstr_sink,
+                    ),
                     update_metrics,
                 ))
             }
@@ -474,7 +1515,7 @@ impl Tunnel {
         };
 
         let mut revalidate_interval = tokio::time::interval(Duration::from_secs(30));
-        let exchange = pipe.exchange();
+        let exchange = capacity::metered(Category::Pipe, pipe.exchange());
         tokio::pin!(exchange);
 
         let exchange_result = loop {
@@ -482,11 +1523,14 @@ impl Tunnel {
                 res = &mut exchange => break res,
                 _ = revalidate_interval.tick() => {
                     if let (Some(auth), Some(authenticator)) = (&forwarder_auth, context.authenticator.as_ref()) {
-                        if authenticator.authenticate(auth, &request_id) == Status::Reject {
+                        if authenticator.revalidate(auth, &request_id) == Status::Reject {
                             break Err(io::Error::new(ErrorKind::PermissionDenied, "Authentication revoked"));
                         }
                     }
                 }
+                _ = QuotaSession::exceeded(quota) => {
+                    break Err(io::Error::new(ErrorKind::PermissionDenied, "Data quota used up"));
+                }
             }
         };
 
@@ -502,4 +1546,80 @@ impl Tunnel {
             )),
         }
     }
+
+    /// Check the canonical target of a CONNECT-UDP request against the metadata endpoints,
+    /// the profile and the destination ACL of the client, and resolve it into the address
+    /// the datagrams are relayed to
+    async fn check_connect_udp_target(
+        context: &core::Context,
+        request_id: &log_utils::IdChain<u64>,
+        target: TcpDestination,
+        profile: Option<&Profile>,
+        destination_acl: Option<&DestinationAcl>,
+    ) -> Result<SocketAddr, ConnectionError> {
+        let metadata_allowed = context.settings.allow_metadata_endpoint_connections;
+        if !metadata_allowed && net_utils::is_metadata_destination(&target) {
+            log_id!(debug, request_id, "CONNECT-UDP: metadata endpoint refused");
+            return Err(ConnectionError::MetadataEndpoint);
+        }
+        let (host, addresses) = match target {
+            TcpDestination::Address(x) => (None, vec![x]),
+            TcpDestination::HostName((host, port)) => {
+                let addresses = tokio::net::lookup_host((host.as_str(), port))
+                    .await
+                    .map_err(ConnectionError::Io)?
+                    .collect();
+                (Some(host), addresses)
+            }
+        };
+        let name = host
+            .clone()
+            .or_else(|| addresses.first().map(|x| x.ip().to_string()))
+            .unwrap_or_default();
+        if profile.is_some_and(|x| !x.is_allowed(&name)) {
+            log_id!(debug, request_id, "CONNECT-UDP: {} denied by profile", name);
+            return Err(ConnectionError::DestinationDenied);
+        }
+
+        let mut refusal = None;
+        for x in addresses
+            .into_iter()
+            .filter(|x| context.settings.ipv6_available || x.is_ipv4())
+        {
+            if destination_acl.is_some_and(|acl| !acl.is_allowed(host.as_deref(), x.ip(), x.port()))
+            {
+                refusal.get_or_insert(ConnectionError::DestinationDenied);
+                continue;
+            }
+            // Any name may resolve to a metadata service address
+            if !metadata_allowed && net_utils::is_metadata_address(&x.ip()) {
+                refusal.get_or_insert(ConnectionError::MetadataEndpoint);
+                continue;
+            }
+            log_id!(debug, request_id, "CONNECT-UDP: {} resolved to {}", name, x);
+            return Ok(x);
+        }
+
+        log_id!(
+            debug,
+            request_id,
+            "CONNECT-UDP: no usable address of {}",
+            name
+        );
+        Err(refusal.unwrap_or_else(|| {
+            ConnectionError::Io(io::Error::new(
+                ErrorKind::Other,
+                format!("No usable address of CONNECT-UDP target {}", name),
+            ))
+        }))
+    }
+}
+
+/// Whether the listener of the protocol responds to a CONNECT request before connecting to the peer
+fn is_fast_connect_ack(settings: &ListenProtocolSettings, protocol: Protocol) -> bool {
+    match protocol {
+        Protocol::Http1 => settings.http1.as_ref().is_some_and(|x| x.fast_connect_ack),
+        Protocol::Http2 => settings.http2.as_ref().is_some_and(|x| x.fast_connect_ack),
+        Protocol::Http3 => settings.quic.as_ref().is_some_and(|x| x.fast_connect_ack),
+    }
 }
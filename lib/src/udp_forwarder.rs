@@ -1,13 +1,17 @@
 use crate::forwarder::UdpMultiplexer;
 use crate::metrics::OutboundUdpSocketCounter;
-use crate::{core, datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils};
+use crate::port_blocks::PortLease;
+use crate::{
+    authentication, core, datagram_pipe, downstream, egress, forwarder, log_id, log_utils,
+    net_utils,
+};
 use async_trait::async_trait;
 use bytes::Bytes;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, LinkedList};
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -18,6 +22,7 @@ struct Connection {
     socket: Arc<UdpSocket>,
     being_listened: bool,
     _metrics_guard: OutboundUdpSocketCounter,
+    _port_lease: Option<PortLease>,
 }
 
 type Connections = HashMap<forwarder::UdpDatagramMeta, Connection>;
@@ -25,6 +30,8 @@ type Connections = HashMap<forwarder::UdpDatagramMeta, Connection>;
 struct MultiplexerShared {
     connections: Mutex<Connections>,
     context: Arc<core::Context>,
+    /// The client the sockets are opened for, selects the source addresses
+    auth: Option<authentication::Source<'static>>,
 }
 
 struct MultiplexerSource {
@@ -52,10 +59,12 @@ enum PollStatus {
 pub(crate) fn make_multiplexer(
     context: Arc<core::Context>,
     id: log_utils::IdChain<u64>,
+    auth: Option<authentication::Source<'static>>,
 ) -> io::Result<UdpMultiplexer> {
     let shared = Arc::new(MultiplexerShared {
         connections: Mutex::new(Default::default()),
         context,
+        auth,
     });
     let (wake_tx, wake_rx) = sync::mpsc::channel(1);
 
@@ -178,11 +187,39 @@ impl forwarder::UdpDatagramPipeShared for MultiplexerShared {
         {
             Entry::Occupied(_) => Err(io::Error::new(ErrorKind::Other, "Already present")),
             Entry::Vacant(e) => {
+                if !self.context.settings.allow_metadata_endpoint_connections
+                    && net_utils::is_metadata_address(&meta.destination.ip())
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Cloud metadata endpoint is forbidden",
+                    ));
+                }
                 let metrics_guard = self.context.metrics.clone().outbound_udp_socket_counter();
+                let source =
+                    egress::select(&self.context, self.auth.as_ref(), meta.destination.ip());
+                let port_lease = match (
+                    self.context.port_blocks.as_ref(),
+                    self.auth
+                        .as_ref()
+                        .and_then(authentication::Source::username),
+                ) {
+                    (Some(blocks), Some(username)) => {
+                        Some(blocks.lease(source, &username).ok_or_else(|| {
+                            io::Error::new(ErrorKind::AddrInUse, "No free source port block")
+                        })?)
+                    }
+                    _ => None,
+                };
                 e.insert(Connection {
-                    socket: Arc::new(make_udp_socket(&meta.destination)?),
+                    socket: Arc::new(make_udp_socket(
+                        &meta.destination,
+                        source,
+                        port_lease.as_ref(),
+                    )?),
                     being_listened: false,
                     _metrics_guard: metrics_guard,
+                    _port_lease: port_lease,
                 });
                 Ok(())
             }
@@ -263,8 +300,16 @@ impl datagram_pipe::Sink for MultiplexerSink {
     }
 }
 
-fn make_udp_socket(peer: &SocketAddr) -> io::Result<UdpSocket> {
-    let socket = net_utils::make_udp_socket(peer.is_ipv4())?;
+fn make_udp_socket(
+    peer: &SocketAddr,
+    source: Option<IpAddr>,
+    port_lease: Option<&PortLease>,
+) -> io::Result<UdpSocket> {
+    let socket = match (port_lease, source) {
+        (Some(x), _) => x.bind_udp(peer)?,
+        (None, Some(x)) => std::net::UdpSocket::bind((x, 0))?,
+        (None, None) => net_utils::make_udp_socket(peer.is_ipv4())?,
+    };
     socket.connect(peer)?;
     socket.set_nonblocking(true)?;
     UdpSocket::from_std(socket)
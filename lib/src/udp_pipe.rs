@@ -1,3 +1,5 @@
+use crate::authentication::destination_acl::DestinationAcl;
+use crate::profiles::Profile;
 use crate::{datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils, pipe};
 use async_trait::async_trait;
 use futures::future;
@@ -21,6 +23,10 @@ struct LeftPipe<F: Send + Sync> {
     shared: Arc<UdpPipeShared<F>>,
     direction: pipe::SimplexDirection,
     next_connection_id: std::ops::RangeFrom<u64>,
+    /// The profile of the client restricting the destinations
+    profile: Option<Arc<Profile>>,
+    /// The destinations the authenticated client is restricted to
+    destination_acl: Option<Arc<DestinationAcl>>,
 }
 
 /// Forwards UDP packets from a target host to a client
@@ -94,6 +100,27 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> LeftPipe<F> {
             return Ok(());
         }
 
+        if self
+            .profile
+            .as_ref()
+            .is_some_and(|x| !x.is_allowed(&meta.destination.ip().to_string()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Destination is not allowed by client profile",
+            ));
+        }
+        if self
+            .destination_acl
+            .as_ref()
+            .is_some_and(|x| !x.is_allowed(None, meta.destination.ip(), meta.destination.port()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Destination is not allowed by client ACL",
+            ));
+        }
+
         let is_plain_dns = meta.destination.port() == net_utils::PLAIN_DNS_PORT_NUMBER;
         self.shared.udp_connections.lock().unwrap().insert(
             forwarder::UdpDatagramMeta::from(meta),
@@ -221,6 +248,8 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> DuplexPipe<F> {
         ),
         update_metrics: F,
         timeout: Duration,
+        profile: Option<Arc<Profile>>,
+        destination_acl: Option<Arc<DestinationAcl>>,
     ) -> Self {
         let shared = Arc::new(UdpPipeShared {
             udp_connections: Mutex::new(Default::default()),
@@ -235,6 +264,8 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> DuplexPipe<F> {
                 shared: shared.clone(),
                 direction: pipe::SimplexDirection::Outgoing,
                 next_connection_id: 0..,
+                profile,
+                destination_acl,
             },
             right_pipe: RightPipe {
                 source: source2,
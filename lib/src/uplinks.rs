@@ -0,0 +1,219 @@
+//! The racing of the tunneled TCP connections across the egress uplinks of a multi-uplink
+//! host. A connection matching a routing rule with the uplinks is attempted over the healthiest
+//! of them first, and over each next one [`EgressUplinksSettings::race_delay`] later
+//! unless the previous attempts have failed already, like the Happy Eyeballs do for
+//! the address families. The first established connection is kept, the others are dropped.
+//!
+//! The health score of an uplink is the moving average of its connection times.
+//! A failed attempt counts as [`FAILURE_PENALTY`], and an attempt dropped in favor
+//! of the winner counts as the time it had taken by then.
+
+use crate::metrics::Metrics;
+use crate::net_utils;
+use crate::settings::EgressUplinksSettings;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::Instant;
+
+/// The connection time a failed attempt is scored as
+const FAILURE_PENALTY: Duration = Duration::from_secs(10);
+/// The weight of the scores accumulated so far relative to a new sample
+const SCORE_SMOOTHING: u32 = 4;
+
+/// The egress uplinks along with their health scores
+pub(crate) struct UplinkSet {
+    uplinks: Vec<Uplink>,
+    race_delay: Duration,
+}
+
+struct Uplink {
+    name: String,
+    interface: String,
+    /// The moving average of the connection times, [`None`] if the uplink is not tried yet
+    score: Mutex<Option<Duration>>,
+}
+
+impl UplinkSet {
+    pub fn new(settings: &EgressUplinksSettings) -> Self {
+        Self {
+            uplinks: settings
+                .uplink
+                .iter()
+                .map(|x| Uplink {
+                    name: x.name.clone(),
+                    interface: x.interface.clone(),
+                    score: Default::default(),
+                })
+                .collect(),
+            race_delay: settings.race_delay,
+        }
+    }
+
+    /// Get the uplinks of the `names` in the order of trying: the untried ones first,
+    /// then the others by their scores. The unknown names are skipped.
+    pub fn candidates(&self, names: &[String]) -> Vec<&str> {
+        let mut candidates: Vec<_> = self
+            .uplinks
+            .iter()
+            .filter(|x| names.contains(&x.name))
+            .map(|x| (*x.score.lock().unwrap(), x.name.as_str()))
+            .collect();
+        // The sort is stable, so the configured order breaks the ties
+        candidates.sort_by_key(|(score, _)| *score);
+        candidates.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Race a connection to the `peer` across the `candidates` in their order.
+    /// Returns the established connection and the name of the uplink it goes through.
+    pub async fn race<'a>(
+        &self,
+        metrics: &Metrics,
+        candidates: &[&'a str],
+        peer: SocketAddr,
+        max_segment_size: Option<u16>,
+    ) -> io::Result<(TcpStream, &'a str)> {
+        let mut next = 0;
+        let mut started: Vec<(&str, Instant)> = Vec::with_capacity(candidates.len());
+        let mut attempts = FuturesUnordered::new();
+        let mut error = None;
+        let mut start_next = true;
+
+        loop {
+            if start_next {
+                start_next = false;
+                match candidates.get(next) {
+                    Some(&name) => {
+                        next += 1;
+                        let uplink = self.uplink(name);
+                        started.push((name, Instant::now()));
+                        attempts.push(async move {
+                            (
+                                name,
+                                connect(&uplink.interface, peer, max_segment_size).await,
+                            )
+                        });
+                    }
+                    None if attempts.is_empty() => break,
+                    None => (),
+                }
+            }
+
+            tokio::select! {
+                Some((name, result)) = attempts.next() => {
+                    let elapsed = started
+                        .iter()
+                        .find(|(x, _)| *x == name)
+                        .map(|(_, x)| x.elapsed())
+                        .unwrap_or_default();
+                    started.retain(|(x, _)| *x != name);
+                    match result {
+                        Ok(stream) => {
+                            self.report(metrics, name, Some(elapsed));
+                            metrics.add_egress_uplink_win(name);
+                            // The dropped attempts took at least that long
+                            for (x, started_at) in started {
+                                self.report(metrics, x, Some(started_at.elapsed()));
+                            }
+                            return Ok((stream, name));
+                        }
+                        Err(e) => {
+                            debug!("Connection to {} over uplink {} failed: {}", peer, name, e);
+                            self.report(metrics, name, None);
+                            error = Some(e);
+                            start_next = true;
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(self.race_delay), if next < candidates.len() => start_next = true,
+                else => break,
+            }
+        }
+
+        Err(error.unwrap_or_else(|| io::Error::new(ErrorKind::Other, "No uplinks to race")))
+    }
+
+    fn uplink(&self, name: &str) -> &Uplink {
+        self.uplinks
+            .iter()
+            .find(|x| x.name == name)
+            .expect("Uplink is not configured")
+    }
+
+    /// Record the connection time of an attempt over the uplink, [`None`] if it failed
+    fn report(&self, metrics: &Metrics, name: &str, time: Option<Duration>) {
+        let sample = time.unwrap_or(FAILURE_PENALTY);
+        let mut score = self.uplink(name).score.lock().unwrap();
+        let updated = match *score {
+            None => sample,
+            Some(x) => (x * (SCORE_SMOOTHING - 1) + sample) / SCORE_SMOOTHING,
+        };
+        *score = Some(updated);
+        metrics.set_egress_uplink_score(name, updated);
+    }
+}
+
+/// Connect to the `peer` through the network `interface`
+async fn connect(
+    interface: &str,
+    peer: SocketAddr,
+    max_segment_size: Option<u16>,
+) -> io::Result<TcpStream> {
+    let (socket, family) = match peer {
+        SocketAddr::V4(_) => (TcpSocket::new_v4()?, libc::AF_INET),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, libc::AF_INET6),
+    };
+    net_utils::bind_to_interface(socket.as_raw_fd(), family, interface)?;
+    if let Some(x) = max_segment_size {
+        net_utils::set_tcp_max_segment_size(socket.as_raw_fd(), x)?;
+    }
+    socket.connect(peer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn healthiest_uplinks_are_tried_first() {
+        let settings = EgressUplinksSettings::builder()
+            .uplink("isp1", "eth0")
+            .uplink("isp2", "eth1")
+            .uplink("lte", "wwan0")
+            .build()
+            .unwrap();
+        let uplinks = UplinkSet::new(&settings);
+        let metrics = Metrics::new().unwrap();
+        let all = names(&["lte", "isp2", "isp1"]);
+        assert_eq!(vec!["isp1", "isp2", "lte"], uplinks.candidates(&all));
+        assert_eq!(
+            vec!["isp2"],
+            uplinks.candidates(&names(&["isp2", "unknown"]))
+        );
+
+        uplinks.report(&metrics, "isp1", Some(Duration::from_millis(80)));
+        uplinks.report(&metrics, "isp2", Some(Duration::from_millis(20)));
+        // The untried one is given a chance
+        assert_eq!(vec!["lte", "isp2", "isp1"], uplinks.candidates(&all));
+
+        uplinks.report(&metrics, "lte", Some(Duration::from_millis(200)));
+        assert_eq!(vec!["isp2", "isp1", "lte"], uplinks.candidates(&all));
+
+        // A failure outweighs a good history
+        uplinks.report(&metrics, "isp2", None);
+        assert_eq!(vec!["isp1", "lte", "isp2"], uplinks.candidates(&all));
+        assert_eq!(
+            Some(Duration::from_millis(2515)),
+            *uplinks.uplink("isp2").score.lock().unwrap()
+        );
+    }
+}
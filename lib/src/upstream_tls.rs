@@ -0,0 +1,302 @@
+use crate::metrics::OutboundTcpSocketCounter;
+use crate::settings::UpstreamTlsSettings;
+use crate::{log_utils, pipe, utils};
+use async_trait::async_trait;
+use base64::Engine;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+
+/// The TLS client of an upstream hop
+pub(crate) struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    verifier: Arc<Verifier>,
+    connector: tokio_rustls::TlsConnector,
+    /// The configured name, [`None`] if the certificate is checked against the hop address
+    server_name: Option<ServerName>,
+}
+
+/// Runs the regular certificate verification unless it is disabled,
+/// then checks the chain against the pinned public keys
+struct Verifier {
+    webpki: WebPkiVerifier,
+    /// The decoded [`UpstreamTlsSettings.spki_pins`]
+    pins: Vec<Vec<u8>>,
+    insecure_skip_verify: bool,
+}
+
+/// Checks the certificate against the configured name instead of the one
+/// the client connects to
+struct NamedVerifier {
+    inner: Arc<Verifier>,
+    server_name: ServerName,
+}
+
+/// Keeps the connection counted in the metrics while the stream is open
+struct CountedSource {
+    inner: Box<dyn pipe::Source>,
+    _metrics_guard: OutboundTcpSocketCounter,
+}
+
+impl UpstreamTls {
+    /// Prepare the client of the hop named `hop` in the logs
+    pub fn new(settings: &UpstreamTlsSettings, hop: &str) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match &settings.ca_bundle_path {
+            Some(path) => {
+                for x in utils::load_certs(path)? {
+                    roots.add(&x).map_err(|e| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid CA certificate in {}: {}", path, e),
+                        )
+                    })?;
+                }
+            }
+            None if !settings.insecure_skip_verify => {
+                let native = rustls_native_certs::load_native_certs();
+                roots.add_parsable_certificates(&native.certs);
+            }
+            None => (),
+        }
+        if roots.is_empty() && !settings.insecure_skip_verify {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "No trusted CA certificates found",
+            ));
+        }
+
+        if settings.insecure_skip_verify {
+            warn!(
+                "Certificate verification of the {} is DISABLED, \
+                the connections are open to interception",
+                hop
+            );
+        }
+
+        let verifier = Arc::new(Verifier {
+            webpki: WebPkiVerifier::new(roots, None),
+            pins: settings
+                .spki_pins
+                .iter()
+                .map(|x| base64::engine::general_purpose::STANDARD.decode(x))
+                .collect::<Result<_, _>>()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+            insecure_skip_verify: settings.insecure_skip_verify,
+        });
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+
+        let server_name = settings
+            .server_name
+            .as_ref()
+            .map(|x| ServerName::try_from(x.as_str()))
+            .transpose()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        let config = Arc::new(config);
+        Ok(Self {
+            connector: config.clone().into(),
+            config,
+            verifier,
+            server_name,
+        })
+    }
+
+    /// Run the TLS handshake over the connection to the hop listening on `address`
+    pub async fn connect<IO>(&self, io: IO, address: SocketAddr) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector.connect(self.server_name(address), io).await
+    }
+
+    /// Run the TLS handshake over the connection to a server named `server_name`
+    /// offering the application protocols `alpn`
+    pub async fn connect_with<IO>(
+        &self,
+        io: IO,
+        server_name: ServerName,
+        alpn: Vec<Vec<u8>>,
+    ) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut config = ClientConfig::clone(&self.config);
+        config.alpn_protocols = alpn;
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(server_name, io)
+            .await
+    }
+
+    /// Run the TLS handshake over the blocking connection to the hop listening on `address`
+    pub fn connect_blocking<IO>(
+        &self,
+        mut io: IO,
+        address: SocketAddr,
+    ) -> io::Result<StreamOwned<ClientConnection, IO>>
+    where
+        IO: Read + Write,
+    {
+        let mut connection = ClientConnection::new(self.config.clone(), self.server_name(address))
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut io)?;
+        }
+        Ok(StreamOwned::new(connection, io))
+    }
+
+    /// Get the configuration for a client which takes the server name from the address
+    /// it connects to, e.g., the host of a URL. The certificate is checked against
+    /// the configured name still, if any.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        let Some(server_name) = &self.server_name else {
+            return self.config.clone();
+        };
+        let mut config = ClientConfig::clone(&self.config);
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NamedVerifier {
+                inner: self.verifier.clone(),
+                server_name: server_name.clone(),
+            }));
+        Arc::new(config)
+    }
+
+    fn server_name(&self, address: SocketAddr) -> ServerName {
+        self.server_name
+            .clone()
+            .unwrap_or(ServerName::IpAddress(address.ip()))
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.insecure_skip_verify {
+            self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let is_pinned = |x: &Certificate| {
+            spki_digest(x).is_some_and(|d| self.pins.iter().any(|p| p == d.as_ref()))
+        };
+        if !self.pins.is_empty()
+            && !std::iter::once(end_entity)
+                .chain(intermediates)
+                .any(is_pinned)
+        {
+            return Err(rustls::Error::General(
+                "No certificate matches the pinned public keys".to_string(),
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ServerCertVerifier for NamedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// Get the SHA-256 digest of the certificate SubjectPublicKeyInfo
+fn spki_digest(certificate: &Certificate) -> Option<ring::digest::Digest> {
+    let (_, x) = x509_parser::parse_x509_certificate(&certificate.0).ok()?;
+    Some(ring::digest::digest(
+        &ring::digest::SHA256,
+        x.tbs_certificate.subject_pki.raw,
+    ))
+}
+
+/// Make a pipe of a TLS connection to an upstream hop
+pub(crate) fn pipe_from_stream<S>(
+    stream: S,
+    id: log_utils::IdChain<u64>,
+    metrics_guard: OutboundTcpSocketCounter,
+) -> (Box<dyn pipe::Source>, Box<dyn pipe::Sink>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (source, sink) = pipe::from_io(stream, id);
+    (
+        Box::new(CountedSource {
+            inner: source,
+            _metrics_guard: metrics_guard,
+        }),
+        sink,
+    )
+}
+
+#[async_trait]
+impl pipe::Source for CountedSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.inner.id()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        self.inner.read().await
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        self.inner.consume(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::UpstreamTlsSettings;
+
+    #[test]
+    fn insecure_skip_verify_needs_no_roots() {
+        let settings = UpstreamTlsSettings::builder()
+            .ca_bundle_path("/nonexistent/ca.pem")
+            .build()
+            .unwrap();
+        assert!(UpstreamTls::new(&settings, "test hop").is_err());
+
+        let settings = UpstreamTlsSettings::builder()
+            .insecure_skip_verify(true)
+            .build()
+            .unwrap();
+        assert!(UpstreamTls::new(&settings, "test hop").is_ok());
+    }
+}
@@ -0,0 +1,313 @@
+//! The tunnels carried over a [WebSocket](https://datatracker.ietf.org/doc/html/rfc6455)
+//! connection, so that the endpoint may operate behind the CDNs and the reverse proxies
+//! passing nothing but the WebSocket traffic. A client upgrades an HTTP/1.1 request
+//! to [`WebSocketSettings::path`](crate::settings::WebSocketSettings) and then speaks HTTP/2
+//! over the binary messages of the connection, the regular tunnel requests included.
+//!
+//! The upgrade request is parsed by the HTTP/1.1 codec, so the handshake response is sent
+//! through it, and [`tokio_tungstenite`] takes over the framing of the upgraded stream.
+//! The message boundaries carry no meaning, the payloads of the binary messages make up
+//! a byte stream, like the one of a TLS connection.
+
+use crate::http_codec::RequestHeaders;
+use crate::{http_codec, net_utils, pipe};
+use base64::Engine;
+use futures::{Sink, Stream};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+const VERSION: &str = "13";
+/// The length of the decoded `Sec-WebSocket-Key` value
+const KEY_LENGTH: usize = 16;
+/// The largest payload of a binary message the writes are split into
+const MAX_MESSAGE_PAYLOAD_LENGTH: usize = 16 * 1024;
+
+/// The byte stream of the payloads of the binary messages of a WebSocket connection
+pub(crate) struct WebSocketIo<IO> {
+    ws: WebSocketStream<IO>,
+    peer: SocketAddr,
+    /// The payload of the message being read
+    message: Vec<u8>,
+    /// The position in the payload the next read starts from
+    offset: usize,
+    /// Set once the client has closed the connection
+    is_eof: bool,
+}
+
+/// Check whether the `request` asks for a WebSocket connection to the tunnel `path`
+pub(crate) fn is_upgrade(request: &RequestHeaders, path: &str) -> bool {
+    request.method == http::Method::GET
+        && request.uri.path() == path
+        && request
+            .headers
+            .get(http::header::UPGRADE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// Complete the handshake of the upgrade request of the `stream`, giving the connection.
+/// An invalid request is responded with an error and [`None`] is returned.
+pub(crate) async fn accept(
+    stream: Box<dyn http_codec::Stream>,
+) -> io::Result<Option<WebSocketIo<pipe::PipeIo>>> {
+    let peer = SocketAddr::new(stream.request().client_address()?, 0);
+    let key = handshake_key(stream.request().request());
+    let (request, respond) = stream.split();
+    let key = match key {
+        Some(x) => x,
+        None => {
+            respond.send_bad_response(
+                http::StatusCode::BAD_REQUEST,
+                vec![("sec-websocket-version".to_string(), VERSION.to_string())],
+            )?;
+            return Ok(None);
+        }
+    };
+
+    let response = http::Response::builder()
+        .status(http::StatusCode::SWITCHING_PROTOCOLS)
+        .header(http::header::UPGRADE, "websocket")
+        .header(http::header::CONNECTION, "Upgrade")
+        .header(
+            http::header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        )
+        .body(())
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?
+        .into_parts()
+        .0;
+    let sink = respond.send_response(response, false)?.into_pipe_sink();
+
+    Ok(Some(
+        WebSocketIo::new(pipe::into_io(request.finalize(), sink), peer).await,
+    ))
+}
+
+/// Get the key of a valid handshake `request`
+fn handshake_key(request: &RequestHeaders) -> Option<String> {
+    let header = |name| {
+        request
+            .headers
+            .get(name)
+            .and_then(|x: &http::HeaderValue| x.to_str().ok())
+    };
+
+    let is_upgrade = header(http::header::CONNECTION)?
+        .split(',')
+        .any(|x| x.trim().eq_ignore_ascii_case("upgrade"));
+    if request.version != http::Version::HTTP_11
+        || !is_upgrade
+        || header(http::header::SEC_WEBSOCKET_VERSION)? != VERSION
+    {
+        return None;
+    }
+
+    let key = header(http::header::SEC_WEBSOCKET_KEY)?.trim();
+    base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .ok()
+        .filter(|x| x.len() == KEY_LENGTH)
+        .map(|_| key.to_string())
+}
+
+fn into_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => ErrorKind::BrokenPipe.into(),
+        e => io::Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+impl<IO> WebSocketIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap the upgraded `io`, the handshake of which is complete
+    async fn new(io: IO, peer: SocketAddr) -> Self {
+        Self {
+            ws: WebSocketStream::from_raw_socket(io, Role::Server, None).await,
+            peer,
+            message: Default::default(),
+            offset: 0,
+            is_eof: false,
+        }
+    }
+}
+
+impl<IO> AsyncRead for WebSocketIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.offset < this.message.len() {
+                let n = (this.message.len() - this.offset).min(buf.remaining());
+                buf.put_slice(&this.message[this.offset..this.offset + n]);
+                this.offset += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.is_eof || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // The replies to the control messages are sent by the library
+            match futures::ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(x))) => (this.message, this.offset) = (x, 0),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Unexpected text message",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => this.is_eof = true,
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => (),
+                Some(Err(WsError::ConnectionClosed)) => this.is_eof = true,
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<IO> AsyncWrite for WebSocketIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(Pin::new(&mut this.ws).poll_ready(cx)).map_err(into_io_error)?;
+        let n = buf.len().min(MAX_MESSAGE_PAYLOAD_LENGTH);
+        Pin::new(&mut this.ws)
+            .start_send(Message::Binary(buf[..n].to_vec()))
+            .map_err(into_io_error)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Sends the Close message, unless the client has sent one already
+        match futures::ready!(Pin::new(&mut self.get_mut().ws).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(into_io_error(e))),
+        }
+    }
+}
+
+impl<IO> net_utils::PeerAddr for WebSocketIo<IO> {
+    /// The port is not known as the request of the connection is passed by the HTTP codec
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeaders {
+        let mut builder = http::Request::builder().uri("/ws");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    async fn server(io: tokio::io::DuplexStream) -> WebSocketIo<tokio::io::DuplexStream> {
+        WebSocketIo::new(io, (std::net::Ipv4Addr::LOCALHOST, 0).into()).await
+    }
+
+    #[test]
+    fn handshakes() {
+        let valid = [
+            ("upgrade", "websocket"),
+            ("connection", "keep-alive, Upgrade"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ];
+        assert!(is_upgrade(&request(&valid), "/ws"));
+        assert!(!is_upgrade(&request(&valid), "/tunnel"));
+        assert_eq!(
+            Some("dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+            handshake_key(&request(&valid))
+        );
+
+        let mut old_version = valid;
+        old_version[2].1 = "8";
+        assert_eq!(None, handshake_key(&request(&old_version)));
+        let mut short_key = valid;
+        short_key[3].1 = "c2hvcnQ=";
+        assert_eq!(None, handshake_key(&request(&short_key)));
+        assert_eq!(None, handshake_key(&request(&valid[..2])));
+    }
+
+    #[tokio::test]
+    async fn exchanges_messages() {
+        let (client, server_io) = tokio::io::duplex(64 * 1024);
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = server(server_io).await;
+
+        let long = vec![b'x'; 300];
+        client
+            .send(Message::Binary(b"hello, ".to_vec()))
+            .await
+            .unwrap();
+        client.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+        client.send(Message::Binary(long.clone())).await.unwrap();
+
+        let mut received = vec![0; 7 + long.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(b"hello, ", &received[..7]);
+        assert_eq!(long, received[7..]);
+
+        server.write_all(b"world").await.unwrap();
+        server.flush().await.unwrap();
+        let mut replies = vec![];
+        while replies.len() < 2 {
+            replies.push(client.next().await.unwrap().unwrap());
+        }
+        assert!(replies.contains(&Message::Pong(b"ping".to_vec())));
+        assert!(replies.contains(&Message::Binary(b"world".to_vec())));
+
+        client.close(None).await.unwrap();
+        assert_eq!(0, server.read(&mut received).await.unwrap());
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(Message::Close(_))) | None
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_unmasked_frames() {
+        let (mut client, server_io) = tokio::io::duplex(1024);
+        let mut server = server(server_io).await;
+        client.write_all(b"\x82\x02hi").await.unwrap();
+        let error = server.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+}
@@ -132,6 +132,7 @@ async fn run_endpoint(
             authentication::registry_based::Client {
                 username: "a".into(),
                 password: "b".into(),
+                ..Default::default()
             },
         )));
     }
@@ -193,14 +193,25 @@ tBkmxjMw8cFLCP9o7W7QSb9XIqfCyg4dX4Fl9l1fDNX/xK2c3dlDJv6Spi1IMdFY
 ";
 
 pub async fn run_endpoint(listen_address: &SocketAddr) {
-    let settings = Settings::builder()
-        .listen_address(listen_address)
-        .unwrap()
-        .listen_protocols(ListenProtocolSettings {
+    run_endpoint_with_protocols(
+        listen_address,
+        ListenProtocolSettings {
             http1: Some(Http1Settings::builder().build()),
             http2: Some(Http2Settings::builder().build()),
             quic: Some(QuicSettings::builder().build()),
-        })
+        },
+    )
+    .await;
+}
+
+pub async fn run_endpoint_with_protocols(
+    listen_address: &SocketAddr,
+    protocols: ListenProtocolSettings,
+) {
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(protocols)
         .allow_private_network_connections(true)
         .speedtest_enable(true)
         .build()
@@ -241,9 +252,9 @@ pub async fn run_endpoint(listen_address: &SocketAddr) {
 
 pub async fn run_endpoint_with_settings(settings: Settings, hosts_settings: TlsHostsSettings) {
     let shutdown = Shutdown::new();
-    let authenticator: Option<Arc<dyn Authenticator>> = if !settings.get_clients().is_empty() {
+    let authenticator: Option<Arc<dyn Authenticator>> = if !settings.clients_list().is_empty() {
         Some(Arc::new(RegistryBasedAuthenticator::new(
-            settings.get_clients(),
+            settings.clients_list(),
         )))
     } else {
         None
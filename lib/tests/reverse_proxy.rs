@@ -1,9 +1,12 @@
 use bytes::Bytes;
+use futures::future;
 use http::{Request, Response};
+use hyper::body::HttpBody;
 use log::info;
 use std::future::Future;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use trusttunnel::settings::{
     Http1Settings, Http2Settings, ListenProtocolSettings, QuicSettings, ReverseProxySettings,
@@ -58,6 +61,86 @@ reverse_proxy_tests! {
     path_h3: path_h3_client,
 }
 
+#[tokio::test]
+async fn chunked_trailers_h2() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let (proxy_address, proxy_task) = run_raw_proxy(
+        "HTTP/1.1 103 Early Hints\r\n\
+        link: </style.css>; rel=preload\r\n\
+        \r\n\
+        HTTP/1.1 200 OK\r\n\
+        transfer-encoding: chunked\r\n\
+        \r\n\
+        f\r\nhow much watch?\r\n\
+        0\r\n\
+        grpc-status: 0\r\n\
+        \r\n",
+    );
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // HTTP/2 reaches the reverse proxy as a website request to the main host
+        let stream = common::establish_tls_connection(
+            common::MAIN_DOMAIN_NAME,
+            &endpoint_address,
+            Some(b"h2"),
+        )
+        .await;
+        let (mut request, conn) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await
+            .unwrap();
+
+        let exchange = async {
+            let response = request
+                .send_request(
+                    Request::get(format!(
+                        "https://{}:{}/grpc",
+                        common::MAIN_DOMAIN_NAME,
+                        endpoint_address.port()
+                    ))
+                    .version(http::Version::HTTP_2)
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+            assert!(!response
+                .headers()
+                .contains_key(http::header::TRANSFER_ENCODING));
+
+            let mut body = response.into_body();
+            let mut content = Vec::new();
+            while let Some(x) = body.data().await {
+                content.extend_from_slice(&x.unwrap());
+            }
+            assert_eq!(content, b"how much watch?");
+            let trailers = body.trailers().await.unwrap().unwrap();
+            assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        };
+
+        futures::pin_mut!(exchange);
+        match future::select(conn, exchange).await {
+            future::Either::Left((r, _)) => panic!("HTTP connection closed: {:?}", r),
+            future::Either::Right(_) => (),
+        }
+    };
+
+    tokio::select! {
+        _ = run_endpoint_serving_non_tunnel_requests(
+            &endpoint_address,
+            &proxy_address,
+            true,
+        ) => unreachable!(),
+        _ = proxy_task => unreachable!(),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+        _ = client_task => (),
+    }
+}
+
 async fn sni_h1_client(endpoint_address: &SocketAddr) -> (http::response::Parts, Bytes) {
     let stream = common::establish_tls_connection(
         &format!("hello.{}", common::MAIN_DOMAIN_NAME),
@@ -133,6 +216,16 @@ async fn path_h3_client(endpoint_address: &SocketAddr) -> (http::response::Parts
 }
 
 async fn run_endpoint(endpoint_address: &SocketAddr, proxy_address: &SocketAddr) {
+    run_endpoint_serving_non_tunnel_requests(endpoint_address, proxy_address, false).await
+}
+
+/// Run the endpoint routing the plain requests to the main host to the reverse proxy
+/// in case of `serve_non_tunnel_requests`
+async fn run_endpoint_serving_non_tunnel_requests(
+    endpoint_address: &SocketAddr,
+    proxy_address: &SocketAddr,
+    serve_non_tunnel_requests: bool,
+) {
     let settings = Settings::builder()
         .listen_address(endpoint_address)
         .unwrap()
@@ -146,6 +239,7 @@ async fn run_endpoint(endpoint_address: &SocketAddr, proxy_address: &SocketAddr)
                 .server_address(proxy_address)
                 .unwrap()
                 .path_mask("/hello".to_string())
+                .serve_non_tunnel_requests(serve_non_tunnel_requests)
                 .build()
                 .unwrap(),
         )
@@ -193,6 +287,31 @@ fn run_proxy() -> (SocketAddr, impl Future<Output = ()>) {
     })
 }
 
+/// Run an origin server answering the first request with the raw `response`
+fn run_raw_proxy(response: &'static str) -> (SocketAddr, impl Future<Output = ()>) {
+    let server = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let _ = server.set_nonblocking(true);
+    let server_addr = server.local_addr().unwrap();
+    (server_addr, async move {
+        let (mut socket, peer) = TcpListener::from_std(server)
+            .unwrap()
+            .accept()
+            .await
+            .unwrap();
+        info!("New connection from {}", peer);
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buffer = [0; 1024];
+            let n = socket.read(&mut buffer).await.unwrap();
+            assert_ne!(n, 0);
+            request.extend_from_slice(&buffer[..n]);
+        }
+        socket.write_all(response.as_bytes()).await.unwrap();
+        future::pending::<()>().await;
+    })
+}
+
 async fn request_handler(
     request: Request<hyper::Body>,
 ) -> Result<Response<hyper::Body>, hyper::Error> {
@@ -10,6 +10,7 @@ use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, UdpSocket};
 use trusttunnel::net_utils;
+use trusttunnel::settings::{Http1Settings, ListenProtocolSettings};
 
 #[allow(dead_code)]
 mod common;
@@ -366,6 +367,50 @@ async fn h3_udp_upload() {
     }
 }
 
+#[tokio::test]
+async fn h1_fast_connect_ack() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let client_task = async {
+        // Nothing listens on the port once the listener is dropped
+        let server_address = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // The tunnel is acknowledged even though the peer is unreachable
+        let (conn_driver, io) = make_h1_tunnel(endpoint_address, server_address.to_string()).await;
+
+        let exchange = async {
+            let mut io = io.await;
+            let mut buf = [0; 1024];
+            assert!(!matches!(io.read(&mut buf).await, Ok(n) if n > 0));
+        };
+
+        futures::pin_mut!(exchange);
+        match future::select(conn_driver, exchange).await {
+            future::Either::Left((r, exchange)) => {
+                info!("HTTP connection closed with result: {:?}", r);
+                exchange.await
+            }
+            future::Either::Right(_) => (),
+        }
+    };
+
+    let protocols = ListenProtocolSettings {
+        http1: Some(Http1Settings::builder().fast_connect_ack(true).build()),
+        http2: None,
+        quic: None,
+    };
+    tokio::select! {
+        _ = common::run_endpoint_with_protocols(&endpoint_address, protocols) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
 async fn make_h1_tunnel(
     endpoint_address: SocketAddr,
     server_address: String,
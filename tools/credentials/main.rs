@@ -0,0 +1,140 @@
+use std::io::{BufRead, Write};
+use trusttunnel::authentication::migration::{self, ExportOptions, Format};
+
+const CONVERT_COMMAND_NAME: &str = "convert";
+const HASH_COMMAND_NAME: &str = "hash-password";
+const FROM_PARAM_NAME: &str = "from";
+const TO_PARAM_NAME: &str = "to";
+const INPUT_PARAM_NAME: &str = "input";
+const OUTPUT_PARAM_NAME: &str = "output";
+const HASH_PASSWORDS_PARAM_NAME: &str = "hash_passwords";
+const SQL_TABLE_PARAM_NAME: &str = "sql_table";
+const REDIS_KEY_PREFIX_PARAM_NAME: &str = "redis_key_prefix";
+
+fn main() {
+    let args = clap::Command::new("TrustTunnel credentials tool")
+        .about("Move the client credentials between the authenticator backends")
+        .after_help(
+            r#"The credentials are read from a credentials file (toml) or an htpasswd file,
+and are written as either of them, as an SQL script of INSERT statements (sql) or
+as a Redis mass insertion script (redis).
+
+EXAMPLES:
+    ./trusttunnel-credentials convert --from toml --input credentials.toml --to sql --hash-passwords | psql trusttunnel
+    ./trusttunnel-credentials convert --from htpasswd --input .htpasswd --to redis | redis-cli --pipe
+    ./trusttunnel-credentials convert --from toml --input credentials.toml --to toml --hash-passwords -o hashed.toml
+    ./trusttunnel-credentials hash-password < password.txt
+"#,
+        )
+        .subcommand_required(true)
+        .subcommands([
+            clap::Command::new(CONVERT_COMMAND_NAME)
+                .about("Convert the credentials from one format to another")
+                .args(&[
+                    clap::Arg::new(FROM_PARAM_NAME)
+                        .long("from")
+                        .action(clap::ArgAction::Set)
+                        .value_parser(["toml", "htpasswd"])
+                        .required(true)
+                        .help("The format of the input"),
+                    clap::Arg::new(INPUT_PARAM_NAME)
+                        .short('i')
+                        .long("input")
+                        .action(clap::ArgAction::Set)
+                        .required(true)
+                        .help("The path of the input file"),
+                    clap::Arg::new(TO_PARAM_NAME)
+                        .long("to")
+                        .action(clap::ArgAction::Set)
+                        .value_parser(["toml", "htpasswd", "sql", "redis"])
+                        .required(true)
+                        .help("The format of the output"),
+                    clap::Arg::new(OUTPUT_PARAM_NAME)
+                        .short('o')
+                        .long("output")
+                        .action(clap::ArgAction::Set)
+                        .help("The path of the output file, the standard output if not set"),
+                    clap::Arg::new(HASH_PASSWORDS_PARAM_NAME)
+                        .long("hash-passwords")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Replace the plain text passwords with their SHA-512 crypt hashes"),
+                    clap::Arg::new(SQL_TABLE_PARAM_NAME)
+                        .long("sql-table")
+                        .action(clap::ArgAction::Set)
+                        .default_value("clients")
+                        .help("The table of the SQL script"),
+                    clap::Arg::new(REDIS_KEY_PREFIX_PARAM_NAME)
+                        .long("redis-key-prefix")
+                        .action(clap::ArgAction::Set)
+                        .help("The key prefix of the Redis script, the default one of the endpoint if not set"),
+                ]),
+            clap::Command::new(HASH_COMMAND_NAME)
+                .about("Hash the password read from the standard input for the password_hash field"),
+        ])
+        .get_matches();
+
+    let result = match args.subcommand() {
+        Some((CONVERT_COMMAND_NAME, args)) => convert(args),
+        Some((HASH_COMMAND_NAME, _)) => hash_password(),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn convert(args: &clap::ArgMatches) -> Result<(), String> {
+    let format = |name| args.get_one::<String>(name).unwrap().parse::<Format>();
+    let from = format(FROM_PARAM_NAME)?;
+    let to = format(TO_PARAM_NAME)?;
+    let input = args.get_one::<String>(INPUT_PARAM_NAME).unwrap();
+
+    let content =
+        std::fs::read_to_string(input).map_err(|e| format!("Failed to read {}: {}", input, e))?;
+    let credentials = migration::import(from, &content)?;
+    for x in &credentials {
+        let dropped = migration::dropped_attributes(to, x);
+        if !dropped.is_empty() {
+            eprintln!("Dropping {} of {}", dropped.join(", "), x.username);
+        }
+    }
+
+    let mut options = ExportOptions {
+        hash_passwords: args.get_flag(HASH_PASSWORDS_PARAM_NAME),
+        sql_table: args
+            .get_one::<String>(SQL_TABLE_PARAM_NAME)
+            .unwrap()
+            .clone(),
+        ..Default::default()
+    };
+    if let Some(x) = args.get_one::<String>(REDIS_KEY_PREFIX_PARAM_NAME) {
+        options.redis_key_prefix = x.clone();
+    }
+    let output = migration::export(to, &credentials, &options)?;
+
+    match args.get_one::<String>(OUTPUT_PARAM_NAME) {
+        Some(path) => {
+            std::fs::write(path, output).map_err(|e| format!("Failed to write {}: {}", path, e))?
+        }
+        None => std::io::stdout()
+            .write_all(output.as_bytes())
+            .map_err(|e| e.to_string())?,
+    }
+    eprintln!("Converted {} clients", credentials.len());
+    Ok(())
+}
+
+fn hash_password() -> Result<(), String> {
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("Empty password".into());
+    }
+    println!("{}", migration::hash_password(password)?);
+    Ok(())
+}
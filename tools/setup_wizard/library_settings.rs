@@ -80,7 +80,11 @@ fn build_credentials() -> (String, Vec<Client>) {
 
     let clients = users
         .into_iter()
-        .map(|(username, password)| Client { username, password })
+        .map(|(username, password)| Client {
+            username,
+            password,
+            ..Default::default()
+        })
         .collect();
 
     (path, clients)
@@ -94,9 +98,41 @@ fn read_credentials_file(path: &str) -> Option<Vec<Client>> {
         tables
             .iter()
             .filter_map(|t| {
+                let password = t.get("password").and_then(Item::as_str);
+                let password_hash = t.get("password_hash").and_then(Item::as_str);
+                if password.is_none() && password_hash.is_none() {
+                    return None;
+                }
                 Some(Client {
                     username: t.get("username")?.as_str()?.to_string(),
-                    password: t.get("password")?.as_str()?.to_string(),
+                    password: password.unwrap_or_default().to_string(),
+                    password_hash: password_hash.map(str::to_string),
+                    totp_secret: t
+                        .get("totp_secret")
+                        .and_then(Item::as_str)
+                        .map(str::to_string),
+                    tier: t.get("tier").and_then(Item::as_str).map(str::to_string),
+                    egress_address: t
+                        .get("egress_address")
+                        .and_then(Item::as_str)
+                        .and_then(|x| x.parse().ok()),
+                    max_connections: t
+                        .get("max_connections")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| usize::try_from(x).ok()),
+                    max_streams: t
+                        .get("max_streams")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| usize::try_from(x).ok()),
+                    data_quota_bytes: t
+                        .get("data_quota_bytes")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| u64::try_from(x).ok()),
+                    data_quota_period_days: t
+                        .get("data_quota_period_days")
+                        .and_then(Item::as_integer)
+                        .and_then(|x| u32::try_from(x).ok()),
+                    profile: t.get("profile").and_then(Item::as_str).map(str::to_string),
                 })
             })
             .collect(),
@@ -13,7 +13,10 @@ pub fn build() -> RulesConfig {
 fn build_non_interactive() -> RulesConfig {
     // In non-interactive mode, generate empty rules
     // The actual examples will be in the serialized TOML comments
-    RulesConfig { rule: vec![] }
+    RulesConfig {
+        rule: vec![],
+        route: vec![],
+    }
 }
 
 fn build_interactive() -> RulesConfig {
@@ -24,7 +27,10 @@ fn build_interactive() -> RulesConfig {
     // Ask if user wants to configure rules
     if !ask_for_agreement("Do you want to configure connection filtering rules? (if not, all connections will be allowed)") {
         info!("Skipping rules configuration - all connections will be allowed.");
-        return RulesConfig { rule: vec![] };
+        return RulesConfig {
+            rule: vec![],
+            route: vec![],
+        };
     }
 
     println!();
@@ -37,7 +43,10 @@ fn build_interactive() -> RulesConfig {
 
     add_custom_rules(&mut rules);
 
-    RulesConfig { rule: rules }
+    RulesConfig {
+        rule: rules,
+        route: vec![],
+    }
 }
 
 fn add_custom_rules(rules: &mut Vec<Rule>) {
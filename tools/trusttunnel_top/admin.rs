@@ -0,0 +1,166 @@
+use serde::Deserialize;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+#[derive(Deserialize)]
+pub struct SessionList {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Session {
+    pub session: u64,
+    pub protocol: String,
+    pub age_secs: u64,
+    pub active_streams: usize,
+    pub draining: bool,
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Stats {
+    pub samples: Vec<StatsSample>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct StatsSample {
+    pub active_sessions: i64,
+    pub new_sessions: u64,
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+    pub errors: u64,
+    pub cpu_percent: f64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Event {
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub session: u64,
+    pub protocol: Option<String>,
+    pub server_name: Option<String>,
+    pub username: Option<String>,
+    pub reason: Option<String>,
+    pub duration_ms: Option<u64>,
+}
+
+/// A client of the endpoint admin interface (see `MetricsSettings` of the library)
+#[derive(Clone)]
+pub struct AdminClient {
+    address: SocketAddr,
+    /// The token the event stream is requested with
+    admin_token: Option<String>,
+}
+
+impl AdminClient {
+    pub fn new(address: SocketAddr, admin_token: Option<String>) -> Self {
+        Self {
+            address,
+            admin_token,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub async fn sessions(&self) -> io::Result<SessionList> {
+        self.get_json("/sessions").await
+    }
+
+    pub async fn stats(&self, last: usize) -> io::Result<Stats> {
+        self.get_json(&format!("/stats?last={}", last)).await
+    }
+
+    /// Subscribe to the event stream. The events are sent to `tx` until the stream
+    /// is closed by either side.
+    pub async fn stream_events(&self, tx: mpsc::Sender<Event>) -> io::Result<()> {
+        let mut stream = self.request("/events").await?;
+        let mut reader = BufReader::new(&mut stream);
+        read_response_head(&mut reader).await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+
+            // The event names are duplicated in the payload, the comments are ignored
+            let payload = match line.trim_end().strip_prefix("data:") {
+                Some(x) => x.trim_start(),
+                None => continue,
+            };
+            match serde_json::from_str(payload) {
+                Ok(x) => {
+                    if tx.send(x).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => log::debug!("Skipping malformed event: {}: {}", e, payload),
+            }
+        }
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, path: &str) -> io::Result<T> {
+        let mut stream = self.request(path).await?;
+        let mut reader = BufReader::new(&mut stream);
+        read_response_head(&mut reader).await?;
+
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await?;
+        serde_json::from_slice(&body).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    async fn request(&self, path: &str) -> io::Result<TcpStream> {
+        let authorization = self
+            .admin_token
+            .as_ref()
+            .map(|x| format!("Authorization: Bearer {}\r\n", x))
+            .unwrap_or_default();
+        let mut stream = TcpStream::connect(self.address).await?;
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+                    path, self.address, authorization
+                )
+                .as_bytes(),
+            )
+            .await?;
+        Ok(stream)
+    }
+}
+
+/// Read the status line and the headers, and check that the request succeeded
+async fn read_response_head<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<()> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    if status != "200" {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Unexpected response: {}", line.trim_end()),
+        ));
+    }
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        if line.trim_end().is_empty() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::admin::{Event, Session, StatsSample};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The number of the latest events shown
+const RECENT_EVENTS_NUMBER: usize = 8;
+/// The number of the sessions shown in the top talkers table
+const TOP_TALKERS_NUMBER: usize = 10;
+/// The period the error rates are calculated over
+const ERROR_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct Dashboard {
+    address: String,
+    sessions: Vec<Session>,
+    /// Transfer rates of the sessions (bytes per second) keyed by session identifier
+    rates: HashMap<u64, (u64, u64)>,
+    stats: Vec<StatsSample>,
+    events: VecDeque<Event>,
+    /// Timestamps (ms) of the recent failures
+    auth_failures: VecDeque<u64>,
+    request_failures: VecDeque<u64>,
+    last_error: Option<String>,
+}
+
+impl Dashboard {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            ..Default::default()
+        }
+    }
+
+    pub fn update_sessions(&mut self, sessions: Vec<Session>, elapsed: Duration) {
+        let previous: HashMap<u64, &Session> =
+            self.sessions.iter().map(|x| (x.session, x)).collect();
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        self.rates = sessions
+            .iter()
+            .map(|x| {
+                let (inbound, outbound) = previous
+                    .get(&x.session)
+                    .map(|p| (p.inbound_bytes, p.outbound_bytes))
+                    .unwrap_or((x.inbound_bytes, x.outbound_bytes));
+                (
+                    x.session,
+                    (
+                        (x.inbound_bytes.saturating_sub(inbound) as f64 / secs) as u64,
+                        (x.outbound_bytes.saturating_sub(outbound) as f64 / secs) as u64,
+                    ),
+                )
+            })
+            .collect();
+        self.sessions = sessions;
+    }
+
+    pub fn update_stats(&mut self, stats: Vec<StatsSample>) {
+        self.stats = stats;
+    }
+
+    pub fn on_event(&mut self, event: Event) {
+        match event.kind.as_str() {
+            "auth_failure" => self.auth_failures.push_back(event.timestamp),
+            "request_failed" => self.request_failures.push_back(event.timestamp),
+            _ => (),
+        }
+        for queue in [&mut self.auth_failures, &mut self.request_failures] {
+            let threshold = event
+                .timestamp
+                .saturating_sub(ERROR_RATE_WINDOW.as_millis() as u64);
+            while queue.front().is_some_and(|x| *x < threshold) {
+                queue.pop_front();
+            }
+        }
+
+        if self.events.len() == RECENT_EVENTS_NUMBER {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.last_error = error;
+    }
+
+    /// Render the dashboard into a string of ANSI terminal commands
+    pub fn render(&self) -> String {
+        let mut out = String::from("\x1b[H\x1b[2J");
+        let _ = write!(out, "\x1b[1mtrusttunnel-top\x1b[0m  {}", self.address);
+        if let Some(e) = &self.last_error {
+            let _ = write!(out, "  \x1b[31m{}\x1b[0m", e);
+        }
+        out.push_str("\r\n\r\n");
+
+        self.render_summary(&mut out);
+        self.render_protocols(&mut out);
+        self.render_top_talkers(&mut out);
+        self.render_events(&mut out);
+
+        out.push_str("\r\nPress Ctrl+C to exit");
+        out
+    }
+
+    fn render_summary(&self, out: &mut String) {
+        let latest = self.stats.last().copied();
+        let minute: Vec<&StatsSample> = self.stats.iter().rev().take(60).collect();
+        let _ = write!(
+            out,
+            "Sessions: {} active, {} opened in the last minute    CPU: {:.1}%\r\n",
+            latest.map(|x| x.active_sessions).unwrap_or_default(),
+            minute.iter().map(|x| x.new_sessions).sum::<u64>(),
+            latest.map(|x| x.cpu_percent).unwrap_or_default(),
+        );
+        let _ = write!(
+            out,
+            "Traffic:  {} up, {} down\r\n",
+            format_rate(latest.map(|x| x.inbound_bytes).unwrap_or_default()),
+            format_rate(latest.map(|x| x.outbound_bytes).unwrap_or_default()),
+        );
+        let _ = write!(
+            out,
+            "Errors:   {} failed requests in the last minute ({} auth failures, {} other)\r\n\r\n",
+            minute.iter().map(|x| x.errors).sum::<u64>(),
+            self.auth_failures.len(),
+            self.request_failures.len(),
+        );
+    }
+
+    fn render_protocols(&self, out: &mut String) {
+        let mut protocols: BTreeMap<&str, (usize, u64, u64)> = BTreeMap::new();
+        for x in &self.sessions {
+            let (inbound, outbound) = self.rates.get(&x.session).copied().unwrap_or_default();
+            let entry = protocols.entry(&x.protocol).or_default();
+            entry.0 += 1;
+            entry.1 += inbound;
+            entry.2 += outbound;
+        }
+
+        let _ = write!(
+            out,
+            "\x1b[7m{:<10}{:>10}{:>14}{:>14}\x1b[0m\r\n",
+            "PROTOCOL", "SESSIONS", "UP", "DOWN"
+        );
+        for (protocol, (n, inbound, outbound)) in protocols {
+            let _ = write!(
+                out,
+                "{:<10}{:>10}{:>14}{:>14}\r\n",
+                protocol,
+                n,
+                format_rate(inbound),
+                format_rate(outbound)
+            );
+        }
+        out.push_str("\r\n");
+    }
+
+    fn render_top_talkers(&self, out: &mut String) {
+        let mut sessions: Vec<&Session> = self.sessions.iter().collect();
+        let rate = |x: &Session| {
+            let (inbound, outbound) = self.rates.get(&x.session).copied().unwrap_or_default();
+            inbound + outbound
+        };
+        sessions.sort_by_key(|x| std::cmp::Reverse((rate(x), x.inbound_bytes + x.outbound_bytes)));
+
+        let _ = write!(
+            out,
+            "\x1b[7m{:<10}{:<10}{:>10}{:>9}{:>14}{:>14}{:>12}\x1b[0m\r\n",
+            "SESSION", "PROTOCOL", "AGE", "STREAMS", "RATE", "TOTAL", ""
+        );
+        for x in sessions.into_iter().take(TOP_TALKERS_NUMBER) {
+            let _ = write!(
+                out,
+                "{:<10}{:<10}{:>10}{:>9}{:>14}{:>14}{:>12}\r\n",
+                x.session,
+                x.protocol,
+                format_duration(x.age_secs),
+                x.active_streams,
+                format_rate(rate(x)),
+                format_bytes(x.inbound_bytes + x.outbound_bytes),
+                if x.draining { "draining" } else { "" },
+            );
+        }
+        out.push_str("\r\n");
+    }
+
+    fn render_events(&self, out: &mut String) {
+        let _ = write!(out, "\x1b[7m{:<80}\x1b[0m\r\n", "RECENT EVENTS");
+        for x in self.events.iter().rev() {
+            let details = match x.kind.as_str() {
+                "session_opened" => format!(
+                    "{} {}",
+                    x.protocol.as_deref().unwrap_or_default(),
+                    x.server_name.as_deref().unwrap_or_default()
+                ),
+                "session_closed" => format!(
+                    "after {}",
+                    format_duration(x.duration_ms.unwrap_or_default() / 1000)
+                ),
+                "auth_failure" => x.username.clone().unwrap_or_else(|| "-".to_string()),
+                _ => x.reason.clone().unwrap_or_default(),
+            };
+            let color = match x.kind.as_str() {
+                "auth_failure" | "request_failed" => "\x1b[31m",
+                _ => "",
+            };
+            let _ = write!(
+                out,
+                "{}{:<16} session {:<8} {}\x1b[0m\r\n",
+                color, x.kind, x.session, details
+            );
+        }
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_rate(n: u64) -> String {
+    format!("{}/s", format_bytes(n))
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        x if x < 60 => format!("{}s", x),
+        x if x < 3600 => format!("{}m{:02}s", x / 60, x % 60),
+        x => format!("{}h{:02}m", x / 3600, x % 3600 / 60),
+    }
+}
@@ -0,0 +1,113 @@
+use crate::admin::AdminClient;
+use crate::dashboard::Dashboard;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+mod admin;
+mod dashboard;
+
+const ADDRESS_PARAM_NAME: &str = "address";
+const INTERVAL_PARAM_NAME: &str = "interval";
+const ADMIN_TOKEN_PARAM_NAME: &str = "admin_token";
+/// The number of the stats samples requested on each refresh
+const STATS_WINDOW: usize = 60;
+/// The delay before resubscribing to the event stream after a failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+
+fn main() {
+    let args = clap::Command::new("TrustTunnel endpoint monitor")
+        .about("Show live activity of a running TrustTunnel endpoint")
+        .after_help(
+            r#"The endpoint must have the metrics listener enabled (the [metrics] section
+of the main settings file). The monitor connects to its address. The latest events
+are shown only if the admin token of the listener is passed.
+
+EXAMPLES:
+    ./trusttunnel-top -a 127.0.0.1:1987
+"#,
+        )
+        .args(&[
+            clap::Arg::new(ADDRESS_PARAM_NAME)
+                .short('a')
+                .long("address")
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(SocketAddr))
+                .default_value("127.0.0.1:1987")
+                .help("The address of the endpoint metrics listener"),
+            clap::Arg::new(INTERVAL_PARAM_NAME)
+                .short('i')
+                .long("interval")
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("1")
+                .help("The refresh interval in seconds"),
+            clap::Arg::new(ADMIN_TOKEN_PARAM_NAME)
+                .long("admin-token")
+                .action(clap::ArgAction::Set)
+                .help("The admin token of the endpoint metrics listener"),
+        ])
+        .get_matches();
+
+    let address = *args.get_one::<SocketAddr>(ADDRESS_PARAM_NAME).unwrap();
+    let interval = Duration::from_secs(*args.get_one::<u64>(INTERVAL_PARAM_NAME).unwrap());
+    let admin_token = args.get_one::<String>(ADMIN_TOKEN_PARAM_NAME).cloned();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create the runtime");
+
+    // Switch to the alternate screen and hide the cursor
+    print!("\x1b[?1049h\x1b[?25l");
+    runtime.block_on(async {
+        tokio::select! {
+            _ = run(AdminClient::new(address, admin_token), interval) => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    });
+    print!("\x1b[?25h\x1b[?1049l");
+    let _ = std::io::stdout().flush();
+}
+
+async fn run(client: AdminClient, interval: Duration) {
+    let (event_tx, mut event_rx) = mpsc::channel(1024);
+    let events = client.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = events.stream_events(event_tx.clone()).await {
+                log::debug!("Event stream failure: {}", e);
+            }
+            if event_tx.is_closed() {
+                return;
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+
+    let mut dashboard = Dashboard::new(client.address().to_string());
+    let mut ticker = tokio::time::interval(interval);
+    let mut last_refresh = Instant::now();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let now = Instant::now();
+                match tokio::try_join!(client.sessions(), client.stats(STATS_WINDOW)) {
+                    Ok((sessions, stats)) => {
+                        dashboard.update_sessions(sessions.sessions, now - last_refresh);
+                        dashboard.update_stats(stats.samples);
+                        dashboard.set_error(None);
+                    }
+                    Err(e) => dashboard.set_error(Some(e.to_string())),
+                }
+                last_refresh = now;
+
+                print!("{}", dashboard.render());
+                let _ = std::io::stdout().flush();
+            }
+            Some(event) = event_rx.recv() => dashboard.on_event(event),
+        }
+    }
+}